@@ -0,0 +1,88 @@
+//! # Engine Benchmarks
+//! Measures the parser and the recalculation engine on the synthetic workloads of
+//! `spreadsheet::workload`. Run with `cargo bench`; criterion keeps the previous results and
+//! reports the change against them.
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use spreadsheet::parser::{detect_formula, update_and_recalc};
+use spreadsheet::storage::{ColumnStore, SheetStorage};
+use spreadsheet::utils::compute_range;
+use spreadsheet::workload::{chain_sheet, constant_sheet, formula_corpus};
+use spreadsheet::{Cell, CellData, Valtype};
+
+/// Parses a corpus of formulas of every kind.
+fn bench_detect_formula(c: &mut Criterion) {
+    let corpus = formula_corpus(1000, 999, 18278, 42);
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: Default::default(),
+    };
+    c.bench_function("detect_formula/corpus_1000", |b| {
+        b.iter(|| {
+            for formula in &corpus {
+                detect_formula(&mut cell, black_box(formula));
+            }
+        })
+    });
+}
+
+/// Changes the head of long chains, recalculating every cell after it.
+fn bench_chain_recalc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("update_and_recalc/chain");
+    group.sample_size(20);
+    for length in [1_000, 10_000] {
+        let mut workload = chain_sheet(length);
+        let mut head = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(length), &length, |b, _| {
+            b.iter(|| {
+                head = (head + 1) % 100;
+                let backup = workload.sheet[&0].clone();
+                let mut cell = backup.clone();
+                detect_formula(&mut cell, &head.to_string());
+                workload.sheet.insert(0, cell);
+                update_and_recalc(
+                    &mut workload.sheet,
+                    &mut workload.ranged,
+                    &mut workload.is_range,
+                    workload.total_dims,
+                    0,
+                    0,
+                    backup,
+                )
+                .status
+                .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Sums a whole sheet, with every cell filled and with one cell in fifty filled, in each
+/// storage backend.
+fn bench_compute_range(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_range/sum_500x100");
+    for (label, spacing) in [("dense", 1), ("sparse", 50)] {
+        let workload = constant_sheet(500, 100, spacing, 7);
+        let mut columns = ColumnStore::new(100);
+        for (&key, cell) in &workload.sheet {
+            columns.insert(key, cell.clone());
+        }
+        group.bench_function(format!("{}/map", label), |b| {
+            b.iter(|| compute_range(black_box(&workload.sheet), 100, 0, 499, 0, 99, 4))
+        });
+        group.bench_function(format!("{}/columns", label), |b| {
+            b.iter(|| compute_range(black_box(&columns), 100, 0, 499, 0, 99, 4))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_detect_formula,
+    bench_chain_recalc,
+    bench_compute_range
+);
+criterion_main!(benches);
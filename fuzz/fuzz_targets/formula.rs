@@ -0,0 +1,41 @@
+//! Fuzzes the formula parser and evaluator with arbitrary text.
+//!
+//! Run with `cargo fuzz run formula` from the repository root. Each input is split into lines
+//! and every line is assigned in turn to a cell of a small sheet, so that formulas get to read
+//! each other; no input may panic. The sheet runs in safe mode, so a SLEEP formula is rejected
+//! rather than stalling the fuzzer for as many seconds as it asks for.
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use spreadsheet::parser::assign_formula;
+use spreadsheet::utils::EvalContext;
+use spreadsheet::{Cell, CellKey};
+
+const ROWS: usize = 6;
+const COLS: usize = 6;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let context = EvalContext::default();
+    context.set_safe(true);
+    context.install();
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; ROWS * COLS];
+    for (i, line) in text.lines().enumerate().take(ROWS * COLS) {
+        let (row, col) = (i / COLS, i % COLS);
+        let _ = assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            (ROWS, COLS),
+            row,
+            col,
+            line,
+        );
+    }
+});
@@ -0,0 +1,225 @@
+//! # Cache Module
+//! This module keeps the totals of the ranges read by range formulas between recalculations,
+//! so that editing one cell of a large range updates a SUM, AVG or COUNT over it in constant
+//! time instead of reading the whole range again. MIN and MAX are updated the same way unless
+//! the edit lowers the largest value or raises the smallest, and STDEV is always computed
+//! afresh.
+//!
+//! A cache only follows the cells through `update_and_recalc`, the recalculation of a single
+//! edited cell, and only while it is installed on the thread with `RangeCache::scope`. Any
+//! other recalculation run in a scope empties the cache, and its owner empties it with
+//! `RangeCache::clear` after changing the sheet outside a scope.
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::format::Corners;
+use crate::storage::SheetStorage;
+use crate::utils::RangeTotals;
+use crate::{CellKey, EvalStatus, Valtype};
+
+thread_local! {
+    /// The cache installed on the thread by `RangeCache::scope`, if any.
+    static INSTALLED: RefCell<Option<RangeCache>> = const { RefCell::new(None) };
+}
+
+/// The totals of the ranges read by one range formula, as of its last evaluation.
+///
+/// # Fields
+/// * `corners` - The areas of the formula.
+/// * `choice` - The function of the formula, as for `compute_range`.
+/// * `totals` - The totals of the areas.
+/// * `bounded` - Whether `totals.min` and `totals.max` are still known; an edit that lowers
+///   the largest value or raises the smallest leaves them unknown.
+#[derive(Clone, Debug)]
+struct CachedRange {
+    corners: Vec<Corners>,
+    choice: i32,
+    totals: RangeTotals,
+    bounded: bool,
+}
+
+impl CachedRange {
+    /// Takes a cell that changed from `old` to `new` into the totals.
+    fn apply(&mut self, old: i32, new: i32) {
+        let totals = &mut self.totals;
+        totals.sum += new as i64 - old as i64;
+        if new >= totals.max {
+            totals.max = new;
+        } else if old == totals.max {
+            self.bounded = false;
+        }
+        if new <= totals.min {
+            totals.min = new;
+        } else if old == totals.min {
+            self.bounded = false;
+        }
+    }
+}
+
+/// The totals of range formulas, by the key of the formula's cell, kept between
+/// recalculations of one sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::cache::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 3000]);
+/// # let dims = (1000, 3);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, dims, 0, 2, "SUM(A1:B1000)")?;
+/// let mut cache = RangeCache::default();
+/// // C1 = SUM(A1:B1000); the first edit reads the range, the second only A1
+/// RangeCache::scope(&mut cache, || assign_formula(&mut sheet, &mut ranged, &mut is_range, dims, 0, 0, "5"))?;
+/// RangeCache::scope(&mut cache, || assign_formula(&mut sheet, &mut ranged, &mut is_range, dims, 0, 0, "7"))?;
+/// assert_eq!(cache.hits(), 1);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RangeCache {
+    entries: HashMap<CellKey, CachedRange>,
+    hits: usize,
+}
+
+impl RangeCache {
+    /// Runs a closure with the cache installed on the thread, so that the recalculations it
+    /// runs read and update the cache.
+    ///
+    /// # Arguments
+    /// * `cache` - The cache of the sheet the closure changes.
+    /// * `run` - The closure.
+    ///
+    /// # Returns
+    /// * `R` - What the closure returned.
+    pub fn scope<R>(cache: &mut RangeCache, run: impl FnOnce() -> R) -> R {
+        let installed = std::mem::take(cache);
+        INSTALLED.with(|slot| *slot.borrow_mut() = Some(installed));
+        let result = run();
+        *cache = INSTALLED
+            .with(|slot| slot.borrow_mut().take())
+            .unwrap_or_default();
+        result
+    }
+
+    /// Takes the cache installed on the thread, for the length of a recalculation; it is put
+    /// back with `reinstall`.
+    pub(crate) fn take_installed() -> Option<RangeCache> {
+        INSTALLED.with(|slot| slot.borrow_mut().take())
+    }
+
+    /// Puts back a cache taken with `take_installed`.
+    pub(crate) fn reinstall(self) {
+        INSTALLED.with(|slot| *slot.borrow_mut() = Some(self));
+    }
+
+    /// Forgets every range, after the sheet was changed without the cache.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the number of range formulas whose totals are kept.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether no totals are kept.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the number of evaluations answered from kept totals, without reading the range.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Forgets the totals of a formula, whose cell was assigned again.
+    pub(crate) fn forget(&mut self, key: CellKey) {
+        self.entries.remove(&key);
+    }
+
+    /// Computes a range formula from its kept totals, brought up to date with the cells that
+    /// changed since it was last evaluated.
+    ///
+    /// # Arguments
+    /// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    /// * `key` - The key of the formula's cell.
+    /// * `corners` - The areas of the formula.
+    /// * `choice` - The function of the formula, as for `compute_range`.
+    /// * `before` - The cells changed since the last recalculation, with their value before.
+    ///
+    /// # Returns
+    /// * `Option<Result<i32, EvalStatus>>` - The result, or `None` if the totals are not kept,
+    ///   or could not be brought up to date, in which case they are forgotten.
+    pub(crate) fn lookup<S: SheetStorage + ?Sized>(
+        &mut self,
+        sheet: &S,
+        total_cols: usize,
+        key: CellKey,
+        corners: &[Corners],
+        choice: i32,
+        before: &HashMap<CellKey, Valtype>,
+    ) -> Option<Result<i32, EvalStatus>> {
+        let mut entry = self.entries.remove(&key)?;
+        if entry.corners != corners || entry.choice != choice {
+            return None;
+        }
+        for (&changed, old) in before {
+            let (row, col) = (changed as usize / total_cols, changed as usize % total_cols);
+            let inside = corners
+                .iter()
+                .filter(|&&((r1, c1), (r2, c2))| {
+                    (r1..=r2).contains(&row) && (c1..=c2).contains(&col)
+                })
+                .count();
+            if inside == 0 {
+                continue;
+            }
+            let new = sheet
+                .get(&changed)
+                .map_or(Valtype::Int(0), |cell| cell.value.clone());
+            // A cell that holds or held text or an error changes the count, or makes the whole
+            // range fail or recover
+            let (&Valtype::Int(old), Valtype::Int(new)) = (old, new) else {
+                return None;
+            };
+            // A cell lying in two areas counts twice
+            for _ in 0..inside {
+                entry.apply(old, new);
+            }
+        }
+        if !entry.bounded && matches!(choice, 1 | 2) {
+            return None;
+        }
+        let result = entry.totals.result(choice)?;
+        self.entries.insert(key, entry);
+        self.hits += 1;
+        Some(result)
+    }
+
+    /// Keeps the totals of a range formula that was computed from the whole range.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the formula's cell.
+    /// * `corners` - The areas of the formula.
+    /// * `choice` - The function of the formula, as for `compute_range`.
+    /// * `totals` - The totals of the areas.
+    pub(crate) fn store(
+        &mut self,
+        key: CellKey,
+        corners: Vec<Corners>,
+        choice: i32,
+        totals: RangeTotals,
+    ) {
+        self.entries.insert(
+            key,
+            CachedRange {
+                corners,
+                choice,
+                totals,
+                bounded: true,
+            },
+        );
+    }
+}
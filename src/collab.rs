@@ -0,0 +1,182 @@
+//! # Collab Module
+//! This module keeps track of the clients sharing one sheet through the HTTP server of the
+//! `server` feature: the cell each client has selected, shown to the others as a cursor, and a
+//! log of the edits made through the server, numbered by version. A client polls for the
+//! edits after the last version it saw, and applies them to its own copy of the sheet; a
+//! client that fell further behind than the log reaches reads the whole sheet again.
+//!
+//! Clients name themselves. A cursor that is not moved or refreshed for `PEER_TIMEOUT` is
+//! dropped, so that a client that went away without leaving stops showing.
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The number of edits kept in the log. A client further behind reads the whole sheet.
+pub const MAX_CHANGES: usize = 1024;
+
+/// How long the cursor of a client is shown after it was last moved or refreshed.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest client name, in bytes.
+pub const MAX_CLIENT_NAME: usize = 32;
+
+/// The cursor of a client: the cell it has selected.
+///
+/// # Fields
+/// * `client` - The name the client chose.
+/// * `cell` - The selected cell, such as "B2".
+/// * `color` - The index of the color the cursor is drawn in, the same for as long as the
+///   client stays.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Peer {
+    pub client: String,
+    pub cell: String,
+    pub color: usize,
+}
+
+/// An edit made through the server.
+///
+/// # Fields
+/// * `version` - The version of the sheet the edit produced.
+/// * `cell` - The edited cell, such as "A1".
+/// * `formula` - The formula the cell holds after the edit, empty if it was cleared.
+/// * `client` - The client that made the edit, if it gave its name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub version: u64,
+    pub cell: String,
+    pub formula: String,
+    pub client: Option<String>,
+}
+
+/// The cursors and recent edits of the clients of one sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::collab::*;
+/// # use std::time::Instant;
+/// let mut collab = Collab::default();
+/// let now = Instant::now();
+/// collab.move_cursor("ann", "B2", now);
+/// collab.record_edit("A1", "5", Some("ann"));
+/// assert_eq!(collab.peers(now)[0].cell, "B2");
+/// assert_eq!(collab.changes_since(0).unwrap()[0].formula, "5");
+/// ```
+#[derive(Debug, Default)]
+pub struct Collab {
+    version: u64,
+    peers: BTreeMap<String, (Peer, Instant)>,
+    changes: VecDeque<Change>,
+    next_color: usize,
+}
+
+impl Collab {
+    /// Checks a client name: 1 to `MAX_CLIENT_NAME` letters, digits, '-' or '_'.
+    pub fn valid_client(name: &str) -> bool {
+        (1..=MAX_CLIENT_NAME).contains(&name.len())
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    /// Returns the version of the sheet: the number of edits recorded so far.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Moves the cursor of a client, adding the client if it is new.
+    ///
+    /// # Arguments
+    /// * `client` - The name of the client, checked with `valid_client`.
+    /// * `cell` - The cell the client selected.
+    /// * `now` - The current time, from which the cursor is kept for `PEER_TIMEOUT`.
+    ///
+    /// # Returns
+    /// * `Peer` - The cursor as the other clients see it.
+    pub fn move_cursor(&mut self, client: &str, cell: &str, now: Instant) -> Peer {
+        self.prune(now);
+        let color = match self.peers.get(client) {
+            Some((peer, _)) => peer.color,
+            None => {
+                self.next_color += 1;
+                self.next_color - 1
+            }
+        };
+        let peer = Peer {
+            client: client.to_string(),
+            cell: cell.to_string(),
+            color,
+        };
+        self.peers.insert(client.to_string(), (peer.clone(), now));
+        peer
+    }
+
+    /// Removes the cursor of a client that stopped editing.
+    ///
+    /// # Returns
+    /// * `bool` - Whether the client had a cursor.
+    pub fn leave(&mut self, client: &str) -> bool {
+        self.peers.remove(client).is_some()
+    }
+
+    /// Lists the cursors, by client name, after dropping those not refreshed for
+    /// `PEER_TIMEOUT`.
+    pub fn peers(&mut self, now: Instant) -> Vec<Peer> {
+        self.prune(now);
+        self.peers.values().map(|(peer, _)| peer.clone()).collect()
+    }
+
+    /// Drops the cursors not refreshed for `PEER_TIMEOUT`.
+    fn prune(&mut self, now: Instant) {
+        self.peers
+            .retain(|_, (_, seen)| now.saturating_duration_since(*seen) < PEER_TIMEOUT);
+    }
+
+    /// Logs an edit, dropping the oldest past `MAX_CHANGES`.
+    ///
+    /// # Arguments
+    /// * `cell` - The edited cell.
+    /// * `formula` - The formula the cell holds after the edit.
+    /// * `client` - The client that made the edit, if known.
+    ///
+    /// # Returns
+    /// * `u64` - The new version of the sheet.
+    pub fn record_edit(&mut self, cell: &str, formula: &str, client: Option<&str>) -> u64 {
+        self.version += 1;
+        if self.changes.len() == MAX_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(Change {
+            version: self.version,
+            cell: cell.to_string(),
+            formula: formula.to_string(),
+            client: client.map(str::to_string),
+        });
+        self.version
+    }
+
+    /// Lists the edits made after a version, oldest first.
+    ///
+    /// # Arguments
+    /// * `since` - The last version the client saw, 0 for none.
+    ///
+    /// # Returns
+    /// * `Option<Vec<Change>>` - The edits, or `None` if some of them were dropped from the
+    ///   log or `since` is a version the sheet never had, as after the server restarted. The
+    ///   client then has to read the whole sheet again.
+    pub fn changes_since(&self, since: u64) -> Option<Vec<Change>> {
+        let oldest = self.changes.front().map_or(self.version + 1, |c| c.version);
+        if since > self.version || since + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.changes
+                .iter()
+                .filter(|change| change.version > since)
+                .cloned()
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,122 @@
+//! # Dump Module
+//! This module prints a sheet as plain text, one line per non-empty cell, and compares a sheet
+//! against such a listing. Each line reads `A1=formula # value`, in row-major order, so two
+//! dumps of the same sheet are identical and can be compared line by line, by `diff` or by a
+//! grader.
+use std::collections::BTreeMap;
+
+use crate::storage::SheetStorage;
+use crate::utils::{formula_string, to_cell_name, to_indices};
+use crate::{Cell, CellData, EvalStatus, Valtype};
+
+/// Formats the formula and value of a cell as they appear after the `=` of a dump line.
+fn dump_entry(cell: &Cell) -> String {
+    let value = match &cell.value {
+        Valtype::Int(v) => v.to_string(),
+        Valtype::Str(s) => s.to_string(),
+        Valtype::Err(error) => error.to_string(),
+    };
+    format!("{} # {}", formula_string(cell), value)
+}
+
+/// Lists the non-empty cells of a sheet, keyed by position, with their dump entries. A cell
+/// left holding a formula that was not understood has no formula to list, and is skipped.
+fn dump_entries<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_cols: usize,
+) -> BTreeMap<(usize, usize), String> {
+    spreadsheet
+        .cells()
+        .filter(|(_, cell)| !matches!(cell.data, CellData::Empty | CellData::Invalid))
+        .map(|(key, cell)| {
+            let key = key as usize;
+            ((key / total_cols, key % total_cols), dump_entry(cell))
+        })
+        .collect()
+}
+
+/// Lists every non-empty cell as `A1=formula # value`, in row-major order.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Vec<String>` - One line per cell holding a constant or formula.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::dump::*;
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "B1+2")?;
+/// // B1 = 5, A1 = B1+2
+/// assert_eq!(dump_lines(&sheet, 3), vec!["A1=B1+2 # 7", "B1=5 # 5"]);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn dump_lines<S: SheetStorage + ?Sized>(spreadsheet: &S, total_cols: usize) -> Vec<String> {
+    dump_entries(spreadsheet, total_cols)
+        .into_iter()
+        .map(|((row, col), entry)| format!("{}={}", to_cell_name(row, col), entry))
+        .collect()
+}
+
+/// Compares a sheet against a listing written by `dump_lines`.
+///
+/// Blank lines of the listing are skipped. A cell missing on either side is shown as
+/// `(empty)`.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+/// * `previous` - The earlier listing.
+///
+/// # Returns
+/// * `Result<Vec<String>, EvalStatus>` - One `A1: before -> after` line per changed cell, in
+///   row-major order, or `EvalStatus::UnrecognizedCmd` if a line of the listing does not start
+///   with a cell name and `=`.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::dump::*;
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "6")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "B1+2")?;
+/// // B1 changed from 5 to 6 since the dump
+/// let changes = diff_lines(&sheet, 3, "A1=B1+2 # 7\nB1=5 # 5\n")?;
+/// assert_eq!(changes, vec!["A1: B1+2 # 7 -> B1+2 # 8", "B1: 5 # 5 -> 6 # 6"]);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn diff_lines<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_cols: usize,
+    previous: &str,
+) -> Result<Vec<String>, EvalStatus> {
+    let mut before = BTreeMap::new();
+    for line in previous.lines().filter(|line| !line.trim().is_empty()) {
+        let (name, entry) = line.split_once('=').ok_or(EvalStatus::UnrecognizedCmd)?;
+        let cell = to_indices(name.trim()).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+        before.insert(cell, entry.trim().to_string());
+    }
+    let mut after = dump_entries(spreadsheet, total_cols);
+    let mut cells: Vec<(usize, usize)> = before.keys().chain(after.keys()).copied().collect();
+    cells.sort_unstable();
+    cells.dedup();
+    let empty = || "(empty)".to_string();
+    Ok(cells
+        .into_iter()
+        .filter_map(|(row, col)| {
+            let old = before.remove(&(row, col)).unwrap_or_else(empty);
+            let new = after.remove(&(row, col)).unwrap_or_else(empty);
+            (old != new).then(|| format!("{}: {} -> {}", to_cell_name(row, col), old, new))
+        })
+        .collect())
+}
@@ -0,0 +1,322 @@
+//! # Engine Module
+//! This module provides the `Spreadsheet` struct, which owns the cell map and the range
+//! bookkeeping used for dependency tracking, and exposes them through a small API for
+//! setting formulas and reading values.
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+use crate::cache::RangeCache;
+use crate::import::{ImportProgress, ImportSummary, stream_csv};
+use crate::info::{SheetInfo, sheet_info};
+use crate::lint::{LintIssue, LintOptions, lint};
+use crate::lock::LockTable;
+use crate::names::{NameTable, define_name};
+use crate::parser::{assign_formula, recalc_all};
+use crate::structure::{LineEdit, edit_lines};
+use crate::utils::{EvalContext, formula_string, resize_sheet};
+use crate::validation::ValidationTable;
+use crate::{Cell, CellAddr, CellData, CellKey, EvalStatus, Valtype};
+
+/// A spreadsheet of fixed dimensions that keeps its values up to date as formulas change.
+///
+/// Each spreadsheet evaluates its cells with an `EvalContext` of its own, so that its `RAND`
+/// cells are drawn independently of any other sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// let mut sheet = Spreadsheet::new(10, 10);
+/// sheet.set_formula(CellAddr::new(0, 0), "5").unwrap();
+/// sheet.set_formula(CellAddr::new(1, 0), "A1*2").unwrap();
+/// assert_eq!(sheet.get_value(CellAddr::new(1, 0)), Some(Valtype::Int(10)));
+/// assert_eq!(sheet.get_formula(CellAddr::new(1, 0)), Some("A1*2".to_string()));
+/// ```
+pub struct Spreadsheet {
+    cells: HashMap<CellKey, Cell>,
+    ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: Vec<bool>,
+    names: NameTable,
+    range_cache: RangeCache,
+    eval: EvalContext,
+    total_rows: usize,
+    total_cols: usize,
+}
+
+impl Spreadsheet {
+    /// Creates an empty spreadsheet.
+    ///
+    /// # Arguments
+    /// * `total_rows` - The number of rows in the spreadsheet.
+    /// * `total_cols` - The number of columns in the spreadsheet.
+    pub fn new(total_rows: usize, total_cols: usize) -> Self {
+        Spreadsheet {
+            cells: HashMap::new(),
+            ranged: HashMap::new(),
+            is_range: vec![false; total_rows * total_cols],
+            names: NameTable::default(),
+            range_cache: RangeCache::default(),
+            eval: EvalContext::default(),
+            total_rows,
+            total_cols,
+        }
+    }
+
+    /// Turns safe mode on or off: in safe mode the volatile formulas, SLEEP, RAND and
+    /// RANDBETWEEN, are rejected and no evaluation sleeps.
+    ///
+    /// # Arguments
+    /// * `safe` - Whether the sheet runs in safe mode.
+    pub fn set_safe_mode(&mut self, safe: bool) {
+        self.eval.set_safe(safe);
+    }
+
+    /// Sets the script functions the formulas of the sheet may call, replacing any set before.
+    ///
+    /// # Arguments
+    /// * `functions` - The compiled script, or `None` for no script functions.
+    #[cfg(feature = "scripting")]
+    pub fn set_functions(
+        &mut self,
+        functions: Option<std::sync::Arc<crate::scripting::UserFunctions>>,
+    ) {
+        self.eval.set_functions(functions);
+    }
+
+    /// Returns the dimensions of the spreadsheet as `(total_rows, total_cols)`.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.total_rows, self.total_cols)
+    }
+
+    /// Returns the map key of a cell, or `None` if it lies outside the sheet.
+    fn key(&self, addr: CellAddr) -> Option<CellKey> {
+        (addr.row < self.total_rows && addr.col < self.total_cols)
+            .then(|| (addr.row * self.total_cols + addr.col) as CellKey)
+    }
+
+    /// Changes the dimensions of the sheet, keeping every cell at its row and column.
+    ///
+    /// # Arguments
+    /// * `total_rows` - The new number of rows, at least 1.
+    /// * `total_cols` - The new number of columns, at least 1.
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())` if the sheet was resized, or
+    ///   `EvalStatus::InvalidRange` if a dimension is 0 or a non-empty cell or a reference would
+    ///   fall outside the new bounds, in which case nothing changes.
+    pub fn resize(&mut self, total_rows: usize, total_cols: usize) -> Result<(), EvalStatus> {
+        if total_rows == 0 || total_cols == 0 {
+            return Err(EvalStatus::InvalidRange);
+        }
+        resize_sheet(
+            &mut self.cells,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+            (total_rows, total_cols),
+        )?;
+        self.range_cache.clear();
+        self.total_rows = total_rows;
+        self.total_cols = total_cols;
+        Ok(())
+    }
+
+    /// Inserts or deletes a row or column, moving the cells past it and their references.
+    ///
+    /// # Arguments
+    /// * `edit` - The row or column to insert or delete.
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())` if the sheet was edited, or
+    ///   `EvalStatus::InvalidRange` if the sheet cannot grow or shrink that way or a formula
+    ///   refers to a deleted row or column, in which case nothing changes.
+    pub fn edit_lines(&mut self, edit: LineEdit) -> Result<(), EvalStatus> {
+        (self.total_rows, self.total_cols) = self.eval.scope(|| {
+            edit_lines(
+                &mut self.cells,
+                &mut self.ranged,
+                &mut self.is_range,
+                (self.total_rows, self.total_cols),
+                edit,
+            )
+        })?;
+        self.range_cache.clear();
+        Ok(())
+    }
+
+    /// Assigns a formula to a cell and recalculates everything that depends on it.
+    ///
+    /// # Arguments
+    /// * `addr` - The cell to assign.
+    /// * `formula` - The formula text (e.g., "B1+2", "SUM(A1:A5)" or "SUM(taxes)" for a range
+    ///   named with `define_name`).
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())` on success, `EvalStatus::InvalidRange` if `addr`
+    ///   or a reference lies outside the sheet, or the status explaining why the formula was
+    ///   rejected. A formula that would create a cycle leaves the cell unchanged.
+    pub fn set_formula(&mut self, addr: CellAddr, formula: &str) -> Result<(), EvalStatus> {
+        self.key(addr).ok_or(EvalStatus::InvalidRange)?;
+        let named = self.names.resolve(formula);
+        // Only the edited cell changes, so range totals are updated rather than read again
+        self.eval.scope(|| {
+            RangeCache::scope(&mut self.range_cache, || {
+                assign_formula(
+                    &mut self.cells,
+                    &mut self.ranged,
+                    &mut self.is_range,
+                    (self.total_rows, self.total_cols),
+                    addr.row,
+                    addr.col,
+                    named.as_ref().map_or(formula, |(resolved, _)| resolved),
+                )
+            })
+        })?;
+        let name = named.as_ref().map(|(_, name)| name.as_str());
+        self.names.record(addr.row, addr.col, name);
+        Ok(())
+    }
+
+    /// Names a range, so that formulas can read it as e.g. `SUM(taxes)`. See
+    /// `names::define_name`.
+    ///
+    /// # Arguments
+    /// * `name` - The name, matched ignoring case.
+    /// * `range` - The range it labels (e.g., "A1:B10").
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())` if the name was defined, or the status explaining
+    ///   why it was rejected. Redefining a name moves the formulas using it to the new range.
+    pub fn define_name(&mut self, name: &str, range: &str) -> Result<(), EvalStatus> {
+        self.range_cache.clear();
+        self.eval.scope(|| {
+            define_name(
+                &mut self.names,
+                &mut self.cells,
+                &mut self.ranged,
+                &mut self.is_range,
+                (self.total_rows, self.total_cols),
+                // Cells of the API are never locked
+                &LockTable::default(),
+                (name, range),
+            )
+        })
+    }
+
+    /// Streams CSV records into the sheet, one record per row starting at A1.
+    ///
+    /// Cells not mentioned in the input keep their contents. See `import::stream_csv`.
+    ///
+    /// # Arguments
+    /// * `reader` - The CSV input.
+    /// * `progress` - Called after every `import::IMPORT_CHUNK_ROWS` rows; returning `false`
+    ///   cancels the import, leaving the rows read so far.
+    ///
+    /// # Returns
+    /// * `io::Result<ImportSummary>` - An error if the input could not be read, or how much was
+    ///   loaded and whether every field was valid.
+    pub fn import_csv<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        progress: impl FnMut(ImportProgress) -> bool,
+    ) -> io::Result<ImportSummary> {
+        self.range_cache.clear();
+        self.eval.scope(|| {
+            stream_csv(
+                reader,
+                &mut self.cells,
+                &mut self.ranged,
+                &mut self.is_range,
+                (self.total_rows, self.total_cols),
+                &mut ValidationTable::default(),
+                progress,
+            )
+        })
+    }
+
+    /// Returns the current value of a cell.
+    ///
+    /// # Returns
+    /// * `Option<Valtype>` - The value, `Valtype::Int(0)` for an empty cell, or `None` if `addr`
+    ///   lies outside the sheet.
+    pub fn get_value(&self, addr: CellAddr) -> Option<Valtype> {
+        let key = self.key(addr)?;
+        Some(
+            self.cells
+                .get(&key)
+                .map_or(Valtype::Int(0), |cell| cell.value.clone()),
+        )
+    }
+
+    /// Returns the formula text of a cell, as it would be entered to re-create the cell.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The formula, empty for an empty cell, or `None` if `addr` lies
+    ///   outside the sheet.
+    pub fn get_formula(&self, addr: CellAddr) -> Option<String> {
+        let key = self.key(addr)?;
+        Some(
+            self.cells
+                .get(&key)
+                .map_or_else(String::new, formula_string),
+        )
+    }
+
+    /// Lists the cells holding a value or formula, with their formulas, in row-major order.
+    ///
+    /// # Returns
+    /// * `Vec<(CellAddr, String)>` - Each non-empty cell and its formula, as `get_formula`
+    ///   returns it.
+    pub fn formulas(&self) -> Vec<(CellAddr, String)> {
+        let mut cells: Vec<(CellAddr, String)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.data != CellData::Empty)
+            .map(|(&key, cell)| {
+                let key = key as usize;
+                let addr = CellAddr::new(key / self.total_cols, key % self.total_cols);
+                (addr, formula_string(cell))
+            })
+            .collect();
+        cells.sort_unstable_by_key(|(addr, _)| (addr.row, addr.col));
+        cells
+    }
+
+    /// Checks the sheet for suspicious formula patterns. See `lint::lint`.
+    ///
+    /// # Returns
+    /// * `Vec<LintIssue>` - The issues found, most severe first.
+    pub fn lint(&self, options: &LintOptions) -> Vec<LintIssue> {
+        lint(
+            &self.cells,
+            &self.ranged,
+            &self.is_range,
+            (self.total_rows, self.total_cols),
+            options,
+        )
+    }
+
+    /// Sums up the sheet: its cells by kind of formula, its error cells, its longest chain of
+    /// formulas and its estimated memory use. See `info::sheet_info`.
+    ///
+    /// # Returns
+    /// * `SheetInfo` - The statistics of the sheet.
+    pub fn info(&self) -> SheetInfo {
+        sheet_info(
+            &self.cells,
+            &self.ranged,
+            &self.is_range,
+            (self.total_rows, self.total_cols),
+        )
+    }
+
+    /// Re-evaluates every formula, in dependency order.
+    ///
+    /// Values are normally kept up to date by `set_formula`; this recomputes them all, e.g. to
+    /// repeat the delays of `SLEEP` formulas or draw new `RAND` values.
+    pub fn recalculate(&mut self) {
+        let total_dims = (self.total_rows, self.total_cols);
+        self.range_cache.clear();
+        self.eval
+            .scope(|| recalc_all(&mut self.cells, &self.ranged, total_dims));
+    }
+}
@@ -0,0 +1,285 @@
+//! # Format Module
+//! This module describes how a cell is displayed: bold or italic text, its alignment, and how
+//! its number is written. Formats only change how values are shown and exported, never the
+//! values themselves, so formulas reading a formatted cell see the plain number. It also
+//! describes how CSV files are written for a locale, such as German files separated by
+//! semicolons with a decimal comma.
+use serde::{Deserialize, Serialize};
+
+use crate::utils::parse_range;
+use crate::{EvalStatus, Valtype};
+
+/// The most decimal places a number can be shown with.
+pub const MAX_DECIMALS: u8 = 10;
+
+/// Where the text of a cell is placed horizontally.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Align {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+/// The display format of a cell. The default format shows the value as it is, centered.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::format::*;
+/// let format = CellFormat { thousands: true, decimals: 2, ..CellFormat::default() };
+/// assert_eq!(format.display(&Valtype::Int(-1234567)), "-1,234,567.00");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CellFormat {
+    /// Whether the text is drawn bold.
+    pub bold: bool,
+    /// Whether the text is drawn in italics.
+    pub italic: bool,
+    /// Where the text is placed in the cell.
+    pub align: Align,
+    /// Whether groups of three digits are separated by commas.
+    pub thousands: bool,
+    /// The number of decimal places shown, up to `MAX_DECIMALS`.
+    pub decimals: u8,
+}
+
+/// The top-left and bottom-right (row, col) of a block of cells.
+pub type Corners = ((usize, usize), (usize, usize));
+
+/// One change made by the `format` command, such as `bold` or `decimals=2`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatChange {
+    Bold(bool),
+    Italic(bool),
+    Align(Align),
+    Thousands(bool),
+    Decimals(u8),
+    /// Restores the default format.
+    Clear,
+}
+
+impl CellFormat {
+    /// Applies a change to the format.
+    pub fn apply(&mut self, change: FormatChange) {
+        match change {
+            FormatChange::Bold(on) => self.bold = on,
+            FormatChange::Italic(on) => self.italic = on,
+            FormatChange::Align(align) => self.align = align,
+            FormatChange::Thousands(on) => self.thousands = on,
+            FormatChange::Decimals(places) => self.decimals = places.min(MAX_DECIMALS),
+            FormatChange::Clear => *self = CellFormat::default(),
+        }
+    }
+
+    /// Writes a value the way this format shows it. Text and error values, such as `#DIV/0!`,
+    /// are shown as they are.
+    ///
+    /// # Arguments
+    /// * `value` - The value of the cell.
+    ///
+    /// # Returns
+    /// * `String` - The text to display or export.
+    pub fn display(&self, value: &Valtype) -> String {
+        let number = match value {
+            Valtype::Int(number) => *number,
+            Valtype::Str(text) => return text.to_string(),
+            Valtype::Err(error) => return error.to_string(),
+        };
+        let digits = number.unsigned_abs().to_string();
+        let mut text = String::with_capacity(digits.len() + 4 + self.decimals as usize);
+        if number < 0 {
+            text.push('-');
+        }
+        for (i, digit) in digits.chars().enumerate() {
+            if self.thousands && i > 0 && (digits.len() - i) % 3 == 0 {
+                text.push(',');
+            }
+            text.push(digit);
+        }
+        if self.decimals > 0 {
+            text.push('.');
+            text.extend(std::iter::repeat_n('0', self.decimals as usize));
+        }
+        text
+    }
+}
+
+/// Parses one option of the `format` command.
+fn parse_change(option: &str) -> Option<FormatChange> {
+    let option = option.to_ascii_lowercase();
+    Some(match option.as_str() {
+        "bold" => FormatChange::Bold(true),
+        "nobold" => FormatChange::Bold(false),
+        "italic" => FormatChange::Italic(true),
+        "noitalic" => FormatChange::Italic(false),
+        "left" => FormatChange::Align(Align::Left),
+        "center" => FormatChange::Align(Align::Center),
+        "right" => FormatChange::Align(Align::Right),
+        "thousands" => FormatChange::Thousands(true),
+        "nothousands" => FormatChange::Thousands(false),
+        "clear" => FormatChange::Clear,
+        _ => {
+            let places = option.strip_prefix("decimals=")?.parse().ok()?;
+            if places > MAX_DECIMALS {
+                return None;
+            }
+            FormatChange::Decimals(places)
+        }
+    })
+}
+
+/// Parses the arguments of `format <range> <option>...`.
+///
+/// The options are `bold`, `italic`, `left`, `center`, `right`, `thousands`, `decimals=N`,
+/// `nobold`, `noitalic`, `nothousands` and `clear`, applied in order.
+///
+/// # Arguments
+/// * `args` - The text following `format`, e.g. "A1:B5 bold right decimals=2".
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<(Corners, Vec<FormatChange>), EvalStatus>` - The corners of the range (a single
+///   cell is a range of one) and the changes, or `EvalStatus::InvalidRange` if the range is
+///   malformed or outside the sheet, or `EvalStatus::UnrecognizedCmd` if an option is unknown
+///   or none is given.
+pub fn parse_format_args(
+    args: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<(Corners, Vec<FormatChange>), EvalStatus> {
+    let mut parts = args.split_whitespace();
+    let range = parts
+        .next()
+        .ok_or(EvalStatus::UnrecognizedCmd)?
+        .to_ascii_uppercase();
+    let changes = parts
+        .map(parse_change)
+        .collect::<Option<Vec<_>>>()
+        .filter(|changes| !changes.is_empty())
+        .ok_or(EvalStatus::UnrecognizedCmd)?;
+    let range = if range.contains(':') {
+        range
+    } else {
+        format!("{}:{}", range, range)
+    };
+    let corners = parse_range(&range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    Ok((corners, changes))
+}
+
+/// How numbers and CSV files are written in a country, chosen with `--locale`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Locale {
+    /// Commas between fields and thousands, and a decimal point: `1,234.50`.
+    #[default]
+    En,
+    /// Semicolons between fields, dots between thousands, and a decimal comma: `1.234,50`.
+    De,
+}
+
+impl Locale {
+    /// Returns the locale named by a code such as "de", ignoring case.
+    pub fn from_name(name: &str) -> Option<Locale> {
+        match name.to_ascii_lowercase().as_str() {
+            "en" => Some(Locale::En),
+            "de" => Some(Locale::De),
+            _ => None,
+        }
+    }
+
+    /// Returns the byte separating the fields of a CSV record.
+    pub fn delimiter(self) -> u8 {
+        match self {
+            Locale::En => b',',
+            Locale::De => b';',
+        }
+    }
+
+    /// Writes a number shown by `CellFormat::display` the way this locale writes it.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::format::*;
+    /// assert_eq!(Locale::De.localize("-1,234,567.50"), "-1.234.567,50");
+    /// ```
+    pub fn localize(self, number: &str) -> String {
+        match self {
+            Locale::En => number.to_string(),
+            Locale::De => number
+                .chars()
+                .map(|ch| match ch {
+                    ',' => '.',
+                    '.' => ',',
+                    _ => ch,
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads a number written by `localize` back into a plain integer, so that an export with
+    /// thousands or decimal places can be loaded again.
+    ///
+    /// # Returns
+    /// * `Option<i32>` - The number, or `None` if the text is not a number written this way,
+    ///   its thousands are not grouped by three, or its decimal places are not all zero.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::format::*;
+    /// assert_eq!(Locale::De.read_number("-1.234,00"), Some(-1234));
+    /// assert_eq!(Locale::De.read_number("1.5"), None);
+    /// ```
+    pub fn read_number(self, text: &str) -> Option<i32> {
+        let (thousands, decimal) = match self {
+            Locale::En => (',', '.'),
+            Locale::De => ('.', ','),
+        };
+        let (whole, fraction) = text.split_once(decimal).unwrap_or((text, ""));
+        if !fraction.bytes().all(|b| b == b'0') || text.ends_with(decimal) {
+            return None;
+        }
+        let digits = whole.strip_prefix('-').unwrap_or(whole);
+        let mut groups = digits.split(thousands);
+        let first = groups.next()?;
+        let grouped = groups.all(|group| group.len() == 3);
+        let plain: String = digits.chars().filter(|&ch| ch != thousands).collect();
+        if first.is_empty()
+            || first.len() > 3 && digits.contains(thousands)
+            || !grouped
+            || !plain.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let number: i32 = plain.parse().ok()?;
+        Some(if whole.starts_with('-') {
+            -number
+        } else {
+            number
+        })
+    }
+}
+
+/// Splits the arguments of a CSV command, `<file> [--locale <code>]`.
+///
+/// # Returns
+/// * `Option<(&str, Locale)>` - The file name and the locale, `Locale::En` if none is given,
+///   or `None` if the locale is unknown or the file name is missing.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::format::*;
+/// assert_eq!(parse_locale_args("data.csv --locale de"), Some(("data.csv", Locale::De)));
+/// assert_eq!(parse_locale_args("data.csv"), Some(("data.csv", Locale::En)));
+/// ```
+pub fn parse_locale_args(args: &str) -> Option<(&str, Locale)> {
+    let (file, locale) = match args.split_once("--locale") {
+        Some((file, code)) => (file, Locale::from_name(code.trim())?),
+        None => (args, Locale::En),
+    };
+    let file = file.trim();
+    (!file.is_empty()).then_some((file, locale))
+}
@@ -0,0 +1,134 @@
+//! # Framing Module
+//! This module reads framed payloads from the command stream, so a dataset can be streamed
+//! into the sheet on stdin before any commands are issued.
+//!
+//! A frame starts with a `#begin csv` header line and ends with a `#end` line. The header may
+//! also carry the payload length in bytes (`#begin csv 1234`), in which case exactly that many
+//! bytes are read before the closing `#end`, so the payload may contain any bytes, including
+//! lines that read `#end`.
+use std::io::{self, BufRead, Read};
+
+/// Largest payload accepted in a single frame, in bytes.
+pub const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+/// Checks whether an input line opens a frame.
+///
+/// # Arguments
+/// * `line` - The input line, with or without its line terminator.
+///
+/// # Returns
+/// * `bool` - `true` if the line starts with `#begin`.
+pub fn is_frame_header(line: &str) -> bool {
+    line.split_whitespace().next() == Some("#begin")
+}
+
+/// Reads the payload of a frame whose header line has already been consumed.
+///
+/// Oversized frames are drained up to their `#end` line before the error is returned, so the
+/// next command is read from a clean line boundary.
+///
+/// # Arguments
+/// * `reader` - The stream positioned just after the header line.
+/// * `header` - The header line (e.g., "#begin csv" or "#begin csv 1234").
+///
+/// # Returns
+/// * `io::Result<Vec<u8>>` - The payload bytes.
+///
+/// # Errors
+/// * `InvalidInput` if the header names a format other than `csv` or has a bad length.
+/// * `InvalidData` if the payload exceeds [`MAX_FRAME_BYTES`] or the `#end` line is missing
+///   after a length-prefixed payload.
+/// * `UnexpectedEof` if the stream ends before the frame is complete.
+pub fn read_frame<R: BufRead>(reader: &mut R, header: &str) -> io::Result<Vec<u8>> {
+    let mut words = header.split_whitespace().skip(1);
+    if words.next() != Some("csv") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unsupported frame format",
+        ));
+    }
+    let length = match words.next() {
+        Some(n) => Some(
+            n.parse::<usize>()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid frame length"))?,
+        ),
+        None => None,
+    };
+    if words.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unexpected frame header argument",
+        ));
+    }
+
+    match length {
+        Some(n) if n > MAX_FRAME_BYTES => {
+            let skipped = io::copy(&mut reader.take(n as u64), &mut io::sink())?;
+            if skipped < n as u64 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            expect_end(reader)?;
+            Err(too_large())
+        }
+        Some(n) => {
+            let mut payload = vec![0u8; n];
+            reader.read_exact(&mut payload)?;
+            expect_end(reader)?;
+            Ok(payload)
+        }
+        None => {
+            let mut payload = Vec::new();
+            let mut oversized = false;
+            loop {
+                let mut line = Vec::new();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                if line.trim_ascii() == b"#end" {
+                    break;
+                }
+                if payload.len() + line.len() > MAX_FRAME_BYTES {
+                    oversized = true;
+                    payload.clear();
+                } else if !oversized {
+                    payload.extend_from_slice(&line);
+                }
+            }
+            if oversized {
+                Err(too_large())
+            } else {
+                Ok(payload)
+            }
+        }
+    }
+}
+
+/// Consumes the `#end` line that closes a length-prefixed frame.
+///
+/// Blank lines are skipped, since a payload that ends without a newline leaves the rest of
+/// its last line in front of the terminator.
+fn expect_end<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match line.trim_ascii() {
+            b"" => continue,
+            b"#end" => return Ok(()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "missing #end after frame payload",
+                ));
+            }
+        }
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame payload exceeds {} bytes", MAX_FRAME_BYTES),
+    )
+}
@@ -0,0 +1,121 @@
+//! # Graph Module
+//! This module writes the dependency graph of a sheet in Graphviz DOT, so that a complex sheet
+//! can be drawn with `dot -Tsvg` and an unexpected chain of recalculations traced by eye. Each
+//! cell holding a formula or referred to by one is a node labelled with its name and formula,
+//! each direct reference is an edge from the cell read to the cell reading it, and each range
+//! read by a range formula is a box node with an edge to that formula.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::storage::SheetStorage;
+use crate::utils::{formula_string, to_cell_name};
+use crate::{CellData, CellKey};
+
+/// Returns the name of the cell at a key.
+fn key_name(key: CellKey, total_cols: usize) -> String {
+    let key = key as usize;
+    to_cell_name(key / total_cols, key % total_cols)
+}
+
+/// Escapes text for the inside of a double-quoted DOT string.
+fn escaped(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Quotes text as a DOT string.
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", escaped(text))
+}
+
+/// Writes the dependency graph of a sheet in Graphviz DOT.
+///
+/// Nodes and edges are listed in row-major order, so the same sheet always gives the same file.
+/// Cells within a range are not linked to its formula one by one; the range is a single node.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking the ranges read by each range formula.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `String` - The DOT source of a `digraph`, ending with a newline.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::graph::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "A1+1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 2, "SUM(A1:B1)")?;
+/// // A1 = 5, B1 = A1+1, C1 = SUM(A1:B1)
+/// let dot = dependency_dot(&sheet, &ranged, 3);
+/// assert!(dot.contains("\"A1\" -> \"B1\";"));
+/// assert!(dot.contains("\"A1:B1\" -> \"C1\";"));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn dependency_dot<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_cols: usize,
+) -> String {
+    let mut nodes: BTreeMap<CellKey, String> = BTreeMap::new();
+    let mut edges: BTreeSet<(CellKey, CellKey)> = BTreeSet::new();
+    for (key, cell) in spreadsheet.cells() {
+        if cell.data != CellData::Empty {
+            nodes.insert(key, formula_string(cell));
+        }
+        for &dependent in &cell.dependents {
+            edges.insert((key, dependent));
+        }
+    }
+    for &(from, to) in &edges {
+        nodes.entry(from).or_default();
+        nodes.entry(to).or_default();
+    }
+    let ranges: BTreeSet<(CellKey, CellKey, CellKey)> = ranged
+        .iter()
+        .flat_map(|(&parent, ranges)| ranges.iter().map(move |&(start, end)| (start, end, parent)))
+        .collect();
+
+    let mut dot = String::from("digraph dependencies {\n    rankdir=LR;\n");
+    for (&key, formula) in &nodes {
+        let name = key_name(key, total_cols);
+        if formula.is_empty() {
+            let _ = writeln!(dot, "    {};", quoted(&name));
+        } else {
+            // The label shows the formula on a second line, through DOT's `\n` escape
+            let label = format!("{}\\n{}", escaped(&name), escaped(formula));
+            let _ = writeln!(dot, "    {} [label=\"{}\"];", quoted(&name), label);
+        }
+    }
+    let mut range_nodes = BTreeSet::new();
+    for &(start, end, _) in &ranges {
+        if range_nodes.insert((start, end)) {
+            let name = format!(
+                "{}:{}",
+                key_name(start, total_cols),
+                key_name(end, total_cols)
+            );
+            let _ = writeln!(dot, "    {} [shape=box];", quoted(&name));
+        }
+    }
+    for &(from, to) in &edges {
+        let (from, to) = (key_name(from, total_cols), key_name(to, total_cols));
+        let _ = writeln!(dot, "    {} -> {};", quoted(&from), quoted(&to));
+    }
+    for &(start, end, parent) in &ranges {
+        let name = format!(
+            "{}:{}",
+            key_name(start, total_cols),
+            key_name(end, total_cols)
+        );
+        let parent = key_name(parent, total_cols);
+        let _ = writeln!(dot, "    {} -> {};", quoted(&name), quoted(&parent));
+    }
+    dot.push_str("}\n");
+    dot
+}
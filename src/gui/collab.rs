@@ -0,0 +1,448 @@
+//! # Collab Module
+//! This module shares the open sheet of the GUI with the other clients of a server started
+//! with `--port` (built with the `server` feature). After `join <host:port> <name>`, a thread
+//! reads every formula of the served sheet, then polls for the edits of the other clients
+//! every `POLL_INTERVAL`, and sends the cell selected here and the cells typed in here. The
+//! grid outlines the cells the other clients have selected, with their names.
+//!
+//! The thread speaks plain HTTP/1.1 over one connection per request, so that no HTTP client
+//! library is needed, and stops when the link is dropped, as on `leave`.
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use eframe::egui;
+use serde_json::{Value, json};
+use spreadsheet::collab::{Change, Collab, PEER_TIMEOUT, Peer};
+
+use crate::gui::gui_defs::SpreadsheetApp;
+use crate::utils::{to_cell_name, to_indices};
+use crate::{CellKey, EvalStatus, parser};
+
+/// How often the server is asked for the edits of the other clients.
+pub(in crate::gui) const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a request may wait to connect, send or read before it fails.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The largest response read, in bytes, enough for every formula of a full sheet.
+const MAX_RESPONSE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// What the GUI asks the link thread to send.
+enum Outgoing {
+    /// The cell selected here, such as "B2".
+    Cursor(String),
+    /// A cell typed in here and the formula it now holds.
+    Edit(String, String),
+}
+
+/// What the link thread reports to the GUI.
+pub(in crate::gui) enum Incoming {
+    /// Every formula of the served sheet, as (cell, formula) pairs, which replace the cells
+    /// of the open sheet.
+    Sheet(Vec<(String, String)>),
+    /// The edits of the other clients since the last poll, oldest first.
+    Changes(Vec<Change>),
+    /// The cursors of the other clients.
+    Peers(Vec<Peer>),
+    /// A request failed, with the reason; the link keeps trying.
+    Failed(String),
+}
+
+/// The link of the open sheet to a server, created with `join`.
+///
+/// # Fields
+/// * `server` - The `host:port` of the server.
+/// * `client` - The name this client goes by.
+/// * `cursors` - The cursors of the other clients, with the (row, col) of their cells.
+/// * `sent_cursor` - The cell last sent as selected here.
+/// * `outbox` - Requests for the link thread; dropping it stops the thread.
+/// * `inbox` - Reports of the link thread.
+pub(in crate::gui) struct CollabLink {
+    pub(in crate::gui) server: String,
+    pub(in crate::gui) client: String,
+    pub(in crate::gui) cursors: Vec<((usize, usize), Peer)>,
+    sent_cursor: Option<(usize, usize)>,
+    outbox: Sender<Outgoing>,
+    inbox: Receiver<Incoming>,
+}
+
+impl CollabLink {
+    /// Starts the thread linking the open sheet to a server.
+    ///
+    /// # Arguments
+    /// * `server` - The `host:port` of the server, e.g. "127.0.0.1:8080".
+    /// * `client` - The name shown to the other clients.
+    pub(in crate::gui) fn join(server: &str, client: &str) -> Self {
+        let (outbox, requests) = mpsc::channel();
+        let (reports, inbox) = mpsc::channel();
+        let (address, name) = (server.to_string(), client.to_string());
+        thread::spawn(move || run_link(&address, &name, requests, reports));
+        CollabLink {
+            server: server.to_string(),
+            client: client.to_string(),
+            cursors: Vec::new(),
+            sent_cursor: None,
+            outbox,
+            inbox,
+        }
+    }
+
+    /// Sends the cell selected here, if it moved since it was last sent.
+    pub(in crate::gui) fn move_cursor(&mut self, cell: (usize, usize)) {
+        if self.sent_cursor != Some(cell) {
+            self.sent_cursor = Some(cell);
+            let _ = self
+                .outbox
+                .send(Outgoing::Cursor(to_cell_name(cell.0, cell.1)));
+        }
+    }
+
+    /// Sends a cell typed in here to the server, for the other clients to follow.
+    ///
+    /// # Arguments
+    /// * `cell` - The (row, col) of the cell.
+    /// * `formula` - The formula the cell now holds, empty if it was cleared.
+    pub(in crate::gui) fn send_edit(&self, cell: (usize, usize), formula: &str) {
+        let name = to_cell_name(cell.0, cell.1);
+        let _ = self.outbox.send(Outgoing::Edit(name, formula.to_string()));
+    }
+
+    /// Takes the reports of the link thread that arrived since the last call, keeping the
+    /// cursors of the other clients up to date.
+    pub(in crate::gui) fn receive(&mut self) -> Vec<Incoming> {
+        let reports: Vec<Incoming> = self.inbox.try_iter().collect();
+        for report in &reports {
+            if let Incoming::Peers(peers) = report {
+                self.cursors = peers
+                    .iter()
+                    .filter(|peer| peer.client != self.client)
+                    .filter_map(|peer| Some((to_indices(&peer.cell).ok()?, peer.clone())))
+                    .collect();
+            }
+        }
+        reports
+    }
+}
+
+/// Sends one request to the server and reads its JSON answer.
+///
+/// # Arguments
+/// * `server` - The `host:port` of the server.
+/// * `method` - The HTTP method, e.g. "GET".
+/// * `path` - The path of the URL.
+/// * `body` - The JSON body to send, if any.
+///
+/// # Returns
+/// * `io::Result<(u16, Value)>` - The status code and the JSON body of the answer, or the
+///   error that kept the request from being answered.
+fn request(
+    server: &str,
+    method: &str,
+    path: &str,
+    body: Option<Value>,
+) -> io::Result<(u16, Value)> {
+    let address = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("no address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        server,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream
+        .take(MAX_RESPONSE_BYTES)
+        .read_to_string(&mut response)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid response");
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let body = serde_json::from_str(body).map_err(|_| invalid())?;
+    Ok((status, body))
+}
+
+/// Describes a failed request for the status bar.
+fn failure(answer: io::Result<(u16, Value)>) -> String {
+    match answer {
+        Ok((status, body)) => match body["error"].as_str() {
+            Some(error) => format!("{} ({})", error, status),
+            None => format!("status {}", status),
+        },
+        Err(e) => e.to_string(),
+    }
+}
+
+/// The loop of the link thread: sends the requests of the GUI as they come, and polls the
+/// server every `POLL_INTERVAL`, until the GUI drops its end of the link.
+///
+/// # Arguments
+/// * `server` - The `host:port` of the server.
+/// * `client` - The name this client goes by.
+/// * `requests` - What the GUI asks to send.
+/// * `reports` - Where the answers of the server go.
+fn run_link(server: &str, client: &str, requests: Receiver<Outgoing>, reports: Sender<Incoming>) {
+    // No version until every formula was read
+    let mut version: Option<u64> = None;
+    let mut cursor: Option<String> = None;
+    // When the cursor was last sent, `None` once it moved
+    let mut cursor_sent: Option<Instant> = None;
+    let mut last_poll: Option<Instant> = None;
+    // Only the first of a run of failures is reported
+    let mut failing = false;
+    let mut report = |result: Result<Incoming, String>| -> bool {
+        let message = match result {
+            Ok(incoming) => {
+                failing = false;
+                incoming
+            }
+            Err(_) if failing => return true,
+            Err(message) => {
+                failing = true;
+                Incoming::Failed(message)
+            }
+        };
+        reports.send(message).is_ok()
+    };
+    loop {
+        let wait = last_poll.map_or(Duration::ZERO, |last| {
+            POLL_INTERVAL.saturating_sub(last.elapsed())
+        });
+        let sent = match requests.recv_timeout(wait) {
+            Ok(Outgoing::Cursor(cell)) => {
+                cursor = Some(cell);
+                cursor_sent = None;
+                None
+            }
+            Ok(Outgoing::Edit(cell, formula)) => {
+                let body = json!({ "formula": formula, "client": client });
+                Some(request(
+                    server,
+                    "PUT",
+                    &format!("/cells/{}", cell),
+                    Some(body),
+                ))
+            }
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Some(answer) = sent
+            && !matches!(answer, Ok((200, _)))
+            && !report(Err(failure(answer)))
+        {
+            break;
+        }
+        // The cursor is sent again before the server forgets it
+        if let Some(cell) = &cursor
+            && cursor_sent.is_none_or(|sent| sent.elapsed() >= PEER_TIMEOUT / 3)
+        {
+            cursor_sent = Some(Instant::now());
+            let body = json!({ "cell": cell });
+            let answer = request(server, "PUT", &format!("/cursors/{}", client), Some(body));
+            if !matches!(answer, Ok((200, _))) && !report(Err(failure(answer))) {
+                break;
+            }
+        }
+        if last_poll.is_some_and(|last| last.elapsed() < POLL_INTERVAL) {
+            continue;
+        }
+        last_poll = Some(Instant::now());
+        let polled = match version {
+            None => match request(server, "GET", "/formulas", None) {
+                Ok((200, body)) => {
+                    version = body["version"].as_u64();
+                    let cells = body["cells"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|cell| {
+                            let name = cell["cell"].as_str()?.to_string();
+                            Some((name, cell["formula"].as_str()?.to_string()))
+                        })
+                        .collect();
+                    Ok(vec![Incoming::Sheet(cells)])
+                }
+                answer => Err(failure(answer)),
+            },
+            Some(since) => match request(server, "GET", &format!("/changes/{}", since), None) {
+                Ok((200, body)) => {
+                    version = body["version"].as_u64();
+                    let changes = serde_json::from_value(body["changes"].clone());
+                    let peers = serde_json::from_value(body["cursors"].clone());
+                    match (changes, peers) {
+                        (Ok(changes), Ok(peers)) => {
+                            Ok(vec![Incoming::Changes(changes), Incoming::Peers(peers)])
+                        }
+                        _ => Err("invalid response".to_string()),
+                    }
+                }
+                // Too far behind: every formula is read again on the next poll
+                Ok((410, _)) => {
+                    version = None;
+                    last_poll = None;
+                    Ok(Vec::new())
+                }
+                answer => Err(failure(answer)),
+            },
+        };
+        let delivered = match polled {
+            Ok(incoming) => incoming.into_iter().all(|incoming| report(Ok(incoming))),
+            Err(message) => report(Err(message)),
+        };
+        if !delivered {
+            break;
+        }
+    }
+    // A client that leaves stops showing its cursor at once
+    let _ = request(server, "DELETE", &format!("/cursors/{}", client), None);
+}
+
+impl SpreadsheetApp {
+    /// Shares the open sheet through a server, as in `join 127.0.0.1:8080 ann`. The cells of
+    /// the sheet are replaced by those of the server once they are read.
+    ///
+    /// # Arguments
+    /// * `args` - The `host:port` of the server and the name to go by.
+    pub fn join_server(&mut self, args: &str) {
+        let [server, client] = args.split_whitespace().collect::<Vec<_>>()[..] else {
+            self.status_message = "Usage: join <host:port> <name>".to_string();
+            return;
+        };
+        if !Collab::valid_client(client) {
+            self.status_message = format!("Invalid name: {}", client);
+            return;
+        }
+        // A sheet shared through another server leaves it first
+        self.collab = Some(CollabLink::join(server, client));
+        self.status_message = format!("Joining {} as {}...", server, client);
+    }
+
+    /// Stops sharing the open sheet, keeping its cells as they are.
+    pub fn leave_server(&mut self) {
+        self.status_message = match self.collab.take() {
+            Some(link) => format!("Left {}", link.server),
+            None => "The sheet is not shared".to_string(),
+        };
+    }
+
+    /// Sends the selected cell of a shared sheet, and applies what its link received: the
+    /// cells of the server once joined, then the edits of the other clients. Called once per
+    /// frame, while no edit is being recalculated.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context, asked to draw again when the server is next polled.
+    pub(in crate::gui) fn sync_collab(&mut self, ctx: &egui::Context) {
+        let Some(link) = self.collab.as_mut() else {
+            return;
+        };
+        if let Some(cell) = self.selected {
+            link.move_cursor(cell);
+        }
+        let (client, server) = (link.client.clone(), link.server.clone());
+        for report in link.receive() {
+            match report {
+                Incoming::Sheet(cells) => self.replace_shared_cells(&server, &client, &cells),
+                Incoming::Changes(changes) => {
+                    for change in changes {
+                        if change.client.as_deref() != Some(client.as_str()) {
+                            self.apply_shared_edit(&change.cell, &change.formula);
+                        }
+                    }
+                }
+                Incoming::Peers(_) => {}
+                Incoming::Failed(message) => {
+                    self.status_message = format!("{}: {}", server, message);
+                }
+            }
+        }
+        ctx.request_repaint_after(POLL_INTERVAL);
+    }
+
+    /// Replaces the cells of the open sheet with those read from the server.
+    ///
+    /// # Arguments
+    /// * `server` - The `host:port` of the server, for the status message.
+    /// * `client` - The name this client goes by, for the status message.
+    /// * `cells` - Every non-empty cell of the served sheet, as (cell, formula) pairs.
+    fn replace_shared_cells(&mut self, server: &str, client: &str, cells: &[(String, String)]) {
+        let total_dims = (self.total_rows, self.total_cols);
+        let _ = parser::clear_cells(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            total_dims,
+            ((0, 0), (self.total_rows - 1, self.total_cols - 1)),
+        );
+        self.links.clear();
+        let skipped = cells
+            .iter()
+            .filter(|(cell, formula)| !self.apply_shared_edit(cell, formula))
+            .count();
+        // The cells of the server are not an edit made here
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.search_index = None;
+        self.validations.recheck(&self.sheet, self.total_cols);
+        self.status_message = match skipped {
+            0 => format!("Joined {} as {}: {} cells", server, client, cells.len()),
+            _ => format!(
+                "Joined {} as {}: {} cells, {} outside the sheet or rejected",
+                server,
+                client,
+                cells.len() - skipped,
+                skipped
+            ),
+        };
+    }
+
+    /// Writes a cell edited through the server into the open sheet, and recalculates the
+    /// cells computed from it.
+    ///
+    /// # Arguments
+    /// * `cell` - The edited cell, such as "A1".
+    /// * `formula` - The formula it now holds.
+    ///
+    /// # Returns
+    /// * `bool` - Whether the edit was written: `false` for a cell outside the open sheet or a
+    ///   formula it rejects.
+    fn apply_shared_edit(&mut self, cell: &str, formula: &str) -> bool {
+        let Ok((row, col)) = to_indices(cell) else {
+            return false;
+        };
+        if row >= self.total_rows || col >= self.total_cols {
+            return false;
+        }
+        let status = parser::assign_and_recalc(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+            row,
+            col,
+            formula,
+        )
+        .status;
+        self.names.record(row, col, None);
+        self.links.remove(&(row, col));
+        self.reindex_cell((row * self.total_cols + col) as CellKey);
+        !matches!(
+            status,
+            Err(EvalStatus::CycleDetected | EvalStatus::InvalidRange | EvalStatus::UnrecognizedCmd)
+        )
+    }
+}
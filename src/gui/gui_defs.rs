@@ -1,7 +1,36 @@
-use eframe::egui::{Color32, Stroke, Vec2};
+use eframe::egui::{self, Color32, Galley, Stroke, Vec2};
 
-use crate::Cell;
-use crate::HashMap;
+use crate::gui::collab::CollabLink;
+use crate::gui::keys::KeyMap;
+use crate::utils::HistogramBin;
+use crate::workbook::GridSizes;
+use crate::{Cell, CellKey, EvalStatus, Valtype};
+use spreadsheet::CellRef;
+use spreadsheet::format::CellFormat;
+use spreadsheet::info::SheetInfo;
+use spreadsheet::lint::LintIssue;
+use spreadsheet::lock::LockTable;
+use spreadsheet::names::NameTable;
+use spreadsheet::search::SearchIndex;
+use spreadsheet::structure::LineEdit;
+use spreadsheet::utils::{CancelToken, EvalContext};
+use spreadsheet::validation::ValidationTable;
+use spreadsheet::visits::VisitHistory;
+use spreadsheet::watch::WatchList;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the open sheet is written to the autosave file.
+pub(in crate::gui) const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a toast stays on screen.
+pub(in crate::gui) const TOAST_DURATION: Duration = Duration::from_secs(4);
+
+/// How long a cell edit waits for its recalculation before the window shows it as running,
+/// so that quick edits never leave the grid.
+pub(in crate::gui) const EVALUATION_WAIT: Duration = Duration::from_millis(100);
 
 /// Represents the direction of movement or scrolling in the spreadsheet interface.
 pub(in crate::gui) enum Direction {
@@ -11,6 +40,54 @@ pub(in crate::gui) enum Direction {
     Right,
 }
 
+/// Whether the GUI is drawn with light or dark colors, set with `theme light|dark|system`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(in crate::gui) enum ThemeMode {
+    Dark,
+    Light,
+    /// Light or dark as the operating system is, dark if it does not say.
+    System,
+}
+
+impl ThemeMode {
+    /// The name of the mode in commands and theme files.
+    pub(in crate::gui) fn name(self) -> &'static str {
+        match self {
+            ThemeMode::Dark => "dark",
+            ThemeMode::Light => "light",
+            ThemeMode::System => "system",
+        }
+    }
+
+    /// Looks up a mode by name.
+    ///
+    /// # Arguments
+    /// * `name` - One of "dark", "light" or "system".
+    ///
+    /// # Returns
+    /// The matching `ThemeMode`, or `None` for an unknown name.
+    pub(in crate::gui) fn from_name(name: &str) -> Option<Self> {
+        [ThemeMode::Dark, ThemeMode::Light, ThemeMode::System]
+            .into_iter()
+            .find(|mode| mode.name() == name)
+    }
+
+    /// Checks whether the GUI should be dark in this mode.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context, which knows the system theme.
+    ///
+    /// # Returns
+    /// * `bool` - `true` for dark colors.
+    pub(in crate::gui) fn is_dark(self, ctx: &egui::Context) -> bool {
+        match self {
+            ThemeMode::Dark => true,
+            ThemeMode::Light => false,
+            ThemeMode::System => ctx.system_theme() != Some(egui::Theme::Light),
+        }
+    }
+}
+
 /// Defines the styling configuration for the spreadsheet GUI.
 ///
 /// # Fields
@@ -31,6 +108,13 @@ pub(in crate::gui) enum Direction {
 /// * `get_cell_bg` - Optional function to dynamically determine cell background color.
 /// * `range_selection_bg` - Background color for range selection.
 /// * `range_selection_text` - Text color for range selection.
+/// * `reference_colors` - Palette used to color references while editing a formula.
+/// * `search_match` - Outline color of the cells matching the search query.
+/// * `invalid_cell` - Outline color of the cells marked as breaking a validation rule.
+/// * `error_text` - Text color of the cells holding an error such as `#DIV/0!`.
+/// * `dependent_cell` - Outline color of the cells computed directly from the selected cell.
+/// * `mode` - Whether the GUI is light, dark, or follows the system.
+/// * `dark` - Boolean indicating the colors above are meant for a dark GUI.
 pub struct SpreadsheetStyle {
     pub(in crate::gui) header_bg: Color32,
     pub(in crate::gui) header_text: Color32,
@@ -49,6 +133,13 @@ pub struct SpreadsheetStyle {
     pub(in crate::gui) get_cell_bg: Option<Box<dyn Fn(usize, usize) -> Color32>>, // Function to get cell background
     pub(in crate::gui) range_selection_bg: Color32,
     pub(in crate::gui) range_selection_text: Color32,
+    pub(in crate::gui) reference_colors: [Color32; 6],
+    pub(in crate::gui) search_match: Color32,
+    pub(in crate::gui) invalid_cell: Color32,
+    pub(in crate::gui) error_text: Color32,
+    pub(in crate::gui) dependent_cell: Color32,
+    pub(in crate::gui) mode: ThemeMode,
+    pub(in crate::gui) dark: bool,
 }
 
 impl Default for SpreadsheetStyle {
@@ -75,8 +166,67 @@ impl Default for SpreadsheetStyle {
             get_cell_bg: None,
             range_selection_bg: Color32::from_rgb(80, 160, 160), // Lighter blue
             range_selection_text: Color32::from_rgb(230, 230, 230),
+            reference_colors: [
+                Color32::from_rgb(66, 133, 244),
+                Color32::from_rgb(219, 68, 55),
+                Color32::from_rgb(155, 89, 182),
+                Color32::from_rgb(15, 157, 88),
+                Color32::from_rgb(244, 160, 0),
+                Color32::from_rgb(0, 172, 193),
+            ],
+            search_match: Color32::from_rgb(255, 200, 40),
+            invalid_cell: Color32::from_rgb(230, 60, 60),
+            error_text: Color32::from_rgb(255, 120, 120),
+            dependent_cell: Color32::from_rgb(240, 240, 240),
+            mode: ThemeMode::Dark,
+            dark: true,
+        }
+    }
+}
+
+impl SpreadsheetStyle {
+    /// Creates the light counterpart of the default style, with dark text on light cells.
+    ///
+    /// # Returns
+    /// A `SpreadsheetStyle` for `ThemeMode::Light`.
+    pub(in crate::gui) fn light() -> Self {
+        Self {
+            header_bg: Color32::from_rgb(214, 218, 240),
+            header_text: Color32::from_rgb(30, 30, 40),
+            cell_bg_even: Color32::from_rgb(246, 242, 252),
+            cell_bg_odd: Color32::WHITE,
+            cell_text: Color32::from_rgb(25, 25, 25),
+            selected_cell_bg: Color32::from_rgb(120, 120, 200),
+            selected_cell_text: Color32::WHITE,
+            grid_line: Stroke::new(1.0, Color32::from_rgb(200, 200, 200)),
+            prev_base_color: Color32::from_rgb(246, 242, 252),
+            range_selection_bg: Color32::from_rgb(170, 220, 220),
+            range_selection_text: Color32::from_rgb(20, 20, 20),
+            search_match: Color32::from_rgb(230, 150, 0),
+            invalid_cell: Color32::from_rgb(210, 30, 30),
+            error_text: Color32::from_rgb(190, 20, 20),
+            dependent_cell: Color32::from_rgb(40, 40, 40),
+            mode: ThemeMode::Light,
+            dark: false,
+            ..Self::default()
         }
     }
+
+    /// Switches to the light or dark colors, keeping the sizes, the animation and the mode.
+    ///
+    /// # Arguments
+    /// * `dark` - Whether to switch to the dark colors.
+    pub(in crate::gui) fn set_dark(&mut self, dark: bool) {
+        let colors = if dark { Self::default() } else { Self::light() };
+        *self = Self {
+            cell_size: self.cell_size,
+            font_size: self.font_size,
+            rainbow: self.rainbow,
+            frequency: self.frequency,
+            mode: self.mode,
+            ..colors
+        };
+    }
 }
 
 /// Represents the state and configuration of the spreadsheet application in GUI mode.
@@ -98,19 +248,81 @@ impl Default for SpreadsheetStyle {
 /// * `should_reset_scroll` - Boolean to trigger scroll reset.
 /// * `focus_on` - Index for focusing on a specific element.
 /// * `request_formula_focus` - Boolean to request focus on formula input.
-/// * `clipboard` - Optional cell data for clipboard.
-/// * `clipboard_formula` - Formula string in the clipboard.
-/// * `undo_stack` - Stack of undo actions.
-/// * `redo_stack` - Stack of redo actions.
+/// * `request_cell_focus` - Boolean to request focus on the editor inside the selected cell.
+/// * `clipboard` - Optional block of copied cells, indexed by row then column.
+/// * `clipboard_formulas` - Formula strings of the copied cells, in the same layout.
+/// * `clipboard_cell_formats` - Display formats of the copied cells, in the same layout.
+/// * `clipboard_origin` - Position of the top-left copied cell, used to shift references on paste.
+/// * `cut_source` - Optional corners of a cut block, whose references follow it when pasted.
+/// * `clipboard_text` - The copied cells as TSV, the way they were put on the system clipboard.
+/// * `clipboard_sync` - Boolean set when `clipboard_text` is still to be put on the system
+///   clipboard.
+/// * `undo_stack` - Stack of undo groups, each undone as one step.
+/// * `redo_stack` - Stack of redo groups, each redone as one step.
+/// * `open_undo_group` - Optional group collecting the cell changes of a multi-cell operation.
 /// * `max_undo_levels` - Maximum number of undo levels.
 /// * `show_save_dialog` - Boolean to show the save dialog.
 /// * `save_filename` - Filename for saving the spreadsheet.
 /// * `range_start` - Optional starting point of a range selection.
 /// * `range_end` - Optional ending point of a range selection.
 /// * `is_selecting_range` - Boolean indicating range selection mode.
+/// * `formula_bar_focused` - Boolean indicating the formula bar had focus this frame.
+/// * `histogram_chart` - Optional histogram shown in the chart panel.
+/// * `lint_report` - Optional issues from the last `lint` command, shown in the lint panel.
+/// * `sheet_info` - Optional statistics of the open sheet from the last `sheetinfo` command,
+///   shown in the "About this sheet" panel.
+/// * `names` - Named ranges defined with `name range`, usable in range functions.
+/// * `clamp_ranges` - Boolean to clamp ranges running past the edge of the sheet instead of
+///   rejecting them.
+/// * `show_search` - Boolean to show the search panel.
+/// * `search_query` - Text typed into the search panel.
+/// * `replace_with` - Text typed into the replace field of the search panel.
+/// * `search_current` - Position of the selected match among the current matches.
+/// * `search_index` - Optional index of cell contents, built when the search panel is first used
+///   and kept up to date on every edit after that.
+/// * `window_rows` - Maximum number of rows laid out in the scrollable grid window.
+/// * `window_cols` - Maximum number of columns laid out in the scrollable grid window.
+/// * `visible_rows` - Number of rows that fully fit in the viewport, measured each frame.
+/// * `visible_cols` - Number of columns that fully fit in the viewport, measured each frame.
+/// * `pending_scroll` - Optional scroll offset to apply after the grid window was paged.
+/// * `sheets` - Every sheet of the workbook, in tab order. The entry of the open sheet only
+///   keeps its name, as its contents live in the fields above while it is open.
+/// * `active_sheet` - Index of the open sheet in `sheets`.
+/// * `links` - Cells of the open sheet that show a cell of another sheet, by (row, col).
+/// * `grid_sizes` - Column widths and row heights of the open sheet that differ from
+///   `style.cell_size`, set by dragging the header edges.
+/// * `frozen` - Tuple of the number of rows and columns of the open sheet pinned to the top
+///   and left of the grid while scrolling, set with `freeze <cell>`.
+/// * `formats` - Display formats of the cells of the open sheet, by (row, col); cells without
+///   an entry use the default format.
+/// * `validations` - Validation rules of the open sheet and the cells marked as breaking them.
+/// * `fill_drag` - Optional cell the fill handle of the selected cell is being dragged over.
+/// * `split` - Optional second view of the open sheet, shown beside or below the grid.
+/// * `last_autosave` - When the workbook was last written to the autosave file.
+/// * `history` - Formulas and commands entered in the formula bar, oldest first.
+/// * `history_pos` - Optional index in `history` of the entry shown in the formula bar.
+/// * `suggestions` - Completions offered for the word at the end of the formula bar text.
+/// * `suggestion_pos` - Index in `suggestions` of the highlighted completion.
+/// * `suggestions_for` - The formula bar text `suggestions` were found for.
+/// * `watches` - Cells of the open sheet watched with `watch <cell>`.
+/// * `visits` - Cells of the open sheet jumped to, retraced with `back` and `forward`.
+/// * `locks` - Cells of the open sheet locked with `lock <range>`.
+/// * `charts` - Charts of ranges of the open sheet, opened with `chart bar|line <range>`.
+/// * `toasts` - Messages shown in the corner of the window, with the time each one appeared.
+/// * `text_cache` - Laid-out text of the cells drawn in the grid, reused while unchanged.
+/// * `keys` - Keyboard shortcuts of the copy, paste, cut, save, undo, redo, back and forward
+///   actions.
+/// * `theme_changed` - Boolean set when the theme changed since it was last written to the
+///   user's theme file.
+/// * `evaluation` - Optional cell edit still being recalculated on a worker thread, which
+///   holds the sheet until it ends or Esc cancels it.
+/// * `eval` - The context the open sheet is evaluated with, installed at the start of every
+///   frame and on the worker thread of `evaluation`.
+/// * `collab` - Optional link of the open sheet to a server it is shared through, made with
+///   `join <host:port> <name>`.
 pub struct SpreadsheetApp {
-    pub(in crate::gui) sheet: HashMap<u32, Cell>,
-    pub(in crate::gui) ranged: HashMap<u32, Vec<(u32, u32)>>,
+    pub(in crate::gui) sheet: HashMap<CellKey, Cell>,
+    pub(in crate::gui) ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>>,
     pub(in crate::gui) is_range: Vec<bool>,
     pub(in crate::gui) total_rows: usize,
     pub(in crate::gui) total_cols: usize,
@@ -125,16 +337,65 @@ pub struct SpreadsheetApp {
     pub(in crate::gui) should_reset_scroll: bool,
     pub(in crate::gui) focus_on: usize,
     pub(in crate::gui) request_formula_focus: bool,
-    pub(in crate::gui) clipboard: Option<Cell>,
-    pub(in crate::gui) clipboard_formula: String,
-    pub(in crate::gui) undo_stack: Vec<UndoAction>,
-    pub(in crate::gui) redo_stack: Vec<UndoAction>,
+    pub(in crate::gui) request_cell_focus: bool,
+    pub(in crate::gui) clipboard: Option<Vec<Vec<Cell>>>,
+    pub(in crate::gui) clipboard_formulas: Vec<Vec<String>>,
+    pub(in crate::gui) clipboard_cell_formats: Vec<Vec<CellFormat>>,
+    pub(in crate::gui) clipboard_origin: (usize, usize),
+    pub(in crate::gui) cut_source: Option<((usize, usize), (usize, usize))>,
+    pub(in crate::gui) clipboard_text: String,
+    pub(in crate::gui) clipboard_sync: bool,
+    pub(in crate::gui) undo_stack: Vec<UndoGroup>,
+    pub(in crate::gui) redo_stack: Vec<UndoGroup>,
+    pub(in crate::gui) open_undo_group: Option<UndoGroup>,
     pub(in crate::gui) max_undo_levels: usize,
     pub(in crate::gui) show_save_dialog: bool,
     pub(in crate::gui) save_filename: String,
     pub(in crate::gui) range_start: Option<(usize, usize)>,
     pub(in crate::gui) range_end: Option<(usize, usize)>,
     pub(in crate::gui) is_selecting_range: bool,
+    pub(in crate::gui) formula_bar_focused: bool,
+    pub(in crate::gui) histogram_chart: Option<HistogramChart>,
+    pub(in crate::gui) lint_report: Option<Vec<LintIssue>>,
+    pub(in crate::gui) sheet_info: Option<SheetInfo>,
+    pub(in crate::gui) names: NameTable,
+    pub(in crate::gui) clamp_ranges: bool,
+    pub(in crate::gui) show_search: bool,
+    pub(in crate::gui) search_query: String,
+    pub(in crate::gui) replace_with: String,
+    pub(in crate::gui) search_current: usize,
+    pub(in crate::gui) search_index: Option<SearchIndex>,
+    pub(in crate::gui) window_rows: usize,
+    pub(in crate::gui) window_cols: usize,
+    pub(in crate::gui) visible_rows: usize,
+    pub(in crate::gui) visible_cols: usize,
+    pub(in crate::gui) pending_scroll: Option<Vec2>,
+    pub(in crate::gui) sheets: Vec<SheetState>,
+    pub(in crate::gui) active_sheet: usize,
+    pub(in crate::gui) links: HashMap<(usize, usize), SheetLink>,
+    pub(in crate::gui) grid_sizes: GridSizes,
+    pub(in crate::gui) frozen: (usize, usize),
+    pub(in crate::gui) formats: HashMap<(usize, usize), CellFormat>,
+    pub(in crate::gui) validations: ValidationTable,
+    pub(in crate::gui) fill_drag: Option<(usize, usize)>,
+    pub(in crate::gui) split: Option<SplitView>,
+    pub(in crate::gui) last_autosave: Instant,
+    pub(in crate::gui) history: Vec<String>,
+    pub(in crate::gui) history_pos: Option<usize>,
+    pub(in crate::gui) suggestions: Vec<String>,
+    pub(in crate::gui) suggestion_pos: usize,
+    pub(in crate::gui) suggestions_for: String,
+    pub(in crate::gui) watches: WatchList,
+    pub(in crate::gui) visits: VisitHistory,
+    pub(in crate::gui) locks: LockTable,
+    pub(in crate::gui) charts: Vec<RangeChart>,
+    pub(in crate::gui) toasts: Vec<(String, Instant)>,
+    pub(in crate::gui) text_cache: CellTextCache,
+    pub(in crate::gui) keys: KeyMap,
+    pub(in crate::gui) theme_changed: bool,
+    pub(in crate::gui) evaluation: Option<Evaluation>,
+    pub(in crate::gui) eval: EvalContext,
+    pub(in crate::gui) collab: Option<CollabLink>,
 }
 
 impl SpreadsheetApp {
@@ -149,8 +410,8 @@ impl SpreadsheetApp {
     /// # Returns
     /// A `SpreadsheetApp` instance initialized with default values.
     pub fn new(rows: usize, cols: usize, start_row: usize, start_col: usize) -> Self {
-        let sheet: HashMap<u32, Cell> = HashMap::with_capacity(1024);
-        let ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(512);
+        let sheet: HashMap<CellKey, Cell> = HashMap::with_capacity(1024);
+        let ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(512);
         let is_range: Vec<bool> = vec![false; rows * cols];
         let total_rows = rows;
         let total_cols = cols;
@@ -171,20 +432,232 @@ impl SpreadsheetApp {
             should_reset_scroll: false,
             focus_on: 0,
             request_formula_focus: false,
+            request_cell_focus: false,
             clipboard: None,
-            clipboard_formula: String::new(),
+            clipboard_formulas: Vec::new(),
+            clipboard_cell_formats: Vec::new(),
+            clipboard_origin: (0, 0),
+            cut_source: None,
+            clipboard_text: String::new(),
+            clipboard_sync: false,
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            open_undo_group: None,
             max_undo_levels: 100,
             show_save_dialog: false,
             save_filename: String::new(),
             range_start: None,
             range_end: None,
             is_selecting_range: false,
+            formula_bar_focused: false,
+            histogram_chart: None,
+            lint_report: None,
+            sheet_info: None,
+            names: NameTable::default(),
+            clamp_ranges: false,
+            show_search: false,
+            search_query: String::new(),
+            replace_with: String::new(),
+            search_current: 0,
+            search_index: None,
+            window_rows: 500,
+            window_cols: 300,
+            visible_rows: 31,
+            visible_cols: 15,
+            pending_scroll: None,
+            sheets: vec![SheetState::new("Sheet1", 0, 0)],
+            active_sheet: 0,
+            links: HashMap::new(),
+            grid_sizes: GridSizes::default(),
+            frozen: (0, 0),
+            formats: HashMap::new(),
+            validations: ValidationTable::default(),
+            fill_drag: None,
+            split: None,
+            last_autosave: Instant::now(),
+            history: Vec::new(),
+            history_pos: None,
+            suggestions: Vec::new(),
+            suggestion_pos: 0,
+            suggestions_for: String::new(),
+            watches: WatchList::default(),
+            visits: VisitHistory::default(),
+            locks: LockTable::default(),
+            charts: Vec::new(),
+            toasts: Vec::new(),
+            text_cache: CellTextCache::default(),
+            keys: KeyMap::default(),
+            theme_changed: false,
+            evaluation: None,
+            eval: EvalContext::default(),
+            collab: None,
         }
     }
 }
 
+/// The contents and view of one sheet of the workbook, swapped with the fields of
+/// `SpreadsheetApp` when its tab is opened or closed.
+///
+/// # Fields
+/// * `name` - The tab name, used in cross-sheet references such as `Sheet2!A1`.
+/// * `sheet` - Hash map storing cell data.
+/// * `ranged` - Hash map tracking range dependencies.
+/// * `is_range` - Boolean vector indicating range membership.
+/// * `total_rows` - Total number of rows.
+/// * `total_cols` - Total number of columns.
+/// * `selected` - Optional tuple of the selected cell (row, col).
+/// * `start_row` - Starting row index for the visible area.
+/// * `start_col` - Starting column index for the visible area.
+/// * `undo_stack` - Stack of undo groups of this sheet.
+/// * `redo_stack` - Stack of redo groups of this sheet.
+/// * `names` - Named ranges of this sheet.
+/// * `search_index` - Optional index of cell contents of this sheet.
+/// * `links` - Cells that show a cell of another sheet, by (row, col).
+/// * `grid_sizes` - Column widths and row heights that differ from the default.
+/// * `frozen` - Tuple of the number of rows and columns pinned while scrolling.
+/// * `formats` - Display formats of the cells, by (row, col).
+/// * `validations` - Validation rules of this sheet.
+/// * `watches` - Watched cells of this sheet.
+/// * `visits` - Cells of this sheet jumped to.
+/// * `locks` - Locked cells of this sheet.
+/// * `charts` - Charts of ranges of this sheet.
+/// * `eval` - The context this sheet is evaluated with, holding its `RAND` seed.
+/// * `collab` - Optional link of this sheet to a server it is shared through.
+pub(in crate::gui) struct SheetState {
+    pub(in crate::gui) name: String,
+    pub(in crate::gui) sheet: HashMap<CellKey, Cell>,
+    pub(in crate::gui) ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    pub(in crate::gui) is_range: Vec<bool>,
+    pub(in crate::gui) total_rows: usize,
+    pub(in crate::gui) total_cols: usize,
+    pub(in crate::gui) selected: Option<(usize, usize)>,
+    pub(in crate::gui) start_row: usize,
+    pub(in crate::gui) start_col: usize,
+    pub(in crate::gui) undo_stack: Vec<UndoGroup>,
+    pub(in crate::gui) redo_stack: Vec<UndoGroup>,
+    pub(in crate::gui) names: NameTable,
+    pub(in crate::gui) search_index: Option<SearchIndex>,
+    pub(in crate::gui) links: HashMap<(usize, usize), SheetLink>,
+    pub(in crate::gui) grid_sizes: GridSizes,
+    pub(in crate::gui) frozen: (usize, usize),
+    pub(in crate::gui) formats: HashMap<(usize, usize), CellFormat>,
+    pub(in crate::gui) validations: ValidationTable,
+    pub(in crate::gui) watches: WatchList,
+    pub(in crate::gui) visits: VisitHistory,
+    pub(in crate::gui) locks: LockTable,
+    pub(in crate::gui) charts: Vec<RangeChart>,
+    pub(in crate::gui) eval: EvalContext,
+    pub(in crate::gui) collab: Option<CollabLink>,
+}
+
+impl SheetState {
+    /// Creates an empty sheet.
+    ///
+    /// # Arguments
+    /// * `name` - The tab name.
+    /// * `rows` - The total number of rows.
+    /// * `cols` - The total number of columns.
+    pub(in crate::gui) fn new(name: &str, rows: usize, cols: usize) -> Self {
+        SheetState {
+            name: name.to_string(),
+            sheet: HashMap::new(),
+            ranged: HashMap::new(),
+            is_range: vec![false; rows * cols],
+            total_rows: rows,
+            total_cols: cols,
+            selected: Some((0, 0)),
+            start_row: 0,
+            start_col: 0,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            names: NameTable::default(),
+            search_index: None,
+            links: HashMap::new(),
+            grid_sizes: GridSizes::default(),
+            frozen: (0, 0),
+            formats: HashMap::new(),
+            validations: ValidationTable::default(),
+            watches: WatchList::default(),
+            visits: VisitHistory::default(),
+            locks: LockTable::default(),
+            charts: Vec::new(),
+            eval: EvalContext::default(),
+            collab: None,
+        }
+    }
+}
+
+/// The second view of a split grid, with its own scroll position over the same cells. Its
+/// fields are swapped with those of `SpreadsheetApp` while it is drawn.
+///
+/// # Fields
+/// * `horizontal` - Boolean indicating the views are stacked rather than side by side.
+/// * `start_row` - Starting row index for the visible area.
+/// * `start_col` - Starting column index for the visible area.
+/// * `should_reset_scroll` - Boolean to trigger scroll reset.
+/// * `pending_scroll` - Optional scroll offset to apply after the grid window was paged.
+/// * `visible_rows` - Number of rows that fully fit in the view, measured each frame.
+/// * `visible_cols` - Number of columns that fully fit in the view, measured each frame.
+pub(in crate::gui) struct SplitView {
+    pub(in crate::gui) horizontal: bool,
+    pub(in crate::gui) start_row: usize,
+    pub(in crate::gui) start_col: usize,
+    pub(in crate::gui) should_reset_scroll: bool,
+    pub(in crate::gui) pending_scroll: Option<Vec2>,
+    pub(in crate::gui) visible_rows: usize,
+    pub(in crate::gui) visible_cols: usize,
+}
+
+/// The text of a cell as laid out for the grid, and what it was laid out from.
+///
+/// # Fields
+/// * `value` - The value of the cell.
+/// * `format` - The display format of the cell.
+/// * `galley` - The laid-out text, without a color so that it can be drawn in any.
+/// * `frame` - The last frame the cell was drawn in.
+pub(in crate::gui) struct CachedText {
+    pub(in crate::gui) value: Valtype,
+    pub(in crate::gui) format: CellFormat,
+    pub(in crate::gui) galley: Arc<Galley>,
+    pub(in crate::gui) frame: u64,
+}
+
+/// Laid-out text of the cells drawn in the grid, so that each frame only formats and lays out
+/// the cells whose value or format changed, or that scrolled into view.
+///
+/// An entry is reused only while the cell still holds the value and format it was laid out
+/// from, which also covers edits made outside the engine, such as undo or opening a file.
+///
+/// # Fields
+/// * `cells` - The text of each cell drawn in the current or previous frame, by (row, col).
+/// * `font_size` - The font size the texts were laid out in.
+/// * `pixels_per_point` - The display scale the texts were laid out for.
+/// * `frame` - The number of the current frame.
+#[derive(Default)]
+pub(in crate::gui) struct CellTextCache {
+    pub(in crate::gui) cells: HashMap<(usize, usize), CachedText>,
+    pub(in crate::gui) font_size: f32,
+    pub(in crate::gui) pixels_per_point: f32,
+    pub(in crate::gui) frame: u64,
+}
+
+/// A cell that shows the value of a cell on another sheet, entered as e.g. `Sheet2!A1`.
+///
+/// The cell holds the value as a constant, which is rewritten whenever the source changes.
+/// Text and error values are copied as they are, so `Sheet2!A1` shows `#DIV/0!` if A1 does.
+///
+/// # Fields
+/// * `sheet` - Index of the source sheet in `SpreadsheetApp::sheets`.
+/// * `cell` - Tuple of (row, col) of the source cell.
+/// * `value` - The value last written into the cell. If the cell holds anything else, it was
+///   overwritten and the link is dropped.
+#[derive(Clone)]
+pub(in crate::gui) struct SheetLink {
+    pub(in crate::gui) sheet: usize,
+    pub(in crate::gui) cell: (usize, usize),
+    pub(in crate::gui) value: Valtype,
+}
+
 /// Represents an action to undo or redo in the spreadsheet.
 ///
 /// # Fields
@@ -196,3 +669,221 @@ pub(in crate::gui) struct UndoAction {
     pub(in crate::gui) old_cell: Cell,
     pub(in crate::gui) old_formula: String,
 }
+
+/// The cell changes made by one user operation, undone and redone together.
+///
+/// # Fields
+/// * `actions` - The previous state of each changed cell, in the order the cells were changed.
+#[derive(Default)]
+pub(in crate::gui) struct UndoGroup {
+    pub(in crate::gui) actions: Vec<UndoAction>,
+}
+
+/// A cell edit whose formula is written to the sheet, waiting for the outcome of its
+/// recalculation to be recorded.
+///
+/// # Fields
+/// * `position` - Tuple of (row, col) of the edited cell.
+/// * `before` - The formula of the cell before the edit.
+/// * `formula` - The formula written to the cell, with names, links and clamped ranges
+///   written out.
+/// * `named` - Optional formula and name of the named range the formula was written with.
+/// * `link` - Optional cell of another sheet that the edited cell shows.
+/// * `clamped` - Optional formula cut down to fit the sheet, with the range it was cut to.
+pub(in crate::gui) struct CellEdit {
+    pub(in crate::gui) position: (usize, usize),
+    pub(in crate::gui) before: String,
+    pub(in crate::gui) formula: String,
+    pub(in crate::gui) named: Option<(String, String)>,
+    pub(in crate::gui) link: Option<SheetLink>,
+    pub(in crate::gui) clamped: Option<(String, CellRef, CellRef)>,
+}
+
+/// What the worker thread of an `Evaluation` gives back: the sheet, its ranges and range
+/// flags, its validation rules, and the status of the edit.
+pub(in crate::gui) type Evaluated = (
+    HashMap<CellKey, Cell>,
+    HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    Vec<bool>,
+    ValidationTable,
+    Result<(), EvalStatus>,
+);
+
+/// A cell edit being recalculated on a worker thread, so that the window keeps responding and
+/// Esc can cancel a long recalculation such as `SLEEP(30)`.
+///
+/// # Fields
+/// * `edit` - The edit, recorded once the recalculation ends.
+/// * `cancel` - The token installed on the worker thread, cancelled by Esc.
+/// * `started` - When the recalculation started.
+/// * `handle` - The worker thread.
+pub(in crate::gui) struct Evaluation {
+    pub(in crate::gui) edit: CellEdit,
+    pub(in crate::gui) cancel: CancelToken,
+    pub(in crate::gui) started: Instant,
+    pub(in crate::gui) handle: JoinHandle<Evaluated>,
+}
+
+/// A cell or range reference found in the formula being edited.
+///
+/// # Fields
+/// * `span` - Byte range of the reference inside the formula text.
+/// * `top_left` - Tuple of (row, col) for the top-left corner of the reference.
+/// * `bottom_right` - Tuple of (row, col) for the bottom-right corner of the reference.
+/// * `color_slot` - Index into `SpreadsheetStyle::reference_colors`.
+pub(in crate::gui) struct FormulaReference {
+    pub(in crate::gui) span: std::ops::Range<usize>,
+    pub(in crate::gui) top_left: (usize, usize),
+    pub(in crate::gui) bottom_right: (usize, usize),
+    pub(in crate::gui) color_slot: usize,
+}
+
+/// A frequency table shown as a bar chart by the `histogram ... chart` command.
+///
+/// # Fields
+/// * `title` - The source range the table was computed from.
+/// * `bins` - The bins of the frequency table.
+pub(in crate::gui) struct HistogramChart {
+    pub(in crate::gui) title: String,
+    pub(in crate::gui) bins: Vec<HistogramBin>,
+}
+
+/// How a chart opened with `chart` draws the values of its range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(in crate::gui) enum ChartKind {
+    /// One bar per cell, rising or falling from zero.
+    Bar,
+    /// One point per cell, joined in order.
+    Line,
+}
+
+/// An action picked from the context menu of a row or column header.
+///
+/// Each action names the row or column it acts on as `(is_row, index)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(in crate::gui) enum HeaderAction {
+    /// Inserts or deletes a row or column.
+    Edit(LineEdit),
+    /// Selects every cell of the row or column as a range.
+    Select(bool, usize),
+    /// Hides the row or column by shrinking it to nothing.
+    Hide(bool, usize),
+    /// Gives the row or column the default size again.
+    ResetSize(bool, usize),
+    /// Shows every hidden row, or every hidden column.
+    UnhideAll(bool),
+}
+
+/// A chart of the values of a range, opened with `chart bar|line <range>`.
+///
+/// The chart keeps only the range, and its values are read from the sheet each time it is
+/// drawn, so it follows the cells as they are edited or recalculated.
+///
+/// # Fields
+/// * `kind` - Whether the values are drawn as bars or as a line.
+/// * `corners` - The top-left and bottom-right (row, col) corners of the range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(in crate::gui) struct RangeChart {
+    pub(in crate::gui) kind: ChartKind,
+    pub(in crate::gui) corners: ((usize, usize), (usize, usize)),
+}
+
+/// The positions of consecutive rows or columns of the grid window, which may each have their
+/// own size. Positions are measured from the start of the first, plus any lead.
+///
+/// # Examples
+/// ```
+/// let widths = BTreeMap::from([(1, 100.0)]);
+/// let cols = GridAxis::new(0, 4, &widths, 60.0);
+/// assert_eq!((cols.start(2), cols.size(1), cols.total()), (160.0, 100.0, 280.0));
+/// assert_eq!(cols.index_at(150.0), 1);
+/// ```
+pub(in crate::gui) struct GridAxis {
+    first: usize,
+    default: f32,
+    /// The start of each row or column from the start of the first, followed by the end of
+    /// the last.
+    offsets: Vec<f32>,
+}
+
+impl GridAxis {
+    /// The smallest size a row or column can be dragged to.
+    pub(in crate::gui) const MIN_SIZE: f32 = 12.0;
+    /// The largest size a row or column can be dragged to.
+    pub(in crate::gui) const MAX_SIZE: f32 = 800.0;
+
+    /// Lays out the rows or columns `first..end`.
+    ///
+    /// # Arguments
+    /// * `first` - The index of the first row or column.
+    /// * `end` - The index just past the last row or column.
+    /// * `sizes` - The sizes that differ from `default`, by index.
+    /// * `default` - The size of every other row or column.
+    pub(in crate::gui) fn new(
+        first: usize,
+        end: usize,
+        sizes: &BTreeMap<usize, f32>,
+        default: f32,
+    ) -> Self {
+        let mut offsets = Vec::with_capacity(end.saturating_sub(first) + 1);
+        let mut offset = 0.0;
+        offsets.push(offset);
+        for index in first..end {
+            offset += sizes.get(&index).copied().unwrap_or(default);
+            offsets.push(offset);
+        }
+        GridAxis {
+            first,
+            default,
+            offsets,
+        }
+    }
+
+    /// Shifts every row or column by the same amount, leaving room before the first.
+    ///
+    /// # Arguments
+    /// * `lead` - The space to leave before the first row or column.
+    pub(in crate::gui) fn with_lead(mut self, lead: f32) -> Self {
+        for offset in &mut self.offsets {
+            *offset += lead;
+        }
+        self
+    }
+
+    /// Returns the index just past the last row or column.
+    pub(in crate::gui) fn end(&self) -> usize {
+        self.first + self.offsets.len() - 1
+    }
+
+    /// Returns the total size of the rows or columns.
+    pub(in crate::gui) fn total(&self) -> f32 {
+        self.offsets[self.offsets.len() - 1]
+    }
+
+    /// Returns where a row or column starts, from the start of the first. Indices outside
+    /// the window are placed as if the rows or columns between had the default size.
+    pub(in crate::gui) fn start(&self, index: usize) -> f32 {
+        if index < self.first {
+            self.offsets[0] - (self.first - index) as f32 * self.default
+        } else if index > self.end() {
+            self.total() + (index - self.end()) as f32 * self.default
+        } else {
+            self.offsets[index - self.first]
+        }
+    }
+
+    /// Returns the size of a row or column.
+    pub(in crate::gui) fn size(&self, index: usize) -> f32 {
+        self.start(index + 1) - self.start(index)
+    }
+
+    /// Returns the row or column of the window covering a position, measured from the start of
+    /// the first; positions outside the window give the first or the last.
+    pub(in crate::gui) fn index_at(&self, position: f32) -> usize {
+        let after = self.offsets.partition_point(|&offset| offset <= position);
+        self.first
+            + after
+                .saturating_sub(1)
+                .min(self.offsets.len().saturating_sub(2))
+    }
+}
@@ -0,0 +1,125 @@
+//! # Heatmap Module
+//! Renders the numeric values of a range as a color-mapped PNG image, one square block of
+//! pixels per cell, for quick visual checks of large numeric grids.
+use std::fs::File;
+use std::io::BufWriter;
+
+/// The color gradients a heatmap can be drawn with, from the minimum to the maximum value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(in crate::gui) enum HeatmapPalette {
+    Viridis,
+    Heat,
+    Gray,
+}
+
+impl HeatmapPalette {
+    /// Looks up a palette by its command-line name.
+    ///
+    /// # Arguments
+    /// * `name` - One of "viridis", "heat" or "gray" (case-insensitive).
+    ///
+    /// # Returns
+    /// The matching `HeatmapPalette`, or `None` for an unknown name.
+    pub(in crate::gui) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "viridis" => Some(Self::Viridis),
+            "heat" => Some(Self::Heat),
+            "gray" | "grey" => Some(Self::Gray),
+            _ => None,
+        }
+    }
+
+    /// The evenly spaced color stops of the gradient.
+    fn stops(self) -> &'static [[u8; 3]] {
+        match self {
+            Self::Viridis => &[
+                [68, 1, 84],
+                [59, 82, 139],
+                [33, 145, 140],
+                [94, 201, 98],
+                [253, 231, 37],
+            ],
+            Self::Heat => &[[0, 0, 0], [180, 0, 0], [255, 140, 0], [255, 255, 160]],
+            Self::Gray => &[[0, 0, 0], [255, 255, 255]],
+        }
+    }
+
+    /// Interpolates the gradient at a position between 0.0 (minimum) and 1.0 (maximum).
+    ///
+    /// # Arguments
+    /// * `t` - The position along the gradient; clamped to `0.0..=1.0`.
+    ///
+    /// # Returns
+    /// The RGB color at that position.
+    pub(in crate::gui) fn color_at(self, t: f32) -> [u8; 3] {
+        let stops = self.stops();
+        let scaled = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let i = (scaled as usize).min(stops.len() - 2);
+        let frac = scaled - i as f32;
+        let mut rgb = [0u8; 3];
+        for (channel, out) in rgb.iter_mut().enumerate() {
+            let (a, b) = (stops[i][channel] as f32, stops[i + 1][channel] as f32);
+            *out = (a + (b - a) * frac).round() as u8;
+        }
+        rgb
+    }
+}
+
+/// Color used for cells that hold an error instead of a number.
+const ERROR_COLOR: [u8; 3] = [96, 96, 96];
+
+/// Largest edge of the image in pixels that cell blocks are scaled up to.
+const TARGET_EDGE: usize = 512;
+
+/// Writes a heatmap of a grid of values to a PNG file.
+///
+/// # Arguments
+/// * `path` - The file to create.
+/// * `values` - The grid in row-major order; `None` marks a cell holding an error.
+/// * `rows` - The number of rows in the grid.
+/// * `cols` - The number of columns in the grid.
+/// * `palette` - The gradient mapping the minimum to maximum value.
+///
+/// # Returns
+/// * `Result<(usize, usize), String>` - The image width and height in pixels, or a message
+///   describing why the file could not be written.
+pub(in crate::gui) fn write_heatmap_png(
+    path: &str,
+    values: &[Option<i32>],
+    rows: usize,
+    cols: usize,
+    palette: HeatmapPalette,
+) -> Result<(usize, usize), String> {
+    let block = (TARGET_EDGE / rows.max(cols)).clamp(1, 32);
+    let (width, height) = (cols * block, rows * block);
+    let min = values.iter().flatten().min().copied().unwrap_or(0) as f32;
+    let max = values.iter().flatten().max().copied().unwrap_or(0) as f32;
+    let span = (max - min).max(1.0);
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for row in 0..rows {
+        let line: Vec<u8> = values[row * cols..(row + 1) * cols]
+            .iter()
+            .flat_map(|value| {
+                let rgb = match value {
+                    Some(v) => palette.color_at((*v as f32 - min) / span),
+                    None => ERROR_COLOR,
+                };
+                std::iter::repeat_n(rgb, block).flatten()
+            })
+            .collect();
+        for _ in 0..block {
+            pixels.extend_from_slice(&line);
+        }
+    }
+
+    let file = File::create(path).map_err(|e| format!("File error: {}", e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| format!("PNG write error: {}", e))?;
+    Ok((width, height))
+}
@@ -1,12 +1,42 @@
+use std::collections::HashSet;
 use std::fs::File;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use csv::Writer;
+use csv::{ReaderBuilder, WriterBuilder};
+use spreadsheet::format::{CellFormat, FormatChange, Locale, parse_format_args};
+use spreadsheet::graph::dependency_dot;
+use spreadsheet::info::sheet_info;
+use spreadsheet::lint::{lint, parse_lint_args};
+use spreadsheet::names::{define_name, parse_name_args};
+use spreadsheet::search::{SearchIndex, replace_formulas, replacements};
+use spreadsheet::sort::{parse_sort_args, sort_formulas, sort_range};
+use spreadsheet::structure::{LineEdit, edit_lines};
+use spreadsheet::utils::CancelToken;
+use spreadsheet::validation::{parse_validate_args, validated_update};
 
-use crate::gui::gui_defs::UndoAction;
+use crate::gui::gui_defs::{
+    AUTOSAVE_INTERVAL, CellEdit, ChartKind, EVALUATION_WAIT, Evaluation, HeaderAction,
+    HistogramChart, RangeChart, ThemeMode, UndoAction, UndoGroup,
+};
+use crate::gui::heatmap::{HeatmapPalette, write_heatmap_png};
+use crate::gui::keys::KeyMap;
+use crate::gui::theme::{load_theme, save_theme, theme_names};
+use crate::submission::package_sheet;
+use crate::workbook::{self, FormattedCell, load_workbook, save_workbook};
 use crate::{
-    Cell, CellData, HashSet, STATUS, STATUS_CODE, Valtype, gui::gui_defs::SpreadsheetApp,
-    gui::utils_gui::cell_data_to_formula_string, gui::utils_gui::col_label,
-    gui::utils_gui::valtype_to_string, parser,
+    Cell, CellAddr, CellData, CellKey, EvalStatus, Valtype,
+    gui::gui_defs::SpreadsheetApp,
+    gui::utils_gui::clipboard_table,
+    gui::utils_gui::col_label,
+    gui::utils_gui::parse_cell_name,
+    gui::utils_gui::valtype_to_string,
+    parser,
+    utils::{
+        autototal_formulas, format_cell_path, formula_string, get_range_values, histogram_formulas,
+        move_cell_data, parse_histogram_args, parse_range, parse_resize_args, resize_sheet,
+        shift_cell_data, to_cell_name, to_indices, transitive_dependents, transitive_precedents,
+    },
 };
 
 impl SpreadsheetApp {
@@ -17,172 +47,1365 @@ impl SpreadsheetApp {
     /// * `col` - The column index of the cell.
     ///
     /// # Returns
-    /// A `String` representing the cell's formula or value.
+    /// A `String` representing the cell's formula or value, or the reference such as
+    /// `Sheet2!A1` for a cell linked to another sheet.
     pub fn get_cell_formula(&self, row: usize, col: usize) -> String {
-        let key = (row * self.total_cols + col) as u32;
-        if let Some(cell) = self.sheet.get(&key) {
-            match &cell.data {
-                CellData::Empty => String::new(),
-
-                CellData::Const => {
-                    if let Valtype::Int(val) = cell.value {
-                        val.to_string()
-                    } else {
-                        String::new()
-                    }
-                }
+        if let Some(formula) = self.link_formula(row, col) {
+            return formula;
+        }
+        let key = (row * self.total_cols + col) as CellKey;
+        self.sheet
+            .get(&key)
+            .map_or_else(String::new, formula_string)
+    }
 
-                CellData::Ref { cell1 } => cell1.as_str().to_string(),
+    /// Updates the value of the currently selected cell with the formula input.
+    ///
+    /// This method saves the previous state for undo and recalculates dependencies.
+    pub fn update_selected_cell(&mut self) {
+        let Some((edit, old_cell)) = self.begin_cell_edit() else {
+            return;
+        };
+        let status = validated_update(
+            &mut self.validations,
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+            edit.position,
+            old_cell,
+        )
+        .status;
+        self.finish_cell_edit(edit, status);
+    }
 
-                CellData::CoC { op_code, value2 } => {
-                    if let Valtype::Int(val1) = &cell.value {
-                        if let Valtype::Int(val2) = value2 {
-                            format!("{}{}{}", val1, op_code, val2)
-                        } else {
-                            String::new()
-                        }
-                    } else {
-                        String::new()
-                    }
-                }
+    /// Updates the selected cell as `update_selected_cell` does, recalculating on a worker
+    /// thread so that Esc can cancel a long recalculation.
+    ///
+    /// The sheet moves to the worker until the recalculation ends. Edits that take less than
+    /// `EVALUATION_WAIT` are finished at once; longer ones are finished by
+    /// `poll_evaluation` on a later frame.
+    pub fn start_cell_update(&mut self) {
+        let Some((edit, old_cell)) = self.begin_cell_edit() else {
+            return;
+        };
+        let cancel = CancelToken::default();
+        let worker = cancel.clone();
+        let context = self.eval.clone();
+        let mut sheet = std::mem::take(&mut self.sheet);
+        let mut ranged = std::mem::take(&mut self.ranged);
+        let mut is_range = std::mem::take(&mut self.is_range);
+        let mut validations = std::mem::take(&mut self.validations);
+        let total_dims = (self.total_rows, self.total_cols);
+        let position = edit.position;
+        let handle = thread::spawn(move || {
+            worker.install();
+            context.install();
+            let status = validated_update(
+                &mut validations,
+                &mut sheet,
+                &mut ranged,
+                &mut is_range,
+                total_dims,
+                position,
+                old_cell,
+            )
+            .status;
+            (sheet, ranged, is_range, validations, status)
+        });
+        let started = Instant::now();
+        while !handle.is_finished() && started.elapsed() < EVALUATION_WAIT {
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.evaluation = Some(Evaluation {
+            edit,
+            cancel,
+            started,
+            handle,
+        });
+        self.poll_evaluation(false);
+    }
 
-                CellData::CoR {
-                    op_code,
-                    value2,
-                    cell2,
-                } => {
-                    if let Valtype::Int(val1) = value2 {
-                        format!("{}{}{}", val1, op_code, cell2.as_str())
-                    } else {
-                        String::new()
-                    }
-                }
+    /// Finishes the cell edit running on a worker thread once its recalculation has ended.
+    ///
+    /// # Arguments
+    /// * `cancel` - Whether to ask the recalculation to stop, e.g. as Esc was pressed.
+    ///
+    /// # Returns
+    /// * `bool` - `true` while the recalculation is still running.
+    pub(in crate::gui) fn poll_evaluation(&mut self, cancel: bool) -> bool {
+        let Some(evaluation) = &self.evaluation else {
+            return false;
+        };
+        if cancel {
+            evaluation.cancel.cancel();
+        }
+        if !evaluation.handle.is_finished() {
+            return true;
+        }
+        let Evaluation { edit, handle, .. } = self.evaluation.take().unwrap();
+        let (sheet, ranged, is_range, validations, status) = handle
+            .join()
+            .expect("the recalculation thread does not panic");
+        self.sheet = sheet;
+        self.ranged = ranged;
+        self.is_range = is_range;
+        self.validations = validations;
+        self.finish_cell_edit(edit, status);
+        false
+    }
 
-                CellData::RoC {
-                    op_code,
-                    value2,
-                    cell1,
-                } => {
-                    if let Valtype::Int(val2) = value2 {
-                        format!("{}{}{}", cell1.as_str(), op_code, val2)
-                    } else {
-                        String::new()
-                    }
-                }
+    /// Writes the formula input to the selected cell, once it is known the cell may change.
+    ///
+    /// Checks the lock of the cell, saves its previous state for undo, and writes out sheet
+    /// links, range functions with the selected range, named ranges and clamped ranges.
+    ///
+    /// # Returns
+    /// * `Option<(CellEdit, Cell)>` - The edit, to be recalculated from the cell before it, or
+    ///   `None` if nothing was written; the status message then says why.
+    fn begin_cell_edit(&mut self) -> Option<(CellEdit, Cell)> {
+        let total_rows = self.total_rows;
+        let total_cols = self.total_cols;
+        let (r, c) = self.selected?;
+        if let Err(status) = self.locks.check([(r, c)]) {
+            self.status_message = format!(
+                "{}: {}{}",
+                EvalStatus::message(Err(status)),
+                col_label(c),
+                r + 1
+            );
+            return None;
+        }
+        // A reference to another sheet, such as Sheet2!A1, is written as the value it shows
+        let formula = self.formula_input.clone();
+        let link = match self.resolve_sheet_link(&formula, r, c) {
+            Some(Ok(link)) => Some(link),
+            Some(Err(message)) => {
+                self.status_message = message;
+                return None;
+            }
+            None => None,
+        };
 
-                CellData::RoR {
-                    op_code,
-                    cell1,
-                    cell2,
-                } => {
-                    format!("{}{}{}", cell1, op_code, cell2)
-                }
+        // Save the current state for undo before making changes
+        self.push_undo_action(r, c);
+        let idx = (r as CellKey) * (total_cols as CellKey) + (c as CellKey);
+        let old_cell = self.sheet.get(&idx).cloned().unwrap_or(Cell {
+            value: Valtype::Int(0),
+            data: CellData::Empty,
+            dependents: HashSet::new(),
+        });
+        let mut new_cell = old_cell.clone();
 
-                CellData::Range {
-                    cell1,
-                    cell2,
-                    value2,
-                } => {
-                    if let Valtype::Str(func) = value2 {
-                        format!("{}({}:{})", func.as_str(), cell1.as_str(), cell2.as_str())
-                    } else {
-                        String::new()
-                    }
-                }
+        // Check if the formula is a range function with empty parentheses
+        let trimmed_input = self.formula_input.trim().to_uppercase();
+        const RANGE_FUNCTIONS: [&str; 7] = ["MAX", "MIN", "AVG", "STDEV", "SUM", "COUNT", "SPARK"];
+        if RANGE_FUNCTIONS
+            .iter()
+            .any(|&func| trimmed_input == format!("{}()", func))
+        {
+            if let (Some(start), Some(end)) = (self.range_start, self.range_end) {
+                // Calculate the range string using min and max to handle any selection order
+                let min_row = start.0.min(end.0);
+                let max_row = start.0.max(end.0);
+                let min_col = start.1.min(end.1);
+                let max_col = start.1.max(end.1);
+                let range_str = format!(
+                    "{}{}:{}{}",
+                    col_label(min_col),
+                    min_row + 1,
+                    col_label(max_col),
+                    max_row + 1
+                );
+                // Modify the formula to include the range
+                let func_name = trimmed_input.trim_end_matches("()");
+                self.formula_input = format!("{}({})", func_name, range_str);
+            } else {
+                // No range selected, set error message and skip update
+                self.status_message = "No range selected for function".to_string();
+                return None;
+            }
+        }
 
-                CellData::SleepC => {
-                    if let Valtype::Int(val) = cell.value {
-                        format!("SLEEP({})", val)
-                    } else {
-                        String::new()
-                    }
+        // Write out a named range, such as SUM(taxes), as the range it labels
+        let named = self.names.resolve(&self.formula_input);
+        if let Some((formula, _)) = &named {
+            self.formula_input = formula.clone();
+        }
+
+        // Cut a range running past the edge of the sheet down to fit, if enabled
+        let clamped = if self.clamp_ranges {
+            parser::clamp_range_formula(&self.formula_input, total_rows, total_cols)
+        } else {
+            None
+        };
+        if let Some((formula, _, _)) = &clamped {
+            self.formula_input = formula.clone();
+        }
+
+        // Parse the formula (modified or original) and update the cell; a link's value may be
+        // text or an error, which no formula writes, so it is set as it is
+        let before = formula_string(&old_cell);
+        match &link {
+            Some(link) => {
+                new_cell.reset();
+                new_cell.data = CellData::Const;
+                new_cell.value = link.value.clone();
+            }
+            None => parser::detect_formula(&mut new_cell, &self.formula_input),
+        }
+        self.sheet.insert(idx, new_cell);
+        let edit = CellEdit {
+            position: (r, c),
+            before,
+            formula: self.formula_input.clone(),
+            named,
+            link,
+            clamped,
+        };
+        Some((edit, old_cell))
+    }
+
+    /// Records the outcome of a recalculated cell edit: its name and sheet link if it was
+    /// kept, and the status message.
+    ///
+    /// # Arguments
+    /// * `edit` - The edit, as written by `begin_cell_edit`.
+    /// * `status` - The status of its recalculation and validation.
+    fn finish_cell_edit(&mut self, edit: CellEdit, status: Result<(), EvalStatus>) {
+        let (total_rows, total_cols) = (self.total_rows, self.total_cols);
+        let (r, c) = edit.position;
+        let idx = (r as CellKey) * (total_cols as CellKey) + (c as CellKey);
+        self.reindex_cell(idx);
+        // A `mark` validation rule keeps the edit, and only marks the cells breaking it
+        let kept = status == Err(EvalStatus::ValidationFailed)
+            && self
+                .sheet
+                .get(&idx)
+                .map_or_else(String::new, formula_string)
+                != edit.before;
+        if status.is_ok() || kept {
+            let name = edit.named.as_ref().map(|(_, name)| name.as_str());
+            self.names.record(r, c, name);
+            self.links.remove(&(r, c));
+            if let Some(link) = edit.link {
+                self.links.insert((r, c), link);
+            }
+            // The other clients of a shared sheet follow the cells typed in here
+            if let Some(collab) = &self.collab {
+                let formula = self
+                    .sheet
+                    .get(&idx)
+                    .map_or_else(String::new, formula_string);
+                collab.send_edit((r, c), &formula);
+            }
+        }
+        self.status_message = match (status, edit.clamped) {
+            (Ok(()), Some((_, from, to))) => format!(
+                "Updated cell {}{} ({}: {} to {})",
+                col_label(c),
+                r + 1,
+                EvalStatus::message(Err(EvalStatus::RangeClamped)),
+                from,
+                to
+            ),
+            (Ok(()), None) => format!("Updated cell {}{}", col_label(c), r + 1),
+            (Err(EvalStatus::CycleDetected), _) => {
+                let path = parser::cycle_path(
+                    &self.sheet,
+                    &self.ranged,
+                    (total_rows, total_cols),
+                    r,
+                    c,
+                    &edit.formula,
+                );
+                match path {
+                    Some(path) => format!(
+                        "{}: {}",
+                        EvalStatus::message(status),
+                        format_cell_path(&path, total_cols)
+                    ),
+                    None => EvalStatus::message(status).to_string(),
+                }
+            }
+            (Err(EvalStatus::Cancelled), _) => format!(
+                "{}: {}{} is unchanged",
+                EvalStatus::message(status),
+                col_label(c),
+                r + 1
+            ),
+            (Err(error), _) => {
+                let detail =
+                    parser::status_detail(error, (total_rows, total_cols), r, c, &edit.formula);
+                match detail {
+                    Some(detail) => format!("{}: {}", EvalStatus::message(status), detail),
+                    None => EvalStatus::message(status).to_string(),
                 }
+            }
+        };
+    }
 
-                CellData::SleepR { cell1 } => {
-                    format!("SLEEP({})", cell1)
+    /// Appends aggregate totals below and to the right of a range.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments: a range and an optional aggregate (defaults to SUM).
+    pub fn autototal(&mut self, args: &str) {
+        let mut parts = args.split_whitespace();
+        let range = parts.next().unwrap_or("");
+        let func = parts.next().unwrap_or("sum");
+        match autototal_formulas(range, func, self.total_rows, self.total_cols) {
+            Ok(formulas) if parts.next().is_none() => {
+                let previous = self.selected;
+                self.begin_undo_group();
+                for (row, col, formula) in formulas {
+                    self.selected = Some((row, col));
+                    self.formula_input = formula;
+                    self.update_selected_cell();
                 }
+                self.end_undo_group();
+                self.selected = previous;
+                self.formula_input.clear();
+                self.status_message = format!("Added {} totals for {}", func.to_uppercase(), range);
+            }
+            Ok(_) => self.status_message = format!("Unknown command: autototal {}", args),
+            Err(status) => self.status_message = EvalStatus::message(Err(status)).to_string(),
+        }
+    }
 
-                CellData::Invalid => String::new(),
+    /// Shows in the status bar the cells that depend on a cell, or that it depends on, directly
+    /// or through other cells.
+    ///
+    /// # Arguments
+    /// * `cell_ref` - The cell to audit (e.g., "A1").
+    /// * `dependents` - Whether to list the cells depending on it (`deps`) rather than the cells
+    ///   it reads (`precedents`).
+    pub fn audit_cell(&mut self, cell_ref: &str, dependents: bool) {
+        let cell_ref = cell_ref.trim();
+        let (row, col) = match to_indices(cell_ref) {
+            Ok((row, col)) if row < self.total_rows && col < self.total_cols => (row, col),
+            _ => {
+                self.status_message = format!("Invalid cell reference: {}", cell_ref);
+                return;
             }
+        };
+        let key = (row * self.total_cols + col) as CellKey;
+        let cells = if dependents {
+            transitive_dependents(key, &self.sheet, &self.ranged, self.total_cols)
+        } else {
+            transitive_precedents(key, &self.sheet, self.total_cols)
+        };
+        let label = if dependents {
+            "Dependents"
         } else {
-            String::new()
+            "Precedents"
+        };
+        // The status bar is one line, so long lists are cut short
+        const SHOWN: usize = 20;
+        let mut names: Vec<String> = cells
+            .iter()
+            .take(SHOWN)
+            .map(|&k| to_cell_name(k as usize / self.total_cols, k as usize % self.total_cols))
+            .collect();
+        if cells.len() > SHOWN {
+            names.push(format!("... ({} more)", cells.len() - SHOWN));
         }
+        self.status_message = if cells.is_empty() {
+            format!("{} of {}: none", label, to_cell_name(row, col))
+        } else {
+            format!(
+                "{} of {} ({}): {}",
+                label,
+                to_cell_name(row, col),
+                cells.len(),
+                names.join(", ")
+            )
+        };
     }
 
-    /// Updates the value of the currently selected cell with the formula input.
+    /// Names a range, so that range functions can read it by name.
     ///
-    /// This method saves the previous state for undo and recalculates dependencies.
-    pub fn update_selected_cell(&mut self) {
-        let total_rows = self.total_rows;
-        let total_cols = self.total_cols;
-        if let Some((r, c)) = self.selected {
-            // Save the current state for undo before making changes
-            self.push_undo_action(r, c);
-            let idx = (r as u32) * (total_cols as u32) + (c as u32);
-            let old_cell = self.sheet.get(&idx).cloned().unwrap_or(Cell {
-                value: Valtype::Int(0),
-                data: CellData::Empty,
-                dependents: HashSet::new(),
-            });
-            let mut new_cell = old_cell.clone();
-
-            // Check if the formula is a range function with empty parentheses
-            let trimmed_input = self.formula_input.trim().to_uppercase();
-            const RANGE_FUNCTIONS: [&str; 5] = ["MAX", "MIN", "AVG", "STDEV", "SUM"];
-            if RANGE_FUNCTIONS
-                .iter()
-                .any(|&func| trimmed_input == format!("{}()", func))
-            {
-                if let (Some(start), Some(end)) = (self.range_start, self.range_end) {
-                    // Calculate the range string using min and max to handle any selection order
-                    let min_row = start.0.min(end.0);
-                    let max_row = start.0.max(end.0);
-                    let min_col = start.1.min(end.1);
-                    let max_col = start.1.max(end.1);
-                    let range_str = format!(
-                        "{}{}:{}{}",
-                        col_label(min_col),
-                        min_row + 1,
-                        col_label(max_col),
-                        max_row + 1
-                    );
-                    // Modify the formula to include the range
-                    let func_name = trimmed_input.trim_end_matches("()");
-                    self.formula_input = format!("{}({})", func_name, range_str);
-                } else {
-                    // No range selected, set error message and skip update
-                    self.status_message = "No range selected for function".to_string();
-                    return;
-                }
-            }
+    /// # Arguments
+    /// * `args` - The command arguments: a name and a range, e.g. "taxes A1:B10".
+    pub fn define_name(&mut self, args: &str) {
+        let result = parse_name_args(args).and_then(|(name, range)| {
+            define_name(
+                &mut self.names,
+                &mut self.sheet,
+                &mut self.ranged,
+                &mut self.is_range,
+                (self.total_rows, self.total_cols),
+                &self.locks,
+                (name, range),
+            )
+        });
+        self.status_message = match result {
+            Ok(()) => format!("Named range {}", args.trim()),
+            Err(status) => EvalStatus::message(Err(status)).to_string(),
+        };
+        // Formulas using a redefined name now read other cells
+        self.search_index = None;
+    }
 
-            // Parse the formula (modified or original) and update the cell
-            parser::detect_formula(&mut new_cell, &self.formula_input);
-            self.sheet.insert(idx, new_cell);
-            parser::update_and_recalc(
+    /// Adds a validation rule, checked on every later edit of the cells it covers.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments, e.g. "A1:A10 int 0..100" or "A1:A10 int 0..100 mark".
+    pub fn validate(&mut self, args: &str) {
+        self.status_message = match parse_validate_args(args, self.total_rows, self.total_cols) {
+            Ok(rule) => match self.validations.add(rule, &self.sheet, self.total_cols) {
+                0 => format!("Validating {}", args.trim()),
+                broken => format!(
+                    "Validating {} ({} cells already break the rule)",
+                    args.trim(),
+                    broken
+                ),
+            },
+            Err(status) => EvalStatus::message(Err(status)).to_string(),
+        };
+    }
+
+    /// Writes a formula across a range, shifting its references for each cell.
+    ///
+    /// Each cell is written as if its formula were entered in the formula bar and checked
+    /// against the validation rules, and a rejected cell keeps its contents.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments, e.g. "A1:A100 = B1*2", with the formula as written
+    ///   for the top-left cell.
+    pub fn fill(&mut self, args: &str) {
+        let formulas = match parser::fill_formulas(args, self.total_rows, self.total_cols) {
+            Ok(formulas) => formulas,
+            Err(status) => {
+                self.status_message = EvalStatus::message(Err(status)).to_string();
+                return;
+            }
+        };
+        if let Err(status) = self
+            .locks
+            .check(formulas.iter().map(|&(row, col, _)| (row, col)))
+        {
+            self.status_message = EvalStatus::message(Err(status)).to_string();
+            return;
+        }
+        let (selected, input) = (self.selected, self.formula_input.clone());
+        let mut status = Ok(());
+        self.begin_undo_group();
+        for (row, col, formula) in &formulas {
+            self.selected = Some((*row, *col));
+            self.formula_input = formula.clone();
+            let Some((edit, old_cell)) = self.begin_cell_edit() else {
+                continue;
+            };
+            let written = validated_update(
+                &mut self.validations,
                 &mut self.sheet,
                 &mut self.ranged,
                 &mut self.is_range,
-                (total_rows, total_cols),
-                r,
-                c,
+                (self.total_rows, self.total_cols),
+                edit.position,
                 old_cell,
+            )
+            .status;
+            self.finish_cell_edit(edit, written);
+            status = status.and(written);
+        }
+        self.end_undo_group();
+        (self.selected, self.formula_input) = (selected, input);
+        self.status_message = match status {
+            Ok(()) => format!("Filled {} cells", formulas.len()),
+            Err(_) => EvalStatus::message(status).to_string(),
+        };
+    }
+
+    /// Finds the last cell filled when the fill handle of the selected cell is released over
+    /// a cell: the fill runs down the column if the cell is further down than right, and
+    /// along the row otherwise.
+    ///
+    /// # Arguments
+    /// * `target` - The (row, col) of the cell under the handle.
+    ///
+    /// # Returns
+    /// * `Option<(usize, usize)>` - The (row, col) of the last cell filled, or `None` if no
+    ///   cell is selected or the handle is not below or right of it.
+    pub fn fill_end(&self, target: (usize, usize)) -> Option<(usize, usize)> {
+        let (row, col) = self.selected?;
+        let down = target.0.saturating_sub(row);
+        let right = target.1.saturating_sub(col);
+        match (down, right) {
+            (0, 0) => None,
+            _ if down >= right => Some((row + down, col)),
+            _ => Some((row, col + right)),
+        }
+    }
+
+    /// Copies the selected cell's formula into the cells its fill handle was dragged over,
+    /// shifting references as `fill` does, as one undo step.
+    ///
+    /// # Arguments
+    /// * `target` - The (row, col) of the cell the handle was released over.
+    pub fn drag_fill(&mut self, target: (usize, usize)) {
+        let (Some((row, col)), Some(end)) = (self.selected, self.fill_end(target)) else {
+            return;
+        };
+        let formula = self.get_cell_formula(row, col);
+        if formula.is_empty() {
+            self.status_message = format!("{} is empty, nothing to fill", to_cell_name(row, col));
+            return;
+        }
+        self.fill(&format!(
+            "{}:{} = {}",
+            to_cell_name(row, col),
+            to_cell_name(end.0, end.1),
+            formula
+        ));
+    }
+
+    /// Writes the frequency table of a range into the sheet and optionally charts it.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments, e.g. "A1:A500 bins=10 -> C1 chart".
+    pub fn histogram(&mut self, args: &str) {
+        let spec = match parse_histogram_args(args, self.total_rows, self.total_cols) {
+            Ok(spec) => spec,
+            Err(status) => {
+                self.status_message = EvalStatus::message(Err(status)).to_string();
+                return;
+            }
+        };
+        let (formulas, bins) = histogram_formulas(&self.sheet, &spec, self.total_cols);
+        let previous = self.selected;
+        self.begin_undo_group();
+        for (row, col, formula) in formulas {
+            self.selected = Some((row, col));
+            self.formula_input = formula;
+            self.update_selected_cell();
+        }
+        self.end_undo_group();
+        self.selected = previous;
+        self.formula_input.clear();
+        let ((r1, c1), (r2, c2)) = spec.source;
+        let title = format!("{}:{}", to_cell_name(r1, c1), to_cell_name(r2, c2));
+        self.status_message = format!("Wrote {} bins for {}", bins.len(), title);
+        if spec.chart {
+            self.histogram_chart = Some(HistogramChart { title, bins });
+        }
+    }
+
+    /// Opens a chart of the values of a range, which follows the cells as they change.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments: `bar` or `line`, then the range, e.g. "bar A1:A10".
+    pub fn open_chart(&mut self, args: &str) {
+        let mut parts = args.split_whitespace();
+        let (kind, range) = match (parts.next(), parts.next(), parts.next()) {
+            (Some("bar"), Some(range), None) => (ChartKind::Bar, range),
+            (Some("line"), Some(range), None) => (ChartKind::Line, range),
+            _ => {
+                self.status_message = format!("Unknown command: chart {}", args);
+                return;
+            }
+        };
+        let range = range.to_ascii_uppercase();
+        let Some(corners) = parse_range(&range, self.total_rows, self.total_cols) else {
+            self.status_message = EvalStatus::message(Err(EvalStatus::InvalidRange)).to_string();
+            return;
+        };
+        let chart = RangeChart { kind, corners };
+        if !self.charts.contains(&chart) {
+            self.charts.push(chart);
+        }
+        self.status_message = format!("Charted {}", range);
+    }
+
+    /// Checks the sheet for suspicious formulas and opens the lint panel with the results.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments: empty, or the longest chain of formulas allowed.
+    pub fn lint(&mut self, args: &str) {
+        let options = match parse_lint_args(args) {
+            Ok(options) => options,
+            Err(status) => {
+                self.status_message = EvalStatus::message(Err(status)).to_string();
+                return;
+            }
+        };
+        let issues = lint(
+            &self.sheet,
+            &self.ranged,
+            &self.is_range,
+            (self.total_rows, self.total_cols),
+            &options,
+        );
+        self.status_message = format!("Lint found {} issues", issues.len());
+        self.lint_report = Some(issues);
+    }
+
+    /// Gathers the statistics of the open sheet and opens the "About this sheet" panel with
+    /// them.
+    pub fn show_sheet_info(&mut self) {
+        self.sheet_info = Some(sheet_info(
+            &self.sheet,
+            &self.ranged,
+            &self.is_range,
+            (self.total_rows, self.total_cols),
+        ));
+    }
+
+    /// Changes the dimensions of the sheet, keeping every cell at its row and column.
+    ///
+    /// Undo history is cleared, as it may refer to cells that no longer exist.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments, e.g. "200 30" for 200 rows and 30 columns.
+    pub fn resize(&mut self, args: &str) {
+        let status = parse_resize_args(args).and_then(|dims| {
+            resize_sheet(
+                &mut self.sheet,
+                &mut self.ranged,
+                &mut self.is_range,
+                (self.total_rows, self.total_cols),
+                dims,
+            )?;
+            Ok(dims)
+        });
+        let (rows, cols) = match status {
+            Ok(dims) => dims,
+            Err(EvalStatus::InvalidRange) if parse_resize_args(args).is_ok() => {
+                self.status_message = format!(
+                    "Cannot resize to {}: cells or references would be lost",
+                    args
+                );
+                return;
+            }
+            Err(status) => {
+                self.status_message = EvalStatus::message(Err(status)).to_string();
+                return;
+            }
+        };
+        self.total_rows = rows;
+        self.total_cols = cols;
+        self.selected = self
+            .selected
+            .map(|(r, c)| (r.min(rows - 1), c.min(cols - 1)));
+        self.start_row = self.start_row.min(rows - 1);
+        self.start_col = self.start_col.min(cols - 1);
+        self.should_reset_scroll = true;
+        self.range_start = None;
+        self.range_end = None;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.search_index = None;
+        self.lint_report = None;
+        self.grid_sizes.row_heights.retain(|&row, _| row < rows);
+        self.grid_sizes.col_widths.retain(|&col, _| col < cols);
+        self.frozen = (self.frozen.0.min(rows - 1), self.frozen.1.min(cols - 1));
+        self.formats
+            .retain(|&(row, col), _| row < rows && col < cols);
+        self.validations.recheck(&self.sheet, cols);
+        self.status_message = format!("Resized sheet to {} rows and {} columns", rows, cols);
+    }
+
+    /// Inserts or deletes a row or column of the open sheet.
+    ///
+    /// The formats, sizes and links of the cells past the edit move with them. As with
+    /// `resize`, the undo history is cleared.
+    ///
+    /// # Arguments
+    /// * `edit` - The row or column to insert or delete.
+    pub fn edit_lines(&mut self, edit: LineEdit) {
+        let (action, line) = match edit {
+            LineEdit::InsertRow(row) => ("insert", format!("row {}", row + 1)),
+            LineEdit::DeleteRow(row) => ("delete", format!("row {}", row + 1)),
+            LineEdit::InsertCol(col) => ("insert", format!("column {}", col_label(col))),
+            LineEdit::DeleteCol(col) => ("delete", format!("column {}", col_label(col))),
+        };
+        let total_dims = (self.total_rows, self.total_cols);
+        let (rows, cols) = match edit_lines(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            total_dims,
+            edit,
+        ) {
+            Ok(dims) => dims,
+            Err(_) if edit.dims(total_dims).is_none() => {
+                self.status_message = format!(
+                    "Cannot {} {}: the sheet cannot grow or shrink further",
+                    action, line
+                );
+                return;
+            }
+            Err(_) => {
+                self.status_message = format!("Cannot {} {}: a formula refers to it", action, line);
+                return;
+            }
+        };
+        let moved = |(row, col): (usize, usize)| {
+            edit.addr(CellAddr::new(row, col))
+                .map(|addr| (addr.row, addr.col))
+        };
+        self.formats = std::mem::take(&mut self.formats)
+            .into_iter()
+            .filter_map(|(cell, format)| Some((moved(cell)?, format)))
+            .collect();
+        self.links = std::mem::take(&mut self.links)
+            .into_iter()
+            .filter_map(|(cell, link)| Some((moved(cell)?, link)))
+            .collect();
+        if matches!(edit, LineEdit::InsertRow(_) | LineEdit::DeleteRow(_)) {
+            self.grid_sizes.row_heights = std::mem::take(&mut self.grid_sizes.row_heights)
+                .into_iter()
+                .filter_map(|(row, height)| Some((moved((row, 0))?.0, height)))
+                .collect();
+        } else {
+            self.grid_sizes.col_widths = std::mem::take(&mut self.grid_sizes.col_widths)
+                .into_iter()
+                .filter_map(|(col, width)| Some((moved((0, col))?.1, width)))
+                .collect();
+        }
+        self.total_rows = rows;
+        self.total_cols = cols;
+        self.selected = self
+            .selected
+            .map(|(r, c)| (r.min(rows - 1), c.min(cols - 1)));
+        self.start_row = self.start_row.min(rows - 1);
+        self.start_col = self.start_col.min(cols - 1);
+        self.range_start = None;
+        self.range_end = None;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.search_index = None;
+        self.lint_report = None;
+        self.frozen = (self.frozen.0.min(rows - 1), self.frozen.1.min(cols - 1));
+        self.validations.recheck(&self.sheet, cols);
+        self.status_message = match action {
+            "insert" => format!("Inserted {}", line),
+            _ => format!("Deleted {}", line),
+        };
+    }
+
+    /// Carries out an action picked from the context menu of a row or column header.
+    ///
+    /// # Arguments
+    /// * `action` - The action picked.
+    pub(in crate::gui) fn header_action(&mut self, action: HeaderAction) {
+        let name = |is_row: bool, index: usize| {
+            if is_row {
+                format!("row {}", index + 1)
+            } else {
+                format!("column {}", col_label(index))
+            }
+        };
+        match action {
+            HeaderAction::Edit(edit) => self.edit_lines(edit),
+            HeaderAction::Select(is_row, index) => {
+                let (start, end) = if is_row {
+                    ((index, 0), (index, self.total_cols - 1))
+                } else {
+                    ((0, index), (self.total_rows - 1, index))
+                };
+                self.selected = Some(start);
+                self.range_start = Some(start);
+                self.range_end = Some(end);
+                self.is_selecting_range = false;
+                self.editing_cell = false;
+                self.formula_input = self.get_cell_formula(start.0, start.1);
+                self.status_message = format!("Selected {}", name(is_row, index));
+            }
+            HeaderAction::Hide(is_row, index) => {
+                let sizes = if is_row {
+                    &mut self.grid_sizes.row_heights
+                } else {
+                    &mut self.grid_sizes.col_widths
+                };
+                sizes.insert(index, 0.0);
+                self.status_message = format!("Hid {}", name(is_row, index));
+            }
+            HeaderAction::ResetSize(is_row, index) => {
+                let sizes = if is_row {
+                    &mut self.grid_sizes.row_heights
+                } else {
+                    &mut self.grid_sizes.col_widths
+                };
+                sizes.remove(&index);
+                self.status_message = format!("Reset the size of {}", name(is_row, index));
+            }
+            HeaderAction::UnhideAll(is_row) => {
+                let sizes = if is_row {
+                    &mut self.grid_sizes.row_heights
+                } else {
+                    &mut self.grid_sizes.col_widths
+                };
+                let hidden = sizes.len();
+                sizes.retain(|_, size| *size > 0.0);
+                self.status_message = format!(
+                    "Showed {} hidden {}",
+                    hidden - sizes.len(),
+                    if is_row { "row(s)" } else { "column(s)" }
+                );
+            }
+        }
+    }
+
+    /// Changes the format of a range, as in `format A1:B5 bold right decimals=2`.
+    ///
+    /// # Arguments
+    /// * `args` - The range or cell followed by the changes, see `format::parse_format_args`.
+    pub fn format_cells(&mut self, args: &str) {
+        match parse_format_args(args, self.total_rows, self.total_cols) {
+            Ok((corners, changes)) => {
+                self.apply_format(corners, &changes);
+                let (start, end) = corners;
+                self.status_message = format!(
+                    "Formatted {}:{}",
+                    to_cell_name(start.0, start.1),
+                    to_cell_name(end.0, end.1)
+                );
+            }
+            Err(status) => self.status_message = EvalStatus::message(Err(status)).to_string(),
+        }
+    }
+
+    /// Applies format changes to every cell of a block, dropping formats that end up default.
+    ///
+    /// # Arguments
+    /// * `corners` - The top-left and bottom-right (row, col) of the block.
+    /// * `changes` - The changes, applied in order.
+    pub fn apply_format(
+        &mut self,
+        corners: ((usize, usize), (usize, usize)),
+        changes: &[FormatChange],
+    ) {
+        let ((r1, c1), (r2, c2)) = corners;
+        for row in r1..=r2 {
+            for col in c1..=c2 {
+                let mut format = self.formats.get(&(row, col)).copied().unwrap_or_default();
+                for &change in changes {
+                    format.apply(change);
+                }
+                if format == CellFormat::default() {
+                    self.formats.remove(&(row, col));
+                } else {
+                    self.formats.insert((row, col), format);
+                }
+            }
+        }
+    }
+
+    /// Pins the rows above and the columns left of a cell, so they stay in view while scrolling.
+    ///
+    /// # Arguments
+    /// * `args` - The cell (e.g. "B3" pins row 1-2 and column A), or "off" to unpin.
+    pub fn freeze(&mut self, args: &str) {
+        let args = args.trim().to_ascii_uppercase();
+        let frozen = if args == "OFF" {
+            Some((0, 0))
+        } else {
+            parse_cell_name(&args)
+                .filter(|&(row, col)| row < self.total_rows && col < self.total_cols)
+        };
+        let Some((rows, cols)) = frozen else {
+            self.status_message = format!("Invalid cell reference: {}", args);
+            return;
+        };
+        self.frozen = (rows, cols);
+        self.should_reset_scroll = true;
+        self.status_message = if (rows, cols) == (0, 0) {
+            "Unfroze panes".to_string()
+        } else {
+            format!("Froze {} rows and {} columns", rows, cols)
+        };
+    }
+
+    /// Starts or stops watching a cell, for `watch <cell>` and `unwatch <cell>`. A toast is
+    /// shown whenever the value of a watched cell changes.
+    ///
+    /// # Arguments
+    /// * `cell_ref` - The cell name, e.g. `A1`.
+    /// * `watch` - Whether to watch the cell, or to stop watching it.
+    pub fn watch_cell(&mut self, cell_ref: &str, watch: bool) {
+        let cell_ref = cell_ref.trim();
+        let total_dims = (self.total_rows, self.total_cols);
+        self.status_message = match self.watches.set(&self.sheet, total_dims, cell_ref, watch) {
+            Ok(()) if watch => format!("Watching {}", cell_ref),
+            Ok(()) => format!("Stopped watching {}", cell_ref),
+            Err(_) => format!("Invalid cell reference: {}", cell_ref),
+        };
+    }
+
+    /// Locks the cells of a range, or unlocks them, for `lock <range>` and `unlock <range>`.
+    /// Formulas cannot be assigned to a locked cell.
+    ///
+    /// # Arguments
+    /// * `range` - The range, e.g. `A1:B10`, or a cell name.
+    /// * `lock` - Whether to lock the cells, or to unlock them.
+    pub fn lock_cells(&mut self, range: &str, lock: bool) {
+        let range = range.trim();
+        let total_dims = (self.total_rows, self.total_cols);
+        self.status_message = match self.locks.set(range, total_dims, lock) {
+            Ok(()) if lock => format!("Locked {}", range),
+            Ok(()) => format!("Unlocked {}", range),
+            Err(status) => EvalStatus::message(Err(status)).to_string(),
+        };
+    }
+
+    /// Opens the search panel, optionally with a query typed in.
+    ///
+    /// # Arguments
+    /// * `query` - The initial query; matches are selected as soon as it is non-empty.
+    pub fn open_search(&mut self, query: &str) {
+        self.show_search = true;
+        self.search_query = query.trim().to_string();
+        self.search_current = 0;
+        self.step_search(0);
+    }
+
+    /// Returns the cells matching the search query, building the search index if needed.
+    ///
+    /// # Returns
+    /// A `Vec<CellKey>` of matching cell indices in row-major order.
+    pub fn search_matches(&mut self) -> Vec<CellKey> {
+        self.search_index
+            .get_or_insert_with(|| SearchIndex::build(&self.sheet))
+            .find(&self.search_query)
+    }
+
+    /// Moves to another match of the search query and selects it.
+    ///
+    /// # Arguments
+    /// * `step` - How many matches to move by: 1 for the next, -1 for the previous, 0 to stay.
+    pub fn step_search(&mut self, step: isize) {
+        let matches = self.search_matches();
+        if matches.is_empty() {
+            self.search_current = 0;
+            if !self.search_query.is_empty() {
+                self.status_message = format!("No matches for {}", self.search_query);
+            }
+            return;
+        }
+        let n = matches.len() as isize;
+        self.search_current = (self.search_current as isize + step).rem_euclid(n) as usize;
+        let key = matches[self.search_current] as usize;
+        let (row, col) = (key / self.total_cols, key % self.total_cols);
+        self.selected = Some((row, col));
+        self.start_row = row;
+        self.start_col = col;
+        self.should_reset_scroll = true;
+        self.status_message = format!(
+            "Match {} of {} at {}",
+            self.search_current + 1,
+            matches.len(),
+            to_cell_name(row, col)
+        );
+    }
+
+    /// Rewrites every formula containing a word, as one undoable change.
+    ///
+    /// # Arguments
+    /// * `from` - The word to replace, e.g. a reference or a function name.
+    /// * `to` - The text written instead.
+    pub fn replace_text(&mut self, from: &str, to: &str) {
+        let formulas = replacements(&self.sheet, self.total_cols, from, to);
+        if formulas.is_empty() {
+            self.status_message = format!("No formulas contain {}", from.trim());
+            return;
+        }
+        self.begin_undo_group();
+        for &(row, col, _) in &formulas {
+            self.push_undo_action(row, col);
+        }
+        let results = replace_formulas(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+            &self.locks,
+            &mut self.validations,
+            (from, to),
+        );
+        let mut replaced = 0;
+        for (key, status) in &results {
+            let (row, col) = (
+                *key as usize / self.total_cols,
+                *key as usize % self.total_cols,
             );
-            self.status_message = match unsafe { STATUS_CODE } {
-                0 => format!("Updated cell {}{}", col_label(c), r + 1),
-                code => STATUS[code].to_string(),
-            };
-            unsafe {
-                STATUS_CODE = 0;
+            if status.is_ok() {
+                replaced += 1;
+                self.names.record(row, col, None);
+                self.links.remove(&(row, col));
+            }
+            self.reindex_cell(*key);
+        }
+        // Rejected rewrites are undone by the engine, so only a change that took is kept
+        if replaced > 0 {
+            self.end_undo_group();
+        } else {
+            self.open_undo_group = None;
+        }
+        self.status_message = match results.iter().find(|(_, status)| status.is_err()) {
+            None => format!("Replaced {} in {} cells", from.trim(), replaced),
+            Some(&(key, status)) => format!(
+                "Replaced {} in {} cells; {} kept: {}",
+                from.trim(),
+                replaced,
+                to_cell_name(
+                    key as usize / self.total_cols,
+                    key as usize % self.total_cols
+                ),
+                EvalStatus::message(status)
+            ),
+        };
+    }
+
+    /// Updates the search index, if it has been built, after a cell was edited.
+    ///
+    /// # Arguments
+    /// * `key` - The index of the edited cell; cells computed from it are re-indexed too.
+    pub(in crate::gui) fn reindex_cell(&mut self, key: CellKey) {
+        if let Some(index) = &mut self.search_index {
+            index.refresh(key, &self.sheet, &self.ranged, self.total_cols);
+        }
+    }
+
+    /// Builds one CSV record for a row of the sheet.
+    ///
+    /// # Arguments
+    /// * `row` - The row index to serialize.
+    /// * `formulas` - Whether to write formulas (as in `fcsv`) instead of evaluated values.
+    /// * `formatted` - Whether to write values the way their cell format shows them.
+    /// * `locale` - How to write the thousands and decimals of numbers.
+    ///
+    /// # Returns
+    /// A `Vec<String>` with one entry per column.
+    fn csv_record(
+        &self,
+        row: usize,
+        formulas: bool,
+        formatted: bool,
+        locale: Locale,
+    ) -> Vec<String> {
+        let mut record: Vec<String> = Vec::with_capacity(self.total_cols);
+        for col in 0..self.total_cols {
+            let key = (row * self.total_cols + col) as CellKey;
+            let format = self.formats.get(&(row, col)).filter(|_| formatted);
+            if let Some(cell) = self.sheet.get(&key) {
+                if formulas {
+                    record.push(match cell.data {
+                        CellData::Empty | CellData::Const | CellData::Invalid => {
+                            valtype_to_string(&cell.value)
+                        }
+                        _ => format!("={}", formula_string(cell)),
+                    });
+                } else if let (Some(format), Valtype::Int(_)) = (format, &cell.value) {
+                    record.push(locale.localize(&format.display(&cell.value)));
+                } else if let Some(format) = format {
+                    record.push(format.display(&cell.value));
+                } else {
+                    record.push(valtype_to_string(&cell.value));
+                }
+            } else if let Some(format) = format {
+                record.push(locale.localize(&format.display(&Valtype::Int(0))));
+            } else {
+                record.push("0".to_string());
+            }
+        }
+        record
+    }
+
+    /// Bundles the sheet into a single zip archive for submission.
+    ///
+    /// The archive contains `values.csv`, `formulas.csv`, the workbook JSON written by `save` as
+    /// `workbook.json`, and a `manifest.json` describing the sheet and listing the size and
+    /// SHA-256 checksum of every other file.
+    ///
+    /// # Arguments
+    /// * `filename` - The name of the archive to create (appends ".zip" if not present).
+    pub fn package_submission(&mut self, filename: &str) {
+        let result = package_sheet(
+            filename,
+            &self.sheet,
+            &self.ranged,
+            &self.is_range,
+            (self.total_rows, self.total_cols),
+            &self.grid_sizes,
+            &self.formatted_cells(),
+        );
+        self.status_message = match result {
+            Ok(filename) => format!("Packaged submission to {}", filename),
+            Err(e) => e,
+        };
+    }
+
+    /// Switches to the theme saved in the user's theme file by an earlier session, if any.
+    pub fn restore_theme(&mut self) {
+        if let Ok(style) = load_theme(None) {
+            self.style = style;
+        }
+    }
+
+    /// Writes the theme to the user's theme file if it changed, once no mouse button is held,
+    /// so dragging a color or slider writes the file once.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context, for the state of the mouse buttons.
+    pub(in crate::gui) fn persist_theme_if_changed(&mut self, ctx: &egui::Context) {
+        if !self.theme_changed || ctx.input(|input| input.pointer.any_down()) {
+            return;
+        }
+        self.theme_changed = false;
+        if let Err(e) = save_theme(&self.style, None) {
+            self.status_message = format!("Theme not kept: {}", e);
+        }
+    }
+
+    /// Draws the GUI light or dark as the theme mode asks, switching the grid colors when the
+    /// mode, or the system theme it follows, changes.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context.
+    pub(in crate::gui) fn follow_theme_mode(&mut self, ctx: &egui::Context) {
+        let dark = self.style.mode.is_dark(ctx);
+        if dark != self.style.dark {
+            self.style.set_dark(dark);
+        }
+        ctx.set_visuals(if dark {
+            egui::Visuals::dark()
+        } else {
+            egui::Visuals::light()
+        });
+    }
+
+    /// Handles the `theme save <name>`, `theme load <name>`, `theme list` and
+    /// `theme light|dark|system` commands.
+    ///
+    /// # Arguments
+    /// * `args` - The text after `theme `, e.g. "save night" or "light".
+    pub(in crate::gui) fn theme_command(&mut self, args: &str) {
+        let (action, name) = args.split_once(' ').unwrap_or((args, ""));
+        let name = name.trim();
+        self.status_message = match action {
+            "save" if !name.is_empty() => match save_theme(&self.style, Some(name)) {
+                Ok(path) => format!("Theme '{}' saved to {}", name, path.display()),
+                Err(e) => e,
+            },
+            "load" if !name.is_empty() => match load_theme(Some(name)) {
+                Ok(style) => {
+                    self.style = style;
+                    self.theme_changed = true;
+                    format!("Theme '{}' loaded", name)
+                }
+                Err(e) => e,
+            },
+            "light" | "dark" | "system" if name.is_empty() => {
+                self.style.mode = ThemeMode::from_name(action).unwrap_or(ThemeMode::Dark);
+                self.theme_changed = true;
+                format!("Theme mode {}", action)
+            }
+            "list" => {
+                let names = theme_names();
+                if names.is_empty() {
+                    "No saved themes".to_string()
+                } else {
+                    format!("Themes: {}", names.join(", "))
+                }
+            }
+            _ => "Usage: theme save <name> | theme load <name> | theme list | theme light|dark|system"
+                .to_string(),
+        };
+    }
+
+    /// Replaces the keyboard shortcuts with those of a keys file.
+    ///
+    /// # Arguments
+    /// * `path` - The TOML file mapping actions to shortcuts; see the `keys` module.
+    ///
+    /// # Returns
+    /// * `Result<(), String>` - `Ok(())`, or a message describing why the file could not be
+    ///   used, in which case the shortcuts are unchanged.
+    pub fn load_keys(&mut self, path: &str) -> Result<(), String> {
+        self.keys = KeyMap::load(path)?;
+        Ok(())
+    }
+
+    /// Replaces the sheet with the contents of a CSV file.
+    ///
+    /// Each non-empty field is parsed like a typed formula, with an optional leading '=' as
+    /// written by `fcsv`, so both value and formula exports can be reloaded. Numbers written
+    /// with thousands or zero decimal places, as by `csv` for a formatted cell, are read as
+    /// plain numbers. The undo history is cleared, since it refers to the sheet being replaced.
+    ///
+    /// # Arguments
+    /// * `filename` - The name of the file to open (appends ".csv" if not present).
+    /// * `locale` - How the file separates its fields and writes its numbers.
+    pub fn open_csv(&mut self, filename: &str, locale: Locale) {
+        let filename = if filename.ends_with(".csv") {
+            filename.to_string()
+        } else {
+            format!("{}.csv", filename)
+        };
+        let mut reader = match ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(locale.delimiter())
+            .from_path(&filename)
+        {
+            Ok(reader) => reader,
+            Err(e) => {
+                self.status_message = format!("File error: {}", e);
+                return;
+            }
+        };
+        let mut records = Vec::new();
+        for record in reader.records() {
+            match record {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    self.status_message = format!("CSV read error: {}", e);
+                    return;
+                }
+            }
+        }
+
+        self.sheet.clear();
+        self.ranged.clear();
+        self.is_range.fill(false);
+        let mut first_error = Ok(());
+        for (row, record) in records.iter().enumerate() {
+            for (col, field) in record.iter().enumerate() {
+                let number = locale.read_number(field.trim()).map(|n| n.to_string());
+                let formula = match &number {
+                    Some(number) => number,
+                    None => field.trim().strip_prefix('=').unwrap_or(field.trim()),
+                };
+                if formula.is_empty() {
+                    continue;
+                }
+                if row >= self.total_rows || col >= self.total_cols {
+                    first_error = first_error.and(Err(EvalStatus::InvalidRange));
+                    continue;
+                }
+                let key = (row * self.total_cols + col) as CellKey;
+                let old_cell = self.sheet.get(&key).cloned().unwrap_or(Cell {
+                    value: Valtype::Int(0),
+                    data: CellData::Empty,
+                    dependents: HashSet::new(),
+                });
+                let mut new_cell = old_cell.clone();
+                parser::detect_formula(&mut new_cell, formula);
+                self.sheet.insert(key, new_cell);
+                let status = parser::update_and_recalc(
+                    &mut self.sheet,
+                    &mut self.ranged,
+                    &mut self.is_range,
+                    (self.total_rows, self.total_cols),
+                    row,
+                    col,
+                    old_cell,
+                )
+                .status;
+                first_error = first_error.and(status);
             }
         }
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.search_index = None;
+        self.status_message = match first_error {
+            Ok(()) => format!("Opened {}", filename),
+            Err(_) => format!("Opened {} ({})", filename, EvalStatus::message(first_error)),
+        };
+    }
+
+    /// Saves the whole session, formulas, dependencies and column and row sizes included, to a
+    /// workbook file.
+    ///
+    /// # Arguments
+    /// * `filename` - The name of the file to save to (appends ".json" if not present).
+    pub fn save_workbook(&mut self, filename: &str) {
+        let filename = if filename.ends_with(".json") {
+            filename.to_string()
+        } else {
+            format!("{}.json", filename)
+        };
+        self.status_message = match self.write_workbook(&filename) {
+            Ok(()) => format!("Saved workbook to {}", filename),
+            Err(e) => e,
+        };
+    }
+
+    /// Writes the workbook to the autosave file once `AUTOSAVE_INTERVAL` has passed since the
+    /// last autosave. The status message is only changed if the write fails.
+    pub fn autosave_if_due(&mut self) {
+        if self.last_autosave.elapsed() < AUTOSAVE_INTERVAL {
+            return;
+        }
+        self.last_autosave = Instant::now();
+        let path = workbook::autosave_path();
+        if let Err(e) = self.write_workbook(&path.to_string_lossy()) {
+            self.status_message = format!("Autosave failed: {}", e);
+        }
+    }
+
+    /// Saves the open sheet with its sizes and formats to `filename`.
+    fn write_workbook(&self, filename: &str) -> Result<(), String> {
+        save_workbook(
+            filename,
+            &self.sheet,
+            &self.ranged,
+            &self.is_range,
+            (self.total_rows, self.total_cols),
+            &self.grid_sizes,
+            &self.formatted_cells(),
+        )
+    }
+
+    /// Returns the (row, col) and format of every formatted cell, as workbooks store them.
+    fn formatted_cells(&self) -> Vec<FormattedCell> {
+        self.formats
+            .iter()
+            .map(|(&(row, col), &format)| (row, col, format))
+            .collect()
+    }
+
+    /// Restores a session saved with [`Self::save_workbook`], replacing the current sheet.
+    ///
+    /// # Arguments
+    /// * `filename` - The name of the file to load (appends ".json" if not present).
+    pub fn load_workbook(&mut self, filename: &str) {
+        let filename = if filename.ends_with(".json") {
+            filename.to_string()
+        } else {
+            format!("{}.json", filename)
+        };
+        self.status_message = match load_workbook(
+            &filename,
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+        ) {
+            Ok((sizes, formats)) => {
+                self.grid_sizes = sizes;
+                self.formats = formats
+                    .into_iter()
+                    .map(|(row, col, format)| ((row, col), format))
+                    .collect();
+                self.undo_stack.clear();
+                self.redo_stack.clear();
+                self.search_index = None;
+                self.validations.recheck(&self.sheet, self.total_cols);
+                format!("Loaded workbook {}", filename)
+            }
+            Err(e) => e,
+        };
     }
 
-    /// Exports the spreadsheet data to a CSV file.
+    /// Exports the spreadsheet data to a CSV file, with numbers written as their cell format
+    /// shows them.
     ///
     /// # Arguments
     /// * `filename` - The name of the file to export to (appends ".csv" if not present).
-    pub fn export_to_csv(&mut self, filename: &str) {
+    /// * `locale` - How to separate fields and write the thousands and decimals of numbers.
+    pub fn export_to_csv(&mut self, filename: &str, locale: Locale) {
         let filename = if filename.ends_with(".csv") {
             filename.to_string()
         } else {
@@ -191,23 +1414,11 @@ impl SpreadsheetApp {
 
         match File::create(&filename) {
             Ok(file) => {
-                let mut wtr = Writer::from_writer(file);
-                for row in 0..self.total_rows {
-                    let mut record: Vec<String> = Vec::with_capacity(self.total_cols);
-                    for col in 0..self.total_cols {
-                        let key = (row * self.total_cols + col) as u32;
-                        if let Some(cell) = self.sheet.get(&key) {
-                            let cell_str = match &cell.value {
-                                Valtype::Int(n) => n.to_string(),
-                                Valtype::Str(s) => s.to_string(),
-                            };
-                            record.push(cell_str);
-                        } else {
-                            record.push("0".to_string());
-                        }
-                    }
-
-                    if let Err(e) = wtr.write_record(&record) {
+                let mut wtr = WriterBuilder::new()
+                    .delimiter(locale.delimiter())
+                    .from_writer(file);
+                for row in 0..self.total_rows {
+                    if let Err(e) = wtr.write_record(self.csv_record(row, false, true, locale)) {
                         self.status_message = format!("CSV write error: {}", e);
                         return;
                     }
@@ -224,11 +1435,80 @@ impl SpreadsheetApp {
         }
     }
 
+    /// Exports the values of a range as a heatmap PNG.
+    ///
+    /// Empty cells count as 0, matching range formulas, and error cells are drawn in gray.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments: `<file.png> <range> [viridis|heat|gray]`.
+    pub fn export_heatmap(&mut self, args: &str) {
+        let mut parts = args.split_whitespace();
+        let (Some(filename), Some(range)) = (parts.next(), parts.next()) else {
+            self.status_message = "Usage: heatmap <file.png> <range> [palette]".to_string();
+            return;
+        };
+        let palette = match parts.next().map(HeatmapPalette::from_name) {
+            None => HeatmapPalette::Viridis,
+            Some(Some(palette)) if parts.next().is_none() => palette,
+            _ => {
+                self.status_message = format!("Unknown command: heatmap {}", args);
+                return;
+            }
+        };
+        let Some(((r1, c1), (r2, c2))) = parse_range(range, self.total_rows, self.total_cols)
+        else {
+            self.status_message = EvalStatus::message(Err(EvalStatus::InvalidRange)).to_string();
+            return;
+        };
+        let filename = if filename.ends_with(".png") {
+            filename.to_string()
+        } else {
+            format!("{}.png", filename)
+        };
+
+        let (rows, cols) = (r2 - r1 + 1, c2 - c1 + 1);
+        let values: Vec<Option<i32>> = get_range_values(
+            &self.sheet,
+            (self.total_rows, self.total_cols),
+            CellAddr::new(r1, c1),
+            CellAddr::new(r2, c2),
+        )
+        .into_iter()
+        .flatten()
+        .map(|value| match value {
+            Valtype::Int(v) => Some(v),
+            Valtype::Str(_) | Valtype::Err(_) => None,
+        })
+        .collect();
+        self.status_message = match write_heatmap_png(&filename, &values, rows, cols, palette) {
+            Ok((width, height)) => format!("Exported {}x{} heatmap to {}", width, height, filename),
+            Err(e) => e,
+        };
+    }
+
+    /// Exports the dependency graph of the sheet as Graphviz DOT.
+    ///
+    /// # Arguments
+    /// * `filename` - The name of the file to export to (appends ".dot" if not present).
+    pub fn export_graph(&mut self, filename: &str) {
+        let filename = if filename.ends_with(".dot") {
+            filename.to_string()
+        } else {
+            format!("{}.dot", filename)
+        };
+        let dot = dependency_dot(&self.sheet, &self.ranged, self.total_cols);
+        self.status_message = match std::fs::write(&filename, dot) {
+            Ok(()) => format!("Exported dependency graph to {}", filename),
+            Err(e) => format!("File error: {}", e),
+        };
+    }
+
     /// Exports the spreadsheet formulas to a CSV file.
     ///
     /// # Arguments
     /// * `filename` - The name of the file to export to (appends ".csv" if not present).
-    pub fn export_formulas_to_csv(&mut self, filename: &str) {
+    /// * `locale` - How to separate fields; formulas are written as typed.
+    pub fn export_formulas_to_csv(&mut self, filename: &str, locale: Locale) {
         let filename = if filename.ends_with(".csv") {
             filename.to_string()
         } else {
@@ -236,21 +1516,11 @@ impl SpreadsheetApp {
         };
         match File::create(&filename) {
             Ok(file) => {
-                let mut wtr = Writer::from_writer(file);
+                let mut wtr = WriterBuilder::new()
+                    .delimiter(locale.delimiter())
+                    .from_writer(file);
                 for row in 0..self.total_rows {
-                    let mut record: Vec<String> = Vec::with_capacity(self.total_cols);
-                    for col in 0..self.total_cols {
-                        let key = (row * self.total_cols + col) as u32;
-                        if let Some(cell) = self.sheet.get(&key) {
-                            let formula_str = cell_data_to_formula_string(&cell.data)
-                                .unwrap_or_else(|| valtype_to_string(&cell.value));
-                            record.push(formula_str);
-                        } else {
-                            record.push("0".to_string());
-                        }
-                    }
-
-                    if let Err(e) = wtr.write_record(&record) {
+                    if let Err(e) = wtr.write_record(self.csv_record(row, true, false, locale)) {
                         self.status_message = format!("CSV write error: {}", e);
                         return;
                     }
@@ -280,11 +1550,18 @@ impl SpreadsheetApp {
         }
     }
 
-    /// Moves the selection to a specified cell reference.
+    /// Moves the selection to a specified cell reference, recording the jump in the history
+    /// retraced by `back` and `forward`.
     ///
     /// # Arguments
-    /// * `cell_ref` - The cell reference (e.g., "A1") to move to.
+    /// * `cell_ref` - The cell reference (e.g., "A1") to move to, or `!last` for the cell
+    ///   visited before the current one.
     pub fn goto_cell(&mut self, cell_ref: &str) {
+        let Ok(cell_ref) = self.visits.expand(cell_ref) else {
+            self.status_message = "No earlier cell to go back to".to_string();
+            return;
+        };
+        let cell_ref = cell_ref.as_str();
         if let Some(pos) = cell_ref.chars().position(|c| c.is_ascii_digit()) {
             let col_str = &cell_ref[..pos];
             let row_str = &cell_ref[pos..];
@@ -299,6 +1576,8 @@ impl SpreadsheetApp {
                 let total_rows = self.total_rows;
                 let total_cols = self.total_cols;
                 if row > 0 && row <= total_rows && col < total_cols {
+                    let from = self.selected.unwrap_or((self.start_row, self.start_col));
+                    self.visits.visit(from, (row_index, col));
                     self.selected = Some((row_index, col));
                     self.status_message = format!("Moved to cell {}", cell_ref);
                     return;
@@ -307,59 +1586,354 @@ impl SpreadsheetApp {
         }
         self.status_message = format!("Invalid cell reference: {}", cell_ref);
     }
+
+    /// Moves the selection back or forward through the cells jumped to, for `back`, `forward`,
+    /// Alt+Left and Alt+Right. Cells left outside the sheet by a resize are skipped.
+    ///
+    /// # Arguments
+    /// * `forward` - Whether to move forward, undoing a move back, instead of back.
+    pub fn step_visits(&mut self, forward: bool) {
+        let in_sheet = |(row, col): (usize, usize)| row < self.total_rows && col < self.total_cols;
+        let cell = loop {
+            let cell = if forward {
+                self.visits.forward()
+            } else {
+                self.visits.back()
+            };
+            match cell {
+                Some(cell) if !in_sheet(cell) => continue,
+                cell => break cell,
+            }
+        };
+        match cell {
+            Some((row, col)) => {
+                self.selected = Some((row, col));
+                self.formula_input = self.get_cell_formula(row, col);
+                self.status_message = format!("Moved to cell {}{}", col_label(col), row + 1);
+            }
+            None if forward => self.status_message = "No later cell to go forward to".to_string(),
+            None => self.status_message = "No earlier cell to go back to".to_string(),
+        }
+    }
 }
 
 impl SpreadsheetApp {
-    /// Copies the currently selected cell to the clipboard.
-    pub fn copy_selected_cell(&mut self) {
-        if let Some((row, col)) = self.selected {
-            let key = (row * self.total_cols + col) as u32;
-            if let Some(cell) = self.sheet.get(&key) {
-                self.clipboard = Some(cell.clone());
-                self.clipboard_formula = self.get_cell_formula(row, col);
-                self.status_message = format!("Copied cell {}{}", col_label(col), row + 1);
-            } else {
-                let empty_cell = Cell {
-                    value: Valtype::Int(0),
-                    data: CellData::Empty,
-                    dependents: HashSet::new(),
-                };
-                self.clipboard = Some(empty_cell);
-                self.clipboard_formula = String::new();
-                self.status_message = format!("Copied empty cell {}{}", col_label(col), row + 1);
+    /// Returns the block targeted by clipboard and format operations.
+    ///
+    /// # Returns
+    /// The normalized (top-left, bottom-right) corners of the selected range if one is
+    /// complete, the selected cell on its own otherwise, or `None` if nothing is selected.
+    pub(in crate::gui) fn clipboard_source(&self) -> Option<((usize, usize), (usize, usize))> {
+        match (self.range_start, self.range_end, self.selected) {
+            (Some(start), Some(end), _) => Some((
+                (start.0.min(end.0), start.1.min(end.1)),
+                (start.0.max(end.0), start.1.max(end.1)),
+            )),
+            (_, _, Some(cell)) => Some((cell, cell)),
+            _ => None,
+        }
+    }
+
+    /// Finds the cell Ctrl+Shift+Arrow extends a selection to, as in other spreadsheets.
+    ///
+    /// From a filled cell next to another filled cell, this is the last filled cell before a
+    /// gap; otherwise it is the next filled cell, or the edge of the sheet if there is none.
+    ///
+    /// # Arguments
+    /// * `from` - Tuple of the (row, col) to start from.
+    /// * `step` - Tuple of the (row, col) direction, each -1, 0 or 1.
+    ///
+    /// # Returns
+    /// A tuple `(usize, usize)` with the row and column of the cell found.
+    pub(in crate::gui) fn data_edge(
+        &self,
+        from: (usize, usize),
+        step: (isize, isize),
+    ) -> (usize, usize) {
+        let filled = |(row, col): (usize, usize)| {
+            self.sheet
+                .get(&((row * self.total_cols + col) as CellKey))
+                .is_some_and(|cell| cell.data != CellData::Empty)
+        };
+        let next = |(row, col): (usize, usize)| {
+            let row = row
+                .checked_add_signed(step.0)
+                .filter(|&r| r < self.total_rows)?;
+            let col = col
+                .checked_add_signed(step.1)
+                .filter(|&c| c < self.total_cols)?;
+            Some((row, col))
+        };
+        let Some(mut cell) = next(from) else {
+            return from;
+        };
+        if filled(from) && filled(cell) {
+            while let Some(further) = next(cell).filter(|&further| filled(further)) {
+                cell = further;
             }
         } else {
+            while !filled(cell) {
+                let Some(further) = next(cell) else {
+                    break;
+                };
+                cell = further;
+            }
+        }
+        cell
+    }
+
+    /// Copies the selected range, or the selected cell, to the clipboard.
+    ///
+    /// The values are also put on the system clipboard as TSV, the way the grid shows them,
+    /// so that they can be pasted into other programs.
+    pub fn copy_selected_cell(&mut self) {
+        let Some(((r1, c1), (r2, c2))) = self.clipboard_source() else {
             self.status_message = "No cell selected for copy".to_string();
+            return;
+        };
+        let mut cells = Vec::with_capacity(r2 - r1 + 1);
+        let mut formulas = Vec::with_capacity(r2 - r1 + 1);
+        let mut cell_formats = Vec::with_capacity(r2 - r1 + 1);
+        let mut text = String::new();
+        for row in r1..=r2 {
+            let mut cell_row = Vec::with_capacity(c2 - c1 + 1);
+            let mut formula_row = Vec::with_capacity(c2 - c1 + 1);
+            let mut format_row = Vec::with_capacity(c2 - c1 + 1);
+            let mut fields = Vec::with_capacity(c2 - c1 + 1);
+            for col in c1..=c2 {
+                let key = (row * self.total_cols + col) as CellKey;
+                fields.push(match self.sheet.get(&key) {
+                    Some(cell) if cell.data != CellData::Empty => {
+                        match self.formats.get(&(row, col)) {
+                            Some(format) => format.display(&cell.value),
+                            None => valtype_to_string(&cell.value),
+                        }
+                    }
+                    _ => String::new(),
+                });
+                cell_row.push(self.sheet.get(&key).map_or(
+                    Cell {
+                        value: Valtype::Int(0),
+                        data: CellData::Empty,
+                        dependents: HashSet::new(),
+                    },
+                    Cell::my_clone,
+                ));
+                formula_row.push(self.get_cell_formula(row, col));
+                format_row.push(self.formats.get(&(row, col)).copied().unwrap_or_default());
+            }
+            cells.push(cell_row);
+            formulas.push(formula_row);
+            cell_formats.push(format_row);
+            text.push_str(&fields.join("\t"));
+            text.push('\n');
         }
+        self.clipboard = Some(cells);
+        self.clipboard_formulas = formulas;
+        self.clipboard_cell_formats = cell_formats;
+        self.clipboard_origin = (r1, c1);
+        self.cut_source = None;
+        self.clipboard_text = text;
+        self.clipboard_sync = true;
+        self.status_message = if (r1, c1) == (r2, c2) {
+            format!("Copied cell {}{}", col_label(c1), r1 + 1)
+        } else {
+            format!(
+                "Copied range {}{}:{}{}",
+                col_label(c1),
+                r1 + 1,
+                col_label(c2),
+                r2 + 1
+            )
+        };
     }
-    /// Cuts the currently selected cell, copying it to the clipboard and clearing it.
+    /// Cuts the selected range, or the selected cell, copying it to the clipboard and clearing it.
     pub fn cut_selected_cell(&mut self) {
+        let Some(((r1, c1), (r2, c2))) = self.clipboard_source() else {
+            self.status_message = "No cell selected for cut".to_string();
+            return;
+        };
         self.copy_selected_cell();
-        if let Some((row, col)) = self.selected {
-            let key = (row * self.total_cols + col) as u32;
-            if let std::collections::hash_map::Entry::Occupied(mut e) = self.sheet.entry(key) {
-                let empty_cell = Cell {
-                    value: Valtype::Int(0),
-                    data: CellData::Empty,
-                    dependents: HashSet::new(),
-                };
-                e.insert(empty_cell);
-                self.status_message = format!("Moved cell {}{}", col_label(col), row + 1);
-            } else {
-                self.status_message = format!("No data to cut at {}{}", col_label(col), row + 1);
+        self.cut_source = Some(((r1, c1), (r2, c2)));
+        let mut moved = false;
+        self.begin_undo_group();
+        for row in r1..=r2 {
+            for col in c1..=c2 {
+                let key = (row * self.total_cols + col) as CellKey;
+                if self.sheet.contains_key(&key) {
+                    self.push_undo_action(row, col);
+                }
+                if let std::collections::hash_map::Entry::Occupied(mut e) = self.sheet.entry(key) {
+                    let empty_cell = Cell {
+                        value: Valtype::Int(0),
+                        data: CellData::Empty,
+                        dependents: HashSet::new(),
+                    };
+                    e.insert(empty_cell);
+                    moved = true;
+                }
+                self.reindex_cell(key);
             }
+        }
+        self.end_undo_group();
+        self.status_message = if moved {
+            format!(
+                "Moved {}",
+                self.status_message.trim_start_matches("Copied ")
+            )
         } else {
-            self.status_message = "No cell selected for cut".to_string();
+            format!("No data to cut at {}{}", col_label(c1), r1 + 1)
+        };
+    }
+
+    /// Clears the contents of the selected range, or the selected cell, as one undo step.
+    ///
+    /// Cleared cells read as empty, and the formulas referring to them are recalculated once.
+    /// Formats are kept. Nothing is cleared if a cell of the block is locked.
+    pub fn clear_selected_cells(&mut self) {
+        let Some(((r1, c1), (r2, c2))) = self.clipboard_source() else {
+            self.status_message = "No cell selected to clear".to_string();
+            return;
+        };
+        // Only the cells holding something are visited, as a block may span whole rows
+        let mut filled: Vec<(usize, usize)> = self
+            .sheet
+            .iter()
+            .filter(|(_, cell)| cell.data != CellData::Empty)
+            .map(|(&key, _)| {
+                (
+                    key as usize / self.total_cols,
+                    key as usize % self.total_cols,
+                )
+            })
+            .filter(|&(row, col)| (r1..=r2).contains(&row) && (c1..=c2).contains(&col))
+            .collect();
+        filled.sort_unstable();
+        if let Err(status) = self.locks.check(filled.iter().copied()) {
+            self.status_message = EvalStatus::message(Err(status)).to_string();
+            return;
+        }
+        self.begin_undo_group();
+        for &(row, col) in &filled {
+            self.push_undo_action(row, col);
+        }
+        let _ = parser::clear_cells(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+            ((r1, c1), (r2, c2)),
+        );
+        self.end_undo_group();
+        for &(row, col) in &filled {
+            self.reindex_cell((row * self.total_cols + col) as CellKey);
+        }
+        self.validations.recheck(&self.sheet, self.total_cols);
+        self.formula_input.clear();
+        self.status_message = format!(
+            "Cleared {} cell(s) in {}{}:{}{}",
+            filled.len(),
+            col_label(c1),
+            r1 + 1,
+            col_label(c2),
+            r2 + 1
+        );
+    }
+
+    /// Selects a range or cell and clears it, as in `clear A1:B5`.
+    ///
+    /// # Arguments
+    /// * `range` - The range or cell to clear.
+    pub fn clear_command(&mut self, range: &str) {
+        let corners = if range.contains(':') {
+            parse_range(range, self.total_rows, self.total_cols)
+        } else {
+            parse_cell_name(range)
+                .filter(|&(row, col)| row < self.total_rows && col < self.total_cols)
+                .map(|cell| (cell, cell))
+        };
+        let Some((start, end)) = corners else {
+            self.status_message = EvalStatus::message(Err(EvalStatus::InvalidRange)).to_string();
+            return;
+        };
+        self.selected = Some(start);
+        self.range_start = Some(start);
+        self.range_end = Some(end);
+        self.is_selecting_range = false;
+        self.clear_selected_cells();
+    }
+
+    /// Sorts the rows of a range as one undo step, as in `sort A1:C10 by B desc natural`.
+    ///
+    /// Nothing is sorted if a cell of the range is locked.
+    ///
+    /// # Arguments
+    /// * `args` - The range to sort, followed by the options of the `sort` command.
+    pub fn sort_command(&mut self, args: &str) {
+        let total_dims = (self.total_rows, self.total_cols);
+        let spec = match parse_sort_args(args, self.total_rows, self.total_cols) {
+            Ok(spec) => spec,
+            Err(status) => {
+                self.status_message = EvalStatus::message(Err(status)).to_string();
+                return;
+            }
+        };
+        let ((r1, c1), (r2, c2)) = spec.corners;
+        let range = (r1..=r2).flat_map(|row| (c1..=c2).map(move |col| (row, col)));
+        if let Err(status) = self.locks.check(range.clone()) {
+            self.status_message = EvalStatus::message(Err(status)).to_string();
+            return;
+        }
+        // Both the cells that hold something now and the ones that will are saved for undo
+        let mut touched: Vec<(usize, usize)> = range
+            .filter(|&(row, col)| {
+                self.sheet
+                    .get(&((row * self.total_cols + col) as CellKey))
+                    .is_some_and(|cell| cell.data != CellData::Empty)
+            })
+            .collect();
+        if let Ok(formulas) = sort_formulas(&self.sheet, &spec, total_dims) {
+            touched.extend(formulas.into_iter().map(|(row, col, _)| (row, col)));
+        }
+        touched.sort_unstable();
+        touched.dedup();
+        self.begin_undo_group();
+        for &(row, col) in &touched {
+            self.push_undo_action(row, col);
         }
+        let result = sort_range(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            total_dims,
+            &spec,
+        );
+        self.end_undo_group();
+        for &(row, col) in &touched {
+            self.reindex_cell((row * self.total_cols + col) as CellKey);
+        }
+        self.validations.recheck(&self.sheet, self.total_cols);
+        self.status_message = match result.status {
+            Ok(()) => format!(
+                "Sorted {} rows of {}:{}",
+                r2 - r1 + 1,
+                to_cell_name(r1, c1),
+                to_cell_name(r2, c2)
+            ),
+            Err(status) => EvalStatus::message(Err(status)).to_string(),
+        };
     }
 
     /// Pushes the current cell state to the undo stack.
     ///
+    /// While an undo group is open the state is added to it, so that the whole operation is
+    /// undone in one step; otherwise it forms a group of its own.
+    ///
     /// # Arguments
     /// * `row` - The row index of the cell.
     /// * `col` - The column index of the cell.
     fn push_undo_action(&mut self, row: usize, col: usize) {
-        let key = (row * self.total_cols + col) as u32;
+        let key = (row * self.total_cols + col) as CellKey;
 
         let old_cell = match self.sheet.get(&key) {
             Some(cell) => cell.clone(),
@@ -371,149 +1945,496 @@ impl SpreadsheetApp {
         };
 
         let old_formula = self.get_cell_formula(row, col);
-        self.undo_stack.push(UndoAction {
+        let action = UndoAction {
             position: (row, col),
             old_cell,
             old_formula,
-        });
+        };
         self.redo_stack.clear();
+        if let Some(group) = self.open_undo_group.as_mut() {
+            group.actions.push(action);
+            return;
+        }
+        self.push_undo_group(UndoGroup {
+            actions: vec![action],
+        });
+    }
 
+    /// Pushes a group to the undo stack, dropping the oldest group past `max_undo_levels`.
+    fn push_undo_group(&mut self, group: UndoGroup) {
+        self.undo_stack.push(group);
         if self.undo_stack.len() > self.max_undo_levels {
             self.undo_stack.remove(0);
         }
     }
 
-    /// Undoes the last action, restoring the previous cell state.
-    pub fn undo(&mut self) {
-        if let Some(action) = self.undo_stack.pop() {
+    /// Starts collecting cell changes into one undo group, until `end_undo_group`.
+    fn begin_undo_group(&mut self) {
+        self.open_undo_group = Some(UndoGroup::default());
+    }
+
+    /// Closes the open undo group, pushing it to the undo stack if any cell changed.
+    fn end_undo_group(&mut self) {
+        if let Some(group) = self.open_undo_group.take()
+            && !group.actions.is_empty()
+        {
+            self.push_undo_group(group);
+        }
+    }
+
+    /// Restores the cells of an undo or redo group, last change first.
+    ///
+    /// # Arguments
+    /// * `group` - The group to restore.
+    ///
+    /// # Returns
+    /// * `UndoGroup` - The states the cells held before, which restore them again.
+    fn restore_undo_group(&mut self, group: UndoGroup) -> UndoGroup {
+        let total_dims = (self.total_rows, self.total_cols);
+        let mut inverse = UndoGroup::default();
+        for action in group.actions.into_iter().rev() {
             let (row, col) = action.position;
-            let idx = (row as u32) * (self.total_cols as u32) + (col as u32);
-            // Save current state for redo
+            let idx = (row as CellKey) * (self.total_cols as CellKey) + (col as CellKey);
+            // Save current state to restore it again
             let current_cell = self.sheet.get(&idx).cloned().unwrap_or(Cell {
                 value: Valtype::Int(0),
                 data: CellData::Empty,
                 dependents: HashSet::new(),
             });
             let current_formula = self.get_cell_formula(row, col);
-
-            self.redo_stack.push(UndoAction {
+            inverse.actions.push(UndoAction {
                 position: (row, col),
                 old_cell: current_cell.clone(), // Clone here
                 old_formula: current_formula,
             });
-            *self.sheet.get_mut(&idx).unwrap() = action.old_cell;
-            // Restore previous state
+            self.sheet.insert(idx, action.old_cell);
             self.formula_input = action.old_formula;
 
             // Update selection
             self.selected = Some((row, col));
 
             // Recalculate dependencies
-            let total_rows = self.total_rows;
-            let total_cols = self.total_cols;
-
-            parser::update_and_recalc(
+            let _ = parser::update_and_recalc(
                 &mut self.sheet,
                 &mut self.ranged,
                 &mut self.is_range,
-                (total_rows, total_cols),
+                total_dims,
                 row,
                 col,
                 current_cell,
             );
+            self.reindex_cell(idx);
+        }
+        self.validations.recheck(&self.sheet, self.total_cols);
+        inverse
+    }
+
+    /// Describes the cells of an undo group for the status bar, e.g. "cell B2" or "6 cells".
+    fn undo_group_label(group: &UndoGroup) -> String {
+        match group.actions.as_slice() {
+            [action] => {
+                let (row, col) = action.position;
+                format!("cell {}{}", col_label(col), row + 1)
+            }
+            actions => format!("{} cells", actions.len()),
+        }
+    }
 
-            self.status_message = format!("Undid change to cell {}{}", col_label(col), row + 1);
+    /// Undoes the last action, restoring the previous state of every cell it changed.
+    pub fn undo(&mut self) {
+        if let Some(group) = self.undo_stack.pop() {
+            let label = Self::undo_group_label(&group);
+            let inverse = self.restore_undo_group(group);
+            self.redo_stack.push(inverse);
+            self.status_message = format!("Undid change to {}", label);
         } else {
             self.status_message = "Nothing to undo".to_string();
         }
     }
-    /// Pastes the clipboard content to the selected cell.
+    /// Pastes the clipboard block with its top-left cell at the selected cell.
+    ///
+    /// Formulas are pasted as written, and copied empty cells clear their target. Nothing is
+    /// pasted if the block would extend past the edge of the sheet. After a cut the block is
+    /// moved instead: references to its cells, inside or outside it, follow them.
     pub fn paste_to_selected_cell(&mut self) {
-        if let Some((row, col)) = self.selected {
-            // Create local copies of any data needed from immutable borrows
-            let clipboard_data = self.clipboard.clone();
-            let clipboard_formula_copy = self.clipboard_formula.clone();
-
-            // Now proceed with mutable operations
-            if let Some(copied_cell) = clipboard_data {
-                // Safe to mutably borrow self now
-                self.push_undo_action(row, col);
-
-                if !clipboard_formula_copy.is_empty() {
-                    self.formula_input = clipboard_formula_copy;
-                    self.update_selected_cell();
-                } else {
-                    let total_rows = self.total_rows;
-                    let total_cols = self.total_cols;
-                    let idx = (row as u32) * (total_cols as u32) + (col as u32);
-                    let old_cell = self.sheet.get(&idx).cloned().unwrap_or(Cell {
-                        value: Valtype::Int(0),
-                        data: CellData::Empty,
-                        dependents: HashSet::new(),
-                    });
-                    *self.sheet.get_mut(&idx).unwrap() = copied_cell;
-                    // Recalculate dependencies
-                    parser::update_and_recalc(
-                        &mut self.sheet,
-                        &mut self.ranged,
-                        &mut self.is_range,
-                        (total_rows, total_cols),
-                        row,
-                        col,
-                        old_cell,
-                    );
-                }
+        self.paste_block(false);
+    }
 
-                self.status_message = format!("Pasted to cell {}{}", col_label(col), row + 1);
-            } else {
-                self.status_message = "Nothing to paste".to_string();
+    /// Pastes the clipboard block like `paste_to_selected_cell`, shifting relative references.
+    ///
+    /// Each reference moves by the distance between the copied and pasted positions, so `A1+1`
+    /// copied from B1 to B2 becomes `A2+1`. Parts marked with '$' stay fixed. Nothing is pasted
+    /// if a shifted reference would leave the sheet.
+    pub fn paste_relative_to_selected_cell(&mut self) {
+        self.paste_block(true);
+    }
+
+    /// Pastes one part of the clipboard block at a cell, as `paste_special` does.
+    ///
+    /// `values` writes each copied value as a constant, so the pasted cells no longer depend on
+    /// anything; copied errors have no constant and leave their target as it is. `formulas`
+    /// pastes the formulas with their references shifted, as `paste_rel` does, and `formats`
+    /// pastes only the display formats, leaving the contents alone.
+    ///
+    /// # Arguments
+    /// * `args` - The part to paste, `values`, `formulas` or `formats`, optionally followed
+    ///   by the cell to paste at instead of the selected cell.
+    pub fn paste_special(&mut self, args: &str) {
+        let (mode, cell_ref) = match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [mode] => (mode, None),
+            [mode, cell_ref] => (mode, Some(cell_ref)),
+            _ => {
+                self.status_message =
+                    "Usage: paste_special values|formulas|formats [cell]".to_string();
+                return;
             }
-        } else {
+        };
+        if !matches!(mode, "values" | "formulas" | "formats") {
+            self.status_message = format!(
+                "Unknown paste_special option: {} (expected values, formulas or formats)",
+                mode
+            );
+            return;
+        }
+        if let Some(cell_ref) = cell_ref {
+            match parse_cell_name(cell_ref)
+                .filter(|&(row, col)| row < self.total_rows && col < self.total_cols)
+            {
+                Some(cell) => self.selected = Some(cell),
+                None => {
+                    self.status_message = format!("Invalid cell reference: {}", cell_ref);
+                    return;
+                }
+            }
+        }
+        if mode == "formulas" {
+            self.paste_relative_to_selected_cell();
+            return;
+        }
+        let Some((row, col)) = self.selected else {
             self.status_message = "No cell selected for paste".to_string();
+            return;
+        };
+        let Some(block) = self.clipboard.clone() else {
+            self.status_message = "Nothing to paste".to_string();
+            return;
+        };
+        let height = block.len();
+        let width = block.first().map_or(0, Vec::len);
+        if row + height > self.total_rows || col + width > self.total_cols {
+            self.status_message = format!(
+                "Cannot paste {}x{} block at {}{}: it extends past the sheet",
+                height,
+                width,
+                col_label(col),
+                row + 1
+            );
+            return;
+        }
+        match mode {
+            "values" => {
+                let mut skipped = 0;
+                self.begin_undo_group();
+                for (i, cell_row) in block.iter().enumerate() {
+                    for (j, cell) in cell_row.iter().enumerate() {
+                        match (&cell.data, &cell.value) {
+                            (CellData::Empty, _) => self.paste_formula(row + i, col + j, ""),
+                            (_, Valtype::Int(value)) => {
+                                self.paste_formula(row + i, col + j, &value.to_string())
+                            }
+                            _ => skipped += 1,
+                        }
+                    }
+                }
+                self.end_undo_group();
+                self.formula_input.clear();
+                self.status_message = format!(
+                    "Pasted values of {}x{} block at {}{}",
+                    height,
+                    width,
+                    col_label(col),
+                    row + 1
+                );
+                if skipped > 0 {
+                    self.status_message
+                        .push_str(&format!(", skipping {} error cell(s)", skipped));
+                }
+            }
+            _ => {
+                for (i, format_row) in self.clipboard_cell_formats.clone().iter().enumerate() {
+                    for (j, &format) in format_row.iter().enumerate() {
+                        if format == CellFormat::default() {
+                            self.formats.remove(&(row + i, col + j));
+                        } else {
+                            self.formats.insert((row + i, col + j), format);
+                        }
+                    }
+                }
+                self.status_message = format!(
+                    "Pasted formats of {}x{} block at {}{}",
+                    height,
+                    width,
+                    col_label(col),
+                    row + 1
+                );
+            }
         }
+        self.selected = Some((row, col));
     }
 
-    /// Redoes the last undone action, restoring the next cell state.
-    pub fn redo(&mut self) {
-        if let Some(action) = self.redo_stack.pop() {
-            let (row, col) = action.position;
-
-            // Save current state for undo
-            let idx = (row as u32) * (self.total_cols as u32) + (col as u32);
-            let current_cell = self.sheet.get(&idx).cloned().unwrap_or(Cell {
-                value: Valtype::Int(0),
-                data: CellData::Empty,
-                dependents: HashSet::new(),
-            });
-            let current_formula = self.get_cell_formula(row, col);
-
-            self.undo_stack.push(UndoAction {
-                position: (row, col),
-                old_cell: current_cell.clone(), // Clone here
-                old_formula: current_formula,
-            });
-
-            // Restore redo state
-            *self.sheet.get_mut(&idx).unwrap() = action.old_cell;
-            self.formula_input = action.old_formula;
+    /// Pastes text from the system clipboard at the selected cell.
+    ///
+    /// Text copied from this sheet pastes the copied block, formulas and all. Other text is
+    /// read as a TSV or CSV table and each field entered into the cell at its place, starting
+    /// at the selected cell; empty fields leave their cell as it is. Nothing is pasted if the
+    /// table would extend past the edge of the sheet.
+    ///
+    /// # Arguments
+    /// * `text` - The text on the system clipboard, or `None` if there was none.
+    pub fn paste_from_system(&mut self, text: Option<&str>) {
+        let Some(text) = text.filter(|text| {
+            self.clipboard.is_none()
+                || text.trim_end_matches(['\r', '\n']).replace("\r\n", "\n")
+                    != self.clipboard_text.trim_end_matches('\n')
+        }) else {
+            self.paste_to_selected_cell();
+            return;
+        };
+        let Some((row, col)) = self.selected else {
+            self.status_message = "No cell selected for paste".to_string();
+            return;
+        };
+        let table = clipboard_table(text);
+        let height = table.len();
+        let width = table.iter().map(Vec::len).max().unwrap_or(0);
+        if height == 0 || width == 0 {
+            self.status_message = "Nothing to paste".to_string();
+            return;
+        }
+        if row + height > self.total_rows || col + width > self.total_cols {
+            self.status_message = format!(
+                "Cannot paste {}x{} block at {}{}: it extends past the sheet",
+                height,
+                width,
+                col_label(col),
+                row + 1
+            );
+            return;
+        }
+        let mut rejected = Vec::new();
+        self.begin_undo_group();
+        for (i, fields) in table.iter().enumerate() {
+            for (j, field) in fields.iter().enumerate() {
+                let formula = field.trim().strip_prefix('=').unwrap_or(field.trim());
+                if formula.is_empty() {
+                    continue;
+                }
+                self.selected = Some((row + i, col + j));
+                self.formula_input = formula.to_string();
+                self.update_selected_cell();
+                if !self.status_message.starts_with("Updated cell") {
+                    rejected.push(format!("{}{}", col_label(col + j), row + i + 1));
+                }
+            }
+        }
+        self.end_undo_group();
+        self.selected = Some((row, col));
+        self.formula_input.clear();
+        self.status_message = format!(
+            "Pasted {}x{} block of text at {}{}",
+            height,
+            width,
+            col_label(col),
+            row + 1
+        );
+        if !rejected.is_empty() {
+            self.status_message
+                .push_str(&format!(", rejected in {}", rejected.join(", ")));
+        }
+    }
 
-            // Update selection
+    /// Writes one pasted formula to a cell, as part of the open undo group, checking the
+    /// validation rules as a typed edit does.
+    ///
+    /// # Arguments
+    /// * `row` - The row index of the cell.
+    /// * `col` - The column index of the cell.
+    /// * `formula` - The formula to write, or an empty string to clear the cell.
+    fn paste_formula(&mut self, row: usize, col: usize, formula: &str) {
+        if !formula.is_empty() {
             self.selected = Some((row, col));
+            self.formula_input = formula.to_string();
+            self.update_selected_cell();
+            return;
+        }
+        // An empty copied cell clears the target but keeps its dependents, which may then
+        // break a rule
+        self.push_undo_action(row, col);
+        let idx = (row * self.total_cols + col) as CellKey;
+        let old_cell = self.sheet.get(&idx).cloned().unwrap_or(Cell {
+            value: Valtype::Int(0),
+            data: CellData::Empty,
+            dependents: HashSet::new(),
+        });
+        let mut new_cell = old_cell.clone();
+        new_cell.reset();
+        self.sheet.insert(idx, new_cell);
+        let _ = validated_update(
+            &mut self.validations,
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            (self.total_rows, self.total_cols),
+            (row, col),
+            old_cell,
+        );
+        self.reindex_cell(idx);
+    }
 
-            // Recalculate dependencies
-            let total_rows = self.total_rows;
-            let total_cols = self.total_cols;
-            parser::update_and_recalc(
-                &mut self.sheet,
-                &mut self.ranged,
-                &mut self.is_range,
-                (total_rows, total_cols),
-                row,
-                col,
-                current_cell,
+    /// Pastes the clipboard block at the selected cell.
+    ///
+    /// # Arguments
+    /// * `relative` - Whether references are shifted by the paste offset.
+    fn paste_block(&mut self, relative: bool) {
+        let Some((row, col)) = self.selected else {
+            self.status_message = "No cell selected for paste".to_string();
+            return;
+        };
+        let Some(block) = self.clipboard.clone() else {
+            self.status_message = "Nothing to paste".to_string();
+            return;
+        };
+        let height = block.len();
+        let width = block.first().map_or(0, Vec::len);
+        if row + height > self.total_rows || col + width > self.total_cols {
+            self.status_message = format!(
+                "Cannot paste {}x{} block at {}{}: it extends past the sheet",
+                height,
+                width,
+                col_label(col),
+                row + 1
+            );
+            return;
+        }
+
+        let formulas = if let Some(source) = self.cut_source {
+            // A cut block moves: references between its own cells follow them
+            let mut formulas = self.clipboard_formulas.clone();
+            for (cell_row, formula_row) in block.iter().zip(formulas.iter_mut()) {
+                for (cell, formula) in cell_row.iter().zip(formula_row.iter_mut()) {
+                    if let Some(data) = move_cell_data(&cell.data, source, (row, col)) {
+                        *formula = formula_string(&Cell {
+                            value: cell.value.clone(),
+                            data,
+                            dependents: HashSet::new(),
+                        });
+                    }
+                }
+            }
+            formulas
+        } else if relative {
+            let (d_row, d_col) = (
+                row as isize - self.clipboard_origin.0 as isize,
+                col as isize - self.clipboard_origin.1 as isize,
+            );
+            let mut formulas = Vec::with_capacity(height);
+            for (i, cell_row) in block.iter().enumerate() {
+                let mut formula_row = Vec::with_capacity(width);
+                for (j, cell) in cell_row.iter().enumerate() {
+                    let Some(data) =
+                        shift_cell_data(&cell.data, d_row, d_col, self.total_rows, self.total_cols)
+                    else {
+                        self.status_message = format!(
+                            "Cannot paste: a reference in {}{} would leave the sheet",
+                            col_label(col + j),
+                            row + i + 1
+                        );
+                        return;
+                    };
+                    formula_row.push(formula_string(&Cell {
+                        value: cell.value.clone(),
+                        data,
+                        dependents: HashSet::new(),
+                    }));
+                }
+                formulas.push(formula_row);
+            }
+            formulas
+        } else {
+            self.clipboard_formulas.clone()
+        };
+        // Formulas elsewhere that referred to a cut block follow it to its new place
+        let mut followers = Vec::new();
+        if let Some(source) = self.cut_source {
+            for (&key, cell) in &self.sheet {
+                let (r, c) = (
+                    key as usize / self.total_cols,
+                    key as usize % self.total_cols,
+                );
+                if (row..row + height).contains(&r) && (col..col + width).contains(&c) {
+                    continue;
+                }
+                if let Some(data) = move_cell_data(&cell.data, source, (row, col)) {
+                    followers.push((
+                        r,
+                        c,
+                        formula_string(&Cell {
+                            value: cell.value.clone(),
+                            data,
+                            dependents: HashSet::new(),
+                        }),
+                    ));
+                }
+            }
+        }
+        self.begin_undo_group();
+        for (i, formula_row) in formulas.iter().enumerate() {
+            for (j, formula) in formula_row.iter().enumerate() {
+                self.paste_formula(row + i, col + j, formula);
+            }
+        }
+        for (r, c, formula) in &followers {
+            self.selected = Some((*r, *c));
+            self.formula_input = formula.clone();
+            self.update_selected_cell();
+        }
+        self.end_undo_group();
+        self.selected = Some((row, col));
+        self.formula_input.clear();
+        if self.cut_source.take().is_some() {
+            // A moved block is pasted once, as its source no longer holds it
+            self.clipboard = None;
+            self.clipboard_formulas.clear();
+            self.status_message = format!(
+                "Moved {}x{} block to {}{}, updating {} referring formula(s)",
+                height,
+                width,
+                col_label(col),
+                row + 1,
+                followers.len()
             );
+            return;
+        }
+        self.status_message = if (height, width) == (1, 1) {
+            format!("Pasted to cell {}{}", col_label(col), row + 1)
+        } else {
+            format!(
+                "Pasted {}x{} block at {}{}",
+                height,
+                width,
+                col_label(col),
+                row + 1
+            )
+        };
+    }
 
-            self.status_message = format!("Redid change to cell {}{}", col_label(col), row + 1);
+    /// Redoes the last undone action, restoring the next state of every cell it changed.
+    pub fn redo(&mut self) {
+        if let Some(group) = self.redo_stack.pop() {
+            let label = Self::undo_group_label(&group);
+            let inverse = self.restore_undo_group(group);
+            self.push_undo_group(inverse);
+            self.status_message = format!("Redid change to {}", label);
         } else {
             self.status_message = "Nothing to redo".to_string();
         }
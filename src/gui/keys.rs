@@ -0,0 +1,273 @@
+//! # Keys Module
+//! The keyboard shortcuts of the GUI. Each action has one or more shortcuts, such as `Ctrl+C`
+//! for copy, and a TOML file given with `--keys <file>` can change them:
+//!
+//! ```toml
+//! copy = "Ctrl+E"
+//! redo = ["Ctrl+Y", "Ctrl+Shift+Z"]
+//! ```
+//!
+//! Actions the file does not mention keep their default shortcuts.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use eframe::egui;
+use serde::Deserialize;
+
+/// The actions that can be bound to a shortcut.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(in crate::gui) enum KeyAction {
+    Save,
+    Copy,
+    Paste,
+    Cut,
+    Undo,
+    Redo,
+    Back,
+    Forward,
+}
+
+impl KeyAction {
+    /// Every action, in the order `keys` lists them.
+    const ALL: [KeyAction; 8] = [
+        KeyAction::Save,
+        KeyAction::Copy,
+        KeyAction::Paste,
+        KeyAction::Cut,
+        KeyAction::Undo,
+        KeyAction::Redo,
+        KeyAction::Back,
+        KeyAction::Forward,
+    ];
+
+    /// The name of the action in the keys file.
+    fn name(self) -> &'static str {
+        match self {
+            KeyAction::Save => "save",
+            KeyAction::Copy => "copy",
+            KeyAction::Paste => "paste",
+            KeyAction::Cut => "cut",
+            KeyAction::Undo => "undo",
+            KeyAction::Redo => "redo",
+            KeyAction::Back => "back",
+            KeyAction::Forward => "forward",
+        }
+    }
+}
+
+/// A key pressed together with a set of modifiers, e.g. `Ctrl+Shift+Z`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(in crate::gui) struct Shortcut {
+    ctrl: bool,
+    shift: bool,
+    alt: bool,
+    key: egui::Key,
+}
+
+impl Shortcut {
+    /// Parses a shortcut written as modifiers and a key joined by '+'.
+    ///
+    /// # Arguments
+    /// * `text` - The shortcut, e.g. "Ctrl+Shift+Z" (modifier names ignore case).
+    ///
+    /// # Returns
+    /// * `Result<Shortcut, String>` - The shortcut, or a message naming the part that is not
+    ///   a modifier or key.
+    ///
+    /// # Examples
+    /// ```
+    /// let redo = Shortcut::parse("ctrl+shift+Z")?;
+    /// assert_eq!(redo.to_string(), "Ctrl+Shift+Z");
+    /// ```
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let key_name = parts.pop().unwrap_or_default();
+        let key = egui::Key::from_name(key_name)
+            .or_else(|| egui::Key::from_name(&key_name.to_ascii_uppercase()))
+            .ok_or_else(|| format!("Unknown key '{}' in '{}'", key_name, text))?;
+        let mut shortcut = Shortcut {
+            ctrl: false,
+            shift: false,
+            alt: false,
+            key,
+        };
+        for part in parts {
+            match part.to_ascii_lowercase().as_str() {
+                "ctrl" => shortcut.ctrl = true,
+                "shift" => shortcut.shift = true,
+                "alt" => shortcut.alt = true,
+                _ => return Err(format!("Unknown modifier '{}' in '{}'", part, text)),
+            }
+        }
+        Ok(shortcut)
+    }
+
+    /// Checks whether the shortcut was pressed this frame.
+    ///
+    /// egui turns Ctrl+C, Ctrl+X and Ctrl+V into clipboard events instead of key presses, so
+    /// those shortcuts are matched against the events.
+    ///
+    /// # Arguments
+    /// * `input` - The input state of the frame.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the key was pressed with exactly these modifiers.
+    fn pressed(&self, input: &egui::InputState) -> bool {
+        let modifiers = input.modifiers;
+        if (modifiers.ctrl, modifiers.shift, modifiers.alt) != (self.ctrl, self.shift, self.alt) {
+            return false;
+        }
+        let clipboard = |event: &egui::Event| {
+            matches!(
+                (self.key, event),
+                (egui::Key::C, egui::Event::Copy)
+                    | (egui::Key::X, egui::Event::Cut)
+                    | (egui::Key::V, egui::Event::Paste(_))
+            )
+        };
+        input.key_pressed(self.key) || (self.ctrl && input.events.iter().any(clipboard))
+    }
+}
+
+impl fmt::Display for Shortcut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (held, name) in [
+            (self.ctrl, "Ctrl+"),
+            (self.shift, "Shift+"),
+            (self.alt, "Alt+"),
+        ] {
+            if held {
+                f.write_str(name)?;
+            }
+        }
+        f.write_str(self.key.name())
+    }
+}
+
+/// One or several shortcuts for an action in the keys file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Bound {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The shortcuts of every action.
+///
+/// # Fields
+/// * `bindings` - Each action with its shortcuts, in the order of `KeyAction::ALL`.
+#[derive(Clone, Debug)]
+pub(in crate::gui) struct KeyMap {
+    bindings: Vec<(KeyAction, Vec<Shortcut>)>,
+}
+
+impl Default for KeyMap {
+    /// The usual shortcuts: Ctrl+S, Ctrl+C, Ctrl+V, Ctrl+X, Ctrl+Z, Ctrl+Y or Ctrl+Shift+Z,
+    /// and Alt+Left and Alt+Right to go back and forward through the cells jumped to.
+    fn default() -> Self {
+        let shortcuts = |texts: &[&str]| {
+            texts
+                .iter()
+                .map(|text| Shortcut::parse(text).unwrap())
+                .collect::<Vec<_>>()
+        };
+        KeyMap {
+            bindings: vec![
+                (KeyAction::Save, shortcuts(&["Ctrl+S"])),
+                (KeyAction::Copy, shortcuts(&["Ctrl+C"])),
+                (KeyAction::Paste, shortcuts(&["Ctrl+V"])),
+                (KeyAction::Cut, shortcuts(&["Ctrl+X"])),
+                (KeyAction::Undo, shortcuts(&["Ctrl+Z"])),
+                (KeyAction::Redo, shortcuts(&["Ctrl+Y", "Ctrl+Shift+Z"])),
+                (KeyAction::Back, shortcuts(&["Alt+ArrowLeft"])),
+                (KeyAction::Forward, shortcuts(&["Alt+ArrowRight"])),
+            ],
+        }
+    }
+}
+
+impl KeyMap {
+    /// Reads the shortcuts from the text of a keys file, over the defaults.
+    ///
+    /// # Arguments
+    /// * `source` - The TOML text, mapping action names to a shortcut or a list of them.
+    ///
+    /// # Returns
+    /// * `Result<KeyMap, String>` - The shortcuts, or a message describing the first entry
+    ///   that is not valid.
+    ///
+    /// # Examples
+    /// ```
+    /// let keys = KeyMap::parse("copy = \"Ctrl+E\"\nredo = [\"Ctrl+Y\"]")?;
+    /// assert!(keys.describe().contains("copy Ctrl+E"));
+    /// ```
+    fn parse(source: &str) -> Result<Self, String> {
+        let entries: BTreeMap<String, Bound> =
+            toml::from_str(source).map_err(|e| format!("Keys file error: {}", e))?;
+        let mut keys = KeyMap::default();
+        for (name, bound) in entries {
+            let action = KeyAction::ALL
+                .into_iter()
+                .find(|action| action.name() == name)
+                .ok_or_else(|| format!("Unknown action '{}'", name))?;
+            let texts = match bound {
+                Bound::One(text) => vec![text],
+                Bound::Many(texts) => texts,
+            };
+            let shortcuts = texts
+                .iter()
+                .map(|text| Shortcut::parse(text))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some((_, bound)) = keys.bindings.iter_mut().find(|(a, _)| *a == action) {
+                *bound = shortcuts;
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Reads the shortcuts from a keys file.
+    ///
+    /// # Arguments
+    /// * `path` - The TOML file to read.
+    ///
+    /// # Returns
+    /// * `Result<KeyMap, String>` - The shortcuts, or a message describing why the file could
+    ///   not be used.
+    pub(in crate::gui) fn load(path: &str) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("File error: {}", e))?;
+        Self::parse(&source)
+    }
+
+    /// Finds the action whose shortcut was pressed this frame.
+    ///
+    /// # Arguments
+    /// * `input` - The input state of the frame.
+    ///
+    /// # Returns
+    /// * `Option<KeyAction>` - The first action with a pressed shortcut, if any.
+    pub(in crate::gui) fn pressed(&self, input: &egui::InputState) -> Option<KeyAction> {
+        self.bindings
+            .iter()
+            .find(|(_, shortcuts)| shortcuts.iter().any(|shortcut| shortcut.pressed(input)))
+            .map(|(action, _)| *action)
+    }
+
+    /// Lists the shortcuts of every action, for the `keys` command.
+    ///
+    /// # Returns
+    /// A `String` such as "copy Ctrl+C, ..., redo Ctrl+Y or Ctrl+Shift+Z".
+    pub(in crate::gui) fn describe(&self) -> String {
+        self.bindings
+            .iter()
+            .map(|(action, shortcuts)| {
+                let shortcuts: Vec<String> = shortcuts.iter().map(Shortcut::to_string).collect();
+                if shortcuts.is_empty() {
+                    format!("{} (none)", action.name())
+                } else {
+                    format!("{} {}", action.name(), shortcuts.join(" or "))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
@@ -1,5 +1,10 @@
+mod collab;
 pub mod gui_defs;
+mod heatmap;
 mod impl_helpers;
+mod keys;
 mod render_gui;
 mod scroll_gui;
+mod sheets;
+mod theme;
 mod utils_gui;
@@ -1,11 +1,142 @@
-use egui::{Color32, Stroke};
+use std::sync::Arc;
+use std::time::Instant;
+
+use egui::{Color32, Galley, Stroke, text::LayoutJob};
 
 use crate::{
-    Valtype,
-    gui::gui_defs::{Direction, SpreadsheetApp, SpreadsheetStyle},
-    gui::utils_gui::{col_label, parse_cell_name},
-    utils::to_indices,
+    CellAddr, CellData, CellKey, EvalStatus, Valtype,
+    gui::gui_defs::{
+        CachedText, ChartKind, Direction, EVALUATION_WAIT, FormulaReference, GridAxis,
+        HeaderAction, SpreadsheetApp, SpreadsheetStyle, TOAST_DURATION,
+    },
+    gui::keys::KeyAction,
+    gui::utils_gui::{col_label, completions, formula_references, parse_cell_name, trailing_word},
+    utils::{
+        compute_range, formula_string, get_range_values, range_parents, spark_range, to_cell_name,
+        to_indices,
+    },
+};
+use spreadsheet::CellError;
+use spreadsheet::format::{
+    Align, CellFormat, FormatChange, Locale, MAX_DECIMALS, parse_locale_args,
 };
+use spreadsheet::lint::Severity;
+use spreadsheet::structure::LineEdit;
+
+/// Moves the text cursor of a text edit to the end of its text.
+///
+/// # Arguments
+/// * `ctx` - The egui context.
+/// * `id` - The id of the text edit.
+/// * `text` - The text it holds.
+fn move_cursor_to_end(ctx: &egui::Context, id: egui::Id, text: &str) {
+    if let Some(mut state) = egui::TextEdit::load_state(ctx, id) {
+        let end = egui::text::CCursor::new(text.chars().count());
+        state
+            .cursor
+            .set_char_range(Some(egui::text::CCursorRange::one(end)));
+        state.store(ctx, id);
+    }
+}
+
+/// Lays out formula text with each cell reference colored by its palette slot.
+///
+/// # Arguments
+/// * `text` - The formula text to lay out.
+/// * `font_id` - The font used for the whole text.
+/// * `text_color` - The color of the non-reference parts of the text.
+/// * `palette` - The reference colors, indexed by `FormulaReference::color_slot`.
+///
+/// # Returns
+/// A `LayoutJob` ready to be turned into a galley by a `TextEdit` layouter.
+fn reference_layout_job(
+    text: &str,
+    font_id: egui::FontId,
+    text_color: Color32,
+    palette: &[Color32],
+) -> LayoutJob {
+    let mut job = LayoutJob::default();
+    let plain = egui::TextFormat::simple(font_id.clone(), text_color);
+    let mut cursor = 0;
+    for reference in formula_references(text) {
+        job.append(&text[cursor..reference.span.start], 0.0, plain.clone());
+        let color = palette[reference.color_slot % palette.len()];
+        job.append(
+            &text[reference.span.clone()],
+            0.0,
+            egui::TextFormat::simple(font_id.clone(), color),
+        );
+        cursor = reference.span.end;
+    }
+    job.append(&text[cursor..], 0.0, plain);
+    job
+}
+
+/// Shows the context menu of a row or column header.
+///
+/// # Arguments
+/// * `ui` - The menu's UI.
+/// * `is_row` - Whether the header is a row label rather than a column header.
+/// * `index` - The index of the row or column.
+///
+/// # Returns
+/// The action picked, or `None` if nothing was picked this frame.
+fn header_menu(ui: &mut egui::Ui, is_row: bool, index: usize) -> Option<HeaderAction> {
+    let (line, before, after, size) = if is_row {
+        ("row", "above", "below", "height")
+    } else {
+        ("column", "left", "right", "width")
+    };
+    let (insert_before, insert_after, delete) = if is_row {
+        (
+            LineEdit::InsertRow(index),
+            LineEdit::InsertRow(index + 1),
+            LineEdit::DeleteRow(index),
+        )
+    } else {
+        (
+            LineEdit::InsertCol(index),
+            LineEdit::InsertCol(index + 1),
+            LineEdit::DeleteCol(index),
+        )
+    };
+    let items = [
+        (
+            format!("Insert {} {}", line, before),
+            HeaderAction::Edit(insert_before),
+        ),
+        (
+            format!("Insert {} {}", line, after),
+            HeaderAction::Edit(insert_after),
+        ),
+        (format!("Delete {}", line), HeaderAction::Edit(delete)),
+        (
+            format!("Select {}", line),
+            HeaderAction::Select(is_row, index),
+        ),
+        (format!("Hide {}", line), HeaderAction::Hide(is_row, index)),
+        (
+            format!("Reset {}", size),
+            HeaderAction::ResetSize(is_row, index),
+        ),
+        (
+            format!("Unhide all {}s", line),
+            HeaderAction::UnhideAll(is_row),
+        ),
+    ];
+    let mut picked = None;
+    for (i, (label, action)) in items.into_iter().enumerate() {
+        // The edits of the sheet are set apart from the actions on the view
+        if i == 3 {
+            ui.separator();
+        }
+        if ui.button(label).clicked() {
+            picked = Some(action);
+            ui.close_menu();
+        }
+    }
+    picked
+}
 
 impl SpreadsheetApp {
     /// Renders the formula input bar at the top of the UI.
@@ -23,14 +154,41 @@ impl SpreadsheetApp {
                     } else {
                         "Enter command..."
                     };
+                    let formula_mode = self.selected.is_some();
+                    let text_color = self.style.header_text;
+                    let palette = self.style.reference_colors;
+                    let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+                        let font_id = egui::TextStyle::Monospace.resolve(ui.style());
+                        let mut job = if formula_mode {
+                            reference_layout_job(text, font_id, text_color, &palette)
+                        } else {
+                            LayoutJob::simple_singleline(text.to_owned(), font_id, text_color)
+                        };
+                        job.wrap.max_width = wrap_width;
+                        ui.fonts(|f| f.layout_job(job))
+                    };
+                    // Keys picking a completion are taken before the text edit sees them
+                    let completing = self.formula_bar_focused && !self.suggestions.is_empty();
+                    let (accept, step) = if completing {
+                        ui.input_mut(|i| {
+                            let accept = i.consume_key(egui::Modifiers::NONE, egui::Key::Tab);
+                            let up = i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp);
+                            let down = i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown);
+                            (accept, down as isize - up as isize)
+                        })
+                    } else {
+                        (false, 0)
+                    };
                     let response = ui.add(
                         egui::TextEdit::singleline(&mut self.formula_input)
                             .id_salt("command bar")
                             .hint_text(hint)
                             .desired_width(ui.available_width() - 120.0)
                             .font(egui::TextStyle::Monospace)
-                            .text_color(self.style.header_text),
+                            .text_color(self.style.header_text)
+                            .layouter(&mut layouter),
                     );
+                    self.formula_bar_focused = response.has_focus();
                     if self.request_formula_focus {
                         response.request_focus();
                         self.request_formula_focus = false;
@@ -38,6 +196,24 @@ impl SpreadsheetApp {
                     if response.gained_focus() {
                         self.focus_on = 2;
                     }
+                    if response.has_focus() && !completing {
+                        self.recall_history(ui.ctx(), response.id);
+                    }
+                    let len = self.suggestions.len() as isize;
+                    if len > 0 {
+                        self.suggestion_pos =
+                            (self.suggestion_pos as isize + step).rem_euclid(len) as usize;
+                    }
+                    let clicked = if response.has_focus() {
+                        self.render_suggestions(ui.ctx(), response.rect)
+                    } else {
+                        None
+                    };
+                    if let Some(pos) = clicked.or(accept.then_some(self.suggestion_pos)) {
+                        self.accept_suggestion(ui.ctx(), response.id, pos);
+                        response.request_focus();
+                    }
+                    self.update_suggestions();
                     let process_formula = ui
                         .add(
                             egui::Button::new(
@@ -51,8 +227,12 @@ impl SpreadsheetApp {
                         .clicked()
                         || ((self.focus_on == 2) && ui.input(|i| i.key_pressed(egui::Key::Enter)));
                     if process_formula {
+                        if !self.formula_input.trim().is_empty() {
+                            self.history.push(self.formula_input.clone());
+                        }
+                        self.history_pos = None;
                         if self.selected.is_some() {
-                            self.update_selected_cell();
+                            self.start_cell_update();
                             self.editing_cell = false;
                         } else {
                             let cmd = self.formula_input.clone();
@@ -71,35 +251,149 @@ impl SpreadsheetApp {
             });
     }
 
+    /// Finds the completions of the formula bar text again if it changed since they were found.
+    ///
+    /// Completions are only offered while a cell is selected, since commands do not take
+    /// formulas.
+    fn update_suggestions(&mut self) {
+        if self.suggestions_for == self.formula_input {
+            return;
+        }
+        self.suggestions_for = self.formula_input.clone();
+        self.suggestion_pos = 0;
+        self.suggestions = if self.selected.is_some() {
+            let total_cols = self.total_cols;
+            let cells = self
+                .sheet
+                .iter()
+                .filter(|(_, cell)| cell.data != CellData::Empty)
+                .map(|(&key, _)| (key as usize / total_cols, key as usize % total_cols));
+            completions(&self.formula_input, cells)
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Shows the completions of the formula bar text in a popup under it, with the
+    /// highlighted one selected.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context.
+    /// * `bar` - The rectangle of the formula bar text edit.
+    ///
+    /// # Returns
+    /// The index in `suggestions` of the completion clicked, if any.
+    fn render_suggestions(&self, ctx: &egui::Context, bar: egui::Rect) -> Option<usize> {
+        if self.suggestions.is_empty() {
+            return None;
+        }
+        let mut clicked = None;
+        egui::Area::new(egui::Id::new("formula suggestions"))
+            .order(egui::Order::Foreground)
+            .fixed_pos(bar.left_bottom())
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    for (i, suggestion) in self.suggestions.iter().enumerate() {
+                        let label = egui::RichText::new(suggestion).monospace();
+                        if ui
+                            .selectable_label(i == self.suggestion_pos, label)
+                            .clicked()
+                        {
+                            clicked = Some(i);
+                        }
+                    }
+                });
+            });
+        clicked
+    }
+
+    /// Replaces the word at the end of the formula bar text with a completion, and moves the
+    /// text cursor to the end.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context.
+    /// * `id` - The id of the formula bar text edit.
+    /// * `pos` - The index of the completion in `suggestions`.
+    fn accept_suggestion(&mut self, ctx: &egui::Context, id: egui::Id, pos: usize) {
+        let Some(suggestion) = self.suggestions.get(pos) else {
+            return;
+        };
+        let start = self.formula_input.len() - trailing_word(&self.formula_input).len();
+        self.formula_input.replace_range(start.., suggestion);
+        move_cursor_to_end(ctx, id, &self.formula_input);
+    }
+
+    /// Replaces the formula bar text with an earlier entry on Up, or a later one on Down, and
+    /// moves the text cursor to its end.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context.
+    /// * `id` - The id of the formula bar text edit.
+    fn recall_history(&mut self, ctx: &egui::Context, id: egui::Id) {
+        let (up, down) = ctx.input(|i| {
+            (
+                i.key_pressed(egui::Key::ArrowUp),
+                i.key_pressed(egui::Key::ArrowDown),
+            )
+        });
+        let pos = match (up, down, self.history_pos) {
+            (true, _, None) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (true, _, Some(pos)) => Some(pos.saturating_sub(1)),
+            (false, true, Some(pos)) if pos + 1 < self.history.len() => Some(pos + 1),
+            (false, true, Some(_)) => None,
+            _ => return,
+        };
+        self.history_pos = pos;
+        self.formula_input = pos.map_or_else(String::new, |pos| self.history[pos].clone());
+        move_cursor_to_end(ctx, id, &self.formula_input);
+    }
+
     /// Processes commands entered in the formula bar.
     ///
     /// # Arguments
     /// * `cmd` - The command string to process.
     fn process_command(&mut self, cmd: &str) {
+        log::debug!("command: {}", cmd);
         let mut flag = true;
         match cmd {
             "q" => std::process::exit(0),
             "tr" => self.reset_theme(),
             "undo" => self.undo(),
             "redo" => self.redo(),
+            "back" | "forward" => {
+                self.step_visits(cmd == "forward");
+                flag = false;
+            }
             "help" => self.show_command_help(),
+            "keys" => self.status_message = format!("Keys: {}", self.keys.describe()),
+            "sheetinfo" => self.show_sheet_info(),
+            "clamp_ranges on" | "clamp_ranges off" => {
+                self.clamp_ranges = cmd.ends_with("on");
+                self.status_message = format!("Range clamping {}", &cmd["clamp_ranges ".len()..]);
+            }
             "rainbow1" => {
                 self.style.rainbow = 1;
+                self.theme_changed = true;
             }
             "rainbow2" => {
                 self.style.rainbow = 2;
+                self.theme_changed = true;
             }
             "matrix1" => {
                 self.style.rainbow = 3;
+                self.theme_changed = true;
             }
             "matrix2" => {
                 self.style.rainbow = 5;
+                self.theme_changed = true;
             }
             "matrix3" => {
                 self.style.rainbow = 6;
+                self.theme_changed = true;
             }
             "love" => {
                 self.style.rainbow = 4;
+                self.theme_changed = true;
             }
             _ => {
                 if cmd.starts_with("copy ") {
@@ -112,6 +406,13 @@ impl SpreadsheetApp {
                         self.goto_cell(cell_ref);
                         self.cut_selected_cell();
                     }
+                } else if let Some(args) = cmd.strip_prefix("paste_special ") {
+                    self.paste_special(args);
+                } else if cmd.starts_with("paste_rel ") {
+                    if let Some(cell_ref) = cmd.strip_prefix("paste_rel ") {
+                        self.goto_cell(cell_ref);
+                        self.paste_relative_to_selected_cell();
+                    }
                 } else if cmd.starts_with("paste ") {
                     if let Some(cell_ref) = cmd.strip_prefix("paste ") {
                         self.goto_cell(cell_ref);
@@ -133,9 +434,26 @@ impl SpreadsheetApp {
                         self.status_message = "Please enter frequency".to_string();
                     } else if let Ok(count) = arg.parse::<f32>() {
                         self.style.frequency = count * 0.2 / 10.0;
+                        self.theme_changed = true;
                     } else {
                         self.status_message = format!("Unknown command: {}", cmd);
                     }
+                } else if let Some(args) = cmd.strip_prefix("theme ") {
+                    self.theme_command(args.trim());
+                } else if let Some(args) = cmd.strip_prefix("resize ") {
+                    self.resize(args);
+                } else if cmd == "split" || cmd.starts_with("split ") {
+                    self.split_view(&cmd["split".len()..]);
+                } else if let Some(cell_ref) = cmd.strip_prefix("watch ") {
+                    self.watch_cell(cell_ref, true);
+                } else if let Some(cell_ref) = cmd.strip_prefix("unwatch ") {
+                    self.watch_cell(cell_ref, false);
+                } else if let Some(range) = cmd.strip_prefix("lock ") {
+                    self.lock_cells(range, true);
+                } else if let Some(range) = cmd.strip_prefix("unlock ") {
+                    self.lock_cells(range, false);
+                } else if let Some(args) = cmd.strip_prefix("window ") {
+                    self.set_grid_window(args);
                 } else if let Some(stripper) = cmd.strip_prefix("w") {
                     let arg = &stripper.trim();
                     if arg.is_empty() {
@@ -145,12 +463,71 @@ impl SpreadsheetApp {
                     } else {
                         self.status_message = format!("Unknown command: {}", cmd);
                     }
-                } else if cmd.starts_with("csv ") {
-                    let filename = cmd.strip_prefix("csv ").unwrap().trim();
-                    self.export_to_csv(filename);
-                } else if cmd.starts_with("fcsv ") {
-                    let filename = cmd.strip_prefix("fcsv ").unwrap().trim();
-                    self.export_formulas_to_csv(filename);
+                } else if let Some(args) = cmd.strip_prefix("csv ") {
+                    match parse_locale_args(args) {
+                        Some((filename, locale)) => self.export_to_csv(filename, locale),
+                        None => self.status_message = format!("Unknown command: {}", cmd),
+                    }
+                } else if let Some(args) = cmd.strip_prefix("fcsv ") {
+                    match parse_locale_args(args) {
+                        Some((filename, locale)) => self.export_formulas_to_csv(filename, locale),
+                        None => self.status_message = format!("Unknown command: {}", cmd),
+                    }
+                } else if let Some(filename) = cmd.strip_prefix("save ") {
+                    self.save_workbook(filename.trim());
+                } else if let Some(filename) = cmd.strip_prefix("load ") {
+                    self.load_workbook(filename.trim());
+                } else if let Some(args) = cmd.strip_prefix("open ") {
+                    match parse_locale_args(args) {
+                        Some((filename, locale)) => self.open_csv(filename, locale),
+                        None => self.status_message = format!("Unknown command: {}", cmd),
+                    }
+                } else if let Some(args) = cmd.strip_prefix("heatmap ") {
+                    self.export_heatmap(args);
+                } else if let Some(filename) = cmd.strip_prefix("graph ") {
+                    self.export_graph(filename.trim());
+                } else if let Some(filename) = cmd.strip_prefix("package ") {
+                    self.package_submission(filename.trim());
+                } else if let Some(args) = cmd.strip_prefix("autototal ") {
+                    self.autototal(args);
+                } else if let Some(cell_ref) = cmd.strip_prefix("deps ") {
+                    self.audit_cell(cell_ref, true);
+                } else if let Some(cell_ref) = cmd.strip_prefix("precedents ") {
+                    self.audit_cell(cell_ref, false);
+                } else if let Some(args) = cmd.strip_prefix("name range ") {
+                    self.define_name(args);
+                } else if let Some(args) = cmd.strip_prefix("validate ") {
+                    self.validate(args);
+                } else if let Some(args) = cmd.strip_prefix("fill ") {
+                    self.fill(args);
+                } else if let Some(range) = cmd.strip_prefix("clear ") {
+                    self.clear_command(range.trim());
+                } else if let Some(args) = cmd.strip_prefix("join ") {
+                    self.join_server(args);
+                } else if cmd == "leave" {
+                    self.leave_server();
+                } else if let Some(args) = cmd.strip_prefix("sort ") {
+                    self.sort_command(args);
+                } else if let Some(args) = cmd.strip_prefix("format ") {
+                    self.format_cells(args);
+                } else if let Some(args) = cmd.strip_prefix("freeze ") {
+                    self.freeze(args);
+                } else if let Some(args) = cmd.strip_prefix("sheet ") {
+                    self.sheet_command(args);
+                } else if let Some(args) = cmd.strip_prefix("chart ") {
+                    self.open_chart(args);
+                } else if let Some(args) = cmd.strip_prefix("histogram ") {
+                    self.histogram(args);
+                } else if cmd == "lint" || cmd.starts_with("lint ") {
+                    self.lint(cmd.trim_start_matches("lint"));
+                } else if let Some(args) = cmd.strip_prefix("replace ") {
+                    match args.split_whitespace().collect::<Vec<_>>()[..] {
+                        [from, to] => self.replace_text(from, to),
+                        _ => self.status_message = format!("Unknown command: {}", cmd),
+                    }
+                } else if cmd == "find" || cmd.starts_with("find ") {
+                    self.open_search(cmd.trim_start_matches("find"));
+                    flag = false;
                 } else if let Some(stripper) = cmd.strip_prefix("s") {
                     let arg = &stripper.trim();
                     if arg.is_empty() {
@@ -182,13 +559,26 @@ impl SpreadsheetApp {
                     let parts: Vec<&str> = cmd.splitn(2, '=').map(str::trim).collect();
                     if parts.len() == 2 {
                         let (cell_ref, formula) = (parts[0], parts[1]);
-                        let (row, col) = to_indices(cell_ref);
-                        self.selected = Some((row, col));
-                        self.formula_input = formula.to_string();
-                        self.update_selected_cell();
-                        self.formula_input.clear();
-                        self.selected = None;
-                        self.request_formula_focus = true;
+                        match to_indices(cell_ref) {
+                            Ok((row, col)) if row < self.total_rows && col < self.total_cols => {
+                                self.selected = Some((row, col));
+                                self.formula_input = formula.to_string();
+                                self.update_selected_cell();
+                                self.formula_input.clear();
+                                self.selected = None;
+                                self.request_formula_focus = true;
+                            }
+                            Ok(_) => {
+                                self.status_message = format!(
+                                    "{}: {} out of bounds",
+                                    EvalStatus::message(Err(EvalStatus::InvalidRange)),
+                                    cell_ref
+                                )
+                            }
+                            Err(status) => {
+                                self.status_message = EvalStatus::message(Err(status)).to_string()
+                            }
+                        }
                     } else {
                         self.status_message = format!("unrecognized command: {}", cmd);
                     }
@@ -204,13 +594,18 @@ impl SpreadsheetApp {
 
     /// Resets the theme to its default settings.
     fn reset_theme(&mut self) {
-        self.style = SpreadsheetStyle::default();
+        // The mode stays, and picks the light or dark colors on the next frame
+        self.style = SpreadsheetStyle {
+            mode: self.style.mode,
+            ..SpreadsheetStyle::default()
+        };
+        self.theme_changed = true;
         self.status_message = "Theme reset to default".to_string();
     }
 
     /// Displays a help message with available commands.
     fn show_command_help(&mut self) {
-        self.status_message = "Available commands: w,a,s,d Option<Amount> (navigation), q (quit), tr (theme_reset), help, goto [cell], scroll_to [cell], undo, redo, copy [cell], cut[cell], paste [cell], csv <filename>, fcsv <filename>, cell=formula,themes..".to_string();
+        self.status_message = "Available commands: w,a,s,d Option<Amount> (navigation), q (quit), tr (theme_reset), theme save|load <name>, theme list, theme light|dark|system, help, keys, goto [cell], scroll_to [cell], undo, redo, back, forward, copy [cell], cut[cell], paste [cell], paste_rel [cell], paste_special values|formulas|formats [cell], csv <filename> [--locale de], fcsv <filename> [--locale de], open <filename> [--locale de], save <file.json>, load <file.json>, package <file.zip>, heatmap <file.png> <range> [viridis|heat|gray], graph <file.dot>, window <rows> <cols>, resize <rows> <cols>, autototal <range> [sum|avg|min|max|stdev], fill <range> = <formula>, clear <range>, sort <range> [by <col>] [desc] [natural] [nocase] [order=<list>|<range>], name range <name> <range>, validate <range> int <min>..<max> [mark], format <range> bold|italic|left|center|right|thousands|decimals=N|clear, freeze <cell>|off, split [v|h|off], watch [cell], unwatch [cell], lock <range>, unlock <range>, sheet add [name], sheet <name>, join <host:port> <name>, leave, deps [cell], precedents [cell], histogram <range> [bins=N] -> <cell> [chart], chart bar|line <range>, lint [depth], sheetinfo, find [text], replace <from> <to>, clamp_ranges on|off, cell=formula, cell=Sheet2!A1, themes..".to_string();
     }
 
     /// Renders the "Scroll to" input field and button.
@@ -250,6 +645,105 @@ impl SpreadsheetApp {
         }
     }
 
+    /// Renders the button freezing the panes at the selected cell, or unfreezing them.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    fn render_freeze_toggle(&mut self, ui: &mut egui::Ui) {
+        let label = if self.frozen == (0, 0) {
+            "Freeze panes"
+        } else {
+            "Unfreeze panes"
+        };
+        let clicked = ui
+            .add(
+                egui::Button::new(
+                    egui::RichText::new(label)
+                        .size(self.style.font_size)
+                        .color(self.style.selected_cell_text),
+                )
+                .fill(self.style.selected_cell_bg),
+            )
+            .on_hover_text("Keep the rows above and columns left of the selected cell in view")
+            .clicked();
+        if clicked {
+            if self.frozen != (0, 0) {
+                self.freeze("off");
+            } else if let Some((row, col)) = self.selected {
+                self.freeze(&to_cell_name(row, col));
+            }
+        }
+    }
+
+    /// Renders the buttons formatting the selected range, or the selected cell.
+    ///
+    /// Toggles such as bold follow the top-left cell: if it is bold, the button unbolds the
+    /// whole selection.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    fn render_format_toolbar(&mut self, ui: &mut egui::Ui) {
+        let Some(corners) = self.clipboard_source() else {
+            return;
+        };
+        let current = self.formats.get(&corners.0).copied().unwrap_or_default();
+        let decimals = current.decimals;
+        let buttons = [
+            (
+                egui::RichText::new("B").strong(),
+                "Bold",
+                FormatChange::Bold(!current.bold),
+            ),
+            (
+                egui::RichText::new("I").italics(),
+                "Italic",
+                FormatChange::Italic(!current.italic),
+            ),
+            (
+                egui::RichText::new("Left"),
+                "Align left",
+                FormatChange::Align(Align::Left),
+            ),
+            (
+                egui::RichText::new("Center"),
+                "Center",
+                FormatChange::Align(Align::Center),
+            ),
+            (
+                egui::RichText::new("Right"),
+                "Align right",
+                FormatChange::Align(Align::Right),
+            ),
+            (
+                egui::RichText::new("1,000"),
+                "Thousands separator",
+                FormatChange::Thousands(!current.thousands),
+            ),
+            (
+                egui::RichText::new(".0+"),
+                "More decimal places",
+                FormatChange::Decimals((decimals + 1).min(MAX_DECIMALS)),
+            ),
+            (
+                egui::RichText::new(".0-"),
+                "Fewer decimal places",
+                FormatChange::Decimals(decimals.saturating_sub(1)),
+            ),
+        ];
+        for (text, hint, change) in buttons {
+            let text = text
+                .size(self.style.font_size)
+                .color(self.style.selected_cell_text);
+            let clicked = ui
+                .add(egui::Button::new(text).fill(self.style.selected_cell_bg))
+                .on_hover_text(hint)
+                .clicked();
+            if clicked {
+                self.apply_format(corners, &[change]);
+            }
+        }
+    }
+
     /// Renders the color picker and theme settings UI.
     ///
     /// # Arguments
@@ -264,6 +758,7 @@ impl SpreadsheetApp {
         // Original color picking logic when no rainbow mode is active
         let mut base_color = self.style.prev_base_color;
         if ui.color_edit_button_srgba(&mut base_color).changed() {
+            self.theme_changed = true;
             self.style.get_cell_bg = None;
             self.style.rainbow = 0;
             fn adjust_brightness(color: Color32, factor: f32) -> Color32 {
@@ -372,7 +867,9 @@ impl SpreadsheetApp {
 
             ui.horizontal(|ui| {
                 ui.label("Matrix Speed:");
-                ui.add(egui::Slider::new(&mut self.style.frequency, 0.05..=0.5).logarithmic(true));
+                let speed =
+                    egui::Slider::new(&mut self.style.frequency, 0.05..=0.5).logarithmic(true);
+                self.theme_changed |= ui.add(speed).changed();
             });
 
             ui.ctx().request_repaint();
@@ -654,7 +1151,14 @@ impl SpreadsheetApp {
     }
     /// Processes the "scroll to" action, updating the view to the specified cell.
     fn process_scroll_to_cell(&mut self) {
-        if let Some((target_row, target_col)) = parse_cell_name(&self.scroll_to_cell) {
+        let target = self
+            .visits
+            .expand(&self.scroll_to_cell)
+            .ok()
+            .and_then(|cell_ref| parse_cell_name(&cell_ref));
+        if let Some((target_row, target_col)) = target {
+            let from = self.selected.unwrap_or((self.start_row, self.start_col));
+            self.visits.visit(from, (target_row, target_col));
             self.start_row = target_row;
             self.start_col = target_col;
             self.should_reset_scroll = true;
@@ -711,7 +1215,7 @@ impl SpreadsheetApp {
 
         if (enter_pressed || save_clicked) && !self.save_filename.is_empty() {
             let filename = self.save_filename.clone();
-            self.export_to_csv(&filename);
+            self.export_to_csv(&filename, Locale::En);
             self.show_save_dialog = false;
             self.focus_on = 0;
         }
@@ -736,6 +1240,397 @@ impl SpreadsheetApp {
         }
     }
 
+    /// Renders the histogram chart window, if one is open.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to show the window in.
+    fn render_histogram_chart(&mut self, ctx: &egui::Context) {
+        let Some(chart) = &self.histogram_chart else {
+            return;
+        };
+        let mut open = true;
+        egui::Window::new(format!("Histogram of {}", chart.title))
+            .open(&mut open)
+            .resizable(true)
+            .default_size([420.0, 260.0])
+            .show(ctx, |ui| {
+                let (rect, _) = ui.allocate_exact_size(ui.available_size(), egui::Sense::hover());
+                let painter = ui.painter_at(rect);
+                let label_height = self.style.font_size + 4.0;
+                let plot = egui::Rect::from_min_max(
+                    rect.min,
+                    egui::pos2(rect.max.x, rect.max.y - label_height),
+                );
+                let max_count = chart.bins.iter().map(|b| b.2).max().unwrap_or(0).max(1);
+                let slot = plot.width() / chart.bins.len() as f32;
+                let font = egui::FontId::monospace(self.style.font_size * 0.8);
+                for (i, &(lower, upper, count)) in chart.bins.iter().enumerate() {
+                    let x = plot.min.x + slot * i as f32;
+                    let height = plot.height() * count as f32 / max_count as f32;
+                    let bar = egui::Rect::from_min_max(
+                        egui::pos2(x + 1.0, plot.max.y - height),
+                        egui::pos2(x + slot - 1.0, plot.max.y),
+                    );
+                    painter.rect_filled(bar, 0.0, self.style.selected_cell_bg);
+                    painter.text(
+                        egui::pos2(bar.center().x, bar.min.y - 2.0),
+                        egui::Align2::CENTER_BOTTOM,
+                        count.to_string(),
+                        font.clone(),
+                        self.style.header_text,
+                    );
+                    painter.text(
+                        egui::pos2(bar.center().x, rect.max.y),
+                        egui::Align2::CENTER_BOTTOM,
+                        format!("{}-{}", lower, upper),
+                        font.clone(),
+                        self.style.cell_text,
+                    );
+                }
+                painter.line_segment(
+                    [plot.left_bottom(), plot.right_bottom()],
+                    self.style.grid_line,
+                );
+            });
+        if !open {
+            self.histogram_chart = None;
+        }
+    }
+
+    /// Renders a window for each chart of a range of the open sheet, with the current values
+    /// of its cells. Closing a window drops its chart.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to show the windows in.
+    fn render_charts(&mut self, ctx: &egui::Context) {
+        let total_dims = (self.total_rows, self.total_cols);
+        // A resize may have cut a range out of the sheet
+        self.charts
+            .retain(|chart| chart.corners.1.0 < total_dims.0 && chart.corners.1.1 < total_dims.1);
+        let mut closed = Vec::new();
+        for chart in &self.charts {
+            let ((r1, c1), (r2, c2)) = chart.corners;
+            // Text and error values have no height, and leave a gap
+            let values: Vec<Option<i32>> = get_range_values(
+                &self.sheet,
+                total_dims,
+                CellAddr::new(r1, c1),
+                CellAddr::new(r2, c2),
+            )
+            .into_iter()
+            .flatten()
+            .map(|value| match value {
+                Valtype::Int(v) => Some(v),
+                _ => None,
+            })
+            .collect();
+            let kind = match chart.kind {
+                ChartKind::Bar => "Bar chart",
+                ChartKind::Line => "Line chart",
+            };
+            let mut open = true;
+            egui::Window::new(format!(
+                "{} of {}:{}",
+                kind,
+                to_cell_name(r1, c1),
+                to_cell_name(r2, c2)
+            ))
+            .id(egui::Id::new(("chart", chart)))
+            .open(&mut open)
+            .resizable(true)
+            .default_size([420.0, 260.0])
+            .show(ctx, |ui| self.paint_chart(ui, chart.kind, &values));
+            if !open {
+                closed.push(*chart);
+            }
+        }
+        self.charts.retain(|chart| !closed.contains(chart));
+    }
+
+    /// Draws the values of a chart over the space left in a window, scaled so that zero and
+    /// every value fit.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    /// * `kind` - Whether to draw bars or a line.
+    /// * `values` - The values of the cells in row-major order, `None` where there is no
+    ///   number.
+    fn paint_chart(&self, ui: &mut egui::Ui, kind: ChartKind, values: &[Option<i32>]) {
+        let (rect, _) = ui.allocate_exact_size(ui.available_size(), egui::Sense::hover());
+        let painter = ui.painter_at(rect);
+        let numbers = values.iter().flatten().map(|&v| v as f32);
+        let low = numbers.clone().fold(0.0, f32::min);
+        let high = numbers.fold(0.0, f32::max).max(low + 1.0);
+        let y = |v: f32| rect.max.y - rect.height() * (v - low) / (high - low);
+        let slot = rect.width() / values.len().max(1) as f32;
+        let color = self.style.selected_cell_bg;
+        let mut previous: Option<egui::Pos2> = None;
+        for (i, value) in values.iter().enumerate() {
+            let x = rect.min.x + slot * i as f32;
+            let Some(value) = value.map(|v| v as f32) else {
+                previous = None;
+                continue;
+            };
+            match kind {
+                ChartKind::Bar => {
+                    let bar = egui::Rect::from_two_pos(
+                        egui::pos2(x + 1.0, y(0.0)),
+                        egui::pos2(x + slot - 1.0, y(value)),
+                    );
+                    painter.rect_filled(bar, 0.0, color);
+                }
+                ChartKind::Line => {
+                    let point = egui::pos2(x + slot / 2.0, y(value));
+                    if let Some(previous) = previous {
+                        painter.line_segment([previous, point], egui::Stroke::new(2.0, color));
+                    }
+                    painter.circle_filled(point, 3.0, color);
+                    previous = Some(point);
+                }
+            }
+        }
+        painter.line_segment(
+            [
+                egui::pos2(rect.min.x, y(0.0)),
+                egui::pos2(rect.max.x, y(0.0)),
+            ],
+            self.style.grid_line,
+        );
+        let font = egui::FontId::monospace(self.style.font_size * 0.8);
+        for (v, anchor, pos) in [
+            (high, egui::Align2::LEFT_TOP, rect.left_top()),
+            (low, egui::Align2::LEFT_BOTTOM, rect.left_bottom()),
+        ] {
+            painter.text(
+                pos,
+                anchor,
+                v.to_string(),
+                font.clone(),
+                self.style.header_text,
+            );
+        }
+    }
+
+    /// Renders the lint panel, if a report is open. Clicking an issue selects its cell.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to show the window in.
+    fn render_lint_panel(&mut self, ctx: &egui::Context) {
+        let Some(issues) = &self.lint_report else {
+            return;
+        };
+        let mut open = true;
+        let mut clicked = None;
+        egui::Window::new(format!("Lint ({} issues)", issues.len()))
+            .open(&mut open)
+            .resizable(true)
+            .default_size([420.0, 260.0])
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for issue in issues {
+                        let color = match issue.severity() {
+                            Severity::High => self.style.reference_colors[1],
+                            Severity::Medium => self.style.reference_colors[4],
+                            Severity::Low => self.style.cell_text,
+                        };
+                        let text = egui::RichText::new(issue.to_string())
+                            .monospace()
+                            .color(color);
+                        if ui.selectable_label(false, text).clicked() {
+                            clicked = Some(issue.addr);
+                        }
+                    }
+                });
+            });
+        if let Some(addr) = clicked {
+            self.goto_cell(&to_cell_name(addr.row, addr.col));
+        }
+        if !open {
+            self.lint_report = None;
+        }
+    }
+
+    /// Renders the "About this sheet" panel, if it is open.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to show the window in.
+    fn render_sheet_info_panel(&mut self, ctx: &egui::Context) {
+        let Some(info) = &self.sheet_info else {
+            return;
+        };
+        let mut open = true;
+        let mut refresh = false;
+        egui::Window::new("About this sheet")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                for line in info.lines() {
+                    ui.label(
+                        egui::RichText::new(line)
+                            .monospace()
+                            .color(self.style.cell_text),
+                    );
+                }
+                refresh = ui.button("Refresh").clicked();
+            });
+        if refresh {
+            self.show_sheet_info();
+        }
+        if !open {
+            self.sheet_info = None;
+        }
+    }
+
+    /// Draws the window of a cell edit still being recalculated on a worker thread, and
+    /// cancels the recalculation when Esc is pressed.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to draw in.
+    ///
+    /// # Returns
+    /// * `bool` - `true` while the recalculation is running, in which case nothing else is
+    ///   drawn, as the sheet is on the worker thread.
+    fn render_evaluation(&mut self, ctx: &egui::Context) -> bool {
+        let escape = ctx.input(|i| i.key_pressed(egui::Key::Escape));
+        if !self.poll_evaluation(escape) {
+            return false;
+        }
+        let Some(evaluation) = &self.evaluation else {
+            return false;
+        };
+        let (row, col) = evaluation.edit.position;
+        let message = if evaluation.cancel.is_cancelled() {
+            format!("Cancelling {}{}...", col_label(col), row + 1)
+        } else {
+            format!(
+                "Calculating {}{} for {}s... press Esc to cancel",
+                col_label(col),
+                row + 1,
+                evaluation.started.elapsed().as_secs()
+            )
+        };
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.centered_and_justified(|ui| {
+                ui.label(
+                    egui::RichText::new(message)
+                        .size(self.style.font_size)
+                        .color(self.style.header_text),
+                );
+            });
+        });
+        // Look for the end of the recalculation, and update the seconds shown
+        ctx.request_repaint_after(EVALUATION_WAIT);
+        true
+    }
+
+    /// Shows a toast for each watched cell whose value changed during this frame, and draws
+    /// the toasts still on screen in the bottom-right corner, newest last.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to show the toasts in.
+    fn render_toasts(&mut self, ctx: &egui::Context) {
+        let now = Instant::now();
+        for change in self.watches.changes(&self.sheet, self.total_cols) {
+            self.toasts.push((change, now));
+        }
+        self.toasts
+            .retain(|(_, shown)| now.duration_since(*shown) < TOAST_DURATION);
+        if self.toasts.is_empty() {
+            return;
+        }
+        egui::Area::new(egui::Id::new("toasts"))
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::Vec2::new(-16.0, -40.0))
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                for (message, _) in &self.toasts {
+                    egui::Frame::popup(ui.style())
+                        .fill(self.style.header_bg)
+                        .show(ui, |ui| {
+                            ui.label(
+                                egui::RichText::new(format!("watch {}", message))
+                                    .size(self.style.font_size)
+                                    .color(self.style.header_text),
+                            );
+                        });
+                }
+            });
+        // Repaint once the oldest toast is due to disappear
+        let oldest = self.toasts[0].1;
+        ctx.request_repaint_after(TOAST_DURATION.saturating_sub(now.duration_since(oldest)));
+    }
+
+    /// Renders the search panel, if it is open.
+    ///
+    /// Matches are looked up in the search index as the query is typed, and the first one is
+    /// selected. Enter or "Next" moves to the next match and "Prev" to the previous one.
+    /// "Replace all" rewrites the query, as a whole word, in every formula holding it.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context to show the window in.
+    fn render_search_panel(&mut self, ctx: &egui::Context) {
+        if !self.show_search {
+            return;
+        }
+        let mut open = true;
+        let mut step = None;
+        let mut replace = false;
+        let count = self.search_matches().len();
+        egui::Window::new("Find")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut self.search_query)
+                            .hint_text("value, function or cell")
+                            .desired_width(160.0),
+                    );
+                    if response.changed() {
+                        self.search_current = 0;
+                        step = Some(0);
+                    } else if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))
+                    {
+                        step = Some(1);
+                        response.request_focus();
+                    }
+                    if ui.button("Prev").clicked() {
+                        step = Some(-1);
+                    }
+                    if ui.button("Next").clicked() {
+                        step = Some(1);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.replace_with)
+                            .hint_text("replace with")
+                            .desired_width(160.0),
+                    );
+                    let enabled = !self.search_query.trim().is_empty()
+                        && !self.replace_with.trim().is_empty();
+                    if ui
+                        .add_enabled(enabled, egui::Button::new("Replace all"))
+                        .clicked()
+                    {
+                        replace = true;
+                    }
+                });
+                ui.label(match count {
+                    0 => "No matches".to_string(),
+                    n => format!("Match {} of {}", self.search_current.min(n - 1) + 1, n),
+                });
+            });
+        if replace {
+            let (from, to) = (self.search_query.clone(), self.replace_with.clone());
+            self.replace_text(&from, &to);
+        } else if let Some(step) = step {
+            self.step_search(step);
+        }
+        if !open {
+            self.show_search = false;
+        }
+    }
+
     /// Renders a single cell in the spreadsheet grid.
     ///
     /// # Arguments
@@ -759,14 +1654,17 @@ impl SpreadsheetApp {
         if is_selected && self.editing_cell {
             self.render_editable_cell(ui, rect);
         } else {
-            let key = (row * self.total_cols + col) as u32;
-            let text = if let Some(cell) = self.sheet.get(&key) {
-                match &cell.value {
-                    Valtype::Int(n) => n.to_string(),
-                    Valtype::Str(s) => s.as_str().to_string(),
-                }
-            } else {
-                "0".to_string()
+            let key = (row * self.total_cols + col) as CellKey;
+            let format = self.formats.get(&(row, col)).copied().unwrap_or_default();
+            let value = self.sheet.get(&key).map(|cell| &cell.value);
+            let is_error = matches!(value, Some(Valtype::Err(_)));
+            let spark = self
+                .sheet
+                .get(&key)
+                .and_then(|cell| spark_range(cell, (self.total_rows, self.total_cols)));
+            let galley = match spark {
+                Some(_) => None,
+                None => Some(self.cell_galley(ui, (row, col), value.cloned(), format)),
             };
 
             let bg_color = if is_selected {
@@ -785,20 +1683,23 @@ impl SpreadsheetApp {
                 self.style.selected_cell_text
             } else if is_in_range {
                 self.style.range_selection_text
+            } else if is_error {
+                self.style.error_text
             } else {
                 self.style.cell_text
             };
 
             ui.put(
                 rect,
-                egui::Button::new(
-                    egui::RichText::new(text)
-                        .size(self.style.font_size)
-                        .color(text_color),
-                )
-                .fill(bg_color)
-                .stroke(self.style.grid_line),
+                egui::Button::new("")
+                    .fill(bg_color)
+                    .stroke(self.style.grid_line),
             );
+            match (galley, spark) {
+                (Some(galley), _) => self.paint_cell_text(ui, rect, galley, &format, text_color),
+                (None, Some((a, b))) => self.paint_sparkline(ui, rect, a, b, text_color),
+                (None, None) => {}
+            }
 
             let response = ui.interact(
                 rect,
@@ -844,6 +1745,227 @@ impl SpreadsheetApp {
         }
         new_selection
     }
+
+    /// Returns the laid-out text of a cell, from the cache while the cell still holds the
+    /// value and format it was laid out from.
+    ///
+    /// The text is laid out without a color, so the cached galley is drawn in whatever color
+    /// the selection and error state call for.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    /// * `cell` - The (row, col) of the cell.
+    /// * `value` - The value of the cell, `None` if it was never set.
+    /// * `format` - The format of the cell.
+    ///
+    /// # Returns
+    /// The galley to draw in the cell.
+    fn cell_galley(
+        &mut self,
+        ui: &egui::Ui,
+        cell: (usize, usize),
+        value: Option<Valtype>,
+        format: CellFormat,
+    ) -> Arc<Galley> {
+        let value = value.unwrap_or(Valtype::Int(0));
+        let frame = self.text_cache.frame;
+        if let Some(cached) = self.text_cache.cells.get_mut(&cell)
+            && cached.value == value
+            && cached.format == format
+        {
+            cached.frame = frame;
+            return cached.galley.clone();
+        }
+        let job = LayoutJob::single_section(
+            format.display(&value),
+            egui::TextFormat {
+                font_id: egui::FontId::proportional(self.style.font_size),
+                color: Color32::PLACEHOLDER,
+                italics: format.italic,
+                ..Default::default()
+            },
+        );
+        let galley = ui.fonts(|f| f.layout_job(job));
+        self.text_cache.cells.insert(
+            cell,
+            CachedText {
+                value,
+                format,
+                galley: galley.clone(),
+                frame,
+            },
+        );
+        galley
+    }
+
+    /// Starts a frame of the text cache, dropping every entry if the font size or display
+    /// scale changed since the texts were laid out.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context, for the display scale.
+    fn begin_text_cache_frame(&mut self, ctx: &egui::Context) {
+        let cache = &mut self.text_cache;
+        let pixels_per_point = ctx.pixels_per_point();
+        if cache.font_size != self.style.font_size || cache.pixels_per_point != pixels_per_point {
+            cache.cells.clear();
+            cache.font_size = self.style.font_size;
+            cache.pixels_per_point = pixels_per_point;
+        }
+        cache.frame += 1;
+    }
+
+    /// Ends a frame of the text cache, dropping the texts of cells that were not drawn in it,
+    /// such as those scrolled out of view.
+    fn end_text_cache_frame(&mut self) {
+        let frame = self.text_cache.frame;
+        self.text_cache
+            .cells
+            .retain(|_, cached| cached.frame == frame);
+    }
+
+    /// Draws the values of the range of a `SPARK(<range>)` cell as a small line across the
+    /// cell, scaled from the lowest value to the highest. Text and error values leave a gap.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    /// * `rect` - The rectangular area of the cell.
+    /// * `a` - The top-left corner of the range.
+    /// * `b` - The bottom-right corner of the range.
+    /// * `color` - The line color.
+    fn paint_sparkline(
+        &self,
+        ui: &egui::Ui,
+        rect: egui::Rect,
+        a: CellAddr,
+        b: CellAddr,
+        color: Color32,
+    ) {
+        let values: Vec<Option<f32>> =
+            get_range_values(&self.sheet, (self.total_rows, self.total_cols), a, b)
+                .into_iter()
+                .flatten()
+                .map(|value| match value {
+                    Valtype::Int(v) => Some(v as f32),
+                    _ => None,
+                })
+                .collect();
+        let area = rect.shrink2(egui::vec2(4.0, rect.height() * 0.2));
+        let numbers = values.iter().flatten();
+        let low = numbers.clone().copied().fold(f32::INFINITY, f32::min);
+        let high = numbers.copied().fold(f32::NEG_INFINITY, f32::max);
+        let span = (high - low).max(1.0);
+        let step = area.width() / (values.len().max(2) - 1) as f32;
+        let painter = ui.painter_at(rect);
+        let mut previous: Option<egui::Pos2> = None;
+        for (i, value) in values.iter().enumerate() {
+            let Some(value) = value else {
+                previous = None;
+                continue;
+            };
+            let point = egui::pos2(
+                area.min.x + step * i as f32,
+                area.max.y - area.height() * (value - low) / span,
+            );
+            // A point without a neighbour is drawn as a dot
+            match previous {
+                Some(previous) => {
+                    painter.line_segment([previous, point], egui::Stroke::new(1.5, color));
+                }
+                None => {
+                    painter.circle_filled(point, 1.5, color);
+                }
+            }
+            previous = Some(point);
+        }
+    }
+
+    /// Draws the text of a cell in its format, clipped to the cell.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    /// * `rect` - The rectangular area of the cell.
+    /// * `galley` - The laid-out text to draw, from `cell_galley`.
+    /// * `format` - The format of the cell.
+    /// * `color` - The text color.
+    fn paint_cell_text(
+        &self,
+        ui: &egui::Ui,
+        rect: egui::Rect,
+        galley: Arc<Galley>,
+        format: &CellFormat,
+        color: Color32,
+    ) {
+        const PADDING: f32 = 4.0;
+        let x = match format.align {
+            Align::Left => rect.left() + PADDING,
+            Align::Center => rect.center().x - galley.size().x / 2.0,
+            Align::Right => rect.right() - PADDING - galley.size().x,
+        };
+        let pos = egui::pos2(x, rect.center().y - galley.size().y / 2.0);
+        let painter = ui.painter_at(rect);
+        // The default fonts have no bold face, so bold text is drawn twice, slightly apart
+        if format.bold {
+            painter.galley(pos + egui::vec2(0.7, 0.0), galley.clone(), color);
+        }
+        painter.galley(pos, galley, color);
+    }
+
+    /// Returns the references of the formula currently being edited.
+    ///
+    /// References are only reported while a cell's formula is being edited, either in place
+    /// or in the formula bar.
+    ///
+    /// # Returns
+    /// A `Vec<FormulaReference>` to outline in the grid, empty when no formula is being edited.
+    fn highlighted_references(&self) -> Vec<FormulaReference> {
+        if self.selected.is_none() || !(self.editing_cell || self.formula_bar_focused) {
+            return Vec::new();
+        }
+        formula_references(&self.formula_input)
+    }
+
+    /// Finds the cells outlined to audit the formula of the selected cell: the references it
+    /// reads, colored as while editing it, and the cells computed directly from it.
+    ///
+    /// Nothing is reported while a formula is being edited, since its references are outlined
+    /// instead, or while a range is being selected.
+    ///
+    /// # Returns
+    /// A tuple `(Vec<FormulaReference>, Vec<(usize, usize)>)` with the precedents and the
+    /// (row, col) of each dependent.
+    fn audit_highlights(&self) -> (Vec<FormulaReference>, Vec<(usize, usize)>) {
+        let Some((row, col)) = self.selected else {
+            return (Vec::new(), Vec::new());
+        };
+        if self.editing_cell || self.formula_bar_focused || self.range_start.is_some() {
+            return (Vec::new(), Vec::new());
+        }
+        let key = (row * self.total_cols + col) as CellKey;
+        let Some(cell) = self.sheet.get(&key) else {
+            return (Vec::new(), Vec::new());
+        };
+        let precedents = formula_references(&formula_string(cell));
+        // Single references are in `dependents`, while range formulas are found through `ranged`
+        let mut dependents: Vec<CellKey> = cell
+            .dependents
+            .iter()
+            .copied()
+            .chain(range_parents(key, &self.ranged, self.total_cols))
+            .collect();
+        dependents.sort_unstable();
+        dependents.dedup();
+        let dependents = dependents
+            .into_iter()
+            .map(|key| {
+                (
+                    key as usize / self.total_cols,
+                    key as usize % self.total_cols,
+                )
+            })
+            .collect();
+        (precedents, dependents)
+    }
+
     /// Checks if a cell is within the currently selected range.
     ///
     /// # Arguments
@@ -874,22 +1996,70 @@ impl SpreadsheetApp {
     fn render_editable_cell(&mut self, ui: &mut egui::Ui, rect: egui::Rect) {
         let rect =
             egui::Rect::from_min_size(rect.min, egui::Vec2::new(rect.width(), rect.height()));
+        let text_color = self.style.selected_cell_text;
+        let palette = self.style.reference_colors;
+        let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+            let font_id = egui::TextStyle::Body.resolve(ui.style());
+            let mut job = reference_layout_job(text, font_id, text_color, &palette);
+            job.wrap.max_width = wrap_width;
+            ui.fonts(|f| f.layout_job(job))
+        };
         ui.allocate_new_ui(egui::UiBuilder::new().max_rect(rect), |ui| {
             let response = ui.add(
                 egui::TextEdit::singleline(&mut self.formula_input)
+                    .layouter(&mut layouter)
                     .hint_text("Edit...")
                     .text_color(self.style.selected_cell_text)
                     .background_color(self.style.selected_cell_bg)
                     .vertical_align(egui::Align::Center)
-                    .margin(egui::Vec2::new(3.0, 5.0)),
+                    .margin(egui::Vec2::new(3.0, 5.0))
+                    .lock_focus(true),
             );
-            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                self.update_selected_cell();
+            if self.request_cell_focus {
+                response.request_focus();
+                self.request_cell_focus = false;
+            }
+            // Enter commits and moves down, Tab commits and moves right
+            let step = if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                Some((1, 0))
+            } else if response.has_focus() && ui.input(|i| i.key_pressed(egui::Key::Tab)) {
+                Some((0, 1))
+            } else {
+                None
+            };
+            if let Some(step) = step {
+                self.start_cell_update();
                 self.editing_cell = false;
+                response.surrender_focus();
+                self.step_selection(step, (self.visible_rows, self.visible_cols));
             }
         });
     }
 
+    /// Lays out the rows and columns of the grid window.
+    ///
+    /// Frozen rows and columns are drawn pinned above and left of the window, so the window
+    /// leaves room for those it does not cover, and its first cell starts just past them.
+    ///
+    /// # Arguments
+    /// * `start` - Tuple of the (row, col) where the window starts.
+    /// * `end` - Tuple of the (row, col) just past the window.
+    ///
+    /// # Returns
+    /// A tuple of the `GridAxis` of the rows and of the columns.
+    fn grid_axes(&self, start: (usize, usize), end: (usize, usize)) -> (GridAxis, GridAxis) {
+        let cell_size = self.style.cell_size;
+        let heights = &self.grid_sizes.row_heights;
+        let widths = &self.grid_sizes.col_widths;
+        let (frozen_rows, frozen_cols) = self.frozen;
+        let row_lead = GridAxis::new(0, frozen_rows.min(start.0), heights, cell_size.y).total();
+        let col_lead = GridAxis::new(0, frozen_cols.min(start.1), widths, cell_size.x).total();
+        (
+            GridAxis::new(start.0, end.0, heights, cell_size.y).with_lead(row_lead),
+            GridAxis::new(start.1, end.1, widths, cell_size.x).with_lead(col_lead),
+        )
+    }
+
     /// Renders the main spreadsheet grid with cells, headers, and row labels.
     ///
     /// # Arguments
@@ -902,10 +2072,15 @@ impl SpreadsheetApp {
         let cell_size = self.style.cell_size;
         let row_label_width = 30.0;
         let header_height = cell_size.y;
-        let total_cols = self.total_cols.min(self.start_col + 300);
-        let total_rows = self.total_rows.min(self.start_row + 500);
-        let virtual_width = row_label_width + (total_cols - self.start_col) as f32 * cell_size.x;
-        let virtual_height = header_height + (total_rows - self.start_row) as f32 * cell_size.y;
+        let total_cols = self.total_cols.min(self.start_col + self.window_cols);
+        let total_rows = self.total_rows.min(self.start_row + self.window_rows);
+        let (rows, cols) =
+            self.grid_axes((self.start_row, self.start_col), (total_rows, total_cols));
+        let (frozen_rows, frozen_cols) = self.frozen;
+        let pinned_rows = GridAxis::new(0, frozen_rows, &self.grid_sizes.row_heights, cell_size.y);
+        let pinned_cols = GridAxis::new(0, frozen_cols, &self.grid_sizes.col_widths, cell_size.x);
+        let virtual_width = row_label_width + cols.total();
+        let virtual_height = header_height + rows.total();
         let virtual_size = egui::vec2(virtual_width, virtual_height);
         let mut scroll_area = egui::ScrollArea::both()
             .id_salt((self.start_row, self.start_col))
@@ -913,50 +2088,257 @@ impl SpreadsheetApp {
             .auto_shrink([false, false]);
         if self.should_reset_scroll {
             scroll_area = scroll_area.scroll_offset(egui::Vec2::ZERO);
+        } else if let Some(offset) = self.pending_scroll.take() {
+            scroll_area = scroll_area.scroll_offset(offset);
         }
         let mut scroll_offset = egui::Vec2::ZERO;
-        scroll_area.show(ui, |ui| {
+        let mut viewport = egui::Vec2::ZERO;
+        let area = scroll_area.show(ui, |ui| {
             let (virtual_rect, _) = ui.allocate_exact_size(virtual_size, egui::Sense::hover());
             scroll_offset = ui.clip_rect().min - virtual_rect.min;
-            let render_start_col =
-                self.start_col + (scroll_offset.x / cell_size.x).floor() as usize;
-            let render_start_row =
-                self.start_row + (scroll_offset.y / cell_size.y).floor() as usize;
-            let visible_cols = (((ui.available_rect_before_wrap().size().x - row_label_width)
-                / cell_size.x)
-                .ceil() as usize)
-                .max(1)
-                + 1;
-            let visible_rows = total_rows.min(33);
-            for i in render_start_row..(render_start_row + visible_rows).min(total_rows) {
-                for j in render_start_col..(render_start_col + visible_cols).min(total_cols) {
-                    let x = virtual_rect.min.x
-                        + row_label_width
-                        + (j - self.start_col) as f32 * cell_size.x;
-                    let y = virtual_rect.min.y
-                        + header_height
-                        + (i - self.start_row) as f32 * cell_size.y;
-                    let cell_rect = egui::Rect::from_min_size(egui::pos2(x, y), cell_size);
-                    if let Some(selection) = self.render_cell(ui, i, j, cell_rect) {
-                        new_selection = Some(selection);
+            viewport = ui.clip_rect().size();
+            let render_start_col = cols.index_at(scroll_offset.x).max(frozen_cols);
+            let render_start_row = rows.index_at(scroll_offset.y).max(frozen_rows);
+            // Partially visible cells on either edge are rendered too
+            let render_end_col = cols.index_at(scroll_offset.x + viewport.x - row_label_width) + 1;
+            let render_end_row = rows.index_at(scroll_offset.y + viewport.y - header_height) + 1;
+            // Each span is (index, start, size); frozen ones are pinned to the view
+            let origin = virtual_rect.min + egui::vec2(row_label_width, header_height);
+            let pinned_origin = ui.clip_rect().min + egui::vec2(row_label_width, header_height);
+            let scrolled_row_spans: Vec<_> = (render_start_row..render_end_row.min(total_rows))
+                .map(|i| (i, origin.y + rows.start(i), rows.size(i)))
+                .collect();
+            let scrolled_col_spans: Vec<_> = (render_start_col..render_end_col.min(total_cols))
+                .map(|j| (j, origin.x + cols.start(j), cols.size(j)))
+                .collect();
+            let pinned_row_spans: Vec<_> = (0..frozen_rows)
+                .map(|i| {
+                    (
+                        i,
+                        pinned_origin.y + pinned_rows.start(i),
+                        pinned_rows.size(i),
+                    )
+                })
+                .collect();
+            let pinned_col_spans: Vec<_> = (0..frozen_cols)
+                .map(|j| {
+                    (
+                        j,
+                        pinned_origin.x + pinned_cols.start(j),
+                        pinned_cols.size(j),
+                    )
+                })
+                .collect();
+            // Frozen cells are drawn after the cells that scroll beneath them
+            for (row_spans, col_spans) in [
+                (&scrolled_row_spans, &scrolled_col_spans),
+                (&scrolled_row_spans, &pinned_col_spans),
+                (&pinned_row_spans, &scrolled_col_spans),
+                (&pinned_row_spans, &pinned_col_spans),
+            ] {
+                for &(i, y, height) in row_spans.iter().filter(|span| span.2 > 0.0) {
+                    for &(j, x, width) in col_spans.iter().filter(|span| span.2 > 0.0) {
+                        let cell_rect =
+                            egui::Rect::from_min_size(egui::pos2(x, y), egui::vec2(width, height));
+                        if let Some(selection) = self.render_cell(ui, i, j, cell_rect) {
+                            new_selection = Some(selection);
+                        }
                     }
                 }
             }
+            let cell_origin = |row: usize, col: usize| {
+                egui::pos2(origin.x + cols.start(col), origin.y + rows.start(row))
+            };
+            // Outline the cells matching the search query while the search panel is open
+            let matches = if self.show_search {
+                self.search_matches()
+            } else {
+                Vec::new()
+            };
+            for key in matches {
+                let (row, col) = (
+                    key as usize / self.total_cols,
+                    key as usize % self.total_cols,
+                );
+                if row >= rows.end() || col >= cols.end() {
+                    continue;
+                }
+                let outline =
+                    egui::Rect::from_min_max(cell_origin(row, col), cell_origin(row + 1, col + 1));
+                ui.painter().rect_stroke(
+                    outline,
+                    0.0,
+                    Stroke::new(2.0, self.style.search_match),
+                    egui::epaint::StrokeKind::Inside,
+                );
+            }
+            // Outline the cells marked as breaking a validation rule
+            for &key in self.validations.marked() {
+                let (row, col) = (
+                    key as usize / self.total_cols,
+                    key as usize % self.total_cols,
+                );
+                if row >= rows.end() || col >= cols.end() {
+                    continue;
+                }
+                let outline =
+                    egui::Rect::from_min_max(cell_origin(row, col), cell_origin(row + 1, col + 1));
+                ui.painter().rect_stroke(
+                    outline,
+                    0.0,
+                    Stroke::new(2.0, self.style.invalid_cell),
+                    egui::epaint::StrokeKind::Inside,
+                );
+            }
+            // Outline the cells computed from the selected cell; its inputs are outlined below
+            let (precedents, dependents) = self.audit_highlights();
+            for (row, col) in dependents {
+                if row >= rows.end() || col >= cols.end() {
+                    continue;
+                }
+                let outline =
+                    egui::Rect::from_min_max(cell_origin(row, col), cell_origin(row + 1, col + 1));
+                ui.painter().rect_stroke(
+                    outline,
+                    0.0,
+                    Stroke::new(2.0, self.style.dependent_cell),
+                    egui::epaint::StrokeKind::Inside,
+                );
+            }
+            // Outline the cells referenced by the formula being edited, or by the selected one
+            for reference in self.highlighted_references().into_iter().chain(precedents) {
+                let (top, left) = reference.top_left;
+                if top >= self.total_rows || left >= self.total_cols {
+                    continue;
+                }
+                let bottom = reference.bottom_right.0.min(self.total_rows - 1);
+                let right = reference.bottom_right.1.min(self.total_cols - 1);
+                let outline = egui::Rect::from_min_max(
+                    cell_origin(top, left),
+                    cell_origin(bottom + 1, right + 1),
+                );
+                let color = self.style.reference_colors
+                    [reference.color_slot % self.style.reference_colors.len()];
+                ui.painter().rect_stroke(
+                    outline,
+                    0.0,
+                    Stroke::new(2.0, color),
+                    egui::epaint::StrokeKind::Inside,
+                );
+            }
+            // Outline the cells the other clients of a shared sheet have selected, with names
+            for ((row, col), peer) in self.collab.iter().flat_map(|link| &link.cursors) {
+                if *row >= rows.end() || *col >= cols.end() {
+                    continue;
+                }
+                let outline = egui::Rect::from_min_max(
+                    cell_origin(*row, *col),
+                    cell_origin(row + 1, col + 1),
+                );
+                let color =
+                    self.style.reference_colors[peer.color % self.style.reference_colors.len()];
+                ui.painter().rect_stroke(
+                    outline,
+                    0.0,
+                    Stroke::new(2.0, color),
+                    egui::epaint::StrokeKind::Inside,
+                );
+                ui.painter().text(
+                    outline.left_top(),
+                    egui::Align2::LEFT_BOTTOM,
+                    &peer.client,
+                    egui::FontId::proportional(self.style.font_size * 0.75),
+                    color,
+                );
+            }
+            // The fill handle at the bottom-right corner of the selected cell
+            if let Some((row, col)) = self.selected
+                && !self.editing_cell
+                && self.range_start.is_none()
+                && row < rows.end()
+                && col < cols.end()
+            {
+                let handle = egui::Rect::from_center_size(
+                    cell_origin(row + 1, col + 1),
+                    egui::vec2(7.0, 7.0),
+                );
+                let response = ui.interact(
+                    handle,
+                    ui.make_persistent_id("fill_handle"),
+                    egui::Sense::drag(),
+                );
+                if response.hovered() || response.dragged() {
+                    ui.ctx().set_cursor_icon(egui::CursorIcon::Crosshair);
+                }
+                if response.dragged()
+                    && let Some(pointer) = response.interact_pointer_pos()
+                {
+                    let over = (
+                        rows.index_at(pointer.y - origin.y).min(self.total_rows - 1),
+                        cols.index_at(pointer.x - origin.x).min(self.total_cols - 1),
+                    );
+                    self.fill_drag = Some(over);
+                }
+                // Preview the cells to be filled while dragging
+                if let Some(end) = self.fill_drag.and_then(|target| self.fill_end(target)) {
+                    let outline = egui::Rect::from_min_max(
+                        cell_origin(row, col),
+                        cell_origin(end.0 + 1, end.1 + 1),
+                    );
+                    ui.painter().rect_stroke(
+                        outline,
+                        0.0,
+                        Stroke::new(2.0, self.style.selected_cell_bg),
+                        egui::epaint::StrokeKind::Inside,
+                    );
+                }
+                ui.painter()
+                    .rect_filled(handle, 0.0, self.style.selected_cell_bg);
+                if response.drag_stopped()
+                    && let Some(target) = self.fill_drag.take()
+                {
+                    self.drag_fill(target);
+                }
+            }
         });
-        let painter = ui.ctx().layer_painter(egui::LayerId::new(
-            egui::Order::Background,
-            egui::Id::new("pinned_headers"),
-        ));
+        // Rows and columns hidden beneath the frozen ones do not count as visible
+        let first_row = rows.index_at(scroll_offset.y.max(0.0) + pinned_rows.total());
+        let first_col = cols.index_at(scroll_offset.x.max(0.0) + pinned_cols.total());
+        let last_row = rows.index_at(scroll_offset.y + viewport.y - header_height);
+        let last_col = cols.index_at(scroll_offset.x + viewport.x - row_label_width);
+        self.visible_rows = last_row.saturating_sub(first_row).max(1);
+        self.visible_cols = last_col.saturating_sub(first_col).max(1);
+
+        let painter = ui
+            .ctx()
+            .layer_painter(egui::LayerId::new(
+                egui::Order::Background,
+                egui::Id::new("pinned_headers"),
+            ))
+            .with_clip_rect(ui.max_rect());
         let base_x = ui.min_rect().min.x;
         let base_y = ui.min_rect().min.y;
+        // Headers of frozen columns and rows are painted last, over the scrolled ones; hidden
+        // ones are not painted at all
+        let header_cols = (first_col..(last_col + 2).min(total_cols))
+            .map(|j| (j, base_x - scroll_offset.x + cols.start(j), cols.size(j)))
+            .chain(
+                (0..frozen_cols).map(|j| (j, base_x + pinned_cols.start(j), pinned_cols.size(j))),
+            )
+            .filter(|&(_, _, width)| width > 0.0);
+        let header_rows = (first_row..(last_row + 2).min(total_rows))
+            .map(|i| (i, base_y - scroll_offset.y + rows.start(i), rows.size(i)))
+            .chain(
+                (0..frozen_rows).map(|i| (i, base_y + pinned_rows.start(i), pinned_rows.size(i))),
+            )
+            .filter(|&(_, _, height)| height > 0.0);
+        let mut header_action = None;
         // --- Column Headers (pinned vertically, scrolled horizontally) ---
-        for col_idx in self.start_col..total_cols {
-            let header_x = base_x - scroll_offset.x
-                + (col_idx - self.start_col) as f32 * cell_size.x
-                + row_label_width;
+        for (col_idx, x, width) in header_cols {
+            let header_x = x + row_label_width;
             let header_rect = egui::Rect::from_min_size(
                 egui::pos2(header_x.max(base_x), base_y),
-                egui::vec2(cell_size.x, header_height),
+                egui::vec2(width, header_height),
             );
             painter.rect_filled(header_rect, 0.0, self.style.header_bg);
             painter.text(
@@ -968,15 +2350,39 @@ impl SpreadsheetApp {
             );
             use egui::epaint::StrokeKind;
             painter.rect_stroke(header_rect, 0.0, self.style.grid_line, StrokeKind::Middle);
+            let id = ui.make_persistent_id(("col_header", col_idx));
+            let response = ui.interact(header_rect, id, egui::Sense::click());
+            if response.clicked() {
+                header_action = Some(HeaderAction::Select(false, col_idx));
+            }
+            response.context_menu(|ui| header_action = header_menu(ui, false, col_idx));
+            // Dragging the right edge of the header resizes the column
+            let edge_x = header_x + width;
+            if edge_x > base_x + row_label_width {
+                let handle = egui::Rect::from_center_size(
+                    egui::pos2(edge_x, base_y + header_height / 2.0),
+                    egui::vec2(6.0, header_height),
+                );
+                let id = ui.make_persistent_id(("resize_col", col_idx));
+                let response = ui.interact(handle, id, egui::Sense::click_and_drag());
+                if response.hovered() || response.dragged() {
+                    ui.ctx().set_cursor_icon(egui::CursorIcon::ResizeHorizontal);
+                }
+                if response.double_clicked() {
+                    self.grid_sizes.col_widths.remove(&col_idx);
+                } else if response.dragged() {
+                    let width = (width + response.drag_delta().x)
+                        .clamp(GridAxis::MIN_SIZE, GridAxis::MAX_SIZE);
+                    self.grid_sizes.col_widths.insert(col_idx, width);
+                }
+            }
         }
         // --- Row Labels (pinned horizontally, scrolled vertically) ---
-        for row_idx in self.start_row..total_rows {
-            let header_y = base_y - scroll_offset.y
-                + (row_idx - self.start_row) as f32 * cell_size.y
-                + header_height;
+        for (row_idx, y, height) in header_rows {
+            let header_y = y + header_height;
             let row_rect = egui::Rect::from_min_size(
                 egui::pos2(base_x, header_y.max(base_y)),
-                egui::vec2(row_label_width, cell_size.y),
+                egui::vec2(row_label_width, height),
             );
             painter.rect_filled(row_rect, 0.0, self.style.header_bg);
             painter.text(
@@ -988,6 +2394,32 @@ impl SpreadsheetApp {
             );
             use egui::epaint::StrokeKind;
             painter.rect_stroke(row_rect, 0.0, self.style.grid_line, StrokeKind::Inside);
+            let id = ui.make_persistent_id(("row_header", row_idx));
+            let response = ui.interact(row_rect, id, egui::Sense::click());
+            if response.clicked() {
+                header_action = Some(HeaderAction::Select(true, row_idx));
+            }
+            response.context_menu(|ui| header_action = header_menu(ui, true, row_idx));
+            // Dragging the bottom edge of the label resizes the row
+            let edge_y = header_y + height;
+            if edge_y > base_y + header_height {
+                let handle = egui::Rect::from_center_size(
+                    egui::pos2(base_x + row_label_width / 2.0, edge_y),
+                    egui::vec2(row_label_width, 6.0),
+                );
+                let id = ui.make_persistent_id(("resize_row", row_idx));
+                let response = ui.interact(handle, id, egui::Sense::click_and_drag());
+                if response.hovered() || response.dragged() {
+                    ui.ctx().set_cursor_icon(egui::CursorIcon::ResizeVertical);
+                }
+                if response.double_clicked() {
+                    self.grid_sizes.row_heights.remove(&row_idx);
+                } else if response.dragged() {
+                    let height = (height + response.drag_delta().y)
+                        .clamp(GridAxis::MIN_SIZE, GridAxis::MAX_SIZE);
+                    self.grid_sizes.row_heights.insert(row_idx, height);
+                }
+            }
         }
         // --- Corner Cell (optional) ---
         let corner_rect = egui::Rect::from_min_size(
@@ -997,10 +2429,132 @@ impl SpreadsheetApp {
         use egui::epaint::StrokeKind;
         painter.rect_filled(corner_rect, 0.0, self.style.header_bg);
         painter.rect_stroke(corner_rect, 0.0, self.style.grid_line, StrokeKind::Outside);
+        if let Some(action) = header_action {
+            self.header_action(action);
+        }
+        if !self.should_reset_scroll {
+            self.page_grid_window(
+                ui,
+                area.id,
+                scroll_offset,
+                (first_row, first_col),
+                &rows,
+                &cols,
+            );
+        }
         self.should_reset_scroll = false;
         new_selection
     }
 
+    /// Renders the grid, or both views of it when split.
+    ///
+    /// The first view sits in a resizable panel on the left or top, and the second view fills
+    /// the rest. Keyboard navigation scrolls the first view.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    ///
+    /// # Returns
+    /// An optional tuple `(usize, usize)` representing the new selection if a cell was clicked.
+    fn render_split_grid(&mut self, ui: &mut egui::Ui) -> Option<(usize, usize)> {
+        let Some(mut view) = self.split.take() else {
+            return self.render_spreadsheet_grid(ui);
+        };
+        // The sheet may have been switched or resized since the view was last drawn
+        view.start_row = view.start_row.min(self.total_rows - 1);
+        view.start_col = view.start_col.min(self.total_cols - 1);
+        let first = if view.horizontal {
+            egui::TopBottomPanel::top("split_first")
+                .resizable(true)
+                .default_height(ui.available_height() / 2.0)
+                .show_inside(ui, |ui| self.render_spreadsheet_grid(ui))
+                .inner
+        } else {
+            egui::SidePanel::left("split_first")
+                .resizable(true)
+                .default_width(ui.available_width() / 2.0)
+                .show_inside(ui, |ui| self.render_spreadsheet_grid(ui))
+                .inner
+        };
+        self.swap_split_view(&mut view);
+        let second = ui
+            .push_id("split_second", |ui| self.render_spreadsheet_grid(ui))
+            .inner;
+        self.swap_split_view(&mut view);
+        self.split = Some(view);
+        first.or(second)
+    }
+
+    /// Moves the grid window when the view is scrolled against one of its edges.
+    ///
+    /// Only `window_rows` x `window_cols` cells are laid out at a time. Scrolling to within a
+    /// screen of the far edge of the window re-anchors it at the first visible cell, and
+    /// scrolling back to its start moves it back by half a window, so the whole sheet can be
+    /// browsed by scrolling. The mouse wheel scrolls down and up, shift+wheel (which egui turns
+    /// into a horizontal delta) right and left, and a drag that is let go keeps scrolling on its
+    /// own. The scroll offset and that kinetic velocity are carried over to the new window, so
+    /// the visible cells and the pinned headers keep moving without a jump.
+    ///
+    /// # Arguments
+    /// * `ui` - The mutable reference to the egui UI context.
+    /// * `area_id` - The id of the scroll area showing the current window.
+    /// * `scroll_offset` - The scroll offset of the grid inside the current window.
+    /// * `first_visible` - Tuple of the (row, col) of the first cell not hidden by frozen ones.
+    /// * `rows` - The layout of the rows of the current window.
+    /// * `cols` - The layout of the columns of the current window.
+    fn page_grid_window(
+        &mut self,
+        ui: &egui::Ui,
+        area_id: egui::Id,
+        scroll_offset: egui::Vec2,
+        first_visible: (usize, usize),
+        rows: &GridAxis,
+        cols: &GridAxis,
+    ) {
+        let state = egui::scroll_area::State::load(ui.ctx(), area_id);
+        // Only the view under the pointer is scrolled by the wheel
+        let scroll_delta = if ui.ui_contains_pointer() {
+            ui.input(|i| i.smooth_scroll_delta)
+        } else {
+            egui::Vec2::ZERO
+        };
+        // Both are positive while the view moves back toward the first row or column
+        let moving_back = scroll_delta + state.as_ref().map_or(egui::Vec2::ZERO, |s| s.velocity());
+        // Paging back starts within a cell of the edge, before egui stops the motion there
+        let edge_gap = scroll_offset - self.style.cell_size;
+        let mut offset = scroll_offset;
+        let (first_row, first_col) = first_visible;
+        let (start_row, start_col) = (self.start_row, self.start_col);
+
+        if rows.end() < self.total_rows && first_row + 2 * self.visible_rows >= rows.end() {
+            self.start_row = first_row;
+        } else if self.start_row > 0 && edge_gap.y <= 0.0 && moving_back.y > 0.0 {
+            self.start_row = self.start_row.saturating_sub(self.window_rows / 2);
+        }
+        if cols.end() < self.total_cols && first_col + 2 * self.visible_cols >= cols.end() {
+            self.start_col = first_col;
+        } else if self.start_col > 0 && edge_gap.x <= 0.0 && moving_back.x > 0.0 {
+            self.start_col = self.start_col.saturating_sub(self.window_cols / 2);
+        }
+        if (self.start_row, self.start_col) != (start_row, start_col) {
+            // Keep a cell that both windows lay out where it was on screen
+            let anchor = (self.start_row.max(start_row), self.start_col.max(start_col));
+            let (new_rows, new_cols) = self.grid_axes(
+                (self.start_row, self.start_col),
+                (anchor.0 + 1, anchor.1 + 1),
+            );
+            offset.y += new_rows.start(anchor.0) - rows.start(anchor.0);
+            offset.x += new_cols.start(anchor.1) - cols.start(anchor.1);
+            self.pending_scroll = Some(offset);
+            // The new window has its own scroll area, which takes over the motion
+            if let Some(mut state) = state {
+                state.offset = offset;
+                let id = ui.make_persistent_id((self.start_row, self.start_col));
+                state.store(ui.ctx(), id);
+            }
+        }
+    }
+
     /// Displays information about the currently selected cell.
     ///
     /// # Arguments
@@ -1014,6 +2568,151 @@ impl SpreadsheetApp {
                     .color(self.style.header_text),
             );
         }
+        if let Some(summary) = self.range_summary() {
+            ui.label(
+                egui::RichText::new(summary)
+                    .size(self.style.font_size)
+                    .color(self.style.header_text),
+            );
+        }
+    }
+
+    /// Summarizes the selected range with its sum, average and cell count.
+    ///
+    /// # Returns
+    /// The summary text, the error of the first failing cell in place of the sum and average,
+    /// or `None` if no range is selected.
+    fn range_summary(&self) -> Option<String> {
+        let (start, end) = (self.range_start?, self.range_end?);
+        let (r_min, r_max) = (start.0.min(end.0), start.0.max(end.0));
+        let (c_min, c_max) = (start.1.min(end.1), start.1.max(end.1));
+        let aggregate = |choice| {
+            compute_range(
+                &self.sheet,
+                self.total_cols,
+                r_min,
+                r_max,
+                c_min,
+                c_max,
+                choice,
+            )
+            .map(|value| value.to_string())
+            .unwrap_or_else(|status| CellError::from(status).to_string())
+        };
+        let count = (r_max - r_min + 1) * (c_max - c_min + 1);
+        Some(format!(
+            "Sum: {}    Avg: {}    Count: {}",
+            aggregate(4),
+            aggregate(3),
+            count
+        ))
+    }
+
+    /// Displays the tab bar below the grid, with a tab for each sheet and a button adding one.
+    ///
+    /// # Arguments
+    /// * `ctx` - The egui context for rendering.
+    fn render_sheet_tabs(&mut self, ctx: &egui::Context) {
+        let mut clicked = None;
+        let mut add = false;
+        egui::TopBottomPanel::bottom("sheet_tabs").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for (index, state) in self.sheets.iter().enumerate() {
+                    let tab = egui::RichText::new(&state.name).size(self.style.font_size);
+                    if ui
+                        .selectable_label(index == self.active_sheet, tab)
+                        .clicked()
+                    {
+                        clicked = Some(index);
+                    }
+                }
+                add = ui.button("+").on_hover_text("Add sheet").clicked();
+            });
+        });
+        if let Some(index) = clicked {
+            self.switch_sheet(index);
+        }
+        if add {
+            self.add_sheet("");
+        }
+    }
+
+    /// Moves the selected cell by one step, scrolling the grid to keep it in view.
+    ///
+    /// # Arguments
+    /// * `step` - Tuple of the (row, col) change, each -1, 0 or 1.
+    /// * `visible` - Tuple of the number of visible (rows, cols) in the viewport.
+    fn step_selection(&mut self, step: (isize, isize), visible: (usize, usize)) {
+        if let Some((row, col)) = self.selected {
+            let row = row.saturating_add_signed(step.0).min(self.total_rows - 1);
+            let col = col.saturating_add_signed(step.1).min(self.total_cols - 1);
+            self.selected = Some((row, col));
+            self.scroll_into_view((row, col), visible);
+        }
+        self.formula_input.clear();
+    }
+
+    /// Extends the selected range from the selected cell, which stays selected, by one cell
+    /// (Shift+Arrow) or to the edge of the data (Ctrl+Shift+Arrow).
+    ///
+    /// A complete range is extended from its far corner; otherwise a new range starts at the
+    /// selected cell.
+    ///
+    /// # Arguments
+    /// * `step` - Tuple of the (row, col) direction, each -1, 0 or 1.
+    /// * `to_edge` - Whether to extend to the edge of the data instead of by one cell.
+    /// * `visible` - Tuple of the number of visible (rows, cols) in the viewport.
+    fn extend_selection(&mut self, step: (isize, isize), to_edge: bool, visible: (usize, usize)) {
+        let Some(selected) = self.selected else {
+            return;
+        };
+        let (start, end) = match (self.range_start, self.range_end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => (selected, selected),
+        };
+        let end = if to_edge {
+            self.data_edge(end, step)
+        } else {
+            (
+                end.0.saturating_add_signed(step.0).min(self.total_rows - 1),
+                end.1.saturating_add_signed(step.1).min(self.total_cols - 1),
+            )
+        };
+        self.range_start = Some(start);
+        self.range_end = Some(end);
+        self.is_selecting_range = false;
+        self.scroll_into_view(end, visible);
+        let (min_row, max_row) = (start.0.min(end.0), start.0.max(end.0));
+        let (min_col, max_col) = (start.1.min(end.1), start.1.max(end.1));
+        self.status_message = format!(
+            "Selected range {}{}:{}{}",
+            col_label(min_col),
+            min_row + 1,
+            col_label(max_col),
+            max_row + 1
+        );
+    }
+
+    /// Scrolls the grid the least needed to bring a cell into view.
+    ///
+    /// # Arguments
+    /// * `cell` - Tuple of the (row, col) to show.
+    /// * `visible` - Tuple of the number of visible (rows, cols) in the viewport.
+    fn scroll_into_view(&mut self, (row, col): (usize, usize), visible: (usize, usize)) {
+        if row < self.start_row {
+            self.start_row = row;
+            self.should_reset_scroll = true;
+        } else if row >= self.start_row + visible.0 {
+            self.start_row = row + 1 - visible.0;
+            self.should_reset_scroll = true;
+        }
+        if col < self.start_col {
+            self.start_col = col;
+            self.should_reset_scroll = true;
+        } else if col >= self.start_col + visible.1 {
+            self.start_col = col + 1 - visible.1;
+            self.should_reset_scroll = true;
+        }
     }
 
     /// Handles keyboard events for navigation and other actions.
@@ -1028,51 +2727,48 @@ impl SpreadsheetApp {
         visible_rows: usize,
         visible_cols: usize,
     ) {
+        let typing = !ctx.wants_keyboard_input();
         ctx.input(|input| {
-            if input.key_pressed(egui::Key::ArrowDown) {
-                if let Some((row, col)) = self.selected {
-                    if row + 1 < self.sheet.len() {
-                        self.selected = Some((row + 1, col));
-                        if row + 1 >= self.start_row + visible_rows {
-                            self.start_row = row + 1 - visible_rows + 1;
-                            self.should_reset_scroll = true;
-                        }
-                    }
-                }
-                self.formula_input.clear();
-            } else if input.key_pressed(egui::Key::ArrowUp) {
-                if let Some((row, col)) = self.selected {
-                    if row > 0 {
-                        self.selected = Some((row - 1, col));
-                        if row - 1 < self.start_row {
-                            self.start_row = row - 1;
-                            self.should_reset_scroll = true;
-                        }
-                    }
-                }
-                self.formula_input.clear();
-            } else if input.key_pressed(egui::Key::ArrowRight) {
-                if let Some((row, col)) = self.selected {
-                    if col + 1 < self.total_cols {
-                        self.selected = Some((row, col + 1));
-                        if col + 1 >= self.start_col + visible_cols {
-                            self.start_col = col + 1 - visible_cols + 1;
-                            self.should_reset_scroll = true;
-                        }
-                    }
+            let visible = (visible_rows, visible_cols);
+            let arrow = [
+                (egui::Key::ArrowDown, (1, 0)),
+                (egui::Key::ArrowUp, (-1, 0)),
+                (egui::Key::ArrowRight, (0, 1)),
+                (egui::Key::ArrowLeft, (0, -1)),
+            ]
+            .into_iter()
+            .find(|&(key, _)| input.key_pressed(key));
+            if self.editing_cell {
+                // Arrow keys move the text cursor while a cell is being edited
+            } else if let Some((_, step)) = arrow
+                && !input.modifiers.alt
+            {
+                if input.modifiers.shift {
+                    self.extend_selection(step, input.modifiers.ctrl, visible);
+                } else {
+                    self.step_selection(step, visible);
                 }
-                self.formula_input.clear();
-            } else if input.key_pressed(egui::Key::ArrowLeft) {
+            }
+            if input.key_pressed(egui::Key::F2) && typing {
                 if let Some((row, col)) = self.selected {
-                    if col > 0 {
-                        self.selected = Some((row, col - 1));
-                        if col - 1 < self.start_col {
-                            self.start_col = col - 1;
-                            self.should_reset_scroll = true;
-                        }
-                    }
+                    self.formula_input = self.get_cell_formula(row, col);
+                    self.editing_cell = true;
+                    self.request_cell_focus = true;
                 }
-                self.formula_input.clear();
+            } else if let Some(text) = input.events.iter().find_map(|event| match event {
+                egui::Event::Text(text) if !text.trim().is_empty() => Some(text),
+                _ => None,
+            }) && typing
+                && !self.editing_cell
+                && self.selected.is_some()
+                && !input.modifiers.command
+            {
+                // Typing over a selected cell replaces its contents
+                self.formula_input = text.clone();
+                self.editing_cell = true;
+                self.request_cell_focus = true;
+            }
+            if input.key_pressed(egui::Key::Escape) {
             } else if input.key_pressed(egui::Key::Escape) {
                 if self.editing_cell {
                     self.editing_cell = false;
@@ -1085,6 +2781,12 @@ impl SpreadsheetApp {
                     self.status_message = "Selection cleared, command mode".to_string();
                     self.request_formula_focus = true;
                 }
+            } else if (input.key_pressed(egui::Key::Delete)
+                || input.key_pressed(egui::Key::Backspace))
+                && typing
+                && !self.editing_cell
+            {
+                self.clear_selected_cells();
             } else if input.key_pressed(egui::Key::Space) {
                 if let Some((row, col)) = self.selected {
                     self.formula_input = self.get_cell_formula(row, col);
@@ -1092,23 +2794,30 @@ impl SpreadsheetApp {
                     self.request_formula_focus = true;
                 }
             }
-            if input.modifiers.ctrl {
-                if input.key_pressed(egui::Key::S) {
+            match self.keys.pressed(input) {
+                Some(KeyAction::Save) => {
                     self.show_save_dialog = true;
                     self.focus_on = 0;
-                } else if input.key_pressed(egui::Key::E) {
-                    self.copy_selected_cell();
-                } else if input.key_pressed(egui::Key::R) {
-                    self.paste_to_selected_cell();
-                } else if input.key_pressed(egui::Key::T) {
-                    self.cut_selected_cell();
-                } else if input.key_pressed(egui::Key::Z) {
-                    self.undo();
-                } else if input.key_pressed(egui::Key::Y)
-                    || (input.modifiers.shift && input.key_pressed(egui::Key::Z))
-                {
-                    self.redo();
                 }
+                // The clipboard shortcuts of a text field act on its text, not on the cells
+                Some(KeyAction::Copy) if typing => self.copy_selected_cell(),
+                Some(KeyAction::Paste) if typing => {
+                    let text = input.events.iter().find_map(|event| match event {
+                        egui::Event::Paste(text) => Some(text.as_str()),
+                        _ => None,
+                    });
+                    self.paste_from_system(text);
+                }
+                Some(KeyAction::Cut) if typing => self.cut_selected_cell(),
+                Some(KeyAction::Undo) => self.undo(),
+                Some(KeyAction::Redo) => self.redo(),
+                Some(action @ (KeyAction::Back | KeyAction::Forward)) if typing => {
+                    self.step_visits(action == KeyAction::Forward);
+                    if let Some(cell) = self.selected {
+                        self.scroll_into_view(cell, visible);
+                    }
+                }
+                _ => {}
             }
         });
     }
@@ -1121,8 +2830,18 @@ impl eframe::App for SpreadsheetApp {
     /// * `ctx` - The egui context for rendering and input handling.
     /// * `_frame` - A mutable reference to the eframe frame (unused).
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        ctx.set_visuals(egui::Visuals::dark());
+        // The open sheet may have changed since the last frame
+        self.eval.install();
+        self.follow_theme_mode(ctx);
+        if self.render_evaluation(ctx) {
+            return;
+        }
         let mut new_selection = None;
+        self.sync_sheet_links();
+        self.sync_collab(ctx);
+        self.autosave_if_due();
+        self.persist_theme_if_changed(ctx);
+        self.begin_text_cache_frame(ctx);
 
         egui::TopBottomPanel::top("formula_panel").show(ctx, |ui| {
             self.render_formula_bar(ui);
@@ -1132,6 +2851,14 @@ impl eframe::App for SpreadsheetApp {
                 ui.separator();
                 ui.add_space(16.0);
                 self.render_colour(ui);
+                ui.add_space(16.0);
+                ui.separator();
+                ui.add_space(16.0);
+                self.render_freeze_toggle(ui);
+                ui.add_space(16.0);
+                ui.separator();
+                ui.add_space(16.0);
+                self.render_format_toolbar(ui);
                 if self.show_save_dialog {
                     ui.add_space(16.0);
                     ui.separator();
@@ -1142,23 +2869,40 @@ impl eframe::App for SpreadsheetApp {
                 ui.separator();
             });
         });
+        if self.evaluation.is_some() {
+            // An edit from the formula bar is still being recalculated, with the sheet
+            ctx.request_repaint();
+            return;
+        }
+
+        self.render_sheet_tabs(ctx);
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            if let Some(selection) = self.render_spreadsheet_grid(ui) {
+            if let Some(selection) = self.render_split_grid(ui) {
                 new_selection = Some(selection);
             }
             self.render_selected_cell_info(ui);
         });
+        if self.evaluation.is_some() {
+            // An edit from the cell editor is still being recalculated, with the sheet
+            ctx.request_repaint();
+            return;
+        }
 
         self.handle_selection_change(new_selection);
-
-        let avail_rect = ctx.available_rect();
-        let avail_size = avail_rect.size();
-        let row_label_width = 30.0;
-        let visible_rows = 31;
-        let visible_cols =
-            (((avail_size.x - row_label_width) / self.style.cell_size.x).ceil() as usize).max(1);
-
-        self.handle_keyboard_events(ctx, visible_rows, visible_cols - 1);
+        self.render_histogram_chart(ctx);
+        self.render_charts(ctx);
+        self.render_lint_panel(ctx);
+        self.render_sheet_info_panel(ctx);
+        self.render_search_panel(ctx);
+
+        self.handle_keyboard_events(ctx, self.visible_rows, self.visible_cols);
+        if self.clipboard_sync {
+            // Written once input is released, as the clipboard is part of the frame output
+            ctx.copy_text(self.clipboard_text.clone());
+            self.clipboard_sync = false;
+        }
+        self.render_toasts(ctx);
+        self.end_text_cache_frame();
     }
 }
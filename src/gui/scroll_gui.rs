@@ -1,8 +1,13 @@
+use std::mem;
+
 use crate::{
-    gui::gui_defs::{Direction, SpreadsheetApp},
+    gui::gui_defs::{Direction, SplitView, SpreadsheetApp},
     gui::utils_gui::col_label,
 };
 
+/// Smallest grid window, in rows or columns, accepted by the `window` command.
+const MIN_GRID_WINDOW: usize = 100;
+
 /// Moves the view upward by a specified amount.
 ///
 /// If the amount exceeds the current position, it moves to the top (row 0).
@@ -92,4 +97,81 @@ impl SpreadsheetApp {
             (self.start_row + 1)
         );
     }
+
+    /// Sets the maximum size of the grid window laid out for scrolling.
+    ///
+    /// Larger windows let the scroll bars cover more of the sheet before paging, at the cost
+    /// of layout work. Sizes below `MIN_GRID_WINDOW` are raised to it, so the window always
+    /// exceeds the viewport.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments: the number of rows and of columns.
+    pub(in crate::gui) fn set_grid_window(&mut self, args: &str) {
+        let sizes: Vec<usize> = args
+            .split_whitespace()
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        if let [rows, cols] = sizes[..]
+            && args.split_whitespace().count() == 2
+        {
+            self.window_rows = rows.max(MIN_GRID_WINDOW);
+            self.window_cols = cols.max(MIN_GRID_WINDOW);
+            self.status_message = format!(
+                "Grid window set to {} rows x {} columns",
+                self.window_rows, self.window_cols
+            );
+        } else {
+            self.status_message = "Usage: window <rows> <cols>".to_string();
+        }
+    }
+
+    /// Splits the grid into two views of the open sheet, or removes the split.
+    ///
+    /// The second view starts at the same cell as the first and scrolls on its own. Without
+    /// an orientation the command toggles a side-by-side split.
+    ///
+    /// # Arguments
+    /// * `args` - The command arguments: empty, `v` for side by side, `h` for stacked, or `off`.
+    pub(in crate::gui) fn split_view(&mut self, args: &str) {
+        let horizontal = match args.trim() {
+            "" if self.split.is_some() => None,
+            "" | "v" => Some(false),
+            "h" => Some(true),
+            "off" => None,
+            _ => {
+                self.status_message = "Usage: split [v|h|off]".to_string();
+                return;
+            }
+        };
+        self.split = horizontal.map(|horizontal| SplitView {
+            horizontal,
+            start_row: self.start_row,
+            start_col: self.start_col,
+            should_reset_scroll: true,
+            pending_scroll: None,
+            visible_rows: self.visible_rows,
+            visible_cols: self.visible_cols,
+        });
+        self.status_message = match horizontal {
+            Some(true) => "Split view stacked",
+            Some(false) => "Split view side by side",
+            None => "Split view closed",
+        }
+        .to_string();
+    }
+
+    /// Swaps the view of the grid with the second view of a split.
+    ///
+    /// Called again, it swaps them back.
+    ///
+    /// # Arguments
+    /// * `view` - The second view.
+    pub(in crate::gui) fn swap_split_view(&mut self, view: &mut SplitView) {
+        mem::swap(&mut self.start_row, &mut view.start_row);
+        mem::swap(&mut self.start_col, &mut view.start_col);
+        mem::swap(&mut self.should_reset_scroll, &mut view.should_reset_scroll);
+        mem::swap(&mut self.pending_scroll, &mut view.pending_scroll);
+        mem::swap(&mut self.visible_rows, &mut view.visible_rows);
+        mem::swap(&mut self.visible_cols, &mut view.visible_cols);
+    }
 }
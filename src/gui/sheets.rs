@@ -0,0 +1,407 @@
+//! # Sheets Module
+//! This module keeps the sheets of a workbook behind the tab bar. The open sheet lives in the
+//! fields of `SpreadsheetApp`, as before, and is swapped with its `SheetState` slot when another
+//! tab is opened, so every sheet keeps its own cells, view and undo history.
+//!
+//! A cell can show a cell of another sheet when its whole formula is a reference such as
+//! `Sheet2!A1`. Such a cell holds the value as a constant, text and error values included, and
+//! is rewritten once per frame when the source changes, following chains of links across
+//! sheets.
+use std::collections::HashSet;
+use std::mem;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::gui::gui_defs::{SheetLink, SheetState, SpreadsheetApp};
+use crate::{
+    Cell, CellData, CellKey, EvalStatus, Valtype, parser, utils::to_cell_name, utils::to_indices,
+    utils::transitive_dependents,
+};
+
+/// A cell of the workbook: the index of its sheet and its (row, col).
+type SheetCell = (usize, (usize, usize));
+
+/// A formula that is a whole reference to a cell of another sheet, such as `Sheet2!$A$1`.
+static RE_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)!(\$?[A-Za-z]+\$?[0-9]+)$").unwrap());
+
+/// Writes the value of a link into a cell of a sheet as a constant, and recalculates the
+/// cells depending on it.
+///
+/// # Arguments
+/// * `state` - The sheet holding the linked cell.
+/// * `row` - The row index of the linked cell.
+/// * `col` - The column index of the linked cell.
+/// * `value` - The value of the source cell.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - The status of the recalculation.
+fn write_link_value(
+    state: &mut SheetState,
+    row: usize,
+    col: usize,
+    value: &Valtype,
+) -> Result<(), EvalStatus> {
+    let key = (row * state.total_cols + col) as CellKey;
+    let old_cell = state.sheet.get(&key).cloned().unwrap_or(Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    });
+    let mut new_cell = old_cell.clone();
+    new_cell.reset();
+    new_cell.data = CellData::Const;
+    new_cell.value = value.clone();
+    state.sheet.insert(key, new_cell);
+    // The sheet may not be the open one, so it is recalculated with its own context
+    let eval = state.eval.clone();
+    eval.scope(|| {
+        parser::update_and_recalc(
+            &mut state.sheet,
+            &mut state.ranged,
+            &mut state.is_range,
+            (state.total_rows, state.total_cols),
+            row,
+            col,
+            old_cell,
+        )
+        .status
+    })
+}
+
+impl SpreadsheetApp {
+    /// Swaps the open sheet with the contents of a slot of `sheets`.
+    ///
+    /// Called with the index of the open sheet, it moves the open sheet into its slot, so that
+    /// every sheet can be reached through `sheets`; called again, it moves it back.
+    ///
+    /// # Arguments
+    /// * `index` - The slot to swap with.
+    fn swap_sheet_state(&mut self, index: usize) {
+        let state = &mut self.sheets[index];
+        mem::swap(&mut self.sheet, &mut state.sheet);
+        mem::swap(&mut self.ranged, &mut state.ranged);
+        mem::swap(&mut self.is_range, &mut state.is_range);
+        mem::swap(&mut self.total_rows, &mut state.total_rows);
+        mem::swap(&mut self.total_cols, &mut state.total_cols);
+        mem::swap(&mut self.selected, &mut state.selected);
+        mem::swap(&mut self.start_row, &mut state.start_row);
+        mem::swap(&mut self.start_col, &mut state.start_col);
+        mem::swap(&mut self.undo_stack, &mut state.undo_stack);
+        mem::swap(&mut self.redo_stack, &mut state.redo_stack);
+        mem::swap(&mut self.names, &mut state.names);
+        mem::swap(&mut self.search_index, &mut state.search_index);
+        mem::swap(&mut self.links, &mut state.links);
+        mem::swap(&mut self.grid_sizes, &mut state.grid_sizes);
+        mem::swap(&mut self.frozen, &mut state.frozen);
+        mem::swap(&mut self.formats, &mut state.formats);
+        mem::swap(&mut self.validations, &mut state.validations);
+        mem::swap(&mut self.watches, &mut state.watches);
+        mem::swap(&mut self.visits, &mut state.visits);
+        mem::swap(&mut self.locks, &mut state.locks);
+        mem::swap(&mut self.charts, &mut state.charts);
+        mem::swap(&mut self.eval, &mut state.eval);
+        mem::swap(&mut self.collab, &mut state.collab);
+    }
+
+    /// Finds a sheet by name, ignoring case.
+    fn sheet_index(&self, name: &str) -> Option<usize> {
+        self.sheets
+            .iter()
+            .position(|state| state.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Opens the sheet at an index of `sheets`.
+    ///
+    /// # Arguments
+    /// * `index` - The sheet to open.
+    pub fn switch_sheet(&mut self, index: usize) {
+        if index == self.active_sheet || index >= self.sheets.len() {
+            return;
+        }
+        self.swap_sheet_state(self.active_sheet);
+        self.swap_sheet_state(index);
+        self.active_sheet = index;
+        self.eval.install();
+        // Ranges, cut sources and reports refer to the cells of the sheet they were made on
+        self.range_start = None;
+        self.range_end = None;
+        self.cut_source = None;
+        self.lint_report = None;
+        self.sheet_info = None;
+        self.histogram_chart = None;
+        if let Some((row, col)) = self.selected {
+            self.formula_input = self.get_cell_formula(row, col);
+        }
+        self.status_message = format!("Opened sheet {}", self.sheets[index].name);
+    }
+
+    /// Turns safe mode on or off for every sheet of the workbook, including those added later.
+    ///
+    /// # Arguments
+    /// * `safe` - Whether SLEEP, RAND and RANDBETWEEN formulas are rejected, and SLEEP never
+    ///   sleeps.
+    pub fn set_safe_mode(&mut self, safe: bool) {
+        self.eval.set_safe(safe);
+        for state in &self.sheets {
+            state.eval.set_safe(safe);
+        }
+    }
+
+    /// Sets the script functions the formulas of every sheet of the workbook may call,
+    /// including sheets added later.
+    ///
+    /// # Arguments
+    /// * `functions` - The compiled script, or `None` for no script functions.
+    #[cfg(feature = "scripting")]
+    pub fn set_functions(
+        &mut self,
+        functions: Option<std::sync::Arc<spreadsheet::scripting::UserFunctions>>,
+    ) {
+        self.eval.set_functions(functions.clone());
+        for state in &self.sheets {
+            state.eval.set_functions(functions.clone());
+        }
+    }
+
+    /// Adds an empty sheet with the dimensions of the open one and opens it.
+    ///
+    /// # Arguments
+    /// * `name` - The tab name, or empty for the first free "SheetN".
+    pub fn add_sheet(&mut self, name: &str) {
+        let name = if name.is_empty() {
+            (1..)
+                .map(|n| format!("Sheet{}", n))
+                .find(|name| self.sheet_index(name).is_none())
+                .unwrap()
+        } else {
+            name.to_string()
+        };
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            self.status_message = format!("Invalid sheet name: {}", name);
+            return;
+        }
+        if self.sheet_index(&name).is_some() {
+            self.status_message = format!("Sheet {} already exists", name);
+            return;
+        }
+        let state = SheetState::new(&name, self.total_rows, self.total_cols);
+        state.eval.set_safe(self.eval.is_safe());
+        #[cfg(feature = "scripting")]
+        state.eval.set_functions(self.eval.functions());
+        self.sheets.push(state);
+        self.switch_sheet(self.sheets.len() - 1);
+        self.status_message = format!("Added sheet {}", name);
+    }
+
+    /// Handles `sheet add [name]` and `sheet <name>`.
+    ///
+    /// # Arguments
+    /// * `args` - The text following `sheet`.
+    pub fn sheet_command(&mut self, args: &str) {
+        let args = args.trim();
+        if let Some(name) = args
+            .strip_prefix("add")
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        {
+            self.add_sheet(name.trim());
+        } else if let Some(index) = self.sheet_index(args) {
+            self.switch_sheet(index);
+        } else {
+            self.status_message = format!("No sheet named {}", args);
+        }
+    }
+
+    /// Returns the value of a cell of any sheet as a link reads it: text and error values as
+    /// they are, and 0 for empty cells and cells outside the sheet. Every sheet must be in its
+    /// slot.
+    fn linked_value(&self, (sheet, (row, col)): SheetCell) -> Valtype {
+        let state = &self.sheets[sheet];
+        if row >= state.total_rows || col >= state.total_cols {
+            return Valtype::Int(0);
+        }
+        state
+            .sheet
+            .get(&((row * state.total_cols + col) as CellKey))
+            .map_or(Valtype::Int(0), |cell| cell.value.clone())
+    }
+
+    /// Checks whether a cell's value flows into another cell, through formulas on one sheet
+    /// and links between sheets. Every sheet must be in its slot.
+    fn link_reaches(&self, from: SheetCell, to: SheetCell) -> bool {
+        let mut seen = HashSet::from([from]);
+        let mut pending = vec![from];
+        while let Some((sheet, (row, col))) = pending.pop() {
+            let state = &self.sheets[sheet];
+            let cols = state.total_cols;
+            let key = (row * cols + col) as CellKey;
+            let reached = transitive_dependents(key, &state.sheet, &state.ranged, cols)
+                .into_iter()
+                .map(|key| (key as usize / cols, key as usize % cols))
+                .chain([(row, col)]);
+            for cell in reached {
+                if (sheet, cell) == to {
+                    return true;
+                }
+                for (target, other) in self.sheets.iter().enumerate() {
+                    for (&linked, link) in &other.links {
+                        if (link.sheet, link.cell) == (sheet, cell) && seen.insert((target, linked))
+                        {
+                            pending.push((target, linked));
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Reads a formula of the form `Sheet2!A1` as a link to be written into a cell of the
+    /// open sheet.
+    ///
+    /// # Arguments
+    /// * `formula` - The formula text as entered.
+    /// * `row` - The row index of the cell being written.
+    /// * `col` - The column index of the cell being written.
+    ///
+    /// # Returns
+    /// * `Option<Result<SheetLink, String>>` - `None` if the formula is not a cross-sheet
+    ///   reference, the link holding the current value of the source, or a message if the
+    ///   sheet or cell does not exist or the link would make a cycle.
+    pub(in crate::gui) fn resolve_sheet_link(
+        &mut self,
+        formula: &str,
+        row: usize,
+        col: usize,
+    ) -> Option<Result<SheetLink, String>> {
+        let caps = RE_LINK.captures(formula.trim())?;
+        let Some(sheet) = self.sheet_index(&caps[1]) else {
+            return Some(Err(format!("No sheet named {}", &caps[1])));
+        };
+        let state = &self.sheets[sheet];
+        let (total_rows, total_cols) = if sheet == self.active_sheet {
+            (self.total_rows, self.total_cols)
+        } else {
+            (state.total_rows, state.total_cols)
+        };
+        // A link is never shifted, so '$' markers change nothing
+        let cell = match to_indices(&caps[2].replace('$', "").to_ascii_uppercase()) {
+            Ok((r, c)) if r < total_rows && c < total_cols => (r, c),
+            _ => {
+                return Some(Err(format!(
+                    "Cell {} is outside sheet {}",
+                    &caps[2], state.name
+                )));
+            }
+        };
+
+        let active = self.active_sheet;
+        self.swap_sheet_state(active);
+        let cycle = self.link_reaches((active, (row, col)), (sheet, cell));
+        let value = self.linked_value((sheet, cell));
+        self.swap_sheet_state(active);
+        if cycle {
+            return Some(Err(format!(
+                "Cycle detected through {}!{}",
+                self.sheets[sheet].name,
+                to_cell_name(cell.0, cell.1)
+            )));
+        }
+        Some(Ok(SheetLink { sheet, cell, value }))
+    }
+
+    /// Returns the formula shown for a linked cell of the open sheet, such as `Sheet2!A1`.
+    pub(in crate::gui) fn link_formula(&self, row: usize, col: usize) -> Option<String> {
+        let link = self.links.get(&(row, col))?;
+        Some(format!(
+            "{}!{}",
+            self.sheets[link.sheet].name,
+            to_cell_name(link.cell.0, link.cell.1)
+        ))
+    }
+
+    /// Rewrites every linked cell whose source changed, once. Every sheet must be in its slot.
+    ///
+    /// # Returns
+    /// * `Vec<SheetCell>` - The cells rewritten.
+    fn sync_pass(&mut self) -> Vec<SheetCell> {
+        let mut changed = Vec::new();
+        for target in 0..self.sheets.len() {
+            let links: Vec<_> = self.sheets[target]
+                .links
+                .iter()
+                .map(|(&cell, link)| (cell, link.clone()))
+                .collect();
+            for ((row, col), link) in links {
+                let state = &self.sheets[target];
+                let key = (row * state.total_cols + col) as CellKey;
+                // A link lasts only while its cell holds the value last written into it
+                let intact = row < state.total_rows
+                    && col < state.total_cols
+                    && state.sheet.get(&key).is_some_and(|cell| {
+                        cell.data == CellData::Const && cell.value == link.value
+                    });
+                if !intact {
+                    self.sheets[target].links.remove(&(row, col));
+                    continue;
+                }
+                let value = self.linked_value((link.sheet, link.cell));
+                if value == link.value {
+                    continue;
+                }
+                let state = &mut self.sheets[target];
+                if write_link_value(state, row, col, &value).is_err() {
+                    state.links.remove(&(row, col));
+                    continue;
+                }
+                if let Some(index) = &mut state.search_index {
+                    index.refresh(key, &state.sheet, &state.ranged, state.total_cols);
+                }
+                state.links.insert((row, col), SheetLink { value, ..link });
+                changed.push((target, (row, col)));
+            }
+        }
+        changed
+    }
+
+    /// Brings every linked cell of the workbook up to date with its source.
+    ///
+    /// Links are followed until no value changes. Edits on one sheet can close a cycle through
+    /// links that was not there when they were made; such links keep changing, and are dropped.
+    pub fn sync_sheet_links(&mut self) {
+        if self.links.is_empty() && self.sheets.iter().all(|state| state.links.is_empty()) {
+            return;
+        }
+        let active = self.active_sheet;
+        self.swap_sheet_state(active);
+        let total_links: usize = self.sheets.iter().map(|state| state.links.len()).sum();
+        let mut changed = Vec::new();
+        // With no cycle, every value has reached the end of its chain after one pass per link
+        for _ in 0..=total_links {
+            changed = self.sync_pass();
+            if changed.is_empty() {
+                break;
+            }
+        }
+        if !changed.is_empty() {
+            let cells: Vec<String> = changed
+                .iter()
+                .map(|&(sheet, (row, col))| {
+                    self.sheets[sheet].links.remove(&(row, col));
+                    format!("{}!{}", self.sheets[sheet].name, to_cell_name(row, col))
+                })
+                .collect();
+            self.status_message = format!(
+                "Cycle detected through links, removed: {}",
+                cells.join(", ")
+            );
+        }
+        self.swap_sheet_state(active);
+    }
+}
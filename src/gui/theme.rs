@@ -0,0 +1,237 @@
+//! # Theme Module
+//! Keeps the colors and animation of the GUI between launches. The theme in use is written to
+//! `theme.toml` in the user's config directory whenever it changes and read back on the next
+//! launch, and `theme save <name>` / `theme load <name>` keep any number of named themes in
+//! its `themes` folder. Colors are written as hex strings such as `"#3c3f64ff"`.
+use std::fs;
+use std::path::PathBuf;
+
+use eframe::egui::{Color32, Stroke, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::gui::gui_defs::{SpreadsheetStyle, ThemeMode};
+
+/// The settings of a `SpreadsheetStyle` kept in a theme file. Settings a file leaves out take
+/// their default values.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct ThemeFile {
+    header_bg: String,
+    header_text: String,
+    cell_bg_even: String,
+    cell_bg_odd: String,
+    cell_text: String,
+    selected_cell_bg: String,
+    selected_cell_text: String,
+    grid_line: String,
+    grid_line_width: f32,
+    cell_width: f32,
+    cell_height: f32,
+    font_size: f32,
+    base_color: String,
+    animation: u32,
+    frequency: f32,
+    range_selection_bg: String,
+    range_selection_text: String,
+    reference_colors: Vec<String>,
+    search_match: String,
+    invalid_cell: String,
+    error_text: String,
+    dependent_cell: String,
+    mode: String,
+    dark: bool,
+}
+
+impl Default for ThemeFile {
+    fn default() -> Self {
+        ThemeFile::from(&SpreadsheetStyle::default())
+    }
+}
+
+impl From<&SpreadsheetStyle> for ThemeFile {
+    fn from(style: &SpreadsheetStyle) -> Self {
+        ThemeFile {
+            header_bg: style.header_bg.to_hex(),
+            header_text: style.header_text.to_hex(),
+            cell_bg_even: style.cell_bg_even.to_hex(),
+            cell_bg_odd: style.cell_bg_odd.to_hex(),
+            cell_text: style.cell_text.to_hex(),
+            selected_cell_bg: style.selected_cell_bg.to_hex(),
+            selected_cell_text: style.selected_cell_text.to_hex(),
+            grid_line: style.grid_line.color.to_hex(),
+            grid_line_width: style.grid_line.width,
+            cell_width: style.cell_size.x,
+            cell_height: style.cell_size.y,
+            font_size: style.font_size,
+            base_color: style.prev_base_color.to_hex(),
+            animation: style.rainbow,
+            frequency: style.frequency,
+            range_selection_bg: style.range_selection_bg.to_hex(),
+            range_selection_text: style.range_selection_text.to_hex(),
+            reference_colors: style.reference_colors.iter().map(Color32::to_hex).collect(),
+            search_match: style.search_match.to_hex(),
+            invalid_cell: style.invalid_cell.to_hex(),
+            error_text: style.error_text.to_hex(),
+            dependent_cell: style.dependent_cell.to_hex(),
+            mode: style.mode.name().to_string(),
+            dark: style.dark,
+        }
+    }
+}
+
+impl ThemeFile {
+    /// Builds the style the file describes.
+    ///
+    /// # Returns
+    /// * `Result<SpreadsheetStyle, String>` - The style, or a message naming the first color
+    ///   that is not a hex color, size that is not positive, or unknown mode.
+    fn style(&self) -> Result<SpreadsheetStyle, String> {
+        let color = |hex: &str| {
+            Color32::from_hex(hex).map_err(|_| format!("Invalid color '{}' in theme", hex))
+        };
+        let size = |size: f32| {
+            if size.is_finite() && size > 0.0 {
+                Ok(size)
+            } else {
+                Err(format!("Invalid size {} in theme", size))
+            }
+        };
+        let mut reference_colors = SpreadsheetStyle::default().reference_colors;
+        for (slot, hex) in reference_colors.iter_mut().zip(&self.reference_colors) {
+            *slot = color(hex)?;
+        }
+        Ok(SpreadsheetStyle {
+            header_bg: color(&self.header_bg)?,
+            header_text: color(&self.header_text)?,
+            cell_bg_even: color(&self.cell_bg_even)?,
+            cell_bg_odd: color(&self.cell_bg_odd)?,
+            cell_text: color(&self.cell_text)?,
+            selected_cell_bg: color(&self.selected_cell_bg)?,
+            selected_cell_text: color(&self.selected_cell_text)?,
+            grid_line: Stroke::new(size(self.grid_line_width)?, color(&self.grid_line)?),
+            cell_size: Vec2::new(size(self.cell_width)?, size(self.cell_height)?),
+            font_size: size(self.font_size)?,
+            prev_base_color: color(&self.base_color)?,
+            rainbow: self.animation,
+            frequency: size(self.frequency)?,
+            range_selection_bg: color(&self.range_selection_bg)?,
+            range_selection_text: color(&self.range_selection_text)?,
+            reference_colors,
+            search_match: color(&self.search_match)?,
+            invalid_cell: color(&self.invalid_cell)?,
+            error_text: color(&self.error_text)?,
+            dependent_cell: color(&self.dependent_cell)?,
+            mode: ThemeMode::from_name(&self.mode)
+                .ok_or_else(|| format!("Invalid mode '{}' in theme", self.mode))?,
+            dark: self.dark,
+            ..SpreadsheetStyle::default()
+        })
+    }
+}
+
+/// The folder theme files are kept in: `rust_spreadsheet` in `$XDG_CONFIG_HOME`, or in
+/// `~/.config` (`%APPDATA%` on Windows).
+///
+/// # Returns
+/// * `Option<PathBuf>` - The folder, or `None` if no config or home directory is known.
+fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("rust_spreadsheet"))
+}
+
+/// The file of a named theme, or of the theme in use.
+///
+/// # Arguments
+/// * `name` - The theme name, made of letters, digits, '-' and '_', or `None` for the theme
+///   in use.
+///
+/// # Returns
+/// * `Result<PathBuf, String>` - The path, or a message if the name is not allowed or there is
+///   no config directory.
+fn theme_path(name: Option<&str>) -> Result<PathBuf, String> {
+    let dir = config_dir().ok_or("No config directory to keep themes in")?;
+    match name {
+        None => Ok(dir.join("theme.toml")),
+        Some(name)
+            if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            Ok(dir.join("themes").join(format!("{}.toml", name)))
+        }
+        Some(name) => Err(format!(
+            "Invalid theme name '{}': use letters, digits, '-' and '_'",
+            name
+        )),
+    }
+}
+
+/// Writes a style to a theme file, creating the folders it goes in.
+///
+/// # Arguments
+/// * `style` - The style to save.
+/// * `name` - The theme name, or `None` for the theme in use.
+///
+/// # Returns
+/// * `Result<PathBuf, String>` - The file written, or a message describing why it could not be.
+pub(in crate::gui) fn save_theme(
+    style: &SpreadsheetStyle,
+    name: Option<&str>,
+) -> Result<PathBuf, String> {
+    let path = theme_path(name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("File error: {}", e))?;
+    }
+    let text = toml::to_string(&ThemeFile::from(style)).map_err(|e| e.to_string())?;
+    fs::write(&path, text).map_err(|e| format!("File error: {}", e))?;
+    Ok(path)
+}
+
+/// Reads a style from a theme file.
+///
+/// # Arguments
+/// * `name` - The theme name, or `None` for the theme in use.
+///
+/// # Returns
+/// * `Result<SpreadsheetStyle, String>` - The style, or a message describing why the file
+///   could not be read.
+///
+/// # Examples
+/// ```
+/// save_theme(&app.style, Some("night"))?;
+/// app.style = load_theme(Some("night"))?;
+/// ```
+pub(in crate::gui) fn load_theme(name: Option<&str>) -> Result<SpreadsheetStyle, String> {
+    let path = theme_path(name)?;
+    let text = fs::read_to_string(&path).map_err(|e| format!("File error: {}", e))?;
+    let file: ThemeFile = toml::from_str(&text).map_err(|e| format!("Theme file error: {}", e))?;
+    file.style()
+}
+
+/// Lists the named themes saved with `theme save`.
+///
+/// # Returns
+/// A `Vec<String>` with the theme names in alphabetical order, empty if there are none.
+pub(in crate::gui) fn theme_names() -> Vec<String> {
+    let Some(dir) = config_dir().map(|dir| dir.join("themes")) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "toml" {
+                return None;
+            }
+            path.file_stem()?.to_str().map(String::from)
+        })
+        .collect();
+    names.sort();
+    names
+}
@@ -1,5 +1,9 @@
-use crate::CellData;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
 use crate::Valtype;
+use crate::gui::gui_defs::FormulaReference;
 
 /// Converts a column index to an Excel-style label (e.g., 0 to "A", 1 to "B", 25 to "Z", 26 to "AA", etc.).
 ///
@@ -107,86 +111,176 @@ pub fn col_label_to_index(label: &str) -> Option<usize> {
 /// let int_val = Valtype::Int(42);
 /// assert_eq!(valtype_to_string(&int_val), "42");
 ///
-/// let str_val = Valtype::Str(String::from("hello"));
+/// let str_val = Valtype::Str(CellText::new("hello"));
 /// assert_eq!(valtype_to_string(&str_val), "hello");
 /// ```
 pub fn valtype_to_string(v: &Valtype) -> String {
     match v {
         Valtype::Int(n) => n.to_string(),
         Valtype::Str(s) => s.to_string(),
+        Valtype::Err(error) => error.to_string(),
     }
 }
 
-/// Reconstructs an Excel-style formula from `CellData`.
+/// A cell reference or range, either corner possibly marked absolute with '$'.
+static RE_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$?\b([A-Z]+\$?[0-9]+)(?::\$?\b([A-Z]+\$?[0-9]+))?\b").unwrap());
+
+/// Finds every cell reference and range in a formula, in order of appearance.
 ///
-/// Returns `None` if the cell has no formula (e.g., `Empty` or `Const`).
+/// Repeated references share a color slot, so `A1+A1` highlights a single cell in one color,
+/// as does `A1+$A$1`: references marked absolute with '$' are found like relative ones.
 ///
 /// # Arguments
-/// * `data` - The `CellData` to convert into a formula string.
+/// * `formula` - The formula text being edited (e.g., "SUM(A1:B3)", "A1+C2").
 ///
 /// # Returns
-/// An `Option<String>` containing the formula (e.g., "=A1", "=1+2") if applicable, or `None`
-/// for cells without formulas.
+/// A `Vec<FormulaReference>` with the byte span, normalized corners and color slot of each reference.
 ///
 /// # Examples
 /// ```rust
-/// use crate::CellData;
-/// use crate::Valtype;
+/// let refs = formula_references("A1+SUM(B2:C3)");
+/// assert_eq!(refs[1].top_left, (1, 1));
+/// assert_eq!(refs[1].bottom_right, (2, 2));
+/// ```
+pub(in crate::gui) fn formula_references(formula: &str) -> Vec<FormulaReference> {
+    let mut seen: Vec<String> = Vec::new();
+    let mut references = Vec::new();
+    for caps in RE_REFERENCE.captures_iter(formula) {
+        let whole = caps.get(0).unwrap();
+        // '$' markers only matter when a formula is copied, so $A$1 is colored like A1
+        let unmarked = whole.as_str().replace('$', "");
+        let Some(start) = parse_cell_name(&caps[1].replace('$', "")) else {
+            continue;
+        };
+        let end = match caps.get(2) {
+            Some(m) => match parse_cell_name(&m.as_str().replace('$', "")) {
+                Some(end) => end,
+                None => continue,
+            },
+            None => start,
+        };
+        let color_slot = match seen.iter().position(|s| *s == unmarked) {
+            Some(slot) => slot,
+            None => {
+                seen.push(unmarked);
+                seen.len() - 1
+            }
+        };
+        references.push(FormulaReference {
+            span: whole.range(),
+            top_left: (start.0.min(end.0), start.1.min(end.1)),
+            bottom_right: (start.0.max(end.0), start.1.max(end.1)),
+            color_slot,
+        });
+    }
+    references
+}
+
+/// The functions offered by formula bar autocomplete.
+const FUNCTION_NAMES: [&str; 19] = [
+    "ABS",
+    "AVG",
+    "CEIL",
+    "COUNT",
+    "FLOOR",
+    "MAX",
+    "MAX2",
+    "MIN",
+    "MIN2",
+    "MOD",
+    "POW",
+    "RAND",
+    "RANDBETWEEN",
+    "ROUND",
+    "SLEEP",
+    "SPARK",
+    "STDEV",
+    "SUM",
+    "VLOOKUP",
+];
+
+/// The most completions offered at once.
+const MAX_SUGGESTIONS: usize = 8;
+
+/// Returns the word being typed at the end of a formula: its trailing letters and digits.
+///
+/// # Arguments
+/// * `text` - The formula text being edited.
+///
+/// # Returns
+/// The trailing word, empty if the text ends in an operator, a parenthesis or a space.
+pub(in crate::gui) fn trailing_word(text: &str) -> &str {
+    let start = text
+        .rfind(|c: char| !c.is_ascii_alphanumeric())
+        .map_or(0, |i| i + 1);
+    &text[start..]
+}
+
+/// Lists the completions of the word at the end of a formula: function names, with their
+/// opening parenthesis, followed by the names of non-empty cells.
 ///
-/// let ref_data = CellData::Ref { cell1: String::from("A1") };
-/// assert_eq!(cell_data_to_formula_string(&ref_data), Some(String::from("=A1")));
+/// # Arguments
+/// * `text` - The formula text being edited.
+/// * `cells` - The (row, col) of every non-empty cell.
 ///
-/// let empty_data = CellData::Empty;
-/// assert_eq!(cell_data_to_formula_string(&empty_data), None);
+/// # Returns
+/// A `Vec<String>` of at most `MAX_SUGGESTIONS` completions, cells in column-major order,
+/// empty if the word does not start with a letter or nothing completes it.
+///
+/// # Examples
+/// ```rust
+/// assert_eq!(completions("1+SU", [].into_iter()), vec!["SUM("]);
+/// assert_eq!(completions("A", [(0, 0), (1, 1)].into_iter()), vec!["AVG(", "A1"]);
 /// ```
-pub fn cell_data_to_formula_string(data: &CellData) -> Option<String> {
-    use CellData::*;
-    match data {
-        Empty | Const => None,
-        Ref { cell1 } => Some(format!("={}", cell1)),
-        CoC { op_code, value2 } => Some(format!(
-            "={}{}{}",
-            /* left operand? */ "",
-            op_code,
-            valtype_to_string(value2)
-        )),
-        CoR {
-            op_code,
-            value2,
-            cell2,
-        } => Some(format!(
-            "={}{}{}",
-            valtype_to_string(value2),
-            op_code,
-            cell2
-        )),
-        RoC {
-            op_code,
-            value2,
-            cell1,
-        } => Some(format!(
-            "={}{}{}",
-            cell1,
-            op_code,
-            valtype_to_string(value2)
-        )),
-        RoR {
-            op_code,
-            cell1,
-            cell2,
-        } => Some(format!("={}{}{}", cell1, op_code, cell2)),
-        Range {
-            cell1,
-            cell2,
-            value2,
-        } => Some(format!(
-            "=RANGE({}:{},{})",
-            cell1,
-            cell2,
-            valtype_to_string(value2)
-        )),
-        SleepC => Some("=SLEEP()".into()),
-        SleepR { cell1 } => Some(format!("=SLEEP({})", cell1)),
-        Invalid => Some("#INVALID".into()),
+pub(in crate::gui) fn completions(
+    text: &str,
+    cells: impl Iterator<Item = (usize, usize)>,
+) -> Vec<String> {
+    let word = trailing_word(text).to_ascii_uppercase();
+    if !word.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Vec::new();
     }
+    let mut suggestions: Vec<String> = FUNCTION_NAMES
+        .iter()
+        .filter(|name| name.starts_with(&word))
+        .map(|name| format!("{}(", name))
+        .collect();
+    let mut names: Vec<((usize, usize), String)> = cells
+        .map(|(row, col)| ((col, row), format!("{}{}", col_label(col), row + 1)))
+        .filter(|(_, name)| name.starts_with(&word) && *name != word)
+        .collect();
+    names.sort_unstable();
+    suggestions.extend(names.into_iter().map(|(_, name)| name));
+    suggestions.truncate(MAX_SUGGESTIONS);
+    suggestions
+}
+
+/// Splits text from the system clipboard into the rows and fields of a table.
+///
+/// Text with a tab in it is read as TSV, as other spreadsheets copy it, and anything else as
+/// CSV. Quoted fields may hold the separator or a line break.
+///
+/// # Arguments
+/// * `text` - The pasted text.
+///
+/// # Returns
+/// A `Vec<Vec<String>>` of fields, indexed by row then column. Rows may differ in length.
+///
+/// # Examples
+/// ```rust
+/// assert_eq!(clipboard_table("1\t2\n3\t4\n"), vec![vec!["1", "2"], vec!["3", "4"]]);
+/// assert_eq!(clipboard_table("5,\"6\""), vec![vec!["5", "6"]]);
+/// ```
+pub(in crate::gui) fn clipboard_table(text: &str) -> Vec<Vec<String>> {
+    let delimiter = if text.contains('\t') { b'\t' } else { b',' };
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(text.as_bytes())
+        .records()
+        .map_while(Result::ok)
+        .map(|record| record.iter().map(str::to_string).collect())
+        .collect()
 }
@@ -0,0 +1,159 @@
+//! # Import Module
+//! This module streams CSV data into a spreadsheet one line at a time, so that files larger
+//! than memory can be loaded. Only the current line is buffered, plain integer constants are
+//! written straight into the sparse cell map, and the caller is told about progress after
+//! every chunk of rows and may cancel the import between chunks.
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead};
+
+use crate::storage::SheetStorage;
+use crate::utils::parse_csv_record;
+use crate::validation::{ValidationTable, validated_assign};
+use crate::{Cell, CellData, CellKey, EvalStatus, Valtype};
+
+/// Number of rows loaded between two progress reports.
+pub const IMPORT_CHUNK_ROWS: usize = 1024;
+
+/// How far a streaming import has got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportProgress {
+    /// Rows (lines) read so far.
+    pub rows: usize,
+    /// Bytes read so far.
+    pub bytes: u64,
+    /// Non-empty fields written into the sheet so far.
+    pub cells: usize,
+}
+
+/// The outcome of a streaming import.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Progress at the point the import finished or was cancelled.
+    pub progress: ImportProgress,
+    /// `Ok(())` if every field loaded, or the status of the first failure.
+    pub status: Result<(), EvalStatus>,
+    /// Whether the progress callback cancelled the import before the end of the input.
+    pub cancelled: bool,
+}
+
+/// Parses a field that is a plain integer constant, as accepted by `parser::detect_formula`.
+fn constant(field: &str) -> Option<i32> {
+    let digits = field.strip_prefix('-').unwrap_or(field);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+/// Streams CSV records into the spreadsheet, one record per row starting at A1.
+///
+/// Each non-empty field is assigned like a typed formula, with an optional leading '=' as
+/// written by the formula export, and checked against the validation rules. A constant going
+/// into a cell that nothing refers to yet, and that breaks no rule, is stored directly,
+/// skipping the dependency update. Loading continues past bad fields.
+///
+/// Every cell is fully recalculated as it is written, so when the import stops, whether at
+/// the end of the input, on a read error, or because it was cancelled, the sheet holds the
+/// rows read so far with consistent values.
+///
+/// # Arguments
+/// * `reader` - The CSV input.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `rules` - The validation rules of the sheet, checked as by `validated_assign`.
+/// * `progress` - Called after every `IMPORT_CHUNK_ROWS` rows; returning `false` cancels the
+///   import.
+///
+/// # Returns
+/// * `io::Result<ImportSummary>` - An error if the input could not be read, or how much was
+///   loaded and whether every field was valid.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::import::*;
+/// # use spreadsheet::validation::ValidationTable;
+/// # let mut rules = ValidationTable::default();
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// let summary = stream_csv(&mut "1,2\n=A1+B1\n".as_bytes(), &mut sheet, &mut ranged,
+///     &mut is_range, (10, 10), &mut rules, |p| { println!("{} rows", p.rows); true })?;
+/// assert_eq!(summary.progress.rows, 2);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn stream_csv<S: SheetStorage + ?Sized, R: BufRead>(
+    reader: &mut R,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    rules: &mut ValidationTable,
+    mut progress: impl FnMut(ImportProgress) -> bool,
+) -> io::Result<ImportSummary> {
+    let (total_rows, total_cols) = total_dims;
+    let mut done = ImportProgress::default();
+    let mut status = Ok(());
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let len = reader.read_line(&mut line)?;
+        if len == 0 {
+            break;
+        }
+        let row = done.rows;
+        done.rows += 1;
+        done.bytes += len as u64;
+        for (col, field) in parse_csv_record(&line).iter().enumerate() {
+            let formula = field.strip_prefix('=').unwrap_or(field).trim();
+            if formula.is_empty() {
+                continue;
+            }
+            if row >= total_rows || col >= total_cols {
+                status = status.and(Err(EvalStatus::InvalidRange));
+                continue;
+            }
+            let key = (row * total_cols + col) as CellKey;
+            let fresh = !spreadsheet.contains_key(&key) && !is_range[key as usize];
+            let constant = constant(formula).map(|value| Cell {
+                value: Valtype::Int(value),
+                data: CellData::Const,
+                dependents: HashSet::new(),
+            });
+            let result = match constant {
+                Some(cell) if fresh && rules.broken_rule(row, col, Some(&cell)).is_none() => {
+                    spreadsheet.insert(key, cell);
+                    Ok(())
+                }
+                _ => {
+                    validated_assign(
+                        rules,
+                        spreadsheet,
+                        ranged,
+                        is_range,
+                        total_dims,
+                        (row, col),
+                        formula,
+                    )
+                    .status
+                }
+            };
+            done.cells += 1;
+            status = status.and(result);
+        }
+        if done.rows % IMPORT_CHUNK_ROWS == 0 && !progress(done) {
+            return Ok(ImportSummary {
+                progress: done,
+                status,
+                cancelled: true,
+            });
+        }
+    }
+    Ok(ImportSummary {
+        progress: done,
+        status,
+        cancelled: false,
+    })
+}
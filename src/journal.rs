@@ -0,0 +1,246 @@
+//! # Journal Module
+//! This module decides what goes into the journal of a command-line session: a log of every
+//! command that changed the sheet, which `replay` runs again to rebuild it after a crash, and
+//! which shows exactly what was typed when a graded result is disputed. A journal starts with a
+//! header naming the dimensions of the sheet, then the commands that rebuild what the sheet
+//! already held: its names, cells, validation rules and locks. One command per line follows,
+//! except for commands that read a file or a framed import, whose changed cells are written as
+//! assignments instead.
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::CellData;
+use crate::lock::LockTable;
+use crate::names::NameTable;
+use crate::storage::SheetStorage;
+use crate::utils::{formula_string, to_cell_name};
+use crate::validation::ValidationTable;
+
+/// The start of the first line of a journal, followed by the rows and columns of the sheet.
+const HEADER: &str = "# journal";
+
+/// Commands that change the sheet, or how later commands change it, by their first words.
+/// `resize` is not among them: a journal replays on a sheet of one size, so the sheet cannot
+/// be resized while a journal is recorded.
+const MUTATING: [&str; 13] = [
+    "histogram ",
+    "fill ",
+    "clear ",
+    "sort ",
+    "load ",
+    "open ",
+    "replace ",
+    "autototal ",
+    "validate ",
+    "name range ",
+    "lock ",
+    "unlock ",
+    "seed ",
+];
+
+/// Commands matched before assignments, which may contain '=' without assigning a cell.
+const QUERIES: [&str; 9] = [
+    "save ",
+    "export_snapshot ",
+    "run ",
+    "replay ",
+    "journal ",
+    "find ",
+    "dump",
+    "diff ",
+    "graph ",
+];
+
+/// Commands without arguments that change how the sheet is calculated.
+const SETTINGS: [&str; 6] = [
+    "calc manual",
+    "calc auto",
+    "recalc",
+    "recalc all",
+    "clamp_ranges on",
+    "clamp_ranges off",
+];
+
+/// Returns whether a command changes the sheet, and so belongs in the journal.
+///
+/// Commands that only look at the sheet, move the view or change how it is printed are left
+/// out, as are `run` and `replay`, whose own commands are journaled one by one.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::journal::*;
+/// assert!(is_mutating("A1=B1+1"));
+/// assert!(is_mutating("clear A1:B2"));
+/// assert!(!is_mutating("find =SUM"));
+/// assert!(!is_mutating("w"));
+/// ```
+pub fn is_mutating(command: &str) -> bool {
+    if MUTATING.iter().any(|prefix| command.starts_with(prefix)) {
+        return true;
+    }
+    if QUERIES.iter().any(|prefix| command.starts_with(prefix)) {
+        return false;
+    }
+    command.contains('=') || SETTINGS.contains(&command)
+}
+
+/// Commands that read a file, which may have changed or be gone when the journal is replayed.
+const READS_FILE: [&str; 2] = ["load ", "open "];
+
+/// Returns whether a command is written to the journal as typed: it changes the sheet and
+/// reads no file. The journal gets the cells a `load` or `open` changed instead, so that it
+/// replays without the file.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::journal::*;
+/// assert!(is_journaled("A1=B1+1"));
+/// assert!(!is_journaled("open data.csv"));
+/// assert!(!is_journaled("find =SUM"));
+/// ```
+pub fn is_journaled(command: &str) -> bool {
+    is_mutating(command) && !READS_FILE.iter().any(|prefix| command.starts_with(prefix))
+}
+
+/// Reads the dimensions from the first line of a journal.
+///
+/// # Arguments
+/// * `line` - The first line of the journal.
+///
+/// # Returns
+/// * `Option<(usize, usize)>` - The `(total_rows, total_cols)` of the sheet the journal was
+///   recorded on, or `None` if the line is not a journal header.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::journal::*;
+/// assert_eq!(parse_journal_header("# journal 10 20"), Some((10, 20)));
+/// assert_eq!(parse_journal_header("A1=5"), None);
+/// ```
+pub fn parse_journal_header(line: &str) -> Option<(usize, usize)> {
+    let mut dims = line.strip_prefix(HEADER)?.split_whitespace();
+    let (Some(rows), Some(cols), None) = (dims.next(), dims.next(), dims.next()) else {
+        return None;
+    };
+    Some((rows.parse().ok()?, cols.parse().ok()?))
+}
+
+/// Lists the opening lines of a journal: the header, then the commands that rebuild the sheet
+/// as it is when the journal is started, so that replaying the journal on an empty sheet starts
+/// from there. The names come first, then an assignment for each non-empty cell, in row-major
+/// order, written with its name if it was, then the validation rules and last the locks, which
+/// would refuse the assignments.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `names` - The named ranges of the sheet.
+/// * `rules` - The validation rules of the sheet.
+/// * `locks` - The locked cells of the sheet.
+///
+/// # Returns
+/// * `Vec<String>` - The lines, without line endings.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::journal::*;
+/// # use spreadsheet::lock::LockTable;
+/// # use spreadsheet::names::NameTable;
+/// # use spreadsheet::validation::ValidationTable;
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 30]);
+/// # let (names, rules, mut locks) = (NameTable::default(), ValidationTable::default(), LockTable::default());
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 3), 0, 1, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 3), 0, 0, "B1+2")?;
+/// # locks.set("B1", (10, 3), true)?;
+/// // B1 = 5, A1 = B1+2, B1 locked
+/// assert_eq!(
+///     journal_start(&sheet, (10, 3), &names, &rules, &locks),
+///     vec!["# journal 10 3", "A1=B1+2", "B1=5", "lock B1:B1"]
+/// );
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn journal_start<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_dims: (usize, usize),
+    names: &NameTable,
+    rules: &ValidationTable,
+    locks: &LockTable,
+) -> Vec<String> {
+    let mut lines = vec![format!("{} {} {}", HEADER, total_dims.0, total_dims.1)];
+    lines.extend(names.commands());
+    lines.extend(cell_formulas(spreadsheet, total_dims.1).into_iter().map(
+        |((row, col), formula)| {
+            let formula = names.named_formula(row, col, &formula).unwrap_or(formula);
+            format!("{}={}", to_cell_name(row, col), formula)
+        },
+    ));
+    lines.extend(rules.commands());
+    lines.extend(locks.commands());
+    lines
+}
+
+/// Lists the formula of every non-empty cell, by (row, col), e.g. to find the cells a command
+/// that cannot be journaled itself, such as a framed CSV import, wrote.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `BTreeMap<(usize, usize), String>` - The formulas, in row-major order.
+pub fn cell_formulas<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_cols: usize,
+) -> BTreeMap<(usize, usize), String> {
+    spreadsheet
+        .cells()
+        .filter(|(_, cell)| !matches!(cell.data, CellData::Empty | CellData::Invalid))
+        .map(|(key, cell)| {
+            let key = key as usize;
+            ((key / total_cols, key % total_cols), formula_string(cell))
+        })
+        .collect()
+}
+
+/// Lists the commands that replay the changes between two listings of `cell_formulas`: an
+/// assignment for each cell whose formula changed, and a `clear` for each cell emptied.
+///
+/// # Arguments
+/// * `before` - The formulas before the change.
+/// * `after` - The formulas after it.
+///
+/// # Returns
+/// * `Vec<String>` - The commands, in row-major order.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::journal::*;
+/// # use std::collections::BTreeMap;
+/// let before = BTreeMap::from([((0, 0), "1".to_string()), ((0, 1), "2".to_string())]);
+/// let after = BTreeMap::from([((0, 0), "5".to_string()), ((1, 0), "A1+1".to_string())]);
+/// assert_eq!(journal_changes(&before, &after), vec!["A1=5", "clear B1", "A2=A1+1"]);
+/// ```
+pub fn journal_changes(
+    before: &BTreeMap<(usize, usize), String>,
+    after: &BTreeMap<(usize, usize), String>,
+) -> Vec<String> {
+    let cells: BTreeSet<&(usize, usize)> = before.keys().chain(after.keys()).collect();
+    cells
+        .into_iter()
+        .filter_map(
+            |&(row, col)| match (before.get(&(row, col)), after.get(&(row, col))) {
+                (old, Some(formula)) if old != Some(formula) => {
+                    Some(format!("{}={}", to_cell_name(row, col), formula))
+                }
+                (Some(_), None) => Some(format!("clear {}", to_cell_name(row, col))),
+                _ => None,
+            },
+        )
+        .collect()
+}
@@ -0,0 +1,732 @@
+//! # Spreadsheet Engine
+//! The core of the Rust Spreadsheet, shared by the command-line interface, the GUI, and
+//! external programs. It defines the cell model and formula types, and exposes the formula
+//! parser, evaluator, and dependency tracking through the `Spreadsheet` struct.
+use std::collections::HashSet;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub mod cache;
+pub mod collab;
+pub mod dump;
+mod engine;
+pub mod format;
+pub mod graph;
+pub mod import;
+pub mod info;
+pub mod journal;
+pub mod lint;
+pub mod lock;
+pub mod names;
+pub mod parser;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod search;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod snapshot;
+pub mod sort;
+pub mod storage;
+pub mod structure;
+pub mod syntax;
+pub mod utils;
+pub mod validation;
+pub mod visits;
+pub mod watch;
+pub mod workload;
+
+pub use engine::Spreadsheet;
+
+/// The key a cell is stored under, `row * total_cols + col` for zero-based indices.
+///
+/// It is 64 bits wide so that keys cannot overflow however far the sheet dimensions grow.
+pub type CellKey = u64;
+
+/// A compact inline string of at most 7 bytes, such as a function name (e.g., "SUM").
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CellName {
+    len: u8,
+    data: [u8; 7],
+}
+
+impl CellName {
+    /// Creates a new `CellName` from a string.
+    ///
+    /// # Arguments
+    /// * `s` - The string representation of the cell (e.g., "A1").
+    ///
+    /// # Returns
+    /// * `Result<Self, &'static str>` - Success with a `CellName` or an error message if the input is invalid.
+    ///
+    /// # Errors
+    /// * Returns `Err` if the string is longer than 7 bytes in UTF-8.
+    pub fn new(s: &str) -> Result<Self, &'static str> {
+        if s.len() > 7 {
+            return Err("CellName too long");
+        }
+        let mut data = [0u8; 7];
+        data[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(CellName {
+            len: s.len() as u8,
+            data,
+        })
+    }
+    /// Returns the string representation of the `CellName`.
+    ///
+    /// # Returns
+    /// * `&str` - The string representation of the cell reference.
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.data[..self.len as usize]).unwrap()
+    }
+}
+
+impl std::fmt::Display for CellName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for CellName {
+    /// Serializes the `CellName` as its string form (e.g., "A1").
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for CellName {
+    /// Deserializes a `CellName` from its string form, rejecting invalid names.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        CellName::new(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::str::FromStr for CellName {
+    type Err = &'static str;
+    /// Parses a string into a `CellName`.
+    ///
+    /// # Arguments
+    /// * `s` - The string to parse.
+    ///
+    /// # Returns
+    /// * `Result<Self, Self::Err>` - Success with a `CellName` or an error if parsing fails.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CellName::new(s)
+    }
+}
+
+/// The text held by a cell, such as a label typed as `A1="Größe"`, or the function name of a
+/// range formula. Unlike `CellName` it holds UTF-8 text of any length, and is saved as a plain
+/// string.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// let label = CellText::new("Größe");
+/// assert_eq!(label.as_str(), "Größe");
+/// assert_eq!(label.to_string().len(), 7);
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CellText(Box<str>);
+
+impl CellText {
+    /// Creates a new `CellText` holding a copy of a string.
+    pub fn new(s: &str) -> Self {
+        CellText(s.into())
+    }
+
+    /// Returns the text.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for CellText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<CellName> for CellText {
+    /// Copies a function name, such as "SUM", into a `CellText`.
+    fn from(name: CellName) -> Self {
+        CellText::new(name.as_str())
+    }
+}
+
+/// A cell reference as written in a formula, packed as its one-based row and column.
+///
+/// References of any length are supported, and '$' markers (as in "$A$1") are kept so that
+/// absolute parts can be preserved when a formula is copied.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// let cell = CellRef::new("$AB$12").unwrap();
+/// assert_eq!(cell.indices(), (11, 27));
+/// assert_eq!(cell.to_string(), "$AB$12");
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CellRef {
+    row: u32,
+    col: u32,
+    abs_row: bool,
+    abs_col: bool,
+}
+
+impl CellRef {
+    /// Creates a new `CellRef` from a reference string.
+    ///
+    /// # Arguments
+    /// * `s` - The reference: column letters then a row number, each optionally marked with '$'.
+    ///
+    /// # Returns
+    /// * `Result<Self, &'static str>` - Success with a `CellRef` or an error message if the input is invalid.
+    ///
+    /// # Errors
+    /// * Returns `Err` if the string is not a reference, or its row or column does not fit in a `u32`.
+    ///   A row of 0 is accepted, and reported as an invalid range when the reference is resolved.
+    pub fn new(s: &str) -> Result<Self, &'static str> {
+        let (abs_col, s) = match s.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let split_pos = s
+            .find(|c: char| !c.is_ascii_uppercase())
+            .ok_or("CellRef missing row")?;
+        let (letters, rest) = s.split_at(split_pos);
+        let (abs_row, digits) = match rest.strip_prefix('$') {
+            Some(digits) => (true, digits),
+            None => (false, rest),
+        };
+        if letters.is_empty() || digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err("CellRef must be column letters followed by a row number");
+        }
+        let col = letters
+            .bytes()
+            .try_fold(0u32, |acc, b| {
+                acc.checked_mul(26)?.checked_add((b - b'A' + 1) as u32)
+            })
+            .ok_or("CellRef column too large")?;
+        let row = digits.parse::<u32>().map_err(|_| "CellRef row too large")?;
+        Ok(CellRef {
+            row,
+            col,
+            abs_row,
+            abs_col,
+        })
+    }
+
+    /// Creates a relative `CellRef` for zero-based row and column indices.
+    pub fn from_indices(row: usize, col: usize) -> Self {
+        CellRef {
+            row: row as u32 + 1,
+            col: col as u32 + 1,
+            abs_row: false,
+            abs_col: false,
+        }
+    }
+
+    /// Converts the reference to zero-based (row, column) indices.
+    ///
+    /// # Returns
+    /// A tuple `(usize, usize)`. A reference to row 0, which never passes validation, gives
+    /// (0, 0); use `addr` to tell it apart.
+    pub fn indices(&self) -> (usize, usize) {
+        self.addr().map_or((0, 0), |a| (a.row, a.col))
+    }
+
+    /// Returns the zero-based address of the reference.
+    ///
+    /// # Returns
+    /// * `Option<CellAddr>` - The address, or `None` if the row is 0.
+    pub fn addr(&self) -> Option<CellAddr> {
+        (self.row > 0).then(|| CellAddr::new(self.row as usize - 1, self.col as usize - 1))
+    }
+
+    /// Returns the same reference pointed at another cell, keeping its '$' markers.
+    pub fn with_addr(&self, addr: CellAddr) -> Self {
+        CellRef {
+            row: addr.row as u32 + 1,
+            col: addr.col as u32 + 1,
+            ..*self
+        }
+    }
+
+    /// Moves the reference by a row and column offset, as when a formula is copied.
+    ///
+    /// A part marked absolute with '$' (the column in "$A1", the row in "A$1") is left as is.
+    ///
+    /// # Arguments
+    /// * `d_row` - The row offset.
+    /// * `d_col` - The column offset.
+    /// * `total_rows` - The total number of rows in the spreadsheet.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    ///
+    /// # Returns
+    /// * `Option<Self>` - The shifted reference, or `None` if it would leave the sheet.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// let cell = CellRef::new("A$1").unwrap();
+    /// assert_eq!(cell.shifted(1, 1, 10, 10).unwrap().to_string(), "B$1");
+    /// ```
+    pub fn shifted(
+        &self,
+        d_row: isize,
+        d_col: isize,
+        total_rows: usize,
+        total_cols: usize,
+    ) -> Option<Self> {
+        let shift = |v: u32, d: isize, abs: bool| {
+            if abs {
+                Some(v)
+            } else {
+                u32::try_from((v as isize).checked_add(d)?).ok()
+            }
+        };
+        let row = shift(self.row, d_row, self.abs_row)?;
+        let col = shift(self.col, d_col, self.abs_col)?;
+        if row == 0 || row as usize > total_rows || col == 0 || col as usize > total_cols {
+            return None;
+        }
+        Some(CellRef { row, col, ..*self })
+    }
+}
+
+impl std::fmt::Display for CellRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut letters = Vec::new();
+        let mut n = self.col;
+        while n > 0 {
+            letters.push(b'A' + ((n - 1) % 26) as u8);
+            n = (n - 1) / 26;
+        }
+        letters.reverse();
+        write!(
+            f,
+            "{}{}{}{}",
+            if self.abs_col { "$" } else { "" },
+            std::str::from_utf8(&letters).unwrap(),
+            if self.abs_row { "$" } else { "" },
+            self.row
+        )
+    }
+}
+
+impl Serialize for CellRef {
+    /// Serializes the `CellRef` as its string form (e.g., "A1").
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CellRef {
+    /// Deserializes a `CellRef` from its string form, rejecting invalid references.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        CellRef::new(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::str::FromStr for CellRef {
+    type Err = &'static str;
+    /// Parses a string into a `CellRef`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CellRef::new(s)
+    }
+}
+/// A zero-based cell address, used by the range-reading API.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// let addr = CellAddr::parse("B3").unwrap();
+/// assert_eq!(addr, CellAddr::new(2, 1));
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CellAddr {
+    pub row: usize,
+    pub col: usize,
+}
+
+impl CellAddr {
+    /// Creates a new `CellAddr` from zero-based row and column indices.
+    pub fn new(row: usize, col: usize) -> Self {
+        CellAddr { row, col }
+    }
+
+    /// Parses a cell reference such as "A1".
+    ///
+    /// # Arguments
+    /// * `name` - The cell reference: column letters followed by a one-based row number.
+    ///
+    /// # Returns
+    /// * `Option<Self>` - The address, or `None` if `name` is not a valid reference.
+    pub fn parse(name: &str) -> Option<Self> {
+        let split_pos = name.find(|c: char| c.is_ascii_digit())?;
+        let (letters, digits) = name.split_at(split_pos);
+        if letters.is_empty() || !letters.bytes().all(|b| b.is_ascii_uppercase()) {
+            return None;
+        }
+        let row = digits.parse::<usize>().ok()?.checked_sub(1)?;
+        let col = letters.bytes().try_fold(0usize, |acc, b| {
+            acc.checked_mul(26)?.checked_add((b - b'A' + 1) as usize)
+        })? - 1;
+        Some(CellAddr { row, col })
+    }
+}
+
+/// Array of status messages used to indicate the outcome of operations.
+pub const STATUS: [&str; 9] = [
+    "ok",
+    "Invalid range",
+    "unrecognized cmd",
+    "cycle detected",
+    "range clamped",
+    "validation failed",
+    "cell locked",
+    "evaluation cancelled",
+    "journal recording",
+];
+/// The failure of a spreadsheet operation, returned in place of a status code.
+///
+/// Every variant but `ErrValue` maps to a message in `STATUS`, and `Ok(())` stands for "ok".
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvalStatus {
+    /// A reference or range lies outside the sheet.
+    InvalidRange,
+    /// The command or formula is not recognized.
+    UnrecognizedCmd,
+    /// The assignment would make a cell depend on itself.
+    CycleDetected,
+    /// A range running past the edge of the sheet was cut down to fit, and the formula was
+    /// assigned. This is a warning: the command did not fail.
+    RangeClamped,
+    /// A cell breaks a validation rule after the edit, which was undone or kept and marked
+    /// depending on the rule.
+    ValidationFailed,
+    /// The cell is locked with `lock`, so no formula can be assigned to it.
+    CellLocked,
+    /// The recalculation was stopped with `utils::CancelToken`, and the edit rolled back.
+    Cancelled,
+    /// The command would change the sheet in a way the journal being recorded cannot replay,
+    /// such as a `resize`.
+    JournalRecording,
+    /// The value is an error, e.g. after a division by zero. `parser::eval` turns it into an
+    /// error cell value, so it is never reported as a status.
+    ErrValue(CellError),
+}
+
+impl EvalStatus {
+    /// Returns the index of the status message in `STATUS`.
+    pub fn code(self) -> usize {
+        match self {
+            EvalStatus::InvalidRange => 1,
+            EvalStatus::UnrecognizedCmd => 2,
+            EvalStatus::CycleDetected => 3,
+            EvalStatus::RangeClamped => 4,
+            EvalStatus::ValidationFailed => 5,
+            EvalStatus::CellLocked => 6,
+            EvalStatus::Cancelled => 7,
+            EvalStatus::JournalRecording => 8,
+            EvalStatus::ErrValue(_) => 0,
+        }
+    }
+
+    /// Returns the status message shown for the outcome of an operation.
+    ///
+    /// # Arguments
+    /// * `status` - The outcome of the operation.
+    ///
+    /// # Returns
+    /// * `&'static str` - The message from `STATUS`, "ok" on success.
+    pub fn message(status: Result<(), EvalStatus>) -> &'static str {
+        STATUS[status.err().map_or(0, EvalStatus::code)]
+    }
+}
+/// An error held by a cell in place of a number, shown as in other spreadsheets.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CellError {
+    /// `#DIV/0!`: a division by zero.
+    DivZero,
+    /// `#REF!`: a range that is reversed or lies outside the sheet.
+    Ref,
+    /// `#CYCLE!`: the cell depends on itself, e.g. in a workbook saved with a cycle.
+    Cycle,
+    /// `#VALUE!`: an operand is text rather than a number.
+    Value,
+    /// `#NAME?`: an unknown function name.
+    Name,
+    /// `#N/A`: a lookup key that is not in the table.
+    NotAvailable,
+    /// `#NUM!`: a result too large for a cell, such as the sum of a range of large numbers.
+    Num,
+}
+
+impl CellError {
+    /// Returns the text shown for the error.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CellError::DivZero => "#DIV/0!",
+            CellError::Ref => "#REF!",
+            CellError::Cycle => "#CYCLE!",
+            CellError::Value => "#VALUE!",
+            CellError::Name => "#NAME?",
+            CellError::NotAvailable => "#N/A",
+            CellError::Num => "#NUM!",
+        }
+    }
+}
+
+impl From<EvalStatus> for CellError {
+    /// Returns the error a cell shows when its formula fails with the given status.
+    fn from(status: EvalStatus) -> Self {
+        match status {
+            EvalStatus::ErrValue(error) => error,
+            EvalStatus::InvalidRange => CellError::Ref,
+            EvalStatus::UnrecognizedCmd => CellError::Name,
+            EvalStatus::CycleDetected => CellError::Cycle,
+            EvalStatus::RangeClamped
+            | EvalStatus::ValidationFailed
+            | EvalStatus::CellLocked
+            | EvalStatus::Cancelled
+            | EvalStatus::JournalRecording => CellError::Value,
+        }
+    }
+}
+
+impl std::fmt::Display for CellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Represents the type of formula a cell can contain.
+pub enum FormulaType {
+    SleepC,
+    SleepR,
+    Const,
+    Ref,
+    CoR,
+    RoC,
+    CoC,
+    RoR,
+    Range,
+    Invalid,
+}
+/// Represents the value of a cell: an integer, text (such as a label or a function name), or
+/// an error.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Valtype {
+    Int(i32),
+    Str(CellText),
+    Err(CellError),
+}
+/// Represents one side of a binary expression that uses a range function as an operand.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operand {
+    Const(i32),
+    Ref(CellRef),
+    Range {
+        func: CellName,
+        cell1: CellRef,
+        cell2: CellRef,
+    },
+}
+
+impl Operand {
+    /// Returns the corners of the cells the operand reads.
+    ///
+    /// # Returns
+    /// * `Option<(CellRef, CellRef)>` - The (top-left, bottom-right) corners, the same cell
+    ///   twice for a reference, or `None` for a constant.
+    pub fn corners(&self) -> Option<(CellRef, CellRef)> {
+        match self {
+            Operand::Const(_) => None,
+            Operand::Ref(cell) => Some((*cell, *cell)),
+            Operand::Range { cell1, cell2, .. } => Some((*cell1, *cell2)),
+        }
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Const(value) => write!(f, "{}", value),
+            Operand::Ref(cell) => write!(f, "{}", cell),
+            Operand::Range { func, cell1, cell2 } => write!(f, "{}({}:{})", func, cell1, cell2),
+        }
+    }
+}
+
+/// Represents a binary expression with at least one range function operand
+/// (e.g., "SUM(A1:A5)/COUNT(A1:A5)").
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Expr {
+    pub op_code: char,
+    pub lhs: Operand,
+    pub rhs: Operand,
+}
+
+impl Expr {
+    /// Returns the two operands, left first.
+    pub fn operands(&self) -> [&Operand; 2] {
+        [&self.lhs, &self.rhs]
+    }
+}
+
+/// Represents a `VLOOKUP(key, A1:C20, column)` formula, which finds the key in the first
+/// column of the table and reads the same row of another column.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lookup {
+    /// The value looked up, a constant or a cell reference.
+    pub key: Operand,
+    /// The top-left corner of the table.
+    pub cell1: CellRef,
+    /// The bottom-right corner of the table.
+    pub cell2: CellRef,
+    /// The column of the table to read, 1 for its first column.
+    pub column: u32,
+}
+
+impl Lookup {
+    /// Returns the corners of the cells the lookup reads, the key first if it is a reference.
+    ///
+    /// # Returns
+    /// * `Vec<(CellRef, CellRef)>` - The (top-left, bottom-right) corners, the same cell twice
+    ///   for the key.
+    pub fn corners(&self) -> Vec<(CellRef, CellRef)> {
+        self.key
+            .corners()
+            .into_iter()
+            .chain([(self.cell1, self.cell2)])
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Lookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "VLOOKUP({},{}:{},{})",
+            self.key, self.cell1, self.cell2, self.column
+        )
+    }
+}
+
+/// Represents the type of data stored in a cell, including constants, references, and operations.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CellData {
+    Empty,
+    SleepC,
+    SleepR {
+        cell1: CellRef,
+    },
+    Const,
+    Ref {
+        cell1: CellRef,
+    },
+    CoC {
+        value1: Valtype,
+        op_code: char,
+        value2: Valtype,
+    },
+    CoR {
+        op_code: char,
+        value2: Valtype,
+        cell2: CellRef,
+    },
+    RoC {
+        op_code: char,
+        value2: Valtype,
+        cell1: CellRef,
+    },
+    RoR {
+        op_code: char,
+        cell1: CellRef,
+        cell2: CellRef,
+    },
+    Range {
+        cell1: CellRef,
+        cell2: CellRef,
+        value2: Valtype,
+        /// The areas after `cell1:cell2` of a function over several, e.g. `SUM(A1:A5,C1:C5)`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        extra: Vec<(CellRef, CellRef)>,
+    },
+    Expr(Box<Expr>),
+    Lookup(Box<Lookup>),
+    /// A scalar function of constants and references, e.g. `MOD(A1,3)`.
+    Func {
+        func: CellName,
+        args: Vec<Operand>,
+    },
+    Invalid,
+}
+
+impl CellData {
+    /// Returns the areas a range function reads, `cell1:cell2` first.
+    ///
+    /// # Returns
+    /// * `Vec<(CellRef, CellRef)>` - The (top-left, bottom-right) corners of each area, or
+    ///   nothing if the data is not a range function.
+    pub fn range_areas(&self) -> Vec<(CellRef, CellRef)> {
+        match self {
+            CellData::Range {
+                cell1,
+                cell2,
+                extra,
+                ..
+            } => std::iter::once((*cell1, *cell2))
+                .chain(extra.iter().copied())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks whether the formula is volatile: evaluated again at every recalculation, whether
+    /// or not its inputs changed. `SLEEP` waits again each time, and `RAND()` and
+    /// `RANDBETWEEN(a,b)` draw new values.
+    ///
+    /// The parser sets the formula, so this is the volatile flag of a cell.
+    pub fn is_volatile(&self) -> bool {
+        match self {
+            CellData::SleepC | CellData::SleepR { .. } => true,
+            CellData::Func { func, .. } => utils::VOLATILE_FUNCTIONS.contains(&func.as_str()),
+            _ => false,
+        }
+    }
+}
+/// Represents a cell in the spreadsheet, containing its value, data type, and dependents.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Cell {
+    pub value: Valtype,
+    pub data: CellData,
+    pub dependents: HashSet<CellKey>,
+}
+impl Cell {
+    /// Resets the cell to its default state, preserving its dependents.
+    pub fn reset(&mut self) {
+        let current_dependents = std::mem::take(&mut self.dependents);
+        *self = Self {
+            value: Valtype::Int(0),
+            data: CellData::Empty,
+            dependents: current_dependents,
+        };
+    }
+
+    /// Clones a cell for backup without copying its dependents.
+    ///
+    /// # Returns
+    /// * `Self` - A new `Cell` with the same value and data, but an empty set of dependents.
+    pub fn my_clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            data: self.data.clone(),
+            dependents: HashSet::new(), // intentionally not cloning dependents
+        }
+    }
+}
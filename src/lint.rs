@@ -0,0 +1,315 @@
+//! # Lint Module
+//! This module looks for formulas that are valid but likely to be mistakes or to slow the sheet
+//! down: references to empty cells, the same constant typed into many formulas, very large
+//! ranges, long chains of formulas, and `SLEEP` formulas that other cells depend on.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::storage::SheetStorage;
+use crate::utils::{chain_depths, range_parents, to_cell_name};
+use crate::{CellAddr, CellData, CellKey, CellRef, EvalStatus, Operand, Valtype};
+
+/// How much attention a lint issue deserves, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    /// Returns the label shown in lint reports.
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+        }
+    }
+}
+
+/// The pattern a lint issue was raised for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintKind {
+    /// The formula refers to a cell that holds nothing.
+    EmptyReference(CellRef),
+    /// The same constant operand is written into `count` formulas.
+    RepeatedConstant { value: i32, count: usize },
+    /// The range formula reads `cells` cells.
+    OversizedRange { cells: usize },
+    /// The cell ends a chain of `depth` formulas, each reading the previous one.
+    DeepChain { depth: usize },
+    /// A `SLEEP` formula is read by `dependents` formulas, which wait on it when recalculated.
+    VolatileInHotPath { dependents: usize },
+}
+
+impl LintKind {
+    /// Returns the severity the issue is ranked by.
+    pub fn severity(&self) -> Severity {
+        match self {
+            LintKind::EmptyReference(_) | LintKind::RepeatedConstant { .. } => Severity::Low,
+            LintKind::OversizedRange { .. } | LintKind::DeepChain { .. } => Severity::Medium,
+            LintKind::VolatileInHotPath { .. } => Severity::High,
+        }
+    }
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintKind::EmptyReference(cell) => write!(f, "refers to empty cell {}", cell),
+            LintKind::RepeatedConstant { value, count } => write!(
+                f,
+                "constant {} appears in {} formulas; consider keeping it in one cell",
+                value, count
+            ),
+            LintKind::OversizedRange { cells } => write!(f, "range covers {} cells", cells),
+            LintKind::DeepChain { depth } => write!(f, "ends a chain of {} formulas", depth),
+            LintKind::VolatileInHotPath { dependents } => {
+                write!(f, "SLEEP is read by {} formulas", dependents)
+            }
+        }
+    }
+}
+
+/// A suspicious pattern found in a cell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LintIssue {
+    /// The cell the issue was raised for.
+    pub addr: CellAddr,
+    /// What was found.
+    pub kind: LintKind,
+}
+
+impl LintIssue {
+    /// Returns the severity of the issue.
+    pub fn severity(&self) -> Severity {
+        self.kind.severity()
+    }
+}
+
+impl fmt::Display for LintIssue {
+    /// Formats the issue as a report line, e.g. "high    B2: SLEEP is read by 3 formulas".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<7} {}: {}",
+            self.severity().label(),
+            to_cell_name(self.addr.row, self.addr.col),
+            self.kind
+        )
+    }
+}
+
+/// The thresholds above which patterns are reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LintOptions {
+    /// Longest chain of formulas that is not reported.
+    pub max_depth: usize,
+    /// Largest range, in cells, that is not reported.
+    pub max_range_cells: usize,
+    /// Number of formulas a constant must appear in to be reported.
+    pub min_repeats: usize,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        LintOptions {
+            max_depth: 16,
+            max_range_cells: 10_000,
+            min_repeats: 3,
+        }
+    }
+}
+
+/// Parses the arguments of `lint [depth]`.
+///
+/// # Arguments
+/// * `args` - The text following the `lint` keyword: empty, or the longest chain of formulas
+///   that is not reported.
+///
+/// # Returns
+/// * `Result<LintOptions, EvalStatus>` - The options, or `EvalStatus::UnrecognizedCmd` if the
+///   depth is not a number.
+pub fn parse_lint_args(args: &str) -> Result<LintOptions, EvalStatus> {
+    let mut options = LintOptions::default();
+    let args = args.trim();
+    if !args.is_empty() {
+        options.max_depth = args.parse().map_err(|_| EvalStatus::UnrecognizedCmd)?;
+    }
+    Ok(options)
+}
+
+/// Returns the single-cell references read by a formula.
+fn references(data: &CellData) -> Vec<CellRef> {
+    match data {
+        CellData::Ref { cell1 } | CellData::SleepR { cell1 } | CellData::RoC { cell1, .. } => {
+            vec![*cell1]
+        }
+        CellData::CoR { cell2, .. } => vec![*cell2],
+        CellData::RoR { cell1, cell2, .. } => vec![*cell1, *cell2],
+        CellData::Expr(expr) => expr
+            .operands()
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Ref(cell1) => Some(*cell1),
+                _ => None,
+            })
+            .collect(),
+        CellData::Lookup(lookup) => match lookup.key {
+            Operand::Ref(cell1) => vec![cell1],
+            _ => Vec::new(),
+        },
+        CellData::Func { args, .. } => args
+            .iter()
+            .filter_map(|operand| match operand {
+                Operand::Ref(cell1) => Some(*cell1),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Checks the sheet for suspicious formula patterns.
+///
+/// Constants 0 and 1 are too common to be worth naming and are never reported as repeated.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The thresholds above which patterns are reported.
+///
+/// # Returns
+/// * `Vec<LintIssue>` - The issues found, most severe first, then by row and column.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::lint::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "SLEEP(0)")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "A1+1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "A1+2")?;
+/// // A1=SLEEP(0), B1=A1+1, C1=A1+2
+/// let issues = lint(&sheet, &ranged, &is_range, (10, 10), &LintOptions::default());
+/// assert_eq!(issues[0].to_string(), "high    A1: SLEEP is read by 2 formulas");
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn lint<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &[bool],
+    total_dims: (usize, usize),
+    options: &LintOptions,
+) -> Vec<LintIssue> {
+    let (_, total_cols) = total_dims;
+    let addr = |key: CellKey| CellAddr::new(key as usize / total_cols, key as usize % total_cols);
+    let is_empty = |cell: &CellRef| {
+        cell.addr().is_none_or(|a| {
+            let key = (a.row * total_cols + a.col) as CellKey;
+            spreadsheet
+                .get(&key)
+                .is_none_or(|c| c.data == CellData::Empty)
+        })
+    };
+    let oversized = |key: CellKey, cell1: &CellRef, cell2: &CellRef| {
+        let ((r1, c1), (r2, c2)) = (cell1.indices(), cell2.indices());
+        let cells = (r2 + 1 - r1) * (c2 + 1 - c1);
+        (cells > options.max_range_cells).then(|| LintIssue {
+            addr: addr(key),
+            kind: LintKind::OversizedRange { cells },
+        })
+    };
+    let mut issues = Vec::new();
+    // First cell and number of formulas for each constant operand
+    let mut constants: BTreeMap<i32, (CellKey, usize)> = BTreeMap::new();
+    let mut keys: Vec<CellKey> = spreadsheet.cells().map(|(key, _)| key).collect();
+    keys.sort_unstable();
+
+    for &key in &keys {
+        let cell = spreadsheet.get(&key).unwrap();
+        let mut refs = references(&cell.data);
+        refs.dedup();
+        for cell_ref in refs.into_iter().filter(is_empty) {
+            issues.push(LintIssue {
+                addr: addr(key),
+                kind: LintKind::EmptyReference(cell_ref),
+            });
+        }
+        match &cell.data {
+            CellData::CoR { value2, .. } | CellData::RoC { value2, .. } => {
+                if let Valtype::Int(value) = *value2
+                    && value != 0
+                    && value != 1
+                {
+                    constants.entry(value).or_insert((key, 0)).1 += 1;
+                }
+            }
+            CellData::Range { .. } => {
+                for (cell1, cell2) in cell.data.range_areas() {
+                    issues.extend(oversized(key, &cell1, &cell2));
+                }
+            }
+            CellData::Expr(expr) => {
+                for operand in expr.operands() {
+                    match operand {
+                        Operand::Const(value) if *value != 0 && *value != 1 => {
+                            constants.entry(*value).or_insert((key, 0)).1 += 1;
+                        }
+                        Operand::Range { cell1, cell2, .. } => {
+                            issues.extend(oversized(key, cell1, cell2));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            CellData::SleepC | CellData::SleepR { .. } => {
+                let dependents =
+                    cell.dependents.len() + range_parents(key, ranged, total_cols).count();
+                if dependents > 0 {
+                    issues.push(LintIssue {
+                        addr: addr(key),
+                        kind: LintKind::VolatileInHotPath { dependents },
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    for (value, (key, count)) in constants {
+        if count >= options.min_repeats {
+            issues.push(LintIssue {
+                addr: addr(key),
+                kind: LintKind::RepeatedConstant { value, count },
+            });
+        }
+    }
+
+    // Only the last cell of a chain is reported, as everything it reads is deep too
+    for (key, d) in chain_depths(spreadsheet, ranged, total_cols) {
+        let Some(cell) = spreadsheet.get(&key) else {
+            continue;
+        };
+        if d > options.max_depth && cell.dependents.is_empty() && !is_range[key as usize] {
+            issues.push(LintIssue {
+                addr: addr(key),
+                kind: LintKind::DeepChain { depth: d },
+            });
+        }
+    }
+
+    issues.sort_by_key(|issue| {
+        (
+            std::cmp::Reverse(issue.severity()),
+            issue.addr.row,
+            issue.addr.col,
+        )
+    });
+    issues
+}
@@ -0,0 +1,140 @@
+//! # Lock Module
+//! This module keeps the cells locked with `lock A1:B10`, which formulas cannot be assigned to
+//! until they are unlocked with `unlock`. Locks are kept as the ranges given to the commands,
+//! in order, so locking a whole column costs no more than locking a cell, and a later
+//! `unlock` of part of a locked range leaves the rest of it locked.
+use crate::EvalStatus;
+use crate::utils::{parse_range, to_cell_name};
+
+/// The top-left and bottom-right (row, col) corners of a range.
+type Corners = ((usize, usize), (usize, usize));
+
+/// The locked cells of a sheet.
+///
+/// # Fields
+/// * `ranges` - The ranges given to `lock` and `unlock`, as top-left and bottom-right
+///   corners, with whether they were locked, oldest first. The last range holding a cell
+///   decides whether it is locked.
+#[derive(Clone, Debug, Default)]
+pub struct LockTable {
+    ranges: Vec<(Corners, bool)>,
+}
+
+/// Parses the range of a `lock` or `unlock` command, which may be a single cell.
+///
+/// # Arguments
+/// * `range` - The range, e.g. `A1:B10`, or a cell name.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+///
+/// # Returns
+/// * `Result<Corners, EvalStatus>` - The corners of the range, or `EvalStatus::InvalidRange` if
+///   the range is malformed or outside the sheet.
+fn parse_lock_range(range: &str, total_dims: (usize, usize)) -> Result<Corners, EvalStatus> {
+    let range = range.trim().to_ascii_uppercase();
+    let range = if range.contains(':') {
+        range
+    } else {
+        format!("{}:{}", range, range)
+    };
+    parse_range(&range, total_dims.0, total_dims.1).ok_or(EvalStatus::InvalidRange)
+}
+
+impl LockTable {
+    /// Locks the cells of a range, or unlocks them.
+    ///
+    /// # Arguments
+    /// * `range` - The range, e.g. `A1:B10`, or a cell name.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    /// * `lock` - Whether to lock the cells, or to unlock them.
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())`, or `EvalStatus::InvalidRange` if the range is
+    ///   malformed or outside the sheet.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::lock::*;
+    /// # let mut locks = LockTable::default();
+    /// locks.set("A1:B10", (20, 20), true)?;
+    /// locks.set("A5", (20, 20), false)?;
+    /// assert!(locks.is_locked(0, 1));
+    /// assert!(!locks.is_locked(4, 0));
+    /// # Ok::<(), EvalStatus>(())
+    /// ```
+    pub fn set(
+        &mut self,
+        range: &str,
+        total_dims: (usize, usize),
+        lock: bool,
+    ) -> Result<(), EvalStatus> {
+        let ((r1, c1), (r2, c2)) = parse_lock_range(range, total_dims)?;
+        // Ranges the new one covers no longer decide anything
+        self.ranges.retain(|&(((top, left), (bottom, right)), _)| {
+            !(r1 <= top && bottom <= r2 && c1 <= left && right <= c2)
+        });
+        if lock || !self.ranges.is_empty() {
+            self.ranges.push((((r1, c1), (r2, c2)), lock));
+        }
+        Ok(())
+    }
+
+    /// Lists the `lock` and `unlock` commands that lock the same cells again, in order.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::lock::*;
+    /// # let mut locks = LockTable::default();
+    /// locks.set("A1:B10", (20, 20), true)?;
+    /// locks.set("A5", (20, 20), false)?;
+    /// assert_eq!(locks.commands(), vec!["lock A1:B10", "unlock A5:A5"]);
+    /// # Ok::<(), EvalStatus>(())
+    /// ```
+    pub fn commands(&self) -> Vec<String> {
+        self.ranges
+            .iter()
+            .map(|&(((r1, c1), (r2, c2)), lock)| {
+                format!(
+                    "{} {}:{}",
+                    if lock { "lock" } else { "unlock" },
+                    to_cell_name(r1, c1),
+                    to_cell_name(r2, c2)
+                )
+            })
+            .collect()
+    }
+
+    /// Checks whether a cell is locked.
+    ///
+    /// # Arguments
+    /// * `row` - The row index of the cell.
+    /// * `col` - The column index of the cell.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if the last `lock` or `unlock` range holding the cell locked it.
+    pub fn is_locked(&self, row: usize, col: usize) -> bool {
+        self.ranges
+            .iter()
+            .rev()
+            .find(|&&(((top, left), (bottom, right)), _)| {
+                (top..=bottom).contains(&row) && (left..=right).contains(&col)
+            })
+            .is_some_and(|&(_, lock)| lock)
+    }
+
+    /// Checks that none of the cells about to be assigned is locked.
+    ///
+    /// # Arguments
+    /// * `cells` - The (row, col) of each cell to assign.
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())`, or `EvalStatus::CellLocked` if any cell is
+    ///   locked, in which case none should be assigned.
+    pub fn check(&self, cells: impl IntoIterator<Item = (usize, usize)>) -> Result<(), EvalStatus> {
+        if cells.into_iter().any(|(row, col)| self.is_locked(row, col)) {
+            return Err(EvalStatus::CellLocked);
+        }
+        Ok(())
+    }
+}
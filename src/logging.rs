@@ -0,0 +1,49 @@
+//! # Logging Module
+//! This module prints the messages of the `log` facade on stderr, so that the decisions taken
+//! during a recalculation can be traced when debugging a large sheet. Warnings and errors are
+//! printed by default; `--verbose` adds the commands run and the recalculations they start,
+//! `--verbose` twice adds every dependency edge added or removed, and `-q` leaves only errors.
+//! Messages from other crates, such as the GUI toolkit, are never printed below warnings.
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Prints messages on stderr as `[LEVEL target] message`.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let max = if metadata.target().starts_with("spreadsheet") {
+            log::max_level()
+        } else {
+            log::max_level().min(LevelFilter::Warn)
+        };
+        metadata.level() <= max
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Starts printing log messages on stderr.
+///
+/// # Arguments
+/// * `level` - The most detailed messages to print.
+///
+/// # Examples
+/// ```
+/// logging::init(LevelFilter::Debug);
+/// log::debug!("printed");
+/// log::trace!("not printed");
+/// ```
+pub fn init(level: LevelFilter) {
+    // Only fails if a logger was already installed, which then keeps printing
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,129 @@
+//! # Machine Module
+//! This module reports the outcome of each command as one line of JSON, for graders and
+//! scripts that drive the command line with `--machine` instead of reading the prompt:
+//!
+//! ```text
+//! A1=2      ->  {"elapsed":0.0,"status":"ok","changed":["A1","B1"]}
+//! A1=B1     ->  {"elapsed":0.0,"status":"cycle detected","changed":[]}
+//! show      ->  {"elapsed":0.0,"status":"ok","changed":[],"view":"      A       B ..."}
+//! ```
+//!
+//! A line is written for each command read, and none when the sheet is first shown or the
+//! session ends. The changed cells are found by comparing each command that changes the sheet
+//! against a `CellSnapshot` taken before it, so a redefined name lists the cells that use it and
+//! a sort that moves nothing lists none. The grid is only sent when asked for with `show`, and
+//! the prompt is written to stderr.
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+use spreadsheet::storage::SheetStorage;
+use spreadsheet::{CellData, EvalStatus, Valtype};
+
+/// The value and formula of every cell that was set, taken before a command that changes the
+/// sheet so that the cells it changed can be found afterwards.
+#[derive(Debug, Default)]
+pub struct CellSnapshot {
+    cells: BTreeMap<(usize, usize), (Valtype, CellData)>,
+}
+
+/// Reads the value and formula of every cell that was set, by (row, col).
+fn cells_of<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_cols: usize,
+) -> BTreeMap<(usize, usize), (Valtype, CellData)> {
+    spreadsheet
+        .cells()
+        .map(|(key, cell)| {
+            let key = key as usize;
+            (
+                (key / total_cols, key % total_cols),
+                (cell.value.clone(), cell.data.clone()),
+            )
+        })
+        .collect()
+}
+
+impl CellSnapshot {
+    /// Records the cells of a sheet.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    pub fn take<S: SheetStorage + ?Sized>(spreadsheet: &S, total_cols: usize) -> Self {
+        CellSnapshot {
+            cells: cells_of(spreadsheet, total_cols),
+        }
+    }
+
+    /// Finds the cells whose value or formula differs from the snapshot.
+    ///
+    /// Cells are compared by position, so a `resize` that changes the number of columns only
+    /// reports the cells it cleared.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet now.
+    ///
+    /// # Returns
+    /// A `Vec<(usize, usize)>` with the (row, col) of the changed cells, row by row.
+    ///
+    /// # Examples
+    /// ```
+    /// let before = CellSnapshot::take(&sheet, 3);
+    /// assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "A1+1")?;
+    /// assert_eq!(before.changed(&sheet, 3), vec![(0, 1)]);
+    /// ```
+    pub fn changed<S: SheetStorage + ?Sized>(
+        &self,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) -> Vec<(usize, usize)> {
+        let after = cells_of(spreadsheet, total_cols);
+        let positions: BTreeSet<&(usize, usize)> = self.cells.keys().chain(after.keys()).collect();
+        positions
+            .into_iter()
+            .filter(|position| {
+                // A cell that was never set reads as an empty 0, which referring to it stores
+                let unset = (Valtype::Int(0), CellData::Empty);
+                self.cells.get(position).unwrap_or(&unset) != after.get(position).unwrap_or(&unset)
+            })
+            .copied()
+            .collect()
+    }
+}
+
+/// The outcome of a command, in the order its fields are written.
+#[derive(Serialize)]
+struct StatusLine<'a> {
+    elapsed: f64,
+    status: &'static str,
+    changed: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    view: Option<&'a str>,
+}
+
+/// Builds the line reporting the outcome of a command.
+///
+/// # Arguments
+/// * `elapsed` - The time the command took, in seconds.
+/// * `status` - The outcome of the command, reported as its prompt message.
+/// * `changed` - The names of the cells the command changed.
+/// * `view` - The grid, if `show` asked for it.
+///
+/// # Returns
+/// A `String` holding one line of JSON, without the newline.
+pub fn status_line(
+    elapsed: f64,
+    status: Result<(), EvalStatus>,
+    changed: &[String],
+    view: Option<&str>,
+) -> String {
+    let line = StatusLine {
+        // Rounded as the prompt shows it, so lines do not carry float noise
+        elapsed: (elapsed * 10.0).round() / 10.0,
+        status: EvalStatus::message(status),
+        changed,
+        view,
+    };
+    serde_json::to_string(&line).unwrap()
+}
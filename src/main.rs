@@ -4,14 +4,15 @@
 //! The application processes command-line arguments to set up the spreadsheet dimensions and delegates to
 //! either `interactive_mode` or a GUI interface based on configuration.
 #[cfg(any(feature = "autograder", feature = "gui"))]
-use std::{
-    collections::{HashMap, HashSet},
-    env, process,
-};
+use std::{env, process};
 
 #[cfg(feature = "autograder")]
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
     io::{self, Write},
+    path::PathBuf,
+    sync::OnceLock,
+    thread,
     time::Instant,
 };
 
@@ -19,361 +20,1790 @@ use std::{
 use eframe::egui;
 #[cfg(feature = "gui")]
 use gui::gui_defs::SpreadsheetApp;
+#[cfg(feature = "gui")]
+use spreadsheet::CellData;
+#[cfg(feature = "server")]
+use spreadsheet::Spreadsheet;
+#[cfg(feature = "autograder")]
+use spreadsheet::cache::RangeCache;
+#[cfg(feature = "autograder")]
+use spreadsheet::storage::{SheetStorage, StorageKind};
+#[cfg(feature = "autograder")]
+use spreadsheet::utils::{CancelToken, EvalContext};
+#[cfg(any(feature = "autograder", feature = "gui"))]
+use spreadsheet::{Cell, CellKey, EvalStatus, parser, utils};
+#[cfg(feature = "gui")]
+use spreadsheet::{CellAddr, Valtype};
+#[cfg(feature = "autograder")]
+use spreadsheet::{
+    dump, graph, import, info, journal, lint, lock, names, search, search::SearchIndex,
+    snapshot::ValueSnapshot, sort, validation, visits, watch,
+};
+#[cfg(feature = "autograder")]
+use terminal::print_sheet;
+
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(feature = "autograder")]
+mod framing;
+#[cfg(feature = "autograder")]
+mod machine;
+#[cfg(feature = "autograder")]
+mod scrolling;
+#[cfg(feature = "autograder")]
+mod serve;
+
+#[cfg(feature = "gui")]
+mod gui;
+#[cfg(any(feature = "autograder", feature = "gui"))]
+mod logging;
+#[cfg(any(feature = "autograder", feature = "gui"))]
+mod submission;
+#[cfg(feature = "autograder")]
+mod terminal;
+#[cfg(feature = "autograder")]
+mod test;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(any(feature = "autograder", feature = "gui"))]
+mod workbook;
+/// Removes an option that takes a value, such as `--open <file>`, from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+/// * `flag` - The option to look for (e.g., "--open").
+///
+/// # Returns
+/// * `Result<Option<String>, &'static str>` - The value, if the option was given, or an error
+///   message if it has no value.
+#[cfg(any(feature = "autograder", feature = "gui"))]
+fn take_value_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, &'static str> {
+    let Some(pos) = args.iter().position(|arg| arg == flag) else {
+        return Ok(None);
+    };
+    if pos + 1 >= args.len() {
+        return Err(
+            "Usage: <program> <num_rows> <num_columns> [--open <file.csv>] [--script <file>] [--journal <file>] [--autosave [<file>]] [--safe] [--serve] [--machine] [--port <n>] [--storage map|columns] [--verbose] [-q]",
+        );
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Ok(Some(value))
+}
+
+/// Removes an `--open <file>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<Option<String>, &'static str>` - The file to open, if one was given, or an error
+///   message if `--open` has no value.
+#[cfg(any(feature = "autograder", feature = "gui"))]
+fn take_open_flag(args: &mut Vec<String>) -> Result<Option<String>, &'static str> {
+    take_value_flag(args, "--open")
+}
+
+/// Removes a `--script <file>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<Option<String>, &'static str>` - The script to run, if one was given, or an error
+///   message if `--script` has no value.
+#[cfg(feature = "autograder")]
+fn take_script_flag(args: &mut Vec<String>) -> Result<Option<String>, &'static str> {
+    take_value_flag(args, "--script")
+}
+
+/// Removes a `--journal <file>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<Option<String>, &'static str>` - The file to record the journal to, if one was
+///   given, or an error message if `--journal` has no value.
+#[cfg(feature = "autograder")]
+fn take_journal_flag(args: &mut Vec<String>) -> Result<Option<String>, &'static str> {
+    take_value_flag(args, "--journal")
+}
+
+/// Removes a `--functions <file>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<Option<String>, &'static str>` - The Rhai script defining formula functions, if
+///   one was given, or an error message if `--functions` has no value.
+#[cfg(all(feature = "scripting", any(feature = "autograder", feature = "gui")))]
+fn take_functions_flag(args: &mut Vec<String>) -> Result<Option<String>, &'static str> {
+    take_value_flag(args, "--functions")
+}
+
+/// Removes a `--keys <file>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<Option<String>, &'static str>` - The TOML file of GUI keyboard shortcuts, if one
+///   was given, or an error message if `--keys` has no value.
+#[cfg(feature = "gui")]
+fn take_keys_flag(args: &mut Vec<String>) -> Result<Option<String>, &'static str> {
+    take_value_flag(args, "--keys")
+}
+
+/// Removes a `--safe` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; every `--safe` is removed.
+///
+/// # Returns
+/// * `bool` - `true` if the option was present.
+#[cfg(any(feature = "autograder", feature = "gui"))]
+fn take_safe_flag(args: &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != "--safe");
+    args.len() != before
+}
+
+/// Removes a `--restore` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; every `--restore` is removed.
+///
+/// # Returns
+/// * `bool` - `true` if the option was present.
+#[cfg(any(feature = "autograder", feature = "gui"))]
+fn take_restore_flag(args: &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != "--restore");
+    args.len() != before
+}
+
+/// Removes an `--autosave [<file>]` option from the command-line arguments.
+///
+/// The file is optional: an argument that follows `--autosave` is taken as the file unless it
+/// is another option or a number, which is left for the dimensions.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its file are removed if present.
+///
+/// # Returns
+/// * `Option<PathBuf>` - The file to autosave the session to, `workbook::autosave_path()` if
+///   none was given, or `None` if `--autosave` is absent and the session is not autosaved.
+#[cfg(feature = "autograder")]
+fn take_autosave_flag(args: &mut Vec<String>) -> Option<PathBuf> {
+    let pos = args.iter().position(|arg| arg == "--autosave")?;
+    args.remove(pos);
+    let has_file = args
+        .get(pos)
+        .is_some_and(|arg| !arg.starts_with('-') && arg.parse::<usize>().is_err());
+    Some(if has_file {
+        PathBuf::from(args.remove(pos))
+    } else {
+        workbook::autosave_path()
+    })
+}
+
+/// Removes a `--storage <map|columns>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<StorageKind, &'static str>` - The backend to keep the cells in, `StorageKind::Map`
+///   if none was given, or an error message if the value is missing or unknown.
+#[cfg(feature = "autograder")]
+fn take_storage_flag(args: &mut Vec<String>) -> Result<StorageKind, &'static str> {
+    match take_value_flag(args, "--storage")? {
+        Some(kind) => kind.parse(),
+        None => Ok(StorageKind::default()),
+    }
+}
+
+/// Removes the `--verbose` and `-q` options from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; every `--verbose`, `-q` and `--quiet` is removed.
+///
+/// # Returns
+/// * `Result<log::LevelFilter, &'static str>` - The most detailed log messages to print on
+///   stderr: warnings by default, debug messages with one `--verbose`, every message with two
+///   or more, and only errors with `-q`; or an error message if both options were given.
+#[cfg(any(feature = "autograder", feature = "gui"))]
+fn take_verbosity_flags(args: &mut Vec<String>) -> Result<log::LevelFilter, &'static str> {
+    let before = args.len();
+    args.retain(|arg| arg != "--verbose");
+    let verbose = before - args.len();
+    let before = args.len();
+    args.retain(|arg| arg != "-q" && arg != "--quiet");
+    let quiet = args.len() != before;
+    match (verbose, quiet) {
+        (0, true) => Ok(log::LevelFilter::Error),
+        (_, true) => Err("--verbose and -q cannot be used together"),
+        (0, false) => Ok(log::LevelFilter::Warn),
+        (1, false) => Ok(log::LevelFilter::Debug),
+        _ => Ok(log::LevelFilter::Trace),
+    }
+}
+
+/// Removes a `--serve` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; every `--serve` is removed.
+///
+/// # Returns
+/// * `bool` - `true` if the option was present.
+#[cfg(feature = "autograder")]
+fn take_serve_flag(args: &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != "--serve");
+    args.len() != before
+}
+
+/// Removes a `--machine` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; every `--machine` is removed.
+///
+/// # Returns
+/// * `bool` - `true` if the option was present.
+#[cfg(feature = "autograder")]
+fn take_machine_flag(args: &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != "--machine");
+    args.len() != before
+}
+
+/// The token cancelled by Ctrl+C in the command-line session.
+#[cfg(feature = "autograder")]
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
+
+/// Handles Ctrl+C: stops the running recalculation, or quits as usual if there is none.
+#[cfg(feature = "autograder")]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if !INTERRUPT.get().is_some_and(CancelToken::cancel) {
+        // Only async-signal-safe calls may be made here, so the process ends without unwinding
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Makes Ctrl+C cancel a long recalculation on the current thread instead of quitting; the
+/// edited cell is rolled back and the prompt shows "evaluation cancelled".
+#[cfg(feature = "autograder")]
+fn catch_interrupt() {
+    INTERRUPT.get_or_init(CancelToken::default).install();
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+}
 
-/// A compact representation of a cell reference (e.g., "A1") with a maximum length of 7 bytes.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub struct CellName {
-    len: u8,
-    data: [u8; 7],
+/// Removes a `--tui` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; every `--tui` is removed.
+///
+/// # Returns
+/// * `bool` - `true` if the option was present.
+#[cfg(feature = "tui")]
+fn take_tui_flag(args: &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != "--tui");
+    args.len() != before
+}
+
+/// Removes a `--port <n>` option from the command-line arguments.
+///
+/// # Arguments
+/// * `args` - The command-line arguments; the option and its value are removed if present.
+///
+/// # Returns
+/// * `Result<Option<u16>, &'static str>` - The port to serve the HTTP API on, if one was given,
+///   or an error message if `--port` has no value or the value is not a port number.
+#[cfg(feature = "server")]
+fn take_port_flag(args: &mut Vec<String>) -> Result<Option<u16>, &'static str> {
+    match take_value_flag(args, "--port")? {
+        Some(port) => port.parse().map(Some).map_err(|_| "Invalid port"),
+        None => Ok(None),
+    }
+}
+
+/// Parses command-line arguments to determine spreadsheet dimensions.
+///
+/// # Arguments
+/// * `args` - A vector of command-line arguments.
+///
+/// # Returns
+/// * `Result<(usize, usize), &'static str>` - A tuple `(rows, cols)` on success, or an error message on failure.
+#[cfg(any(feature = "autograder", feature = "gui"))]
+fn parse_dimensions(args: Vec<String>) -> Result<(usize, usize), &'static str> {
+    if args.len() == 3 {
+        let total_rows = args[1].parse::<usize>().map_err(|_| "Invalid rows")?;
+        let total_cols = args[2].parse::<usize>().map_err(|_| "Invalid columns")?;
+        if !(1..=999).contains(&total_rows) || !(1..=18278).contains(&total_cols) {
+            return Err("Invalid dimensions.");
+        }
+        Ok((total_rows, total_cols))
+    } else {
+        Err("Usage: <program> <num_rows> <num_columns>")
+    }
+}
+
+#[cfg(feature = "autograder")]
+/// Loads CSV text into the spreadsheet, one record per row starting at A1.
+///
+/// Each non-empty field is assigned like a typed formula, with an optional leading '=' as
+/// written by the formula export, and checked against the validation rules. Loading continues
+/// past bad fields.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `rules` - The validation rules of the sheet.
+/// * `text` - The CSV text to load.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Ok(())` if every field loaded, or the status of the first failure.
+fn import_csv<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    rules: &mut validation::ValidationTable,
+    text: &str,
+) -> Result<(), EvalStatus> {
+    let mut reader = text.as_bytes();
+    import::stream_csv(
+        &mut reader,
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        rules,
+        |_| true,
+    )
+    .map_or(Err(EvalStatus::UnrecognizedCmd), |summary| summary.status)
+}
+
+#[cfg(feature = "autograder")]
+/// Reads a frame announced by `header` from `reader` and loads its payload into the sheet.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `reader` - The input stream, positioned just after the header line.
+/// * `header` - The `#begin` line that opened the frame.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, whose validation rules the imported cells are checked
+///   against and whose journal, if one is being recorded, gets the cells the import wrote.
+///
+/// # Returns
+/// * `Option<Result<(), EvalStatus>>` - `None` if the input ended inside the frame, or the
+///   outcome of loading it.
+fn import_frame<S: SheetStorage + ?Sized, R: io::BufRead>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    reader: &mut R,
+    header: &str,
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+) -> Option<Result<(), EvalStatus>> {
+    let text = match framing::read_frame(reader, header) {
+        Ok(payload) => match String::from_utf8(payload) {
+            Ok(text) => text,
+            Err(_) => return Some(Err(EvalStatus::UnrecognizedCmd)),
+        },
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+        Err(_) => return Some(Err(EvalStatus::UnrecognizedCmd)),
+    };
+    // The payload is not a command, so the journal gets the cells it changed instead
+    let before = options.journal_cells(spreadsheet, total_dims.1);
+    let status = import_csv(
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        &mut options.validations,
+        &text,
+    );
+    options.journal_changes(before, spreadsheet, total_dims.1);
+    Some(status)
+}
+
+#[cfg(feature = "autograder")]
+/// Replaces the spreadsheet with the contents of a CSV file.
+///
+/// The file is streamed a line at a time, so it may be larger than memory, and the number of
+/// rows loaded is reported on stderr as large files are read.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `path` - The CSV file to load, as written by `csv` or `fcsv` in the GUI.
+///
+/// # Returns
+/// * `io::Result<Result<(), EvalStatus>>` - An error if the file could not be opened, in which
+///   case the sheet is left untouched, or could not be read, in which case the rows read so far
+///   are kept; otherwise the outcome of loading it.
+fn open_csv_file<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    path: &str,
+) -> io::Result<Result<(), EvalStatus>> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+    spreadsheet.clear();
+    ranged.clear();
+    is_range.fill(false);
+    // The file replaces the sheet, as `load` does, so its cells are marked rather than rejected
+    let summary = import::stream_csv(
+        &mut reader,
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        &mut validation::ValidationTable::default(),
+        |progress| {
+            eprint!("\rLoaded {} rows", progress.rows);
+            true
+        },
+    )?;
+    if summary.progress.rows >= import::IMPORT_CHUNK_ROWS {
+        eprintln!();
+    }
+    Ok(summary.status)
+}
+
+/// Prints a line of command output, such as the matches of `find`, on stdout, or on stderr
+/// with `--machine`, whose stdout only carries the JSON lines.
+#[cfg(feature = "autograder")]
+macro_rules! say {
+    ($options:expr, $($arg:tt)*) => {
+        if $options.machine {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Settings of a command-line session that commands can change.
+///
+/// # Fields
+/// * `enable_output` - Whether to print the spreadsheet after each command.
+/// * `clamp_ranges` - Whether ranges running past the edge of the sheet are clamped to it, with
+///   a warning, instead of rejected.
+/// * `exports` - Snapshot exports still being written, with the file each one writes.
+/// * `names` - The named ranges defined with `name range`.
+/// * `validations` - The validation rules set with `validate`, checked after each assignment.
+/// * `view` - How the grid is printed, set with `view <rows> <cols>` and `color on|off`.
+/// * `autosave` - The file the sheet is autosaved to, set with `--autosave` or `autosave on`,
+///   or `None`, the default, after `autosave off`.
+/// * `journal` - The file each command that changes the sheet is appended to, with its path, set
+///   with `--journal` or `journal <file>`, or `None` after `journal off`.
+/// * `unsaved_commands` - The number of commands run since the last autosave.
+/// * `history` - The commands typed at the prompt, oldest first, listed by `history`.
+/// * `watches` - The cells watched with `watch`, whose changes are printed after each command.
+/// * `visits` - The cells scrolled to with `scroll_to`, retraced with `back` and `forward`.
+/// * `machine` - Whether each command is reported as a line of JSON, as with `--machine`.
+/// * `show` - Whether `show` asked for the grid to be printed after the current command.
+/// * `changed` - The (row, col) of the cells changed since the last report, collected in machine
+///   mode.
+/// * `range_cache` - The totals of the ranges read by range formulas, kept up to date through
+///   single-cell assignments and dropped by any other command that changes the sheet.
+/// * `search_index` - The index `find` looks cells up in, built by the first `find` and
+///   dropped by any command that changes the sheet.
+/// * `eval` - The context the sheet is evaluated with, holding the seed set with `seed`,
+///   installed on the thread that runs the commands.
+#[cfg(feature = "autograder")]
+#[derive(Debug)]
+struct SessionOptions {
+    enable_output: bool,
+    clamp_ranges: bool,
+    exports: Vec<(String, thread::JoinHandle<io::Result<()>>)>,
+    names: names::NameTable,
+    validations: validation::ValidationTable,
+    view: terminal::View,
+    autosave: Option<PathBuf>,
+    journal: Option<(PathBuf, std::fs::File)>,
+    unsaved_commands: usize,
+    history: Vec<String>,
+    watches: watch::WatchList,
+    visits: visits::VisitHistory,
+    locks: lock::LockTable,
+    calc_manual: bool,
+    stale: BTreeSet<CellKey>,
+    machine: bool,
+    show: bool,
+    changed: BTreeSet<(usize, usize)>,
+    range_cache: RangeCache,
+    search_index: Option<SearchIndex>,
+    eval: EvalContext,
+}
+
+#[cfg(feature = "autograder")]
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            enable_output: true,
+            clamp_ranges: false,
+            exports: Vec::new(),
+            names: names::NameTable::default(),
+            validations: validation::ValidationTable::default(),
+            view: terminal::View::default(),
+            // Off unless asked for, so piped runs write nothing and sessions share no file
+            autosave: None,
+            journal: None,
+            unsaved_commands: 0,
+            history: Vec::new(),
+            watches: watch::WatchList::default(),
+            visits: visits::VisitHistory::default(),
+            locks: lock::LockTable::default(),
+            calc_manual: false,
+            stale: BTreeSet::new(),
+            machine: false,
+            show: false,
+            changed: BTreeSet::new(),
+            range_cache: RangeCache::default(),
+            search_index: None,
+            eval: EvalContext::default(),
+        }
+    }
 }
 
-impl CellName {
-    /// Creates a new `CellName` from a string.
+#[cfg(feature = "autograder")]
+impl SessionOptions {
+    /// Collects snapshot exports that have been written, reporting any failure on stderr.
+    ///
+    /// # Arguments
+    /// * `wait` - Whether to wait for every export, as before exiting, or only collect the
+    ///   finished ones.
+    fn finish_exports(&mut self, wait: bool) {
+        let (done, pending) = std::mem::take(&mut self.exports)
+            .into_iter()
+            .partition(|(_, handle)| wait || handle.is_finished());
+        self.exports = pending;
+        for (path, handle) in done {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("{}: {}", path, e),
+                Err(_) => eprintln!("{}: export failed", path),
+            }
+        }
+    }
+
+    /// Expands a `!N` command to the `N`th command of the history, and records the command.
     ///
     /// # Arguments
-    /// * `s` - The string representation of the cell (e.g., "A1").
+    /// * `input` - The trimmed command typed at the prompt.
     ///
     /// # Returns
-    /// * `Result<Self, &'static str>` - Success with a `CellName` or an error message if the input is invalid.
+    /// * `Result<String, EvalStatus>` - The command to run, or `EvalStatus::InvalidRange` if
+    ///   `N` is not the number of a command in the history.
     ///
-    /// # Errors
-    /// * Returns `Err` if the string is longer than 7 characters or contains non-ASCII characters.
-    pub fn new(s: &str) -> Result<Self, &'static str> {
-        if s.len() > 7 {
-            return Err("CellName too long");
-        }
-        if !s.is_ascii() {
-            return Err("CellName must be ASCII");
-        }
-        let mut data = [0u8; 7];
-        data[..s.len()].copy_from_slice(s.as_bytes());
-        Ok(CellName {
-            len: s.len() as u8,
-            data,
-        })
+    /// # Examples
+    /// ```no_run
+    /// options.recall("A1=5")?; // history: [A1=5]
+    /// assert_eq!(options.recall("!1"), Ok("A1=5".to_string()));
+    /// ```
+    fn recall(&mut self, input: &str) -> Result<String, EvalStatus> {
+        let command = match input.strip_prefix('!') {
+            Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => n
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.history.get(n.wrapping_sub(1)))
+                .cloned()
+                .ok_or(EvalStatus::InvalidRange)?,
+            _ => input.to_string(),
+        };
+        if !command.is_empty() {
+            self.history.push(command.clone());
+        }
+        Ok(command)
     }
-    /// Returns the string representation of the `CellName`.
+
+    /// Recalculates the cells left stale by edits made in manual calculation mode, and redraws
+    /// the `RAND` and `RANDBETWEEN` cells, then checks the validation rules against the new
+    /// values.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `ranged` - A hash map tracking ranges for dependency management.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
     ///
     /// # Returns
-    /// * `&str` - The string representation of the cell reference.
-    pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.data[..self.len as usize]).unwrap()
+    /// * `Result<(), EvalStatus>` - The status of the first stale cell that could not be
+    ///   evaluated, or `Ok(())`.
+    fn recalc<S: SheetStorage + ?Sized>(
+        &mut self,
+        spreadsheet: &mut S,
+        ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+        total_dims: (usize, usize),
+    ) -> Result<(), EvalStatus> {
+        // Volatile cells are redrawn even when nothing is stale
+        let stale: Vec<CellKey> = std::mem::take(&mut self.stale).into_iter().collect();
+        let result = parser::recalc_stale(spreadsheet, ranged, total_dims, &stale);
+        self.validations.recheck(spreadsheet, total_dims.1);
+        self.note_changed(result, total_dims.1)
     }
-}
 
-impl std::fmt::Display for CellName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.as_str())
+    /// Prints a line for each watched cell whose value changed since the last command.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    fn report_watches<S: SheetStorage + ?Sized>(&mut self, spreadsheet: &S, total_cols: usize) {
+        for change in self.watches.changes(spreadsheet, total_cols) {
+            say!(self, "watch {}", change);
+        }
+    }
+
+    /// Reports the outcome of a command: the grid, if output is enabled or `show` asked for it,
+    /// then the prompt. With `--machine`, a line of JSON is printed instead, listing the cells
+    /// changed since the last report and holding the grid only if `show` asked for it, and the
+    /// prompt goes to stderr.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `start` - The (row, col) of the top-left cell of the grid.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    /// * `elapsed` - The time the command took, in seconds.
+    /// * `status` - The outcome of the command.
+    fn report<S: SheetStorage + ?Sized>(
+        &mut self,
+        spreadsheet: &S,
+        start: (usize, usize),
+        total_dims: (usize, usize),
+        elapsed: f64,
+        status: Result<(), EvalStatus>,
+    ) {
+        let show = std::mem::take(&mut self.show);
+        if !self.machine {
+            if self.enable_output || show {
+                print_sheet(spreadsheet, &start, &total_dims, &mut self.view);
+            }
+            prompt(elapsed, EvalStatus::message(status));
+            return;
+        }
+        let changed = self.take_changed();
+        let view =
+            show.then(|| terminal::render_sheet(spreadsheet, &start, &total_dims, &mut self.view));
+        println!(
+            "{}",
+            machine::status_line(elapsed, status, &changed, view.as_deref())
+        );
+        eprint!("> ");
     }
-}
 
-impl std::str::FromStr for CellName {
-    type Err = &'static str;
-    /// Parses a string into a `CellName`.
+    /// Notes the cells whose value a recalculation changed, if the session is in machine mode.
     ///
     /// # Arguments
-    /// * `s` - The string to parse.
+    /// * `result` - The result of the recalculation.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
     ///
     /// # Returns
-    /// * `Result<Self, Self::Err>` - Success with a `CellName` or an error if parsing fails.
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        CellName::new(s)
+    /// * `Result<(), EvalStatus>` - The status of the recalculation.
+    fn note_changed(
+        &mut self,
+        result: parser::RecalcResult,
+        total_cols: usize,
+    ) -> Result<(), EvalStatus> {
+        if self.machine {
+            self.changed.extend(
+                result
+                    .changed
+                    .iter()
+                    .map(|&key| (key as usize / total_cols, key as usize % total_cols)),
+            );
+        }
+        result.status
+    }
+
+    /// Records the cells of the sheet before a command that changes it, if the session is in
+    /// machine mode.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    ///
+    /// # Returns
+    /// * `Option<machine::CellSnapshot>` - The cells, or `None` outside machine mode.
+    fn snapshot<S: SheetStorage + ?Sized>(
+        &self,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) -> Option<machine::CellSnapshot> {
+        self.machine
+            .then(|| machine::CellSnapshot::take(spreadsheet, total_cols))
+    }
+
+    /// Notes the cells that differ from a snapshot taken by `snapshot`.
+    ///
+    /// # Arguments
+    /// * `before` - The snapshot, or `None` outside machine mode.
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet now.
+    fn note_snapshot<S: SheetStorage + ?Sized>(
+        &mut self,
+        before: Option<machine::CellSnapshot>,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) {
+        if let Some(before) = before {
+            self.changed.extend(before.changed(spreadsheet, total_cols));
+        }
+    }
+
+    /// Takes the names of the cells changed since the last report, row by row.
+    ///
+    /// # Returns
+    /// * `Vec<String>` - The names of the cells, which are no longer noted.
+    fn take_changed(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.changed)
+            .into_iter()
+            .map(|(row, col)| utils::to_cell_name(row, col))
+            .collect()
+    }
+
+    /// Starts recording a journal to a file, replacing any file already there. The journal
+    /// opens with the dimensions of the sheet and the names, cells, rules and locks it already
+    /// holds.
+    ///
+    /// # Arguments
+    /// * `path` - The file to record the journal to.
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    ///
+    /// # Returns
+    /// * `io::Result<()>` - An error if the file could not be written.
+    fn start_journal<S: SheetStorage + ?Sized>(
+        &mut self,
+        path: &str,
+        spreadsheet: &S,
+        total_dims: (usize, usize),
+    ) -> io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        let start = journal::journal_start(
+            spreadsheet,
+            total_dims,
+            &self.names,
+            &self.validations,
+            &self.locks,
+        );
+        for line in start {
+            writeln!(file, "{}", line)?;
+        }
+        self.journal = Some((PathBuf::from(path), file));
+        Ok(())
+    }
+
+    /// Logs the outcome of a command, then appends the command to the journal, if one is being
+    /// recorded, the command is journaled as typed (see `journal::is_journaled`), and it
+    /// succeeded. A failed write is reported on stderr.
+    ///
+    /// # Arguments
+    /// * `command` - The trimmed command that was run.
+    /// * `status` - The outcome of the command.
+    fn record(&mut self, command: &str, status: Result<(), EvalStatus>) {
+        log::debug!("{}: {}", command, EvalStatus::message(status));
+        // A clamped range is only a warning, so the command still changed the sheet
+        if matches!(status, Ok(()) | Err(EvalStatus::RangeClamped))
+            && journal::is_journaled(command)
+        {
+            self.write_journal(command);
+        }
+    }
+
+    /// Appends a line to the journal, if one is being recorded. A failed write is reported on
+    /// stderr.
+    ///
+    /// # Arguments
+    /// * `line` - The command to append.
+    fn write_journal(&mut self, line: &str) {
+        if let Some((path, file)) = &mut self.journal
+            && let Err(e) = writeln!(file, "{}", line)
+        {
+            eprintln!("{}: {}", path.display(), e);
+        }
+    }
+
+    /// Lists the formula of every cell, if a journal is being recorded, before a command whose
+    /// changes are journaled by `journal_changes` rather than as the command itself.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    ///
+    /// # Returns
+    /// * `Option<BTreeMap<(usize, usize), String>>` - The formulas, or `None` without a journal.
+    fn journal_cells<S: SheetStorage + ?Sized>(
+        &self,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) -> Option<BTreeMap<(usize, usize), String>> {
+        self.journal
+            .is_some()
+            .then(|| journal::cell_formulas(spreadsheet, total_cols))
+    }
+
+    /// Appends to the journal the commands that replay the changes since `journal_cells`.
+    ///
+    /// # Arguments
+    /// * `before` - The formulas listed by `journal_cells`, or `None` without a journal.
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    fn journal_changes<S: SheetStorage + ?Sized>(
+        &mut self,
+        before: Option<BTreeMap<(usize, usize), String>>,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) {
+        if let Some(before) = before {
+            let after = journal::cell_formulas(spreadsheet, total_cols);
+            for line in journal::journal_changes(&before, &after) {
+                self.write_journal(&line);
+            }
+        }
+    }
+
+    /// Counts a command typed by the user, and autosaves the sheet as a workbook once every
+    /// `AUTOSAVE_COMMANDS` commands. A failed autosave is reported on stderr.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `ranged` - A hash map tracking ranges for dependency management.
+    /// * `is_range` - A boolean array indicating whether each cell is part of a range.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    fn count_command<S: SheetStorage + ?Sized>(
+        &mut self,
+        spreadsheet: &S,
+        ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+        is_range: &[bool],
+        total_dims: (usize, usize),
+    ) {
+        let Some(path) = &self.autosave else {
+            return;
+        };
+        self.unsaved_commands += 1;
+        if self.unsaved_commands < AUTOSAVE_COMMANDS {
+            return;
+        }
+        self.unsaved_commands = 0;
+        let sizes = workbook::GridSizes::default();
+        let path = path.to_string_lossy();
+        if let Err(e) = workbook::save_workbook(
+            &path,
+            spreadsheet,
+            ranged,
+            is_range,
+            total_dims,
+            &sizes,
+            &[],
+        ) {
+            eprintln!("autosave: {}", e);
+        }
     }
 }
-////////////////////////////////////////////////////////////////////////////////
-#[cfg(any(feature = "autograder", feature = "gui"))]
-mod parser;
+
+/// Number of commands typed between two autosaves of the command-line session.
 #[cfg(feature = "autograder")]
-mod scrolling;
+const AUTOSAVE_COMMANDS: usize = 20;
 
-#[cfg(feature = "gui")]
-mod gui;
+/// A command that failed in a script: its one-based line number, text, and status.
 #[cfg(feature = "autograder")]
-mod test;
-#[cfg(any(feature = "autograder", feature = "gui"))]
-mod utils;
-/// Array of status messages used to indicate the outcome of operations.
-#[cfg(any(feature = "autograder", feature = "gui"))]
-const STATUS: [&str; 4] = ["ok", "Invalid range", "unrecognized cmd", "cycle detected"];
-/// A global variable to store the current status code (0-3).
-/// Use with `unsafe` due to its mutable global nature.
-pub static mut STATUS_CODE: usize = 0;
-/// Represents the type of formula a cell can contain.
-pub enum FormulaType {
-    SleepC,
-    SleepR,
-    Const,
-    Ref,
-    CoR,
-    RoC,
-    CoC,
-    RoR,
-    Range,
-    Invalid,
-}
-/// Represents the value of a cell, which can be either an integer or a string (for errors).
-#[derive(Clone, PartialEq, Debug)]
-pub enum Valtype {
-    Int(i32),
-    Str(CellName),
-}
-/// Represents the type of data stored in a cell, including constants, references, and operations.
-#[derive(Clone, Debug, PartialEq)]
-pub enum CellData {
-    Empty,
-    SleepC,
-    SleepR {
-        cell1: CellName,
-    },
-    Const,
-    Ref {
-        cell1: CellName,
-    },
-    CoC {
-        op_code: char,
-        value2: Valtype,
-    },
-    CoR {
-        op_code: char,
-        value2: Valtype,
-        cell2: CellName,
-    },
-    RoC {
-        op_code: char,
-        value2: Valtype,
-        cell1: CellName,
-    },
-    RoR {
-        op_code: char,
-        cell1: CellName,
-        cell2: CellName,
-    },
-    Range {
-        cell1: CellName,
-        cell2: CellName,
-        value2: Valtype,
-    },
-    Invalid,
-}
-/// Represents a cell in the spreadsheet, containing its value, data type, and dependents.
-#[cfg(any(feature = "autograder", feature = "gui"))]
-#[derive(Clone)]
-pub struct Cell {
-    pub value: Valtype,
-    pub data: CellData,
-    pub dependents: HashSet<u32>,
-}
-#[cfg(any(feature = "autograder", feature = "gui"))]
-impl Cell {
-    /// Resets the cell to its default state, preserving its dependents.
-    pub fn reset(&mut self) {
-        let current_dependents = std::mem::take(&mut self.dependents);
-        *self = Self {
-            value: Valtype::Int(0),
-            data: CellData::Empty,
-            dependents: current_dependents,
+type ScriptError = (usize, String, EvalStatus);
+
+#[cfg(feature = "autograder")]
+/// Runs a script of commands non-interactively, as if each line had been typed.
+///
+/// Blank lines and lines starting with '#' are skipped, `q` ends the script early, and scripts
+/// cannot `run` other scripts or `replay` journals. The sheet is not printed after each
+/// command, and the commands that change it are added to the journal.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `reader` - The script, one command per line.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, which commands such as `disable_output` change.
+/// * `start_dims` - A mutable tuple `(&mut start_row, &mut start_col)` defining the current view position.
+///
+/// # Returns
+/// * `io::Result<(usize, Vec<ScriptError>)>` - An error if the script could not be read, or the
+///   number of commands run and the commands that failed.
+fn run_script<S: SheetStorage + ?Sized, R: io::BufRead>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    reader: R,
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+    start_dims: &mut (&mut usize, &mut usize),
+) -> io::Result<(usize, Vec<ScriptError>)> {
+    let mut commands = 0;
+    let mut errors = Vec::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        let input = line.trim();
+        if input.is_empty() || input.starts_with('#') {
+            continue;
+        }
+        if input == "q" {
+            break;
+        }
+        commands += 1;
+        let status = if input.starts_with("run ") || input.starts_with("replay ") {
+            Err(EvalStatus::UnrecognizedCmd)
+        } else {
+            run_command(
+                spreadsheet,
+                ranged,
+                is_range,
+                input,
+                total_dims,
+                options,
+                start_dims,
+            )
         };
+        options.record(input, status);
+        options.report_watches(spreadsheet, total_dims.1);
+        // A clamped range is only a warning, so the command still counts as successful
+        if let Err(status) = status
+            && status != EvalStatus::RangeClamped
+        {
+            errors.push((line_no + 1, input.to_string(), status));
+        }
     }
+    Ok((commands, errors))
+}
 
-    /// Clones a cell for backup without copying its dependents.
-    ///
-    /// # Returns
-    /// * `Self` - A new `Cell` with the same value and data, but an empty set of dependents.
-    pub fn my_clone(&self) -> Self {
-        Self {
-            value: self.value.clone(),
-            data: self.data.clone(),
-            dependents: HashSet::new(), // intentionally not cloning dependents
-        }
+#[cfg(feature = "autograder")]
+/// Prints the outcome of a script run by `run_script`.
+///
+/// # Arguments
+/// * `path` - The script file, used to label the summary.
+/// * `commands` - The number of commands run.
+/// * `errors` - The commands that failed.
+fn print_script_summary(path: &str, commands: usize, errors: &[ScriptError]) {
+    println!("{}: {} commands, {} errors", path, commands, errors.len());
+    for (line_no, command, status) in errors {
+        println!(
+            "  line {}: {} ({})",
+            line_no,
+            command,
+            EvalStatus::message(Err(*status))
+        );
+    }
+}
+
+#[cfg(feature = "autograder")]
+/// Processes a single input command in interactive mode, updating the spreadsheet state.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `input` - The user input command to process.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, which commands such as `disable_output` change.
+/// * `start_dims` - A mutable tuple `(&mut start_row, &mut start_col)` defining the current view position.
+///
+/// # Returns
+/// * `bool` - `true` to continue the interactive loop, `false` to exit.
+fn interactive_mode<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    input: String,
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+    start_dims: &mut (&mut usize, &mut usize),
+) -> bool {
+    if !options.machine {
+        println!();
+    }
+    let start_time = Instant::now();
+    let input = input.trim();
+    if input == "q" {
+        return false;
+    }
+    let status = run_noted_command(
+        spreadsheet,
+        ranged,
+        is_range,
+        input,
+        total_dims,
+        options,
+        start_dims,
+    );
+    options.record(input, status);
+    options.report_watches(spreadsheet, total_dims.1);
+    options.report(
+        spreadsheet,
+        (*start_dims.0, *start_dims.1),
+        total_dims,
+        start_time.elapsed().as_secs_f64(),
+        status,
+    );
+    // Saved after the prompt, so the time shown is that of the command alone
+    options.count_command(spreadsheet, ranged, is_range, total_dims);
+    true
+}
+
+#[cfg(feature = "autograder")]
+/// Assigns a formula to one cell as typed at the prompt, once its lock has been checked.
+///
+/// A named range is written out and, with `clamp_ranges on`, a range past the edge of the
+/// sheet is cut down to fit. In `calc manual` mode the cell is only marked stale; otherwise it
+/// is recalculated through the range cache and checked against the validation rules.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, which commands such as `disable_output` change.
+/// * `cell` - The (row, col) of the cell to assign.
+/// * `formula` - The formula as typed.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - The outcome of the assignment, `EvalStatus::ValidationFailed`
+///   if a cell breaks a rule, or `EvalStatus::RangeClamped` if a range was cut down.
+fn assign_cell<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+    (row, col): (usize, usize),
+    formula: &str,
+) -> Result<(), EvalStatus> {
+    let (total_rows, total_cols) = total_dims;
+    let named = options.names.resolve(formula);
+    let formula = named.as_ref().map_or(formula, |(resolved, _)| resolved);
+    let clamped = options
+        .clamp_ranges
+        .then(|| parser::clamp_range_formula(formula, total_rows, total_cols))
+        .flatten();
+    let formula = clamped.as_ref().map_or(formula, |(clamped, _, _)| clamped);
+    let key = (row * total_cols + col) as CellKey;
+    let before = spreadsheet
+        .get(&key)
+        .map_or_else(String::new, utils::formula_string);
+    let status = if options.calc_manual {
+        // Rules are checked when the stale cells are recalculated
+        options.range_cache.clear();
+        parser::assign_deferred(spreadsheet, ranged, is_range, total_dims, row, col, formula)
+            .map(|stale| options.stale.extend(stale))
+    } else {
+        let mut range_cache = std::mem::take(&mut options.range_cache);
+        let result = RangeCache::scope(&mut range_cache, || {
+            validation::validated_assign(
+                &mut options.validations,
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                (row, col),
+                formula,
+            )
+        });
+        options.range_cache = range_cache;
+        options.note_changed(result, total_cols)
+    };
+    if status == Err(EvalStatus::CycleDetected)
+        && let Some(path) = parser::cycle_path(spreadsheet, ranged, total_dims, row, col, formula)
+    {
+        // On stderr in every mode, so stdout carries only the sheet and the prompt
+        eprintln!("cycle: {}", utils::format_cell_path(&path, total_cols));
+    }
+    if let Err(error) = status
+        && let Some(detail) = parser::status_detail(error, total_dims, row, col, formula)
+    {
+        eprintln!("{}: {}", EvalStatus::message(status), detail);
+    }
+    // A `mark` rule keeps the edit, so the cell is listed instead of restored
+    let kept = spreadsheet
+        .get(&key)
+        .map_or_else(String::new, utils::formula_string)
+        != before;
+    // A new formula changes the cell even if its value stays the same
+    if kept && options.machine {
+        options.changed.insert((row, col));
+    }
+    if status == Err(EvalStatus::ValidationFailed) && kept {
+        let marked: Vec<String> = options
+            .validations
+            .marked()
+            .iter()
+            .map(|&k| utils::to_cell_name(k as usize / total_cols, k as usize % total_cols))
+            .collect();
+        say!(options, "marked: {}", marked.join(", "));
+    } else {
+        status?;
+    }
+    let name = named.as_ref().map(|(_, name)| name.as_str());
+    options.names.record(row, col, name);
+    status?;
+    if let Some((_, from, to)) = clamped {
+        say!(options, "clamped {} to {}", from, to);
+        return Err(EvalStatus::RangeClamped);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "autograder")]
+/// Assigns a formula to every cell of a range, for `fill` and assignments such as
+/// `A1:B2=C1+1`, with references shifted by each cell's offset from the top-left cell; a named
+/// range names the same cells from every cell.
+///
+/// Names and `clamp_ranges` apply to each cell as in `assign_cell`, then every cell is written
+/// and recalculated once, and the validation rules are checked after that single pass. The
+/// assignment is all or nothing: if a cell is rejected, would create a cycle or breaks a
+/// `reject` rule, every cell keeps its contents. In `calc manual` mode the cells are only
+/// marked stale.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, which commands such as `disable_output` change.
+/// * `range` - The range to assign, e.g. "A1:B2".
+/// * `formula` - The formula as written for the top-left cell.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Ok(())` if every cell was assigned, the status explaining
+///   why none was, such as a locked cell, a shifted reference leaving the sheet or a cycle,
+///   `EvalStatus::ValidationFailed` if a cell breaks a rule, or `EvalStatus::RangeClamped` if
+///   a range was cut down.
+fn assign_range<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+    range: &str,
+    formula: &str,
+) -> Result<(), EvalStatus> {
+    let (total_rows, total_cols) = total_dims;
+    let formulas = if options.names.resolve(formula).is_some() {
+        let ((r1, c1), (r2, c2)) =
+            utils::parse_range(range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+        (r1..=r2)
+            .flat_map(|row| (c1..=c2).map(move |col| (row, col, formula.to_string())))
+            .collect()
+    } else {
+        parser::fill_formulas(&format!("{}={}", range, formula), total_rows, total_cols)?
+    };
+    assign_cells(spreadsheet, ranged, is_range, total_dims, options, formulas)
+}
+
+#[cfg(feature = "autograder")]
+/// Assigns formulas to several cells at once, for range assignments and commands such as
+/// `histogram` and `autototal` that write a block of formulas.
+///
+/// Locks, names and `clamp_ranges` apply to each cell as in `assign_cell`, then every cell is
+/// written and recalculated once and the validation rules are checked after that pass. If a
+/// cell is rejected, would create a cycle or breaks a `reject` rule, every cell keeps its
+/// contents. In `calc manual` mode the cells are only marked stale.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, which commands such as `disable_output` change.
+/// * `formulas` - The (row, col, formula) of each cell to assign.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - As for `assign_range`.
+fn assign_cells<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+    formulas: Vec<(usize, usize, String)>,
+) -> Result<(), EvalStatus> {
+    let (total_rows, total_cols) = total_dims;
+    options
+        .locks
+        .check(formulas.iter().map(|&(row, col, _)| (row, col)))?;
+    let mut names = Vec::with_capacity(formulas.len());
+    let mut clamps = Vec::new();
+    let formulas: Vec<(usize, usize, String)> = formulas
+        .into_iter()
+        .map(|(row, col, formula)| {
+            let (formula, name) = match options.names.resolve(&formula) {
+                Some((resolved, name)) => (resolved, Some(name)),
+                None => (formula, None),
+            };
+            names.push((row, col, name));
+            let clamped = options
+                .clamp_ranges
+                .then(|| parser::clamp_range_formula(&formula, total_rows, total_cols))
+                .flatten();
+            match clamped {
+                Some((clamped, from, to)) => {
+                    clamps.push((from, to));
+                    (row, col, clamped)
+                }
+                None => (row, col, formula),
+            }
+        })
+        .collect();
+    // The formulas of the cells, to tell a `mark` rule that kept them from one that did not
+    let current = |spreadsheet: &S| -> Vec<String> {
+        formulas
+            .iter()
+            .map(|(row, col, _)| {
+                let key = (row * total_cols + col) as CellKey;
+                spreadsheet
+                    .get(&key)
+                    .map_or_else(String::new, utils::formula_string)
+            })
+            .collect()
+    };
+    let before = current(spreadsheet);
+    // Recalculating drops the totals kept for single-cell edits
+    options.range_cache.clear();
+    let status = if options.calc_manual {
+        // Rules are checked when the stale cells are recalculated
+        parser::assign_formulas_deferred(spreadsheet, ranged, is_range, total_dims, &formulas)
+            .map(|stale| options.stale.extend(stale))
+    } else {
+        let result = validation::validated_assign_formulas(
+            &mut options.validations,
+            spreadsheet,
+            ranged,
+            is_range,
+            total_dims,
+            &formulas,
+        );
+        options.note_changed(result, total_cols)
+    };
+    // A `mark` rule keeps the cells, so they are listed instead of restored
+    let kept = status.is_ok()
+        || (status == Err(EvalStatus::ValidationFailed) && current(spreadsheet) != before);
+    if !kept {
+        return status;
+    }
+    if options.machine {
+        // A new formula changes the cell even if its value stays the same
+        options
+            .changed
+            .extend(formulas.iter().map(|&(row, col, _)| (row, col)));
+    }
+    for (row, col, name) in names {
+        options.names.record(row, col, name.as_deref());
+    }
+    if status == Err(EvalStatus::ValidationFailed) {
+        let marked: Vec<String> = options
+            .validations
+            .marked()
+            .iter()
+            .map(|&k| utils::to_cell_name(k as usize / total_cols, k as usize % total_cols))
+            .collect();
+        say!(options, "marked: {}", marked.join(", "));
+        return status;
     }
-}
-#[cfg(feature = "autograder")]
-/// A trait for types that can dynamically reserve additional capacity when growing.
-///
-/// This trait is used to implement a capacity reservation strategy for collections, ensuring
-/// efficient growth by pre-allocating memory when the collection is about to exceed its current
-/// capacity. It is particularly useful for optimizing performance in scenarios where frequent
-/// insertions are expected, such as in the autograder's spreadsheet operations
-trait ReserveOnGrow {
-    /// Reserves additional capacity in the collection if it is about to grow beyond its current capacity.
-    ///
-    /// This method checks if adding one more element would exceed the current capacity. If so, it
-    /// reserves additional space, typically by increasing the capacity to the next power of two
-    /// greater than or equal to the new size. This helps reduce the number of reallocations during
-    /// growth, improving performance.
-    fn reserve_on_grow(&mut self);
-}
-#[cfg(feature = "autograder")]
-impl ReserveOnGrow for HashMap<u32, Cell> {
-    /// Implements the `ReserveOnGrow` trait for `HashMap<u32, Cell>`.
-    ///
-    /// This implementation ensures that the `HashMap` has enough capacity to accommodate a new
-    /// element without reallocation. If the current length plus one exceeds the capacity, it
-    /// reserves additional space by increasing the capacity to the next power of two.
-    ///
-    /// # Behavior
-    /// - If `len + 1 > capacity`, it calculates the new capacity as the next power of two greater
-    ///   than or equal to `len + 1` and reserves the additional space.
-    /// - If there is already sufficient capacity, no action is taken.
-    ///
-    /// # Examples
-    /// ```rust
-    /// use std::collections::HashMap;
-    ///
-    /// let mut map: HashMap<u32, Cell> = HashMap::new();
-    /// map.reserve_on_grow(); // Ensures capacity for at least one more element
-    /// ```
-    fn reserve_on_grow(&mut self) {
-        let len = self.len();
-        let cap = self.capacity();
-        if len + 1 > cap {
-            // bump to the next power of two ≥ len+1
-            let new_cap = (len + 1).next_power_of_two();
-            self.reserve(new_cap - cap);
-        }
+    for (from, to) in &clamps {
+        say!(options, "clamped {} to {}", from, to);
+    }
+    if !clamps.is_empty() {
+        return Err(EvalStatus::RangeClamped);
     }
+    Ok(())
 }
 
 #[cfg(feature = "autograder")]
-/// Prints the spreadsheet grid starting from the given position.
-///
-/// # Arguments
-/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `u32` key.
-/// * `pointer` - A tuple `(row, col)` indicating the starting position to display.
-/// * `dimension` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
-fn print_sheet(
-    spreadsheet: &HashMap<u32, Cell>,
-    pointer: &(usize, usize),
-    dimension: &(usize, usize),
-) {
-    let view_rows = dimension.0.saturating_sub(pointer.0).min(10);
-    let view_cols = dimension.1.saturating_sub(pointer.1).min(10);
-
-    print!("{:<5}", "");
-    for j in 0..view_cols {
-        let col = pointer.1 + j;
-        let mut name = String::new();
-        let mut n = col + 1;
-        while n > 0 {
-            let rem = (n - 1) % 26;
-            name.push((b'A' + rem as u8) as char);
-            n = (n - 1) / 26;
-        }
-        print!("{:>10}  ", name.chars().rev().collect::<String>());
-    }
-    println!();
-
-    for i in 0..view_rows {
-        print!("{:4}  ", pointer.0 + i + 1);
-        for j in 0..view_cols {
-            let row = pointer.0 + i;
-            let col = pointer.1 + j;
-            let idx = (row as u32) * (dimension.1 as u32) + (col as u32);
-            let cell = spreadsheet.get(&idx).cloned().unwrap_or(Cell {
-                value: Valtype::Int(0),
-                data: CellData::Empty,
-                dependents: HashSet::new(),
-            });
-            match &cell.value {
-                Valtype::Int(v) => print!("{:<10}  ", v),
-                Valtype::Str(s) => print!("{:<10}         ", s),
-            }
-        }
-        println!();
-    }
-}
-/// Parses command-line arguments to determine spreadsheet dimensions.
+/// Runs a single command other than `q` as `run_command` does, and in machine mode notes the
+/// cells it changed by comparing their values before and after. A recalculation alone misses
+/// the dependents of a redefined name, and lists every cell a sort rewrote even when none moved.
 ///
 /// # Arguments
-/// * `args` - A vector of command-line arguments.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `input` - The trimmed command to run.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings, which commands such as `disable_output` change.
+/// * `start_dims` - A mutable tuple `(&mut start_row, &mut start_col)` defining the current view position.
 ///
 /// # Returns
-/// * `Result<(usize, usize), &'static str>` - A tuple `(rows, cols)` on success, or an error message on failure.
-#[cfg(any(feature = "autograder", feature = "gui"))]
-fn parse_dimensions(args: Vec<String>) -> Result<(usize, usize), &'static str> {
-    if args.len() == 3 {
-        let total_rows = args[1].parse::<usize>().map_err(|_| "Invalid rows")?;
-        let total_cols = args[2].parse::<usize>().map_err(|_| "Invalid columns")?;
-        if !(1..=999).contains(&total_rows) || !(1..=18278).contains(&total_cols) {
-            return Err("Invalid dimensions.");
-        }
-        Ok((total_rows, total_cols))
+/// * `Result<(), EvalStatus>` - The outcome shown in the prompt.
+fn run_noted_command<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    input: &str,
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+    start_dims: &mut (&mut usize, &mut usize),
+) -> Result<(), EvalStatus> {
+    let mutating =
+        journal::is_mutating(input) || input.starts_with("run ") || input.starts_with("replay ");
+    let before = if mutating {
+        options.snapshot(spreadsheet, total_dims.1)
     } else {
-        Err("Usage: <program> <num_rows> <num_columns>")
+        None
+    };
+    let status = run_command(
+        spreadsheet,
+        ranged,
+        is_range,
+        input,
+        total_dims,
+        options,
+        start_dims,
+    );
+    if before.is_some() {
+        options.changed.clear();
+        options.note_snapshot(before, spreadsheet, total_dims.1);
     }
+    status
 }
 
 #[cfg(feature = "autograder")]
-/// Processes a single input command in interactive mode, updating the spreadsheet state.
+/// Runs a single command other than `q`, updating the spreadsheet state.
 ///
 /// # Arguments
-/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `u32` key.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
 /// * `ranged` - A hash map tracking ranges for dependency management.
 /// * `is_range` - A boolean array indicating whether each cell is part of a range.
-/// * `input` - The user input command to process.
+/// * `input` - The trimmed command to run.
 /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
-/// * `enable_output` - A mutable boolean controlling whether to print the spreadsheet after each command.
+/// * `options` - The session settings, which commands such as `disable_output` change.
 /// * `start_dims` - A mutable tuple `(&mut start_row, &mut start_col)` defining the current view position.
 ///
 /// # Returns
-/// * `bool` - `true` to continue the interactive loop, `false` to exit.
-fn interactive_mode(
-    spreadsheet: &mut HashMap<u32, Cell>,
-    ranged: &mut HashMap<u32, Vec<(u32, u32)>>,
+/// * `Result<(), EvalStatus>` - The outcome shown in the prompt.
+fn run_command<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
     is_range: &mut [bool],
-    input: String,
+    input: &str,
     total_dims: (usize, usize),
-    enable_output: &mut bool,
+    options: &mut SessionOptions,
     start_dims: &mut (&mut usize, &mut usize),
-) -> bool {
-    println!();
-    let start_time = Instant::now();
-    let input = input.trim();
-    unsafe {
-        STATUS_CODE = 0;
-    }
+) -> Result<(), EvalStatus> {
     let (total_rows, total_cols) = total_dims;
-    //let (start_row, start_col) = start_dims;
+    // Single-cell assignments put the range cache back; any other command that changes the
+    // sheet may change cells behind it, so it is dropped
+    let mut range_cache = std::mem::take(&mut options.range_cache);
+    if !journal::is_mutating(input) {
+        options.range_cache = std::mem::take(&mut range_cache);
+    } else {
+        options.search_index = None;
+    }
     match input {
         "w" => scrolling::w(start_dims.0),
         "s" => scrolling::s(start_dims.0, total_rows),
         "a" => scrolling::a(start_dims.1),
         "d" => scrolling::d(start_dims.1, total_cols),
-        "q" => return false,
+        _ if input.starts_with("histogram ") => {
+            // Matched before assignments since `bins=N` contains '='
+            let args = input.trim_start_matches("histogram ");
+            let spec = utils::parse_histogram_args(args, total_rows, total_cols)?;
+            // The chart panel only exists in the GUI
+            if spec.chart {
+                return Err(EvalStatus::UnrecognizedCmd);
+            }
+            let (formulas, _) = utils::histogram_formulas(spreadsheet, &spec, total_cols);
+            options.range_cache = range_cache;
+            assign_cells(spreadsheet, ranged, is_range, total_dims, options, formulas)?;
+        }
+        _ if input.starts_with("fill ") => {
+            // Matched before assignments since the template follows '='
+            let (range, formula) = input
+                .trim_start_matches("fill ")
+                .split_once('=')
+                .ok_or(EvalStatus::UnrecognizedCmd)?;
+            options.range_cache = range_cache;
+            assign_range(
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                options,
+                range.trim(),
+                formula.trim(),
+            )?;
+        }
+        _ if input.starts_with("clear ") => {
+            let range = input.trim_start_matches("clear ").trim();
+            let corners = if range.contains(':') {
+                utils::parse_range(range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?
+            } else {
+                let (row, col) = utils::to_indices(range)?;
+                if row >= total_rows || col >= total_cols {
+                    return Err(EvalStatus::InvalidRange);
+                }
+                ((row, col), (row, col))
+            };
+            let ((r1, c1), (r2, c2)) = corners;
+            options
+                .locks
+                .check((r1..=r2).flat_map(|row| (c1..=c2).map(move |col| (row, col))))?;
+            let result = parser::clear_cells(spreadsheet, ranged, is_range, total_dims, corners);
+            options.validations.recheck(spreadsheet, total_cols);
+            options.note_changed(result, total_cols)?;
+        }
+        _ if input.starts_with("sort ") => {
+            // Matched before assignments since `order=...` contains '='
+            let args = input.trim_start_matches("sort ");
+            let spec = sort::parse_sort_args(args, total_rows, total_cols)?;
+            let ((r1, c1), (r2, c2)) = spec.corners;
+            options
+                .locks
+                .check((r1..=r2).flat_map(|row| (c1..=c2).map(move |col| (row, col))))?;
+            let result = sort::sort_range(spreadsheet, ranged, is_range, total_dims, &spec);
+            options.validations.recheck(spreadsheet, total_cols);
+            options.note_changed(result, total_cols)?;
+        }
+        _ if input.starts_with("save ") => {
+            let path = input.trim_start_matches("save ").trim();
+            // The command line has no column widths, row heights or formats to keep
+            let sizes = workbook::GridSizes::default();
+            workbook::save_workbook(path, spreadsheet, ranged, is_range, total_dims, &sizes, &[])
+                .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+        }
+        _ if input.starts_with("package ") => {
+            let filename = input.trim_start_matches("package ").trim();
+            // The command line has no column widths, row heights or formats to keep
+            let filename = submission::package_sheet(
+                filename,
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                &workbook::GridSizes::default(),
+                &[],
+            )
+            .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            say!(options, "package {}", filename);
+        }
+        _ if input.starts_with("load ") => {
+            let path = input.trim_start_matches("load ").trim();
+            let before = options.snapshot(spreadsheet, total_cols);
+            // The file may be gone when the journal is replayed, so it gets the cells instead
+            let journaled = options.journal_cells(spreadsheet, total_cols);
+            workbook::load_workbook(path, spreadsheet, ranged, is_range, total_dims)
+                .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            options.journal_changes(journaled, spreadsheet, total_cols);
+            options.note_snapshot(before, spreadsheet, total_cols);
+            // The loaded sheet replaces every stale cell, with the values it was saved with
+            options.stale.clear();
+            options.validations.recheck(spreadsheet, total_cols);
+        }
+        _ if input.starts_with("open ") => {
+            let path = input.trim_start_matches("open ").trim();
+            let before = options.snapshot(spreadsheet, total_cols);
+            let journaled = options.journal_cells(spreadsheet, total_cols);
+            let status = open_csv_file(spreadsheet, ranged, is_range, total_dims, path)
+                .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            options.journal_changes(journaled, spreadsheet, total_cols);
+            options.validations.recheck(spreadsheet, total_cols);
+            options.note_snapshot(before, spreadsheet, total_cols);
+            status?;
+        }
+        _ if input.starts_with("export_snapshot ") => {
+            // The values are copied now and written while later commands run
+            let path = input.trim_start_matches("export_snapshot ").trim();
+            if path.is_empty() {
+                return Err(EvalStatus::UnrecognizedCmd);
+            }
+            options.finish_exports(false);
+            let snapshot = ValueSnapshot::capture(spreadsheet, total_dims);
+            let handle = snapshot.export_in_background(PathBuf::from(path));
+            options.exports.push((path.to_string(), handle));
+        }
+        _ if input.starts_with("run ") => {
+            let path = input.trim_start_matches("run ").trim();
+            let file = std::fs::File::open(path).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            let (commands, errors) = run_script(
+                spreadsheet,
+                ranged,
+                is_range,
+                io::BufReader::new(file),
+                total_dims,
+                options,
+                start_dims,
+            )
+            .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            print_script_summary(path, commands, &errors);
+            if let Some(&(_, _, status)) = errors.first() {
+                return Err(status);
+            }
+        }
+        _ if input.starts_with("replay ") => {
+            let path = input.trim_start_matches("replay ").trim();
+            // The journal being recorded would grow while it is replayed
+            let recording = options.journal.as_ref().is_some_and(|(journal, _)| {
+                std::fs::canonicalize(journal).ok() == std::fs::canonicalize(path).ok()
+            });
+            if recording {
+                return Err(EvalStatus::UnrecognizedCmd);
+            }
+            let text = std::fs::read_to_string(path).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            let dims = text
+                .lines()
+                .next()
+                .and_then(journal::parse_journal_header)
+                .ok_or(EvalStatus::UnrecognizedCmd)?;
+            if dims != total_dims {
+                say!(
+                    options,
+                    "replay {}: recorded on a {} x {} sheet",
+                    path,
+                    dims.0,
+                    dims.1
+                );
+                return Err(EvalStatus::InvalidRange);
+            }
+            let (commands, errors) = run_script(
+                spreadsheet,
+                ranged,
+                is_range,
+                text.as_bytes(),
+                total_dims,
+                options,
+                start_dims,
+            )
+            .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            print_script_summary(path, commands, &errors);
+            if let Some(&(_, _, status)) = errors.first() {
+                return Err(status);
+            }
+        }
+        "journal off" => options.journal = None,
+        _ if input.starts_with("journal ") => {
+            let path = input.trim_start_matches("journal ").trim();
+            if path.is_empty() {
+                return Err(EvalStatus::UnrecognizedCmd);
+            }
+            options
+                .start_journal(path, spreadsheet, total_dims)
+                .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+        }
+        _ if input.starts_with("find ") => {
+            let query = input.trim_start_matches("find ").trim();
+            let cells = options
+                .search_index
+                .get_or_insert_with(|| SearchIndex::build(spreadsheet))
+                .find(query);
+            let names: Vec<String> = cells
+                .iter()
+                .map(|&k| utils::to_cell_name(k as usize / total_cols, k as usize % total_cols))
+                .collect();
+            if names.is_empty() {
+                say!(options, "find {}: none", query);
+            } else {
+                say!(options, "find {}: {}", query, names.join(", "));
+            }
+        }
+        _ if input == "dump" || input.starts_with("dump ") => {
+            let lines = dump::dump_lines(spreadsheet, total_cols);
+            let path = input.trim_start_matches("dump").trim();
+            if path.is_empty() {
+                for line in &lines {
+                    say!(options, "{}", line);
+                }
+            } else {
+                let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+                std::fs::write(path, text).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+                say!(options, "dump {}: {} cells", path, lines.len());
+            }
+        }
+        _ if input.starts_with("diff ") => {
+            let path = input.trim_start_matches("diff ").trim();
+            let previous =
+                std::fs::read_to_string(path).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            let changes = dump::diff_lines(spreadsheet, total_cols, &previous)?;
+            say!(options, "diff {}: {} changed", path, changes.len());
+            for change in &changes {
+                say!(options, "  {}", change);
+            }
+        }
+        "sheetinfo" => {
+            let info = info::sheet_info(spreadsheet, ranged, is_range, total_dims);
+            for line in info.lines() {
+                say!(options, "{}", line);
+            }
+        }
+        _ if input.starts_with("graph ") => {
+            let path = input.trim_start_matches("graph ").trim();
+            let dot = graph::dependency_dot(spreadsheet, ranged, total_cols);
+            std::fs::write(path, dot).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            say!(options, "graph {}", path);
+        }
+        _ if input.starts_with("replace ") => {
+            let mut args = input.trim_start_matches("replace ").split_whitespace();
+            let (Some(from), Some(to), None) = (args.next(), args.next(), args.next()) else {
+                return Err(EvalStatus::UnrecognizedCmd);
+            };
+            // Each formula is assigned and put back on failure, so the net changes are compared
+            let before = options.snapshot(spreadsheet, total_cols);
+            let results = search::replace_formulas(
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                &options.locks,
+                &mut options.validations,
+                (from, to),
+            );
+            options.note_snapshot(before, spreadsheet, total_cols);
+            let rejected: Vec<_> = results
+                .iter()
+                .filter(|(_, status)| status.is_err())
+                .collect();
+            say!(
+                options,
+                "replace {} {}: {} cells",
+                from,
+                to,
+                results.len() - rejected.len()
+            );
+            for &&(key, status) in &rejected {
+                let key = key as usize;
+                say!(
+                    options,
+                    "  {}: {}",
+                    utils::to_cell_name(key / total_cols, key % total_cols),
+                    EvalStatus::message(status)
+                );
+            }
+            if let Some(&&(_, status)) = rejected.first() {
+                return status;
+            }
+        }
         _ if input.contains('=') => {
             let parts: Vec<&str> = input.splitn(2, '=').map(str::trim).collect();
             if parts.len() == 2 {
                 let (cell_ref, formula) = (parts[0], parts[1]);
-                let (row, col) = utils::to_indices(cell_ref);
-                if row < total_rows && col < total_cols && unsafe { STATUS_CODE } == 0 {
-                    let idx = (row as u32) * (total_cols as u32) + (col as u32);
-                    let old_cell = spreadsheet.get(&idx).cloned().unwrap_or(Cell {
-                        value: Valtype::Int(0),
-                        data: CellData::Empty,
-                        dependents: HashSet::new(),
-                    });
-                    let mut new_cell = old_cell.clone();
-                    parser::detect_formula(&mut new_cell, formula);
-                    spreadsheet.insert(idx, new_cell);
-                    spreadsheet.reserve_on_grow();
-                    parser::update_and_recalc(
+                // A range such as A1:B2=C1+1 is assigned as by `fill`
+                if cell_ref.contains(':') {
+                    options.range_cache = range_cache;
+                    return assign_range(
                         spreadsheet,
                         ranged,
                         is_range,
-                        (total_rows, total_cols),
-                        row,
-                        col,
-                        old_cell,
+                        total_dims,
+                        options,
+                        cell_ref,
+                        formula,
                     );
-                } else {
-                    unsafe {
-                        STATUS_CODE = 1;
-                    }
                 }
+                let (row, col) = utils::to_indices(cell_ref)?;
+                if row >= total_rows || col >= total_cols {
+                    eprintln!(
+                        "{}: {} out of bounds",
+                        EvalStatus::message(Err(EvalStatus::InvalidRange)),
+                        cell_ref
+                    );
+                    return Err(EvalStatus::InvalidRange);
+                }
+                options.locks.check([(row, col)])?;
+                options.range_cache = range_cache;
+                assign_cell(
+                    spreadsheet,
+                    ranged,
+                    is_range,
+                    total_dims,
+                    options,
+                    (row, col),
+                    formula,
+                )?;
+            }
+        }
+        _ if input == "lint" || input.starts_with("lint ") => {
+            let lint_options = lint::parse_lint_args(input.trim_start_matches("lint"))?;
+            let issues = lint::lint(spreadsheet, ranged, is_range, total_dims, &lint_options);
+            say!(options, "lint: {} issues", issues.len());
+            for issue in &issues {
+                say!(options, "  {}", issue);
+            }
+        }
+        _ if input.starts_with("autototal ") => {
+            let mut args = input.trim_start_matches("autototal ").split_whitespace();
+            let range = args.next().unwrap_or("");
+            let func = args.next().unwrap_or("sum");
+            let formulas = utils::autototal_formulas(range, func, total_rows, total_cols)?;
+            if args.next().is_some() {
+                return Err(EvalStatus::UnrecognizedCmd);
+            }
+            options.range_cache = range_cache;
+            assign_cells(spreadsheet, ranged, is_range, total_dims, options, formulas)?;
+        }
+        _ if input.starts_with("deps ") || input.starts_with("precedents ") => {
+            let (command, cell_ref) = input.split_once(' ').unwrap();
+            let cell_ref = cell_ref.trim();
+            let (row, col) = utils::to_indices(cell_ref)?;
+            if row >= total_rows || col >= total_cols {
+                return Err(EvalStatus::InvalidRange);
+            }
+            let key = (row * total_cols + col) as CellKey;
+            let cells = if command == "deps" {
+                utils::transitive_dependents(key, spreadsheet, ranged, total_cols)
+            } else {
+                utils::transitive_precedents(key, spreadsheet, total_cols)
+            };
+            let names: Vec<String> = cells
+                .iter()
+                .map(|&k| utils::to_cell_name(k as usize / total_cols, k as usize % total_cols))
+                .collect();
+            if names.is_empty() {
+                say!(options, "{} {}: none", command, cell_ref);
+            } else {
+                say!(options, "{} {}: {}", command, cell_ref, names.join(", "));
+            }
+        }
+        _ if input.starts_with("validate ") => {
+            let args = input.trim_start_matches("validate ");
+            let rule = validation::parse_validate_args(args, total_rows, total_cols)?;
+            let broken = options.validations.add(rule, spreadsheet, total_cols);
+            if broken > 0 {
+                say!(
+                    options,
+                    "validate {}: {} cells already break the rule",
+                    args.trim(),
+                    broken
+                );
             }
         }
+        _ if input.starts_with("name range ") => {
+            let (name, range) = names::parse_name_args(input.trim_start_matches("name range "))?;
+            names::define_name(
+                &mut options.names,
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                &options.locks,
+                (name, range),
+            )?;
+        }
         _ if input.starts_with("scroll_to ") => {
-            let cell_ref = input.trim_start_matches("scroll_to ").trim();
+            let cell_ref = options
+                .visits
+                .expand(input.trim_start_matches("scroll_to "))?;
+            let from = (*start_dims.0, *start_dims.1);
             if cell_ref.is_empty()
                 || !cell_ref.chars().next().unwrap().is_alphabetic()
                 || scrolling::scroll_to(
@@ -381,33 +1811,107 @@ fn interactive_mode(
                     start_dims.1,
                     total_rows,
                     total_cols,
-                    cell_ref,
+                    &cell_ref,
                 )
                 .is_err()
             {
-                unsafe {
-                    STATUS_CODE = 1;
-                }
+                return Err(EvalStatus::InvalidRange);
             }
+            options.visits.visit(from, (*start_dims.0, *start_dims.1));
         }
-        "disable_output" => *enable_output = false,
-        "enable_output" => *enable_output = true,
-        _ => unsafe {
-            STATUS_CODE = 2;
-        },
-    }
-    if *enable_output {
-        print_sheet(
-            spreadsheet,
-            &(*start_dims.0, *start_dims.1),
-            &(total_rows, total_cols),
-        );
+        "back" | "forward" => {
+            let cell = if input == "back" {
+                options.visits.back()
+            } else {
+                options.visits.forward()
+            };
+            let (row, col) = cell.ok_or(EvalStatus::InvalidRange)?;
+            *start_dims.0 = row;
+            *start_dims.1 = col;
+        }
+        _ if input.starts_with("view ") => {
+            let sizes: Vec<usize> = input
+                .trim_start_matches("view ")
+                .split_whitespace()
+                .map(|n| n.parse().map_err(|_| EvalStatus::UnrecognizedCmd))
+                .collect::<Result<_, _>>()?;
+            let [rows, cols] = sizes[..] else {
+                return Err(EvalStatus::UnrecognizedCmd);
+            };
+            if rows == 0 || cols == 0 {
+                return Err(EvalStatus::InvalidRange);
+            }
+            options.view.size = (rows, cols);
+        }
+        _ if input.starts_with("watch ") || input.starts_with("unwatch ") => {
+            let (command, cell) = input.split_once(' ').unwrap();
+            options
+                .watches
+                .set(spreadsheet, total_dims, cell, command == "watch")?;
+        }
+        _ if input.starts_with("lock ") || input.starts_with("unlock ") => {
+            let (command, range) = input.split_once(' ').unwrap();
+            options.locks.set(range, total_dims, command == "lock")?;
+        }
+        "calc manual" => options.calc_manual = true,
+        "calc auto" => {
+            options.calc_manual = false;
+            options.recalc(spreadsheet, ranged, total_dims)?;
+        }
+        "calc" => say!(
+            options,
+            "calc {}: {} stale cells",
+            if options.calc_manual {
+                "manual"
+            } else {
+                "auto"
+            },
+            options.stale.len()
+        ),
+        "recalc" => options.recalc(spreadsheet, ranged, total_dims)?,
+        "recalc all" => {
+            // Every formula is brought up to date, stale or not
+            options.stale.clear();
+            let result = parser::recalc_all(spreadsheet, ranged, total_dims);
+            options.validations.recheck(spreadsheet, total_dims.1);
+            options.note_changed(result, total_cols)?;
+        }
+        _ if input.starts_with("seed ") => {
+            let seed = input["seed ".len()..]
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| EvalStatus::UnrecognizedCmd)?;
+            let result = parser::reseed(spreadsheet, ranged, total_dims, seed);
+            options.validations.recheck(spreadsheet, total_dims.1);
+            options.note_changed(result, total_cols)?;
+        }
+        "watch" => {
+            for (row, col) in options.watches.cells() {
+                say!(options, "{}", utils::to_cell_name(row, col));
+            }
+        }
+        "history" => {
+            for (i, command) in options.history.iter().enumerate() {
+                say!(options, "{:5}  {}", i + 1, command);
+            }
+        }
+        "show" => options.show = true,
+        "disable_output" => options.enable_output = false,
+        "enable_output" => options.enable_output = true,
+        "color on" => options.view.color = true,
+        "color off" => options.view.color = false,
+        "autosave on" => {
+            options.autosave = options
+                .autosave
+                .take()
+                .or_else(|| Some(workbook::autosave_path()))
+        }
+        "autosave off" => options.autosave = None,
+        "clamp_ranges on" => options.clamp_ranges = true,
+        "clamp_ranges off" => options.clamp_ranges = false,
+        _ => return Err(EvalStatus::UnrecognizedCmd),
     }
-    prompt(
-        start_time.elapsed().as_secs_f64(),
-        STATUS[unsafe { STATUS_CODE }],
-    );
-    true
+    Ok(())
 }
 #[cfg(feature = "autograder")]
 /// Prints the command prompt with elapsed time and status.
@@ -423,7 +1927,92 @@ fn prompt(elapsed: f64, status: &str) {
 fn main() {
     #[cfg(any(feature = "autograder", feature = "gui"))]
     {
-        let args: Vec<String> = env::args().collect();
+        let mut args: Vec<String> = env::args().collect();
+        match take_verbosity_flags(&mut args) {
+            Ok(level) => logging::init(level),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        let open_path = match take_open_flag(&mut args) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        #[cfg(feature = "autograder")]
+        let script_path = match take_script_flag(&mut args) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        #[cfg(feature = "autograder")]
+        let journal_path = match take_journal_flag(&mut args) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        #[cfg(feature = "autograder")]
+        let serve_mode = take_serve_flag(&mut args);
+        #[cfg(feature = "tui")]
+        let tui_mode = take_tui_flag(&mut args);
+        #[cfg(feature = "autograder")]
+        let machine_mode = take_machine_flag(&mut args);
+        #[cfg(feature = "autograder")]
+        let autosave = take_autosave_flag(&mut args);
+        #[cfg(feature = "autograder")]
+        let storage_kind = match take_storage_flag(&mut args) {
+            Ok(kind) => kind,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        #[cfg(feature = "server")]
+        let port = match take_port_flag(&mut args) {
+            Ok(port) => port,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        // Compiled once, then given to the session, each sheet of the GUI or the served sheet
+        #[cfg(feature = "scripting")]
+        let functions = match take_functions_flag(&mut args) {
+            Ok(Some(path)) => {
+                let loaded = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("File error: {}", e))
+                    .and_then(|source| spreadsheet::scripting::UserFunctions::compile(&source));
+                match loaded {
+                    Ok(functions) => Some(std::sync::Arc::new(functions)),
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        #[cfg(feature = "gui")]
+        let keys_path = match take_keys_flag(&mut args) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        let restore = take_restore_flag(&mut args);
+        let safe_mode = take_safe_flag(&mut args);
         let (total_rows, total_cols) = match parse_dimensions(args.clone()) {
             Ok(dim) => dim,
             Err(e) => {
@@ -434,6 +2023,19 @@ fn main() {
 
         #[cfg(feature = "gui")]
         {
+            let open_path = open_path.clone();
+            // A bad keys file is reported before the window opens
+            let mut app = SpreadsheetApp::new(total_rows, total_cols, 0, 0);
+            app.set_safe_mode(safe_mode);
+            #[cfg(feature = "scripting")]
+            app.set_functions(functions.clone());
+            app.restore_theme();
+            if let Some(path) = &keys_path
+                && let Err(e) = app.load_keys(path)
+            {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            }
             let options = eframe::NativeOptions {
                 viewport: egui::ViewportBuilder::default()
                     .with_inner_size([1024.0, 768.0])
@@ -444,34 +2046,282 @@ fn main() {
                 "Rust Spreadsheet",
                 options,
                 Box::new(move |_cc| {
-                    Ok(Box::new(SpreadsheetApp::new(total_rows, total_cols, 0, 0)))
+                    if let Some(path) = &open_path {
+                        app.open_csv(path, spreadsheet::format::Locale::En);
+                    }
+                    if restore {
+                        app.load_workbook(&workbook::autosave_path().to_string_lossy());
+                    }
+                    Ok(Box::new(app))
                 }),
             )
             .unwrap();
         }
         #[cfg(feature = "autograder")]
         {
-            let mut spreadsheet: HashMap<u32, Cell> = HashMap::with_capacity(1024);
-            let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(512);
+            #[cfg(feature = "server")]
+            if let Some(port) = port {
+                let mut sheet = Spreadsheet::new(total_rows, total_cols);
+                sheet.set_safe_mode(safe_mode);
+                #[cfg(feature = "scripting")]
+                sheet.set_functions(functions.clone());
+                if let Some(path) = &open_path {
+                    let loaded = std::fs::File::open(path)
+                        .and_then(|file| sheet.import_csv(&mut io::BufReader::new(file), |_| true));
+                    if let Err(e) = loaded {
+                        eprintln!("{}: {}", path, e);
+                        process::exit(1);
+                    }
+                }
+                eprintln!("Serving on http://127.0.0.1:{}", port);
+                let sheet = std::sync::Arc::new(std::sync::Mutex::new(sheet));
+                if let Err(e) = spreadsheet::server::run(port, sheet) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            }
+            // `resize` changes the dimensions for the rest of the session
+            let (mut total_rows, mut total_cols) = (total_rows, total_cols);
+            let mut spreadsheet = storage_kind.create(total_cols);
+            let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(512);
             let mut is_range: Vec<bool> = vec![false; total_rows * total_cols];
             let mut start_row = 0;
             let mut start_col = 0;
-            let mut enable_output = true;
+            // `--restore` reads the file the session autosaves to
+            let restore_path = autosave.clone().unwrap_or_else(workbook::autosave_path);
+            let mut options = SessionOptions {
+                machine: machine_mode,
+                autosave,
+                ..SessionOptions::default()
+            };
+            // Installed before the sheet is loaded, so that its volatile cells are noted in it
+            options.eval.set_safe(safe_mode);
+            #[cfg(feature = "scripting")]
+            options.eval.set_functions(functions);
+            options.eval.install();
             let start_time = Instant::now();
-            print_sheet(
-                &spreadsheet,
-                &(start_row, start_col),
-                &(total_rows, total_cols),
-            );
-            prompt(
-                start_time.elapsed().as_secs_f64(),
-                STATUS[unsafe { STATUS_CODE }],
-            );
+            let mut status = Ok(());
+            if let Some(path) = &open_path {
+                match open_csv_file(
+                    &mut spreadsheet,
+                    &mut ranged,
+                    &mut is_range,
+                    (total_rows, total_cols),
+                    path,
+                ) {
+                    Ok(loaded) => status = loaded,
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            if restore {
+                let path = &restore_path;
+                if let Err(e) = workbook::load_workbook(
+                    &path.to_string_lossy(),
+                    &mut spreadsheet,
+                    &mut ranged,
+                    &mut is_range,
+                    (total_rows, total_cols),
+                ) {
+                    eprintln!("{}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            }
+            // Started after the sheet is loaded, so the journal opens with its cells
+            if let Some(path) = &journal_path
+                && let Err(e) = options.start_journal(path, &spreadsheet, (total_rows, total_cols))
+            {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            }
+            if serve_mode {
+                let stdin = io::stdin();
+                if let Err(e) = serve::serve(
+                    stdin.lock(),
+                    io::stdout(),
+                    &mut spreadsheet,
+                    &mut ranged,
+                    &mut is_range,
+                    (total_rows, total_cols),
+                ) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                process::exit(0);
+            }
+            #[cfg(feature = "tui")]
+            if tui_mode {
+                if let Err(e) = tui::run(
+                    &mut spreadsheet,
+                    &mut ranged,
+                    &mut is_range,
+                    (total_rows, total_cols),
+                    &mut options,
+                ) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                options.finish_exports(true);
+                process::exit(0);
+            }
+            catch_interrupt();
+            if let Some(path) = &script_path {
+                let file = std::fs::File::open(path).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                });
+                let result = run_script(
+                    &mut spreadsheet,
+                    &mut ranged,
+                    &mut is_range,
+                    io::BufReader::new(file),
+                    (total_rows, total_cols),
+                    &mut options,
+                    &mut (&mut start_row, &mut start_col),
+                );
+                let (commands, errors) = result.unwrap_or_else(|e| {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                });
+                if options.enable_output {
+                    print_sheet(
+                        &spreadsheet,
+                        &(start_row, start_col),
+                        &(total_rows, total_cols),
+                        &mut options.view,
+                    );
+                }
+                print_script_summary(path, commands, &errors);
+                options.finish_exports(true);
+                process::exit(if errors.is_empty() { 0 } else { 1 });
+            }
+            // With `--machine`, only the commands read are reported
+            if options.machine {
+                if let (Some(path), Err(_)) = (&open_path, status) {
+                    eprintln!("{}: {}", path, EvalStatus::message(status));
+                }
+                eprint!("> ");
+            } else {
+                options.report(
+                    &spreadsheet,
+                    (start_row, start_col),
+                    (total_rows, total_cols),
+                    start_time.elapsed().as_secs_f64(),
+                    status,
+                );
+            }
+            #[cfg(feature = "tui")]
+            let mut line_reader = tui::LineReader::default();
             loop {
-                let mut input = String::new();
-                let bytes_read = io::stdin().read_line(&mut input).unwrap();
-                if bytes_read == 0 {
+                let read_plain = || {
+                    let mut input = String::new();
+                    (io::stdin().read_line(&mut input).unwrap() > 0).then_some(input)
+                };
+                // Line editing echoes to stdout, which `--machine` keeps for the JSON lines
+                #[cfg(feature = "tui")]
+                let input = if options.machine {
+                    read_plain()
+                } else {
+                    line_reader.read_line(&options.history).unwrap()
+                };
+                #[cfg(not(feature = "tui"))]
+                let input = read_plain();
+                let Some(input) = input else {
                     break;
+                };
+                let input = match options.recall(input.trim()) {
+                    Ok(command) if command != input.trim() => {
+                        if !options.machine {
+                            println!("{}", command);
+                        }
+                        command
+                    }
+                    Ok(_) => input,
+                    Err(status) if options.machine => {
+                        println!("{}", machine::status_line(0.0, Err(status), &[], None));
+                        eprint!("> ");
+                        continue;
+                    }
+                    Err(status) => {
+                        println!();
+                        prompt(0.0, EvalStatus::message(Err(status)));
+                        continue;
+                    }
+                };
+                // Resizing replaces `is_range`, so it is handled here rather than in `run_command`
+                if let Some(args) = input.trim().strip_prefix("resize ") {
+                    let start_time = Instant::now();
+                    let before = options.snapshot(&spreadsheet, total_cols);
+                    options.range_cache.clear();
+                    options.search_index = None;
+                    // A journal replays on a sheet of the size it was started on
+                    if let Some((path, _)) = &options.journal {
+                        eprintln!(
+                            "resize: {} is being recorded; stop it with journal off first",
+                            path.display()
+                        );
+                    }
+                    let status = match options.journal {
+                        Some(_) => Err(EvalStatus::JournalRecording),
+                        None => utils::parse_resize_args(args),
+                    };
+                    let status = status.and_then(|dims| {
+                        // Stale cells are keyed by position, so they are brought up to date
+                        // first; a cell that fails to evaluate shows its error value
+                        let _ = options.recalc(&mut spreadsheet, &ranged, (total_rows, total_cols));
+                        utils::resize_sheet(
+                            &mut spreadsheet,
+                            &mut ranged,
+                            &mut is_range,
+                            (total_rows, total_cols),
+                            dims,
+                        )?;
+                        (total_rows, total_cols) = dims;
+                        start_row = start_row.min(total_rows - 1);
+                        start_col = start_col.min(total_cols - 1);
+                        options.validations.recheck(&spreadsheet, total_cols);
+                        Ok(())
+                    });
+                    options.note_snapshot(before, &spreadsheet, total_cols);
+                    options.record(input.trim(), status);
+                    options.report_watches(&spreadsheet, total_cols);
+                    options.report(
+                        &spreadsheet,
+                        (start_row, start_col),
+                        (total_rows, total_cols),
+                        start_time.elapsed().as_secs_f64(),
+                        status,
+                    );
+                    continue;
+                }
+                if framing::is_frame_header(&input) {
+                    options.range_cache.clear();
+                    options.search_index = None;
+                    let start_time = Instant::now();
+                    let before = options.snapshot(&spreadsheet, total_cols);
+                    let Some(status) = import_frame(
+                        &mut spreadsheet,
+                        &mut ranged,
+                        &mut is_range,
+                        &mut io::stdin().lock(),
+                        &input,
+                        (total_rows, total_cols),
+                        &mut options,
+                    ) else {
+                        break;
+                    };
+                    options.note_snapshot(before, &spreadsheet, total_cols);
+                    options.report(
+                        &spreadsheet,
+                        (start_row, start_col),
+                        (total_rows, total_cols),
+                        start_time.elapsed().as_secs_f64(),
+                        status,
+                    );
+                    continue;
                 }
                 if !interactive_mode(
                     &mut spreadsheet,
@@ -479,12 +2329,13 @@ fn main() {
                     &mut is_range,
                     input,
                     (total_rows, total_cols),
-                    &mut enable_output,
+                    &mut options,
                     &mut (&mut start_row, &mut start_col),
                 ) {
                     break;
                 }
             }
+            options.finish_exports(true);
         }
     }
 }
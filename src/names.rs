@@ -0,0 +1,219 @@
+//! # Names Module
+//! This module keeps the named ranges defined with `name range <name> <range>`, so that a range
+//! function can be written as `SUM(taxes)`. A named formula is stored as the range it names and
+//! tracked through `ranged` like any other range formula; the table remembers which cells were
+//! written with each name, so that redefining the name moves them to the new range.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::lock::LockTable;
+use crate::parser::assign_formulas;
+use crate::storage::SheetStorage;
+use crate::utils::parse_range;
+use crate::{CellData, CellKey, CellRef, EvalStatus, Valtype};
+
+/// A range function over a name, such as `SUM(taxes)`.
+static RE_NAMED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([A-Z]+)\(\s*([A-Za-z_][A-Za-z0-9_]*)\s*\)$").unwrap());
+
+/// The named ranges of a sheet and the cells using them.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::names::*;
+/// # let locks = spreadsheet::lock::LockTable::default();
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// let mut names = NameTable::default();
+/// define_name(&mut names, &mut sheet, &mut ranged, &mut is_range, (10, 10), &locks, ("taxes", "A1:B10"))?;
+/// let (formula, name) = names.resolve("SUM(taxes)").unwrap();
+/// assert_eq!((formula.as_str(), name.as_str()), ("SUM(A1:B10)", "TAXES"));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct NameTable {
+    /// The corners of each range, keyed by the name in upper case.
+    ranges: BTreeMap<String, (CellRef, CellRef)>,
+    /// The (row, col) of each cell whose formula was written with a name.
+    users: HashMap<String, BTreeSet<(usize, usize)>>,
+}
+
+/// Checks that a name can label a range: letters, digits and '_', starting with a letter or
+/// '_', and not readable as a cell reference.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && CellRef::new(&name.to_ascii_uppercase()).is_err()
+}
+
+impl NameTable {
+    /// Rewrites a range function over a named range, such as `SUM(taxes)`, to read the range.
+    ///
+    /// # Arguments
+    /// * `formula` - The formula text as entered.
+    ///
+    /// # Returns
+    /// * `Option<(String, String)>` - The formula with the range written out and the name it
+    ///   used, or `None` if the formula does not name a defined range.
+    pub fn resolve(&self, formula: &str) -> Option<(String, String)> {
+        let caps = RE_NAMED.captures(formula.trim())?;
+        let name = caps[2].to_ascii_uppercase();
+        let (cell1, cell2) = self.ranges.get(&name)?;
+        Some((format!("{}({}:{})", &caps[1], cell1, cell2), name))
+    }
+
+    /// Lists the `name range` commands that define every name again, in order of name.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::names::*;
+    /// # use std::collections::HashMap;
+    /// # let mut names = NameTable::default();
+    /// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    /// # let mut ranged = HashMap::new();
+    /// # let mut is_range = vec![false; 100];
+    /// # let locks = spreadsheet::lock::LockTable::default();
+    /// define_name(&mut names, &mut sheet, &mut ranged, &mut is_range, (10, 10), &locks, ("taxes", "A1:B10"))?;
+    /// assert_eq!(names.commands(), vec!["name range TAXES A1:B10"]);
+    /// # Ok::<(), EvalStatus>(())
+    /// ```
+    pub fn commands(&self) -> Vec<String> {
+        self.ranges
+            .iter()
+            .map(|(name, (cell1, cell2))| format!("name range {} {}:{}", name, cell1, cell2))
+            .collect()
+    }
+
+    /// Writes a cell's formula with the name it was written with, if it still reads the named
+    /// range, so that the cell moves with the name when it is assigned again.
+    ///
+    /// # Arguments
+    /// * `row` - The row index of the cell.
+    /// * `col` - The column index of the cell.
+    /// * `formula` - The formula the cell holds, with the range written out.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The formula naming the range, such as `SUM(TAXES)`, or `None` if the
+    ///   cell was not written with a name or no longer reads its range.
+    pub fn named_formula(&self, row: usize, col: usize, formula: &str) -> Option<String> {
+        let (name, _) = self
+            .users
+            .iter()
+            .find(|(_, cells)| cells.contains(&(row, col)))?;
+        let (func, _) = formula.split_once('(')?;
+        let named = format!("{}({})", func, name);
+        let (resolved, _) = self.resolve(&named)?;
+        (resolved == formula).then_some(named)
+    }
+
+    /// Records the name a cell's formula was written with, after it was assigned.
+    ///
+    /// # Arguments
+    /// * `row` - The row index of the cell.
+    /// * `col` - The column index of the cell.
+    /// * `name` - The name from `resolve`, or `None` if the new formula names no range.
+    pub fn record(&mut self, row: usize, col: usize, name: Option<&str>) {
+        for cells in self.users.values_mut() {
+            cells.remove(&(row, col));
+        }
+        if let Some(name) = name {
+            self.users
+                .entry(name.to_string())
+                .or_default()
+                .insert((row, col));
+        }
+    }
+}
+
+/// Parses the arguments of `name range <name> <range>`.
+///
+/// # Arguments
+/// * `args` - The text following `name range`, e.g. "taxes A1:B10".
+///
+/// # Returns
+/// * `Result<(&str, &str), EvalStatus>` - The name and the range, or
+///   `EvalStatus::UnrecognizedCmd` if there are not exactly two arguments.
+pub fn parse_name_args(args: &str) -> Result<(&str, &str), EvalStatus> {
+    let mut parts = args.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(range), None) => Ok((name, range)),
+        _ => Err(EvalStatus::UnrecognizedCmd),
+    }
+}
+
+/// Defines or redefines a named range.
+///
+/// Cells written with the name are moved to the new range in one recalculation. A cell that
+/// was reassigned since, and no longer reads the old range, is left alone. Moving a locked cell
+/// would change its formula, so the name keeps its old range if any of them is locked.
+///
+/// # Arguments
+/// * `names` - The name table to update.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `locks` - The locked cells, whose formulas the name cannot move.
+/// * `(name, range)` - The name, matched ignoring case, and the range it labels (e.g.,
+///   "A1:B10").
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Ok(())` if the name was defined, `EvalStatus::UnrecognizedCmd`
+///   if the name is not valid, `EvalStatus::InvalidRange` if the range is malformed or outside
+///   the sheet, `EvalStatus::CellLocked` if a cell using the name is locked, or the status from `parser::assign_formulas` if moving the cells using the name
+///   was rejected, in which case the old definition is kept.
+pub fn define_name<S: SheetStorage + ?Sized>(
+    names: &mut NameTable,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    locks: &LockTable,
+    (name, range): (&str, &str),
+) -> Result<(), EvalStatus> {
+    if !is_valid_name(name) {
+        return Err(EvalStatus::UnrecognizedCmd);
+    }
+    let (total_rows, total_cols) = total_dims;
+    let ((r1, c1), (r2, c2)) =
+        parse_range(range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    let new_range = (CellRef::from_indices(r1, c1), CellRef::from_indices(r2, c2));
+    let name = name.to_ascii_uppercase();
+
+    if let Some(&(old1, old2)) = names.ranges.get(&name) {
+        let mut formulas = Vec::new();
+        let users = names.users.entry(name.clone()).or_default();
+        users.retain(|&(row, col)| {
+            let key = (row * total_cols + col) as CellKey;
+            match spreadsheet.get(&key).map(|cell| &cell.data) {
+                Some(CellData::Range {
+                    cell1,
+                    cell2,
+                    value2: Valtype::Str(func),
+                    extra,
+                }) if (*cell1, *cell2) == (old1, old2) && extra.is_empty() => {
+                    formulas.push((
+                        row,
+                        col,
+                        format!("{}({}:{})", func, new_range.0, new_range.1),
+                    ));
+                    true
+                }
+                _ => false,
+            }
+        });
+        locks.check(formulas.iter().map(|&(row, col, _)| (row, col)))?;
+        assign_formulas(spreadsheet, ranged, is_range, total_dims, &formulas)?;
+    }
+    names.ranges.insert(name, new_range);
+    Ok(())
+}
@@ -3,11 +3,22 @@
 //! managing cell dependencies and performing calculations.
 //! It supports various formula types including constants, references, operations,
 //! ranges, and sleep functions, with cycle detection for dependency graphs.
+use log::{debug, trace};
 use regex::Regex;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::LazyLock;
 
+use crate::cache::RangeCache;
+use crate::format::Corners;
+use crate::storage::SheetStorage;
 use crate::utils::*;
-use crate::{Cell, CellData, CellName, STATUS_CODE, Valtype};
+use crate::{
+    Cell, CellAddr, CellData, CellError, CellKey, CellRef, EvalStatus, Lookup, Operand, Valtype,
+};
+
+/// A cell reference, possibly marked absolute with '$'.
+static RE_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$?\b[A-Z]+\$?[0-9]+\b").unwrap());
 
 /// Detects the type of formula and updates the cell's data and value accordingly.
 ///
@@ -17,6 +28,9 @@ use crate::{Cell, CellData, CellName, STATUS_CODE, Valtype};
 ///
 /// # Examples
 /// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashSet;
 /// let mut cell = Cell {
 ///     value: Valtype::Int(0),
 ///     data: CellData::Empty,
@@ -25,569 +39,968 @@ use crate::{Cell, CellData, CellName, STATUS_CODE, Valtype};
 /// detect_formula(&mut cell, "=A1+5");
 /// ```
 pub fn detect_formula(block: &mut Cell, form: &str) {
-    let form = form.trim();
+    parse_formula(block, form);
+    if block.data.is_volatile() {
+        EvalContext::current().note_volatile();
+    }
+}
 
-    // 1. SLEEP_CONST: "SLEEP(<int>)"
-    let re_sleep_const = Regex::new(r"^SLEEP\((-?\d+)\)$").unwrap();
-    if let Some(caps) = re_sleep_const.captures(form) {
-        if let Some(m) = caps.get(1) {
-            if let Ok(val) = m.as_str().parse::<i32>() {
-                block.reset();
-                block.value = Valtype::Int(val);
-                block.data = CellData::SleepC;
-                return;
+/// Parses a formula into a cell, for `detect_formula`.
+///
+/// # Arguments
+/// * `block` - The mutable cell to update with the parsed formula.
+/// * `form` - The formula string to parse (e.g., "=A1+5").
+fn parse_formula(block: &mut Cell, form: &str) {
+    match crate::syntax::read_formula(form.trim()) {
+        Ok((data, value)) => {
+            block.reset();
+            if let Some(value) = value {
+                block.value = value;
             }
+            block.data = data;
         }
+        Err(_) => block.data = CellData::Invalid,
     }
-    // 2. SLEEP_REF: "SLEEP(<ref>)"
-    let re_sleep_ref = Regex::new(r"^SLEEP\(([A-Z]+[0-9]+)\)$").unwrap();
-    if let Some(caps) = re_sleep_ref.captures(form) {
-        if let Some(m) = caps.get(1) {
-            block.reset();
-            let cell_ref = CellName::new(m.as_str()).unwrap();
-            block.data = CellData::SleepR { cell1: cell_ref };
-            return;
-        }
+}
+
+/// Resolves a reference to zero-based indices, provided it names a cell inside the sheet.
+///
+/// # Arguments
+/// * `cell` - The reference to resolve.
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<(usize, usize), EvalStatus>` - The (row, col) indices, or
+///   `EvalStatus::InvalidRange` if the reference is outside the sheet.
+fn resolve(
+    cell: &CellRef,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<(usize, usize), EvalStatus> {
+    match cell.addr() {
+        Some(a) if a.row < total_rows && a.col < total_cols => Ok((a.row, a.col)),
+        _ => Err(EvalStatus::InvalidRange),
     }
-    // 3. CONSTANT: a lone integer
-    let re_constant = Regex::new(r"^(-?\d+)$").unwrap();
-    if let Some(caps) = re_constant.captures(form) {
-        if let Some(m) = caps.get(1) {
-            if let Ok(val) = m.as_str().parse::<i32>() {
-                block.reset();
-                block.value = Valtype::Int(val);
-                block.data = CellData::Const;
-                return;
-            }
-        }
+}
+
+/// Clamps a range formula whose range runs past the edge of the sheet.
+///
+/// Only the bottom-right corner is moved, and only when the top-left corner lies inside the
+/// sheet, so a range that starts outside the sheet is still rejected. '$' markers are kept.
+///
+/// # Arguments
+/// * `formula` - The formula text (e.g., "SUM(A1:ZZZ999)").
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Option<(String, CellRef, CellRef)>` - The clamped formula, with the corner as written and
+///   as clamped, or `None` if the formula is not a range function crossing the edge.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// let (formula, from, to) = clamp_range_formula("SUM(A1:ZZZ999)", 10, 10).unwrap();
+/// assert_eq!(formula, "SUM(A1:J10)");
+/// assert_eq!((from.to_string(), to.to_string()), ("ZZZ999".to_string(), "J10".to_string()));
+/// ```
+pub fn clamp_range_formula(
+    formula: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Option<(String, CellRef, CellRef)> {
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    detect_formula(&mut cell, formula);
+    let CellData::Range {
+        cell1,
+        cell2,
+        value2,
+        extra,
+    } = cell.data
+    else {
+        return None;
+    };
+    resolve(&cell1, total_rows, total_cols).ok()?;
+    let end = cell2.addr()?;
+    if end.row < total_rows && end.col < total_cols {
+        return None;
     }
-    // 4. REFERENCE: a cell reference (e.g., "A1")
-    let re_reference = Regex::new(r"^([A-Z]+[0-9]+)$").unwrap();
-    if let Some(caps) = re_reference.captures(form) {
-        if let Some(m) = caps.get(1) {
-            block.reset();
-            let cell_ref = CellName::new(m.as_str()).unwrap();
-            block.data = CellData::Ref { cell1: cell_ref };
-            return;
-        }
-    }
-    // 5. CONSTANT_CONSTANT: "<int><op><int>"
-    let re_const_const = Regex::new(r"^(-?\d+)([-+*/])(-?\d+)$").unwrap();
-    if let Some(caps) = re_const_const.captures(form) {
-        block.reset();
-        let val1: i32 = caps.get(1).unwrap().as_str().parse().unwrap();
-        let op = caps.get(2).unwrap().as_str().chars().next().unwrap();
-        let val2: i32 = caps.get(3).unwrap().as_str().parse().unwrap();
-        block.value = Valtype::Int(val1);
-        block.data = CellData::CoC {
-            op_code: op,
-            value2: Valtype::Int(val2),
-        };
-        return;
-    }
-    // 6. CONSTANT_REFERENCE: "<int><op><ref>"
-    let re_const_ref = Regex::new(r"^(-?\d+)([-+*/])([A-Z]+[0-9]+)$").unwrap();
-    if let Some(caps) = re_const_ref.captures(form) {
-        block.reset();
-        let val1: i32 = caps.get(1).unwrap().as_str().parse().unwrap();
-        let op = caps.get(2).unwrap().as_str().chars().next().unwrap();
-        let ref2 = CellName::new(caps.get(3).unwrap().as_str()).unwrap();
-        block.value = Valtype::Int(val1);
-        block.data = CellData::CoR {
-            op_code: op,
-            value2: Valtype::Int(val1),
-            cell2: ref2,
-        };
-        return;
-    }
-    // 7. REFERENCE_CONSTANT: "<ref><op><int>"
-    let re_ref_const = Regex::new(r"^([A-Z]+[0-9]+)([-+*/])(-?\d+)$").unwrap();
-    if let Some(caps) = re_ref_const.captures(form) {
-        block.reset();
-        let ref1 = CellName::new(caps.get(1).unwrap().as_str()).unwrap();
-        let op = caps.get(2).unwrap().as_str().chars().next().unwrap();
-        let val1: i32 = caps.get(3).unwrap().as_str().parse().unwrap();
-        block.data = CellData::RoC {
-            op_code: op,
-            value2: Valtype::Int(val1),
-            cell1: ref1,
-        };
-        return;
-    }
-    // 8. REFERENCE_REFERENCE: "<ref><op><ref>"
-    let re_ref_ref = Regex::new(r"^([A-Z]+[0-9]+)([-+*/])([A-Z]+[0-9]+)$").unwrap();
-    if let Some(caps) = re_ref_ref.captures(form) {
-        block.reset();
-        let ref1 = CellName::new(caps.get(1).unwrap().as_str()).unwrap();
-        let op = caps.get(2).unwrap().as_str().chars().next().unwrap();
-        let ref2 = CellName::new(caps.get(3).unwrap().as_str()).unwrap();
-        block.data = CellData::RoR {
-            op_code: op,
-            cell1: ref1,
-            cell2: ref2,
-        };
-        return;
-    }
-    // 9. RANGE_FUNCTION: "<func>(<ref1>:<ref2>)"
-    let re_range_func = Regex::new(r"^([A-Z]+)\(([A-Z]+[0-9]+):([A-Z]+[0-9]+)\)$").unwrap();
-    if let Some(caps) = re_range_func.captures(form) {
-        block.reset();
-        let func = caps.get(1).unwrap().as_str();
-        let ref1 = CellName::new(caps.get(2).unwrap().as_str()).unwrap();
-        let ref2 = CellName::new(caps.get(3).unwrap().as_str()).unwrap();
-        // Wrap the function name as a CellName
-        block.data = CellData::Range {
-            cell1: ref1,
-            cell2: ref2,
-            value2: Valtype::Str(CellName::new(func).unwrap()),
-        };
-        return;
+    let clamped = cell2.with_addr(CellAddr::new(
+        end.row.min(total_rows - 1),
+        end.col.min(total_cols - 1),
+    ));
+    cell.data = CellData::Range {
+        cell1,
+        cell2: clamped,
+        value2,
+        extra,
+    };
+    Some((formula_string(&cell), cell2, clamped))
+}
+
+/// Builds the formulas written by `fill <range> = <formula>`.
+///
+/// The formula is written as it would be entered in the top-left cell of the range, and each
+/// other cell gets a copy with its references shifted by the cell's offset from that corner.
+/// Parts of a reference marked with '$' stay fixed.
+///
+/// # Arguments
+/// * `args` - The text following the `fill` keyword, e.g. "A1:A100 = B1*2".
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<Vec<(usize, usize, String)>, EvalStatus>` - The (row, col, formula) assignments
+///   in row-major order, `EvalStatus::UnrecognizedCmd` if the command or formula is malformed,
+///   or `EvalStatus::InvalidRange` if the range or a shifted reference lies outside the sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// let formulas = fill_formulas("C1:C3 = A1*$B$1", 10, 10).unwrap();
+/// assert_eq!(formulas[2], (2, 2, "A3*$B$1".to_string()));
+/// ```
+pub fn fill_formulas(
+    args: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<Vec<(usize, usize, String)>, EvalStatus> {
+    let (range, formula) = args.split_once('=').ok_or(EvalStatus::UnrecognizedCmd)?;
+    let ((r1, c1), (r2, c2)) =
+        parse_range(range.trim(), total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    let mut template = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    detect_formula(&mut template, formula.trim());
+    if matches!(template.data, CellData::Invalid | CellData::Empty) {
+        return Err(EvalStatus::UnrecognizedCmd);
+    }
+    let mut formulas = Vec::with_capacity((r2 - r1 + 1) * (c2 - c1 + 1));
+    for row in r1..=r2 {
+        for col in c1..=c2 {
+            let data = shift_cell_data(
+                &template.data,
+                (row - r1) as isize,
+                (col - c1) as isize,
+                total_rows,
+                total_cols,
+            )
+            .ok_or(EvalStatus::InvalidRange)?;
+            let cell = Cell {
+                value: template.value.clone(),
+                data,
+                dependents: HashSet::new(),
+            };
+            formulas.push((row, col, formula_string(&cell)));
+        }
     }
-    block.data = CellData::Invalid;
+    Ok(formulas)
 }
 
 /// Evaluates the value of a cell based on its data type and dependencies.
 ///
 /// # Arguments
-/// * `sheet` - A hash map containing cell data, indexed by a unique `u32` key.
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
 /// * `total_rows` - The total number of rows in the spreadsheet.
 /// * `total_cols` - The total number of columns in the spreadsheet.
 /// * `r` - The row index of the cell to evaluate.
 /// * `c` - The column index of the cell to evaluate.
 ///
 /// # Returns
-/// The computed value of the cell as a `Valtype`.
+/// * `Result<Valtype, EvalStatus>` - The computed value of the cell, which is `#DIV/0!`
+///   after a division by zero and the error of an input holding one, or the status if the
+///   formula refers outside the sheet or is not recognized.
 ///
 /// # Examples
 /// ```
-/// let mut sheet: HashMap<u32, Cell> = HashMap::new();
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
 /// let result = eval(&sheet, 10, 10, 0, 0);
+/// assert_eq!(result, Ok(Valtype::Int(0)));
 /// ```
-pub fn eval(
-    sheet: &HashMap<u32, Cell>,
+pub fn eval<S: SheetStorage + ?Sized>(
+    sheet: &S,
     total_rows: usize,
     total_cols: usize,
     r: usize,
     c: usize,
-) -> Valtype {
-    unsafe {
-        EVAL_ERROR = false;
-        STATUS_CODE = 0;
-    }
-    let err_value = Valtype::Str(CellName::new("ERR").unwrap());
+) -> Result<Valtype, EvalStatus> {
+    eval_with(
+        sheet,
+        total_rows,
+        total_cols,
+        r,
+        c,
+        None,
+        &EvalContext::current(),
+    )
+}
 
-    // lookup-or-default
-    let key = (r * total_cols + c) as u32;
-    let parsed = sheet.get(&key).cloned().unwrap_or(Cell {
-        value: Valtype::Int(0),
-        data: CellData::Empty,
-        dependents: Default::default(),
-    });
+/// Evaluates a cell as `eval` does, with a `SLEEP` stopping early once `cancel` is cancelled
+/// and `RAND` and `RANDBETWEEN` drawn from the session's `context`.
+///
+/// # Returns
+/// * `Result<Valtype, EvalStatus>` - As for `eval`, or `EvalStatus::Cancelled` if a `SLEEP`
+///   was cut short.
+fn eval_with<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_rows: usize,
+    total_cols: usize,
+    r: usize,
+    c: usize,
+    cancel: Option<&CancelToken>,
+    context: &EvalContext,
+) -> Result<Valtype, EvalStatus> {
+    let key = (r * total_cols + c) as CellKey;
+    let Some(parsed) = sheet.get(&key) else {
+        return Ok(Valtype::Int(0));
+    };
 
+    // helper for integer operands; an error value propagates, and text is a #VALUE! error
+    let int = |value: &Valtype| match value {
+        Valtype::Int(v) => Ok(*v),
+        Valtype::Str(_) => Err(EvalStatus::ErrValue(CellError::Value)),
+        Valtype::Err(error) => Err(EvalStatus::ErrValue(*error)),
+    };
     // helper for single‑cell refs
-    let get_cell_val = |ref_name: &CellName| -> Option<i32> {
-        let (ri, ci) = to_indices(ref_name.as_str());
-        if ri < total_rows && ci < total_cols {
-            let idx = (ri * total_cols + ci) as u32;
-            match sheet
-                .get(&idx)
-                .map(|c| &c.value)
-                .unwrap_or(&Valtype::Int(0))
-            {
-                Valtype::Int(v) => Some(*v),
-                Valtype::Str(_) => {
-                    unsafe {
-                        EVAL_ERROR = true;
-                    }
-                    None
-                }
-            }
-        } else {
-            unsafe {
-                STATUS_CODE = 1;
-            }
-            None
-        }
+    let get_cell_val = |ref_name: &CellRef| -> Result<i32, EvalStatus> {
+        let (ri, ci) = resolve(ref_name, total_rows, total_cols)?;
+        let idx = (ri * total_cols + ci) as CellKey;
+        sheet.get(&idx).map_or(Ok(0), |cell| int(&cell.value))
     };
 
-    let result: i32 = match parsed.data {
-        CellData::Const => match parsed.value {
-            Valtype::Int(v) => v,
-            Valtype::Str(_) => {
-                unsafe {
-                    EVAL_ERROR = true;
-                }
-                0
-            }
-        },
-        CellData::Ref { ref cell1 } => get_cell_val(cell1).unwrap_or(0),
+    // Text is shown as it is, while a formula reading it gets #VALUE!
+    if let (CellData::Const, Valtype::Str(text)) = (&parsed.data, &parsed.value) {
+        return Ok(Valtype::Str(text.clone()));
+    }
+    let result = (|| match &parsed.data {
+        CellData::Const => int(&parsed.value),
+        CellData::Ref { cell1 } => get_cell_val(cell1),
         CellData::CoC {
+            value1,
             op_code,
-            ref value2,
-        } => {
-            let v1 = if let Valtype::Int(v) = parsed.value {
-                v
-            } else {
-                unsafe {
-                    EVAL_ERROR = true;
-                }
-                0
-            };
-            let v2 = if let Valtype::Int(v) = *value2 {
-                v
-            } else {
-                unsafe {
-                    EVAL_ERROR = true;
-                }
-                0
-            };
-            compute(v1, Some(op_code), v2)
-        }
+            value2,
+        } => compute(int(value1)?, Some(*op_code), int(value2)?),
         CellData::CoR {
             op_code,
-            ref value2,
-            ref cell2,
-        } => {
-            let v1 = if let Valtype::Int(v) = *value2 {
-                v
-            } else {
-                unsafe {
-                    EVAL_ERROR = true;
-                }
-                0
-            };
-            if let Some(v2) = get_cell_val(cell2) {
-                compute(v1, Some(op_code), v2)
-            } else {
-                0
-            }
-        }
+            value2,
+            cell2,
+        } => compute(int(value2)?, Some(*op_code), get_cell_val(cell2)?),
         CellData::RoC {
             op_code,
-            ref value2,
-            ref cell1,
-        } => {
-            let v2 = if let Valtype::Int(v) = *value2 {
-                v
-            } else {
-                unsafe {
-                    EVAL_ERROR = true;
-                }
-                0
-            };
-            if let Some(v1) = get_cell_val(cell1) {
-                compute(v1, Some(op_code), v2)
-            } else {
-                0
-            }
-        }
+            value2,
+            cell1,
+        } => compute(get_cell_val(cell1)?, Some(*op_code), int(value2)?),
         CellData::RoR {
             op_code,
-            ref cell1,
-            ref cell2,
-        } => {
-            let v1 = get_cell_val(cell1).unwrap_or(0);
-            let v2 = get_cell_val(cell2).unwrap_or(0);
-            compute(v1, Some(op_code), v2)
-        }
-        CellData::Range {
             cell1,
             cell2,
+        } => compute(get_cell_val(cell1)?, Some(*op_code), get_cell_val(cell2)?),
+        CellData::Range {
             value2: Valtype::Str(func),
-        } => {
-            let (r1, c1) = to_indices(cell1.as_str());
-            let (r2, c2) = to_indices(cell2.as_str());
-            if r1 <= r2 && c1 <= c2 && r2 < total_rows && c2 < total_cols {
-                let choice = match func.as_str().to_uppercase().as_str() {
-                    "MAX" => 1,
-                    "MIN" => 2,
-                    "AVG" => 3,
-                    "SUM" => 4,
-                    "STDEV" => 5,
-                    _ => {
-                        unsafe {
-                            STATUS_CODE = 2;
-                        }
-                        0
-                    }
-                };
-                compute_range(sheet, total_cols, r1, r2, c1, c2, choice)
+            ..
+        } => range_value(
+            sheet,
+            (total_rows, total_cols),
+            func.as_str(),
+            &parsed.data.range_areas(),
+            context,
+        ),
+        CellData::Expr(expr) => {
+            let operand = |operand: &Operand| match operand {
+                Operand::Const(value) => Ok(*value),
+                Operand::Ref(cell1) => get_cell_val(cell1),
+                Operand::Range { func, cell1, cell2 } => range_value(
+                    sheet,
+                    (total_rows, total_cols),
+                    func.as_str(),
+                    &[(*cell1, *cell2)],
+                    context,
+                ),
+            };
+            compute(operand(&expr.lhs)?, Some(expr.op_code), operand(&expr.rhs)?)
+        }
+        CellData::Lookup(lookup) => lookup_value(sheet, (total_rows, total_cols), lookup),
+        CellData::Func { func, args } => {
+            let values = args
+                .iter()
+                .map(|arg| match arg {
+                    Operand::Const(value) => Ok(*value),
+                    Operand::Ref(cell1) => get_cell_val(cell1),
+                    Operand::Range { .. } => Err(EvalStatus::UnrecognizedCmd),
+                })
+                .collect::<Result<Vec<i32>, EvalStatus>>()?;
+            let func = written_function_name(func.as_str());
+            if parsed.data.is_volatile() {
+                draw_random(func, &values, key, context)
             } else {
-                unsafe {
-                    STATUS_CODE = 1;
-                }
-                0
+                compute_func(func, &values)
             }
         }
         CellData::SleepC => {
             if let Valtype::Int(v) = parsed.value {
-                sleepy(v);
-                v
+                sleepy(v, cancel, context)?;
+                Ok(v)
             } else {
-                0
+                Ok(0)
             }
         }
-        CellData::SleepR { ref cell1 } => {
-            if let Some(v) = get_cell_val(cell1) {
-                sleepy(v);
-                v
-            } else {
-                0
-            }
+        CellData::SleepR { cell1 } => {
+            let v = get_cell_val(cell1)?;
+            sleepy(v, cancel, context)?;
+            Ok(v)
         }
-        CellData::Invalid => {
-            unsafe {
-                STATUS_CODE = 2;
+        CellData::Invalid => Err(EvalStatus::UnrecognizedCmd),
+        _ => Ok(0),
+    })();
+
+    match result {
+        Ok(v) => Ok(Valtype::Int(v)),
+        Err(EvalStatus::ErrValue(error)) => Ok(Valtype::Err(error)),
+        Err(status) => Err(status),
+    }
+}
+
+/// Applies a range function such as SUM or COUNT to the cells of one or more areas.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `func` - The function name (case-insensitive).
+/// * `areas` - The (top-left, bottom-right) corners of each area, as written.
+/// * `context` - The context of the session the sheet belongs to.
+///
+/// # Returns
+/// * `Result<i32, EvalStatus>` - The result over the cells of every area,
+///   `EvalStatus::InvalidRange` if an area is reversed or outside the sheet, or
+///   `EvalStatus::UnrecognizedCmd` for an unknown function. With the `scripting` feature, names
+///   the engine does not know are looked up in the script the session loaded.
+fn range_value<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_dims: (usize, usize),
+    func: &str,
+    areas: &[(CellRef, CellRef)],
+    context: &EvalContext,
+) -> Result<i32, EvalStatus> {
+    // Only the functions of a script are looked up in the context
+    #[cfg(not(feature = "scripting"))]
+    let _ = context;
+    let total_cols = total_dims.1;
+    let corners = range_corners(total_dims, areas)?;
+    let Some(&(_, (r2, c2))) = corners.last() else {
+        return Err(EvalStatus::InvalidRange);
+    };
+    if let Some(choice) = range_choice(func) {
+        return compute_areas(sheet, total_cols, &corners, choice);
+    }
+    match func.to_uppercase().as_str() {
+        // A sparkline stands for its latest point, the last cell of the range
+        "SPARK" => match sheet.get(&((r2 * total_cols + c2) as CellKey)) {
+            Some(cell) => match &cell.value {
+                Valtype::Int(v) => Ok(*v),
+                Valtype::Str(_) => Err(EvalStatus::ErrValue(CellError::Value)),
+                Valtype::Err(error) => Err(EvalStatus::ErrValue(*error)),
+            },
+            None => Ok(0),
+        },
+        #[cfg(feature = "scripting")]
+        name if context.functions().is_some_and(|f| f.contains(name)) => {
+            let mut values = Vec::new();
+            for &((r1, c1), (r2, c2)) in &corners {
+                for row in get_range_values(
+                    sheet,
+                    total_dims,
+                    CellAddr::new(r1, c1),
+                    CellAddr::new(r2, c2),
+                ) {
+                    for value in row {
+                        values.push(match value {
+                            Valtype::Int(v) => v,
+                            Valtype::Str(_) => {
+                                return Err(EvalStatus::ErrValue(CellError::Value));
+                            }
+                            Valtype::Err(error) => return Err(EvalStatus::ErrValue(error)),
+                        });
+                    }
+                }
             }
-            0
+            context
+                .functions()
+                .map_or(Err(CellError::Name), |functions| {
+                    functions.call(name, &values, values.len() == 1)
+                })
+                .map_err(EvalStatus::ErrValue)
+        }
+        _ => Err(EvalStatus::UnrecognizedCmd),
+    }
+}
+
+/// Resolves the areas of a range function to the (row, col) of their corners.
+///
+/// # Arguments
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `areas` - The (top-left, bottom-right) corners of each area, as written.
+///
+/// # Returns
+/// * `Result<Vec<Corners>, EvalStatus>` - The corners of each area, or
+///   `EvalStatus::InvalidRange` if an area is reversed or outside the sheet.
+fn range_corners(
+    total_dims: (usize, usize),
+    areas: &[(CellRef, CellRef)],
+) -> Result<Vec<Corners>, EvalStatus> {
+    let (total_rows, total_cols) = total_dims;
+    let mut corners = Vec::with_capacity(areas.len());
+    for (cell1, cell2) in areas {
+        let (r1, c1) = resolve(cell1, total_rows, total_cols)?;
+        let (r2, c2) = resolve(cell2, total_rows, total_cols)?;
+        if r1 > r2 || c1 > c2 {
+            return Err(EvalStatus::InvalidRange);
+        }
+        corners.push(((r1, c1), (r2, c2)));
+    }
+    Ok(corners)
+}
+
+/// Returns the `compute_range` choice of a built-in range function such as SUM, or `None` for
+/// SPARK and the functions of a loaded script.
+fn range_choice(func: &str) -> Option<i32> {
+    match func.to_uppercase().as_str() {
+        "MAX" => Some(1),
+        "MIN" => Some(2),
+        "AVG" => Some(3),
+        "SUM" => Some(4),
+        "STDEV" => Some(5),
+        "COUNT" => Some(6),
+        _ => None,
+    }
+}
+
+/// Evaluates a range formula other than STDEV from the totals kept in a `RangeCache`,
+/// brought up to date with the cells changed since, or else from its whole ranges, keeping
+/// their totals for the next time.
+///
+/// # Arguments
+/// * `cache` - The range cache of the sheet.
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `key` - The key of the cell to evaluate.
+/// * `before` - The cells changed since the last recalculation, with their value before.
+///
+/// # Returns
+/// * `Option<Result<i32, EvalStatus>>` - The result as from `range_value`, or `None` if the
+///   cell does not hold such a formula.
+fn cached_range_value<S: SheetStorage + ?Sized>(
+    cache: &mut RangeCache,
+    sheet: &S,
+    total_dims: (usize, usize),
+    key: CellKey,
+    before: &HashMap<CellKey, Valtype>,
+) -> Option<Result<i32, EvalStatus>> {
+    let cell = sheet.get(&key)?;
+    let CellData::Range {
+        value2: Valtype::Str(func),
+        ..
+    } = &cell.data
+    else {
+        return None;
+    };
+    let choice = range_choice(func.as_str()).filter(|&choice| choice != 5)?;
+    let corners = match range_corners(total_dims, &cell.data.range_areas()) {
+        Ok(corners) => corners,
+        Err(status) => return Some(Err(status)),
+    };
+    if let Some(result) = cache.lookup(sheet, total_dims.1, key, &corners, choice, before) {
+        trace!(
+            "{} computed from kept range totals",
+            key_name(key, total_dims.1)
+        );
+        return Some(result);
+    }
+    Some(
+        range_totals(sheet, total_dims.1, &corners).and_then(|totals| {
+            cache.store(key, corners, choice, totals);
+            totals
+                .result(choice)
+                .unwrap_or(Err(EvalStatus::UnrecognizedCmd))
+        }),
+    )
+}
+
+/// Finds the key of a `VLOOKUP` in the first column of its table, top to bottom, and reads the
+/// same row of the requested column.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `lookup` - The lookup to evaluate.
+///
+/// # Returns
+/// * `Result<i32, EvalStatus>` - The value found, `CellError::NotAvailable` if the first column
+///   does not hold the key, `EvalStatus::InvalidRange` if the table is reversed or outside the
+///   sheet or the column is outside the table, or the error held by the key or the value found.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "10")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 1, 0, "20")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 2, 0, "30")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 1, 1, "2")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 2, 1, "3")?;
+/// // A1..A3 hold 10, 20, 30 and B1..B3 hold 1, 2, 3
+/// assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "VLOOKUP(20,A1:B3,2)")?;
+/// assert_eq!(sheet[&2].value, Valtype::Int(2));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+fn lookup_value<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_dims: (usize, usize),
+    lookup: &Lookup,
+) -> Result<i32, EvalStatus> {
+    let (total_rows, total_cols) = total_dims;
+    let (r1, c1) = resolve(&lookup.cell1, total_rows, total_cols)?;
+    let (r2, c2) = resolve(&lookup.cell2, total_rows, total_cols)?;
+    let column = lookup.column as usize;
+    if r1 > r2 || c1 > c2 || column == 0 || column > c2 - c1 + 1 {
+        return Err(EvalStatus::InvalidRange);
+    }
+    let value_at = |row: usize, col: usize| {
+        sheet
+            .get(&((row * total_cols + col) as CellKey))
+            .map_or(Valtype::Int(0), |cell| cell.value.clone())
+    };
+    let key = match &lookup.key {
+        Operand::Const(value) => Valtype::Int(*value),
+        Operand::Ref(cell) => {
+            let (row, col) = resolve(cell, total_rows, total_cols)?;
+            value_at(row, col)
         }
-        _ => 0,
+        Operand::Range { .. } => return Err(EvalStatus::UnrecognizedCmd),
     };
+    if let Valtype::Err(error) = key {
+        return Err(EvalStatus::ErrValue(error));
+    }
+    let row = (r1..=r2)
+        .find(|&row| value_at(row, c1) == key)
+        .ok_or(EvalStatus::ErrValue(CellError::NotAvailable))?;
+    match value_at(row, c1 + column - 1) {
+        Valtype::Int(v) => Ok(v),
+        Valtype::Str(_) => Err(EvalStatus::ErrValue(CellError::Value)),
+        Valtype::Err(error) => Err(EvalStatus::ErrValue(error)),
+    }
+}
 
-    if unsafe { EVAL_ERROR } {
-        err_value
-    } else {
-        Valtype::Int(result)
+/// The outcome of `update_and_recalc`.
+///
+/// # Fields
+/// * `changed` - The keys of the cells whose value changed, the updated cell first and the
+///   others in the order they were recalculated. Empty if the update was rejected.
+/// * `status` - `Ok(())` if the cell was updated, or the status explaining why its formula was
+///   rejected or could not be evaluated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecalcResult {
+    pub changed: Vec<CellKey>,
+    pub status: Result<(), EvalStatus>,
+}
+
+impl RecalcResult {
+    /// Creates the result of an update that was rejected, which changed no cell.
+    fn rejected(status: EvalStatus) -> Self {
+        RecalcResult {
+            changed: Vec::new(),
+            status: Err(status),
+        }
     }
 }
 
 /// Updates a cell's formula and recalculates dependent cells, handling cycle detection.
 ///
 /// # Arguments
-/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `u32` key.
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
 /// * `ranged` - A hash map tracking ranges for dependency management.
 /// * `is_r` - A boolean array indicating whether each cell is part of a range.
 /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
 /// * `r` - The row index of the cell to update.
 /// * `c` - The column index of the cell to update.
 /// * `backup` - A backup of the cell’s previous state for rollback if needed.
-pub fn update_and_recalc(
-    sheet: &mut HashMap<u32, Cell>,
-    ranged: &mut HashMap<u32, Vec<(u32, u32)>>,
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed, and whether the cell was updated: its
+///   formula may be rejected (a reference outside the sheet or a cycle is rolled back) or fail
+///   to evaluate. A recalculation cancelled through the `CancelToken` installed on the thread
+///   is rolled back like a cycle, with `EvalStatus::Cancelled`.
+pub fn update_and_recalc<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
     is_r: &mut [bool],
     total_dims: (usize, usize),
     r: usize,
     c: usize,
     backup: Cell,
-) {
-    type Coord = (usize, usize);
+) -> RecalcResult {
+    let cell_key = (r * total_dims.1 + c) as CellKey;
+    if let Err(status) = relink(sheet, ranged, is_r, total_dims, cell_key, &backup) {
+        debug!(
+            "{} rejected: {}",
+            to_cell_name(r, c),
+            EvalStatus::message(Err(status))
+        );
+        return RecalcResult::rejected(status);
+    }
+    let cancel = CancelToken::current();
+    if let Some(token) = &cancel {
+        token.set_running(true);
+    }
+    // The totals kept for the cell's previous formula no longer apply
+    let mut range_cache = RangeCache::take_installed();
+    if let Some(cache) = &mut range_cache {
+        cache.forget(cell_key);
+    }
+    let result = recalc_from(
+        sheet,
+        ranged,
+        total_dims,
+        &[cell_key],
+        cancel.as_ref(),
+        range_cache
+            .as_mut()
+            .map(|cache| (cache, cell_key, &backup.value)),
+    );
+    if let Some(token) = &cancel {
+        token.set_running(false);
+    }
+    if let Some(mut cache) = range_cache {
+        // A cancelled recalculation puts back values the totals were brought up to date with
+        if result
+            .as_ref()
+            .is_some_and(|result| result.status == Err(EvalStatus::Cancelled))
+        {
+            cache.clear();
+        }
+        cache.reinstall();
+    }
+    match result {
+        Some(result) if result.status == Err(EvalStatus::Cancelled) => {
+            // The recalculation put back the values it changed; roll back the cell as well
+            debug!(
+                "recalculation cancelled; {} rolled back",
+                to_cell_name(r, c)
+            );
+            unlink(sheet, ranged, is_r, total_dims, cell_key, backup);
+            result
+        }
+        Some(mut result) => {
+            // A constant gets its value when parsed, before the recalculation
+            let value = sheet.get(&cell_key).map(|cell| &cell.value);
+            if value != Some(&backup.value) && !result.changed.contains(&cell_key) {
+                result.changed.insert(0, cell_key);
+            }
+            result
+        }
+        None => {
+            // Roll back the cell and restore the edges it had before
+            debug!("cycle through {}; formula rolled back", to_cell_name(r, c));
+            unlink(sheet, ranged, is_r, total_dims, cell_key, backup);
+            RecalcResult::rejected(EvalStatus::CycleDetected)
+        }
+    }
+}
 
-    // 1) VALIDATION (unchanged)
+/// Replaces the dependency edges of a cell's previous formula with those of its new formula.
+///
+/// # Arguments
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_r` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `cell_key` - The key of the cell, which already holds its new formula.
+/// * `backup` - The cell’s previous state, whose edges are removed.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Ok(())` if the edges were replaced, or the status explaining
+///   why the new formula was rejected, in which case the cell is restored from `backup`.
+fn relink<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_r: &mut [bool],
+    total_dims: (usize, usize),
+    cell_key: CellKey,
+    backup: &Cell,
+) -> Result<(), EvalStatus> {
+    // 1) VALIDATION
     {
         let data = &sheet
-            .get(&((r * total_dims.1 + c) as u32))
+            .get(&cell_key)
             .map(|cell| &cell.data)
             .unwrap_or(&CellData::Empty);
-        match data {
-            CellData::Invalid => {
-                unsafe {
-                    STATUS_CODE = 2;
-                }
-                return;
-            }
-            CellData::Range { cell1, cell2, .. } => {
-                for name in &[cell1, cell2] {
-                    let (ri, ci) = to_indices(name.as_str());
-                    if ri >= total_dims.0 || ci >= total_dims.1 {
-                        unsafe {
-                            STATUS_CODE = 1;
-                        }
-                        return;
-                    }
-                }
+        let (total_rows, total_cols) = total_dims;
+        let refs_valid = match data {
+            CellData::Invalid => Err(EvalStatus::UnrecognizedCmd),
+            CellData::Range { .. } => {
+                data.range_areas()
+                    .into_iter()
+                    .try_fold((0, 0), |_, (cell1, cell2)| {
+                        resolve(&cell1, total_rows, total_cols)
+                            .and(resolve(&cell2, total_rows, total_cols))
+                    })
             }
-            CellData::Ref { cell1 } | CellData::SleepR { cell1 } | CellData::RoC { cell1, .. } => {
-                let (ri, ci) = to_indices(cell1.as_str());
-                if ri >= total_dims.0 || ci >= total_dims.1 {
-                    unsafe {
-                        STATUS_CODE = 1;
-                    }
-                    return;
-                }
+            CellData::RoR { cell1, cell2, .. } => {
+                resolve(cell1, total_rows, total_cols).and(resolve(cell2, total_rows, total_cols))
             }
-            CellData::CoR { cell2, .. } => {
-                let (ri, ci) = to_indices(cell2.as_str());
-                if ri >= total_dims.0 || ci >= total_dims.1 {
-                    unsafe {
-                        STATUS_CODE = 1;
-                    }
-                    return;
-                }
+            CellData::Ref { cell1 } | CellData::SleepR { cell1 } | CellData::RoC { cell1, .. } => {
+                resolve(cell1, total_rows, total_cols)
             }
-            CellData::RoR { cell1, cell2, .. } => {
-                for name in &[cell1, cell2] {
-                    let (ri, ci) = to_indices(name.as_str());
-                    if ri >= total_dims.0 || ci >= total_dims.1 {
-                        unsafe {
-                            STATUS_CODE = 1;
-                        }
-                        return;
-                    }
-                }
+            CellData::CoR { cell2, .. } => resolve(cell2, total_rows, total_cols),
+            CellData::Expr(expr) => expr
+                .operands()
+                .into_iter()
+                .filter_map(Operand::corners)
+                .try_fold((0, 0), |_, (cell1, cell2)| {
+                    resolve(&cell1, total_rows, total_cols)
+                        .and(resolve(&cell2, total_rows, total_cols))
+                }),
+            CellData::Lookup(lookup) => {
+                lookup
+                    .corners()
+                    .into_iter()
+                    .try_fold((0, 0), |_, (cell1, cell2)| {
+                        resolve(&cell1, total_rows, total_cols)
+                            .and(resolve(&cell2, total_rows, total_cols))
+                    })
             }
-            _ => {}
+            CellData::Func { args, .. } => args
+                .iter()
+                .filter_map(Operand::corners)
+                .try_fold((0, 0), |_, (cell1, _)| {
+                    resolve(&cell1, total_rows, total_cols)
+                }),
+            _ => Ok((0, 0)),
+        };
+        // Restore the old formula, so that no unread formula or reference outside the sheet is
+        // ever stored
+        if let Err(status) = refs_valid {
+            sheet.insert(cell_key, backup.clone());
+            return Err(status);
         }
     }
-    if unsafe { STATUS_CODE } != 0 {
-        return;
-    }
-
-    let cell_key = (r * total_dims.1 + c) as u32;
 
     // 2) REMOVE old dependency edges
     macro_rules! remove_dep {
         ($ri:expr, $ci:expr) => {{
-            let idx = ($ri * total_dims.1 + $ci) as u32;
-            if let Some(dep) = sheet.get_mut(&idx) {
-                dep.dependents.remove(&cell_key);
+            let idx = ($ri * total_dims.1 + $ci) as CellKey;
+            if let Some(dep) = sheet.get_mut(&idx)
+                && dep.dependents.remove(&cell_key)
+            {
+                trace!(
+                    "edge {} -> {} removed",
+                    key_name(idx, total_dims.1),
+                    key_name(cell_key, total_dims.1)
+                );
             }
         }};
     }
+    if let Some(ranges) = ranged.get(&cell_key) {
+        for &(start, end) in ranges {
+            trace!(
+                "range {}:{} -> {} removed",
+                key_name(start, total_dims.1),
+                key_name(end, total_dims.1),
+                key_name(cell_key, total_dims.1)
+            );
+        }
+    }
     match &backup.data {
-        CellData::Range { cell1, cell2, .. } => {
-            let (sr, sc) = to_indices(cell1.as_str());
-            let (er, ec) = to_indices(cell2.as_str());
+        CellData::Range { .. } => {
             // remove old mapping
             ranged.remove(&cell_key);
             // clear each child’s ranged flag only if not in any other range
-            for rr in sr..=er {
-                for cc in sc..=ec {
-                    let idx = (rr * total_dims.1 + cc) as u32;
-                    let still_covered = ranged.iter().any(|(_, ranges)| {
-                        ranges
-                            .iter()
-                            .any(|&(s, e)| in_range(idx, s, e, total_dims.1))
-                    });
-                    is_r[idx as usize] = still_covered;
+            let index = RangeIndex::build(ranged, total_dims.1);
+            for (cell1, cell2) in backup.data.range_areas() {
+                let (sr, sc) = cell1.indices();
+                let (er, ec) = cell2.indices();
+                for rr in sr..=er {
+                    for cc in sc..=ec {
+                        let idx = (rr * total_dims.1 + cc) as CellKey;
+                        is_r[idx as usize] = !index.parents(idx).is_empty();
+                    }
                 }
             }
         }
         CellData::Ref { cell1 } => {
-            let (ri, ci) = to_indices(cell1.as_str());
+            let (ri, ci) = cell1.indices();
             remove_dep!(ri, ci);
         }
         CellData::CoR { cell2, .. } => {
-            let (ri, ci) = to_indices(cell2.as_str());
+            let (ri, ci) = cell2.indices();
             remove_dep!(ri, ci);
         }
         CellData::RoC { cell1, .. } => {
-            let (ri, ci) = to_indices(cell1.as_str());
+            let (ri, ci) = cell1.indices();
             remove_dep!(ri, ci);
         }
         CellData::RoR { cell1, cell2, .. } => {
-            let (r1, c1) = to_indices(cell1.as_str());
+            let (r1, c1) = cell1.indices();
             remove_dep!(r1, c1);
-            let (r2, c2) = to_indices(cell2.as_str());
+            let (r2, c2) = cell2.indices();
             remove_dep!(r2, c2);
         }
         CellData::SleepR { cell1 } => {
-            let (ri, ci) = to_indices(cell1.as_str());
+            let (ri, ci) = cell1.indices();
             remove_dep!(ri, ci);
         }
-        _ => {}
-    }
-
-    // 3) ADD new edges
-    let new_data = sheet
-        .get(&cell_key)
-        .map(|c| c.data.clone())
-        .unwrap_or(CellData::Empty);
-    match &new_data {
-        CellData::Range { cell1, cell2, .. } => {
-            let (sr, sc) = to_indices(cell1.as_str());
-            let (er, ec) = to_indices(cell2.as_str());
-            ranged.entry(cell_key).or_default().push((
-                (sr * total_dims.1 + sc) as u32,
-                (er * total_dims.1 + ec) as u32,
-            ));
-            for rr in sr..=er {
-                for cc in sc..=ec {
-                    let idx = (rr * total_dims.1 + cc) as u32;
-                    is_r[idx as usize] = true;
+        CellData::Expr(expr) => {
+            ranged.remove(&cell_key);
+            let index = RangeIndex::build(ranged, total_dims.1);
+            for operand in expr.operands() {
+                match operand {
+                    Operand::Ref(cell1) => {
+                        let (ri, ci) = cell1.indices();
+                        remove_dep!(ri, ci);
+                    }
+                    Operand::Range { cell1, cell2, .. } => {
+                        let ((sr, sc), (er, ec)) = (cell1.indices(), cell2.indices());
+                        for rr in sr..=er {
+                            for cc in sc..=ec {
+                                let idx = (rr * total_dims.1 + cc) as CellKey;
+                                is_r[idx as usize] = !index.parents(idx).is_empty();
+                            }
+                        }
+                    }
+                    Operand::Const(_) => {}
                 }
             }
         }
-        CellData::Ref { cell1 } => {
-            let (ri, ci) = to_indices(cell1.as_str());
-            let idx = (ri * total_dims.1 + ci) as u32;
-            sheet
-                .entry(idx)
-                .or_insert_with(|| Cell {
-                    value: Valtype::Int(0),
-                    data: CellData::Empty,
-                    dependents: HashSet::new(),
-                })
-                .dependents
-                .insert(cell_key);
-        }
-        CellData::CoR { cell2, .. } => {
-            let (ri, ci) = to_indices(cell2.as_str());
-            let idx = (ri * total_dims.1 + ci) as u32;
-            sheet
-                .entry(idx)
-                .or_insert_with(|| Cell {
-                    value: Valtype::Int(0),
-                    data: CellData::Empty,
-                    dependents: HashSet::new(),
-                })
-                .dependents
-                .insert(cell_key);
-        }
-        CellData::RoC { cell1, .. } => {
-            let (ri, ci) = to_indices(cell1.as_str());
-            let idx = (ri * total_dims.1 + ci) as u32;
-            sheet
-                .entry(idx)
-                .or_insert_with(|| Cell {
-                    value: Valtype::Int(0),
-                    data: CellData::Empty,
-                    dependents: HashSet::new(),
-                })
-                .dependents
-                .insert(cell_key);
-        }
-        CellData::RoR { cell1, cell2, .. } => {
-            for name in &[cell1, cell2] {
-                let (ri, ci) = to_indices(name.as_str());
-                let idx = (ri * total_dims.1 + ci) as u32;
-                sheet
-                    .entry(idx)
-                    .or_insert_with(|| Cell {
-                        value: Valtype::Int(0),
-                        data: CellData::Empty,
-                        dependents: HashSet::new(),
-                    })
-                    .dependents
-                    .insert(cell_key);
+        CellData::Lookup(lookup) => {
+            if let Operand::Ref(cell1) = &lookup.key {
+                let (ri, ci) = cell1.indices();
+                remove_dep!(ri, ci);
+            }
+            ranged.remove(&cell_key);
+            let index = RangeIndex::build(ranged, total_dims.1);
+            let ((sr, sc), (er, ec)) = (lookup.cell1.indices(), lookup.cell2.indices());
+            for rr in sr..=er {
+                for cc in sc..=ec {
+                    let idx = (rr * total_dims.1 + cc) as CellKey;
+                    is_r[idx as usize] = !index.parents(idx).is_empty();
+                }
             }
         }
-        CellData::SleepR { cell1 } => {
-            let (ri, ci) = to_indices(cell1.as_str());
-            let idx = (ri * total_dims.1 + ci) as u32;
-            sheet
-                .entry(idx)
-                .or_insert_with(|| Cell {
-                    value: Valtype::Int(0),
-                    data: CellData::Empty,
-                    dependents: HashSet::new(),
-                })
-                .dependents
-                .insert(cell_key);
+        CellData::Func { args, .. } => {
+            for arg in args {
+                if let Operand::Ref(cell1) = arg {
+                    let (ri, ci) = cell1.indices();
+                    remove_dep!(ri, ci);
+                }
+            }
         }
         _ => {}
     }
 
+    // 3) ADD new edges
+    let new_data = sheet
+        .get(&cell_key)
+        .map(|c| c.data.clone())
+        .unwrap_or(CellData::Empty);
+    add_edges(sheet, ranged, is_r, total_dims.1, cell_key, &new_data);
+    Ok(())
+}
+
+/// Puts back a cell's previous state after `relink`, restoring the edges of its old formula.
+///
+/// # Arguments
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_r` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `cell_key` - The key of the cell to roll back.
+/// * `backup` - The cell’s previous state.
+fn unlink<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_r: &mut [bool],
+    total_dims: (usize, usize),
+    cell_key: CellKey,
+    backup: Cell,
+) {
+    let current = sheet.insert(cell_key, backup).unwrap_or(Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    });
+    // The old formula was valid before, so relinking it cannot fail
+    let _ = relink(sheet, ranged, is_r, total_dims, cell_key, &current);
+}
+
+/// The cells a recalculation evaluates, grouped into levels whose cells do not read each
+/// other.
+///
+/// # Fields
+/// * `affected` - The (row, col) of the changed cells, first, then of every cell depending on
+///   them.
+/// * `levels` - Indices into `affected`, level by level in dependency order.
+/// * `n_sources` - The number of changed cells at the start of `affected`.
+struct RecalcPlan {
+    affected: Vec<(usize, usize)>,
+    levels: Vec<Vec<usize>>,
+    n_sources: usize,
+}
+
+/// Finds the cells depending on the given cells, and the order to evaluate them in.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `sources` - The keys of the changed cells, whose edges are already up to date.
+///
+/// # Returns
+/// * `Option<RecalcPlan>` - The cells to evaluate, or `None` if the changed cells are part of
+///   a cycle.
+fn plan_recalc<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_dims: (usize, usize),
+    sources: &[CellKey],
+) -> Option<RecalcPlan> {
+    type Coord = (usize, usize);
+
     // 4) BUILD affected-list via BFS
+    let index = RangeIndex::build(ranged, total_dims.1);
     let mut affected = Vec::<Coord>::new();
-    let mut index_map = HashMap::<u32, usize>::new();
+    let mut index_map = HashMap::<CellKey, usize>::new();
     let mut queue = VecDeque::<Coord>::new();
 
-    affected.push((r, c));
-    index_map.insert(cell_key, 0);
-    queue.push_back((r, c));
+    for &cell_key in sources {
+        if let std::collections::hash_map::Entry::Vacant(e) = index_map.entry(cell_key) {
+            let (r, c) = (
+                cell_key as usize / total_dims.1,
+                cell_key as usize % total_dims.1,
+            );
+            e.insert(affected.len());
+            affected.push((r, c));
+            queue.push_back((r, c));
+        }
+    }
+    let n_sources = affected.len();
 
     while let Some((rr, cc)) = queue.pop_front() {
-        let idx = (rr * total_dims.1 + cc) as u32;
+        let idx = (rr * total_dims.1 + cc) as CellKey;
         // direct dependents
         if let Some(cell) = sheet.get(&idx) {
             for &dep_key in &cell.dependents {
@@ -602,25 +1015,23 @@ pub fn update_and_recalc(
             }
         }
         // range-based dependents without is_r check
-        for (&parent, ranges) in ranged.iter() {
-            for &(start, end) in ranges.iter() {
-                if in_range(idx, start, end, total_dims.1) && !index_map.contains_key(&parent) {
-                    let pr = (parent as usize) / total_dims.1;
-                    let pc = (parent as usize) % total_dims.1;
-                    let ni = affected.len();
-                    index_map.insert(parent, ni);
-                    affected.push((pr, pc));
-                    queue.push_back((pr, pc));
-                }
+        for parent in index.parents(idx) {
+            if let std::collections::hash_map::Entry::Vacant(e) = index_map.entry(parent) {
+                let pr = (parent as usize) / total_dims.1;
+                let pc = (parent as usize) % total_dims.1;
+                let ni = affected.len();
+                e.insert(ni);
+                affected.push((pr, pc));
+                queue.push_back((pr, pc));
             }
         }
     }
 
-    // 5) TOPOLOGICAL ORDER & EVAL
+    // 5) TOPOLOGICAL ORDER
     let n = affected.len();
     let mut in_degree = vec![0; n];
     for &(rr, cc) in &affected {
-        let idx = (rr * total_dims.1 + cc) as u32;
+        let idx = (rr * total_dims.1 + cc) as CellKey;
         if let Some(cell) = sheet.get(&idx) {
             for &dep_key in &cell.dependents {
                 if let Some(&j) = index_map.get(&dep_key) {
@@ -628,119 +1039,1078 @@ pub fn update_and_recalc(
                 }
             }
         }
-        for (&parent, ranges) in ranged.iter() {
-            for &(start, end) in ranges.iter() {
-                if in_range(idx, start, end, total_dims.1) {
-                    if let Some(&j) = index_map.get(&parent) {
-                        in_degree[j] += 1;
-                    }
-                }
+        for parent in index.parents(idx) {
+            if let Some(&j) = index_map.get(&parent) {
+                in_degree[j] += 1;
             }
         }
     }
 
-    // Cycle detection
-    if in_degree[0] > 0 {
-        // Remove newly added dependency edges
-        let new_data = sheet
-            .get(&cell_key)
-            .map(|c| c.data.clone())
-            .unwrap_or(CellData::Empty);
-        match &new_data {
-            CellData::Range { cell1, cell2, .. } => {
-                let (sr, sc) = to_indices(cell1.as_str());
-                let (er, ec) = to_indices(cell2.as_str());
-                for rr in sr..=er {
-                    for cc in sc..=ec {
-                        let idx = (rr * total_dims.1 + cc) as u32;
-                        is_r[idx as usize] = false;
-                    }
+    // Kahn’s algorithm; the whole order is found before evaluating, so that a cycle changes
+    // no value. Each cell's level is one more than that of the deepest cell it reads, so the
+    // cells of one level never read each other.
+    let mut order = Vec::with_capacity(n);
+    let mut level = vec![0; n];
+    let mut zero_q: Vec<usize> = in_degree
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &d)| if d == 0 { Some(i) } else { None })
+        .collect();
+    while let Some(idx0) = zero_q.pop() {
+        order.push(idx0);
+        let (rr, cc) = affected[idx0];
+        let key = (rr * total_dims.1 + cc) as CellKey;
+        let dependents = sheet.get(&key).into_iter().flat_map(|c| &c.dependents);
+        for dep_key in dependents.copied().chain(index.parents(key)) {
+            if let Some(&j) = index_map.get(&dep_key) {
+                level[j] = level[j].max(level[idx0] + 1);
+                in_degree[j] -= 1;
+                if in_degree[j] == 0 {
+                    zero_q.push(j);
                 }
-                ranged.remove(&cell_key);
             }
-            CellData::Ref { cell1 } => {
-                let (ri, ci) = to_indices(cell1.as_str());
-                let idx = (ri * total_dims.1 + ci) as u32;
-                if let Some(dep) = sheet.get_mut(&idx) {
-                    dep.dependents.remove(&cell_key);
-                }
+        }
+    }
+    // Cycle detection
+    if order.len() < n {
+        debug!(
+            "{} of {} cells are in or behind a cycle; nothing recalculated",
+            n - order.len(),
+            n
+        );
+        return None;
+    }
+
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+    for idx0 in order {
+        if levels.len() <= level[idx0] {
+            levels.resize_with(level[idx0] + 1, Vec::new);
+        }
+        levels[level[idx0]].push(idx0);
+    }
+    debug!(
+        "recalculating {} cells from {} changed, in {} levels",
+        n,
+        n_sources,
+        levels.len()
+    );
+    Some(RecalcPlan {
+        affected,
+        levels,
+        n_sources,
+    })
+}
+
+/// Records in the context installed on the thread that a sheet may hold volatile formulas,
+/// e.g. after loading cells that were not parsed with `detect_formula`.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+pub fn note_volatile<S: SheetStorage + ?Sized>(sheet: &S) {
+    if sheet.cells().any(|(_, cell)| cell.data.is_volatile()) {
+        EvalContext::current().note_volatile();
+    }
+}
+
+/// Lists the cells holding a volatile formula, which every recalculation redraws.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `context` - The context of the session the sheet belongs to; a sheet whose context has
+///   seen no volatile formula is not searched.
+///
+/// # Returns
+/// * `Vec<CellKey>` - The keys of the cells whose formula `CellData::is_volatile`, in no
+///   particular order.
+pub fn volatile_cells<S: SheetStorage + ?Sized>(sheet: &S, context: &EvalContext) -> Vec<CellKey> {
+    if !context.volatile_seen() {
+        return Vec::new();
+    }
+    sheet
+        .cells()
+        .filter(|(_, cell)| cell.data.is_volatile())
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Sets the seed of `RAND` and `RANDBETWEEN` in the context installed on the thread, and
+/// redraws every volatile cell from it, as the `seed` command does.
+///
+/// # Arguments
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `seed` - The new seed.
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "RANDBETWEEN(1,6)")?;
+/// // A1 = RANDBETWEEN(1,6)
+/// reseed(&mut sheet, &ranged, (3, 3), 42);
+/// let first = sheet[&0].value.clone();
+/// reseed(&mut sheet, &ranged, (3, 3), 42);
+/// assert_eq!(sheet[&0].value, first);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn reseed<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_dims: (usize, usize),
+    seed: u64,
+) -> RecalcResult {
+    EvalContext::current().set_seed(seed);
+    recalc_from(sheet, ranged, total_dims, &[], None, None)
+        .unwrap_or_else(|| RecalcResult::rejected(EvalStatus::CycleDetected))
+}
+
+/// Recalculates every formula of the sheet in dependency order, as the `recalc all` command
+/// does, e.g. after cells were loaded with values that may be out of date.
+///
+/// # Arguments
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed, and the status of the first cell that
+///   could not be evaluated, or `EvalStatus::CycleDetected` with nothing evaluated if the
+///   sheet holds a cycle.
+pub fn recalc_all<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_dims: (usize, usize),
+) -> RecalcResult {
+    let mut formulas: Vec<CellKey> = sheet
+        .cells()
+        .filter(|(_, cell)| cell.data != CellData::Empty)
+        .map(|(key, _)| key)
+        .collect();
+    // Sorted so the status is that of the first failing cell in row-major order
+    formulas.sort_unstable();
+    recalc_from(sheet, ranged, total_dims, &formulas, None, None)
+        .unwrap_or_else(|| RecalcResult::rejected(EvalStatus::CycleDetected))
+}
+
+/// Recalculates the given cells and every cell depending on them, in dependency order.
+///
+/// Volatile cells are recalculated too, after the given cells, with everything depending on
+/// them, so that each recalculation sleeps again and draws new random values.
+///
+/// # Arguments
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `sources` - The keys of the changed cells, whose edges are already up to date.
+/// * `cancel` - The token that stops the recalculation, if it may be cancelled.
+/// * `cache` - The range cache of the sheet, with the key and previous value of the single
+///   source, whose value may already have changed. Without it, a cache installed on the
+///   thread is emptied, as the previous values of the sources are not known.
+///
+/// # Returns
+/// * `Option<RecalcResult>` - `None`, with nothing evaluated, if the changed cells are part of
+///   a cycle; otherwise the cells whose value changed and the status of the first source that
+///   could not be evaluated, or `Ok(())`. A cancelled recalculation puts every value back and
+///   reports `EvalStatus::Cancelled`.
+fn recalc_from<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_dims: (usize, usize),
+    sources: &[CellKey],
+    cancel: Option<&CancelToken>,
+    cache: Option<(&mut RangeCache, CellKey, &Valtype)>,
+) -> Option<RecalcResult> {
+    let (mut cache, mut before) = match cache {
+        Some((cache, key, value)) => (Some(cache), HashMap::from([(key, value.clone())])),
+        None => {
+            if let Some(mut installed) = RangeCache::take_installed() {
+                installed.clear();
+                installed.reinstall();
             }
-            CellData::CoR { cell2, .. } => {
-                let (ri, ci) = to_indices(cell2.as_str());
-                let idx = (ri * total_dims.1 + ci) as u32;
-                if let Some(dep) = sheet.get_mut(&idx) {
-                    dep.dependents.remove(&cell_key);
+            (None, HashMap::new())
+        }
+    };
+    let context = EvalContext::current();
+    let mut sources = sources.to_vec();
+    let volatile = volatile_cells(sheet, &context);
+    // A `SLEEP` waits again but draws nothing, so only random cells start a new draw
+    let draws = volatile
+        .iter()
+        .filter_map(|key| sheet.get(key))
+        .any(|cell| matches!(cell.data, CellData::Func { .. }));
+    if draws {
+        context.next_draw();
+    }
+    sources.extend(volatile);
+    let RecalcPlan {
+        affected,
+        levels,
+        n_sources,
+    } = plan_recalc(sheet, ranged, total_dims, &sources)?;
+
+    // 6) EVAL level by level; the status is that of the first failing source
+    let mut status = Ok(());
+    let mut changed = Vec::new();
+    let mut previous = Vec::new();
+    for cells in levels {
+        // Range formulas with kept totals are computed here, and the other cells by eval_level
+        let mut from_cache = HashMap::new();
+        if let Some(cache) = cache.as_deref_mut() {
+            for &idx0 in &cells {
+                let (rr, cc) = affected[idx0];
+                let key = (rr * total_dims.1 + cc) as CellKey;
+                if let Some(result) = cached_range_value(cache, sheet, total_dims, key, &before) {
+                    let evaluated = match result {
+                        Ok(v) => (idx0, key, Valtype::Int(v), Ok(())),
+                        Err(EvalStatus::ErrValue(error)) => {
+                            (idx0, key, Valtype::Err(error), Ok(()))
+                        }
+                        Err(e) => (idx0, key, Valtype::Err(e.into()), Err(e)),
+                    };
+                    from_cache.insert(idx0, evaluated);
                 }
             }
-            CellData::RoC { cell1, .. } => {
-                let (ri, ci) = to_indices(cell1.as_str());
-                let idx = (ri * total_dims.1 + ci) as u32;
-                if let Some(dep) = sheet.get_mut(&idx) {
-                    dep.dependents.remove(&cell_key);
-                }
+        }
+        let rest: Vec<usize> = cells
+            .iter()
+            .copied()
+            .filter(|idx0| !from_cache.contains_key(idx0))
+            .collect();
+        let mut rest = eval_level(sheet, total_dims, &affected, &rest, cancel, &context)
+            .into_iter()
+            .peekable();
+        // Merged back in the order of the level, so the status is that of the same source
+        let evaluated: Vec<Evaluated> = cells
+            .iter()
+            .filter_map(|idx0| {
+                from_cache
+                    .remove(idx0)
+                    .or_else(|| rest.next_if(|evaluated| evaluated.0 == *idx0))
+            })
+            .collect();
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            // Put back every value changed so far, latest first
+            for (key, value) in previous.into_iter().rev() {
+                sheet.get_mut(&key).unwrap().value = value;
             }
-            CellData::RoR { cell1, cell2, .. } => {
-                for name in &[cell1, cell2] {
-                    let (ri, ci) = to_indices(name.as_str());
-                    let idx = (ri * total_dims.1 + ci) as u32;
-                    if let Some(dep) = sheet.get_mut(&idx) {
-                        dep.dependents.remove(&cell_key);
-                    }
-                }
+            return Some(RecalcResult::rejected(EvalStatus::Cancelled));
+        }
+        for (idx0, key, val, result) in evaluated {
+            if idx0 < n_sources && status.is_ok() {
+                status = result;
             }
-            CellData::SleepR { cell1 } => {
-                let (ri, ci) = to_indices(cell1.as_str());
-                let idx = (ri * total_dims.1 + ci) as u32;
-                if let Some(dep) = sheet.get_mut(&idx) {
-                    dep.dependents.remove(&cell_key);
+            let cell = sheet.get_mut(&key).unwrap();
+            if cell.value != val {
+                changed.push(key);
+                let value = std::mem::replace(&mut cell.value, val);
+                if cache.is_some() {
+                    before.entry(key).or_insert_with(|| value.clone());
+                }
+                if cancel.is_some() {
+                    previous.push((key, value));
                 }
             }
-            _ => {}
         }
+    }
+    Some(RecalcResult { changed, status })
+}
 
-        // Roll back the cell
-        *sheet.get_mut(&cell_key).unwrap() = backup;
-        unsafe {
-            STATUS_CODE = 3;
+/// The fewest cells in one level of a recalculation that are evaluated on several threads.
+/// Smaller levels are evaluated in turn, since starting the threads would cost more.
+#[cfg(feature = "parallel")]
+pub const PARALLEL_MIN_CELLS: usize = 256;
+
+/// A cell evaluated during a recalculation: its index into the affected cells, its key, its
+/// new value and whether it could be evaluated.
+type Evaluated = (usize, CellKey, Valtype, Result<(), EvalStatus>);
+
+/// Evaluates cells that do not read each other, on several threads when there are at least
+/// `PARALLEL_MIN_CELLS` of them and the `parallel` feature is enabled.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `affected` - The (row, col) of every cell being recalculated.
+/// * `cells` - The indices into `affected` of the cells to evaluate.
+/// * `cancel` - The token of the recalculation; once cancelled, no further cell is evaluated.
+/// * `context` - The context of the session the sheet belongs to.
+///
+/// # Returns
+/// * `Vec<Evaluated>` - The non-empty cells evaluated, in the order of `cells`.
+fn eval_level<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_dims: (usize, usize),
+    affected: &[(usize, usize)],
+    cells: &[usize],
+    cancel: Option<&CancelToken>,
+    context: &EvalContext,
+) -> Vec<Evaluated> {
+    let evaluate = |&idx0: &usize| {
+        let (rr, cc) = affected[idx0];
+        let key = (rr * total_dims.1 + cc) as CellKey;
+        let cell = sheet.get(&key)?;
+        if cell.data == CellData::Empty || cancel.is_some_and(CancelToken::is_cancelled) {
+            return None;
         }
-        return;
+        Some(
+            match eval_with(sheet, total_dims.0, total_dims.1, rr, cc, cancel, context) {
+                Ok(val) => (idx0, key, val, Ok(())),
+                // The cell shows why it could not be evaluated, e.g. #NAME? for an unknown function
+                Err(e) => (idx0, key, Valtype::Err(e.into()), Err(e)),
+            },
+        )
+    };
+    #[cfg(feature = "parallel")]
+    if cells.len() >= PARALLEL_MIN_CELLS {
+        use rayon::prelude::*;
+        return cells.par_iter().filter_map(evaluate).collect();
     }
+    cells.iter().filter_map(evaluate).collect()
+}
 
-    // 6) Kahn’s algorithm
-    let mut zero_q: Vec<usize> = in_degree
-        .iter()
-        .enumerate()
-        .filter_map(|(i, &d)| if d == 0 { Some(i) } else { None })
-        .collect();
-    while let Some(idx0) = zero_q.pop() {
-        let (rr, cc) = affected[idx0];
-        let key = (rr * total_dims.1 + cc) as u32;
-        if let Some(cell) = sheet.get(&key) {
-            if cell.data != CellData::Empty {
-                let val = eval(sheet, total_dims.0, total_dims.1, rr, cc);
-                sheet.get_mut(&key).unwrap().value = val;
+/// Finds the loop of cells that made a formula rejected with `EvalStatus::CycleDetected`.
+///
+/// The sheet is the one left after the rejection, so the loop runs from the cell through the
+/// cells computed from it to one of the cells the rejected formula reads.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `r` - The row index of the cell the formula was assigned to.
+/// * `c` - The column index of the cell the formula was assigned to.
+/// * `formula` - The rejected formula text.
+///
+/// # Returns
+/// * `Option<Vec<CellKey>>` - The keys of the cells in the loop, each feeding the next and starting
+///   and ending with the cell, or `None` if the formula would not create a cycle.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "A1+1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "B1*2")?;
+/// // B1=A1+1, C1=B1*2; assigning A1=C1 is rejected
+/// let path = cycle_path(&sheet, &ranged, (10, 10), 0, 0, "C1").unwrap();
+/// assert_eq!(format_cell_path(&path, 10), "A1 -> B1 -> C1 -> A1");
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn cycle_path<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_dims: (usize, usize),
+    r: usize,
+    c: usize,
+    formula: &str,
+) -> Option<Vec<CellKey>> {
+    let total_cols = total_dims.1;
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    detect_formula(&mut cell, formula);
+    let key_of = |cell: &CellRef| {
+        let (row, col) = cell.indices();
+        (row * total_cols + col) as CellKey
+    };
+    // The cells read by the rejected formula, as spans of a single cell or a range
+    let inputs: Vec<(CellKey, CellKey)> = match &cell.data {
+        CellData::Ref { cell1 } | CellData::SleepR { cell1 } | CellData::RoC { cell1, .. } => {
+            vec![(key_of(cell1), key_of(cell1))]
+        }
+        CellData::CoR { cell2, .. } => vec![(key_of(cell2), key_of(cell2))],
+        CellData::RoR { cell1, cell2, .. } => vec![
+            (key_of(cell1), key_of(cell1)),
+            (key_of(cell2), key_of(cell2)),
+        ],
+        CellData::Range { .. } => cell
+            .data
+            .range_areas()
+            .iter()
+            .map(|(cell1, cell2)| (key_of(cell1), key_of(cell2)))
+            .collect(),
+        CellData::Expr(expr) => expr
+            .operands()
+            .into_iter()
+            .filter_map(Operand::corners)
+            .map(|(cell1, cell2)| (key_of(&cell1), key_of(&cell2)))
+            .collect(),
+        CellData::Lookup(lookup) => lookup
+            .corners()
+            .iter()
+            .map(|(cell1, cell2)| (key_of(cell1), key_of(cell2)))
+            .collect(),
+        CellData::Func { args, .. } => args
+            .iter()
+            .filter_map(Operand::corners)
+            .map(|(cell1, cell2)| (key_of(&cell1), key_of(&cell2)))
+            .collect(),
+        _ => return None,
+    };
+
+    // Breadth-first search along dependents, so that the shortest loop is reported
+    let start = (r * total_cols + c) as CellKey;
+    let index = RangeIndex::build(ranged, total_cols);
+    let mut came_from = HashMap::from([(start, start)]);
+    let mut queue = VecDeque::from([start]);
+    while let Some(key) = queue.pop_front() {
+        if inputs
+            .iter()
+            .any(|&(first, last)| in_range(key, first, last, total_cols))
+        {
+            let mut path = vec![start, key];
+            let mut step = key;
+            while step != start {
+                step = came_from[&step];
+                path.push(step);
             }
-            for &dep_key in &sheet.get(&key).unwrap().dependents {
-                if let Some(&j) = index_map.get(&dep_key) {
-                    in_degree[j] -= 1;
-                    if in_degree[j] == 0 {
-                        zero_q.push(j);
+            // The path was collected backwards, from the start back to it
+            path.reverse();
+            return Some(path);
+        }
+        let dependents = sheet.get(&key).into_iter().flat_map(|c| &c.dependents);
+        for dep_key in dependents.copied().chain(index.parents(key)) {
+            if let std::collections::hash_map::Entry::Vacant(e) = came_from.entry(dep_key) {
+                e.insert(key);
+                queue.push_back(dep_key);
+            }
+        }
+    }
+    None
+}
+
+/// Explains why assigning a formula to a cell failed, naming the cell or reference at fault,
+/// for the line the command line prints on stderr and the GUI shows in its status bar.
+///
+/// # Arguments
+/// * `status` - The status the assignment failed with.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `r` - The row index of the cell the formula was assigned to.
+/// * `c` - The column index of the cell the formula was assigned to.
+/// * `formula` - The formula text, as assigned.
+///
+/// # Returns
+/// * `Option<String>` - The explanation, or `None` if the status says all there is to say. An
+///   `EvalStatus::InvalidRange` names the first reference outside the sheet, or the cell itself,
+///   and an `EvalStatus::UnrecognizedCmd` says where the formula cannot be read, or else
+///   repeats the assignment.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// let detail = status_detail(EvalStatus::InvalidRange, (5, 5), 2, 0, "A1/E6");
+/// assert_eq!(detail.as_deref(), Some("E6 out of bounds in A3=A1/E6"));
+/// ```
+pub fn status_detail(
+    status: EvalStatus,
+    total_dims: (usize, usize),
+    r: usize,
+    c: usize,
+    formula: &str,
+) -> Option<String> {
+    let (total_rows, total_cols) = total_dims;
+    let cell = to_cell_name(r, c);
+    match status {
+        EvalStatus::InvalidRange if r >= total_rows || c >= total_cols => {
+            Some(format!("{} out of bounds", cell))
+        }
+        EvalStatus::InvalidRange => {
+            let outside = RE_REFERENCE.find_iter(formula).find(|reference| {
+                !to_indices(reference.as_str())
+                    .is_ok_and(|(row, col)| row < total_rows && col < total_cols)
+            })?;
+            Some(format!(
+                "{} out of bounds in {}={}",
+                outside.as_str(),
+                cell,
+                formula
+            ))
+        }
+        EvalStatus::UnrecognizedCmd => match crate::syntax::read_formula(formula.trim()) {
+            Err(error) => Some(format!("{} in {}={}", error, cell, formula)),
+            Ok(_) => Some(format!("{}={}", cell, formula)),
+        },
+        _ => None,
+    }
+}
+
+/// Returns the name of the cell at a key, for log messages.
+fn key_name(key: CellKey, total_cols: usize) -> String {
+    let key = key as usize;
+    to_cell_name(key / total_cols, key % total_cols)
+}
+
+/// Registers the dependency edges implied by a cell's formula.
+///
+/// Single-cell references add `cell_key` to the referenced cell's dependents, while range
+/// formulas and range operands are recorded in `ranged` and flag their cells in `is_r`.
+///
+/// # Arguments
+/// * `sheet` - A mutable hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_r` - A boolean array indicating whether each cell is part of a range.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+/// * `cell_key` - The key of the cell whose formula is `data`.
+/// * `data` - The formula to register edges for.
+fn add_edges<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_r: &mut [bool],
+    total_cols: usize,
+    cell_key: CellKey,
+    data: &CellData,
+) {
+    macro_rules! add_dep {
+        ($idx:expr) => {{
+            let idx = $idx;
+            if sheet.entry_or_empty(idx).dependents.insert(cell_key) {
+                trace!(
+                    "edge {} -> {} added",
+                    key_name(idx, total_cols),
+                    key_name(cell_key, total_cols)
+                );
+            }
+        }};
+    }
+    macro_rules! add_range {
+        ($sr:expr, $sc:expr, $er:expr, $ec:expr) => {{
+            let (start, end) = (
+                ($sr * total_cols + $sc) as CellKey,
+                ($er * total_cols + $ec) as CellKey,
+            );
+            ranged.entry(cell_key).or_default().push((start, end));
+            trace!(
+                "range {}:{} -> {} added",
+                key_name(start, total_cols),
+                key_name(end, total_cols),
+                key_name(cell_key, total_cols)
+            );
+        }};
+    }
+    match data {
+        CellData::Range { .. } => {
+            for (cell1, cell2) in data.range_areas() {
+                let (sr, sc) = cell1.indices();
+                let (er, ec) = cell2.indices();
+                add_range!(sr, sc, er, ec);
+                for rr in sr..=er {
+                    for cc in sc..=ec {
+                        let idx = (rr * total_cols + cc) as CellKey;
+                        is_r[idx as usize] = true;
                     }
                 }
             }
         }
-        // ranged parents
-        for (&parent, ranges) in ranged.iter() {
-            for &(start, end) in ranges.iter() {
-                if in_range(key, start, end, total_dims.1) {
-                    if let Some(&j) = index_map.get(&parent) {
-                        in_degree[j] -= 1;
-                        if in_degree[j] == 0 {
-                            zero_q.push(j);
+        CellData::Ref { cell1 } => {
+            let (ri, ci) = cell1.indices();
+            let idx = (ri * total_cols + ci) as CellKey;
+            add_dep!(idx);
+        }
+        CellData::CoR { cell2, .. } => {
+            let (ri, ci) = cell2.indices();
+            let idx = (ri * total_cols + ci) as CellKey;
+            add_dep!(idx);
+        }
+        CellData::RoC { cell1, .. } => {
+            let (ri, ci) = cell1.indices();
+            let idx = (ri * total_cols + ci) as CellKey;
+            add_dep!(idx);
+        }
+        CellData::RoR { cell1, cell2, .. } => {
+            for name in &[cell1, cell2] {
+                let (ri, ci) = name.indices();
+                let idx = (ri * total_cols + ci) as CellKey;
+                add_dep!(idx);
+            }
+        }
+        CellData::SleepR { cell1 } => {
+            let (ri, ci) = cell1.indices();
+            let idx = (ri * total_cols + ci) as CellKey;
+            add_dep!(idx);
+        }
+        CellData::Expr(expr) => {
+            for operand in expr.operands() {
+                match operand {
+                    Operand::Ref(cell1) => {
+                        let (ri, ci) = cell1.indices();
+                        let idx = (ri * total_cols + ci) as CellKey;
+                        add_dep!(idx);
+                    }
+                    Operand::Range { cell1, cell2, .. } => {
+                        let (sr, sc) = cell1.indices();
+                        let (er, ec) = cell2.indices();
+                        add_range!(sr, sc, er, ec);
+                        for rr in sr..=er {
+                            for cc in sc..=ec {
+                                is_r[rr * total_cols + cc] = true;
+                            }
                         }
                     }
+                    Operand::Const(_) => {}
+                }
+            }
+        }
+        CellData::Lookup(lookup) => {
+            if let Operand::Ref(cell1) = &lookup.key {
+                let (ri, ci) = cell1.indices();
+                let idx = (ri * total_cols + ci) as CellKey;
+                add_dep!(idx);
+            }
+            let (sr, sc) = lookup.cell1.indices();
+            let (er, ec) = lookup.cell2.indices();
+            add_range!(sr, sc, er, ec);
+            for rr in sr..=er {
+                for cc in sc..=ec {
+                    is_r[rr * total_cols + cc] = true;
+                }
+            }
+        }
+        CellData::Func { args, .. } => {
+            for arg in args {
+                if let Operand::Ref(cell1) = arg {
+                    let (ri, ci) = cell1.indices();
+                    let idx = (ri * total_cols + ci) as CellKey;
+                    add_dep!(idx);
                 }
             }
         }
+        _ => {}
+    }
+}
+
+/// Assigns a formula to a cell and recalculates everything that depends on it.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `row` - The row index of the cell to assign.
+/// * `col` - The column index of the cell to assign.
+/// * `formula` - The formula text (e.g., "B1+2").
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - The outcome of the assignment: the status from `update_and_recalc`.
+pub fn assign_formula<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    row: usize,
+    col: usize,
+    formula: &str,
+) -> Result<(), EvalStatus> {
+    assign_and_recalc(spreadsheet, ranged, is_range, total_dims, row, col, formula).status
+}
+
+/// Assigns a formula to a cell as `assign_formula` does, listing the cells whose value changed.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `row` - The row index of the cell to assign.
+/// * `col` - The column index of the cell to assign.
+/// * `formula` - The formula text (e.g., "B1+2").
+///
+/// # Returns
+/// * `RecalcResult` - The result of `update_and_recalc`.
+pub fn assign_and_recalc<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    row: usize,
+    col: usize,
+    formula: &str,
+) -> RecalcResult {
+    let idx = (row as CellKey) * (total_dims.1 as CellKey) + (col as CellKey);
+    let old_cell = spreadsheet.get(&idx).cloned().unwrap_or(Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    });
+    let mut new_cell = old_cell.clone();
+    detect_formula(&mut new_cell, formula);
+    spreadsheet.insert(idx, new_cell);
+    spreadsheet.reserve_on_grow();
+    update_and_recalc(
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        row,
+        col,
+        old_cell,
+    )
+}
+
+/// Assigns a formula to a cell without evaluating anything, for manual calculation.
+///
+/// The dependency edges are updated and checked for cycles as by `assign_formula`, but the
+/// cell keeps its old value, unless it is a constant, and so does every cell depending on it
+/// until the stale cells are passed to `recalc_stale`.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `row` - The row index of the cell to assign.
+/// * `col` - The column index of the cell to assign.
+/// * `formula` - The formula text (e.g., "B1+2").
+///
+/// # Returns
+/// * `Result<Vec<CellKey>, EvalStatus>` - The keys of the cell and of every cell depending on it,
+///   which are now stale, or the status explaining why the formula was rejected, in which case
+///   the cell is left as it was.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "A1+1")?;
+/// // B1 = A1+1
+/// let stale = assign_deferred(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "5")?;
+/// assert_eq!(stale, vec![0, 1]);
+/// recalc_stale(&mut sheet, &ranged, (3, 3), &stale).status?;
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn assign_deferred<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    row: usize,
+    col: usize,
+    formula: &str,
+) -> Result<Vec<CellKey>, EvalStatus> {
+    let idx = (row * total_dims.1 + col) as CellKey;
+    let old_cell = spreadsheet.get(&idx).cloned().unwrap_or(Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    });
+    let mut new_cell = old_cell.clone();
+    detect_formula(&mut new_cell, formula);
+    if new_cell.data != CellData::Const {
+        new_cell.value = old_cell.value.clone();
+    }
+    spreadsheet.insert(idx, new_cell);
+    spreadsheet.reserve_on_grow();
+    relink(spreadsheet, ranged, is_range, total_dims, idx, &old_cell)?;
+    match plan_recalc(spreadsheet, ranged, total_dims, &[idx]) {
+        Some(plan) => Ok(plan
+            .affected
+            .into_iter()
+            .map(|(r, c)| (r * total_dims.1 + c) as CellKey)
+            .collect()),
+        None => {
+            unlink(spreadsheet, ranged, is_range, total_dims, idx, old_cell);
+            Err(EvalStatus::CycleDetected)
+        }
+    }
+}
+
+/// Recalculates the cells left stale by `assign_deferred`, in a single pass in dependency
+/// order.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `stale` - The keys of the stale cells, in any order.
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed, and the status of the first stale cell
+///   that could not be evaluated, or `EvalStatus::CycleDetected` with nothing evaluated if the
+///   cells are part of a cycle.
+pub fn recalc_stale<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_dims: (usize, usize),
+    stale: &[CellKey],
+) -> RecalcResult {
+    recalc_from(spreadsheet, ranged, total_dims, stale, None, None)
+        .unwrap_or_else(|| RecalcResult::rejected(EvalStatus::CycleDetected))
+}
+
+/// Writes formulas to several cells and links their dependency edges, without evaluating
+/// anything, for the `assign_formulas` family.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `formulas` - The (row, col, formula) assignments.
+/// * `deferred` - Whether cells keep their old value unless they hold a constant, as in
+///   `assign_deferred`.
+///
+/// # Returns
+/// * `Result<Vec<(CellKey, Cell)>, EvalStatus>` - The key and previous state of each cell, in
+///   the order written, or the status explaining why a formula was rejected, in which case
+///   every cell is left as it was.
+fn link_formulas<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    formulas: &[(usize, usize, String)],
+    deferred: bool,
+) -> Result<Vec<(CellKey, Cell)>, EvalStatus> {
+    let mut backups: Vec<(CellKey, Cell)> = Vec::with_capacity(formulas.len());
+    for (row, col, formula) in formulas {
+        let idx = (row * total_dims.1 + col) as CellKey;
+        let old_cell = spreadsheet.get(&idx).cloned().unwrap_or(Cell {
+            value: Valtype::Int(0),
+            data: CellData::Empty,
+            dependents: HashSet::new(),
+        });
+        let mut new_cell = old_cell.clone();
+        detect_formula(&mut new_cell, formula);
+        if deferred && new_cell.data != CellData::Const {
+            new_cell.value = old_cell.value.clone();
+        }
+        spreadsheet.insert(idx, new_cell);
+        spreadsheet.reserve_on_grow();
+        // Edges are linked cell by cell, and values are only computed once all are in place
+        if let Err(rejected) = relink(spreadsheet, ranged, is_range, total_dims, idx, &old_cell) {
+            spreadsheet.insert(idx, old_cell);
+            unlink_formulas(spreadsheet, ranged, is_range, total_dims, backups);
+            return Err(rejected);
+        }
+        backups.push((idx, old_cell));
+    }
+    Ok(backups)
+}
+
+/// Puts back the cells written by `link_formulas`, with the edges of their old formulas.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `backups` - The key and previous state of each cell, in the order written.
+fn unlink_formulas<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    backups: Vec<(CellKey, Cell)>,
+) {
+    // Undo the assignments last first, so that each cell's edges are restored as they were
+    for (key, backup) in backups.into_iter().rev() {
+        unlink(spreadsheet, ranged, is_range, total_dims, key, backup);
+    }
+}
+
+/// Assigns formulas to several cells, then recalculates everything depending on them once.
+///
+/// The assignments are all or nothing: if a formula is rejected or the formulas would create a
+/// cycle, every cell keeps its previous contents.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `formulas` - The (row, col, formula) assignments, e.g. from `fill_formulas`.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Ok(())` if every cell was updated, the status explaining why
+///   the assignments were rejected, or the status of the first cell that could not be
+///   evaluated.
+pub fn assign_formulas<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    formulas: &[(usize, usize, String)],
+) -> Result<(), EvalStatus> {
+    let backups = link_formulas(spreadsheet, ranged, is_range, total_dims, formulas, false)?;
+    let keys: Vec<CellKey> = backups.iter().map(|&(key, _)| key).collect();
+    match recalc_from(spreadsheet, ranged, total_dims, &keys, None, None) {
+        Some(result) => result.status,
+        None => {
+            unlink_formulas(spreadsheet, ranged, is_range, total_dims, backups);
+            Err(EvalStatus::CycleDetected)
+        }
+    }
+}
+
+/// Assigns formulas to several cells as `assign_formulas` does, then lets a check accept or
+/// refuse the recalculated sheet.
+///
+/// Unlike `assign_formulas`, the cells are also restored if one of them could not be
+/// evaluated, so that nothing is written unless everything is.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `formulas` - The (row, col, formula) assignments, e.g. from `fill_formulas`.
+/// * `check` - Called once with the recalculated sheet, its dependency ranges and the keys of
+///   the assigned cells; an error restores every cell and the sheet is recalculated as it was.
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed, or the status explaining why nothing
+///   was written, in which case no cell is listed.
+pub fn assign_formulas_checked<S, F>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    formulas: &[(usize, usize, String)],
+    check: F,
+) -> RecalcResult
+where
+    S: SheetStorage + ?Sized,
+    F: FnOnce(&S, &HashMap<CellKey, Vec<(CellKey, CellKey)>>, &[CellKey]) -> Result<(), EvalStatus>,
+{
+    let backups = match link_formulas(spreadsheet, ranged, is_range, total_dims, formulas, false) {
+        Ok(backups) => backups,
+        Err(status) => return RecalcResult::rejected(status),
+    };
+    let keys: Vec<CellKey> = backups.iter().map(|&(key, _)| key).collect();
+    let Some(result) = recalc_from(spreadsheet, ranged, total_dims, &keys, None, None) else {
+        unlink_formulas(spreadsheet, ranged, is_range, total_dims, backups);
+        return RecalcResult::rejected(EvalStatus::CycleDetected);
+    };
+    let Err(status) = result
+        .status
+        .and_then(|()| check(spreadsheet, ranged, &keys))
+    else {
+        return result;
+    };
+    unlink_formulas(spreadsheet, ranged, is_range, total_dims, backups);
+    // The cells computed from the restored ones still hold the values of the new formulas
+    let _ = recalc_from(spreadsheet, ranged, total_dims, &keys, None, None);
+    RecalcResult::rejected(status)
+}
+
+/// Assigns formulas to several cells without evaluating anything, for manual calculation, as
+/// `assign_deferred` does for one cell.
+///
+/// The assignments are all or nothing: if a formula is rejected or the formulas would create a
+/// cycle, every cell keeps its previous contents.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `formulas` - The (row, col, formula) assignments, e.g. from `fill_formulas`.
+///
+/// # Returns
+/// * `Result<Vec<CellKey>, EvalStatus>` - The keys of the assigned cells and of every cell
+///   depending on them, which are now stale, or the status explaining why the assignments
+///   were rejected.
+pub fn assign_formulas_deferred<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    formulas: &[(usize, usize, String)],
+) -> Result<Vec<CellKey>, EvalStatus> {
+    let backups = link_formulas(spreadsheet, ranged, is_range, total_dims, formulas, true)?;
+    let keys: Vec<CellKey> = backups.iter().map(|&(key, _)| key).collect();
+    match plan_recalc(spreadsheet, ranged, total_dims, &keys) {
+        Some(plan) => Ok(plan
+            .affected
+            .into_iter()
+            .map(|(r, c)| (r * total_dims.1 + c) as CellKey)
+            .collect()),
+        None => {
+            unlink_formulas(spreadsheet, ranged, is_range, total_dims, backups);
+            Err(EvalStatus::CycleDetected)
+        }
+    }
+}
+
+/// Clears every cell of a block, then recalculates everything depending on them once.
+///
+/// A cleared cell reads as an empty 0, as if it had never been set, and the formulas referring
+/// to it keep their references.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `corners` - The top-left and bottom-right (row, col) of the block.
+///
+/// # Returns
+/// * `RecalcResult` - The cleared cells and those recalculated after them, and the status of
+///   the first cell that could not be evaluated.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "5")?;
+/// assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "A1+1")?;
+/// clear_cells(&mut sheet, &mut ranged, &mut is_range, (3, 3), ((0, 0), (0, 0)));
+/// assert_eq!(sheet[&1].value, Valtype::Int(1));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn clear_cells<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    corners: ((usize, usize), (usize, usize)),
+) -> RecalcResult {
+    let ((r1, c1), (r2, c2)) = corners;
+    let total_cols = total_dims.1;
+    // Only the cells holding something are visited, as a block may span whole rows
+    let mut keys: Vec<CellKey> = spreadsheet
+        .cells()
+        .filter(|(key, cell)| {
+            let (row, col) = (*key as usize / total_cols, *key as usize % total_cols);
+            cell.data != CellData::Empty && (r1..=r2).contains(&row) && (c1..=c2).contains(&col)
+        })
+        .map(|(key, _)| key)
+        .collect();
+    keys.sort_unstable();
+    for &key in &keys {
+        let Some(cell) = spreadsheet.get_mut(&key) else {
+            continue;
+        };
+        let backup = cell.my_clone();
+        cell.reset();
+        // An empty cell has no references, so dropping its edges cannot be rejected
+        let _ = relink(spreadsheet, ranged, is_range, total_dims, key, &backup);
+    }
+    let mut result = recalc_from(spreadsheet, ranged, total_dims, &keys, None, None)
+        .unwrap_or_else(|| RecalcResult::rejected(EvalStatus::CycleDetected));
+    for &key in keys.iter().rev() {
+        if !result.changed.contains(&key) {
+            result.changed.insert(0, key);
+        }
     }
+    result
 }
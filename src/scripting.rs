@@ -0,0 +1,196 @@
+//! # Scripting Module
+//! This module lets users define formula functions of their own in a Rhai script, loaded at
+//! startup with `--functions <file.rhai>`. Every function of the script whose name is written
+//! in capitals and takes one parameter, such as
+//!
+//! ```text
+//! fn DOUBLE(x) { x * 2 }
+//! fn SUMSQ(values) { values.reduce(|sum, v| sum + v * v, 0) }
+//! ```
+//!
+//! can then be used in formulas as `DOUBLE(A1)` or `SUMSQ(A1:B5)`: a single cell is passed as
+//! a number, and a range as an array of its values, row by row. The function must return an
+//! integer. A formula calling a script function depends on the cells it passes, like a range
+//! function, and its results are cached by function and arguments, so the functions are
+//! expected to give the same result for the same arguments. Names are at most 7 letters, as
+//! formulas store them, and a script naming such a function with more fails to load. Each
+//! session keeps its functions in its `EvalContext`.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use rhai::{AST, Array, Dynamic, Engine, Scope};
+
+use crate::CellError;
+use crate::syntax::is_built_in;
+use crate::utils::EvalContext;
+
+/// The largest number of operations one call may run, so a script cannot hang the sheet.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Formulas store function names in a `CellName`, which holds at most this many bytes.
+const MAX_NAME_LEN: usize = 7;
+
+/// The result of each call made so far, by function name and arguments.
+type CallCache = HashMap<(String, Vec<i32>), Result<i32, CellError>>;
+
+/// A compiled script and the results of the calls made so far. A session keeps the functions
+/// it loaded in its `EvalContext`, so sessions and sheets never see each other's scripts.
+///
+/// # Fields
+/// * `engine` - The Rhai engine the script runs in.
+/// * `ast` - The compiled script.
+/// * `names` - The names of the functions usable in formulas.
+/// * `cache` - The result of each call, by function name and arguments.
+#[derive(Debug)]
+pub struct UserFunctions {
+    engine: Engine,
+    ast: AST,
+    names: HashSet<String>,
+    cache: Mutex<CallCache>,
+}
+
+impl UserFunctions {
+    /// Compiles a script, keeping the functions usable in formulas: those whose name is in
+    /// capitals, is not that of a built-in function, and which take one parameter.
+    ///
+    /// # Arguments
+    /// * `source` - The text of the Rhai script.
+    ///
+    /// # Returns
+    /// * `Result<UserFunctions, String>` - The functions, or the error that stopped the script
+    ///   from compiling, or that names a function too long for a formula.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::scripting::*;
+    /// let functions = UserFunctions::compile("fn DOUBLE(x) { x * 2 } fn helper(x) { x }")?;
+    /// assert_eq!(functions.names(), vec!["DOUBLE"]);
+    /// assert!(UserFunctions::compile("fn QUADRUPLE(x) { x * 4 }").is_err());
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine
+            .compile(source)
+            .map_err(|e| format!("Script error: {}", e))?;
+        let mut names = HashSet::new();
+        for f in ast.iter_functions() {
+            if f.params.len() != 1
+                || !f.name.bytes().all(|b| b.is_ascii_uppercase())
+                || is_built_in(f.name)
+            {
+                continue;
+            }
+            if f.name.len() > MAX_NAME_LEN {
+                return Err(format!(
+                    "Script error: function {} has a name longer than {} letters",
+                    f.name, MAX_NAME_LEN
+                ));
+            }
+            names.insert(f.name.to_string());
+        }
+        Ok(UserFunctions {
+            engine,
+            ast,
+            names,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the names of the functions usable in formulas, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut sorted: Vec<String> = self.names.iter().cloned().collect();
+        sorted.sort();
+        sorted
+    }
+
+    /// Checks whether the script defines a function usable in formulas by a name.
+    ///
+    /// # Arguments
+    /// * `name` - The function name, as written in the formula.
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Calls a function of the script, or returns its cached result.
+    ///
+    /// # Arguments
+    /// * `name` - The function name.
+    /// * `values` - The values of the cells passed, row by row.
+    /// * `single` - Whether a single cell was passed, which the function receives as a number
+    ///   rather than an array.
+    ///
+    /// # Returns
+    /// * `Result<i32, CellError>` - The result, `CellError::Name` if the script does not define
+    ///   the function, or `CellError::Value` if the call fails or does not return an integer
+    ///   that fits in a cell.
+    pub fn call(&self, name: &str, values: &[i32], single: bool) -> Result<i32, CellError> {
+        if !self.contains(name) {
+            return Err(CellError::Name);
+        }
+        let key = (name.to_string(), values.to_vec());
+        if let Some(result) = self.cache.lock().unwrap().get(&key) {
+            return *result;
+        }
+        let arg = if single {
+            Dynamic::from_int(values[0] as i64)
+        } else {
+            Dynamic::from_array(
+                values
+                    .iter()
+                    .map(|&v| Dynamic::from_int(v as i64))
+                    .collect::<Array>(),
+            )
+        };
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, (arg,))
+            .ok()
+            .and_then(|value| value.as_int().ok())
+            .and_then(|value| i32::try_from(value).ok())
+            .ok_or(CellError::Value);
+        self.cache.lock().unwrap().insert(key, result);
+        result
+    }
+}
+
+/// Compiles a script and makes its functions usable in the formulas of the session whose
+/// context is installed on the thread, replacing those of any script it loaded before.
+///
+/// # Arguments
+/// * `source` - The text of the Rhai script.
+///
+/// # Returns
+/// * `Result<Vec<String>, String>` - The names of the functions usable in formulas, sorted, or
+///   the error that stopped the script from loading.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::scripting::*;
+/// let names = load_functions("fn DOUBLE(x) { x * 2 } fn helper(x) { x }")?;
+/// assert_eq!(names, vec!["DOUBLE"]);
+/// assert!(is_user_function("DOUBLE"));
+/// # Ok::<(), String>(())
+/// ```
+pub fn load_functions(source: &str) -> Result<Vec<String>, String> {
+    let functions = UserFunctions::compile(source)?;
+    let names = functions.names();
+    EvalContext::current().set_functions(Some(Arc::new(functions)));
+    Ok(names)
+}
+
+/// Checks whether a function name belongs to the script of the session whose context is
+/// installed on the thread.
+///
+/// # Arguments
+/// * `name` - The function name, as written in the formula.
+///
+/// # Returns
+/// * `bool` - `true` if the session's script defines a function usable in formulas by that
+///   name.
+pub fn is_user_function(name: &str) -> bool {
+    EvalContext::current()
+        .functions()
+        .is_some_and(|functions| functions.contains(name))
+}
@@ -3,7 +3,7 @@
 //! allowing navigation through rows and columns using keyboard-like commands
 //! (e.g., 'w' for up, 's' for down, 'a' for left, 'd' for right) and direct cell targeting.
 
-use crate::{STATUS_CODE, utils::to_indices};
+use crate::utils::to_indices;
 
 /// Moves the view up by 10 rows if possible.
 ///
@@ -85,8 +85,8 @@ pub fn scroll_to(
     total_cols: usize,
     cell_ref: &str,
 ) -> Result<(), ()> {
-    let (row, col) = to_indices(cell_ref);
-    if row >= total_rows || col >= total_cols || unsafe { STATUS_CODE } == 1 {
+    let (row, col) = to_indices(cell_ref).map_err(|_| ())?;
+    if row >= total_rows || col >= total_cols {
         return Err(());
     }
     *start_row = row;
@@ -0,0 +1,298 @@
+//! # Search Module
+//! This module keeps an inverted index of cell contents, so that a `find` query looks up the
+//! matching cells instead of scanning the sheet. Each cell is indexed under its value and the
+//! words of its formula, and the index is updated cell by cell as the sheet is edited.
+//! It also rewrites a word across every formula, for `replace <from> <to>`.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::lock::LockTable;
+use crate::parser::assign_formula;
+use crate::storage::SheetStorage;
+use crate::utils::{formula_string, range_parents};
+use crate::validation::{ValidationTable, validated_assign};
+use crate::{Cell, CellData, CellKey, EvalStatus, Valtype};
+
+/// An inverted index from the words in cells to the cells holding them.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::search::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 1, "SUM(A1:A2)")?;
+/// let mut index = SearchIndex::build(&sheet);
+/// // After A1 is edited, re-index it and everything computed from it
+/// assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "7")?;
+/// index.refresh(0, &sheet, &ranged, 3);
+/// assert_eq!(index.find("sum"), vec![1]);
+/// assert_eq!(index.find("7"), vec![0, 1]);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SearchIndex {
+    /// Cells indexed under each token, kept sorted so that tokens sharing a prefix are adjacent.
+    postings: BTreeMap<String, BTreeSet<CellKey>>,
+    /// Tokens each cell is indexed under, so that they can be removed when it changes.
+    tokens: HashMap<CellKey, Vec<String>>,
+}
+
+/// Returns the tokens a cell is indexed under: its value and each word of its formula.
+fn cell_tokens(cell: &Cell) -> Vec<String> {
+    if cell.data == CellData::Empty {
+        return Vec::new();
+    }
+    let mut tokens = vec![match &cell.value {
+        Valtype::Int(value) => value.to_string(),
+        Valtype::Str(name) => name.to_string(),
+        Valtype::Err(error) => error.to_string(),
+    }];
+    tokens.extend(
+        formula_string(cell)
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_string),
+    );
+    tokens.sort_unstable();
+    tokens.dedup();
+    tokens
+}
+
+impl SearchIndex {
+    /// Indexes every cell of a sheet.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    pub fn build<S: SheetStorage + ?Sized>(spreadsheet: &S) -> Self {
+        let mut index = SearchIndex::default();
+        for (key, cell) in spreadsheet.cells() {
+            index.update(key, Some(cell));
+        }
+        index
+    }
+
+    /// Replaces the entries of one cell.
+    ///
+    /// # Arguments
+    /// * `key` - The cell index.
+    /// * `cell` - The new contents of the cell, or `None` if it was removed.
+    pub fn update(&mut self, key: CellKey, cell: Option<&Cell>) {
+        for token in self.tokens.remove(&key).unwrap_or_default() {
+            if let Some(cells) = self.postings.get_mut(&token) {
+                cells.remove(&key);
+                if cells.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+        let tokens = cell.map(cell_tokens).unwrap_or_default();
+        if tokens.is_empty() {
+            return;
+        }
+        for token in &tokens {
+            self.postings.entry(token.clone()).or_default().insert(key);
+        }
+        self.tokens.insert(key, tokens);
+    }
+
+    /// Re-indexes an edited cell and every cell whose value is computed from it.
+    ///
+    /// # Arguments
+    /// * `key` - The index of the edited cell.
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `ranged` - A hash map tracking ranges for dependency management.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    pub fn refresh<S: SheetStorage + ?Sized>(
+        &mut self,
+        key: CellKey,
+        spreadsheet: &S,
+        ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+        total_cols: usize,
+    ) {
+        let mut seen = HashSet::from([key]);
+        let mut pending = vec![key];
+        while let Some(key) = pending.pop() {
+            let cell = spreadsheet.get(&key);
+            self.update(key, cell);
+            let dependents = cell.into_iter().flat_map(|c| &c.dependents).copied();
+            for dep_key in dependents.chain(range_parents(key, ranged, total_cols)) {
+                if seen.insert(dep_key) {
+                    pending.push(dep_key);
+                }
+            }
+        }
+    }
+
+    /// Finds the cells holding a word that starts with the query, ignoring case.
+    ///
+    /// # Arguments
+    /// * `query` - The text to look for, e.g. "12" for the value 120 or "sum" for SUM formulas.
+    ///
+    /// # Returns
+    /// * `Vec<CellKey>` - The matching cell indices in row-major order; empty for an empty query.
+    pub fn find(&self, query: &str) -> Vec<CellKey> {
+        let query = query.trim().to_ascii_uppercase();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let cells: BTreeSet<CellKey> = self
+            .postings
+            .range(query.clone()..)
+            .take_while(|(token, _)| token.starts_with(&query))
+            .flat_map(|(_, cells)| cells.iter().copied())
+            .collect();
+        cells.into_iter().collect()
+    }
+}
+
+/// Replaces every occurrence of a word in a formula, ignoring case.
+///
+/// Only whole words are replaced: `A1` is replaced in `A1+A10` but not inside `A10`, so that
+/// renaming a reference or a function never changes another one.
+///
+/// # Arguments
+/// * `formula` - The formula text, e.g. "SUM(A1:A10)".
+/// * `from` - The text to replace.
+/// * `to` - The text written instead, in capitals like the rest of the formula.
+///
+/// # Returns
+/// * `Option<String>` - The rewritten formula, or `None` if `from` does not occur in it.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::search::*;
+/// assert_eq!(replace_word("A1+A10", "a1", "b2"), Some("B2+A10".to_string()));
+/// assert_eq!(replace_word("MAX(A1:A5)", "MA", "MIN"), None);
+/// ```
+pub fn replace_word(formula: &str, from: &str, to: &str) -> Option<String> {
+    let from = from.trim().to_ascii_uppercase();
+    if from.is_empty() {
+        return None;
+    }
+    let upper = formula.to_ascii_uppercase();
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    let mut rewritten = String::with_capacity(formula.len());
+    let mut copied = 0;
+    let mut replaced = false;
+    for (start, _) in upper.match_indices(&from) {
+        let end = start + from.len();
+        if start < copied
+            || is_word(upper[..start].chars().next_back())
+            || is_word(upper[end..].chars().next())
+        {
+            continue;
+        }
+        rewritten.push_str(&formula[copied..start]);
+        rewritten.push_str(&to.trim().to_ascii_uppercase());
+        copied = end;
+        replaced = true;
+    }
+    rewritten.push_str(&formula[copied..]);
+    replaced.then_some(rewritten)
+}
+
+/// Lists the formulas `replace <from> <to>` would write, without changing the sheet.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+/// * `from` - The word to replace.
+/// * `to` - The text written instead.
+///
+/// # Returns
+/// * `Vec<(usize, usize, String)>` - The (row, col, formula) of each cell whose formula
+///   contains `from`, in row-major order.
+pub fn replacements<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_cols: usize,
+    from: &str,
+    to: &str,
+) -> Vec<(usize, usize, String)> {
+    let mut formulas: Vec<(CellKey, String)> = spreadsheet
+        .cells()
+        .filter(|(_, cell)| cell.data != CellData::Empty)
+        .filter_map(|(key, cell)| Some((key, replace_word(&formula_string(cell), from, to)?)))
+        .collect();
+    formulas.sort_unstable_by_key(|&(key, _)| key);
+    formulas
+        .into_iter()
+        .map(|(key, formula)| {
+            let key = key as usize;
+            (key / total_cols, key % total_cols, formula)
+        })
+        .collect()
+}
+
+/// Rewrites every formula containing a word, one cell at a time.
+///
+/// Each rewritten formula is parsed and recalculated as if it had been typed in, so a formula
+/// the engine rejects is undone, leaving its cell unchanged without stopping the other
+/// replacements. Locked cells are left alone and reported as `EvalStatus::CellLocked`, and each
+/// rewritten cell is checked against the validation rules like a typed formula.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `locks` - The locked cells of the sheet.
+/// * `rules` - The validation rules of the sheet.
+/// * `(from, to)` - The word to replace and the text written instead.
+///
+/// # Returns
+/// * `Vec<(CellKey, Result<(), EvalStatus>)>` - The index of each cell whose formula contained
+///   `from`, in row-major order, with the outcome of its assignment.
+pub fn replace_formulas<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    locks: &LockTable,
+    rules: &mut ValidationTable,
+    (from, to): (&str, &str),
+) -> Vec<(CellKey, Result<(), EvalStatus>)> {
+    replacements(spreadsheet, total_dims.1, from, to)
+        .into_iter()
+        .map(|(row, col, formula)| {
+            let key = (row * total_dims.1 + col) as CellKey;
+            if locks.is_locked(row, col) {
+                return (key, Err(EvalStatus::CellLocked));
+            }
+            let previous = spreadsheet
+                .get(&key)
+                .map(formula_string)
+                .unwrap_or_default();
+            let status = validated_assign(
+                rules,
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                (row, col),
+                &formula,
+            )
+            .status;
+            // A broken rule is handled by the rule itself, which restores or marks the cell;
+            // some other rejected formulas, such as unknown functions, are kept in the cell
+            if status.is_err()
+                && status != Err(EvalStatus::ValidationFailed)
+                && spreadsheet.get(&key).map(formula_string).as_ref() != Some(&previous)
+            {
+                let _ = assign_formula(
+                    spreadsheet,
+                    ranged,
+                    is_range,
+                    total_dims,
+                    row,
+                    col,
+                    &previous,
+                );
+            }
+            (key, status)
+        })
+        .collect()
+}
@@ -0,0 +1,144 @@
+//! # Serve Module
+//! This module drives the sheet with JSON requests instead of typed commands, so that other
+//! programs (graders, scripts, notebooks) can use the engine without parsing the prompt or the
+//! printed grid. Started with `--serve`, the program reads one request per line on stdin and
+//! answers each with one line of JSON on stdout:
+//!
+//! ```text
+//! {"set": {"cell": "A1", "formula": "B1+2"}}   ->  {"ok":true}
+//! {"get": "A1"}                                ->  {"ok":true,"cell":"A1","value":2,"formula":"B1+2"}
+//! {"get": "ZZ99"}                              ->  {"ok":false,"error":"Invalid range"}
+//! ```
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use spreadsheet::parser::assign_formula;
+use spreadsheet::storage::SheetStorage;
+use spreadsheet::utils::formula_string;
+use spreadsheet::{CellAddr, CellKey, EvalStatus, Valtype};
+
+/// A request read from one input line.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum Request {
+    /// Assigns a formula to a cell and recalculates its dependents.
+    Set { cell: String, formula: String },
+    /// Reads the value and formula of a cell.
+    Get(String),
+}
+
+/// Builds the response reporting a failed request.
+fn error_response(message: &str) -> Value {
+    json!({ "ok": false, "error": message })
+}
+
+/// Finds a cell named in a request.
+///
+/// # Returns
+/// * `Option<(usize, usize)>` - The (row, col) of the cell, or `None` if the name is malformed
+///   or the cell lies outside the sheet.
+fn parse_cell(name: &str, total_dims: (usize, usize)) -> Option<(usize, usize)> {
+    let CellAddr { row, col } = CellAddr::parse(&name.trim().to_ascii_uppercase())?;
+    (row < total_dims.0 && col < total_dims.1).then_some((row, col))
+}
+
+/// Answers one request line.
+///
+/// # Arguments
+/// * `line` - The request, as JSON.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+///
+/// # Returns
+/// * `Value` - The response: `"ok": true` with the cell for a `get`, or `"ok": false` with an
+///   `error` message.
+pub fn handle_request<S: SheetStorage + ?Sized>(
+    line: &str,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+) -> Value {
+    let request = match serde_json::from_str::<Request>(line) {
+        Ok(request) => request,
+        Err(e) => return error_response(&format!("invalid request: {}", e)),
+    };
+    let invalid_cell = || error_response(EvalStatus::message(Err(EvalStatus::InvalidRange)));
+    match request {
+        Request::Set { cell, formula } => {
+            let Some((row, col)) = parse_cell(&cell, total_dims) else {
+                return invalid_cell();
+            };
+            match assign_formula(
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                row,
+                col,
+                formula.trim(),
+            ) {
+                Ok(()) => json!({ "ok": true }),
+                Err(status) => error_response(EvalStatus::message(Err(status))),
+            }
+        }
+        Request::Get(cell) => {
+            let Some((row, col)) = parse_cell(&cell, total_dims) else {
+                return invalid_cell();
+            };
+            let stored = spreadsheet.get(&((row * total_dims.1 + col) as CellKey));
+            let value = match stored.map(|cell| &cell.value) {
+                Some(Valtype::Str(text)) => json!(text.to_string()),
+                Some(Valtype::Err(error)) => json!(error.to_string()),
+                Some(Valtype::Int(number)) => json!(number),
+                None => json!(0),
+            };
+            let formula = stored.map_or_else(String::new, formula_string);
+            json!({
+                "ok": true,
+                "cell": cell.trim().to_ascii_uppercase(),
+                "value": value,
+                "formula": formula,
+            })
+        }
+    }
+}
+
+/// Answers requests until the input ends, one line of JSON per request.
+///
+/// Blank lines are skipped. Each response is flushed as soon as it is written, so a program
+/// can wait for the answer before sending its next request.
+///
+/// # Arguments
+/// * `reader` - The requests, one per line.
+/// * `writer` - Where the responses are written.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+///
+/// # Returns
+/// * `io::Result<()>` - An error if the input could not be read or the output written.
+pub fn serve<S: SheetStorage + ?Sized, R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_request(&line, spreadsheet, ranged, is_range, total_dims);
+        writeln!(writer, "{}", response)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,304 @@
+//! # Server Module
+//! This module serves a `Spreadsheet` over HTTP, so that web frontends can use the engine as a
+//! backend. It is built with the `server` feature. The sheet is shared behind a mutex by a few
+//! worker threads, and every request holds the lock for as long as it reads or changes cells.
+//!
+//! ```text
+//! GET  /cells/A1       ->  {"cell":"A1","value":7,"formula":"B1+2"}
+//! PUT  /cells/A1       <-  {"formula":"B1+2"}, answered like GET
+//! GET  /range/A1:B2    ->  {"range":"A1:B2","values":[[7,5],[0,"#DIV/0!"]]}
+//! POST /recalc         ->  {"ok":true}
+//! ```
+//!
+//! Several clients can edit the sheet together. Each names itself, shows the others the cell
+//! it has selected, and polls for the edits made since the last version it saw (see
+//! `collab`); a client that is too far behind is answered 410 and reads every formula again.
+//!
+//! ```text
+//! PUT    /cursors/ann  <-  {"cell":"B2"}, answered {"client":"ann","cell":"B2","color":0}
+//! DELETE /cursors/ann  ->  {"ok":true}
+//! GET    /cursors      ->  {"cursors":[{"client":"ann","cell":"B2","color":0}]}
+//! PUT    /cells/A1     <-  {"formula":"5","client":"ann"}, logged as an edit by ann
+//! GET    /changes/3    ->  {"version":4,"changes":[{"version":4,"cell":"A1",...}],"cursors":[...]}
+//! GET    /formulas     ->  {"version":4,"cells":[{"cell":"A1","formula":"5"}]}
+//! ```
+//!
+//! Errors are answered with a status code and `{"error": "..."}`. Error values of cells are
+//! written as their text, such as `"#DIV/0!"`.
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::collab::Collab;
+use crate::utils::{parse_range, to_cell_name};
+use crate::{CellAddr, EvalStatus, Spreadsheet, Valtype};
+
+/// The number of threads answering requests.
+pub const WORKER_THREADS: usize = 4;
+
+/// The largest request body read, in bytes. Longer bodies are rejected.
+pub const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// A spreadsheet shared by the threads of the server.
+pub type SharedSheet = Arc<Mutex<Spreadsheet>>;
+
+/// The body of `PUT /cells/<cell>`, naming the client that made the edit if it shares the
+/// sheet with others.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetCell {
+    formula: String,
+    client: Option<String>,
+}
+
+/// The body of `PUT /cursors/<client>`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MoveCursor {
+    cell: String,
+}
+
+/// Writes a cell value as JSON: a number, or a string such as `"#DIV/0!"`.
+fn value_json(value: &Valtype) -> Value {
+    match value {
+        Valtype::Int(number) => json!(number),
+        Valtype::Str(text) => json!(text.to_string()),
+        Valtype::Err(error) => json!(error.to_string()),
+    }
+}
+
+/// Builds an error response.
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
+
+/// Parses a cell named in a path, ignoring case.
+fn parse_cell(name: &str) -> Option<CellAddr> {
+    CellAddr::parse(&name.to_ascii_uppercase())
+}
+
+/// Describes a cell the way `GET /cells/<cell>` answers it.
+fn cell_json(sheet: &Spreadsheet, name: &str, addr: CellAddr) -> Option<Value> {
+    Some(json!({
+        "cell": name.to_ascii_uppercase(),
+        "value": value_json(&sheet.get_value(addr)?),
+        "formula": sheet.get_formula(addr)?,
+    }))
+}
+
+/// Answers one request.
+///
+/// # Arguments
+/// * `sheet` - The spreadsheet served.
+/// * `collab` - The cursors and edit log of the clients sharing the sheet.
+/// * `method` - The HTTP method, e.g. "GET".
+/// * `path` - The path of the URL, without a query string.
+/// * `body` - The request body.
+///
+/// # Returns
+/// * `(u16, Value)` - The HTTP status code and the JSON body: 200 on success, 400 for a
+///   malformed body, client name or version, 404 for an unknown path, client or a cell
+///   outside the sheet, 405 for a known path with the wrong method, 410 for changes that were
+///   dropped from the log, and 422 for a formula the engine rejects.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::server::*;
+/// # use std::sync::Mutex;
+/// let (sheet, collab) = (Mutex::new(Spreadsheet::new(10, 10)), Mutex::default());
+/// handle(&sheet, &collab, "PUT", "/cells/A1", r#"{"formula": "2+3"}"#);
+/// let (status, body) = handle(&sheet, &collab, "GET", "/cells/A1", "");
+/// assert_eq!((status, body["value"].as_i64()), (200, Some(5)));
+/// ```
+pub fn handle(
+    sheet: &Mutex<Spreadsheet>,
+    collab: &Mutex<Collab>,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, Value) {
+    let mut sheet = sheet.lock().unwrap_or_else(PoisonError::into_inner);
+    // Always taken after the sheet, so that the log follows the order of the edits
+    let mut collab = collab.lock().unwrap_or_else(PoisonError::into_inner);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["cells", name]) => match parse_cell(name).and_then(|a| cell_json(&sheet, name, a))
+        {
+            Some(cell) => (200, cell),
+            None => error(404, EvalStatus::message(Err(EvalStatus::InvalidRange))),
+        },
+        ("PUT", ["cells", name]) => {
+            let request = match serde_json::from_str::<SetCell>(body) {
+                Ok(request) => request,
+                Err(e) => return error(400, &format!("invalid body: {}", e)),
+            };
+            let Some(addr) = parse_cell(name).filter(|&addr| sheet.get_value(addr).is_some())
+            else {
+                return error(404, EvalStatus::message(Err(EvalStatus::InvalidRange)));
+            };
+            if let Some(client) = &request.client
+                && !Collab::valid_client(client)
+            {
+                return error(400, "invalid client name");
+            }
+            match sheet.set_formula(addr, request.formula.trim()) {
+                Ok(()) => {
+                    let cell = cell_json(&sheet, name, addr).unwrap();
+                    collab.record_edit(
+                        &to_cell_name(addr.row, addr.col),
+                        &sheet.get_formula(addr).unwrap(),
+                        request.client.as_deref(),
+                    );
+                    (200, cell)
+                }
+                Err(status) => error(422, EvalStatus::message(Err(status))),
+            }
+        }
+        ("GET", ["formulas"]) => {
+            let cells: Vec<Value> = sheet
+                .formulas()
+                .into_iter()
+                .map(|(addr, formula)| {
+                    json!({ "cell": to_cell_name(addr.row, addr.col), "formula": formula })
+                })
+                .collect();
+            (200, json!({ "version": collab.version(), "cells": cells }))
+        }
+        ("GET", ["changes", since]) => {
+            let Ok(since) = since.parse::<u64>() else {
+                return error(400, "invalid version");
+            };
+            let Some(changes) = collab.changes_since(since) else {
+                return error(410, "changes dropped; read /formulas again");
+            };
+            let cursors = collab.peers(Instant::now());
+            (
+                200,
+                json!({ "version": collab.version(), "changes": changes, "cursors": cursors }),
+            )
+        }
+        ("GET", ["cursors"]) => (200, json!({ "cursors": collab.peers(Instant::now()) })),
+        ("PUT", ["cursors", client]) => {
+            let request = match serde_json::from_str::<MoveCursor>(body) {
+                Ok(request) => request,
+                Err(e) => return error(400, &format!("invalid body: {}", e)),
+            };
+            if !Collab::valid_client(client) {
+                return error(400, "invalid client name");
+            }
+            let Some(addr) = parse_cell(&request.cell).filter(|&a| sheet.get_value(a).is_some())
+            else {
+                return error(404, EvalStatus::message(Err(EvalStatus::InvalidRange)));
+            };
+            let cell = to_cell_name(addr.row, addr.col);
+            let peer = collab.move_cursor(client, &cell, Instant::now());
+            (200, json!(peer))
+        }
+        ("DELETE", ["cursors", client]) => match collab.leave(client) {
+            true => (200, json!({ "ok": true })),
+            false => error(404, "unknown client"),
+        },
+        ("GET", ["range", range]) => {
+            let (total_rows, total_cols) = sheet.dimensions();
+            let range = range.to_ascii_uppercase();
+            let Some(((r1, c1), (r2, c2))) = parse_range(&range, total_rows, total_cols) else {
+                return error(404, EvalStatus::message(Err(EvalStatus::InvalidRange)));
+            };
+            let values: Vec<Vec<Value>> = (r1..=r2)
+                .map(|row| {
+                    (c1..=c2)
+                        .map(|col| value_json(&sheet.get_value(CellAddr::new(row, col)).unwrap()))
+                        .collect()
+                })
+                .collect();
+            (200, json!({ "range": range, "values": values }))
+        }
+        ("POST", ["recalc"]) => {
+            sheet.recalculate();
+            (200, json!({ "ok": true }))
+        }
+        (
+            _,
+            ["cells", _]
+            | ["range", _]
+            | ["recalc"]
+            | ["formulas"]
+            | ["changes", _]
+            | ["cursors"]
+            | ["cursors", _],
+        ) => error(405, "method not allowed"),
+        _ => error(404, "not found"),
+    }
+}
+
+/// Reads the body of a request, up to `MAX_BODY_BYTES`.
+///
+/// # Returns
+/// * `Option<String>` - The body, or `None` if it is too long or not UTF-8.
+fn read_body(request: &mut tiny_http::Request) -> Option<String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_string(&mut body)
+        .ok()?;
+    (body.len() as u64 <= MAX_BODY_BYTES).then_some(body)
+}
+
+/// Answers requests on a port of the local machine until the process ends.
+///
+/// # Arguments
+/// * `port` - The TCP port to listen on, at 127.0.0.1.
+/// * `sheet` - The spreadsheet served, which other threads may keep using.
+///
+/// # Returns
+/// * `io::Result<()>` - An error if the port could not be opened.
+pub fn run(port: u16, sheet: SharedSheet) -> io::Result<()> {
+    let server = Server::http(("127.0.0.1", port)).map_err(io::Error::other)?;
+    let server = Arc::new(server);
+    let collab = Arc::new(Mutex::new(Collab::default()));
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let workers: Vec<_> = (0..WORKER_THREADS)
+        .map(|_| {
+            let (server, sheet, collab, content_type) = (
+                Arc::clone(&server),
+                Arc::clone(&sheet),
+                Arc::clone(&collab),
+                content_type.clone(),
+            );
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let path = request.url().split('?').next().unwrap_or("").to_string();
+                    let (status, body) = match read_body(&mut request) {
+                        Some(body) => {
+                            let method = match request.method() {
+                                Method::Get => "GET",
+                                Method::Put => "PUT",
+                                Method::Post => "POST",
+                                Method::Delete => "DELETE",
+                                _ => "",
+                            };
+                            handle(&sheet, &collab, method, &path, &body)
+                        }
+                        None => error(413, "body too large or not UTF-8"),
+                    };
+                    let response = Response::from_string(body.to_string())
+                        .with_status_code(status)
+                        .with_header(content_type.clone());
+                    // A client that hung up needs no answer
+                    let _ = request.respond(response);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! # Snapshot Module
+//! This module exports the values of a sheet as they stood at one moment. The values are copied
+//! when the snapshot is taken, so the file can be written on a background thread while the sheet
+//! keeps changing, and it is written to a temporary file that is renamed into place, so readers
+//! never see a partly written export.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use crate::storage::SheetStorage;
+use crate::{CellKey, Valtype};
+
+/// The values of a sheet at one moment.
+///
+/// # Examples
+/// ```no_run
+/// # use spreadsheet::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::snapshot::*;
+/// # use std::path::PathBuf;
+/// # let sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// let snapshot = ValueSnapshot::capture(&sheet, (10, 10));
+/// let handle = snapshot.export_in_background(PathBuf::from("values.csv"));
+/// // ... keep editing the sheet ...
+/// handle.join().unwrap()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct ValueSnapshot {
+    values: HashMap<CellKey, Valtype>,
+    total_rows: usize,
+    total_cols: usize,
+}
+
+impl ValueSnapshot {
+    /// Copies the value of every non-empty cell. Formulas and dependents are not copied.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    pub fn capture<S: SheetStorage + ?Sized>(spreadsheet: &S, total_dims: (usize, usize)) -> Self {
+        ValueSnapshot {
+            values: spreadsheet
+                .cells()
+                .map(|(key, cell)| (key, cell.value.clone()))
+                .collect(),
+            total_rows: total_dims.0,
+            total_cols: total_dims.1,
+        }
+    }
+
+    /// Writes the values as CSV, one record per row of the sheet, with 0 for empty cells.
+    ///
+    /// The layout matches the GUI's `csv` export, so the file can be loaded back with `open`.
+    ///
+    /// # Arguments
+    /// * `out` - Where to write the CSV.
+    pub fn write_csv<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+        for row in 0..self.total_rows {
+            for col in 0..self.total_cols {
+                if col > 0 {
+                    out.write_all(b",")?;
+                }
+                match self.values.get(&((row * self.total_cols + col) as CellKey)) {
+                    Some(Valtype::Int(v)) => write!(out, "{}", v)?,
+                    Some(Valtype::Str(s)) => write!(out, "{}", s)?,
+                    Some(Valtype::Err(error)) => write!(out, "{}", error)?,
+                    None => out.write_all(b"0")?,
+                }
+            }
+            out.write_all(b"\n")?;
+        }
+        out.flush()
+    }
+
+    /// Writes the values as CSV to a file, replacing it in one step.
+    ///
+    /// The CSV is written to `<path>.tmp` first and then renamed over `path`, so the file holds
+    /// either its previous contents or the whole snapshot.
+    ///
+    /// # Arguments
+    /// * `path` - The file to write.
+    pub fn write_file(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let result = File::create(&tmp)
+            .and_then(|file| self.write_csv(file))
+            .and_then(|()| fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    /// Writes the snapshot to a file on a new thread.
+    ///
+    /// # Arguments
+    /// * `path` - The file to write, as in `write_file`.
+    ///
+    /// # Returns
+    /// * `JoinHandle<io::Result<()>>` - The thread, which returns the outcome of the write.
+    pub fn export_in_background(self, path: PathBuf) -> JoinHandle<io::Result<()>> {
+        thread::spawn(move || self.write_file(&path))
+    }
+}
@@ -0,0 +1,416 @@
+//! # Sort Module
+//! This module sorts the rows of a range by the values of one of its columns, for
+//! `sort <range> [by <col>] [desc] [natural] [nocase] [order=<list>|order=<range>]`.
+//! Numbers come first in increasing order, then text, then error values, and empty cells
+//! always come last. Text is compared character by character unless `natural` compares the
+//! runs of digits in it as numbers, so that `A2` comes before `A10`, and `nocase` ignores the
+//! case of letters. A custom order such as `order=Low,Medium,High`, or one read from the
+//! cells of a range, puts the values it lists first, in its order, ignoring case.
+//!
+//! Each row keeps its cells together, and the formulas moved to another row have their
+//! relative references shifted by as many rows, as when they are copied.
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::{RecalcResult, assign_and_recalc, clear_cells};
+use crate::storage::SheetStorage;
+use crate::utils::{formula_string, parse_range, shift_cell_data, to_indices};
+use crate::{Cell, CellData, CellKey, EvalStatus, Valtype};
+
+/// The top-left and bottom-right (row, col) corners of a range.
+type Corners = ((usize, usize), (usize, usize));
+
+/// Where the values of a custom order come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SortOrder {
+    /// The values listed after `order=`, separated by commas.
+    List(Vec<String>),
+    /// The values of the cells of a range, row by row, read when the rows are sorted.
+    Range(Corners),
+}
+
+/// A parsed `sort` command.
+///
+/// # Fields
+/// * `corners` - The range whose rows are sorted.
+/// * `key_col` - The column whose values decide the order.
+/// * `descending` - Whether the largest values come first. Empty cells still come last.
+/// * `natural` - Whether runs of digits in text are compared as numbers.
+/// * `ignore_case` - Whether text is compared ignoring the case of letters.
+/// * `order` - A custom order whose values come first, in the order given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortSpec {
+    pub corners: Corners,
+    pub key_col: usize,
+    pub descending: bool,
+    pub natural: bool,
+    pub ignore_case: bool,
+    pub order: Option<SortOrder>,
+}
+
+/// Parses the arguments of `sort <range> [by <col>] [desc] [natural] [nocase] [order=...]`.
+///
+/// # Arguments
+/// * `args` - The text following the `sort` keyword.
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<SortSpec, EvalStatus>` - The parsed command, `EvalStatus::InvalidRange` if a range
+///   or the key column lies outside the sheet or the sorted range, or
+///   `EvalStatus::UnrecognizedCmd` for any other mistake.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::sort::*;
+/// let spec = parse_sort_args("A1:C10 by B desc natural", 999, 10).unwrap();
+/// assert_eq!((spec.key_col, spec.descending, spec.natural), (1, true, true));
+/// let spec = parse_sort_args("A1:A5 order=Low,Medium,High", 999, 10).unwrap();
+/// assert_eq!(spec.order, Some(SortOrder::List(vec!["Low".into(), "Medium".into(), "High".into()])));
+/// ```
+pub fn parse_sort_args(
+    args: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<SortSpec, EvalStatus> {
+    let mut args = args.split_whitespace();
+    let range = args.next().ok_or(EvalStatus::UnrecognizedCmd)?;
+    let corners = parse_range(range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    let ((_, c1), (_, c2)) = corners;
+    let mut spec = SortSpec {
+        corners,
+        key_col: c1,
+        descending: false,
+        natural: false,
+        ignore_case: false,
+        order: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg {
+            "by" => {
+                let col = args.next().ok_or(EvalStatus::UnrecognizedCmd)?;
+                if !col.bytes().all(|b| b.is_ascii_alphabetic()) {
+                    return Err(EvalStatus::UnrecognizedCmd);
+                }
+                let (_, col) = to_indices(&format!("{}1", col.to_ascii_uppercase()))?;
+                if !(c1..=c2).contains(&col) {
+                    return Err(EvalStatus::InvalidRange);
+                }
+                spec.key_col = col;
+            }
+            "asc" => spec.descending = false,
+            "desc" => spec.descending = true,
+            "natural" => spec.natural = true,
+            "nocase" => spec.ignore_case = true,
+            _ if arg.starts_with("order=") => {
+                let list = &arg["order=".len()..];
+                let order = if list.contains(',') {
+                    SortOrder::List(list.split(',').map(str::to_string).collect())
+                } else if list.contains(':') {
+                    let corners = parse_range(list, total_rows, total_cols)
+                        .ok_or(EvalStatus::InvalidRange)?;
+                    SortOrder::Range(corners)
+                } else if !list.is_empty() {
+                    SortOrder::List(vec![list.to_string()])
+                } else {
+                    return Err(EvalStatus::UnrecognizedCmd);
+                };
+                spec.order = Some(order);
+            }
+            _ => return Err(EvalStatus::UnrecognizedCmd),
+        }
+    }
+    Ok(spec)
+}
+
+/// Compares two pieces of text, the runs of digits as numbers if `natural` is set.
+///
+/// # Arguments
+/// * `a` - The first text.
+/// * `b` - The second text.
+/// * `natural` - Whether runs of digits are compared by their value.
+/// * `ignore_case` - Whether the case of letters is ignored.
+///
+/// # Returns
+/// * `Ordering` - How `a` compares to `b`.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::sort::*;
+/// # use std::cmp::Ordering;
+/// assert_eq!(compare_text("A10", "A2", true, false), Ordering::Greater);
+/// assert_eq!(compare_text("A10", "A2", false, false), Ordering::Less);
+/// assert_eq!(compare_text("apple", "Banana", false, true), Ordering::Less);
+/// ```
+pub fn compare_text(a: &str, b: &str, natural: bool, ignore_case: bool) -> Ordering {
+    let fold = |s: &str| {
+        if ignore_case {
+            s.to_lowercase()
+        } else {
+            s.to_string()
+        }
+    };
+    let (a, b) = (fold(a), fold(b));
+    if !natural {
+        return a.cmp(&b);
+    }
+    let (mut a, mut b) = (a.as_str(), b.as_str());
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let split = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let (da, db) = (split(a), split(b));
+            let (na, nb) = (
+                a[..da].trim_start_matches('0'),
+                b[..db].trim_start_matches('0'),
+            );
+            // Leading zeros are ignored, so the longer run is the larger number
+            let order = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+            if order != Ordering::Equal {
+                return order;
+            }
+            (a, b) = (&a[da..], &b[db..]);
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+        }
+    }
+}
+
+/// Returns the text a value is matched by in a custom order.
+fn order_text(value: &Valtype) -> Option<String> {
+    match value {
+        Valtype::Int(n) => Some(n.to_string()),
+        Valtype::Str(text) => Some(text.as_str().to_string()),
+        Valtype::Err(_) => None,
+    }
+}
+
+/// Compares the key values of two rows, `None` standing for an empty cell.
+fn compare_keys(
+    a: Option<&Valtype>,
+    b: Option<&Valtype>,
+    spec: &SortSpec,
+    order: &[String],
+) -> Ordering {
+    let (a, b) = match (a, b) {
+        (None, None) => return Ordering::Equal,
+        // Empty cells come last whichever way the rows are sorted
+        (None, Some(_)) => return Ordering::Greater,
+        (Some(_), None) => return Ordering::Less,
+        (Some(a), Some(b)) => (a, b),
+    };
+    let position = |value: &Valtype| {
+        let text = order_text(value)?.to_lowercase();
+        order.iter().position(|entry| entry.to_lowercase() == text)
+    };
+    let rank = |value: &Valtype| match value {
+        Valtype::Int(_) => 0,
+        Valtype::Str(_) => 1,
+        Valtype::Err(_) => 2,
+    };
+    let listed = match (position(a), position(b)) {
+        (Some(x), Some(y)) => x.cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+            (Valtype::Int(x), Valtype::Int(y)) => x.cmp(y),
+            (Valtype::Str(x), Valtype::Str(y)) => {
+                compare_text(x.as_str(), y.as_str(), spec.natural, spec.ignore_case)
+            }
+            (Valtype::Err(x), Valtype::Err(y)) => x.to_string().cmp(&y.to_string()),
+            _ => Ordering::Equal,
+        }),
+    };
+    if spec.descending {
+        listed.reverse()
+    } else {
+        listed
+    }
+}
+
+/// Finds the new order of the rows of the sorted range.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `spec` - The parsed `sort` command.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Vec<usize>` - The rows of the range in their sorted order; rows with equal keys keep
+///   their order.
+pub fn sorted_rows<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    spec: &SortSpec,
+    total_cols: usize,
+) -> Vec<usize> {
+    let value = |row: usize, col: usize| {
+        spreadsheet
+            .get(&((row * total_cols + col) as CellKey))
+            .filter(|cell| cell.data != CellData::Empty)
+            .map(|cell| cell.value.clone())
+    };
+    let order: Vec<String> = match &spec.order {
+        None => Vec::new(),
+        Some(SortOrder::List(list)) => list.clone(),
+        Some(SortOrder::Range(((r1, c1), (r2, c2)))) => (*r1..=*r2)
+            .flat_map(|row| (*c1..=*c2).map(move |col| (row, col)))
+            .filter_map(|(row, col)| order_text(&value(row, col)?))
+            .collect(),
+    };
+    let ((r1, _), (r2, _)) = spec.corners;
+    let mut rows: Vec<(usize, Option<Valtype>)> = (r1..=r2)
+        .map(|row| (row, value(row, spec.key_col)))
+        .collect();
+    rows.sort_by(|(_, a), (_, b)| compare_keys(a.as_ref(), b.as_ref(), spec, &order));
+    rows.into_iter().map(|(row, _)| row).collect()
+}
+
+/// Lists the formulas the cells of the range hold once its rows are sorted.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `spec` - The parsed `sort` command.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+///
+/// # Returns
+/// * `Result<Vec<(usize, usize, String)>, EvalStatus>` - The (row, col, formula) of every cell
+///   of the range that holds something after the sort, or `EvalStatus::InvalidRange` if a
+///   moved formula would refer outside the sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::sort::*;
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "\"b\"")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 1, 0, "\"a\"")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 1, 1, "A2*2")?;
+/// // A1 = "b", A2 = "a", B2 = A2*2
+/// let spec = parse_sort_args("A1:B2", 10, 10).unwrap();
+/// assert_eq!(
+///     sort_formulas(&sheet, &spec, (10, 10)),
+///     Ok(vec![(0, 0, "\"a\"".to_string()), (0, 1, "A1*2".to_string()), (1, 0, "\"b\"".to_string())])
+/// );
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn sort_formulas<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    spec: &SortSpec,
+    total_dims: (usize, usize),
+) -> Result<Vec<(usize, usize, String)>, EvalStatus> {
+    let (total_rows, total_cols) = total_dims;
+    let ((r1, c1), (_, c2)) = spec.corners;
+    let mut formulas = Vec::new();
+    for (offset, source) in sorted_rows(spreadsheet, spec, total_cols)
+        .into_iter()
+        .enumerate()
+    {
+        let target = r1 + offset;
+        for col in c1..=c2 {
+            let Some(cell) = spreadsheet.get(&((source * total_cols + col) as CellKey)) else {
+                continue;
+            };
+            if cell.data == CellData::Empty {
+                continue;
+            }
+            let d_row = target as isize - source as isize;
+            let data = shift_cell_data(&cell.data, d_row, 0, total_rows, total_cols)
+                .ok_or(EvalStatus::InvalidRange)?;
+            let moved = Cell {
+                value: cell.value.clone(),
+                data,
+                dependents: HashSet::new(),
+            };
+            formulas.push((target, col, formula_string(&moved)));
+        }
+    }
+    Ok(formulas)
+}
+
+/// Sorts the rows of a range, writing every cell of it again in its new row.
+///
+/// The range is cleared before the sorted formulas are assigned, so that a formula never meets
+/// one that has not moved yet. If a moved formula is rejected, such as one that now depends on
+/// itself, the range is put back as it was.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `spec` - The parsed `sort` command.
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed, and `Ok(())` or the status of the first
+///   formula that was rejected or could not be evaluated.
+pub fn sort_range<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    spec: &SortSpec,
+) -> RecalcResult {
+    let total_cols = total_dims.1;
+    let sorted = match sort_formulas(spreadsheet, spec, total_dims) {
+        Ok(sorted) => sorted,
+        Err(status) => {
+            return RecalcResult {
+                changed: Vec::new(),
+                status: Err(status),
+            };
+        }
+    };
+    let ((r1, c1), (r2, c2)) = spec.corners;
+    let original: Vec<(usize, usize, String)> = (r1..=r2)
+        .flat_map(|row| (c1..=c2).map(move |col| (row, col)))
+        .filter_map(|(row, col)| {
+            let cell = spreadsheet.get(&((row * total_cols + col) as CellKey))?;
+            (cell.data != CellData::Empty).then(|| (row, col, formula_string(cell)))
+        })
+        .collect();
+    let mut write = |formulas: &[(usize, usize, String)]| {
+        let mut result = clear_cells(spreadsheet, ranged, is_range, total_dims, spec.corners);
+        for (row, col, formula) in formulas {
+            let assigned = assign_and_recalc(
+                spreadsheet,
+                ranged,
+                is_range,
+                total_dims,
+                *row,
+                *col,
+                formula,
+            );
+            for key in assigned.changed {
+                if !result.changed.contains(&key) {
+                    result.changed.push(key);
+                }
+            }
+            if result.status.is_ok() {
+                result.status = assigned.status;
+            }
+        }
+        result
+    };
+    let result = write(&sorted);
+    if matches!(
+        result.status,
+        Err(EvalStatus::CycleDetected | EvalStatus::InvalidRange | EvalStatus::UnrecognizedCmd)
+    ) {
+        let restored = write(&original);
+        return RecalcResult {
+            changed: restored.changed,
+            status: result.status,
+        };
+    }
+    result
+}
@@ -0,0 +1,496 @@
+//! # Storage Module
+//! This module defines `SheetStorage`, the interface the parser and evaluator use to reach the
+//! cells of a sheet, and its two backends:
+//!
+//! * `HashMap<CellKey, Cell>`, the default, keyed by `row * total_cols + col`;
+//! * `ColumnStore`, which keeps each column as a list of fixed-size chunks of rows, so that a
+//!   range such as `SUM(A1:C5000)` is read column by column from contiguous memory and empty
+//!   stretches of a column are skipped a whole chunk at a time.
+//!
+//! The command-line interface picks the backend with `--storage map|columns`.
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::{Cell, CellData, CellKey, Valtype};
+
+/// The cells of a sheet, addressed by the key `row * total_cols + col`.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::storage::*;
+/// # use std::collections::HashSet;
+/// let mut cells = ColumnStore::new(10);
+/// cells.insert(12, Cell { value: Valtype::Int(5), data: CellData::Const, dependents: HashSet::new() });
+/// let block: Vec<&Cell> = cells.block(0..=1, 0..=2, 10).collect();
+/// assert_eq!(block.len(), 1);
+/// ```
+pub trait SheetStorage: Send + Sync {
+    /// Returns the cell stored at a key.
+    fn get(&self, key: &CellKey) -> Option<&Cell>;
+
+    /// Returns the cell stored at a key, for changing it in place.
+    fn get_mut(&mut self, key: &CellKey) -> Option<&mut Cell>;
+
+    /// Stores a cell, returning the cell it replaced.
+    fn insert(&mut self, key: CellKey, cell: Cell) -> Option<Cell>;
+
+    /// Removes the cell stored at a key and returns it.
+    fn remove(&mut self, key: &CellKey) -> Option<Cell>;
+
+    /// Returns the number of stored cells.
+    fn len(&self) -> usize;
+
+    /// Checks whether no cell is stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks whether a cell is stored at a key.
+    fn contains_key(&self, key: &CellKey) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns every stored cell with its key, in no particular order.
+    fn cells(&self) -> Box<dyn Iterator<Item = (CellKey, &Cell)> + '_>;
+
+    /// Returns every stored cell with its key, for changing them in place.
+    fn cells_mut(&mut self) -> Box<dyn Iterator<Item = (CellKey, &mut Cell)> + '_>;
+
+    /// Returns the stored cells of a block, in no particular order. Cells never written are
+    /// skipped.
+    ///
+    /// # Arguments
+    /// * `rows` - The rows of the block.
+    /// * `cols` - The columns of the block.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    fn block(
+        &self,
+        rows: RangeInclusive<usize>,
+        cols: RangeInclusive<usize>,
+        total_cols: usize,
+    ) -> Box<dyn Iterator<Item = &Cell> + '_>;
+
+    /// Removes every cell.
+    fn clear(&mut self);
+
+    /// Removes every cell, so that the store can be refilled for a sheet with a different
+    /// number of columns.
+    ///
+    /// # Arguments
+    /// * `total_cols` - The number of columns the keys inserted next are computed with.
+    ///
+    /// # Returns
+    /// * `Vec<(CellKey, Cell)>` - The removed cells, with the keys they were stored under.
+    fn take_all(&mut self, total_cols: usize) -> Vec<(CellKey, Cell)>;
+
+    /// Returns the cell stored at a key, first storing an empty cell there if there is none.
+    fn entry_or_empty(&mut self, key: CellKey) -> &mut Cell {
+        if !self.contains_key(&key) {
+            self.insert(
+                key,
+                Cell {
+                    value: Valtype::Int(0),
+                    data: CellData::Empty,
+                    dependents: HashSet::new(),
+                },
+            );
+        }
+        self.get_mut(&key).unwrap()
+    }
+
+    /// Makes room for one more cell ahead of an insertion, if the backend preallocates.
+    fn reserve_on_grow(&mut self) {}
+}
+
+impl SheetStorage for HashMap<CellKey, Cell> {
+    fn get(&self, key: &CellKey) -> Option<&Cell> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &CellKey) -> Option<&mut Cell> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: CellKey, cell: Cell) -> Option<Cell> {
+        HashMap::insert(self, key, cell)
+    }
+
+    fn remove(&mut self, key: &CellKey) -> Option<Cell> {
+        HashMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn cells(&self) -> Box<dyn Iterator<Item = (CellKey, &Cell)> + '_> {
+        Box::new(self.iter().map(|(&key, cell)| (key, cell)))
+    }
+
+    fn cells_mut(&mut self) -> Box<dyn Iterator<Item = (CellKey, &mut Cell)> + '_> {
+        Box::new(self.iter_mut().map(|(&key, cell)| (key, cell)))
+    }
+
+    /// Looks up each cell of the block when the map holds at least as many cells as the block
+    /// has, and otherwise scans the map for the cells inside the block.
+    fn block(
+        &self,
+        rows: RangeInclusive<usize>,
+        cols: RangeInclusive<usize>,
+        total_cols: usize,
+    ) -> Box<dyn Iterator<Item = &Cell> + '_> {
+        let area = rows.clone().count() * cols.clone().count();
+        if HashMap::len(self) >= area {
+            Box::new(rows.flat_map(move |row| {
+                cols.clone().filter_map(move |col| {
+                    HashMap::get(self, &((row * total_cols + col) as CellKey))
+                })
+            }))
+        } else {
+            Box::new(self.iter().filter_map(move |(&key, cell)| {
+                let (row, col) = (key as usize / total_cols, key as usize % total_cols);
+                (rows.contains(&row) && cols.contains(&col)).then_some(cell)
+            }))
+        }
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn take_all(&mut self, _total_cols: usize) -> Vec<(CellKey, Cell)> {
+        self.drain().collect()
+    }
+
+    /// Grows the capacity to the next power of two when the map is full, so that a run of
+    /// insertions reallocates only a logarithmic number of times.
+    fn reserve_on_grow(&mut self) {
+        let len = HashMap::len(self);
+        let cap = self.capacity();
+        if len + 1 > cap {
+            // bump to the next power of two ≥ len+1
+            let new_cap = (len + 1).next_power_of_two();
+            self.reserve(new_cap - cap);
+        }
+    }
+}
+
+/// Lets a backend chosen at run time, such as one from `StorageKind::create`, be used
+/// wherever a `SheetStorage` is expected.
+impl<T: SheetStorage + ?Sized> SheetStorage for Box<T> {
+    fn get(&self, key: &CellKey) -> Option<&Cell> {
+        (**self).get(key)
+    }
+
+    fn get_mut(&mut self, key: &CellKey) -> Option<&mut Cell> {
+        (**self).get_mut(key)
+    }
+
+    fn insert(&mut self, key: CellKey, cell: Cell) -> Option<Cell> {
+        (**self).insert(key, cell)
+    }
+
+    fn remove(&mut self, key: &CellKey) -> Option<Cell> {
+        (**self).remove(key)
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn cells(&self) -> Box<dyn Iterator<Item = (CellKey, &Cell)> + '_> {
+        (**self).cells()
+    }
+
+    fn cells_mut(&mut self) -> Box<dyn Iterator<Item = (CellKey, &mut Cell)> + '_> {
+        (**self).cells_mut()
+    }
+
+    fn block(
+        &self,
+        rows: RangeInclusive<usize>,
+        cols: RangeInclusive<usize>,
+        total_cols: usize,
+    ) -> Box<dyn Iterator<Item = &Cell> + '_> {
+        (**self).block(rows, cols, total_cols)
+    }
+
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+
+    fn take_all(&mut self, total_cols: usize) -> Vec<(CellKey, Cell)> {
+        (**self).take_all(total_cols)
+    }
+
+    fn reserve_on_grow(&mut self) {
+        (**self).reserve_on_grow()
+    }
+}
+
+/// The number of rows in one chunk of a `ColumnStore` column.
+pub const CHUNK_ROWS: usize = 64;
+
+/// `CHUNK_ROWS` consecutive cells of one column, allocated once any of them is written.
+struct Chunk {
+    cells: Vec<Option<Cell>>,
+    /// The number of cells stored, so that emptied chunks are freed.
+    filled: usize,
+}
+
+/// A column-major store: each column is a list of chunks of `CHUNK_ROWS` rows, with no
+/// memory used by chunks that hold no cell.
+///
+/// Keys are split into row and column with the number of columns given to `new`, or to
+/// `take_all` after a resize.
+pub struct ColumnStore {
+    total_cols: usize,
+    /// The chunks of each column, indexed by `row / CHUNK_ROWS`.
+    columns: Vec<Vec<Option<Box<Chunk>>>>,
+    len: usize,
+}
+
+impl ColumnStore {
+    /// Creates an empty store.
+    ///
+    /// # Arguments
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    pub fn new(total_cols: usize) -> Self {
+        ColumnStore {
+            total_cols,
+            columns: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Splits a key into its column, chunk and position in the chunk.
+    fn locate(&self, key: CellKey) -> (usize, usize, usize) {
+        let (row, col) = (
+            key as usize / self.total_cols,
+            key as usize % self.total_cols,
+        );
+        (col, row / CHUNK_ROWS, row % CHUNK_ROWS)
+    }
+
+    /// Returns the chunk holding a key, if it was allocated.
+    fn chunk(&self, key: CellKey) -> Option<(&Chunk, usize)> {
+        let (col, chunk, slot) = self.locate(key);
+        let chunk = self.columns.get(col)?.get(chunk)?.as_deref()?;
+        Some((chunk, slot))
+    }
+
+    /// Returns the stored cells of a column with their keys.
+    fn column_cells(&self, col: usize) -> impl Iterator<Item = (CellKey, &Cell)> + '_ {
+        let total_cols = self.total_cols;
+        self.columns[col]
+            .iter()
+            .enumerate()
+            .filter_map(|(index, chunk)| Some((index, chunk.as_deref()?)))
+            .flat_map(move |(index, chunk)| {
+                chunk
+                    .cells
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(slot, cell)| {
+                        let row = index * CHUNK_ROWS + slot;
+                        Some(((row * total_cols + col) as CellKey, cell.as_ref()?))
+                    })
+            })
+    }
+}
+
+impl SheetStorage for ColumnStore {
+    fn get(&self, key: &CellKey) -> Option<&Cell> {
+        let (chunk, slot) = self.chunk(*key)?;
+        chunk.cells[slot].as_ref()
+    }
+
+    fn get_mut(&mut self, key: &CellKey) -> Option<&mut Cell> {
+        let (col, chunk, slot) = self.locate(*key);
+        let chunk = self.columns.get_mut(col)?.get_mut(chunk)?.as_deref_mut()?;
+        chunk.cells[slot].as_mut()
+    }
+
+    fn insert(&mut self, key: CellKey, cell: Cell) -> Option<Cell> {
+        let (col, index, slot) = self.locate(key);
+        if self.columns.len() <= col {
+            self.columns.resize_with(col + 1, Vec::new);
+        }
+        let column = &mut self.columns[col];
+        if column.len() <= index {
+            column.resize_with(index + 1, || None);
+        }
+        let chunk = column[index].get_or_insert_with(|| {
+            Box::new(Chunk {
+                cells: (0..CHUNK_ROWS).map(|_| None).collect(),
+                filled: 0,
+            })
+        });
+        let old = chunk.cells[slot].replace(cell);
+        if old.is_none() {
+            chunk.filled += 1;
+            self.len += 1;
+        }
+        old
+    }
+
+    fn remove(&mut self, key: &CellKey) -> Option<Cell> {
+        let (col, index, slot) = self.locate(*key);
+        let entry = self.columns.get_mut(col)?.get_mut(index)?;
+        let chunk = entry.as_deref_mut()?;
+        let old = chunk.cells[slot].take()?;
+        chunk.filled -= 1;
+        if chunk.filled == 0 {
+            *entry = None;
+        }
+        self.len -= 1;
+        Some(old)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn cells(&self) -> Box<dyn Iterator<Item = (CellKey, &Cell)> + '_> {
+        Box::new((0..self.columns.len()).flat_map(|col| self.column_cells(col)))
+    }
+
+    fn cells_mut(&mut self) -> Box<dyn Iterator<Item = (CellKey, &mut Cell)> + '_> {
+        let total_cols = self.total_cols;
+        Box::new(
+            self.columns
+                .iter_mut()
+                .enumerate()
+                .flat_map(move |(col, column)| {
+                    column
+                        .iter_mut()
+                        .enumerate()
+                        .filter_map(|(index, chunk)| Some((index, chunk.as_deref_mut()?)))
+                        .flat_map(move |(index, chunk)| {
+                            chunk
+                                .cells
+                                .iter_mut()
+                                .enumerate()
+                                .filter_map(move |(slot, cell)| {
+                                    let row = index * CHUNK_ROWS + slot;
+                                    Some(((row * total_cols + col) as CellKey, cell.as_mut()?))
+                                })
+                        })
+                }),
+        )
+    }
+
+    /// Reads the block column by column, visiting only the chunks that overlap its rows.
+    fn block(
+        &self,
+        rows: RangeInclusive<usize>,
+        cols: RangeInclusive<usize>,
+        _total_cols: usize,
+    ) -> Box<dyn Iterator<Item = &Cell> + '_> {
+        let (first, last) = (*rows.start(), *rows.end());
+        let last_col = (*cols.end()).min(self.columns.len().saturating_sub(1));
+        let cols = *cols.start()..=last_col;
+        Box::new(
+            cols.filter(|&col| col < self.columns.len())
+                .flat_map(move |col| {
+                    let column = &self.columns[col];
+                    let end = (last / CHUNK_ROWS + 1).min(column.len());
+                    (first / CHUNK_ROWS..end)
+                        .filter_map(move |index| Some((index, column.get(index)?.as_deref()?)))
+                })
+                .flat_map(move |(index, chunk)| {
+                    let base = index * CHUNK_ROWS;
+                    let from = first.saturating_sub(base);
+                    let to = (last - base).min(CHUNK_ROWS - 1);
+                    chunk.cells[from..=to].iter().flatten()
+                }),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.columns.clear();
+        self.len = 0;
+    }
+
+    fn take_all(&mut self, total_cols: usize) -> Vec<(CellKey, Cell)> {
+        let old_cols = self.total_cols;
+        let columns = std::mem::take(&mut self.columns);
+        self.total_cols = total_cols;
+        self.len = 0;
+        let mut cells = Vec::new();
+        for (col, column) in columns.into_iter().enumerate() {
+            for (index, chunk) in column.into_iter().enumerate() {
+                let Some(chunk) = chunk else { continue };
+                for (slot, cell) in chunk.cells.into_iter().enumerate() {
+                    if let Some(cell) = cell {
+                        let row = index * CHUNK_ROWS + slot;
+                        cells.push(((row * old_cols + col) as CellKey, cell));
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// The backends a sheet can be stored in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageKind {
+    /// A `HashMap<CellKey, Cell>`.
+    #[default]
+    Map,
+    /// A `ColumnStore`.
+    Columns,
+}
+
+impl FromStr for StorageKind {
+    type Err = &'static str;
+    /// Parses "map" or "columns".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "map" => Ok(StorageKind::Map),
+            "columns" => Ok(StorageKind::Columns),
+            _ => Err("Invalid storage: expected map or columns"),
+        }
+    }
+}
+
+impl StorageKind {
+    /// Creates an empty store of this kind.
+    ///
+    /// # Arguments
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    pub fn create(self, total_cols: usize) -> Box<dyn SheetStorage> {
+        match self {
+            StorageKind::Map => Box::new(HashMap::<CellKey, Cell>::with_capacity(1024)),
+            StorageKind::Columns => Box::new(ColumnStore::new(total_cols)),
+        }
+    }
+}
+
+/// Copies the cells of a store into a map, e.g. to save them.
+pub fn to_map<S: SheetStorage + ?Sized>(sheet: &S) -> HashMap<CellKey, Cell> {
+    sheet
+        .cells()
+        .map(|(key, cell)| (key, cell.clone()))
+        .collect()
+}
+
+/// Replaces the cells of a store with the cells of a map, e.g. after loading them.
+///
+/// # Arguments
+/// * `sheet` - The store to refill.
+/// * `cells` - The new cells.
+/// * `total_cols` - The total number of columns the keys of `cells` are computed with.
+pub fn replace_cells<S: SheetStorage + ?Sized>(
+    sheet: &mut S,
+    cells: HashMap<CellKey, Cell>,
+    total_cols: usize,
+) {
+    sheet.take_all(total_cols);
+    for (key, cell) in cells {
+        sheet.insert(key, cell);
+    }
+}
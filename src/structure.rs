@@ -0,0 +1,284 @@
+//! # Structure Module
+//! This module inserts and deletes whole rows and columns. The cells past the edit move over
+//! by one, the sheet grows or shrinks to match, and every reference follows the cell it names,
+//! '$' markers included, so a range spanning the edit grows or shrinks with it. A row or column
+//! that a formula elsewhere refers to on its own, as a cell or a range lying entirely in it,
+//! cannot be deleted, since the reference would be left naming nothing.
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::assign_formulas;
+use crate::storage::SheetStorage;
+use crate::utils::formula_string;
+use crate::{Cell, CellAddr, CellData, CellKey, CellRef, EvalStatus, Expr, Lookup, Operand};
+
+/// An insertion or deletion of one row or column, by its zero-based index.
+///
+/// An insertion puts an empty row or column at the index, moving the one there and those after
+/// it down or right.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::structure::*;
+/// let edit = LineEdit::InsertRow(1);
+/// assert_eq!(edit.dims((10, 5)), Some((11, 5)));
+/// assert_eq!(edit.addr(CellAddr::new(2, 0)), Some(CellAddr::new(3, 0)));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEdit {
+    InsertRow(usize),
+    DeleteRow(usize),
+    InsertCol(usize),
+    DeleteCol(usize),
+}
+
+impl LineEdit {
+    /// Returns the dimensions of the sheet after the edit.
+    ///
+    /// # Arguments
+    /// * `total_dims` - The current `(total_rows, total_cols)`.
+    ///
+    /// # Returns
+    /// * `Option<(usize, usize)>` - The new `(total_rows, total_cols)`, or `None` if the index is
+    ///   outside the sheet, or the sheet would leave the 1..=999 rows and 1..=18278 columns
+    ///   accepted at startup.
+    pub fn dims(self, total_dims: (usize, usize)) -> Option<(usize, usize)> {
+        let (rows, cols) = total_dims;
+        match self {
+            LineEdit::InsertRow(at) => (at <= rows && rows < 999).then_some((rows + 1, cols)),
+            LineEdit::DeleteRow(at) => (at < rows && rows > 1).then_some((rows - 1, cols)),
+            LineEdit::InsertCol(at) => (at <= cols && cols < 18278).then_some((rows, cols + 1)),
+            LineEdit::DeleteCol(at) => (at < cols && cols > 1).then_some((rows, cols - 1)),
+        }
+    }
+
+    /// Returns the index of a cell along the edited axis, with the index of the edit.
+    fn line(self, addr: CellAddr) -> (usize, usize) {
+        match self {
+            LineEdit::InsertRow(at) | LineEdit::DeleteRow(at) => (addr.row, at),
+            LineEdit::InsertCol(at) | LineEdit::DeleteCol(at) => (addr.col, at),
+        }
+    }
+
+    /// Returns the cell at a new index along the edited axis.
+    fn moved(self, addr: CellAddr, index: usize) -> CellAddr {
+        match self {
+            LineEdit::InsertRow(_) | LineEdit::DeleteRow(_) => CellAddr::new(index, addr.col),
+            LineEdit::InsertCol(_) | LineEdit::DeleteCol(_) => CellAddr::new(addr.row, index),
+        }
+    }
+
+    /// Returns where a cell ends up after the edit.
+    ///
+    /// # Arguments
+    /// * `addr` - The address of the cell before the edit.
+    ///
+    /// # Returns
+    /// * `Option<CellAddr>` - The address after the edit, or `None` if the cell is deleted.
+    pub fn addr(self, addr: CellAddr) -> Option<CellAddr> {
+        let (index, at) = self.line(addr);
+        match self {
+            LineEdit::InsertRow(_) | LineEdit::InsertCol(_) if index >= at => {
+                Some(self.moved(addr, index + 1))
+            }
+            LineEdit::DeleteRow(_) | LineEdit::DeleteCol(_) if index == at => None,
+            LineEdit::DeleteRow(_) | LineEdit::DeleteCol(_) if index > at => {
+                Some(self.moved(addr, index - 1))
+            }
+            _ => Some(addr),
+        }
+    }
+
+    /// Returns where the corners of a range end up after the edit.
+    ///
+    /// A deleted corner moves inwards, so the range loses the deleted row or column.
+    ///
+    /// # Returns
+    /// * `Option<(CellAddr, CellAddr)>` - The corners after the edit, or `None` if the whole
+    ///   range is deleted.
+    fn area(self, start: CellAddr, end: CellAddr) -> Option<(CellAddr, CellAddr)> {
+        if matches!(self, LineEdit::InsertRow(_) | LineEdit::InsertCol(_)) {
+            return Some((self.addr(start)?, self.addr(end)?));
+        }
+        let ((first, at), (last, _)) = (self.line(start), self.line(end));
+        if first == at && last == at {
+            return None;
+        }
+        let start = self.addr(start).unwrap_or(start);
+        let end = self.addr(end).unwrap_or_else(|| self.moved(end, last - 1));
+        Some((start, end))
+    }
+}
+
+/// Rewrites the references of a formula to follow the cells they name through an edit.
+///
+/// # Arguments
+/// * `data` - The formula data to rewrite.
+/// * `edit` - The row or column edit.
+///
+/// # Returns
+/// * `Option<CellData>` - The rewritten data, or `None` if a reference names only deleted
+///   cells.
+pub fn edited_cell_data(data: &CellData, edit: LineEdit) -> Option<CellData> {
+    let point = |cell: &CellRef| Some(cell.with_addr(edit.addr(cell.addr()?)?));
+    let area = |cell1: &CellRef, cell2: &CellRef| {
+        let (start, end) = edit.area(cell1.addr()?, cell2.addr()?)?;
+        Some((cell1.with_addr(start), cell2.with_addr(end)))
+    };
+    let operand = |operand: &Operand| {
+        Some(match operand {
+            Operand::Const(value) => Operand::Const(*value),
+            Operand::Ref(cell1) => Operand::Ref(point(cell1)?),
+            Operand::Range { func, cell1, cell2 } => {
+                let (cell1, cell2) = area(cell1, cell2)?;
+                Operand::Range {
+                    func: *func,
+                    cell1,
+                    cell2,
+                }
+            }
+        })
+    };
+    Some(match data {
+        CellData::SleepR { cell1 } => CellData::SleepR {
+            cell1: point(cell1)?,
+        },
+        CellData::Ref { cell1 } => CellData::Ref {
+            cell1: point(cell1)?,
+        },
+        CellData::CoR {
+            op_code,
+            value2,
+            cell2,
+        } => CellData::CoR {
+            op_code: *op_code,
+            value2: value2.clone(),
+            cell2: point(cell2)?,
+        },
+        CellData::RoC {
+            op_code,
+            value2,
+            cell1,
+        } => CellData::RoC {
+            op_code: *op_code,
+            value2: value2.clone(),
+            cell1: point(cell1)?,
+        },
+        CellData::RoR {
+            op_code,
+            cell1,
+            cell2,
+        } => CellData::RoR {
+            op_code: *op_code,
+            cell1: point(cell1)?,
+            cell2: point(cell2)?,
+        },
+        CellData::Range {
+            cell1,
+            cell2,
+            value2,
+            extra,
+        } => {
+            let (cell1, cell2) = area(cell1, cell2)?;
+            CellData::Range {
+                cell1,
+                cell2,
+                value2: value2.clone(),
+                extra: extra
+                    .iter()
+                    .map(|(cell1, cell2)| area(cell1, cell2))
+                    .collect::<Option<_>>()?,
+            }
+        }
+        CellData::Expr(expr) => CellData::Expr(Box::new(Expr {
+            op_code: expr.op_code,
+            lhs: operand(&expr.lhs)?,
+            rhs: operand(&expr.rhs)?,
+        })),
+        CellData::Lookup(lookup) => {
+            let (cell1, cell2) = area(&lookup.cell1, &lookup.cell2)?;
+            CellData::Lookup(Box::new(Lookup {
+                key: operand(&lookup.key)?,
+                cell1,
+                cell2,
+                column: lookup.column,
+            }))
+        }
+        CellData::Func { func, args } => CellData::Func {
+            func: *func,
+            args: args.iter().map(operand).collect::<Option<_>>()?,
+        },
+        other => other.clone(),
+    })
+}
+
+/// Inserts or deletes a row or column of the sheet.
+///
+/// Every formula is rewritten with `edited_cell_data` and assigned again at its new place, so
+/// the cells, their dependents, the ranges and the range flags are all rebuilt. Nothing changes
+/// if the edit is refused.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean vector indicating whether each cell is part of a range, resized to
+///   the new dimensions.
+/// * `total_dims` - The current `(total_rows, total_cols)`.
+/// * `edit` - The row or column edit.
+///
+/// # Returns
+/// * `Result<(usize, usize), EvalStatus>` - The new `(total_rows, total_cols)`, or
+///   `EvalStatus::InvalidRange` if the sheet cannot grow or shrink that way, or a formula
+///   refers to a deleted row or column.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::structure::*;
+/// # use spreadsheet::utils::formula_string;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 1, 0, "A1+1")?;
+/// let dims = edit_lines(&mut sheet, &mut ranged, &mut is_range, (3, 3), LineEdit::InsertRow(0))?;
+/// assert_eq!(dims, (4, 3));
+/// assert_eq!(formula_string(&sheet[&6]), "A2+1");
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn edit_lines<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut Vec<bool>,
+    total_dims: (usize, usize),
+    edit: LineEdit,
+) -> Result<(usize, usize), EvalStatus> {
+    let new_dims = edit.dims(total_dims).ok_or(EvalStatus::InvalidRange)?;
+    let total_cols = total_dims.1;
+    let mut formulas = Vec::new();
+    for (key, cell) in spreadsheet.cells() {
+        let addr = CellAddr::new(key as usize / total_cols, key as usize % total_cols);
+        let Some(addr) = edit.addr(addr) else {
+            continue;
+        };
+        let data = edited_cell_data(&cell.data, edit).ok_or(EvalStatus::InvalidRange)?;
+        if matches!(data, CellData::Empty | CellData::Invalid) {
+            continue;
+        }
+        let formula = formula_string(&Cell {
+            value: cell.value.clone(),
+            data,
+            dependents: HashSet::new(),
+        });
+        formulas.push((addr.row, addr.col, formula));
+    }
+    formulas.sort_unstable();
+
+    spreadsheet.take_all(new_dims.1);
+    ranged.clear();
+    *is_range = vec![false; new_dims.0 * new_dims.1];
+    // The formulas refer to each other as before, so they form no cycle and are all assigned;
+    // a status left over is an error value, which the cell shows as before the edit
+    let _ = assign_formulas(spreadsheet, ranged, is_range, new_dims, &formulas);
+    Ok(new_dims)
+}
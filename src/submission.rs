@@ -0,0 +1,245 @@
+//! # Submission Module
+//! This module bundles a sheet into the zip archive both interfaces write with
+//! `package <file.zip>`. The archive holds the files handed to [`write_submission`], such as
+//! `values.csv`, `formulas.csv` and `workbook.json`, and a `manifest.json` describing the sheet
+//! and listing the size and SHA-256 checksum of every other file, so that a grader can check
+//! that nothing was changed after the archive was written.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spreadsheet::storage::SheetStorage;
+use spreadsheet::utils::formula_string;
+use spreadsheet::{CellData, CellKey, Valtype};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::workbook::{self, FormattedCell, GridSizes};
+
+/// The name of the manifest inside a submission archive.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// A file of a submission archive, as listed in its manifest.
+///
+/// # Fields
+/// * `name` - The name of the file inside the archive.
+/// * `bytes` - The size of the file before compression.
+/// * `sha256` - The SHA-256 checksum of the file, in lowercase hex.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub bytes: usize,
+    pub sha256: String,
+}
+
+/// The contents of `manifest.json`.
+///
+/// # Fields
+/// * `generator` - The program and version that wrote the archive.
+/// * `created_unix` - When the archive was written, in seconds since the Unix epoch.
+/// * `rows` - Total number of rows of the sheet.
+/// * `cols` - Total number of columns of the sheet.
+/// * `non_empty_cells` - The number of cells holding a value or formula.
+/// * `files` - Every other file of the archive, in the order they were written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub generator: String,
+    pub created_unix: u64,
+    pub rows: usize,
+    pub cols: usize,
+    pub non_empty_cells: usize,
+    pub files: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    /// Describes a sheet and the files bundled with it.
+    ///
+    /// # Arguments
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` of the sheet.
+    /// * `non_empty_cells` - The number of cells holding a value or formula.
+    /// * `files` - The files of the archive, as (name, contents) pairs.
+    ///
+    /// # Returns
+    /// * `Manifest` - The manifest, dated now.
+    pub fn new(
+        total_dims: (usize, usize),
+        non_empty_cells: usize,
+        files: &[(&str, Vec<u8>)],
+    ) -> Self {
+        let created_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Manifest {
+            generator: format!("spreadsheet {}", env!("CARGO_PKG_VERSION")),
+            created_unix,
+            rows: total_dims.0,
+            cols: total_dims.1,
+            non_empty_cells,
+            files: files
+                .iter()
+                .map(|(name, bytes)| ManifestFile {
+                    name: name.to_string(),
+                    bytes: bytes.len(),
+                    sha256: sha256_hex(bytes),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Returns the SHA-256 checksum of some bytes in lowercase hex, as written to the manifest.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Writes a submission archive: the given files, then a `manifest.json` listing them.
+///
+/// # Arguments
+/// * `writer` - Where the archive is written, such as a new file.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` of the sheet.
+/// * `non_empty_cells` - The number of cells holding a value or formula.
+/// * `files` - The files to bundle, as (name, contents) pairs.
+///
+/// # Returns
+/// * `Result<(), String>` - A message describing the failure if the archive could not be
+///   written.
+///
+/// # Examples
+/// ```
+/// let file = File::create("submission.zip").unwrap();
+/// let files = [("values.csv", b"1,2\n".to_vec())];
+/// write_submission(file, (1, 2), 2, &files).unwrap();
+/// ```
+pub fn write_submission<W: Write + Seek>(
+    writer: W,
+    total_dims: (usize, usize),
+    non_empty_cells: usize,
+    files: &[(&str, Vec<u8>)],
+) -> Result<(), String> {
+    let manifest = Manifest::new(total_dims, non_empty_cells, files);
+    let mut manifest_bytes =
+        serde_json::to_vec_pretty(&manifest).map_err(|e| format!("Manifest write error: {}", e))?;
+    manifest_bytes.push(b'\n');
+
+    let mut archive = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, bytes) in files
+        .iter()
+        .map(|(name, bytes)| (*name, bytes))
+        .chain([(MANIFEST_NAME, &manifest_bytes)])
+    {
+        archive
+            .start_file(name, options)
+            .and_then(|_| archive.write_all(bytes).map_err(Into::into))
+            .map_err(|e| format!("Zip write error: {}", e))?;
+    }
+    archive
+        .finish()
+        .map(|_| ())
+        .map_err(|e| format!("Zip write error: {}", e))
+}
+
+/// Writes the sheet as CSV, one record per row of the sheet with 0 for empty cells, as the
+/// files of a submission. Formats are ignored.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `formulas` - Whether to write each formula after an '=' instead of its value; constants
+///   are written as they are either way.
+///
+/// # Returns
+/// * `Result<Vec<u8>, String>` - The CSV, or a message describing why it could not be written.
+pub fn sheet_csv<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_dims: (usize, usize),
+    formulas: bool,
+) -> Result<Vec<u8>, String> {
+    let (total_rows, total_cols) = total_dims;
+    let mut wtr = Writer::from_writer(Vec::new());
+    for row in 0..total_rows {
+        let record = (0..total_cols).map(|col| {
+            match spreadsheet.get(&((row * total_cols + col) as CellKey)) {
+                Some(cell)
+                    if formulas
+                        && !matches!(
+                            cell.data,
+                            CellData::Empty | CellData::Const | CellData::Invalid
+                        ) =>
+                {
+                    format!("={}", formula_string(cell))
+                }
+                Some(cell) => match &cell.value {
+                    Valtype::Int(n) => n.to_string(),
+                    Valtype::Str(s) => s.to_string(),
+                    Valtype::Err(error) => error.to_string(),
+                },
+                None => "0".to_string(),
+            }
+        });
+        wtr.write_record(record)
+            .map_err(|e| format!("CSV write error: {}", e))?;
+    }
+    wtr.into_inner()
+        .map_err(|e| format!("CSV flush error: {}", e.error()))
+}
+
+/// Bundles the sheet into a submission archive, as the `package` command of either interface.
+///
+/// The archive contains `values.csv`, `formulas.csv`, the workbook JSON written by `save` as
+/// `workbook.json`, and a `manifest.json` describing the sheet and listing the size and
+/// SHA-256 checksum of every other file.
+///
+/// # Arguments
+/// * `filename` - The name of the archive to create (appends ".zip" if not present).
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `sizes` - The column widths and row heights to store in the workbook.
+/// * `formats` - The (row, col) and format of every formatted cell, for the workbook.
+///
+/// # Returns
+/// * `Result<String, String>` - The name of the archive written, or a message describing the
+///   failure.
+pub fn package_sheet<S: SheetStorage + ?Sized>(
+    filename: &str,
+    spreadsheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &[bool],
+    total_dims: (usize, usize),
+    sizes: &GridSizes,
+    formats: &[FormattedCell],
+) -> Result<String, String> {
+    let filename = if filename.ends_with(".zip") {
+        filename.to_string()
+    } else {
+        format!("{}.zip", filename)
+    };
+    let mut files: Vec<(&str, Vec<u8>)> = vec![
+        ("values.csv", sheet_csv(spreadsheet, total_dims, false)?),
+        ("formulas.csv", sheet_csv(spreadsheet, total_dims, true)?),
+    ];
+    let mut workbook_json = Vec::new();
+    workbook::write_workbook(
+        &mut workbook_json,
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        sizes,
+        formats,
+    )?;
+    files.push(("workbook.json", workbook_json));
+    let non_empty = spreadsheet
+        .cells()
+        .filter(|(_, cell)| cell.data != CellData::Empty)
+        .count();
+    let file = File::create(&filename).map_err(|e| format!("File error: {}", e))?;
+    write_submission(file, total_dims, non_empty, &files)?;
+    Ok(filename)
+}
@@ -0,0 +1,483 @@
+//! # Syntax Module
+//! This module reads the text of a formula into the `CellData` a cell holds, or says where it
+//! goes wrong, such as "unexpected token '*' at position 4" for `A1+*2`, so that the formula
+//! bar and the prompt can point at a typo. A formula is a single operand, such as `5`, `$B2`
+//! or `SUM(A1:A5,C1:C5)`, a function call such as `MOD(A1,3)`, `VLOOKUP(A1,B1:C5,2)` or
+//! `SLEEP(2)`, or two operands joined by `+`, `-`, `*` or `/`, where each side is a number, a
+//! reference or a range function over one range. A formula may also be text in double
+//! quotes, such as `"Größe"`, which the cell shows as it is. Spaces are not allowed inside a
+//! formula, except in text.
+use std::fmt;
+
+use crate::utils::{EvalContext, SCALAR_FUNCTIONS, stored_function_name};
+use crate::{CellData, CellName, CellRef, CellText, Expr, Lookup, Operand, Valtype};
+
+/// The range functions evaluated by the engine, named in messages about their arguments.
+const RANGE_FUNCTIONS: [&str; 7] = ["SUM", "AVG", "MAX", "MIN", "STDEV", "COUNT", "SPARK"];
+
+/// The functions read into formulas of their own rather than as a range or scalar function.
+const CALL_FUNCTIONS: [&str; 2] = ["SLEEP", "VLOOKUP"];
+
+/// Returns whether a function is built into the engine, by the name it is written or stored
+/// under, so that a script cannot replace it.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::syntax::*;
+/// assert!(is_built_in("SUM"));
+/// assert!(is_built_in("RANDBTW"));
+/// assert!(!is_built_in("DOUBLE"));
+/// ```
+pub fn is_built_in(name: &str) -> bool {
+    RANGE_FUNCTIONS.contains(&name)
+        || CALL_FUNCTIONS.contains(&name)
+        || SCALAR_FUNCTIONS
+            .iter()
+            .any(|(func, _)| *func == name || stored_function_name(func) == name)
+}
+
+/// A formula that could not be read: what is wrong with it, and where.
+///
+/// # Fields
+/// * `position` - The position of the character at fault, counting from 1, or one past the
+///   last character if the formula ends too early.
+/// * `message` - What is wrong, e.g. "unexpected token '*'".
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::syntax::*;
+/// let error = read_formula("A1+*2").unwrap_err();
+/// assert_eq!(error.to_string(), "unexpected token '*' at position 4");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+/// An argument of a function call: a number, a cell, or a range between two cells.
+enum Arg {
+    Int(i32),
+    Ref(CellRef),
+    Area(CellRef, CellRef),
+}
+
+/// One side of a formula: a number, a cell, or a function call with its arguments, each with
+/// the byte offset it starts at.
+enum Term {
+    Int(i32),
+    Ref(CellRef),
+    Call(String, Vec<(usize, Arg)>),
+}
+
+/// The text of a formula, read from left to right.
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    /// Returns the character at the cursor, if any.
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    /// Moves past the character at the cursor if it is `ch`.
+    fn eat(&mut self, ch: char) -> bool {
+        let found = self.peek() == Some(ch);
+        if found {
+            self.pos += ch.len_utf8();
+        }
+        found
+    }
+
+    /// Moves past the characters at the cursor for as long as they match.
+    fn take_while(&mut self, matches: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        while self.peek().is_some_and(&matches) {
+            self.pos += 1;
+        }
+        &self.text[start..self.pos]
+    }
+
+    /// Builds an error about the text at a byte offset.
+    fn error(&self, at: usize, message: String) -> SyntaxError {
+        SyntaxError {
+            position: self.text[..at].chars().count() + 1,
+            message,
+        }
+    }
+
+    /// Builds an error about the character at the cursor.
+    fn unexpected(&self) -> SyntaxError {
+        let message = match self.peek() {
+            Some(ch) => format!("unexpected token '{}'", ch),
+            None => "unexpected end of formula".to_string(),
+        };
+        self.error(self.pos, message)
+    }
+
+    /// Reads text up to its closing '"', after the opening one. Inside the quotes any
+    /// character may appear, spaces included, and `""` stands for one '"'.
+    fn text(&mut self) -> Result<String, SyntaxError> {
+        let start = self.pos - 1;
+        let mut text = String::new();
+        while let Some(ch) = self.peek() {
+            self.pos += ch.len_utf8();
+            if ch != '"' {
+                text.push(ch);
+            } else if self.eat('"') {
+                text.push('"');
+            } else {
+                return Ok(text);
+            }
+        }
+        Err(self.error(start, "text without a closing '\"'".to_string()))
+    }
+
+    /// Reads an integer, `-?[0-9]+`, if one starts at the cursor.
+    fn number(&mut self) -> Result<Option<i32>, SyntaxError> {
+        let start = self.pos;
+        let negative = self.text[start..].starts_with('-');
+        let digits_at = start + usize::from(negative);
+        if !self.text[digits_at..].starts_with(|ch: char| ch.is_ascii_digit()) {
+            return Ok(None);
+        }
+        self.pos = digits_at;
+        self.take_while(|ch| ch.is_ascii_digit());
+        let text = &self.text[start..self.pos];
+        text.parse()
+            .map(Some)
+            .map_err(|_| self.error(start, format!("number {} is too large", text)))
+    }
+
+    /// Reads a cell reference or a function name, `$?[A-Z]+$?[0-9]*`. A name is followed by
+    /// '(' and has no '$' and at most one digit, as in `MIN2(`.
+    ///
+    /// # Returns
+    /// * `Result<(String, bool), SyntaxError>` - The word, and whether it names a function.
+    fn word(&mut self) -> Result<(String, bool), SyntaxError> {
+        let start = self.pos;
+        self.eat('$');
+        if self.take_while(|ch| ch.is_ascii_uppercase()).is_empty() {
+            return Err(self.unexpected());
+        }
+        self.eat('$');
+        let digits = self.take_while(|ch| ch.is_ascii_digit()).len();
+        let word = self.text[start..self.pos].to_string();
+        let is_name = !word.contains('$') && digits <= 1 && self.peek() == Some('(');
+        if !is_name && digits == 0 {
+            let message = format!("expected a row number after '{}'", word);
+            return Err(self.error(self.pos, message));
+        }
+        Ok((word, is_name))
+    }
+
+    /// Reads a cell reference.
+    fn cell_ref(&mut self) -> Result<CellRef, SyntaxError> {
+        let start = self.pos;
+        if !matches!(self.peek(), Some('$' | 'A'..='Z')) {
+            return Err(self.unexpected());
+        }
+        let (word, is_name) = self.word()?;
+        if is_name {
+            return Err(self.error(self.pos, "functions cannot be nested".to_string()));
+        }
+        CellRef::new(&word)
+            .map_err(|_| self.error(start, format!("reference {} is too large", word)))
+    }
+
+    /// Reads the arguments of a function call, after its '(' and up to its ')'.
+    fn args(&mut self) -> Result<Vec<(usize, Arg)>, SyntaxError> {
+        let mut args = Vec::new();
+        if self.eat(')') {
+            return Ok(args);
+        }
+        loop {
+            let start = self.pos;
+            let arg = match self.number()? {
+                Some(value) => Arg::Int(value),
+                None => {
+                    let cell1 = self.cell_ref()?;
+                    if self.eat(':') {
+                        Arg::Area(cell1, self.cell_ref()?)
+                    } else {
+                        Arg::Ref(cell1)
+                    }
+                }
+            };
+            args.push((start, arg));
+            if self.eat(')') {
+                return Ok(args);
+            }
+            if !self.eat(',') {
+                return Err(self.unexpected());
+            }
+        }
+    }
+
+    /// Reads one side of a formula.
+    fn term(&mut self) -> Result<(usize, Term), SyntaxError> {
+        let start = self.pos;
+        if let Some(value) = self.number()? {
+            return Ok((start, Term::Int(value)));
+        }
+        if !matches!(self.peek(), Some('$' | 'A'..='Z')) {
+            return Err(self.unexpected());
+        }
+        let (word, is_name) = self.word()?;
+        if is_name {
+            self.eat('(');
+            return Ok((start, Term::Call(word, self.args()?)));
+        }
+        let cell = CellRef::new(&word)
+            .map_err(|_| self.error(start, format!("reference {} is too large", word)))?;
+        Ok((start, Term::Ref(cell)))
+    }
+}
+
+/// Stores a function name in a `CellName`, which holds at most 7 characters.
+fn function_name(cursor: &Cursor, at: usize, name: &str) -> Result<CellName, SyntaxError> {
+    CellName::new(name).map_err(|_| cursor.error(at, format!("function name {} is too long", name)))
+}
+
+/// Reads a function call standing alone as a formula.
+///
+/// # Returns
+/// * `Result<(CellData, Option<Valtype>), SyntaxError>` - As for `read_formula`.
+fn call(
+    cursor: &Cursor,
+    at: usize,
+    name: &str,
+    args: Vec<(usize, Arg)>,
+) -> Result<(CellData, Option<Valtype>), SyntaxError> {
+    if name == "SLEEP" {
+        match args[..] {
+            [(_, Arg::Int(value))] => return Ok((CellData::SleepC, Some(Valtype::Int(value)))),
+            [(_, Arg::Ref(cell1))] => return Ok((CellData::SleepR { cell1 }, None)),
+            _ => {}
+        }
+    }
+    // Any name over ranges is a range function; an unknown one evaluates to #NAME?
+    let letters = name.bytes().all(|b| b.is_ascii_uppercase());
+    if letters && !args.is_empty() && args.iter().all(|(_, arg)| matches!(arg, Arg::Area(..))) {
+        let func = function_name(cursor, at, name)?;
+        let mut areas = args.into_iter().map(|(_, arg)| match arg {
+            Arg::Area(cell1, cell2) => (cell1, cell2),
+            _ => unreachable!("every argument is a range"),
+        });
+        let (cell1, cell2) = areas.next().unwrap();
+        let data = CellData::Range {
+            cell1,
+            cell2,
+            value2: Valtype::Str(func.into()),
+            extra: areas.collect(),
+        };
+        return Ok((data, None));
+    }
+    #[cfg(feature = "scripting")]
+    if let [(_, Arg::Ref(cell1))] = args[..]
+        && crate::scripting::is_user_function(name)
+        && let Ok(func) = CellName::new(name)
+    {
+        let data = CellData::Range {
+            cell1,
+            cell2: cell1,
+            value2: Valtype::Str(func.into()),
+            extra: Vec::new(),
+        };
+        return Ok((data, None));
+    }
+    if name == "VLOOKUP"
+        && let [
+            (_, ref key),
+            (_, Arg::Area(cell1, cell2)),
+            (_, Arg::Int(column)),
+        ] = args[..]
+        && let (Some(key), Ok(column)) = (scalar_operand(key), u32::try_from(column))
+    {
+        let lookup = Lookup {
+            key,
+            cell1,
+            cell2,
+            column,
+        };
+        return Ok((CellData::Lookup(Box::new(lookup)), None));
+    }
+    let Some(&(name, arity)) = SCALAR_FUNCTIONS.iter().find(|(known, _)| *known == name) else {
+        let message = match name {
+            "SLEEP" => "SLEEP takes one number or cell".to_string(),
+            "VLOOKUP" => "VLOOKUP takes a key, a range and a column number".to_string(),
+            _ if RANGE_FUNCTIONS.contains(&name) => {
+                format!("{} takes ranges such as A1:A5", name)
+            }
+            _ => format!("unknown function {}", name),
+        };
+        return Err(cursor.error(at, message));
+    };
+    let mut operands = Vec::new();
+    for (arg_at, arg) in &args {
+        let operand = scalar_operand(arg).ok_or_else(|| {
+            cursor.error(
+                *arg_at,
+                format!("{} takes numbers or cells, not ranges", name),
+            )
+        })?;
+        operands.push(operand);
+    }
+    if operands.len() != arity {
+        let message = format!("{} takes {} arguments, not {}", name, arity, operands.len());
+        return Err(cursor.error(at, message));
+    }
+    let func = CellName::new(stored_function_name(name)).unwrap();
+    Ok((
+        CellData::Func {
+            func,
+            args: operands,
+        },
+        None,
+    ))
+}
+
+/// Returns a number or cell argument as an operand, or `None` for a range.
+fn scalar_operand(arg: &Arg) -> Option<Operand> {
+    match *arg {
+        Arg::Int(value) => Some(Operand::Const(value)),
+        Arg::Ref(cell1) => Some(Operand::Ref(cell1)),
+        Arg::Area(..) => None,
+    }
+}
+
+/// Returns one side of an expression as an operand.
+fn operand(cursor: &Cursor, at: usize, term: Term, op: char) -> Result<Operand, SyntaxError> {
+    match term {
+        Term::Int(value) => Ok(Operand::Const(value)),
+        Term::Ref(cell1) => Ok(Operand::Ref(cell1)),
+        Term::Call(func, args) => match args[..] {
+            [(_, Arg::Area(cell1, cell2))] if func.bytes().all(|b| b.is_ascii_uppercase()) => {
+                Ok(Operand::Range {
+                    func: function_name(cursor, at, &func)?,
+                    cell1,
+                    cell2,
+                })
+            }
+            _ => Err(cursor.error(
+                at,
+                format!(
+                    "{}(...) cannot be used with '{}'; only a function of one range can",
+                    func, op
+                ),
+            )),
+        },
+    }
+}
+
+/// Reads a formula.
+///
+/// # Arguments
+/// * `text` - The formula text, already trimmed (e.g., "A1+5" or "SUM(A1:B3)").
+///
+/// # Returns
+/// * `Result<(CellData, Option<Valtype>), SyntaxError>` - The formula, with the value its
+///   cell takes at once for a constant, text, `SLEEP(<n>)` or an operation starting with a
+///   constant, or what is wrong with the text. When the evaluation context of the thread
+///   is in safe mode, the volatile formulas, `SLEEP`, `RAND()` and `RANDBETWEEN`, are refused.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::syntax::*;
+/// let (data, value) = read_formula("5*B2").unwrap();
+/// assert!(matches!(data, CellData::CoR { op_code: '*', .. }));
+/// assert_eq!(value, Some(Valtype::Int(5)));
+/// let (_, value) = read_formula("\"Größe \"\"m\"\"\"").unwrap();
+/// assert_eq!(value, Some(Valtype::Str(CellText::new("Größe \"m\""))));
+/// assert_eq!(
+///     read_formula("SUM(A1:A5").unwrap_err().to_string(),
+///     "unexpected end of formula at position 10"
+/// );
+/// ```
+pub fn read_formula(text: &str) -> Result<(CellData, Option<Valtype>), SyntaxError> {
+    let (data, value) = read_any_formula(text)?;
+    if data.is_volatile() && EvalContext::current().is_safe() {
+        let name = text.split('(').next().unwrap_or(text).trim();
+        let cursor = Cursor { text, pos: 0 };
+        return Err(cursor.error(0, format!("{} is not allowed in safe mode", name)));
+    }
+    Ok((data, value))
+}
+
+/// Reads a formula as `read_formula` does, whether or not the session is in safe mode.
+fn read_any_formula(text: &str) -> Result<(CellData, Option<Valtype>), SyntaxError> {
+    let mut cursor = Cursor { text, pos: 0 };
+    if cursor.eat('"') {
+        let text = cursor.text()?;
+        if cursor.peek().is_some() {
+            return Err(cursor.unexpected());
+        }
+        return Ok((CellData::Const, Some(Valtype::Str(CellText::new(&text)))));
+    }
+    let (lhs_at, lhs) = cursor.term()?;
+    if cursor.peek().is_none() {
+        return match lhs {
+            Term::Int(value) => Ok((CellData::Const, Some(Valtype::Int(value)))),
+            Term::Ref(cell1) => Ok((CellData::Ref { cell1 }, None)),
+            Term::Call(name, args) => call(&cursor, lhs_at, &name, args),
+        };
+    }
+    let op_code = match cursor.peek() {
+        Some(op @ ('+' | '-' | '*' | '/')) => op,
+        _ => return Err(cursor.unexpected()),
+    };
+    cursor.pos += 1;
+    let (rhs_at, rhs) = cursor.term()?;
+    if cursor.peek().is_some() {
+        return Err(cursor.unexpected());
+    }
+    Ok(match (lhs, rhs) {
+        (Term::Int(value1), Term::Int(value2)) => (
+            CellData::CoC {
+                value1: Valtype::Int(value1),
+                op_code,
+                value2: Valtype::Int(value2),
+            },
+            Some(Valtype::Int(value1)),
+        ),
+        (Term::Int(value1), Term::Ref(cell2)) => (
+            CellData::CoR {
+                op_code,
+                value2: Valtype::Int(value1),
+                cell2,
+            },
+            Some(Valtype::Int(value1)),
+        ),
+        (Term::Ref(cell1), Term::Int(value2)) => (
+            CellData::RoC {
+                op_code,
+                value2: Valtype::Int(value2),
+                cell1,
+            },
+            None,
+        ),
+        (Term::Ref(cell1), Term::Ref(cell2)) => (
+            CellData::RoR {
+                op_code,
+                cell1,
+                cell2,
+            },
+            None,
+        ),
+        (lhs, rhs) => {
+            let lhs = operand(&cursor, lhs_at, lhs, op_code)?;
+            let rhs = operand(&cursor, rhs_at, rhs, op_code)?;
+            (CellData::Expr(Box::new(Expr { op_code, lhs, rhs })), None)
+        }
+    })
+}
@@ -0,0 +1,169 @@
+//! # Terminal Module
+//! This module draws the part of the sheet in view for the command-line interface. The grid is
+//! plain text by default, as the autograder expects. With `color on` it is drawn with ANSI
+//! colors: error values in red, cells whose value changed since the grid was last printed in
+//! bold yellow, and the column and row headers dimmed. A `SPARK(<range>)` cell is printed as
+//! a sparkline of its range rather than as its value.
+use std::collections::HashMap;
+
+use spreadsheet::storage::SheetStorage;
+use spreadsheet::{CellAddr, CellKey, Valtype, utils};
+
+/// Starts red text.
+const RED: &str = "\x1b[31m";
+/// Starts bold yellow text.
+const HIGHLIGHT: &str = "\x1b[1;33m";
+/// Starts dimmed text.
+const DIM: &str = "\x1b[2m";
+/// Ends any of the above.
+const RESET: &str = "\x1b[0m";
+
+/// How the grid is printed, set with the `view` and `color` commands.
+///
+/// # Fields
+/// * `size` - The largest number of rows and columns printed.
+/// * `color` - Whether the grid is printed with ANSI colors.
+/// * `shown` - The values printed last time, by (row, col), to find the cells that changed.
+#[derive(Debug)]
+pub struct View {
+    pub size: (usize, usize),
+    pub color: bool,
+    shown: HashMap<(usize, usize), String>,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View {
+            size: (10, 10),
+            color: false,
+            shown: HashMap::new(),
+        }
+    }
+}
+
+/// Wraps text in an ANSI color, or leaves it as is when `color` is `None`.
+fn paint(text: String, color: Option<&str>) -> String {
+    match color {
+        Some(color) => format!("{}{}{}", color, text, RESET),
+        None => text,
+    }
+}
+
+/// Lays out the part of the spreadsheet grid in view, as `print_sheet` prints it.
+///
+/// Each column is as wide as its longest value, and at least 10 characters. Widths are
+/// counted in terminal columns, so that accented, combined and wide characters line up.
+/// Colors are added around the padded text, so the columns line up either way.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `pointer` - A tuple `(row, col)` indicating the starting position to display.
+/// * `dimension` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `view` - How to print the grid; the values printed are recorded in it.
+///
+/// # Returns
+/// * `String` - The header line and one line per row, each ending in a newline.
+pub fn render_sheet<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    pointer: &(usize, usize),
+    dimension: &(usize, usize),
+    view: &mut View,
+) -> String {
+    let view_rows = dimension.0.saturating_sub(pointer.0).min(view.size.0);
+    let view_cols = dimension.1.saturating_sub(pointer.1).min(view.size.1);
+    let values = if view_rows == 0 || view_cols == 0 {
+        Vec::new()
+    } else {
+        utils::get_range_values(
+            spreadsheet,
+            *dimension,
+            CellAddr::new(pointer.0, pointer.1),
+            CellAddr::new(pointer.0 + view_rows - 1, pointer.1 + view_cols - 1),
+        )
+    };
+    let texts: Vec<Vec<String>> = values
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(j, value)| {
+                    let key = ((pointer.0 + i) * dimension.1 + pointer.1 + j) as CellKey;
+                    // A sparkline is drawn in place of its value
+                    let sparkline = spreadsheet
+                        .get(&key)
+                        .and_then(|cell| utils::sparkline(spreadsheet, *dimension, cell));
+                    match (sparkline, value) {
+                        (Some(line), _) => line,
+                        (None, Valtype::Int(v)) => v.to_string(),
+                        (None, Valtype::Str(s)) => s.to_string(),
+                        (None, Valtype::Err(error)) => error.to_string(),
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = (0..view_cols)
+        .map(|j| {
+            texts
+                .iter()
+                .map(|row| utils::display_width(&row[j]))
+                .fold(10, usize::max)
+        })
+        .collect();
+    let dim = view.color.then_some(DIM);
+
+    let mut out = format!("{:<5}", "");
+    for (j, width) in widths.iter().enumerate() {
+        let mut name = String::new();
+        let mut n = pointer.1 + j + 1;
+        while n > 0 {
+            let rem = (n - 1) % 26;
+            name.insert(0, (b'A' + rem as u8) as char);
+            n = (n - 1) / 26;
+        }
+        out.push_str(&paint(format!("{:>width$}", name, width = width), dim));
+        out.push_str("  ");
+    }
+    out.push('\n');
+    let mut shown = HashMap::with_capacity(view_rows * view_cols);
+    for (i, row) in texts.into_iter().enumerate() {
+        let row_idx = pointer.0 + i;
+        out.push_str(&paint(format!("{:4}", row_idx + 1), dim));
+        out.push_str("  ");
+        for (j, (text, width)) in row.into_iter().zip(&widths).enumerate() {
+            let cell = (row_idx, pointer.1 + j);
+            let color = if !view.color {
+                None
+            } else if matches!(values[i][j], Valtype::Err(_)) {
+                Some(RED)
+            } else if view.shown.get(&cell).is_some_and(|before| *before != text) {
+                Some(HIGHLIGHT)
+            } else {
+                None
+            };
+            out.push_str(&paint(utils::pad_display(&text, *width), color));
+            out.push_str("  ");
+            shown.insert(cell, text);
+        }
+        out.push('\n');
+    }
+    view.shown = shown;
+    out
+}
+
+/// Prints the spreadsheet grid starting from the given position.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `pointer` - A tuple `(row, col)` indicating the starting position to display.
+/// * `dimension` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `view` - How to print the grid; the values printed are recorded in it.
+pub fn print_sheet<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    pointer: &(usize, usize),
+    dimension: &(usize, usize),
+    view: &mut View,
+) {
+    print!("{}", render_sheet(spreadsheet, pointer, dimension, view));
+}
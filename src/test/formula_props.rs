@@ -0,0 +1,125 @@
+//! Property tests for the formula parser.
+//!
+//! Arbitrary text, and text built from the pieces formulas are made of, is assigned to a cell
+//! through the same path the command line uses, which must reject what it cannot parse rather
+//! than panic. Every formula the parser accepts must also read back unchanged: writing it out
+//! with `formula_string` and parsing that text again gives the same formula.
+use std::collections::{HashMap, HashSet};
+
+use proptest::prelude::*;
+
+use spreadsheet::parser::{assign_formula, detect_formula};
+use spreadsheet::utils::{EvalContext, formula_string};
+use spreadsheet::{Cell, CellData, CellKey, Valtype};
+
+const ROWS: usize = 6;
+const COLS: usize = 6;
+
+fn empty_cell() -> Cell {
+    Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    }
+}
+
+fn arb_ref() -> impl Strategy<Value = String> {
+    "\\$?[A-Z]{1,2}\\$?[1-9][0-9]{0,2}"
+}
+
+fn arb_int() -> impl Strategy<Value = String> {
+    any::<i32>().prop_map(|value| value.to_string())
+}
+
+fn arb_op() -> impl Strategy<Value = char> {
+    prop::sample::select(vec!['+', '-', '*', '/'])
+}
+
+/// A range function over one to three areas, e.g. "SUM(A1:B2,$C3:D$4)".
+fn arb_range() -> impl Strategy<Value = String> {
+    (
+        prop::sample::select(vec!["SUM", "AVG", "MAX", "MIN", "STDEV", "COUNT"]),
+        prop::collection::vec((arb_ref(), arb_ref()), 1..4),
+    )
+        .prop_map(|(func, areas)| {
+            let areas: Vec<String> = areas.iter().map(|(a, b)| format!("{}:{}", a, b)).collect();
+            format!("{}({})", func, areas.join(","))
+        })
+}
+
+/// A single-area range function, as allowed on either side of an expression.
+fn arb_range_operand() -> impl Strategy<Value = String> {
+    (
+        prop::sample::select(vec!["SUM", "AVG", "MAX", "MIN", "STDEV", "COUNT"]),
+        arb_ref(),
+        arb_ref(),
+    )
+        .prop_map(|(func, a, b)| format!("{}({}:{})", func, a, b))
+}
+
+/// Formulas of every kind the parser accepts.
+fn arb_formula() -> impl Strategy<Value = String> {
+    let operand = prop_oneof![arb_int(), arb_ref()];
+    prop_oneof![
+        arb_int(),
+        arb_ref(),
+        (operand.clone(), arb_op(), operand.clone())
+            .prop_map(|(a, op, b)| format!("{}{}{}", a, op, b)),
+        arb_range(),
+        (
+            arb_range_operand(),
+            arb_op(),
+            prop_oneof![arb_int(), arb_ref(), arb_range_operand()]
+        )
+            .prop_map(|(a, op, b)| format!("{}{}{}", a, op, b)),
+        (operand.clone(), arb_op(), arb_range_operand())
+            .prop_map(|(a, op, b)| format!("{}{}{}", a, op, b)),
+        (operand.clone(), arb_ref(), arb_ref(), 0u32..5)
+            .prop_map(|(key, a, b, column)| format!("VLOOKUP({},{}:{},{})", key, a, b, column)),
+        operand.clone().prop_map(|a| format!("ABS({})", a)),
+        (
+            prop::sample::select(vec!["MIN2", "MAX2", "MOD", "POW", "ROUND", "FLOOR", "CEIL"]),
+            operand.clone(),
+            operand,
+        )
+            .prop_map(|(func, a, b)| format!("{}({},{})", func, a, b)),
+    ]
+}
+
+/// Text that is mostly not a formula: anything at all, or a jumble of formula characters.
+fn arb_text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        ".{0,24}",
+        "[A-Z$0-9(),:+*/ -]{0,24}",
+        arb_formula(),
+        (arb_formula(), arb_op(), arb_formula()).prop_map(|(a, op, b)| format!("{}{}{}", a, op, b)),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn parser_never_panics(texts in prop::collection::vec((0..ROWS, 0..COLS, arb_text()), 1..12)) {
+        let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+        let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+        let mut is_range = vec![false; ROWS * COLS];
+        // Safe mode rejects SLEEP, which would stall the test for as many seconds as it asks for
+        let context = EvalContext::default();
+        context.set_safe(true);
+        for (row, col, text) in &texts {
+            let _ = context.scope(|| assign_formula(&mut sheet, &mut ranged, &mut is_range, (ROWS, COLS), *row, *col, text));
+        }
+    }
+
+    #[test]
+    fn formulas_round_trip(text in arb_formula()) {
+        let mut cell = empty_cell();
+        detect_formula(&mut cell, &text);
+        prop_assert_ne!(&cell.data, &CellData::Invalid, "{} was not parsed", text);
+
+        let written = formula_string(&cell);
+        let mut reparsed = empty_cell();
+        detect_formula(&mut reparsed, &written);
+        prop_assert_eq!(&reparsed.data, &cell.data, "{} was written as {}", text, written);
+        prop_assert_eq!(formula_string(&reparsed), written);
+    }
+}
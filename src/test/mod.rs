@@ -1,2 +1,6 @@
 #[cfg(test)]
+mod formula_props;
+#[cfg(test)]
+mod oracle;
+#[cfg(test)]
 mod tests;
@@ -1,30 +1,78 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::io;
 use std::io::Write;
-use std::time::Instant;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use spreadsheet::cache::RangeCache;
+use spreadsheet::dump::{diff_lines, dump_lines};
+use spreadsheet::format::{
+    Align, CellFormat, FormatChange, Locale, parse_format_args, parse_locale_args,
+};
+use spreadsheet::graph::dependency_dot;
+use spreadsheet::import::{IMPORT_CHUNK_ROWS, stream_csv};
+use spreadsheet::journal::{is_journaled, is_mutating, journal_start, parse_journal_header};
+use spreadsheet::lint::{LintKind, LintOptions, Severity, lint, parse_lint_args};
+use spreadsheet::lock::LockTable;
+use spreadsheet::names::{NameTable, parse_name_args};
+use spreadsheet::parser::{
+    assign_formula, assign_formulas, clamp_range_formula, cycle_path, detect_formula, eval,
+    fill_formulas, recalc_all, status_detail, update_and_recalc, volatile_cells,
+};
+use spreadsheet::search::{SearchIndex, replace_formulas, replace_word, replacements};
+use spreadsheet::snapshot::ValueSnapshot;
+use spreadsheet::sort::{SortOrder, compare_text, parse_sort_args};
+use spreadsheet::storage::{ColumnStore, SheetStorage, StorageKind};
+use spreadsheet::structure::LineEdit;
+use spreadsheet::syntax::read_formula;
+use spreadsheet::utils::{
+    CancelToken, EvalContext, RAND_MAX, RangeIndex, autototal_formulas, compute, compute_areas,
+    compute_func, compute_range, display_width, draw_random, format_cell_path, formula_string,
+    get_range_values, histogram_bins, move_cell_data, parse_csv_record, parse_histogram_args,
+    parse_resize_args, resize_sheet, shift_cell_data, sleepy, sparkline, to_indices,
+    transitive_dependents, transitive_precedents, truncate_display,
+};
+use spreadsheet::validation::{
+    Constraint, OnFailure, ValidationTable, parse_validate_args, validated_assign,
+};
+use spreadsheet::visits::{MAX_VISITS, VisitHistory};
+use spreadsheet::workload::{chain_sheet, constant_sheet, formula_corpus};
+use spreadsheet::{
+    Cell, CellAddr, CellData, CellError, CellKey, CellName, CellRef, CellText, EvalStatus, Operand,
+    Spreadsheet, Valtype,
+};
 
-use crate::parser::{detect_formula, eval, update_and_recalc};
+use crate::framing::{MAX_FRAME_BYTES, read_frame};
+use crate::machine::{CellSnapshot, status_line};
 use crate::scrolling::{a, d, s, scroll_to, w};
-use crate::utils::{EVAL_ERROR, compute, compute_range, to_indices};
+use crate::serve::serve;
+use crate::terminal::{View, render_sheet};
+use crate::workbook;
 use crate::{
-    Cell, CellData, CellName, STATUS, STATUS_CODE, Valtype, interactive_mode, parse_dimensions,
-    print_sheet, prompt,
+    SessionOptions, import_frame, interactive_mode, parse_dimensions, print_sheet, prompt,
+    run_noted_command, run_script, take_autosave_flag, take_machine_flag, take_open_flag,
+    take_restore_flag, take_safe_flag, take_script_flag, take_serve_flag, take_storage_flag,
+    take_verbosity_flags,
 };
-fn make_sheet(cap: usize) -> HashMap<u32, Cell> {
+
+fn make_sheet(cap: usize) -> HashMap<CellKey, Cell> {
     HashMap::with_capacity(cap)
 }
 
 /// Insert or overwrite one cell in the map.
 fn set_cell(
-    sheet: &mut HashMap<u32, Cell>,
+    sheet: &mut HashMap<CellKey, Cell>,
     total_cols: usize,
     r: usize,
     c: usize,
     data: CellData,
     value: Valtype,
 ) {
-    let key = (r * total_cols + c) as u32;
+    let key = (r * total_cols + c) as CellKey;
     sheet.insert(
         key,
         Cell {
@@ -34,6 +82,52 @@ fn set_cell(
         },
     );
 }
+
+/// A sheet with the tables and session settings kept beside it at the prompt.
+struct ScriptSheet {
+    cells: HashMap<CellKey, Cell>,
+    ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: Vec<bool>,
+    dims: (usize, usize),
+    options: SessionOptions,
+    view: (usize, usize),
+}
+
+impl ScriptSheet {
+    /// An empty sheet of `rows` by `cols` cells, with the sheet not printed after commands.
+    fn new(rows: usize, cols: usize) -> Self {
+        ScriptSheet {
+            cells: make_sheet(rows * cols),
+            ranged: HashMap::new(),
+            is_range: vec![false; rows * cols],
+            dims: (rows, cols),
+            options: SessionOptions {
+                enable_output: false,
+                ..SessionOptions::default()
+            },
+            view: (0, 0),
+        }
+    }
+
+    /// Runs commands one after another, as typed at the prompt, and returns the status of each.
+    fn run(&mut self, cmds: &[&str]) -> Vec<Result<(), EvalStatus>> {
+        let (start_row, start_col) = &mut self.view;
+        cmds.iter()
+            .map(|cmd| {
+                run_noted_command(
+                    &mut self.cells,
+                    &mut self.ranged,
+                    &mut self.is_range,
+                    cmd,
+                    self.dims,
+                    &mut self.options,
+                    &mut (&mut *start_row, &mut *start_col),
+                )
+            })
+            .collect()
+    }
+}
+
 #[test]
 fn test_detect_formula_various_types() {
     let mut cell = Cell {
@@ -43,49 +137,37 @@ fn test_detect_formula_various_types() {
     };
 
     // Test SLEEP(<int>)
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "SLEEP(5)");
     assert!(matches!(cell.data, CellData::SleepC));
     assert_eq!(cell.value, Valtype::Int(5));
 
     // Test SLEEP(<ref>)
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "SLEEP(A1)");
     if let CellData::SleepR { cell1 } = &cell.data {
-        assert_eq!(cell1.as_str(), "A1");
+        assert_eq!(cell1.to_string(), "A1");
     } else {
         panic!("Expected SleepR, got {:?}", cell.data);
     }
 
     // Test CONSTANT
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "42");
     assert!(matches!(cell.data, CellData::Const));
     assert_eq!(cell.value, Valtype::Int(42));
 
     // Test REFERENCE
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "A1");
     if let CellData::Ref { cell1 } = &cell.data {
-        assert_eq!(cell1.as_str(), "A1");
+        assert_eq!(cell1.to_string(), "A1");
     } else {
         panic!("Expected Ref, got {:?}", cell.data);
     }
 
     // Test CONSTANT_CONSTANT
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "5+3");
-    if let CellData::CoC { op_code, value2 } = &cell.data {
+    if let CellData::CoC {
+        op_code, value2, ..
+    } = &cell.data
+    {
         assert_eq!(*op_code, '+');
         if let Valtype::Int(v) = value2 {
             assert_eq!(*v, 3);
@@ -97,18 +179,16 @@ fn test_detect_formula_various_types() {
     }
 
     // Test RANGE
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "MAX(A1:B2)");
     if let CellData::Range {
         cell1,
         cell2,
         value2,
+        ..
     } = &cell.data
     {
-        assert_eq!(cell1.as_str(), "A1");
-        assert_eq!(cell2.as_str(), "B2");
+        assert_eq!(cell1.to_string(), "A1");
+        assert_eq!(cell2.to_string(), "B2");
         if let Valtype::Str(func) = value2 {
             assert_eq!(func.as_str(), "MAX");
         } else {
@@ -119,9 +199,6 @@ fn test_detect_formula_various_types() {
     }
 
     // Test invalid input
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "INVALID");
     assert!(matches!(cell.data, CellData::Invalid));
 }
@@ -151,10 +228,6 @@ fn test_eval_complex_scenarios() {
         Valtype::Int(20),
     );
     // Test CoR (10 + B2)
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
     set_cell(
         &mut sheet,
         total_cols,
@@ -163,17 +236,13 @@ fn test_eval_complex_scenarios() {
         CellData::CoR {
             op_code: '+',
             value2: Valtype::Int(10),
-            cell2: CellName::new("B2").unwrap(),
+            cell2: CellRef::new("B2").unwrap(),
         },
         Valtype::Int(0), // initial value placeholder
     );
     let result = eval(&sheet, total_rows, total_cols, 2, 0);
-    assert_eq!(result, Valtype::Int(30));
+    assert_eq!(result, Ok(Valtype::Int(30)));
     // Test RoR with out-of-bounds reference
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
     set_cell(
         &mut sheet,
         total_cols,
@@ -181,14 +250,14 @@ fn test_eval_complex_scenarios() {
         0,
         CellData::RoR {
             op_code: '-',
-            cell1: CellName::new("A1").unwrap(),
-            cell2: CellName::new("E6").unwrap(), // Out of bounds
+            cell1: CellRef::new("A1").unwrap(),
+            cell2: CellRef::new("E6").unwrap(), // Out of bounds
         },
         Valtype::Int(0),
     );
 
-    let _ = eval(&sheet, 5, 5, 3, 0);
-    assert_eq!(unsafe { STATUS_CODE }, 1);
+    let result = eval(&sheet, 5, 5, 3, 0);
+    assert_eq!(result, Err(EvalStatus::InvalidRange));
 }
 
 #[test]
@@ -200,32 +269,20 @@ fn test_detect_formula_edge_cases() {
     };
 
     // Test with whitespace
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "  42  ");
     assert!(matches!(cell.data, CellData::Const));
     assert_eq!(cell.value, Valtype::Int(42));
 
     // Test with negative values
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "-42");
     assert!(matches!(cell.data, CellData::Const));
     assert_eq!(cell.value, Valtype::Int(-42));
 
     // Test with invalid formula
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "A1B2");
     assert!(matches!(cell.data, CellData::Invalid));
 
     // Test with empty formula
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "");
     assert!(matches!(cell.data, CellData::Invalid));
 }
@@ -239,11 +296,11 @@ fn test_detect_formula_operations() {
     };
 
     // Test with negative operands
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "-5+3");
-    if let CellData::CoC { op_code, value2 } = &cell.data {
+    if let CellData::CoC {
+        op_code, value2, ..
+    } = &cell.data
+    {
         assert_eq!(*op_code, '+');
         if let Valtype::Int(v) = value2 {
             assert_eq!(*v, 3);
@@ -256,11 +313,11 @@ fn test_detect_formula_operations() {
     }
 
     // Test with division
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "10/2");
-    if let CellData::CoC { op_code, value2 } = &cell.data {
+    if let CellData::CoC {
+        op_code, value2, ..
+    } = &cell.data
+    {
         assert_eq!(*op_code, '/');
         if let Valtype::Int(v) = value2 {
             assert_eq!(*v, 2);
@@ -276,21 +333,21 @@ fn test_detect_formula_operations() {
 #[test]
 fn test_update_and_recalc_complex_cycle() {
     let mut sheet = make_sheet(25); // 5x5 sheet
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 25];
 
     let total_cols = 5;
 
-    let cell_hash_a1 = (0 * total_cols + 0) as u32;
-    let cell_hash_b1 = (1 * total_cols + 0) as u32;
-    let cell_hash_c1 = (2 * total_cols + 0) as u32;
+    let cell_hash_a1 = (0 * total_cols + 0) as CellKey;
+    let cell_hash_b1 = (1 * total_cols + 0) as CellKey;
+    let cell_hash_c1 = (2 * total_cols + 0) as CellKey;
 
     // A1 = B1
     sheet.insert(
         cell_hash_a1,
         Cell {
             data: CellData::Ref {
-                cell1: CellName::new("B1").unwrap(),
+                cell1: CellRef::new("B1").unwrap(),
             },
             value: Valtype::Int(0),
             dependents: {
@@ -306,7 +363,7 @@ fn test_update_and_recalc_complex_cycle() {
         cell_hash_b1,
         Cell {
             data: CellData::Ref {
-                cell1: CellName::new("C1").unwrap(),
+                cell1: CellRef::new("C1").unwrap(),
             },
             value: Valtype::Int(0),
             dependents: {
@@ -322,7 +379,7 @@ fn test_update_and_recalc_complex_cycle() {
         cell_hash_c1,
         Cell {
             data: CellData::Ref {
-                cell1: CellName::new("A1").unwrap(),
+                cell1: CellRef::new("A1").unwrap(),
             },
             value: Valtype::Int(0),
             dependents: {
@@ -335,11 +392,7 @@ fn test_update_and_recalc_complex_cycle() {
 
     let backup = sheet.get(&cell_hash_a1).unwrap().my_clone();
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
-
-    update_and_recalc(
+    let result = update_and_recalc(
         &mut sheet,
         &mut ranged,
         &mut is_range,
@@ -349,7 +402,8 @@ fn test_update_and_recalc_complex_cycle() {
         backup,
     );
 
-    assert_eq!(unsafe { STATUS_CODE }, 3); // Cycle detected
+    assert_eq!(result.status, Err(EvalStatus::CycleDetected));
+    assert!(result.changed.is_empty());
 }
 
 #[test]
@@ -379,17 +433,81 @@ fn test_print_sheet() {
         1,
         2,
         CellData::Empty,
-        Valtype::Str(CellName::new("err").unwrap()),
+        Valtype::Str(CellText::new("err")),
     );
 
     {
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        crate::print_sheet(&sheet, &(0, 0), &(5, 5));
+        crate::print_sheet(&sheet, &(0, 0), &(5, 5), &mut View::default());
         handle.flush().unwrap();
     }
 
-    assert_eq!(unsafe { STATUS_CODE }, 0);
+    // Columns widen to fit their longest value, and the view limits what is shown
+    set_cell(
+        &mut sheet,
+        total_cols,
+        0,
+        1,
+        CellData::Const,
+        Valtype::Int(-1234567890),
+    );
+    let mut view = View::default();
+    view.size = (2, 3);
+    assert_eq!(
+        render_sheet(&sheet, &(0, 0), &(5, 5), &mut view),
+        concat!(
+            "              A            B           C  \n",
+            "   1  1           -1234567890  0           \n",
+            "   2  0           2            err         \n",
+        )
+    );
+    assert_eq!(
+        render_sheet(&sheet, &(4, 4), &(5, 5), &mut view)
+            .lines()
+            .count(),
+        2
+    );
+
+    // In color, errors are red and cells changed since the last print are highlighted
+    view.size = (1, 2);
+    view.color = true;
+    render_sheet(&sheet, &(0, 0), &(5, 5), &mut view);
+    set_cell(
+        &mut sheet,
+        total_cols,
+        0,
+        0,
+        CellData::Const,
+        Valtype::Int(3),
+    );
+    set_cell(
+        &mut sheet,
+        total_cols,
+        0,
+        1,
+        CellData::Const,
+        Valtype::Err(CellError::DivZero),
+    );
+    assert_eq!(
+        render_sheet(&sheet, &(0, 0), &(5, 5), &mut view),
+        concat!(
+            "     \x1b[2m         A\x1b[0m  \x1b[2m         B\x1b[0m  \n",
+            "\x1b[2m   1\x1b[0m  \x1b[1;33m3         \x1b[0m  \x1b[31m#DIV/0!   \x1b[0m  \n",
+        )
+    );
+
+    let mut script = ScriptSheet::new(5, 5);
+    assert_eq!(
+        script.run(&["view 20 4", "view 0 4", "view 20", "view x 4"]),
+        [
+            Ok(()),
+            Err(EvalStatus::InvalidRange),
+            Err(EvalStatus::UnrecognizedCmd),
+            Err(EvalStatus::UnrecognizedCmd)
+        ]
+    );
+    assert_eq!(script.options.view.size, (20, 4));
 }
 
 #[test]
@@ -402,16 +520,10 @@ fn test_parse_dimensions() {
         "10".to_string(),
     ];
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
     let result = crate::parse_dimensions(args_cli);
     assert!(result.is_ok());
     assert_eq!(result.unwrap(), (5, 10));
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
     let result = crate::parse_dimensions(args_invalid);
     assert!(result.is_err());
 }
@@ -425,18 +537,16 @@ fn test_detect_formula_range_functions() {
     };
 
     // Test SUM
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "SUM(A1:B2)");
     if let CellData::Range {
         cell1,
         cell2,
         value2,
+        ..
     } = &cell.data
     {
-        assert_eq!(cell1.as_str(), "A1");
-        assert_eq!(cell2.as_str(), "B2");
+        assert_eq!(cell1.to_string(), "A1");
+        assert_eq!(cell2.to_string(), "B2");
         if let Valtype::Str(func) = value2 {
             assert_eq!(func.as_str(), "SUM");
         } else {
@@ -447,18 +557,16 @@ fn test_detect_formula_range_functions() {
     }
 
     // Test STDEV
-    unsafe {
-        STATUS_CODE = 0;
-    }
     detect_formula(&mut cell, "STDEV(A1:Z9)");
     if let CellData::Range {
         cell1,
         cell2,
         value2,
+        ..
     } = &cell.data
     {
-        assert_eq!(cell1.as_str(), "A1");
-        assert_eq!(cell2.as_str(), "Z9");
+        assert_eq!(cell1.to_string(), "A1");
+        assert_eq!(cell2.to_string(), "Z9");
         if let Valtype::Str(func) = value2 {
             assert_eq!(func.as_str(), "STDEV");
         } else {
@@ -474,19 +582,15 @@ fn test_eval_edge_cases() {
     let sheet = make_sheet(30);
 
     // Eval on empty cell
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
     let result = eval(&sheet, 5, 5, 0, 0);
-    assert_eq!(result, Valtype::Int(0));
+    assert_eq!(result, Ok(Valtype::Int(0)));
 }
 
 #[test]
 fn test_eval_invalid_formula() {
     let mut sheet = make_sheet(4);
     let total_cols = 2;
-    let key = (0 * total_cols + 0) as u32;
+    let key = (0 * total_cols + 0) as CellKey;
 
     sheet.insert(
         key,
@@ -497,21 +601,15 @@ fn test_eval_invalid_formula() {
         },
     );
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     let result = eval(&sheet, 2, 2, 0, 0);
-    assert_eq!(result, Valtype::Int(0));
-    assert_eq!(unsafe { STATUS_CODE }, 2);
+    assert_eq!(result, Err(EvalStatus::UnrecognizedCmd));
 }
 
 #[test]
 fn test_eval_sleep_constant() {
     let mut sheet = make_sheet(4);
     let total_cols = 2;
-    let key = (0 * total_cols + 0) as u32;
+    let key = (0 * total_cols + 0) as CellKey;
 
     sheet.insert(
         key,
@@ -522,16 +620,11 @@ fn test_eval_sleep_constant() {
         },
     );
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     let start = std::time::Instant::now();
     let result = eval(&sheet, 2, 2, 0, 0);
     let elapsed = start.elapsed();
 
-    assert_eq!(result, Valtype::Int(1));
+    assert_eq!(result, Ok(Valtype::Int(1)));
     assert!(
         elapsed.as_millis() >= 900,
         "Sleep should have lasted at least 1 second"
@@ -541,7 +634,7 @@ fn test_eval_sleep_constant() {
 #[test]
 fn test_update_and_recalc_chains() {
     let mut sheet = make_sheet(25);
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 25];
 
     let total_cols = 5;
@@ -565,7 +658,7 @@ fn test_update_and_recalc_chains() {
         CellData::RoC {
             op_code: '+',
             value2: Valtype::Int(1),
-            cell1: CellName::new("A1").unwrap(),
+            cell1: CellRef::new("A1").unwrap(),
         },
         Valtype::Int(0),
     );
@@ -579,7 +672,7 @@ fn test_update_and_recalc_chains() {
         CellData::RoC {
             op_code: '+',
             value2: Valtype::Int(1),
-            cell1: CellName::new("B1").unwrap(),
+            cell1: CellRef::new("B1").unwrap(),
         },
         Valtype::Int(0),
     );
@@ -593,15 +686,15 @@ fn test_update_and_recalc_chains() {
         CellData::RoC {
             op_code: '+',
             value2: Valtype::Int(1),
-            cell1: CellName::new("C1").unwrap(),
+            cell1: CellRef::new("C1").unwrap(),
         },
         Valtype::Int(0),
     );
 
-    let a1 = (0 * total_cols + 0) as u32;
-    let b1 = (0 * total_cols + 1) as u32;
-    let c1 = (0 * total_cols + 2) as u32;
-    let d1 = (0 * total_cols + 3) as u32;
+    let a1 = (0 * total_cols + 0) as CellKey;
+    let b1 = (0 * total_cols + 1) as CellKey;
+    let c1 = (0 * total_cols + 2) as CellKey;
+    let d1 = (0 * total_cols + 3) as CellKey;
 
     sheet.get_mut(&a1).unwrap().dependents.insert(b1);
     sheet.get_mut(&b1).unwrap().dependents.insert(c1);
@@ -609,15 +702,14 @@ fn test_update_and_recalc_chains() {
 
     let backup = sheet.get(&a1).unwrap().my_clone();
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
-
     sheet.get_mut(&a1).unwrap().data = CellData::Const;
     sheet.get_mut(&a1).unwrap().value = Valtype::Int(10);
 
-    update_and_recalc(&mut sheet, &mut ranged, &mut is_range, (5, 5), 0, 0, backup);
+    let result = update_and_recalc(&mut sheet, &mut ranged, &mut is_range, (5, 5), 0, 0, backup);
 
+    assert_eq!(result.status, Ok(()));
+    // The updated cell first, then its dependents in the order they were recalculated
+    assert_eq!(result.changed, [a1, b1, c1, d1]);
     assert_eq!(sheet.get(&a1).unwrap().value, Valtype::Int(10));
     assert_eq!(sheet.get(&b1).unwrap().value, Valtype::Int(11));
     assert_eq!(sheet.get(&c1).unwrap().value, Valtype::Int(12));
@@ -642,9 +734,9 @@ fn test_cellname_functions() {
     let result = CellName::new("ABCDEFGH");
     assert!(result.is_err());
 
-    // Test non-ASCII
-    let result = CellName::new("Ä1");
-    assert!(result.is_err());
+    // Test non-ASCII, limited to 7 bytes
+    assert_eq!(CellName::new("Ä1").unwrap().as_str(), "Ä1");
+    assert!(CellName::new("ÄÖÜß").is_err());
 }
 
 //scrolling.rs
@@ -721,61 +813,59 @@ fn test_invalid_scroll_to() {
 //compute in utils.rs
 #[test]
 fn test_compute_operations_edge_cases() {
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-    assert_eq!(compute(-5, Some('+'), 3), -2);
-    assert_eq!(compute(5, Some('/'), -2), -2);
-    assert_eq!(compute(0, Some('*'), 5), 0);
-    assert_eq!(compute(5, Some('/'), 0), 0); // Division by zero
-    assert!(unsafe { EVAL_ERROR });
-    unsafe {
-        EVAL_ERROR = false;
-    }
-    assert_eq!(compute(5, Some('%'), 3), 0); // Invalid op
-    assert_eq!(unsafe { STATUS_CODE }, 2);
+    assert_eq!(compute(-5, Some('+'), 3), Ok(-2));
+    assert_eq!(compute(5, Some('/'), -2), Ok(-2));
+    assert_eq!(compute(0, Some('*'), 5), Ok(0));
+    assert_eq!(
+        compute(5, Some('/'), 0),
+        Err(EvalStatus::ErrValue(CellError::DivZero))
+    ); // Division by zero
+    assert_eq!(compute(5, Some('%'), 3), Err(EvalStatus::UnrecognizedCmd)); // Invalid op
+}
+
+#[test]
+fn test_compute_overflow() {
+    // Results too large for a cell are errors rather than wrapped around
+    let num = Err(EvalStatus::ErrValue(CellError::Num));
+    assert_eq!(compute(i32::MAX, Some('+'), 1), num);
+    assert_eq!(compute(i32::MIN, Some('-'), 1), num);
+    assert_eq!(compute(65536, Some('*'), 32768), num);
+    assert_eq!(compute(i32::MIN, Some('/'), -1), num);
+    assert_eq!(compute(i32::MAX, Some('+'), 0), Ok(i32::MAX));
+    assert_eq!(compute(i32::MIN, Some('/'), 1), Ok(i32::MIN));
 }
 
 //to_indices in utils
 #[test]
 fn test_to_indices_function() {
-    unsafe {
-        STATUS_CODE = 0;
-    }
-    let (row, col) = to_indices("A1");
+    let (row, col) = to_indices("A1").unwrap();
     assert_eq!(row, 0);
     assert_eq!(col, 0);
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
-    let (row, col) = to_indices("Z26");
+    let (row, col) = to_indices("Z26").unwrap();
     assert_eq!(row, 25);
     assert_eq!(col, 25);
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
-    let (row, col) = to_indices("AA1");
+    let (row, col) = to_indices("AA1").unwrap();
     assert_eq!(row, 0);
     assert_eq!(col, 26);
 
-    unsafe {
-        STATUS_CODE = 0;
-    }
-    let (row, col) = to_indices("BC45");
+    let (row, col) = to_indices("BC45").unwrap();
     assert_eq!(row, 44);
     assert_eq!(col, 54); // B=2, C=3 -> BC = 2*26 + 3 = 55, so 54 zero-indexed
 
     // Test invalid indices
-    unsafe {
-        STATUS_CODE = 0;
-    }
-    let (row, col) = to_indices("A0");
-    assert_eq!(row, 0);
-    assert_eq!(col, 0);
-    assert_eq!(unsafe { STATUS_CODE }, 1);
+    assert_eq!(to_indices("A0"), Err(EvalStatus::InvalidRange));
+    assert_eq!(to_indices("1A"), Err(EvalStatus::InvalidRange));
+    // A column too far to count, or written with other characters, is an error, not a panic
+    assert_eq!(
+        to_indices("ZZZZZZZZZZZZZZZZ1"),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(to_indices("a1"), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(to_indices("A-1"), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(to_indices("#1"), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(to_indices("$B$2"), Ok((1, 1)));
 }
 
 // Test for eval with CoC error case (lines 234-237)
@@ -791,20 +881,15 @@ fn test_eval_coc_error() {
         0,
         0,
         CellData::CoC {
+            value1: Valtype::Err(CellError::DivZero),
             op_code: '+',
             value2: Valtype::Int(5),
         },
-        Valtype::Str(CellName::new("ERR").unwrap()),
+        Valtype::Err(CellError::DivZero),
     );
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     let result = eval(&sheet, 1, 1, 0, 0);
-    assert_eq!(result, Valtype::Str(CellName::new("ERR").unwrap()));
-    assert!(unsafe { EVAL_ERROR });
+    assert_eq!(result, Ok(Valtype::Err(CellError::DivZero)));
 }
 
 // Test for eval with RoR both references valid (lines 255-258)
@@ -841,19 +926,14 @@ fn test_eval_ror_valid() {
         0,
         CellData::RoR {
             op_code: '/',
-            cell1: CellName::new("A1").unwrap(),
-            cell2: CellName::new("B1").unwrap(),
+            cell1: CellRef::new("A1").unwrap(),
+            cell2: CellRef::new("B1").unwrap(),
         },
         Valtype::Int(0),
     );
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     let result = eval(&sheet, 2, 2, 1, 0);
-    assert_eq!(result, Valtype::Int(4));
+    assert_eq!(result, Ok(Valtype::Int(4)));
 }
 
 // Test for detect_formula with invalid CONSTANT_CONSTANT (line 150, 152)
@@ -934,29 +1014,25 @@ fn test_eval_coc_div_zero() {
         0,
         0,
         CellData::CoC {
+            value1: Valtype::Int(5),
             op_code: '/',
             value2: Valtype::Int(0),
         },
         Valtype::Int(5),
     );
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
     let result = eval(&sheet, 1, 1, 0, 0);
-    assert_eq!(result, Valtype::Str(CellName::new("ERR").unwrap()));
-    assert!(unsafe { EVAL_ERROR });
+    assert_eq!(result, Ok(Valtype::Err(CellError::DivZero)));
 }
 #[test]
 fn test_update_and_recalc_roc_addition_out_of_bounds() {
     let mut sheet = make_sheet(2);
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 25];
 
     let cell_data = CellData::RoC {
         op_code: '+',
         value2: Valtype::Int(5),
-        cell1: CellName::new("C1").unwrap(), // Out of bounds
+        cell1: CellRef::new("C1").unwrap(), // Out of bounds
     };
     let backup = Cell {
         value: Valtype::Int(0),
@@ -964,19 +1040,19 @@ fn test_update_and_recalc_roc_addition_out_of_bounds() {
         dependents: HashSet::new(),
     };
     set_cell(&mut sheet, 2, 0, 0, cell_data, Valtype::Int(0));
-    update_and_recalc(&mut sheet, &mut ranged, &mut is_range, (2, 2), 0, 0, backup);
-    assert_eq!(unsafe { STATUS_CODE }, 1);
+    let result = update_and_recalc(&mut sheet, &mut ranged, &mut is_range, (2, 2), 0, 0, backup);
+    assert_eq!(result.status, Err(EvalStatus::InvalidRange));
 }
 #[test]
 fn test_update_and_recalc_cor_addition_invalid() {
     let mut sheet = make_sheet(2);
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 25];
 
     let cell_data = CellData::CoR {
         op_code: '+',
         value2: Valtype::Int(5),
-        cell2: CellName::new("C1").unwrap(), // Out of bounds
+        cell2: CellRef::new("C1").unwrap(), // Out of bounds
     };
     let backup = Cell {
         value: Valtype::Int(0),
@@ -984,8 +1060,8 @@ fn test_update_and_recalc_cor_addition_invalid() {
         dependents: HashSet::new(),
     };
     set_cell(&mut sheet, 2, 0, 0, cell_data, Valtype::Int(0));
-    update_and_recalc(&mut sheet, &mut ranged, &mut is_range, (2, 2), 0, 0, backup);
-    assert_eq!(unsafe { STATUS_CODE }, 1);
+    let result = update_and_recalc(&mut sheet, &mut ranged, &mut is_range, (2, 2), 0, 0, backup);
+    assert_eq!(result.status, Err(EvalStatus::InvalidRange));
 }
 #[test]
 fn test_eval_sleepr_invalid_ref() {
@@ -996,17 +1072,12 @@ fn test_eval_sleepr_invalid_ref() {
         0,
         0,
         CellData::SleepR {
-            cell1: CellName::new("A10").unwrap(),
+            cell1: CellRef::new("A10").unwrap(),
         },
         Valtype::Int(0),
     );
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
     let result = eval(&sheet, 1, 1, 0, 0);
-    assert_eq!(result, Valtype::Int(0));
-    assert_eq!(unsafe { STATUS_CODE }, 1);
+    assert_eq!(result, Err(EvalStatus::InvalidRange));
 }
 #[test]
 fn test_eval_range_unrecognized_func() {
@@ -1017,31 +1088,27 @@ fn test_eval_range_unrecognized_func() {
         0,
         0,
         CellData::Range {
-            cell1: CellName::new("A1").unwrap(),
-            cell2: CellName::new("A1").unwrap(),
-            value2: Valtype::Str(CellName::new("INVALID").unwrap()),
+            cell1: CellRef::new("A1").unwrap(),
+            cell2: CellRef::new("A1").unwrap(),
+            value2: Valtype::Str(CellText::new("INVALID")),
+            extra: Vec::new(),
         },
         Valtype::Int(0),
     );
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
     let result = eval(&sheet, 1, 1, 0, 0);
-    assert_eq!(result, Valtype::Int(0));
-    assert_eq!(unsafe { STATUS_CODE }, 2);
+    assert_eq!(result, Err(EvalStatus::UnrecognizedCmd));
 }
 
 #[test]
 fn test_interactive_mode() {
     // Initialize data structures with HashMap implementation
-    let mut spreadsheet: HashMap<u32, Cell> = HashMap::with_capacity(1024);
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(1024);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 10000]; // This should probably be larger based on grid size
 
     // Initial view position
     let (mut start_row, mut start_col) = (0, 0);
-    let mut enable_output = true;
+    let mut options = SessionOptions::default();
 
     // Total grid dimensions
     let (total_rows, total_cols) = (100, 100);
@@ -1052,10 +1119,11 @@ fn test_interactive_mode() {
         &spreadsheet,
         &(start_row, start_col),
         &(total_rows, total_cols),
+        &mut options.view,
     );
     prompt(
         start_time.elapsed().as_secs_f64(),
-        STATUS[unsafe { STATUS_CODE }],
+        EvalStatus::message(Ok(())),
     );
 
     // Series of commands to test
@@ -1086,7 +1154,7 @@ fn test_interactive_mode() {
             &mut is_range,
             commands[i].to_string(),
             (total_rows, total_cols),
-            &mut enable_output,
+            &mut options,
             &mut (&mut start_row, &mut start_col),
         ) {
             break;
@@ -1103,42 +1171,28 @@ fn test_compute_range_str_value() {
     let mut sheet = make_sheet(10);
     let total_cols = 5;
 
-    // Set A1 (0,0) to a string value ("ERR")
+    // Set A1 (0,0) to an error value (#DIV/0!)
     set_cell(
         &mut sheet,
         total_cols,
         0,
         0,
         CellData::Empty,
-        Valtype::Str(CellName::new("ERR").unwrap()),
+        Valtype::Err(CellError::DivZero),
     );
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
-    // Compute SUM over A1:A1 (single cell with string)
+    // Compute SUM over A1:A1 (single cell with an error)
     let result = compute_range(&sheet, total_cols, 0, 0, 0, 0, 4); // SUM
-    assert_eq!(result, 0); // Should skip string value
-    assert!(unsafe { EVAL_ERROR }); // Should set EVAL_ERROR
-    assert_eq!(unsafe { STATUS_CODE }, 0);
+    assert_eq!(result, Err(EvalStatus::ErrValue(CellError::DivZero))); // Should propagate the error value
 }
 #[test]
 fn test_compute_range_invalid_choice() {
     let sheet = make_sheet(10);
     let total_cols = 5;
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     // Compute with invalid choice (e.g., 0)
     let result = compute_range(&sheet, total_cols, 0, 1, 0, 1, 0);
-    assert_eq!(result, 0); // Should return 0 for invalid choice
-    assert_eq!(unsafe { STATUS_CODE }, 2); // Should set STATUS_CODE
-    assert!(!unsafe { EVAL_ERROR });
+    assert_eq!(result, Err(EvalStatus::UnrecognizedCmd)); // Should reject invalid choice
 }
 #[test]
 fn test_compute_range_stdev_full() {
@@ -1179,17 +1233,10 @@ fn test_compute_range_stdev_full() {
         Valtype::Int(7),
     ); // B2
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     // Compute STDEV over A1:B2
     let result = compute_range(&sheet, total_cols, 0, 1, 0, 1, 5); // STDEV
     // Expected: Values [1, 3, 5, 7], mean = 4, variance = ((1-4)^2 + (3-4)^2 + (5-4)^2 + (7-4)^2)/4 = (9+1+1+9)/4 = 5, sqrt(5) ≈ 2.236, round to 2
-    assert_eq!(result, 2);
-    assert_eq!(unsafe { STATUS_CODE }, 0);
-    assert!(!unsafe { EVAL_ERROR });
+    assert_eq!(result, Ok(2));
 }
 #[test]
 fn test_compute_range_min() {
@@ -1222,30 +1269,21 @@ fn test_compute_range_min() {
         Valtype::Int(8),
     ); // B1
 
-    unsafe {
-        STATUS_CODE = 0;
-        EVAL_ERROR = false;
-    }
-
     // Compute MIN over A1:B2
     let result = compute_range(&sheet, total_cols, 0, 1, 0, 1, 2); // MIN
-    assert_eq!(result, 0); // Minimum of [10, 5, 8, 0] is 5
-    assert_eq!(unsafe { STATUS_CODE }, 0);
-    assert!(!unsafe { EVAL_ERROR });
+    assert_eq!(result, Ok(0)); // Minimum of [10, 5, 8, 0] is 5
     let result = compute_range(&sheet, total_cols, 0, 1, 0, 1, 3); // AVG
-    assert_eq!(result, 5); // Minimum of [10, 5, 8, 0] is 5
-    assert_eq!(unsafe { STATUS_CODE }, 0);
-    assert!(!unsafe { EVAL_ERROR });
+    assert_eq!(result, Ok(5)); // Minimum of [10, 5, 8, 0] is 5
 }
 
 #[test]
 fn test_interactive_mode_parser_coverage() {
     // Initialize data structures
-    let mut spreadsheet: HashMap<u32, Cell> = HashMap::with_capacity(1024);
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(1024);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 10000];
     let (mut start_row, mut start_col) = (0, 0);
-    let mut enable_output = true;
+    let mut options = SessionOptions::default();
     let (total_rows, total_cols) = (100, 100);
 
     // Commands to cover uncovered lines
@@ -1271,10 +1309,11 @@ fn test_interactive_mode_parser_coverage() {
         &spreadsheet,
         &(start_row, start_col),
         &(total_rows, total_cols),
+        &mut options.view,
     );
     prompt(
         start_time.elapsed().as_secs_f64(),
-        STATUS[unsafe { STATUS_CODE }],
+        EvalStatus::message(Ok(())),
     );
 
     let mut i = 0;
@@ -1285,7 +1324,7 @@ fn test_interactive_mode_parser_coverage() {
             &mut is_range,
             commands[i].to_string(),
             (total_rows, total_cols),
-            &mut enable_output,
+            &mut options,
             &mut (&mut start_row, &mut start_col),
         ) {
             break;
@@ -1300,11 +1339,11 @@ fn test_interactive_mode_parser_coverage() {
 #[test]
 fn test_interactive_mode_full_coverage() {
     // Initialize data structures
-    let mut spreadsheet: HashMap<u32, Cell> = HashMap::with_capacity(1024);
-    let mut ranged: HashMap<u32, Vec<(u32, u32)>> = HashMap::with_capacity(32);
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(1024);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::with_capacity(32);
     let mut is_range: Vec<bool> = vec![false; 10000];
     let (mut start_row, mut start_col) = (0, 0);
-    let mut enable_output = true;
+    let mut options = SessionOptions::default();
     let (total_rows, total_cols) = (100, 100);
 
     // Commands to cover all remaining lines
@@ -1338,10 +1377,11 @@ fn test_interactive_mode_full_coverage() {
         &spreadsheet,
         &(start_row, start_col),
         &(total_rows, total_cols),
+        &mut options.view,
     );
     prompt(
         start_time.elapsed().as_secs_f64(),
-        STATUS[unsafe { STATUS_CODE }],
+        EvalStatus::message(Ok(())),
     );
 
     let mut i = 0;
@@ -1352,7 +1392,7 @@ fn test_interactive_mode_full_coverage() {
             &mut is_range,
             commands[i].to_string(),
             (total_rows, total_cols),
-            &mut enable_output,
+            &mut options,
             &mut (&mut start_row, &mut start_col),
         ) {
             break;
@@ -1366,3 +1406,4280 @@ fn test_interactive_mode_full_coverage() {
     assert_eq!(spreadsheet.get(&2).unwrap().value, Valtype::Int(10)); // A3 = MAX(A1:A2)
     assert_eq!(spreadsheet.get(&202).unwrap().value, Valtype::Int(15)); // C3 = C2+3
 }
+
+#[test]
+fn test_autototal_formulas() {
+    let formulas = autototal_formulas("A1:B2", "avg", 10, 10).unwrap();
+    assert_eq!(
+        formulas,
+        vec![
+            (2, 0, "AVG(A1:A2)".to_string()),
+            (2, 1, "AVG(B1:B2)".to_string()),
+            (0, 2, "AVG(A1:B1)".to_string()),
+            (1, 2, "AVG(A2:B2)".to_string()),
+            (2, 2, "AVG(A1:B2)".to_string()),
+        ]
+    );
+    // Reversed corners are normalized
+    assert_eq!(autototal_formulas("B2:A1", "sum", 10, 10).unwrap().len(), 5);
+    // Totals would fall outside the sheet
+    assert_eq!(
+        autototal_formulas("A1:B3", "sum", 3, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        autototal_formulas("A1:B0", "sum", 10, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        autototal_formulas("A1:B2", "median", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+}
+
+#[test]
+fn test_interactive_mode_autototal() {
+    let mut sheet = ScriptSheet::new(10, 10);
+    assert_eq!(
+        sheet.run(&["A1=1", "B1=2", "A2=3", "B2=4", "autototal A1:B2", "A1=11"]),
+        [Ok(()); 6]
+    );
+    let value = |r: usize, c: usize| {
+        sheet
+            .cells
+            .get(&((r * 10 + c) as CellKey))
+            .unwrap()
+            .value
+            .clone()
+    };
+    assert_eq!(value(2, 0), Valtype::Int(14)); // A3 = SUM(A1:A2)
+    assert_eq!(value(2, 1), Valtype::Int(6)); // B3 = SUM(B1:B2)
+    assert_eq!(value(0, 2), Valtype::Int(13)); // C1 = SUM(A1:B1)
+    assert_eq!(value(1, 2), Valtype::Int(7)); // C2 = SUM(A2:B2)
+    assert_eq!(value(2, 2), Valtype::Int(20)); // C3 = SUM(A1:B2)
+
+    assert_eq!(
+        sheet.run(&["autototal A1:J1"]),
+        [Err(EvalStatus::InvalidRange)]
+    );
+
+    // The totals are checked against the rules, and a rejected total leaves all of them out
+    let mut sheet = ScriptSheet::new(10, 10);
+    assert_eq!(
+        sheet.run(&[
+            "A1=5",
+            "B1=6",
+            "validate C1:C1 int 0..10",
+            "autototal A1:B1"
+        ]),
+        [Ok(()), Ok(()), Ok(()), Err(EvalStatus::ValidationFailed)]
+    );
+    let formula = |key: CellKey| {
+        sheet
+            .cells
+            .get(&key)
+            .map(formula_string)
+            .unwrap_or_default()
+    };
+    assert_eq!(formula(10), ""); // A2
+    assert_eq!(formula(2), ""); // C1
+}
+
+#[test]
+fn test_histogram_bins_and_args() {
+    assert_eq!(
+        histogram_bins(&[1, 2, 2, 9, -3], 3),
+        vec![(-3, 1, 2), (2, 6, 2), (7, 11, 1)]
+    );
+    assert_eq!(histogram_bins(&[], 2), vec![(0, 0, 0), (1, 1, 0)]);
+
+    let spec = parse_histogram_args("B5:A1 -> C1 chart", 20, 10).unwrap();
+    assert_eq!(spec.source, ((0, 0), (4, 1)));
+    assert_eq!((spec.bins, spec.target, spec.chart), (10, (0, 2), true));
+    // The table would run off the bottom or right edge of the sheet
+    assert_eq!(
+        parse_histogram_args("A1:A5 bins=3 -> C9", 10, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        parse_histogram_args("A1:A5 -> I1", 20, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        parse_histogram_args("A1:A5 bins=0 -> C1", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        parse_histogram_args("A1:A5 C1", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+}
+
+#[test]
+fn test_interactive_mode_histogram() {
+    let mut sheet = ScriptSheet::new(10, 10);
+    assert_eq!(
+        sheet.run(&[
+            "A1=1",
+            "A2=5",
+            "A3=6",
+            "A4=10",
+            "histogram A1:A5 bins=2 -> C1"
+        ]),
+        [Ok(()); 5]
+    );
+    let value = |r: usize, c: usize| {
+        sheet
+            .cells
+            .get(&((r * 10 + c) as CellKey))
+            .unwrap()
+            .value
+            .clone()
+    };
+    // A5 is empty and is not counted
+    assert_eq!(value(0, 2), Valtype::Int(1));
+    assert_eq!(value(0, 3), Valtype::Int(5));
+    assert_eq!(value(0, 4), Valtype::Int(2));
+    assert_eq!(value(1, 2), Valtype::Int(6));
+    assert_eq!(value(1, 3), Valtype::Int(10));
+    assert_eq!(value(1, 4), Valtype::Int(2));
+
+    assert_eq!(
+        sheet.run(&["histogram A1:A5 -> C1 chart"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+
+    // A count breaking a rule leaves the table as it was
+    assert_eq!(
+        sheet.run(&["validate E1:E2 int 0..1", "histogram A1:A5 bins=2 -> C1"]),
+        [Ok(()), Err(EvalStatus::ValidationFailed)]
+    );
+    assert_eq!(
+        sheet.cells.get(&(4 as CellKey)).unwrap().value,
+        Valtype::Int(2)
+    ); // E1
+}
+
+#[test]
+fn test_cycle_rollback_restores_edges() {
+    let mut sheet = make_sheet(16);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 16];
+    let dims = (4, 4);
+    let mut assign = |row: usize, col: usize, formula: &str| {
+        assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            dims,
+            row,
+            col,
+            formula,
+        )
+    };
+    assert_eq!(assign(0, 0, "SUM(B1:B4)"), Ok(()));
+    assert_eq!(assign(0, 2, "B2"), Ok(()));
+    // Both edits close a cycle and must be rolled back with their old edges intact
+    assert_eq!(assign(0, 0, "A1+1"), Err(EvalStatus::CycleDetected));
+    assert_eq!(assign(0, 2, "C1"), Err(EvalStatus::CycleDetected));
+    assert_eq!(assign(1, 1, "7"), Ok(()));
+
+    assert_eq!(sheet.get(&0).unwrap().value, Valtype::Int(7)); // A1 = SUM(B1:B4)
+    assert_eq!(sheet.get(&2).unwrap().value, Valtype::Int(7)); // C1 = B2
+}
+
+#[test]
+fn test_read_frame() {
+    let mut input = io::Cursor::new("1,2\n3,4\n#end\nA1=5\n");
+    assert_eq!(read_frame(&mut input, "#begin csv").unwrap(), b"1,2\n3,4\n");
+    let mut rest = String::new();
+    io::BufRead::read_line(&mut input, &mut rest).unwrap();
+    assert_eq!(rest, "A1=5\n");
+
+    // A length prefix lets the payload contain a line reading #end
+    let mut input = io::Cursor::new("#end\n7#end\n");
+    assert_eq!(read_frame(&mut input, "#begin csv 6").unwrap(), b"#end\n7");
+
+    let mut input = io::Cursor::new("1,2\n");
+    let err = read_frame(&mut input, "#begin csv").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    let mut input = io::Cursor::new("1,2");
+    let err = read_frame(&mut input, "#begin csv 10").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    let err = read_frame(&mut io::Cursor::new(""), "#begin json").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    // Oversized frames are drained so the next command starts on a clean line
+    let mut big = vec![b'1'; MAX_FRAME_BYTES + 1];
+    big.extend_from_slice(b"\n#end\nq\n");
+    let mut input = io::Cursor::new(big);
+    let header = format!("#begin csv {}", MAX_FRAME_BYTES + 1);
+    let err = read_frame(&mut input, &header).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut rest = String::new();
+    io::BufRead::read_line(&mut input, &mut rest).unwrap();
+    assert_eq!(rest, "q\n");
+}
+
+#[test]
+fn test_import_frame() {
+    assert_eq!(
+        parse_csv_record("1, \"=SUM(A1:A2)\",\"a\"\"b\",\r\n"),
+        vec!["1", "=SUM(A1:A2)", "a\"b", ""]
+    );
+
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(16);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 9];
+    let mut options = SessionOptions::default();
+    let mut input = io::Cursor::new("=B1+1,2\n,\"=SUM(A1:B1)\"\n#end\n");
+    let status = import_frame(
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        &mut input,
+        "#begin csv",
+        (3, 3),
+        &mut options,
+    );
+    assert_eq!(status, Some(Ok(())));
+    assert_eq!(spreadsheet.get(&0).unwrap().value, Valtype::Int(3)); // A1 = B1+1
+    assert_eq!(spreadsheet.get(&4).unwrap().value, Valtype::Int(5)); // B2 = SUM(A1:B1)
+
+    // Fields past the sheet edge are reported but the rest still loads
+    let mut input = io::Cursor::new("9,1,1,1\n#end\n");
+    let status = import_frame(
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        &mut input,
+        "#begin csv",
+        (3, 3),
+        &mut options,
+    );
+    assert_eq!(status, Some(Err(EvalStatus::InvalidRange)));
+    assert_eq!(spreadsheet.get(&0).unwrap().value, Valtype::Int(9));
+    assert_eq!(spreadsheet.get(&4).unwrap().value, Valtype::Int(10));
+
+    // Imported cells are checked against the rules, and a rejected field keeps its cell
+    let rule = parse_validate_args("A3:C3 int 0..9", 3, 3).unwrap();
+    options.validations.add(rule, &spreadsheet, 3);
+    let mut input = io::Cursor::new(",,\n,,\n5,12,=B2\n#end\n");
+    let status = import_frame(
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        &mut input,
+        "#begin csv",
+        (3, 3),
+        &mut options,
+    );
+    assert_eq!(status, Some(Err(EvalStatus::ValidationFailed)));
+    assert_eq!(spreadsheet.get(&6).unwrap().value, Valtype::Int(5));
+    for key in [7, 8] {
+        let formula = spreadsheet
+            .get(&key)
+            .map_or_else(String::new, formula_string);
+        assert_eq!(formula, "");
+    }
+
+    let mut input = io::Cursor::new("1,2\n");
+    let status = import_frame(
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        &mut input,
+        "#begin csv",
+        (3, 3),
+        &mut options,
+    );
+    assert_eq!(status, None);
+}
+
+#[test]
+fn test_interactive_mode_open() {
+    let path = std::env::temp_dir().join("spreadsheet_test_open.csv");
+    std::fs::write(&path, "1,=A1+1\n\"=SUM(A1:B1)\",\n").unwrap();
+    let mut sheet = ScriptSheet::new(3, 3);
+    let open = format!("open {}", path.display());
+    assert_eq!(sheet.run(&["C3=7", &open]), [Ok(()); 2]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(sheet.cells.get(&1).unwrap().value, Valtype::Int(2)); // B1 = A1+1
+    assert_eq!(sheet.cells.get(&3).unwrap().value, Valtype::Int(3)); // A2 = SUM(A1:B1)
+    assert!(!sheet.cells.contains_key(&8)); // C3 was cleared
+
+    assert_eq!(
+        sheet.run(&["open /nonexistent/sheet.csv"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    assert_eq!(sheet.cells.get(&1).unwrap().value, Valtype::Int(2));
+}
+
+#[test]
+fn test_take_open_flag() {
+    let mut args: Vec<String> = ["prog", "--open", "a.csv", "5", "6"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(take_open_flag(&mut args), Ok(Some("a.csv".to_string())));
+    assert_eq!(parse_dimensions(args), Ok((5, 6)));
+
+    let mut args: Vec<String> = vec!["prog".into(), "5".into(), "6".into()];
+    assert_eq!(take_open_flag(&mut args), Ok(None));
+    let mut args: Vec<String> = vec!["prog".into(), "5".into(), "6".into(), "--open".into()];
+    assert!(take_open_flag(&mut args).is_err());
+}
+
+#[test]
+fn test_workbook_round_trip() {
+    let path = std::env::temp_dir().join("spreadsheet_test_workbook.json");
+    let save = format!("save {}", path.display());
+    let load = format!("load {}", path.display());
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&["A1=2", "B1=A1*3", "C1=SUM(A1:B1)", &save]),
+        [Ok(()); 4]
+    );
+
+    let mut restored = ScriptSheet::new(3, 3);
+    assert_eq!(restored.run(&[&load]), [Ok(())]);
+    assert_eq!(restored.ranged, sheet.ranged);
+    assert_eq!(restored.is_range, sheet.is_range);
+    for (key, cell) in &sheet.cells {
+        let other = restored.cells.get(key).unwrap();
+        assert_eq!((&other.data, &other.value), (&cell.data, &cell.value));
+        assert_eq!(other.dependents, cell.dependents);
+    }
+    // Dependencies still drive recalculation after loading
+    assert_eq!(restored.run(&["A1=5"]), [Ok(())]);
+    assert_eq!(restored.cells.get(&1).unwrap().value, Valtype::Int(15));
+    assert_eq!(restored.cells.get(&2).unwrap().value, Valtype::Int(20));
+
+    // A workbook saved with other dimensions is rejected and leaves the sheet alone
+    let mut small_sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut small_ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut small_is_range = vec![false; 4];
+    assert!(
+        crate::workbook::load_workbook(
+            path.to_str().unwrap(),
+            &mut small_sheet,
+            &mut small_ranged,
+            &mut small_is_range,
+            (2, 2),
+        )
+        .is_err()
+    );
+    assert!(small_sheet.is_empty());
+
+    // Column widths, row heights and cell formats are saved with the sheet
+    let mut sizes = crate::workbook::GridSizes::default();
+    sizes.col_widths.insert(1, 120.0);
+    sizes.row_heights.insert(2, 40.0);
+    let bold = CellFormat {
+        bold: true,
+        ..CellFormat::default()
+    };
+    let formats = vec![(0, 1, bold)];
+    let file = path.to_str().unwrap();
+    let dims = (3, 3);
+    let save = |sizes: &crate::workbook::GridSizes| {
+        crate::workbook::save_workbook(
+            file,
+            &sheet.cells,
+            &sheet.ranged,
+            &sheet.is_range,
+            dims,
+            sizes,
+            &formats,
+        )
+    };
+    assert_eq!(save(&sizes), Ok(()));
+    let mut load = || {
+        crate::workbook::load_workbook(
+            file,
+            &mut restored.cells,
+            &mut restored.ranged,
+            &mut restored.is_range,
+            dims,
+        )
+    };
+    assert_eq!(load(), Ok((sizes.clone(), formats.clone())));
+    // A size for a column outside the sheet is rejected
+    sizes.col_widths.insert(3, 50.0);
+    save(&sizes).unwrap();
+    assert!(load().is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_submission_round_trip() {
+    use crate::submission::{MANIFEST_NAME, Manifest, sha256_hex, write_submission};
+    use crate::workbook::{GridSizes, Workbook, write_workbook};
+    use std::io::{Cursor, Read};
+
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&["A1=2", "B1=A1*3", "C1=SUM(A1:B1)"]),
+        [Ok(()); 3]
+    );
+    let mut workbook_json = Vec::new();
+    write_workbook(
+        &mut workbook_json,
+        &sheet.cells,
+        &sheet.ranged,
+        &sheet.is_range,
+        sheet.dims,
+        &GridSizes::default(),
+        &[],
+    )
+    .unwrap();
+    let files = [
+        ("values.csv", b"2,6,8\n0,0,0\n0,0,0\n".to_vec()),
+        ("workbook.json", workbook_json),
+    ];
+    let mut buffer = Cursor::new(Vec::new());
+    write_submission(&mut buffer, sheet.dims, 3, &files).unwrap();
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer.into_inner())).unwrap();
+    let mut read_file = |name: &str| {
+        let mut bytes = Vec::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        bytes
+    };
+    let manifest: Manifest = serde_json::from_slice(&read_file(MANIFEST_NAME)).unwrap();
+    assert_eq!((manifest.rows, manifest.cols), (3, 3));
+    assert_eq!(manifest.non_empty_cells, 3);
+    assert_eq!(
+        manifest
+            .files
+            .iter()
+            .map(|file| &file.name[..])
+            .collect::<Vec<_>>(),
+        ["values.csv", "workbook.json"]
+    );
+    for (file, (name, contents)) in manifest.files.iter().zip(&files) {
+        let bytes = read_file(name);
+        assert_eq!(&bytes, contents);
+        assert_eq!(file.bytes, bytes.len());
+        assert_eq!(file.sha256, sha256_hex(&bytes));
+    }
+    // The checksum is the usual SHA-256, so a grader can check it with any tool
+    assert_eq!(
+        sha256_hex(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+
+    // The bundled workbook restores the sheet
+    let workbook: Workbook = serde_json::from_slice(&read_file("workbook.json")).unwrap();
+    assert_eq!((workbook.rows, workbook.cols), (3, 3));
+    assert_eq!(workbook.ranged, sheet.ranged);
+    for (key, cell) in &sheet.cells {
+        let other = workbook.cells.get(key).unwrap();
+        assert_eq!((&other.data, &other.value), (&cell.data, &cell.value));
+    }
+}
+
+#[test]
+fn test_interactive_mode_package() {
+    use crate::submission::{MANIFEST_NAME, Manifest};
+    use std::io::Read;
+
+    let path = std::env::temp_dir().join("spreadsheet_test_package.zip");
+    let mut sheet = ScriptSheet::new(2, 2);
+    let package = format!("package {}", path.with_extension("").display());
+    assert_eq!(
+        sheet.run(&["A1=2", "B1=A1*3", "A2=\"hi\"", &package]),
+        [Ok(()); 4]
+    );
+    // ".zip" is added as in the GUI
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+    let mut read_file = |name: &str| {
+        let mut text = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    };
+    assert_eq!(read_file("values.csv"), "2,6\nhi,0\n");
+    assert_eq!(read_file("formulas.csv"), "2,=A1*3\nhi,0\n");
+    let manifest: Manifest = serde_json::from_str(&read_file(MANIFEST_NAME)).unwrap();
+    assert_eq!(manifest.non_empty_cells, 3);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        sheet.run(&["package /nonexistent/dir/out.zip"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+}
+
+#[test]
+fn test_safe_mode() {
+    let mut args: Vec<String> = vec!["prog".into(), "--safe".into(), "5".into(), "6".into()];
+    assert!(take_safe_flag(&mut args));
+    assert_eq!(parse_dimensions(args), Ok((5, 6)));
+
+    let safe = EvalContext::default();
+    safe.set_safe(true);
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    safe.scope(|| {
+        detect_formula(&mut cell, "SLEEP(5)");
+        assert_eq!(cell.data, CellData::Invalid);
+        detect_formula(&mut cell, "SLEEP(A1)");
+        assert_eq!(cell.data, CellData::Invalid);
+        // The random functions are volatile too, so they are refused as well
+        detect_formula(&mut cell, "RAND()");
+        assert_eq!(cell.data, CellData::Invalid);
+        detect_formula(&mut cell, "RANDBETWEEN(1,6)");
+        assert_eq!(cell.data, CellData::Invalid);
+        assert_eq!(
+            read_formula("RAND()").unwrap_err().to_string(),
+            "RAND is not allowed in safe mode at position 1"
+        );
+        detect_formula(&mut cell, "A1+1");
+        assert!(matches!(cell.data, CellData::RoC { .. }));
+    });
+    // Safe mode belongs to the session that asked for it, not to the other ones
+    EvalContext::default().scope(|| {
+        detect_formula(&mut cell, "SLEEP(5)");
+        assert_eq!(cell.data, CellData::SleepC);
+    });
+
+    // SLEEP cells that already exist, e.g. from a workbook, evaluate without sleeping
+    let mut sheet = make_sheet(4);
+    set_cell(&mut sheet, 2, 0, 0, CellData::SleepC, Valtype::Int(5));
+    let start = Instant::now();
+    safe.scope(|| assert_eq!(eval(&sheet, 2, 2, 0, 0), Ok(Valtype::Int(5))));
+    assert_eq!(sleepy(5, None, &safe), Ok(()));
+    assert!(start.elapsed().as_secs() < 1);
+
+    let mut sheet = Spreadsheet::new(2, 2);
+    sheet.set_safe_mode(true);
+    assert!(sheet.set_formula(CellAddr::new(0, 0), "SLEEP(5)").is_err());
+    assert_eq!(sheet.set_formula(CellAddr::new(0, 0), "7"), Ok(()));
+}
+
+#[test]
+fn test_get_range_values() {
+    assert_eq!(CellAddr::parse("AB10"), Some(CellAddr::new(9, 27)));
+    assert_eq!(CellAddr::parse("A0"), None);
+    assert_eq!(CellAddr::parse("a1"), None);
+    assert_eq!(CellAddr::parse("12"), None);
+
+    let mut sheet = make_sheet(8);
+    let err = Valtype::Err(CellError::DivZero);
+    set_cell(&mut sheet, 3, 0, 0, CellData::Const, Valtype::Int(4));
+    set_cell(&mut sheet, 3, 1, 2, CellData::Invalid, err.clone());
+    // Corners in any order give the same block
+    let block = get_range_values(&sheet, (2, 3), CellAddr::new(1, 2), CellAddr::new(0, 0));
+    assert_eq!(
+        block,
+        vec![
+            vec![Valtype::Int(4), Valtype::Int(0), Valtype::Int(0)],
+            vec![Valtype::Int(0), Valtype::Int(0), err],
+        ]
+    );
+
+    #[cfg(feature = "ndarray")]
+    {
+        let array = spreadsheet::utils::get_range_array(
+            &sheet,
+            (2, 3),
+            CellAddr::new(0, 0),
+            CellAddr::new(1, 2),
+        );
+        assert_eq!(array.dim(), (2, 3));
+        assert_eq!(array[[0, 0]], 4.0);
+        assert!(array[[1, 2]].is_nan());
+    }
+}
+
+#[test]
+fn test_shift_cell_data() {
+    let name = |s: &str| CellRef::new(s).unwrap();
+    let shifted =
+        |s: &str, d_row, d_col| name(s).shifted(d_row, d_col, 10, 10).map(|n| n.to_string());
+    assert_eq!(shifted("A1", 1, 0).as_deref(), Some("A2"));
+    assert_eq!(shifted("B3", -1, 2).as_deref(), Some("D2"));
+    assert_eq!(shifted("$A$1", 4, 4).as_deref(), Some("$A$1"));
+    assert_eq!(shifted("$A1", 1, 1).as_deref(), Some("$A2"));
+    assert_eq!(shifted("A$1", 1, 1).as_deref(), Some("B$1"));
+    assert_eq!(shifted("A1", -1, 0), None);
+    assert_eq!(shifted("J10", 0, 1), None);
+
+    let data = CellData::Range {
+        cell1: name("A1"),
+        cell2: name("$B$2"),
+        value2: Valtype::Str(CellText::new("SUM")),
+        extra: Vec::new(),
+    };
+    assert_eq!(
+        shift_cell_data(&data, 2, 1, 10, 10),
+        Some(CellData::Range {
+            cell1: name("B3"),
+            cell2: name("$B$2"),
+            value2: Valtype::Str(CellText::new("SUM")),
+            extra: Vec::new(),
+        })
+    );
+    assert_eq!(
+        shift_cell_data(&CellData::Const, 5, 5, 1, 1),
+        Some(CellData::Const)
+    );
+
+    // Absolute references evaluate like plain ones
+    let mut sheet = make_sheet(16);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 16];
+    for (col, formula) in [(0, "5"), (1, "$A$1+1"), (2, "SUM($A1:B$1)")] {
+        let status = assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            (4, 4),
+            0,
+            col,
+            formula,
+        );
+        assert_eq!(status, Ok(()));
+    }
+    assert_eq!(sheet.get(&1).unwrap().value, Valtype::Int(6));
+    assert_eq!(sheet.get(&2).unwrap().value, Valtype::Int(11));
+}
+
+#[test]
+fn test_cell_ref_long_names() {
+    let cell = CellRef::new("$ZZZ$999").unwrap();
+    assert_eq!(cell.indices(), (998, 18277));
+    assert_eq!(cell.to_string(), "$ZZZ$999");
+    assert_eq!(CellRef::new("A0000000001").unwrap().indices(), (0, 0));
+    assert!(CellRef::new("A99999999999").is_err());
+    assert!(CellRef::new("A$").is_err());
+    let cell: CellRef = "XFD1048576".parse().unwrap();
+    assert_eq!(cell.indices(), (1048575, 16383));
+
+    let dims = (999, 18278);
+    let mut sheet = make_sheet(16);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; dims.0 * dims.1];
+    let mut assign = |row: usize, col: usize, formula: &str| {
+        assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            dims,
+            row,
+            col,
+            formula,
+        )
+    };
+    assert_eq!(assign(998, 18277, "7"), Ok(()));
+    assert_eq!(assign(0, 0, "$ZZZ$999+1"), Ok(()));
+    assert_eq!(assign(1, 0, "A000000001*2"), Ok(()));
+    assert_eq!(assign(2, 0, "SUM(ZZZ998:$ZZZ$999)"), Ok(()));
+    // Out of the sheet, or too large for any sheet, is reported rather than panicking
+    assert_eq!(assign(3, 0, "ZZZ99999"), Err(EvalStatus::InvalidRange));
+    assert_eq!(
+        assign(3, 0, "A99999999999+1"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        assign(3, 0, "LONGFUNC(A1:A2)"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+
+    assert_eq!(sheet.get(&0).unwrap().value, Valtype::Int(8));
+    assert_eq!(sheet.get(&18278).unwrap().value, Valtype::Int(16));
+    assert_eq!(sheet.get(&(2 * 18278)).unwrap().value, Valtype::Int(7));
+}
+
+#[test]
+fn test_move_cell_data() {
+    let cell = |s: &str| CellRef::new(s).unwrap();
+    let sum = || Valtype::Str(CellText::new("SUM"));
+    // B2:C3 moves to E5
+    let source = ((1, 1), (2, 2));
+    let target = (4, 4);
+
+    let data = CellData::RoR {
+        op_code: '+',
+        cell1: cell("B2"),
+        cell2: cell("A1"),
+    };
+    assert_eq!(
+        move_cell_data(&data, source, target),
+        Some(CellData::RoR {
+            op_code: '+',
+            cell1: cell("E5"),
+            cell2: cell("A1"),
+        })
+    );
+    // Absolute references follow a move too
+    let data = CellData::Ref {
+        cell1: cell("$C$3"),
+    };
+    assert_eq!(
+        move_cell_data(&data, source, target),
+        Some(CellData::Ref {
+            cell1: cell("$F$6"),
+        })
+    );
+    // A range inside the block follows it
+    let data = CellData::Range {
+        cell1: cell("B2"),
+        cell2: cell("C2"),
+        value2: sum(),
+        extra: Vec::new(),
+    };
+    assert_eq!(
+        move_cell_data(&data, source, target),
+        Some(CellData::Range {
+            cell1: cell("E5"),
+            cell2: cell("F5"),
+            value2: sum(),
+            extra: Vec::new(),
+        })
+    );
+    // Ranges that only partially contain the block, or one of its corners, keep their corners
+    for (a, b) in [("A1", "C3"), ("B2", "D2"), ("A3", "B3")] {
+        let data = CellData::Range {
+            cell1: cell(a),
+            cell2: cell(b),
+            value2: sum(),
+            extra: Vec::new(),
+        };
+        assert_eq!(move_cell_data(&data, source, target), None);
+    }
+    // Formulas that do not refer to the block are untouched
+    let data = CellData::RoC {
+        op_code: '*',
+        value2: Valtype::Int(2),
+        cell1: cell("D4"),
+    };
+    assert_eq!(move_cell_data(&data, source, target), None);
+    assert_eq!(move_cell_data(&CellData::Const, source, target), None);
+}
+
+#[test]
+fn test_spreadsheet_api() {
+    let addr = |s: &str| CellAddr::parse(s).unwrap();
+    let mut sheet = Spreadsheet::new(4, 3);
+    assert_eq!(sheet.dimensions(), (4, 3));
+    assert_eq!(sheet.set_formula(addr("A1"), "2"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("A2"), "A1*3"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("B1"), "SUM(A1:A2)"), Ok(()));
+    assert_eq!(sheet.get_value(addr("B1")), Some(Valtype::Int(8)));
+    assert_eq!(sheet.get_formula(addr("A2")), Some("A1*3".to_string()));
+    assert_eq!(sheet.get_value(addr("C4")), Some(Valtype::Int(0)));
+    assert_eq!(sheet.get_formula(addr("C4")), Some(String::new()));
+
+    // Edits recalculate dependents; cycles and cells outside the sheet are reported
+    assert_eq!(sheet.set_formula(addr("A1"), "5"), Ok(()));
+    assert_eq!(sheet.get_value(addr("B1")), Some(Valtype::Int(20)));
+    assert_eq!(
+        sheet.set_formula(addr("A1"), "B1+1"),
+        Err(EvalStatus::CycleDetected)
+    );
+    assert_eq!(
+        sheet.set_formula(addr("D1"), "1"),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        sheet.set_formula(addr("C1"), "A5"),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(sheet.get_value(addr("D1")), None);
+    assert_eq!(sheet.get_formula(addr("A5")), None);
+
+    sheet.recalculate();
+    assert_eq!(sheet.get_value(addr("A1")), Some(Valtype::Int(5)));
+    assert_eq!(sheet.get_value(addr("A2")), Some(Valtype::Int(15)));
+    assert_eq!(sheet.get_value(addr("B1")), Some(Valtype::Int(20)));
+}
+
+#[test]
+fn test_stream_csv_progress_and_cancel() {
+    let rows = IMPORT_CHUNK_ROWS * 2 + 10;
+    let last = IMPORT_CHUNK_ROWS - 1;
+    let mut text = String::from("=B1+1,2\n");
+    for row in 1..rows {
+        if row == last {
+            // The last row of the first chunk refers to the first row of the next
+            text.push_str(&format!("{},=A{}\n", row, row + 2));
+        } else {
+            text.push_str(&format!("{},{}\n", row, row));
+        }
+    }
+    let dims = (rows, 2);
+    let value = |sheet: &HashMap<CellKey, Cell>, r: usize, c: usize| {
+        sheet
+            .get(&((r * 2 + c) as CellKey))
+            .map(|cell| cell.value.clone())
+    };
+
+    // Cancelling after the first chunk keeps exactly the rows read so far
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; rows * 2];
+    let mut reports = Vec::new();
+    let summary = stream_csv(
+        &mut text.as_bytes(),
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        dims,
+        &mut ValidationTable::default(),
+        |progress| {
+            reports.push(progress.rows);
+            false
+        },
+    )
+    .unwrap();
+    assert_eq!(reports, vec![IMPORT_CHUNK_ROWS]);
+    assert!(summary.cancelled);
+    assert_eq!(summary.status, Ok(()));
+    assert_eq!(summary.progress.rows, IMPORT_CHUNK_ROWS);
+    assert_eq!(summary.progress.cells, IMPORT_CHUNK_ROWS * 2);
+    assert_eq!(value(&spreadsheet, 0, 0), Some(Valtype::Int(3))); // A1 = B1+1
+    assert_eq!(value(&spreadsheet, last, 1), Some(Valtype::Int(0)));
+    let next = ((last + 1) * 2) as CellKey;
+    assert_eq!(spreadsheet.get(&next).unwrap().data, CellData::Empty);
+    assert_eq!(spreadsheet.len(), IMPORT_CHUNK_ROWS * 2 + 1);
+
+    // A constant arriving in a cell that is already referred to updates its dependents
+    let mut rest = text.as_bytes();
+    for _ in 0..IMPORT_CHUNK_ROWS {
+        io::BufRead::read_line(&mut rest, &mut String::new()).unwrap();
+    }
+    let mut tail = "\n".repeat(IMPORT_CHUNK_ROWS);
+    tail.push_str(std::str::from_utf8(rest).unwrap());
+    let summary = stream_csv(
+        &mut tail.as_bytes(),
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        dims,
+        &mut ValidationTable::default(),
+        |_| true,
+    )
+    .unwrap();
+    assert!(!summary.cancelled);
+    assert_eq!(summary.progress.rows, rows);
+    assert_eq!(
+        value(&spreadsheet, last, 1),
+        Some(Valtype::Int(last as i32 + 1))
+    );
+    assert_eq!(
+        value(&spreadsheet, rows - 1, 1),
+        Some(Valtype::Int(rows as i32 - 1))
+    );
+
+    // Fields past the sheet edge are reported but the rest still loads
+    let summary = stream_csv(
+        &mut "7,8,9\n".as_bytes(),
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        dims,
+        &mut ValidationTable::default(),
+        |_| true,
+    )
+    .unwrap();
+    assert_eq!(summary.status, Err(EvalStatus::InvalidRange));
+    assert_eq!(value(&spreadsheet, 0, 1), Some(Valtype::Int(8)));
+    assert_eq!(value(&spreadsheet, 0, 0), Some(Valtype::Int(7)));
+}
+
+#[test]
+fn test_run_script() {
+    let mut sheet = ScriptSheet::new(3, 3);
+    sheet.options.enable_output = true;
+
+    let script =
+        "disable_output\nA1=5\n\nB1=A1*2\nZZ9=1\nfoo\nrun other.txt\nC1=SUM(A1:B1)\nq\nA1=0\n";
+    let (start_row, start_col) = &mut sheet.view;
+    let (commands, errors) = run_script(
+        &mut sheet.cells,
+        &mut sheet.ranged,
+        &mut sheet.is_range,
+        io::Cursor::new(script),
+        (3, 3),
+        &mut sheet.options,
+        &mut (start_row, start_col),
+    )
+    .unwrap();
+    assert_eq!(commands, 7);
+    assert_eq!(
+        errors,
+        vec![
+            (5, "ZZ9=1".to_string(), EvalStatus::InvalidRange),
+            (6, "foo".to_string(), EvalStatus::UnrecognizedCmd),
+            (7, "run other.txt".to_string(), EvalStatus::UnrecognizedCmd),
+        ]
+    );
+    assert!(!sheet.options.enable_output);
+    assert_eq!(sheet.cells.get(&2).unwrap().value, Valtype::Int(15)); // C1, and q stopped before A1=0
+
+    // `run` reports the first failure of the script as its own status
+    let path = std::env::temp_dir().join("spreadsheet_test_script.txt");
+    std::fs::write(&path, "A2=7\nA3=1/0\nB2=A4\n").unwrap();
+    let status = sheet.run(&[&format!("run {}", path.display())]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(status, [Err(EvalStatus::InvalidRange)]);
+    assert_eq!(sheet.cells.get(&3).unwrap().value, Valtype::Int(7));
+
+    let mut args: Vec<String> = ["prog", "5", "--script", "t.txt", "6"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(take_script_flag(&mut args), Ok(Some("t.txt".to_string())));
+    assert_eq!(parse_dimensions(args), Ok((5, 6)));
+}
+
+#[test]
+fn test_lint_report() {
+    let (rows, cols) = (30, 4);
+    let mut sheet = make_sheet(64);
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; rows * cols];
+    // A SLEEP constant, set directly so the test does not depend on safe mode
+    set_cell(&mut sheet, cols, 0, 0, CellData::SleepC, Valtype::Int(0));
+    for (cell, formula) in [
+        ("B1", "A1+5"),
+        ("C1", "A1*5"),
+        ("D1", "B9+5"),
+        ("B2", "SUM(A10:B20)"),
+        ("A2", "7"),
+        ("A3", "A2+1"),
+        ("A4", "A3+1"),
+        ("A5", "A4+1"),
+    ] {
+        let (row, col) = to_indices(cell).unwrap();
+        let status = assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            (rows, cols),
+            row,
+            col,
+            formula,
+        );
+        assert_eq!(status, Ok(()), "{}", cell);
+    }
+
+    let options = LintOptions {
+        max_depth: 2,
+        max_range_cells: 20,
+        ..LintOptions::default()
+    };
+    let issues = lint(&sheet, &ranged, &is_range, (rows, cols), &options);
+    let report: Vec<String> = issues.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        report,
+        [
+            "high    A1: SLEEP is read by 2 formulas",
+            "medium  B2: range covers 22 cells",
+            "medium  A5: ends a chain of 3 formulas",
+            "low     B1: constant 5 appears in 3 formulas; consider keeping it in one cell",
+            "low     D1: refers to empty cell B9",
+        ]
+    );
+    assert_eq!(
+        issues[0].kind,
+        LintKind::VolatileInHotPath { dependents: 2 }
+    );
+    assert_eq!(issues[4].severity(), Severity::Low);
+
+    // The default thresholds let the short chain and the small range through
+    let issues = lint(
+        &sheet,
+        &ranged,
+        &is_range,
+        (rows, cols),
+        &parse_lint_args("").unwrap(),
+    );
+    assert_eq!(issues.len(), 3);
+    assert_eq!(parse_lint_args(" 5").unwrap().max_depth, 5);
+    assert_eq!(parse_lint_args("deep"), Err(EvalStatus::UnrecognizedCmd));
+}
+
+#[test]
+fn test_search_index() {
+    let (rows, cols) = (10, 4);
+    let mut sheet = make_sheet(16);
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; rows * cols];
+    let mut assign =
+        |sheet: &mut HashMap<CellKey, Cell>, ranged: &mut _, cell: &str, formula: &str| {
+            let (row, col) = to_indices(cell).unwrap();
+            let status = assign_formula(
+                sheet,
+                ranged,
+                &mut is_range,
+                (rows, cols),
+                row,
+                col,
+                formula,
+            );
+            assert_eq!(status, Ok(()), "{}", cell);
+            (row * cols + col) as CellKey
+        };
+    assign(&mut sheet, &mut ranged, "A1", "12");
+    assign(&mut sheet, &mut ranged, "A2", "A1*10");
+    assign(&mut sheet, &mut ranged, "B1", "SUM(A1:A2)");
+    assign(&mut sheet, &mut ranged, "C1", "B1/0");
+
+    let mut index = SearchIndex::build(&sheet);
+    // Values and formula words match by prefix, ignoring case
+    assert_eq!(index.find("12"), [0, 4]); // A1 = 12, A2 = 120
+    assert_eq!(index.find("sum"), [1]);
+    assert_eq!(index.find("a1"), [1, 4]); // SUM(A1:A2) and A1*10
+    assert_eq!(index.find("#div"), [2]); // C1 = #DIV/0!
+    assert_eq!(index.find("132"), [1]);
+    assert!(index.find("  ").is_empty());
+
+    // Re-indexing an edit also picks up the new values of the cells computed from it
+    let key = assign(&mut sheet, &mut ranged, "A1", "7");
+    index.refresh(key, &sheet, &ranged, cols);
+    assert!(index.find("12").is_empty());
+    assert_eq!(index.find("70"), [4]);
+    assert_eq!(index.find("77"), [1]);
+    assert_eq!(index.find("7"), [0, 1, 4]);
+}
+
+#[test]
+fn test_clamp_ranges() {
+    let clamp = |formula: &str, rows, cols| {
+        clamp_range_formula(formula, rows, cols)
+            .map(|(formula, from, to)| (formula, from.to_string(), to.to_string()))
+    };
+    assert_eq!(
+        clamp("SUM(A1:ZZZ999)", 10, 10),
+        Some(("SUM(A1:J10)".into(), "ZZZ999".into(), "J10".into()))
+    );
+    assert_eq!(
+        clamp("MAX($B$2:C$99)", 10, 3),
+        Some(("MAX($B$2:C$10)".into(), "C$99".into(), "C$10".into()))
+    );
+    // Ranges inside the sheet, ranges starting outside it and other formulas are left alone
+    assert_eq!(clamp("SUM(A1:B2)", 10, 10), None);
+    assert_eq!(clamp("SUM(K1:Z20)", 10, 10), None);
+    assert_eq!(clamp("A1+ZZ99", 10, 10), None);
+
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(sheet.run(&["A1=1", "B3=2"]), [Ok(()); 2]);
+    assert_eq!(
+        sheet.run(&["C1=SUM(A1:B99)"]),
+        [Err(EvalStatus::InvalidRange)]
+    );
+    assert_eq!(sheet.run(&["clamp_ranges on"]), [Ok(())]);
+    assert!(sheet.options.clamp_ranges);
+    assert_eq!(
+        sheet.run(&["C1=SUM(A1:B99)"]),
+        [Err(EvalStatus::RangeClamped)]
+    );
+    assert_eq!(sheet.run(&["clamp_ranges off"]), [Ok(())]);
+    assert_eq!(
+        sheet.run(&["C2=SUM(A1:B99)"]),
+        [Err(EvalStatus::InvalidRange)]
+    );
+    // The rejected formula is not kept, so the cell can be assigned again
+    assert_eq!(sheet.run(&["C2=4"]), [Ok(())]);
+    assert_eq!(
+        EvalStatus::message(Err(EvalStatus::RangeClamped)),
+        "range clamped"
+    );
+
+    let c1 = &sheet.cells[&2];
+    assert_eq!(formula_string(c1), "SUM(A1:B3)");
+    assert_eq!(c1.value, Valtype::Int(3));
+}
+
+#[test]
+fn test_export_snapshot() {
+    let mut sheet = ScriptSheet::new(2, 3);
+    let path = std::env::temp_dir().join("spreadsheet_test_snapshot.csv");
+    assert_eq!(sheet.run(&["A1=4", "B1=A1*2", "C2=1/0"]), [Ok(()); 3]);
+    let command = format!("export_snapshot {}", path.display());
+    assert_eq!(sheet.run(&[&command]), [Ok(())]);
+    // Edits after the command do not reach the file
+    assert_eq!(sheet.run(&["A1=100"]), [Ok(())]);
+    assert_eq!(
+        sheet.run(&["export_snapshot "]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    sheet.options.finish_exports(true);
+    assert!(sheet.options.exports.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "4,8,0\n0,0,#DIV/0!\n"
+    );
+
+    // The file is replaced as a whole, and no temporary file is left behind
+    let snapshot = ValueSnapshot::capture(&sheet.cells, (2, 3));
+    snapshot
+        .export_in_background(path.clone())
+        .join()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "100,200,0\n0,0,#DIV/0!\n"
+    );
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    assert!(!std::path::Path::new(&tmp).exists());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_resize_sheet() {
+    let addr = |s: &str| CellAddr::parse(s).unwrap();
+    let mut sheet = Spreadsheet::new(3, 3);
+    assert_eq!(sheet.set_formula(addr("A1"), "1"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("B2"), "A1+1"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("C1"), "SUM(A1:B2)"), Ok(()));
+
+    // Growing keeps cells in place, with dependencies and ranges still tracked
+    assert_eq!(sheet.resize(5, 4), Ok(()));
+    assert_eq!(sheet.dimensions(), (5, 4));
+    assert_eq!(
+        sheet.get_formula(addr("C1")),
+        Some("SUM(A1:B2)".to_string())
+    );
+    assert_eq!(sheet.set_formula(addr("A1"), "10"), Ok(()));
+    assert_eq!(sheet.get_value(addr("B2")), Some(Valtype::Int(11)));
+    assert_eq!(sheet.get_value(addr("C1")), Some(Valtype::Int(21)));
+    assert_eq!(sheet.set_formula(addr("D5"), "C1*2"), Ok(()));
+    assert_eq!(sheet.get_value(addr("D5")), Some(Valtype::Int(42)));
+
+    // Shrinking is refused while it would drop a cell or leave a reference outside
+    assert_eq!(sheet.resize(4, 4), Err(EvalStatus::InvalidRange));
+    assert_eq!(sheet.resize(5, 2), Err(EvalStatus::InvalidRange));
+    assert_eq!(sheet.resize(0, 4), Err(EvalStatus::InvalidRange));
+    assert_eq!(sheet.dimensions(), (5, 4));
+    assert_eq!(sheet.get_value(addr("D5")), Some(Valtype::Int(42)));
+    let mut small = Spreadsheet::new(4, 4);
+    assert_eq!(small.set_formula(addr("A1"), "D4+1"), Ok(()));
+    assert_eq!(small.resize(3, 3), Err(EvalStatus::InvalidRange));
+    assert_eq!(small.resize(4, 2), Err(EvalStatus::InvalidRange));
+    assert_eq!(small.resize(9, 4), Ok(()));
+
+    assert_eq!(parse_resize_args("10 5"), Ok((10, 5)));
+    assert_eq!(parse_resize_args("10"), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(parse_resize_args("10 x"), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(parse_resize_args("0 5"), Err(EvalStatus::InvalidRange));
+    assert_eq!(parse_resize_args("1000 5"), Err(EvalStatus::InvalidRange));
+}
+
+#[test]
+fn test_edit_lines() {
+    let addr = |s: &str| CellAddr::parse(s).unwrap();
+    let formula = |sheet: &Spreadsheet, s: &str| sheet.get_formula(addr(s));
+    let mut sheet = Spreadsheet::new(4, 3);
+    assert_eq!(sheet.set_formula(addr("A1"), "1"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("A2"), "2"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("A3"), "3"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("B1"), "SUM(A1:A3)"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("C1"), "A$3*2"), Ok(()));
+
+    // Cells past an inserted row move down, and a range spanning it grows
+    assert_eq!(sheet.edit_lines(LineEdit::InsertRow(1)), Ok(()));
+    assert_eq!(sheet.dimensions(), (5, 3));
+    assert_eq!(formula(&sheet, "A3"), Some("2".to_string()));
+    assert_eq!(formula(&sheet, "B1"), Some("SUM(A1:A4)".to_string()));
+    assert_eq!(formula(&sheet, "C1"), Some("A$4*2".to_string()));
+    assert_eq!(sheet.set_formula(addr("A2"), "10"), Ok(()));
+    assert_eq!(sheet.get_value(addr("B1")), Some(Valtype::Int(16)));
+
+    // Deleting a row shrinks the ranges over it, and moves the cells past it back up
+    assert_eq!(sheet.edit_lines(LineEdit::DeleteRow(1)), Ok(()));
+    assert_eq!(sheet.dimensions(), (4, 3));
+    assert_eq!(formula(&sheet, "B1"), Some("SUM(A1:A3)".to_string()));
+    assert_eq!(formula(&sheet, "C1"), Some("A$3*2".to_string()));
+    assert_eq!(sheet.get_value(addr("B1")), Some(Valtype::Int(6)));
+    assert_eq!(sheet.set_formula(addr("A3"), "4"), Ok(()));
+    assert_eq!(sheet.get_value(addr("C1")), Some(Valtype::Int(8)));
+
+    // Columns work the same way
+    let mut sheet = Spreadsheet::new(2, 3);
+    assert_eq!(sheet.set_formula(addr("A1"), "5"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("B2"), "A1+C1"), Ok(()));
+    assert_eq!(sheet.edit_lines(LineEdit::InsertCol(0)), Ok(()));
+    assert_eq!(formula(&sheet, "C2"), Some("B1+D1".to_string()));
+    assert_eq!(sheet.get_value(addr("C2")), Some(Valtype::Int(5)));
+
+    // A row or column a formula refers to on its own cannot be deleted
+    assert_eq!(
+        sheet.edit_lines(LineEdit::DeleteCol(1)),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(sheet.edit_lines(LineEdit::DeleteCol(0)), Ok(()));
+    assert_eq!(formula(&sheet, "B2"), Some("A1+C1".to_string()));
+    assert_eq!(
+        sheet.edit_lines(LineEdit::InsertRow(3)),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(sheet.dimensions(), (2, 3));
+}
+
+#[test]
+fn test_range_index() {
+    // B1=SUM(A1:A3), C1=MAX(A2:A5), D2=MIN(B1:C4) on a 10x10 sheet
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    ranged.insert(1, vec![(0, 20)]);
+    ranged.insert(2, vec![(10, 40)]);
+    ranged.insert(13, vec![(1, 32)]);
+    let index = RangeIndex::build(&ranged, 10);
+    let parents = |key: CellKey| {
+        let mut parents = index.parents(key);
+        parents.sort_unstable();
+        parents
+    };
+    assert_eq!(parents(0), vec![1]);
+    assert_eq!(parents(20), vec![1, 2]);
+    assert_eq!(parents(10), vec![1, 2]);
+    assert_eq!(parents(11), vec![13]);
+    assert_eq!(parents(40), vec![2]);
+    assert_eq!(parents(33), Vec::<CellKey>::new());
+    assert_eq!(parents(99), Vec::<CellKey>::new());
+    assert!(RangeIndex::build(&HashMap::new(), 10).parents(0).is_empty());
+
+    // Many overlapping range formulas still recalculate in dependency order
+    let addr = |s: &str| CellAddr::parse(s).unwrap();
+    let mut sheet = Spreadsheet::new(400, 3);
+    for row in 1..=400 {
+        assert_eq!(sheet.set_formula(addr(&format!("A{}", row)), "1"), Ok(()));
+        let formula = format!("SUM(A1:A{})", row);
+        assert_eq!(
+            sheet.set_formula(addr(&format!("B{}", row)), &formula),
+            Ok(())
+        );
+    }
+    assert_eq!(sheet.set_formula(addr("C1"), "SUM(B1:B400)"), Ok(()));
+    assert_eq!(
+        sheet.get_value(addr("C1")),
+        Some(Valtype::Int(400 * 401 / 2))
+    );
+    let start = Instant::now();
+    assert_eq!(sheet.set_formula(addr("A1"), "2"), Ok(()));
+    assert!(start.elapsed().as_secs() < 5);
+    assert_eq!(sheet.get_value(addr("B400")), Some(Valtype::Int(401)));
+    assert_eq!(
+        sheet.get_value(addr("C1")),
+        Some(Valtype::Int(400 * 401 / 2 + 400))
+    );
+    sheet.recalculate();
+    assert_eq!(
+        sheet.get_value(addr("C1")),
+        Some(Valtype::Int(400 * 401 / 2 + 400))
+    );
+}
+
+#[test]
+fn test_fill_formulas() {
+    assert_eq!(
+        fill_formulas("C1:C3 = A1*$B$1", 10, 10),
+        Ok(vec![
+            (0, 2, "A1*$B$1".to_string()),
+            (1, 2, "A2*$B$1".to_string()),
+            (2, 2, "A3*$B$1".to_string()),
+        ])
+    );
+    assert_eq!(
+        fill_formulas("B2:C2=SUM(A1:A2)", 10, 10),
+        Ok(vec![
+            (1, 1, "SUM(A1:A2)".to_string()),
+            (1, 2, "SUM(B1:B2)".to_string()),
+        ])
+    );
+    assert_eq!(fill_formulas("A1:A3 = 7", 10, 10).map(|f| f.len()), Ok(3));
+    assert_eq!(
+        fill_formulas("A1:A3 B1", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        fill_formulas("A1:A3 = B1+", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        fill_formulas("A1:A30 = B1", 10, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+    // The last copy would read B11, outside the sheet
+    assert_eq!(
+        fill_formulas("A1:A10 = B2", 10, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+
+    let mut sheet = ScriptSheet::new(4, 4);
+    assert_eq!(
+        sheet.run(&[
+            "fill A1:A4 = 3",
+            "fill B1:B4 = A1*2",
+            "D1=SUM(B1:B4)",
+            "A4=10"
+        ]),
+        [Ok(()); 4]
+    );
+    // If one copy would create a cycle, no cell is written
+    assert_eq!(sheet.run(&["fill C1:C4 = D1+1"]), [Ok(())]);
+    assert_eq!(
+        sheet.run(&["fill A1:A4 = $C$1"]),
+        [Err(EvalStatus::CycleDetected)]
+    );
+    assert_eq!(
+        sheet.run(&["fill A2:A3 = B5"]),
+        [Err(EvalStatus::InvalidRange)]
+    );
+    assert_eq!(
+        sheet.run(&["fill A1:A2"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    // An assignment to a range shifts references the same way, and is refused as a whole too
+    assert_eq!(sheet.run(&["C1:C4=D1+1"]), [Ok(())]);
+    assert_eq!(sheet.run(&["A1:A4=$C$1"]), [Err(EvalStatus::CycleDetected)]);
+    assert_eq!(sheet.run(&["A1:A5=3"]), [Err(EvalStatus::InvalidRange)]);
+
+    let value = |key: CellKey| sheet.cells[&key].value.clone();
+    assert_eq!(formula_string(&sheet.cells[&13]), "A4*2");
+    assert_eq!(value(13), Valtype::Int(20));
+    assert_eq!(value(3), Valtype::Int(38));
+    assert_eq!(value(2), Valtype::Int(39));
+    assert_eq!(formula_string(&sheet.cells[&0]), "3");
+    assert_eq!(formula_string(&sheet.cells[&4]), "3");
+    assert_eq!(sheet.cells[&0].dependents, HashSet::from([1]));
+    assert!(sheet.cells[&2].dependents.is_empty());
+    assert_eq!(sheet.cells[&3].dependents, HashSet::from([2]));
+
+    // The first copy is not kept when a later one closes a cycle
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&["B1=7", "B2=A2", "fill A1:A2 = B1"]),
+        [Ok(()), Ok(()), Err(EvalStatus::CycleDetected)]
+    );
+    assert!(
+        sheet
+            .cells
+            .get(&0)
+            .is_none_or(|cell| cell.data == CellData::Empty)
+    );
+
+    // Cells assigned together may read each other, and are evaluated in dependency order
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 9];
+    let formulas = vec![
+        (0, 2, "A1+B1".to_string()),
+        (0, 1, "A1*2".to_string()),
+        (0, 0, "5".to_string()),
+    ];
+    assert_eq!(
+        assign_formulas(&mut sheet, &mut ranged, &mut is_range, (3, 3), &formulas),
+        Ok(())
+    );
+    assert_eq!(sheet[&2].value, Valtype::Int(15));
+}
+
+#[test]
+fn test_range_assignment() {
+    // The rules are checked once every cell of the range is written and recalculated,
+    // whether the range is assigned directly or filled, and a broken rule restores them all
+    let mut sheet = ScriptSheet::new(5, 3);
+    let rejected = Err(EvalStatus::ValidationFailed);
+    assert_eq!(
+        sheet.run(&[
+            "validate A1:A3 int 0..10",
+            "A1:A3=50",
+            "fill A1:A3 = 50",
+            "B1=1",
+            "B2=3"
+        ]),
+        [Ok(()), rejected, rejected, Ok(()), Ok(())]
+    );
+    let formula = |sheet: &ScriptSheet, key| sheet.cells.get(&key).map(formula_string);
+    assert!((0..3).all(|row| formula(&sheet, row * 3).unwrap_or_default().is_empty()));
+    assert_eq!(
+        sheet.run(&["A1:A2=B1*4"]),
+        [Err(EvalStatus::ValidationFailed)]
+    );
+    assert_eq!(formula(&sheet, 0).unwrap_or_default(), "");
+    assert_eq!(formula(&sheet, 3).unwrap_or_default(), "");
+    assert_eq!(sheet.run(&["A1:A2=B1*2"]), [Ok(())]);
+    assert_eq!(sheet.cells[&3].value, Valtype::Int(6));
+
+    // A cell refused for a cycle leaves the cells before it unwritten
+    assert_eq!(
+        sheet.run(&["B3=7", "B4=A4", "fill A3:A4 = B3"]),
+        [Ok(()), Ok(()), Err(EvalStatus::CycleDetected)]
+    );
+    assert_eq!(formula(&sheet, 6).unwrap_or_default(), "");
+
+    // A named range reads the same cells from every cell, and moves with its name
+    assert_eq!(
+        sheet.run(&["name range taxes B1:B2", "C1:C2=SUM(taxes)"]),
+        [Ok(()); 2]
+    );
+    assert_eq!(formula_string(&sheet.cells[&5]), "SUM(B1:B2)");
+    assert_eq!(sheet.cells[&5].value, Valtype::Int(4));
+    assert_eq!(sheet.run(&["name range taxes B2:B2"]), [Ok(())]);
+    assert_eq!(sheet.cells[&2].value, Valtype::Int(3));
+    assert_eq!(sheet.cells[&5].value, Valtype::Int(3));
+
+    // In manual mode the cells are only computed at `recalc`
+    assert_eq!(sheet.run(&["calc manual", "C3:C4=B1+10"]), [Ok(()); 2]);
+    assert_eq!(formula_string(&sheet.cells[&8]), "B1+10");
+    assert_eq!(sheet.cells[&8].value, Valtype::Int(0));
+    assert_eq!(sheet.run(&["recalc"]), [Ok(())]);
+    assert_eq!(sheet.cells[&8].value, Valtype::Int(11));
+    assert_eq!(sheet.cells[&11].value, Valtype::Int(13));
+}
+
+#[test]
+fn test_named_ranges() {
+    let addr = |s: &str| CellAddr::parse(s).unwrap();
+    let mut sheet = Spreadsheet::new(10, 10);
+    for (cell, value) in [("A1", "1"), ("A2", "2"), ("B1", "10"), ("B2", "20")] {
+        assert_eq!(sheet.set_formula(addr(cell), value), Ok(()));
+    }
+    assert_eq!(sheet.define_name("taxes", "A1:A2"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("C1"), "SUM(taxes)"), Ok(()));
+    assert_eq!(sheet.set_formula(addr("C2"), "MAX(TAXES)"), Ok(()));
+    assert_eq!(sheet.get_value(addr("C1")), Some(Valtype::Int(3)));
+    assert_eq!(
+        sheet.get_formula(addr("C1")),
+        Some("SUM(A1:A2)".to_string())
+    );
+    // The named range is tracked like any other range
+    assert_eq!(sheet.set_formula(addr("A2"), "5"), Ok(()));
+    assert_eq!(sheet.get_value(addr("C1")), Some(Valtype::Int(6)));
+
+    // Redefining the name moves the formulas still using it
+    assert_eq!(sheet.set_formula(addr("C2"), "A1+1"), Ok(()));
+    assert_eq!(sheet.define_name("Taxes", "A1:B2"), Ok(()));
+    assert_eq!(
+        sheet.get_formula(addr("C1")),
+        Some("SUM(A1:B2)".to_string())
+    );
+    assert_eq!(sheet.get_value(addr("C1")), Some(Valtype::Int(36)));
+    assert_eq!(sheet.get_formula(addr("C2")), Some("A1+1".to_string()));
+    assert_eq!(sheet.set_formula(addr("B2"), "0"), Ok(()));
+    assert_eq!(sheet.get_value(addr("C1")), Some(Valtype::Int(16)));
+
+    // A redefinition that would make a formula read itself is rejected
+    assert_eq!(
+        sheet.define_name("taxes", "A1:C2"),
+        Err(EvalStatus::CycleDetected)
+    );
+    assert_eq!(
+        sheet.get_formula(addr("C1")),
+        Some("SUM(A1:B2)".to_string())
+    );
+    assert_eq!(sheet.set_formula(addr("D1"), "SUM(taxes)"), Ok(()));
+    assert_eq!(sheet.get_value(addr("D1")), Some(Valtype::Int(16)));
+
+    assert_eq!(
+        sheet.define_name("A1", "A1:A2"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        sheet.define_name("bad-name", "A1:A2"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        sheet.define_name("wide", "A1:Z2"),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        sheet.set_formula(addr("E1"), "SUM(undefined)"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+
+    let names = NameTable::default();
+    assert_eq!(names.resolve("SUM(taxes)"), None);
+    assert_eq!(parse_name_args(" taxes A1:B2 "), Ok(("taxes", "A1:B2")));
+    assert_eq!(parse_name_args("taxes"), Err(EvalStatus::UnrecognizedCmd));
+
+    let mut cli = ScriptSheet::new(4, 4);
+    assert_eq!(
+        cli.run(&[
+            "A1=4",
+            "A2=6",
+            "name range col A1:A2",
+            "B1=AVG(col)",
+            "name range col A1:A1"
+        ]),
+        [Ok(()); 5]
+    );
+    assert_eq!(
+        cli.run(&["name range col"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    assert_eq!(cli.cells[&1].value, Valtype::Int(4));
+    assert_eq!(formula_string(&cli.cells[&1]), "AVG(A1:A1)");
+}
+
+#[test]
+fn test_dependency_audit() {
+    let mut sheet = ScriptSheet::new(4, 4);
+    // A1 -> B1 -> C1=SUM(B1:B2) -> D4, with A2 read only by B2 and A4 unrelated
+    assert_eq!(
+        sheet.run(&[
+            "A1=1",
+            "A2=2",
+            "A4=7",
+            "B1=A1+1",
+            "B2=A2*3",
+            "C1=SUM(B1:B2)",
+            "D4=C1-A1"
+        ]),
+        [Ok(()); 7]
+    );
+    assert_eq!(
+        transitive_dependents(0, &sheet.cells, &sheet.ranged, 4),
+        vec![1, 2, 15]
+    );
+    assert_eq!(
+        transitive_dependents(4, &sheet.cells, &sheet.ranged, 4),
+        vec![2, 5, 15]
+    );
+    assert!(transitive_dependents(15, &sheet.cells, &sheet.ranged, 4).is_empty());
+    assert!(transitive_dependents(12, &sheet.cells, &sheet.ranged, 4).is_empty());
+    assert_eq!(
+        transitive_precedents(15, &sheet.cells, 4),
+        vec![0, 1, 2, 4, 5]
+    );
+    assert_eq!(transitive_precedents(2, &sheet.cells, 4), vec![0, 1, 4, 5]);
+    assert!(transitive_precedents(0, &sheet.cells, 4).is_empty());
+
+    assert_eq!(sheet.run(&["deps A1", "precedents D4"]), [Ok(()); 2]);
+    assert_eq!(sheet.run(&["deps E1"]), [Err(EvalStatus::InvalidRange)]);
+    assert_eq!(
+        sheet.run(&["precedents 1A", "deps ZZZZZZZZZZZZZZZZ1", "deps a1"]),
+        [
+            Err(EvalStatus::InvalidRange),
+            Err(EvalStatus::InvalidRange),
+            Err(EvalStatus::UnrecognizedCmd)
+        ]
+    );
+}
+
+#[test]
+fn test_cycle_path() {
+    let mut sheet = ScriptSheet::new(4, 4);
+    assert_eq!(
+        sheet.run(&["B1=A1+1", "C1=B1*2", "D1=SUM(A1:C1)", "A2=D1"]),
+        [Ok(()); 4]
+    );
+    assert_eq!(sheet.run(&["A1=C1"]), [Err(EvalStatus::CycleDetected)]);
+    assert_eq!(sheet.run(&["A1=A2-1"]), [Err(EvalStatus::CycleDetected)]);
+
+    let path = |r, c, formula: &str| {
+        cycle_path(&sheet.cells, &sheet.ranged, (4, 4), r, c, formula)
+            .map(|path| format_cell_path(&path, 4))
+    };
+    assert_eq!(path(0, 0, "C1"), Some("A1 -> B1 -> C1 -> A1".to_string()));
+    // The shortest loop is reported, here through the range read by D1
+    assert_eq!(path(0, 0, "A2-1"), Some("A1 -> D1 -> A2 -> A1".to_string()));
+    assert_eq!(path(1, 1, "MAX(A1:C2)"), Some("B2 -> B2".to_string()));
+    assert_eq!(path(0, 0, "D4+1"), None);
+    assert_eq!(path(0, 0, "5"), None);
+    assert_eq!(format_cell_path(&[], 4), "");
+}
+
+#[test]
+fn test_status_detail() {
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(25);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 25];
+    assert_eq!(
+        assign_formula(
+            &mut spreadsheet,
+            &mut ranged,
+            &mut is_range,
+            (5, 5),
+            4,
+            0,
+            "A1/E6"
+        ),
+        Err(EvalStatus::InvalidRange)
+    );
+    let detail = |status, r, c, formula: &str| status_detail(status, (5, 5), r, c, formula);
+    assert_eq!(
+        detail(EvalStatus::InvalidRange, 4, 0, "A1/E6").as_deref(),
+        Some("E6 out of bounds in A5=A1/E6")
+    );
+    assert_eq!(
+        detail(EvalStatus::InvalidRange, 0, 1, "SUM($A$1:F$2)").as_deref(),
+        Some("F$2 out of bounds in B1=SUM($A$1:F$2)")
+    );
+    assert_eq!(
+        detail(EvalStatus::InvalidRange, 7, 0, "1").as_deref(),
+        Some("A8 out of bounds")
+    );
+    assert_eq!(
+        detail(EvalStatus::UnrecognizedCmd, 0, 0, "SUM(A1").as_deref(),
+        Some("unexpected end of formula at position 7 in A1=SUM(A1")
+    );
+    assert_eq!(detail(EvalStatus::CycleDetected, 0, 0, "A1"), None);
+}
+
+#[test]
+fn test_syntax_errors() {
+    let error = |formula| read_formula(formula).unwrap_err().to_string();
+    assert_eq!(error("A1+*2"), "unexpected token '*' at position 4");
+    assert_eq!(
+        error("SUM(A1:A5"),
+        "unexpected end of formula at position 10"
+    );
+    assert_eq!(error("FOO(A1)"), "unknown function FOO at position 1");
+    assert_eq!(error("B"), "expected a row number after 'B' at position 2");
+    assert!(error("MOD(A1)").starts_with("MOD takes 2 arguments, not 1"));
+    assert!(matches!(
+        read_formula("SUM(A1:B2)"),
+        Ok((CellData::Range { .. }, None))
+    ));
+    assert!(matches!(
+        read_formula("-3"),
+        Ok((CellData::Const, Some(Valtype::Int(-3))))
+    ));
+}
+
+#[test]
+fn test_text_values() {
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(9);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 9];
+    let mut assign = |r, c, formula: &str| {
+        assign_formula(
+            &mut spreadsheet,
+            &mut ranged,
+            &mut is_range,
+            (3, 3),
+            r,
+            c,
+            formula,
+        )
+    };
+    assert_eq!(assign(0, 0, "\"数量合计数量合计\""), Ok(()));
+    assert_eq!(assign(0, 1, "A1+1"), Ok(()));
+    assert_eq!(assign(0, 2, "\"数量 合计\"\"\""), Ok(()));
+    assert_eq!(assign(1, 0, "\"Große\""), Ok(()));
+    assert_eq!(assign(1, 1, "\"open"), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(
+        spreadsheet[&2].value,
+        Valtype::Str(CellText::new("数量 合计\""))
+    );
+    assert_eq!(formula_string(&spreadsheet[&2]), "\"数量 合计\"\"\"");
+    // Text read as a number is a #VALUE! error
+    assert_eq!(spreadsheet[&1].value, Valtype::Err(CellError::Value));
+    assert_eq!(
+        read_formula("\"open").unwrap_err().to_string(),
+        "text without a closing '\"' at position 1"
+    );
+
+    // Columns are as wide as their text on a terminal, wide characters taking two columns
+    assert_eq!(display_width("数量 合计\""), 10);
+    assert_eq!(display_width("Gro\u{303}ße"), 5);
+    assert_eq!(truncate_display("数量合计", 5), "数量");
+    let mut view = View::default();
+    view.size = (2, 2);
+    assert_eq!(
+        render_sheet(&spreadsheet, &(0, 0), &(3, 3), &mut view),
+        concat!(
+            "                    A           B  \n",
+            "   1  数量合计数量合计  #VALUE!     \n",
+            "   2  Große             0           \n",
+        )
+    );
+    // Range functions skip text, and COUNT leaves it out
+    let mut assign = |r, c, formula: &str| {
+        assign_formula(
+            &mut spreadsheet,
+            &mut ranged,
+            &mut is_range,
+            (3, 3),
+            r,
+            c,
+            formula,
+        )
+        .map(|()| spreadsheet[&((r * 3 + c) as CellKey)].value.clone())
+    };
+    assert_eq!(assign(2, 1, "5"), Ok(Valtype::Int(5)));
+    for (formula, expected) in [
+        ("COUNT(A2:B3)", Valtype::Int(3)),
+        ("SUM(A2:B3)", Valtype::Int(5)),
+        ("AVG(A2:B3)", Valtype::Int(1)),
+        ("MIN(A2:B3)", Valtype::Int(0)),
+        ("MAX(A2:B3)", Valtype::Int(5)),
+        ("STDEV(A2:B3)", Valtype::Int(2)),
+        ("COUNT(A1:A2)", Valtype::Int(0)),
+        ("MAX(A1:A2)", Valtype::Int(0)),
+        ("AVG(A1:A2)", Valtype::Err(CellError::DivZero)),
+        ("STDEV(A1:A2)", Valtype::Err(CellError::DivZero)),
+        ("SUM(A1:B1)", Valtype::Err(CellError::Value)),
+    ] {
+        assert_eq!(assign(2, 2, formula), Ok(expected), "{}", formula);
+    }
+}
+
+#[test]
+fn test_csv_locale() {
+    let format = CellFormat {
+        thousands: true,
+        decimals: 2,
+        ..CellFormat::default()
+    };
+    let shown = format.display(&Valtype::Int(-1234567));
+    assert_eq!(Locale::De.localize(&shown), "-1.234.567,00");
+    assert_eq!(Locale::En.localize(&shown), shown);
+    assert_eq!(
+        (Locale::En.delimiter(), Locale::De.delimiter()),
+        (b',', b';')
+    );
+
+    // Numbers are read back as the locale writes them, and anything else is left alone
+    assert_eq!(Locale::De.read_number("-1.234.567,00"), Some(-1234567));
+    assert_eq!(Locale::En.read_number(&shown), Some(-1234567));
+    assert_eq!(Locale::De.read_number("42"), Some(42));
+    assert_eq!(Locale::De.read_number("1.5"), None);
+    assert_eq!(Locale::De.read_number("1,50"), None);
+    assert_eq!(Locale::De.read_number("12.34.567"), None);
+    assert_eq!(Locale::De.read_number("A1+1"), None);
+
+    assert_eq!(
+        parse_locale_args(" out.csv --locale DE"),
+        Some(("out.csv", Locale::De))
+    );
+    assert_eq!(parse_locale_args("out.csv"), Some(("out.csv", Locale::En)));
+    assert_eq!(parse_locale_args("out.csv --locale fr"), None);
+    assert_eq!(parse_locale_args("--locale de"), None);
+}
+
+#[test]
+fn test_cell_format() {
+    let mut format = CellFormat::default();
+    assert_eq!(format.display(&Valtype::Int(-1234567)), "-1234567");
+    format.apply(FormatChange::Thousands(true));
+    assert_eq!(format.display(&Valtype::Int(-1234567)), "-1,234,567");
+    assert_eq!(format.display(&Valtype::Int(999)), "999");
+    assert_eq!(format.display(&Valtype::Int(i32::MIN)), "-2,147,483,648");
+    format.apply(FormatChange::Decimals(2));
+    assert_eq!(format.display(&Valtype::Int(1000)), "1,000.00");
+    // Error values are shown as they are
+    let err = Valtype::Err(CellError::DivZero);
+    assert_eq!(format.display(&err), "#DIV/0!");
+    format.apply(FormatChange::Clear);
+    assert_eq!(format, CellFormat::default());
+
+    let (corners, changes) = parse_format_args("a1:B5 bold RIGHT decimals=2", 10, 10).unwrap();
+    assert_eq!(corners, ((0, 0), (4, 1)));
+    assert_eq!(
+        changes,
+        vec![
+            FormatChange::Bold(true),
+            FormatChange::Align(Align::Right),
+            FormatChange::Decimals(2)
+        ]
+    );
+    // A single cell is a range of one
+    assert_eq!(
+        parse_format_args("C3 italic", 10, 10),
+        Ok((((2, 2), (2, 2)), vec![FormatChange::Italic(true)]))
+    );
+    assert_eq!(
+        parse_format_args("A1:K1 bold", 10, 10),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        parse_format_args("A1:B2", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        parse_format_args("A1:B2 bold decimals=11", 10, 10),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+}
+
+#[test]
+fn test_verbosity_flags() {
+    let args = |extra: &[&str]| -> Vec<String> {
+        ["prog", "3", "3"]
+            .iter()
+            .chain(extra)
+            .map(|arg| arg.to_string())
+            .collect()
+    };
+    let mut plain = args(&[]);
+    assert_eq!(take_verbosity_flags(&mut plain), Ok(log::LevelFilter::Warn));
+    let mut verbose = args(&["--verbose"]);
+    assert_eq!(
+        take_verbosity_flags(&mut verbose),
+        Ok(log::LevelFilter::Debug)
+    );
+    assert_eq!(parse_dimensions(verbose), Ok((3, 3)));
+    let mut very_verbose = args(&["--verbose", "--verbose"]);
+    assert_eq!(
+        take_verbosity_flags(&mut very_verbose),
+        Ok(log::LevelFilter::Trace)
+    );
+    let mut quiet = args(&["-q"]);
+    assert_eq!(
+        take_verbosity_flags(&mut quiet),
+        Ok(log::LevelFilter::Error)
+    );
+    assert_eq!(parse_dimensions(quiet), Ok((3, 3)));
+    assert!(take_verbosity_flags(&mut args(&["--verbose", "--quiet"])).is_err());
+}
+
+#[test]
+fn test_machine_mode() {
+    let mut args: Vec<String> = vec!["prog".into(), "--machine".into(), "3".into(), "3".into()];
+    assert!(take_machine_flag(&mut args));
+    assert_eq!(parse_dimensions(args), Ok((3, 3)));
+
+    let mut sheet = ScriptSheet::new(3, 3);
+    sheet.options.machine = true;
+    // The cells are those whose value or formula changed, taken as each command is reported
+    let run = |sheet: &mut ScriptSheet, cmd: &str| {
+        let status = sheet.run(&[cmd])[0];
+        (status, sheet.options.take_changed())
+    };
+    assert_eq!(run(&mut sheet, "B1=A1+1"), (Ok(()), vec!["B1".to_string()]));
+    assert_eq!(
+        run(&mut sheet, "A1=2"),
+        (Ok(()), vec!["A1".to_string(), "B1".to_string()])
+    );
+    // The same formula again changes nothing, and neither does a rejected command
+    assert_eq!(run(&mut sheet, "A1=2"), (Ok(()), vec![]));
+    assert_eq!(
+        run(&mut sheet, "A1=B1"),
+        (Err(EvalStatus::CycleDetected), vec![])
+    );
+    assert_eq!(run(&mut sheet, "show"), (Ok(()), vec![]));
+    assert!(sheet.options.show);
+    // A formula with the same value still changes the cell, and so does a cleared one
+    assert_eq!(run(&mut sheet, "C1=B1-1"), (Ok(()), vec!["C1".to_string()]));
+    assert_eq!(run(&mut sheet, "C1=A1"), (Ok(()), vec!["C1".to_string()]));
+    assert_eq!(
+        run(&mut sheet, "clear A1"),
+        (
+            Ok(()),
+            vec!["A1".to_string(), "B1".to_string(), "C1".to_string()]
+        )
+    );
+    // Commands that replace the sheet are compared against a snapshot
+    let path = std::env::temp_dir().join("spreadsheet_test_machine.csv");
+    std::fs::write(&path, "0,1\n").unwrap();
+    let open = format!("open {}", path.display());
+    assert_eq!(
+        run(&mut sheet, &open),
+        (
+            Ok(()),
+            vec!["A1".to_string(), "B1".to_string(), "C1".to_string()]
+        )
+    );
+    std::fs::remove_file(&path).unwrap();
+    let before = CellSnapshot::take(&sheet.cells, 3);
+    assert_eq!(run(&mut sheet, "A2=5"), (Ok(()), vec!["A2".to_string()]));
+    assert_eq!(before.changed(&sheet.cells, 3), vec![(1, 0)]);
+
+    // Redefining a name changes the cells that use it, though it assigns no formula
+    let mut sheet = ScriptSheet::new(3, 3);
+    sheet.options.machine = true;
+    assert_eq!(
+        sheet.run(&["A1=1", "A2=5", "name range n A1:A1", "C1=SUM(n)"]),
+        [Ok(()); 4]
+    );
+    sheet.options.take_changed();
+    assert_eq!(
+        run(&mut sheet, "name range n A1:A2"),
+        (Ok(()), vec!["C1".to_string()])
+    );
+    // A sort that moves nothing changes nothing, and one that does lists the moved cells
+    assert_eq!(run(&mut sheet, "sort A1:A2"), (Ok(()), vec![]));
+    assert_eq!(
+        run(&mut sheet, "sort A1:A2 desc"),
+        (Ok(()), vec!["A1".to_string(), "A2".to_string()])
+    );
+
+    let line: serde_json::Value = serde_json::from_str(&status_line(
+        0.04,
+        Ok(()),
+        &["A1".to_string(), "B1".to_string()],
+        None,
+    ))
+    .unwrap();
+    assert_eq!(
+        line,
+        serde_json::json!({ "elapsed": 0.0, "status": "ok", "changed": ["A1", "B1"] })
+    );
+    let line: serde_json::Value = serde_json::from_str(&status_line(
+        1.26,
+        Err(EvalStatus::CellLocked),
+        &[],
+        Some("A\n"),
+    ))
+    .unwrap();
+    assert_eq!(line["elapsed"], 1.3);
+    assert_eq!(line["status"], "cell locked");
+    assert_eq!(line["view"], "A\n");
+}
+
+#[test]
+fn test_serve() {
+    let mut args: Vec<String> = vec!["prog".into(), "3".into(), "3".into(), "--serve".into()];
+    assert!(take_serve_flag(&mut args));
+    assert_eq!(parse_dimensions(args), Ok((3, 3)));
+
+    let mut sheet = make_sheet(9);
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; 9];
+    let input = concat!(
+        "{\"set\": {\"cell\": \"B1\", \"formula\": \"5\"}}\n",
+        "{\"set\": {\"cell\": \"a1\", \"formula\": \"B1+2\"}}\n",
+        "\n",
+        "{\"get\": \"A1\"}\n",
+        "{\"get\": \"C3\"}\n",
+        "{\"set\": {\"cell\": \"B1\", \"formula\": \"A1\"}}\n",
+        "{\"set\": {\"cell\": \"B1\", \"formula\": \"1/0\"}}\n",
+        "{\"get\": \"A1\"}\n",
+        "{\"get\": \"D1\"}\n",
+        "{\"delete\": \"A1\"}\n",
+    );
+    let mut output = Vec::new();
+    serve(
+        io::Cursor::new(input),
+        &mut output,
+        &mut sheet,
+        &mut ranged,
+        &mut is_range,
+        (3, 3),
+    )
+    .unwrap();
+    let responses: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(responses.len(), 9);
+    assert_eq!(responses[0], serde_json::json!({ "ok": true }));
+    assert_eq!(
+        responses[2],
+        serde_json::json!({ "ok": true, "cell": "A1", "value": 7, "formula": "B1+2" })
+    );
+    assert_eq!(
+        responses[3],
+        serde_json::json!({ "ok": true, "cell": "C3", "value": 0, "formula": "" })
+    );
+    assert_eq!(responses[4]["ok"], false);
+    assert_eq!(responses[5], serde_json::json!({ "ok": true }));
+    assert_eq!(responses[6]["value"], "#DIV/0!");
+    assert_eq!(responses[7]["error"], "Invalid range");
+    assert!(
+        responses[8]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid request")
+    );
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_http_api() {
+    use spreadsheet::collab::Collab;
+    use spreadsheet::server::handle;
+    use std::sync::Mutex;
+
+    let mut args: Vec<String> = vec!["prog".into(), "--port".into(), "8080".into()];
+    assert_eq!(crate::take_port_flag(&mut args), Ok(Some(8080)));
+    assert_eq!(args, vec!["prog".to_string()]);
+    let mut args: Vec<String> = vec!["prog".into(), "--port".into(), "http".into()];
+    assert_eq!(crate::take_port_flag(&mut args), Err("Invalid port"));
+
+    let sheet = Mutex::new(Spreadsheet::new(3, 3));
+    let collab = Mutex::new(Collab::default());
+    let (status, body) = handle(&sheet, &collab, "PUT", "/cells/B1", r#"{"formula": "5"}"#);
+    assert_eq!(status, 200);
+    assert_eq!(body["value"], 5);
+    let (status, body) = handle(
+        &sheet,
+        &collab,
+        "PUT",
+        "/cells/a1",
+        r#"{"formula": "B1+2"}"#,
+    );
+    assert_eq!(
+        (status, body),
+        (
+            200,
+            serde_json::json!({ "cell": "A1", "value": 7, "formula": "B1+2" })
+        )
+    );
+    assert_eq!(
+        handle(&sheet, &collab, "PUT", "/cells/B1", r#"{"formula": "A1"}"#),
+        (422, serde_json::json!({ "error": "cycle detected" }))
+    );
+    handle(&sheet, &collab, "PUT", "/cells/B2", r#"{"formula": "1/0"}"#);
+    assert_eq!(
+        handle(&sheet, &collab, "GET", "/range/A1:B2", ""),
+        (
+            200,
+            serde_json::json!({ "range": "A1:B2", "values": [[7, 5], [0, "#DIV/0!"]] })
+        )
+    );
+    assert_eq!(
+        handle(&sheet, &collab, "POST", "/recalc", ""),
+        (200, serde_json::json!({ "ok": true }))
+    );
+
+    assert_eq!(handle(&sheet, &collab, "GET", "/cells/D1", "").0, 404);
+    assert_eq!(handle(&sheet, &collab, "GET", "/range/A1:D1", "").0, 404);
+    assert_eq!(handle(&sheet, &collab, "PUT", "/cells/A1", "5").0, 400);
+    assert_eq!(handle(&sheet, &collab, "DELETE", "/cells/A1", "").0, 405);
+    assert_eq!(handle(&sheet, &collab, "GET", "/sheets", "").0, 404);
+
+    // Clients see each other's cursors and follow the edits made since a version
+    assert_eq!(
+        handle(&sheet, &collab, "PUT", "/cursors/ann", r#"{"cell": "b2"}"#),
+        (
+            200,
+            serde_json::json!({ "client": "ann", "cell": "B2", "color": 0 })
+        )
+    );
+    handle(&sheet, &collab, "PUT", "/cursors/bob", r#"{"cell": "C3"}"#);
+    let (status, body) = handle(&sheet, &collab, "GET", "/cursors", "");
+    assert_eq!(
+        (status, body["cursors"][1]["color"].as_u64()),
+        (200, Some(1))
+    );
+    assert_eq!(
+        handle(
+            &sheet,
+            &collab,
+            "PUT",
+            "/cursors/a%20b",
+            r#"{"cell": "B2"}"#
+        )
+        .0,
+        400
+    );
+    assert_eq!(
+        handle(&sheet, &collab, "PUT", "/cursors/ann", r#"{"cell": "D1"}"#).0,
+        404
+    );
+    // Only the edits that took are logged: A1, B1 and B2 above
+    let version = handle(&sheet, &collab, "GET", "/formulas", "").1["version"].clone();
+    assert_eq!(version, 3);
+    let (status, body) = handle(
+        &sheet,
+        &collab,
+        "PUT",
+        "/cells/C1",
+        r#"{"formula": "A1*2", "client": "bob"}"#,
+    );
+    assert_eq!((status, &body["value"]), (200, &serde_json::json!(14)));
+    let (status, body) = handle(&sheet, &collab, "GET", "/changes/3", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["version"], 4);
+    assert_eq!(
+        body["changes"],
+        serde_json::json!([{ "version": 4, "cell": "C1", "formula": "A1*2", "client": "bob" }])
+    );
+    assert_eq!(body["cursors"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        handle(&sheet, &collab, "GET", "/formulas", ""),
+        (
+            200,
+            serde_json::json!({ "version": 4, "cells": [
+                { "cell": "A1", "formula": "B1+2" },
+                { "cell": "B1", "formula": "5" },
+                { "cell": "C1", "formula": "A1*2" },
+                { "cell": "B2", "formula": "1/0" },
+            ] })
+        )
+    );
+    // A version the server never reached means it restarted, so the client reads it all again
+    assert_eq!(handle(&sheet, &collab, "GET", "/changes/9", "").0, 410);
+    assert_eq!(handle(&sheet, &collab, "GET", "/changes/x", "").0, 400);
+    assert_eq!(
+        handle(&sheet, &collab, "DELETE", "/cursors/ann", ""),
+        (200, serde_json::json!({ "ok": true }))
+    );
+    assert_eq!(handle(&sheet, &collab, "DELETE", "/cursors/ann", "").0, 404);
+    assert_eq!(handle(&sheet, &collab, "POST", "/cursors", "").0, 405);
+}
+
+#[test]
+fn test_wide_recalc() {
+    // A1 feeds 300 cells of column B, each read by the cell beside it in column C, so one
+    // recalculation has two wide levels
+    let mut sheet = Spreadsheet::new(300, 3);
+    sheet.set_formula(CellAddr::new(0, 0), "1").unwrap();
+    for row in 0..300 {
+        let formula = format!("A1+{}", row);
+        sheet.set_formula(CellAddr::new(row, 1), &formula).unwrap();
+        let formula = format!("B{}*2", row + 1);
+        sheet.set_formula(CellAddr::new(row, 2), &formula).unwrap();
+    }
+    sheet.set_formula(CellAddr::new(0, 0), "10").unwrap();
+    for row in 0..300 {
+        let expected = 10 + row as i32;
+        assert_eq!(
+            sheet.get_value(CellAddr::new(row, 1)),
+            Some(Valtype::Int(expected))
+        );
+        assert_eq!(
+            sheet.get_value(CellAddr::new(row, 2)),
+            Some(Valtype::Int(expected * 2))
+        );
+    }
+    sheet.set_formula(CellAddr::new(0, 0), "1/0").unwrap();
+    assert_eq!(
+        sheet.get_value(CellAddr::new(299, 2)),
+        Some(Valtype::Err(CellError::DivZero))
+    );
+}
+
+#[test]
+fn test_workloads() {
+    let corpus = formula_corpus(64, 20, 5, 3);
+    assert_eq!(corpus.len(), 64);
+    assert_eq!(corpus, formula_corpus(64, 20, 5, 3));
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    for formula in &corpus {
+        detect_formula(&mut cell, formula);
+        assert_ne!(cell.data, CellData::Invalid, "{}", formula);
+    }
+
+    let chain = chain_sheet(50);
+    assert_eq!(chain.total_dims, (50, 1));
+    assert_eq!(chain.sheet[&49].value, Valtype::Int(50));
+
+    assert_eq!(constant_sheet(10, 10, 1, 5).sheet.len(), 100);
+    let sparse = constant_sheet(10, 10, 10, 5);
+    assert!(!sparse.sheet.is_empty() && sparse.sheet.len() < 50);
+}
+
+#[test]
+fn test_column_store() {
+    let mut args: Vec<String> = vec!["prog".into(), "--storage".into(), "columns".into()];
+    assert_eq!(take_storage_flag(&mut args), Ok(StorageKind::Columns));
+    assert_eq!(take_storage_flag(&mut args), Ok(StorageKind::Map));
+    let mut args: Vec<String> = vec!["prog".into(), "--storage".into(), "rows".into()];
+    assert!(take_storage_flag(&mut args).is_err());
+
+    // Both backends give the same result for every function, dense and sparse, on a sheet
+    // taller than one chunk
+    for spacing in [1, 7] {
+        let map = constant_sheet(200, 6, spacing, 11).sheet;
+        let mut columns = ColumnStore::new(6);
+        for (&key, cell) in &map {
+            columns.insert(key, cell.clone());
+        }
+        assert_eq!(SheetStorage::len(&columns), map.len());
+        for (r_min, r_max, c_min, c_max) in [(0, 199, 0, 5), (3, 130, 1, 4), (70, 70, 2, 2)] {
+            for choice in 1..=5 {
+                assert_eq!(
+                    compute_range(&columns, 6, r_min, r_max, c_min, c_max, choice),
+                    compute_range(&map, 6, r_min, r_max, c_min, c_max, choice)
+                );
+            }
+        }
+    }
+
+    let mut columns = ColumnStore::new(3);
+    let cell = |value| Cell {
+        value: Valtype::Int(value),
+        data: CellData::Const,
+        dependents: HashSet::new(),
+    };
+    assert!(columns.insert(3 * 100 + 2, cell(1)).is_none());
+    assert_eq!(
+        columns.insert(3 * 100 + 2, cell(2)).unwrap().value,
+        Valtype::Int(1)
+    );
+    assert_eq!(columns.get(&302).unwrap().value, Valtype::Int(2));
+    assert!(columns.get(&301).is_none() && columns.get(&9999).is_none());
+    assert_eq!(columns.remove(&302).unwrap().value, Valtype::Int(2));
+    assert!(columns.is_empty() && columns.cells().next().is_none());
+
+    // The engine runs on either backend, including through a resize that re-keys every cell
+    let mut sheet = StorageKind::Columns.create(3);
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; 9];
+    for (row, col, formula) in [(0, 0, "5"), (1, 1, "A1*2"), (2, 2, "SUM(A1:B2)")] {
+        assert_eq!(
+            assign_formula(
+                &mut sheet,
+                &mut ranged,
+                &mut is_range,
+                (3, 3),
+                row,
+                col,
+                formula
+            ),
+            Ok(())
+        );
+    }
+    assert_eq!(
+        resize_sheet(&mut sheet, &mut ranged, &mut is_range, (3, 3), (4, 5)),
+        Ok(())
+    );
+    assert_eq!(
+        assign_formula(&mut sheet, &mut ranged, &mut is_range, (4, 5), 0, 0, "7"),
+        Ok(())
+    );
+    assert_eq!(sheet.get(&6).unwrap().value, Valtype::Int(14));
+    assert_eq!(sheet.get(&12).unwrap().value, Valtype::Int(21));
+}
+
+#[test]
+fn test_find_replace() {
+    // Only whole words are replaced, ignoring case
+    assert_eq!(
+        replace_word("A1+A10", "a1", "b2"),
+        Some("B2+A10".to_string())
+    );
+    assert_eq!(
+        replace_word("SUM(A1:A5)", "sum", "max"),
+        Some("MAX(A1:A5)".to_string())
+    );
+    assert_eq!(replace_word("MAX(A1:A5)", "MA", "MIN"), None);
+    assert_eq!(replace_word("15+5", "5", "7"), Some("15+7".to_string()));
+    assert_eq!(replace_word("A1", " ", "B1"), None);
+
+    let (rows, cols) = (10, 3);
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; rows * cols];
+    for (row, col, formula) in [
+        (0, 0, "4"),
+        (1, 0, "9"),
+        (0, 1, "A1*2"),
+        (1, 1, "A10+A1"),
+        (2, 1, "SUM(A1:A2)"),
+        (0, 2, "B1+1"),
+    ] {
+        assert_eq!(
+            assign_formula(
+                &mut sheet,
+                &mut ranged,
+                &mut is_range,
+                (rows, cols),
+                row,
+                col,
+                formula
+            ),
+            Ok(())
+        );
+    }
+    assert_eq!(
+        replacements(&sheet, cols, "A1", "A2"),
+        [
+            (0, 1, "A2*2".to_string()),
+            (1, 1, "A10+A2".to_string()),
+            (2, 1, "SUM(A2:A2)".to_string())
+        ]
+    );
+
+    // Each rewrite is recalculated, including the cells reading the rewritten ones
+    let results = replace_formulas(
+        &mut sheet,
+        &mut ranged,
+        &mut is_range,
+        (rows, cols),
+        &LockTable::default(),
+        &mut ValidationTable::default(),
+        ("A1", "A2"),
+    );
+    assert_eq!(results, [(1, Ok(())), (4, Ok(())), (7, Ok(()))]);
+    assert_eq!(sheet.get(&1).unwrap().value, Valtype::Int(18));
+    assert_eq!(sheet.get(&2).unwrap().value, Valtype::Int(19));
+    assert_eq!(sheet.get(&7).unwrap().value, Valtype::Int(9));
+    assert_eq!(SearchIndex::build(&sheet).find("a1"), [4]);
+
+    // A rejected rewrite keeps its formula and does not stop the others
+    let results = replace_formulas(
+        &mut sheet,
+        &mut ranged,
+        &mut is_range,
+        (rows, cols),
+        &LockTable::default(),
+        &mut ValidationTable::default(),
+        ("B1", "C1"),
+    );
+    assert_eq!(results, [(2, Err(EvalStatus::CycleDetected))]);
+    assert_eq!(formula_string(sheet.get(&2).unwrap()), "B1+1");
+    let results = replace_formulas(
+        &mut sheet,
+        &mut ranged,
+        &mut is_range,
+        (rows, cols),
+        &LockTable::default(),
+        &mut ValidationTable::default(),
+        ("A2", "A99"),
+    );
+    assert_eq!(
+        results,
+        [
+            (1, Err(EvalStatus::InvalidRange)),
+            (4, Err(EvalStatus::InvalidRange)),
+            (7, Err(EvalStatus::InvalidRange))
+        ]
+    );
+    assert_eq!(formula_string(sheet.get(&1).unwrap()), "A2*2");
+    let results = replace_formulas(
+        &mut sheet,
+        &mut ranged,
+        &mut is_range,
+        (rows, cols),
+        &LockTable::default(),
+        &mut ValidationTable::default(),
+        ("SUM", "TOTAL"),
+    );
+    assert_eq!(results, [(7, Err(EvalStatus::UnrecognizedCmd))]);
+    assert_eq!(formula_string(sheet.get(&7).unwrap()), "SUM(A2:A2)");
+    assert!(
+        replace_formulas(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            (rows, cols),
+            &LockTable::default(),
+            &mut ValidationTable::default(),
+            ("Z9", "A1"),
+        )
+        .is_empty()
+    );
+    // A rewrite breaking a validation rule is refused, or kept and marked with `mark`
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&["validate B1 int 0..10", "B1=3", "C1=3"]),
+        [Ok(()); 3]
+    );
+    assert_eq!(
+        sheet.run(&["replace 3 99"]),
+        [Err(EvalStatus::ValidationFailed)]
+    );
+    assert_eq!(sheet.cells.get(&1).unwrap().value, Valtype::Int(3));
+    assert_eq!(sheet.cells.get(&2).unwrap().value, Valtype::Int(99));
+    assert_eq!(
+        sheet.run(&["validate A2 int 0..10 mark", "A2=4", "replace 4 50"]),
+        [Ok(()), Ok(()), Err(EvalStatus::ValidationFailed)]
+    );
+    assert_eq!(sheet.cells.get(&3).unwrap().value, Valtype::Int(50));
+    assert!(sheet.options.validations.marked().contains(&3));
+}
+
+#[test]
+fn test_find_reuses_index() {
+    // The first `find` builds the index, later ones reuse it until the sheet changes
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(sheet.run(&["A1=12", "B1=A1+1"]), [Ok(()); 2]);
+    assert!(sheet.options.search_index.is_none());
+    assert_eq!(sheet.run(&["find 12", "find 13"]), [Ok(()); 2]);
+    let index = sheet.options.search_index.as_ref().unwrap();
+    assert_eq!(index.find("12"), [0]);
+    assert_eq!(index.find("13"), [1]);
+    // Any command that changes the sheet drops it, so the next `find` sees the change
+    assert_eq!(sheet.run(&["A1=20"]), [Ok(())]);
+    assert!(sheet.options.search_index.is_none());
+    assert_eq!(sheet.run(&["find 21"]), [Ok(())]);
+    assert_eq!(sheet.options.search_index.as_ref().unwrap().find("21"), [1]);
+    assert_eq!(sheet.run(&["clear A1:B1", "find 21"]), [Ok(()); 2]);
+    assert!(
+        sheet
+            .options
+            .search_index
+            .as_ref()
+            .unwrap()
+            .find("21")
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_validation_rules() {
+    assert_eq!(
+        parse_validate_args("a1:a3 int -5..100 mark", 10, 3).map(|rule| rule.on_failure),
+        Ok(OnFailure::Mark)
+    );
+    let rule = parse_validate_args("B2 int 0..9", 10, 3).unwrap();
+    assert_eq!(rule.corners, ((1, 1), (1, 1)));
+    assert_eq!(rule.constraint, Constraint::Int { min: 0, max: 9 });
+    assert_eq!(rule.on_failure, OnFailure::Reject);
+    for args in [
+        "A1:A3 int 5..2",
+        "A1:A3 int 0-9",
+        "A1:A3 text",
+        "A1:A3 int 0..9 warn",
+    ] {
+        assert_eq!(
+            parse_validate_args(args, 10, 3).map(|_| ()),
+            Err(EvalStatus::UnrecognizedCmd),
+            "{}",
+            args
+        );
+    }
+    assert_eq!(
+        parse_validate_args("A1:A30 int 0..9", 10, 3).map(|_| ()),
+        Err(EvalStatus::InvalidRange)
+    );
+
+    let (rows, cols) = (10, 3);
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; rows * cols];
+    let mut rules = ValidationTable::default();
+    let mut assign = |rules: &mut ValidationTable,
+                      sheet: &mut HashMap<CellKey, Cell>,
+                      cell: &str,
+                      formula: &str| {
+        let (row, col) = to_indices(cell).unwrap();
+        validated_assign(
+            rules,
+            sheet,
+            &mut ranged,
+            &mut is_range,
+            (rows, cols),
+            (row, col),
+            formula,
+        )
+        .status
+    };
+    assert_eq!(assign(&mut rules, &mut sheet, "A1", "50"), Ok(()));
+    assert_eq!(assign(&mut rules, &mut sheet, "B1", "A1*3"), Ok(()));
+    let rule = parse_validate_args("B1:B3 int 0..100", rows, cols).unwrap();
+    assert_eq!(rules.add(rule, &sheet, cols), 1);
+    assert_eq!(rules.marked().iter().copied().collect::<Vec<_>>(), [1]);
+
+    // A rejected edit is undone, along with the values computed from it
+    assert_eq!(assign(&mut rules, &mut sheet, "A1", "20"), Ok(()));
+    assert!(rules.marked().is_empty());
+    assert_eq!(
+        assign(&mut rules, &mut sheet, "A1", "40"),
+        Err(EvalStatus::ValidationFailed)
+    );
+    assert_eq!(sheet.get(&0).unwrap().value, Valtype::Int(20));
+    assert_eq!(sheet.get(&1).unwrap().value, Valtype::Int(60));
+    // Error values break an int rule
+    assert_eq!(
+        assign(&mut rules, &mut sheet, "B2", "A1/0"),
+        Err(EvalStatus::ValidationFailed)
+    );
+    assert_eq!(formula_string(sheet.get(&4).unwrap()), "");
+
+    // A mark rule keeps the edit and marks the cells breaking it until they are fixed
+    let rule = parse_validate_args("C1:C3 int 0..10 mark", rows, cols).unwrap();
+    assert_eq!(rules.add(rule, &sheet, cols), 0);
+    assert_eq!(
+        assign(&mut rules, &mut sheet, "C1", "A1"),
+        Err(EvalStatus::ValidationFailed)
+    );
+    assert_eq!(sheet.get(&2).unwrap().value, Valtype::Int(20));
+    assert_eq!(rules.marked().iter().copied().collect::<Vec<_>>(), [2]);
+    assert_eq!(assign(&mut rules, &mut sheet, "A1", "5"), Ok(()));
+    assert!(rules.marked().is_empty());
+    sheet.get_mut(&2).unwrap().value = Valtype::Int(11);
+    rules.recheck(&sheet, cols);
+    assert_eq!(rules.marked().iter().copied().collect::<Vec<_>>(), [2]);
+}
+
+#[test]
+fn test_range_operands() {
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    detect_formula(&mut cell, "2*AVG(A1:$A$2)");
+    let CellData::Expr(expr) = &cell.data else {
+        panic!("expected an expression, got {:?}", cell.data);
+    };
+    assert_eq!(expr.op_code, '*');
+    assert_eq!(expr.lhs, Operand::Const(2));
+    assert_eq!(expr.rhs.to_string(), "AVG(A1:$A$2)");
+    assert_eq!(formula_string(&cell), "2*AVG(A1:$A$2)");
+    for formula in ["SUM(A1:A2)+", "SUM(A1:A2)+SUM(B1)", "SUM(A1:A2)%3"] {
+        detect_formula(&mut cell, formula);
+        assert_eq!(cell.data, CellData::Invalid, "{}", formula);
+    }
+
+    let (rows, cols) = (10, 3);
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; rows * cols];
+    let mut assign = |sheet: &mut HashMap<CellKey, Cell>, cell: &str, formula: &str| {
+        let (row, col) = to_indices(cell).unwrap();
+        let status = assign_formula(
+            sheet,
+            &mut ranged,
+            &mut is_range,
+            (rows, cols),
+            row,
+            col,
+            formula,
+        );
+        (status, ranged.clone(), is_range.clone())
+    };
+    let value = |sheet: &HashMap<CellKey, Cell>, cell: &str| {
+        let (row, col) = to_indices(cell).unwrap();
+        sheet[&((row * cols + col) as CellKey)].value.clone()
+    };
+    for (cell, formula) in [("A1", "2"), ("A2", "4"), ("A3", "6"), ("A4", "8")] {
+        assert_eq!(assign(&mut sheet, cell, formula).0, Ok(()));
+    }
+    assert_eq!(
+        assign(&mut sheet, "B1", "SUM(A1:A4)/COUNT(A1:A4)").0,
+        Ok(())
+    );
+    assert_eq!(value(&sheet, "B1"), Valtype::Int(5));
+    assert_eq!(assign(&mut sheet, "B2", "B1+MAX(A1:A4)").0, Ok(()));
+    assert_eq!(value(&sheet, "B2"), Valtype::Int(13));
+
+    // Both the range operands and the reference operand are followed when a cell changes
+    assert_eq!(assign(&mut sheet, "A1", "10").0, Ok(()));
+    assert_eq!(value(&sheet, "B1"), Valtype::Int(7));
+    assert_eq!(value(&sheet, "B2"), Valtype::Int(17));
+    assert_eq!(assign(&mut sheet, "A4", "0").0, Ok(()));
+    assert_eq!(value(&sheet, "B1"), Valtype::Int(5));
+    assert_eq!(value(&sheet, "B2"), Valtype::Int(15));
+    assert_eq!(
+        transitive_precedents(cols as CellKey + 1, &sheet, cols),
+        vec![0, 1, 3, 6, 9]
+    );
+
+    // A range operand that would read the cell itself is a cycle
+    let (status, ranged_now, _) = assign(&mut sheet, "A1", "1+SUM(B1:B2)");
+    assert_eq!(status, Err(EvalStatus::CycleDetected));
+    assert_eq!(value(&sheet, "A1"), Valtype::Int(10));
+    assert!(cycle_path(&sheet, &ranged_now, (rows, cols), 0, 0, "1+SUM(B1:B2)").is_some());
+    assert_eq!(
+        assign(&mut sheet, "C1", "SUM(A1:A99)-1").0,
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(sheet[&2].data, CellData::Empty);
+
+    // Replacing the formulas drops their ranges and edges
+    assert_eq!(assign(&mut sheet, "B2", "3").0, Ok(()));
+    let (_, ranged_after, is_range_after) = assign(&mut sheet, "B1", "4");
+    assert!(ranged_after.values().all(Vec::is_empty));
+    assert!(is_range_after.iter().all(|&flag| !flag));
+    assert!(sheet[&1].dependents.is_empty());
+
+    assert_eq!(
+        fill_formulas("C1:C2 = SUM(A1:B1)*$A$1", rows, cols),
+        Ok(vec![
+            (0, 2, "SUM(A1:B1)*$A$1".to_string()),
+            (1, 2, "SUM(A2:B2)*$A$1".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn test_error_values() {
+    let (rows, cols) = (5, 5);
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; rows * cols];
+    let mut assign = |sheet: &mut HashMap<CellKey, Cell>, cell: &str, formula: &str| {
+        let (row, col) = to_indices(cell).unwrap();
+        assign_formula(
+            sheet,
+            &mut ranged,
+            &mut is_range,
+            (rows, cols),
+            row,
+            col,
+            formula,
+        )
+    };
+    let value = |sheet: &HashMap<CellKey, Cell>, cell: &str| {
+        let (row, col) = to_indices(cell).unwrap();
+        sheet[&((row * cols + col) as CellKey)].value.clone()
+    };
+
+    // An error propagates unchanged through the cells reading it
+    assert_eq!(assign(&mut sheet, "A1", "1/0"), Ok(()));
+    assert_eq!(assign(&mut sheet, "B1", "A1+1"), Ok(()));
+    assert_eq!(assign(&mut sheet, "B2", "SUM(A1:A3)"), Ok(()));
+    assert_eq!(value(&sheet, "B1"), Valtype::Err(CellError::DivZero));
+    assert_eq!(value(&sheet, "B2"), Valtype::Err(CellError::DivZero));
+
+    // A formula that cannot be evaluated is reported, and its cell shows why
+    assert_eq!(
+        assign(&mut sheet, "C1", "FOO(A2:A3)"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(value(&sheet, "C1"), Valtype::Err(CellError::Name));
+    assert_eq!(assign(&mut sheet, "C2", "C1*2"), Ok(()));
+    assert_eq!(value(&sheet, "C2"), Valtype::Err(CellError::Name));
+    assert_eq!(
+        assign(&mut sheet, "D1", "SUM(A3:A2)"),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(value(&sheet, "D1"), Valtype::Err(CellError::Ref));
+    assert_eq!(assign(&mut sheet, "A1", "3"), Ok(()));
+    assert_eq!(value(&sheet, "B1"), Valtype::Int(4));
+
+    // Text read as a number is a #VALUE! error
+    set_cell(
+        &mut sheet,
+        cols,
+        4,
+        0,
+        CellData::Const,
+        Valtype::Str(CellText::new("TEXT")),
+    );
+    assert_eq!(assign(&mut sheet, "E2", "A5-1"), Ok(()));
+    assert_eq!(value(&sheet, "E2"), Valtype::Err(CellError::Value));
+
+    let shown: Vec<String> = [
+        CellError::DivZero,
+        CellError::Ref,
+        CellError::Cycle,
+        CellError::Value,
+        CellError::Name,
+        CellError::NotAvailable,
+    ]
+    .iter()
+    .map(|error| format!("{:<8}|", error))
+    .collect();
+    assert_eq!(
+        shown,
+        [
+            "#DIV/0! |",
+            "#REF!   |",
+            "#CYCLE! |",
+            "#VALUE! |",
+            "#NAME?  |",
+            "#N/A    |"
+        ]
+    );
+    assert_eq!(CellError::from(EvalStatus::CycleDetected), CellError::Cycle);
+}
+
+#[test]
+fn test_autosave() {
+    let path = std::env::temp_dir().join("spreadsheet_test_autosave.json");
+    let _ = std::fs::remove_file(&path);
+    let mut sheet = ScriptSheet::new(3, 3);
+    sheet.options.autosave = Some(path.clone());
+
+    // Nothing is written until AUTOSAVE_COMMANDS commands were typed
+    for i in 0..crate::AUTOSAVE_COMMANDS {
+        assert!(!path.exists(), "autosaved after {} commands", i);
+        assert_eq!(sheet.run(&[&format!("A1={}", i)]), [Ok(())]);
+        sheet
+            .options
+            .count_command(&sheet.cells, &sheet.ranged, &sheet.is_range, (3, 3));
+    }
+    assert!(path.exists());
+
+    let mut restored: HashMap<CellKey, Cell> = HashMap::new();
+    let mut restored_ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut restored_is_range = vec![false; 9];
+    assert!(
+        crate::workbook::load_workbook(
+            path.to_str().unwrap(),
+            &mut restored,
+            &mut restored_ranged,
+            &mut restored_is_range,
+            (3, 3),
+        )
+        .is_ok()
+    );
+    let last = crate::AUTOSAVE_COMMANDS as i32 - 1;
+    assert_eq!(restored.get(&0).unwrap().value, Valtype::Int(last));
+    std::fs::remove_file(&path).unwrap();
+
+    // `autosave off` stops the counting
+    assert_eq!(sheet.run(&["autosave off"]), [Ok(())]);
+    for _ in 0..crate::AUTOSAVE_COMMANDS {
+        sheet
+            .options
+            .count_command(&sheet.cells, &sheet.ranged, &sheet.is_range, (3, 3));
+    }
+    assert!(!path.exists());
+
+    let mut args = vec!["prog".to_string(), "--restore".to_string(), "3".to_string()];
+    assert!(take_restore_flag(&mut args));
+    assert_eq!(args, ["prog", "3"]);
+    assert!(!take_restore_flag(&mut args));
+
+    // The file after `--autosave` is optional, and a number is left for the dimensions
+    let mut args: Vec<String> = ["prog", "--autosave", "3", "3"].map(String::from).to_vec();
+    assert_eq!(
+        take_autosave_flag(&mut args),
+        Some(workbook::autosave_path())
+    );
+    assert_eq!(args, ["prog", "3", "3"]);
+    let mut args: Vec<String> = ["prog", "3", "3", "--autosave", "a.json"]
+        .map(String::from)
+        .to_vec();
+    assert_eq!(take_autosave_flag(&mut args), Some(PathBuf::from("a.json")));
+    assert_eq!(args, ["prog", "3", "3"]);
+    assert_eq!(take_autosave_flag(&mut args), None);
+}
+
+#[test]
+fn test_autosave_sessions_share_no_file() {
+    // Sessions are not autosaved unless asked to, so neither writes the shared file
+    let shared = workbook::autosave_path();
+    let before = std::fs::metadata(&shared).and_then(|m| m.modified()).ok();
+    let mut first = ScriptSheet::new(3, 3);
+    let mut second = ScriptSheet::new(3, 3);
+    assert_eq!(first.options.autosave, None);
+    for i in 0..crate::AUTOSAVE_COMMANDS {
+        for sheet in [&mut first, &mut second] {
+            assert_eq!(sheet.run(&[&format!("A1={}", i)]), [Ok(())]);
+            sheet
+                .options
+                .count_command(&sheet.cells, &sheet.ranged, &sheet.is_range, (3, 3));
+        }
+    }
+    assert_eq!(
+        std::fs::metadata(&shared).and_then(|m| m.modified()).ok(),
+        before
+    );
+
+    // Sessions given their own files each write only their own
+    let paths = ["first", "second"]
+        .map(|name| std::env::temp_dir().join(format!("spreadsheet_test_autosave_{}.json", name)));
+    for (sheet, path) in [&mut first, &mut second].into_iter().zip(&paths) {
+        let _ = std::fs::remove_file(path);
+        sheet.options.autosave = Some(path.clone());
+    }
+    assert_eq!(second.run(&["A1=99"]), [Ok(())]);
+    for _ in 0..crate::AUTOSAVE_COMMANDS {
+        for sheet in [&mut first, &mut second] {
+            sheet
+                .options
+                .count_command(&sheet.cells, &sheet.ranged, &sheet.is_range, (3, 3));
+        }
+    }
+    for (path, expected) in paths.iter().zip([crate::AUTOSAVE_COMMANDS as i32 - 1, 99]) {
+        let mut restored: HashMap<CellKey, Cell> = HashMap::new();
+        let mut ranged = HashMap::new();
+        let mut is_range = vec![false; 9];
+        workbook::load_workbook(
+            path.to_str().unwrap(),
+            &mut restored,
+            &mut ranged,
+            &mut is_range,
+            (3, 3),
+        )
+        .unwrap();
+        assert_eq!(restored.get(&0).unwrap().value, Valtype::Int(expected));
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn test_command_history() {
+    let mut options = SessionOptions::default();
+    assert_eq!(options.recall("A1=5"), Ok("A1=5".to_string()));
+    assert_eq!(options.recall("w"), Ok("w".to_string()));
+    assert_eq!(options.recall(""), Ok(String::new()));
+    assert_eq!(options.recall("!1"), Ok("A1=5".to_string()));
+    assert_eq!(options.history, ["A1=5", "w", "A1=5"]);
+
+    // Only numbers of commands in the history can be recalled
+    assert_eq!(options.recall("!0"), Err(EvalStatus::InvalidRange));
+    assert_eq!(options.recall("!4"), Err(EvalStatus::InvalidRange));
+    assert_eq!(options.recall("!x"), Ok("!x".to_string()));
+    assert_eq!(options.history.len(), 4);
+}
+
+#[cfg(feature = "scripting")]
+#[test]
+fn test_script_functions() {
+    use spreadsheet::scripting::load_functions;
+
+    let script = r#"
+        fn DOUBLE(x) { x * 2 }
+        fn SUMSQ(values) { values.reduce(|sum, v| sum + v * v, 0) }
+        fn TEXT(x) { "text" }
+        fn helper(x) { x }
+        fn SUM(x) { 0 }
+        fn TWO(x, y) { x + y }
+    "#;
+    assert_eq!(
+        load_functions(script),
+        Ok(vec!["DOUBLE".into(), "SUMSQ".into(), "TEXT".into()])
+    );
+    assert!(load_functions("fn BROKEN(x) {").is_err());
+    // A name too long for a formula is reported rather than skipped
+    let error = load_functions("fn DOUBLE(x) { x * 2 } fn QUADRUPLE(x) { x * 4 }").unwrap_err();
+    assert!(error.contains("QUADRUPLE"), "{}", error);
+    // A script that does not load leaves the functions loaded before
+    assert!(spreadsheet::scripting::is_user_function("DOUBLE"));
+    // Each session has its own functions
+    let other = spreadsheet::utils::EvalContext::default();
+    assert!(!other.scope(|| spreadsheet::scripting::is_user_function("DOUBLE")));
+    other
+        .scope(|| load_functions("fn TRIPLE(x) { x * 3 }"))
+        .unwrap();
+    assert!(!spreadsheet::scripting::is_user_function("TRIPLE"));
+
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&[
+            "A1=3",
+            "A2=4",
+            "B1=DOUBLE(A1)",
+            "C1=SUMSQ(A1:A2)",
+            "C2=TEXT(A1)"
+        ]),
+        [Ok(()); 5]
+    );
+    assert_eq!(sheet.cells[&1].value, Valtype::Int(6));
+    assert_eq!(sheet.cells[&2].value, Valtype::Int(25));
+    assert_eq!(sheet.cells[&5].value, Valtype::Err(CellError::Value));
+    assert_eq!(formula_string(&sheet.cells[&1]), "DOUBLE(A1)");
+    assert_eq!(formula_string(&sheet.cells[&2]), "SUMSQ(A1:A2)");
+
+    // Script functions are recalculated when the cells they read change
+    assert_eq!(sheet.run(&["A1=5"]), [Ok(())]);
+    assert_eq!(sheet.cells[&1].value, Valtype::Int(10));
+    assert_eq!(sheet.cells[&2].value, Valtype::Int(41));
+    assert_eq!(
+        sheet.run(&["C3=helper(A1)"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    assert_eq!(
+        sheet.run(&["C3=NOPE(A1)"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+}
+
+#[test]
+fn test_visit_history() {
+    let mut visits = VisitHistory::default();
+    assert_eq!(visits.back(), None);
+    assert_eq!(visits.expand("!last"), Err(EvalStatus::InvalidRange));
+    visits.visit((0, 0), (9, 1));
+    visits.visit((9, 1), (4, 4));
+    assert_eq!(visits.expand(" !LAST ").as_deref(), Ok("B10"));
+    assert_eq!(visits.expand("c3").as_deref(), Ok("c3"));
+    assert_eq!(visits.back(), Some((9, 1)));
+    assert_eq!(visits.back(), Some((0, 0)));
+    assert_eq!(visits.back(), None);
+    assert_eq!(visits.forward(), Some((9, 1)));
+    // A jump after going back forgets the cells gone back over, and the cell left is kept
+    visits.visit((7, 7), (2, 2));
+    assert_eq!(visits.forward(), None);
+    assert_eq!(visits.last(), Some((7, 7)));
+    assert_eq!(visits.back(), Some((7, 7)));
+    assert_eq!(visits.back(), Some((9, 1)));
+    for i in 0..MAX_VISITS {
+        visits.visit((i, 0), (i, 1));
+    }
+    let mut steps = 0;
+    while visits.back().is_some() {
+        steps += 1;
+    }
+    assert_eq!(steps, MAX_VISITS - 1);
+
+    // At the prompt, scroll_to is retraced by back, forward and scroll_to !last
+    let mut sheet = ScriptSheet::new(20, 20);
+    for (cmd, status, start) in [
+        ("back", Err(EvalStatus::InvalidRange), (0, 0)),
+        ("scroll_to !last", Err(EvalStatus::InvalidRange), (0, 0)),
+        ("scroll_to C5", Ok(()), (4, 2)),
+        ("scroll_to T20", Ok(()), (19, 19)),
+        ("back", Ok(()), (4, 2)),
+        ("back", Ok(()), (0, 0)),
+        ("forward", Ok(()), (4, 2)),
+        ("scroll_to !last", Ok(()), (0, 0)),
+        ("scroll_to !last", Ok(()), (4, 2)),
+        ("forward", Err(EvalStatus::InvalidRange), (4, 2)),
+    ] {
+        assert_eq!(sheet.run(&[cmd]), [status], "command {}", cmd);
+        assert_eq!(sheet.view, start, "command {}", cmd);
+    }
+}
+
+#[test]
+fn test_watch_cells() {
+    let mut sheet = ScriptSheet::new(3, 3);
+
+    assert_eq!(
+        sheet.run(&["watch A1", "watch C3", "B1=5", "A1=B1+1"]),
+        [Ok(()); 4]
+    );
+    assert_eq!(
+        sheet.options.watches.changes(&sheet.cells, 3),
+        ["A1: 0 -> 6"]
+    );
+    assert!(sheet.options.watches.changes(&sheet.cells, 3).is_empty());
+
+    // A change reached through recalculation is reported, once per check
+    assert_eq!(sheet.run(&["B1=7", "C3=A1/0"]), [Ok(()); 2]);
+    assert_eq!(
+        sheet.options.watches.changes(&sheet.cells, 3),
+        ["A1: 6 -> 8", "C3: 0 -> #DIV/0!"]
+    );
+
+    assert_eq!(sheet.run(&["unwatch A1", "B1=1"]), [Ok(()); 2]);
+    assert!(sheet.options.watches.changes(&sheet.cells, 3).is_empty());
+    assert_eq!(sheet.options.watches.cells().collect::<Vec<_>>(), [(2, 2)]);
+    assert_eq!(sheet.run(&["watch D1"]), [Err(EvalStatus::InvalidRange)]);
+    assert_eq!(
+        sheet.run(&["watch 1A", "watch ZZZZZZZZZZZZZZZZ1"]),
+        [Err(EvalStatus::UnrecognizedCmd); 2]
+    );
+}
+
+#[test]
+fn test_lock_cells() {
+    let mut sheet = ScriptSheet::new(3, 3);
+
+    assert_eq!(sheet.run(&["A1=5", "lock A1:B2", "unlock b2"]), [Ok(()); 3]);
+    assert_eq!(sheet.run(&["A1=7"]), [Err(EvalStatus::CellLocked)]);
+    assert_eq!(sheet.cells.get(&0).unwrap().value, Valtype::Int(5));
+    assert_eq!(
+        EvalStatus::message(Err(EvalStatus::CellLocked)),
+        "cell locked"
+    );
+
+    // A fill touching a locked cell writes nothing
+    assert_eq!(
+        sheet.run(&["fill B1:C1 = 1"]),
+        [Err(EvalStatus::CellLocked)]
+    );
+    assert!(!sheet.cells.contains_key(&2));
+    assert_eq!(sheet.run(&["B2=A1*2"]), [Ok(())]);
+    assert_eq!(sheet.cells.get(&4).unwrap().value, Valtype::Int(10));
+
+    // Locked cells still follow the cells they depend on
+    assert_eq!(sheet.run(&["lock B2", "unlock A1", "A1=1"]), [Ok(()); 3]);
+    assert_eq!(sheet.cells.get(&4).unwrap().value, Valtype::Int(2));
+    assert_eq!(sheet.run(&["lock A1:D1"]), [Err(EvalStatus::InvalidRange)]);
+
+    // A replace rewrites the unlocked cells and leaves the locked ones alone
+    assert_eq!(sheet.run(&["A1=5", "C3=5", "lock A1"]), [Ok(()); 3]);
+    assert_eq!(sheet.run(&["replace 5 7"]), [Err(EvalStatus::CellLocked)]);
+    assert_eq!(sheet.cells.get(&0).unwrap().value, Valtype::Int(5));
+    assert_eq!(sheet.cells.get(&8).unwrap().value, Valtype::Int(7));
+
+    // Redefining a name cannot move the formula of a locked cell that uses it
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&[
+            "A1=1",
+            "A2=2",
+            "B1=5",
+            "name range n A1:A2",
+            "C1=SUM(n)",
+            "lock C1"
+        ]),
+        [Ok(()); 6]
+    );
+    assert_eq!(
+        sheet.run(&["name range n B1:B1"]),
+        [Err(EvalStatus::CellLocked)]
+    );
+    assert_eq!(formula_string(&sheet.cells[&2]), "SUM(A1:A2)");
+    assert_eq!(sheet.cells[&2].value, Valtype::Int(3));
+    assert_eq!(sheet.run(&["unlock C1", "name range n B1:B1"]), [Ok(()); 2]);
+    assert_eq!(sheet.cells[&2].value, Valtype::Int(5));
+}
+
+#[test]
+fn test_sparkline() {
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(16);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 16];
+    for (row, formula) in ["1", "3", "6", "8"].into_iter().enumerate() {
+        let status = assign_formula(
+            &mut spreadsheet,
+            &mut ranged,
+            &mut is_range,
+            (4, 4),
+            row,
+            0,
+            formula,
+        );
+        assert_eq!(status, Ok(()));
+    }
+    let status = assign_formula(
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        (4, 4),
+        0,
+        1,
+        "SPARK(A1:A4)",
+    );
+    assert_eq!(status, Ok(()));
+    // The value of a sparkline is that of the last cell of its range
+    assert_eq!(spreadsheet[&1].value, Valtype::Int(8));
+    assert_eq!(
+        sparkline(&spreadsheet, (4, 4), &spreadsheet[&1]).as_deref(),
+        Some("▁▃▆█")
+    );
+    assert_eq!(sparkline(&spreadsheet, (4, 4), &spreadsheet[&0]), None);
+
+    // The range is a dependency like that of any range function
+    let status = assign_formula(
+        &mut spreadsheet,
+        &mut ranged,
+        &mut is_range,
+        (4, 4),
+        3,
+        0,
+        "A1/0",
+    );
+    assert_eq!(status, Ok(()));
+    assert_eq!(spreadsheet[&1].value, Valtype::Err(CellError::DivZero));
+    let mut view = View::default();
+    view.size = (1, 2);
+    assert_eq!(
+        render_sheet(&spreadsheet, &(0, 0), &(4, 4), &mut view),
+        concat!(
+            "              A           B  \n",
+            "   1  1           ▁▃█         \n",
+        )
+    );
+}
+
+#[test]
+fn test_manual_calc() {
+    let mut sheet = ScriptSheet::new(3, 3);
+
+    assert_eq!(
+        sheet.run(&[
+            "A1=1",
+            "B1=A1+1",
+            "C1=SUM(A1:B1)",
+            "calc manual",
+            "A1=5",
+            "A2=A1*2"
+        ]),
+        [Ok(()); 6]
+    );
+    // Only the constant took its value; its dependents and the new formula are stale
+    let value = |sheet: &ScriptSheet, key: CellKey| sheet.cells[&key].value.clone();
+    assert_eq!(value(&sheet, 0), Valtype::Int(5));
+    assert_eq!(value(&sheet, 1), Valtype::Int(2));
+    assert_eq!(value(&sheet, 2), Valtype::Int(3));
+    assert_eq!(value(&sheet, 3), Valtype::Int(0));
+    assert_eq!(sheet.options.stale, BTreeSet::from([0, 1, 2, 3]));
+    // Cycles are still refused when the formula is assigned
+    assert_eq!(sheet.run(&["A1=C1"]), [Err(EvalStatus::CycleDetected)]);
+    assert_eq!(formula_string(&sheet.cells[&0]), "5");
+
+    assert_eq!(sheet.run(&["recalc"]), [Ok(())]);
+    assert!(sheet.options.stale.is_empty());
+    assert_eq!(value(&sheet, 1), Valtype::Int(6));
+    assert_eq!(value(&sheet, 2), Valtype::Int(11));
+    assert_eq!(value(&sheet, 3), Valtype::Int(10));
+
+    // Switching back to automatic recalculates what is left stale
+    assert_eq!(sheet.run(&["B1=A1/0"]), [Ok(())]);
+    assert_eq!(value(&sheet, 1), Valtype::Int(6));
+    assert_eq!(sheet.run(&["calc auto"]), [Ok(())]);
+    assert_eq!(value(&sheet, 1), Valtype::Err(CellError::DivZero));
+    assert_eq!(value(&sheet, 2), Valtype::Err(CellError::DivZero));
+    assert_eq!(sheet.run(&["A1=2"]), [Ok(())]);
+    assert_eq!(value(&sheet, 3), Valtype::Int(4));
+}
+
+#[test]
+fn test_dump_and_diff() {
+    let path = std::env::temp_dir().join("spreadsheet_test_dump.txt");
+    let mut sheet = ScriptSheet::new(3, 3);
+
+    assert_eq!(
+        sheet.run(&["B1=5", "A1=B1+2", "C3=A1/0", "A2=7"]),
+        [Ok(()); 4]
+    );
+    assert_eq!(
+        dump_lines(&sheet.cells, 3),
+        ["A1=B1+2 # 7", "B1=5 # 5", "A2=7 # 7", "C3=A1/0 # #DIV/0!"]
+    );
+    assert_eq!(sheet.run(&[&format!("dump {}", path.display())]), [Ok(())]);
+    assert_eq!(
+        diff_lines(&sheet.cells, 3, ""),
+        Ok(vec![
+            "A1: (empty) -> B1+2 # 7".to_string(),
+            "B1: (empty) -> 5 # 5".to_string(),
+            "A2: (empty) -> 7 # 7".to_string(),
+            "C3: (empty) -> A1/0 # #DIV/0!".to_string(),
+        ])
+    );
+
+    // Changed and cleared cells are listed in row-major order
+    assert_eq!(sheet.run(&["B1=6", "A2=0", "A2=B2"]), [Ok(()); 3]);
+    let previous = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        diff_lines(&sheet.cells, 3, &previous),
+        Ok(vec![
+            "A1: B1+2 # 7 -> B1+2 # 8".to_string(),
+            "B1: 5 # 5 -> 6 # 6".to_string(),
+            "A2: 7 # 7 -> B2 # 0".to_string(),
+        ])
+    );
+    assert_eq!(sheet.run(&[&format!("diff {}", path.display())]), [Ok(())]);
+    assert_eq!(
+        diff_lines(&sheet.cells, 3, "not a dump"),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+    assert_eq!(
+        sheet.run(&["diff /nonexistent/dump.txt"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    // A cell holding a formula that was not understood is neither listed nor compared
+    let listed = dump_lines(&sheet.cells, 3);
+    set_cell(
+        &mut sheet.cells,
+        3,
+        2,
+        1,
+        CellData::Invalid,
+        Valtype::Int(0),
+    );
+    assert_eq!(dump_lines(&sheet.cells, 3), listed);
+    assert_eq!(diff_lines(&sheet.cells, 3, &listed.join("\n")), Ok(vec![]));
+}
+
+#[cfg(feature = "tui")]
+#[test]
+fn test_tui_keys() {
+    use crate::tui::{Key, Mode, Tui, parse_key};
+
+    let mut args: Vec<String> = vec!["prog".into(), "5".into(), "5".into(), "--tui".into()];
+    assert!(crate::take_tui_flag(&mut args));
+    assert_eq!(parse_dimensions(args), Ok((5, 5)));
+
+    assert_eq!(parse_key(b"\x1b[Ab"), (Some(Key::Up), 3));
+    assert_eq!(parse_key(b"\x1bOQ"), (Some(Key::F2), 3));
+    assert_eq!(parse_key(b"\x1b[12~"), (Some(Key::F2), 5));
+    assert_eq!(parse_key(b"\x1b[1;5C"), (None, 6));
+    assert_eq!(parse_key(b"\x1b"), (Some(Key::Escape), 1));
+    assert_eq!(parse_key("é1".as_bytes()), (Some(Key::Char('é')), 2));
+    assert_eq!(parse_key(b"\r"), (Some(Key::Enter), 1));
+    assert_eq!(parse_key(b"\x11"), (Some(Key::Quit), 1));
+
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::new();
+    let mut ranged = HashMap::new();
+    let mut is_range = vec![false; 25];
+    let mut options = SessionOptions::default();
+    let mut tui = Tui {
+        screen: (5, 40),
+        ..Tui::default()
+    };
+    let mut press = |tui: &mut Tui, spreadsheet: &mut HashMap<CellKey, Cell>, keys: &str| {
+        let mut bytes = keys.as_bytes();
+        while !bytes.is_empty() {
+            let (key, len) = parse_key(bytes);
+            bytes = &bytes[len..];
+            if let Some(key) = key {
+                assert!(tui.handle_key(
+                    key,
+                    spreadsheet,
+                    &mut ranged,
+                    &mut is_range,
+                    (5, 5),
+                    &mut options
+                ));
+            }
+        }
+    };
+    let value =
+        |spreadsheet: &HashMap<CellKey, Cell>, key: CellKey| spreadsheet[&key].value.clone();
+
+    // Typing replaces the cell; Tab commits and moves right, Enter commits and moves down
+    press(&mut tui, &mut spreadsheet, "\x1b[C5\t");
+    assert_eq!(tui.cursor, (0, 2));
+    press(&mut tui, &mut spreadsheet, "B1*2\r");
+    assert_eq!(value(&spreadsheet, 2), Valtype::Int(10));
+    assert_eq!(tui.cursor, (1, 2));
+    assert_eq!(tui.status, "ok");
+
+    // F2 edits the formula in place; Esc cancels
+    press(&mut tui, &mut spreadsheet, "\x1b[A\x1bOQ");
+    assert_eq!(tui.mode, Mode::Edit("B1*2".to_string()));
+    press(&mut tui, &mut spreadsheet, "\x7f3\r");
+    assert_eq!(value(&spreadsheet, 2), Valtype::Int(15));
+    press(&mut tui, &mut spreadsheet, "9\x1b");
+    assert_eq!(tui.mode, Mode::Navigate);
+    assert!(!spreadsheet.contains_key(&7));
+    press(&mut tui, &mut spreadsheet, "C1+\r");
+    assert_eq!(tui.status, "unrecognized cmd");
+
+    // Moving past the screen scrolls the view, and commands run after ':'
+    press(&mut tui, &mut spreadsheet, "\x1b[B\x1b[B\x1b[B");
+    assert_eq!((tui.cursor, tui.start), ((4, 2), (3, 1)));
+    press(&mut tui, &mut spreadsheet, ":scroll_to B2\r");
+    assert_eq!((tui.cursor, tui.start), ((1, 1), (1, 1)));
+    let frame = tui.render(&spreadsheet, (5, 5));
+    assert!(frame.starts_with("\x1b[HB2: \x1b[K\r\n"));
+    assert!(frame.contains("\x1b[7m0         \x1b[0m"));
+    assert!(!tui.handle_key(
+        Key::Quit,
+        &mut spreadsheet,
+        &mut HashMap::new(),
+        &mut [false; 25],
+        (5, 5),
+        &mut SessionOptions::default()
+    ));
+}
+
+#[cfg(feature = "tui")]
+#[test]
+fn test_line_reader_recall() {
+    use crate::tui::{Key, LineReader};
+
+    let history = vec!["A1=5".to_string(), "B1=A1+1".to_string()];
+    let mut reader = LineReader::default();
+    let mut press = |keys: &[Key]| {
+        keys.iter()
+            .filter_map(|&key| reader.handle_key(key, &history))
+            .last()
+    };
+    assert_eq!(press(&[Key::Up, Key::Enter]), Some("B1=A1+1".to_string()));
+    assert_eq!(
+        press(&[Key::Up, Key::Up, Key::Up, Key::Enter]),
+        Some("A1=5".to_string())
+    );
+    // Down past the newest command brings back the line being typed
+    assert_eq!(
+        press(&[Key::Char('w'), Key::Up, Key::Down, Key::Down, Key::Enter]),
+        Some("w".to_string())
+    );
+    assert_eq!(
+        press(&[Key::Up, Key::Backspace, Key::Char('2'), Key::Enter]),
+        Some("B1=A1+2".to_string())
+    );
+}
+
+#[test]
+fn test_multi_area_range() {
+    let mut sheet = ScriptSheet::new(3, 3);
+
+    assert_eq!(
+        sheet.run(&[
+            "A1=1",
+            "A2=2",
+            "C1=10",
+            "C2=20",
+            "B3=SUM(A1:A2,C1:C2)",
+            "C3=COUNT(A1:A1,C1:C2)"
+        ]),
+        [Ok(()); 6]
+    );
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(33));
+    assert_eq!(sheet.cells[&8].value, Valtype::Int(3));
+    assert_eq!(formula_string(&sheet.cells[&7]), "SUM(A1:A2,C1:C2)");
+    // A change in either area is tracked
+    assert_eq!(sheet.run(&["C2=0"]), [Ok(())]);
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(13));
+    assert_eq!(sheet.run(&["A2=5"]), [Ok(())]);
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(16));
+    // A loop through the second area is a cycle, and every area must lie inside the sheet
+    assert_eq!(sheet.run(&["C1=B3"]), [Err(EvalStatus::CycleDetected)]);
+    assert_eq!(
+        sheet.run(&["B2=SUM(A1:A2,C1:D2)"]),
+        [Err(EvalStatus::InvalidRange)]
+    );
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(16));
+}
+
+#[test]
+fn test_vlookup() {
+    let mut sheet = ScriptSheet::new(4, 4);
+
+    assert_eq!(
+        sheet.run(&[
+            "A1=10",
+            "B1=1",
+            "A2=20",
+            "B2=2",
+            "A3=30",
+            "B3=3",
+            "C1=30",
+            "D1=VLOOKUP(20,A1:B3,2)",
+            "D2=VLOOKUP(C1,A1:B3,2)"
+        ]),
+        [Ok(()); 9]
+    );
+    // A column outside the table is reported like a reversed range
+    assert_eq!(
+        sheet.run(&["D3=VLOOKUP(10,A1:B3,3)"]),
+        [Err(EvalStatus::InvalidRange)]
+    );
+    assert_eq!(sheet.cells[&3].value, Valtype::Int(2));
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(3));
+    assert_eq!(sheet.cells[&11].value, Valtype::Err(CellError::Ref));
+    assert_eq!(formula_string(&sheet.cells[&7]), "VLOOKUP(C1,A1:B3,2)");
+    // Both the key and the whole table are tracked
+    assert_eq!(sheet.run(&["B3=7"]), [Ok(())]);
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(7));
+    assert_eq!(sheet.run(&["C1=99"]), [Ok(())]);
+    assert_eq!(sheet.cells[&7].value, Valtype::Err(CellError::NotAvailable));
+    assert_eq!(
+        sheet.run(&["B1=VLOOKUP(10,A1:B3,2)"]),
+        [Err(EvalStatus::CycleDetected)]
+    );
+}
+
+#[test]
+fn test_scalar_functions() {
+    let mut sheet = ScriptSheet::new(4, 4);
+
+    assert_eq!(
+        sheet.run(&[
+            "A1=-7",
+            "A2=3",
+            "B1=ABS(A1)",
+            "B2=MOD(A1,A2)",
+            "B3=POW(2,10)",
+            "B4=MIN2(A1,A2)",
+            "C1=MAX2(A1,-20)"
+        ]),
+        [Ok(()); 7]
+    );
+    let value = |sheet: &ScriptSheet, key: CellKey| sheet.cells[&key].value.clone();
+    assert_eq!(value(&sheet, 1), Valtype::Int(7));
+    assert_eq!(value(&sheet, 5), Valtype::Int(2));
+    assert_eq!(value(&sheet, 9), Valtype::Int(1024));
+    assert_eq!(value(&sheet, 13), Valtype::Int(-7));
+    assert_eq!(value(&sheet, 2), Valtype::Int(-7));
+    assert_eq!(formula_string(&sheet.cells[&5]), "MOD(A1,A2)");
+    // Each referenced cell is a dependency
+    assert_eq!(sheet.run(&["A2=-4"]), [Ok(())]);
+    assert_eq!(value(&sheet, 5), Valtype::Int(-3));
+    assert_eq!(value(&sheet, 13), Valtype::Int(-7));
+    assert_eq!(sheet.run(&["A2=0"]), [Ok(())]);
+    assert_eq!(value(&sheet, 5), Valtype::Err(CellError::DivZero));
+    // Results too large for a cell are errors rather than wrapped around
+    let num = Err(EvalStatus::ErrValue(CellError::Num));
+    assert_eq!(compute_func("POW", &[2, 40]), num);
+    assert_eq!(compute_func("POW", &[2, 31]), num);
+    assert_eq!(compute_func("POW", &[-2, 31]), Ok(i32::MIN));
+    assert_eq!(compute_func("POW", &[1, i32::MAX]), Ok(1));
+    assert_eq!(compute_func("POW", &[0, 0]), Ok(1));
+    assert_eq!(
+        compute_func("POW", &[2, -1]),
+        Err(EvalStatus::ErrValue(CellError::Value))
+    );
+    assert_eq!(compute_func("ABS", &[i32::MIN]), num);
+    assert_eq!(compute_func("ABS", &[-i32::MAX]), Ok(i32::MAX));
+    assert_eq!(sheet.run(&["A1=40", "D1=POW(2,A1)"]), [Ok(()); 2]);
+    assert_eq!(value(&sheet, 3), Valtype::Err(CellError::Num));
+    // A wrong number of arguments is not a formula, and loops are still refused
+    assert_eq!(
+        sheet.run(&["C2=ABS(A1,A2)"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    assert_eq!(sheet.run(&["A1=ABS(B1)"]), [Err(EvalStatus::CycleDetected)]);
+}
+
+#[test]
+fn test_rounding_functions() {
+    // Cells hold integers, which have no digits after the point to round
+    assert_eq!(compute_func("ROUND", &[17, 2]), Ok(17));
+    assert_eq!(compute_func("ROUND", &[17, 0]), Ok(17));
+    assert_eq!(compute_func("ROUND", &[1250, -2]), Ok(1300));
+    assert_eq!(compute_func("ROUND", &[-1250, -2]), Ok(-1300));
+    assert_eq!(compute_func("ROUND", &[1249, -2]), Ok(1200));
+    assert_eq!(compute_func("ROUND", &[i32::MAX, -12]), Ok(0));
+    assert_eq!(
+        compute_func("ROUND", &[i32::MAX, -1]),
+        Err(EvalStatus::ErrValue(CellError::Num))
+    );
+    assert_eq!(
+        compute_func("FLOOR", &[i32::MIN, 3]),
+        Err(EvalStatus::ErrValue(CellError::Num))
+    );
+    assert_eq!(
+        compute_func("CEIL", &[i32::MAX, 2]),
+        Err(EvalStatus::ErrValue(CellError::Num))
+    );
+    assert_eq!(compute_func("FLOOR", &[-7, 5]), Ok(-10));
+    assert_eq!(compute_func("FLOOR", &[7, -5]), Ok(5));
+    assert_eq!(compute_func("CEIL", &[7, 5]), Ok(10));
+    assert_eq!(compute_func("CEIL", &[-7, 5]), Ok(-5));
+    assert_eq!(
+        compute_func("CEIL", &[7, 0]),
+        Err(EvalStatus::ErrValue(CellError::DivZero))
+    );
+
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    detect_formula(&mut cell, "ROUND(A1,-1)");
+    assert_eq!(formula_string(&cell), "ROUND(A1,-1)");
+}
+
+#[test]
+fn test_rand_functions() {
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    for formula in ["RAND()", "RANDBETWEEN(A1,6)", "RANDBETWEEN(-3,$B$2)"] {
+        detect_formula(&mut cell, formula);
+        assert!(cell.data.is_volatile(), "{}", formula);
+        assert_eq!(formula_string(&cell), formula);
+    }
+    for formula in ["RAND(1)", "RANDBETWEEN(1)", "RANDBTW(1,6)", "ABS()"] {
+        detect_formula(&mut cell, formula);
+        assert_eq!(cell.data, CellData::Invalid, "{}", formula);
+    }
+    let context = EvalContext::default();
+    assert_eq!(
+        draw_random("RANDBETWEEN", &[6, 1], 0, &context),
+        Err(EvalStatus::ErrValue(CellError::Value))
+    );
+    assert_eq!(draw_random("RANDBETWEEN", &[4, 4], 0, &context), Ok(4));
+
+    // Runs the commands on a new sheet after `seed 42`, recording A1:C1 after each one
+    let run = |commands: &[&str]| {
+        let mut sheet = ScriptSheet::new(4, 4);
+        let mut values = Vec::new();
+        for cmd in std::iter::once("seed 42").chain(commands.iter().copied()) {
+            assert_eq!(sheet.run(&[cmd]), [Ok(())], "command {}", cmd);
+            let value = |col: usize| match sheet.cells.get(&(col as CellKey)) {
+                Some(cell) => cell.value.clone(),
+                None => Valtype::Int(0),
+            };
+            values.push([value(0), value(1), value(2)]);
+        }
+        values
+    };
+    let mut commands = vec!["A1=RAND()", "B1=RANDBETWEEN(1,6)", "C1=B1*10"];
+    commands.extend(["D4=1", "recalc"].repeat(10));
+    let values = run(&commands);
+    assert_eq!(values, run(&commands));
+    for [a1, b1, c1] in &values[3..] {
+        let (Valtype::Int(a1), Valtype::Int(b1)) = (a1, b1) else {
+            panic!("{:?} {:?} are not numbers", a1, b1);
+        };
+        assert!((0..=RAND_MAX).contains(a1));
+        assert!((1..=6).contains(b1));
+        // Dependents of a volatile cell follow each draw
+        assert_eq!(*c1, Valtype::Int(b1 * 10));
+    }
+    // Every edit and `recalc` draws again
+    let redraws = values[3..]
+        .windows(2)
+        .filter(|w| w[0][0] != w[1][0])
+        .count();
+    assert!(redraws > 10, "{:?}", values);
+
+    // Each session draws from its own context, whatever other sessions run meanwhile, and a
+    // recalculation of `SLEEP` cells alone draws nothing
+    let session = || {
+        let sheet = ScriptSheet::new(4, 4);
+        let eval = sheet.options.eval.clone();
+        (sheet, eval)
+    };
+    let (mut alone, alone_eval) = session();
+    alone_eval.scope(|| alone.run(&["seed 7", "A1=RAND()", "recalc"]));
+    let (mut first, first_eval) = session();
+    let (mut second, second_eval) = session();
+    first_eval.scope(|| first.run(&["seed 7", "A1=RAND()"]));
+    second_eval.scope(|| second.run(&["seed 9", "A1=RAND()", "recalc", "recalc"]));
+    first_eval.scope(|| first.run(&["recalc"]));
+    assert_eq!(first.cells[&0].value, alone.cells[&0].value);
+    let (mut sleeper, sleeper_eval) = session();
+    let commands = [
+        "seed 7",
+        "B2=SLEEP(0)",
+        "recalc",
+        "recalc",
+        "A1=RAND()",
+        "recalc",
+    ];
+    sleeper_eval.scope(|| sleeper.run(&commands));
+    assert_eq!(sleeper.cells[&0].value, alone.cells[&0].value);
+}
+
+#[test]
+fn test_volatile_recalc() {
+    let mut cell = Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    };
+    for (formula, volatile) in [
+        ("SLEEP(0)", true),
+        ("SLEEP(A1)", true),
+        ("RANDBETWEEN(1,A1)", true),
+        ("ABS(A1)", false),
+        ("A1+1", false),
+    ] {
+        detect_formula(&mut cell, formula);
+        assert_eq!(cell.data.is_volatile(), volatile, "{}", formula);
+    }
+
+    let mut sheet = ScriptSheet::new(4, 4);
+    assert_eq!(
+        sheet.run(&["A1=0", "B1=SLEEP(A1)", "C1=B1+1", "A2=A1+5"]),
+        [Ok(()); 4]
+    );
+    assert_eq!(
+        volatile_cells(&sheet.cells, &EvalContext::current()),
+        vec![1]
+    );
+    // A context that has seen no volatile formula does not look for any
+    assert!(volatile_cells(&sheet.cells, &EvalContext::default()).is_empty());
+    let value = |sheet: &ScriptSheet, key: CellKey| sheet.cells[&key].value.clone();
+
+    // Out-of-date values, as if the cells had been loaded that way
+    for key in [1, 4] {
+        sheet.cells.get_mut(&key).unwrap().value = Valtype::Int(42);
+    }
+    // An edit elsewhere re-evaluates the volatile cell and its dependents, but no other cell
+    assert_eq!(sheet.run(&["D4=1"]), [Ok(())]);
+    assert_eq!(value(&sheet, 1), Valtype::Int(0));
+    assert_eq!(value(&sheet, 2), Valtype::Int(1));
+    assert_eq!(value(&sheet, 4), Valtype::Int(42));
+    assert_eq!(sheet.run(&["recalc"]), [Ok(())]);
+    assert_eq!(value(&sheet, 4), Valtype::Int(42));
+    assert_eq!(sheet.run(&["recalc all"]), [Ok(())]);
+    assert_eq!(value(&sheet, 4), Valtype::Int(5));
+}
+
+#[test]
+fn test_rejected_edit_keeps_formula() {
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(sheet.run(&["A1=5", "B1=A1+1"]), [Ok(()); 2]);
+    // A formula that is not understood leaves the cell as it was, even after `recalc all`
+    assert_eq!(
+        sheet.run(&["A1=MOD(C2)"]),
+        [Err(EvalStatus::UnrecognizedCmd)]
+    );
+    assert_eq!(dump_lines(&sheet.cells, 3), ["A1=5 # 5", "B1=A1+1 # 6"]);
+    assert_eq!(sheet.run(&["recalc all", "A1=7"]), [Ok(()); 2]);
+    assert_eq!(sheet.cells[&1].value, Valtype::Int(8));
+    assert_eq!(dump_lines(&sheet.cells, 3), ["A1=7 # 7", "B1=A1+1 # 8"]);
+}
+
+#[test]
+fn test_formula_string_round_trip() {
+    let mut spreadsheet: HashMap<CellKey, Cell> = HashMap::with_capacity(20);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 20];
+    let formulas = [
+        "7",
+        "1+2",
+        "-5*3",
+        "A1",
+        "4-A2",
+        "A1/2",
+        "A1+B1",
+        "SUM(A1:B1)",
+        "MAX(A1:A2,$B$1:B2)",
+        "2*AVG(A1:$A$2)",
+        "VLOOKUP(A1,A1:B2,2)",
+        "POW(A1,2)",
+        "SLEEP(0)",
+    ];
+    for (i, formula) in formulas.iter().enumerate() {
+        let (row, col) = (i / 4 + 1, i % 4);
+        assert_eq!(
+            assign_formula(
+                &mut spreadsheet,
+                &mut ranged,
+                &mut is_range,
+                (5, 4),
+                row,
+                col,
+                formula
+            ),
+            Ok(())
+        );
+    }
+    // Evaluating a cell must not change the text that re-creates it
+    for (i, formula) in formulas.iter().enumerate() {
+        let cell = &spreadsheet[&(i as CellKey + 4)];
+        let written = formula_string(cell);
+        assert_eq!(written, *formula);
+        let mut reparsed = Cell {
+            value: Valtype::Int(0),
+            data: CellData::Empty,
+            dependents: HashSet::new(),
+        };
+        detect_formula(&mut reparsed, &written);
+        assert_eq!(reparsed.data, cell.data);
+    }
+    assert_eq!(spreadsheet[&5].value, Valtype::Int(3));
+}
+
+#[test]
+fn test_clear_cells() {
+    let mut sheet = ScriptSheet::new(4, 4);
+    assert_eq!(
+        sheet.run(&["A1=2", "A2=3", "B1=A1*A2", "C1=SUM(A1:A2)", "D4=7"]),
+        [Ok(()); 5]
+    );
+    let value = |sheet: &ScriptSheet, key: CellKey| sheet.cells[&key].value.clone();
+
+    // Cleared cells read as an empty 0, and the formulas referring to them keep their references
+    assert_eq!(sheet.run(&["clear A1:A2"]), [Ok(())]);
+    assert_eq!(sheet.cells[&0].data, CellData::Empty);
+    assert_eq!(value(&sheet, 1), Valtype::Int(0));
+    assert_eq!(value(&sheet, 2), Valtype::Int(0));
+    assert_eq!(formula_string(&sheet.cells[&1]), "A1*A2");
+    assert_eq!(sheet.run(&["A1=4"]), [Ok(())]);
+    assert_eq!(value(&sheet, 2), Valtype::Int(4));
+
+    // A cleared formula no longer depends on anything
+    assert_eq!(sheet.run(&["clear B1"]), [Ok(())]);
+    assert_eq!(sheet.cells[&0].dependents, HashSet::new());
+    assert_eq!(value(&sheet, 15), Valtype::Int(7));
+
+    assert_eq!(
+        sheet.run(&["clear A1:E9", "lock D4", "clear C1:D4"]),
+        [
+            Err(EvalStatus::InvalidRange),
+            Ok(()),
+            Err(EvalStatus::CellLocked)
+        ]
+    );
+    assert_eq!(value(&sheet, 15), Valtype::Int(7));
+}
+
+#[test]
+fn test_dependency_dot() {
+    let path = std::env::temp_dir().join("spreadsheet_test_graph.dot");
+    let mut sheet = ScriptSheet::new(3, 3);
+    assert_eq!(
+        sheet.run(&["A1=5", "B1=A1+C1", "C2=SUM(A1:B1)", "A3=9"]),
+        [Ok(()); 4]
+    );
+
+    // Constants and formulas are labelled, and an empty cell read by a formula is a bare node
+    let dot = dependency_dot(&sheet.cells, &sheet.ranged, 3);
+    assert_eq!(
+        dot,
+        "digraph dependencies {\n    rankdir=LR;\n    \"A1\" [label=\"A1\\n5\"];\n    \
+         \"B1\" [label=\"B1\\nA1+C1\"];\n    \"C1\";\n    \"C2\" [label=\"C2\\nSUM(A1:B1)\"];\n    \
+         \"A3\" [label=\"A3\\n9\"];\n    \"A1:B1\" [shape=box];\n    \"A1\" -> \"B1\";\n    \
+         \"C1\" -> \"B1\";\n    \"A1:B1\" -> \"C2\";\n}\n"
+    );
+    assert_eq!(sheet.run(&[&format!("graph {}", path.display())]), [Ok(())]);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), dot);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_cancel_recalculation() {
+    let total_dims = (3, 3);
+    let mut sheet = make_sheet(8);
+    let mut ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>> = HashMap::new();
+    let mut is_range = vec![false; 9];
+    for (row, col, formula) in [(0, 2, "SLEEP(B1)"), (0, 0, "0"), (0, 1, "A1*2")] {
+        let status = assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            total_dims,
+            row,
+            col,
+            formula,
+        );
+        assert_eq!(status, Ok(()), "formula {}", formula);
+    }
+
+    // Nothing is running, so there is nothing to cancel
+    let token = CancelToken::default();
+    token.install();
+    assert!(!token.cancel());
+
+    // A1=5 makes C1 sleep for 10s; cancelling puts back A1 and the values computed from it
+    let canceller = token.clone();
+    let cancelled = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        let start = Instant::now();
+        while !canceller.cancel() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    let start = Instant::now();
+    let status = assign_formula(
+        &mut sheet,
+        &mut ranged,
+        &mut is_range,
+        total_dims,
+        0,
+        0,
+        "5",
+    );
+    cancelled.join().unwrap();
+    assert_eq!(status, Err(EvalStatus::Cancelled));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(formula_string(&sheet[&0]), "0");
+    for key in [0, 1, 2] {
+        assert_eq!(sheet[&key].value, Valtype::Int(0), "cell {}", key);
+    }
+    assert_eq!(sheet[&0].dependents, HashSet::from([1]));
+    assert_eq!(EvalStatus::message(status), "evaluation cancelled");
+
+    // Later edits run to the end
+    assert_eq!(
+        assign_formula(
+            &mut sheet,
+            &mut ranged,
+            &mut is_range,
+            total_dims,
+            1,
+            0,
+            "A1+4"
+        ),
+        Ok(())
+    );
+    assert_eq!(sheet[&3].value, Valtype::Int(4));
+}
+
+#[test]
+fn test_sheet_info() {
+    let mut sheet = Spreadsheet::new(10, 10);
+    let info = sheet.info();
+    assert_eq!((info.cells, info.errors, info.deepest_chain), (0, 0, 0));
+    assert_eq!(info.lines()[2], "Formulas: none");
+
+    for (cell, formula) in [
+        ("A1", "5"),
+        ("B1", "A1+1"),
+        ("C1", "SUM(A1:B1)"),
+        ("D1", "A1/0"),
+    ] {
+        let addr = CellAddr::parse(cell).unwrap();
+        assert_eq!(sheet.set_formula(addr, formula), Ok(()), "{}", cell);
+    }
+    let info = sheet.info();
+    assert_eq!(info.cells, 4);
+    assert_eq!(info.kinds, vec![("Const", 1), ("RoC", 2), ("Range", 1)]);
+    assert_eq!(info.errors, 1);
+    // C1 reads B1, which reads A1
+    assert_eq!(info.deepest_chain, 2);
+    assert_eq!(info.flag_bytes, 100);
+    assert!(info.cell_bytes > 0 && info.range_bytes > 0);
+    assert_eq!(
+        info.lines()[..4],
+        [
+            "Sheet: 10 rows x 10 columns",
+            "Cells: 4 non-empty, 1 with an error",
+            "Formulas: Const 1, RoC 2, Range 1",
+            "Deepest chain: 2 formulas",
+        ]
+    );
+    assert!(info.lines()[4].starts_with("Memory: ~"));
+}
+
+#[test]
+fn test_journal_replay() {
+    assert!(is_mutating("A1=B1+1"));
+    assert!(is_mutating("fill A1:A3=1"));
+    assert!(is_mutating("sort A1:B4 by B"));
+    assert!(is_mutating("calc manual"));
+    assert!(!is_mutating("find =SUM"));
+    assert!(!is_mutating("dump"));
+    assert!(!is_mutating("w"));
+    // A resize would change the size the journal replays on
+    assert!(!is_mutating("resize 20 20"));
+    assert_eq!(parse_journal_header("# journal 10 20"), Some((10, 20)));
+    assert_eq!(parse_journal_header("# journal 10"), None);
+    assert_eq!(parse_journal_header("A1=5"), None);
+
+    let total_dims = (10, 10);
+    // Commands are journaled as they are recorded at the prompt
+    let run = |sheet: &mut ScriptSheet, cmd: &str| {
+        let status = sheet.run(&[cmd])[0];
+        sheet.options.record(cmd, status);
+        status
+    };
+    let mut sheet = ScriptSheet::new(10, 10);
+    let path = std::env::temp_dir().join("spreadsheet_test_journal.txt");
+    let path = path.to_str().unwrap();
+    // The cells held before the journal starts open it
+    assert_eq!(run(&mut sheet, "B1=5"), Ok(()));
+    assert!(
+        sheet
+            .options
+            .start_journal(path, &sheet.cells, total_dims)
+            .is_ok()
+    );
+    // Queries and failed commands are left out
+    for cmd in ["A1=B1+2", "A2=A1/0", "find 5", "C1=SUM(A1:B1)", "A3=Z99"] {
+        let _ = run(&mut sheet, cmd);
+    }
+    // The journal being recorded cannot be replayed into itself
+    let replay = format!("replay {}", path);
+    assert_eq!(run(&mut sheet, &replay), Err(EvalStatus::UnrecognizedCmd));
+    assert_eq!(run(&mut sheet, "journal off"), Ok(()));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "# journal 10 10\nB1=5\nA1=B1+2\nA2=A1/0\nC1=SUM(A1:B1)\n"
+    );
+
+    let mut replayed = ScriptSheet::new(10, 10);
+    assert_eq!(run(&mut replayed, &replay), Ok(()));
+    assert_eq!(
+        dump_lines(&replayed.cells, 10),
+        dump_lines(&sheet.cells, 10)
+    );
+    assert_eq!(
+        journal_start(
+            &replayed.cells,
+            total_dims,
+            &replayed.options.names,
+            &replayed.options.validations,
+            &replayed.options.locks
+        )[0],
+        "# journal 10 10"
+    );
+
+    // A framed import is journaled as the cells it wrote, so it replays without the frame, and
+    // its empty fields leave B2 alone
+    let mut sheet = ScriptSheet::new(10, 10);
+    assert_eq!(run(&mut sheet, "A1=1"), Ok(()));
+    assert_eq!(run(&mut sheet, "B2=7"), Ok(()));
+    assert!(
+        sheet
+            .options
+            .start_journal(path, &sheet.cells, total_dims)
+            .is_ok()
+    );
+    let mut input = io::Cursor::new("2,=A1*3\n,\n#end\n");
+    let status = import_frame(
+        &mut sheet.cells,
+        &mut sheet.ranged,
+        &mut sheet.is_range,
+        &mut input,
+        "#begin csv",
+        total_dims,
+        &mut sheet.options,
+    );
+    assert_eq!(status, Some(Ok(())));
+    assert_eq!(run(&mut sheet, "C1=B1+A1"), Ok(()));
+    assert_eq!(run(&mut sheet, "journal off"), Ok(()));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "# journal 10 10\nA1=1\nB2=7\nA1=2\nB1=A1*3\nC1=B1+A1\n"
+    );
+    let mut replayed = ScriptSheet::new(10, 10);
+    assert_eq!(run(&mut replayed, &replay), Ok(()));
+    assert_eq!(
+        dump_lines(&replayed.cells, 10),
+        dump_lines(&sheet.cells, 10)
+    );
+    assert_eq!(replayed.cells.get(&2).unwrap().value, Valtype::Int(8)); // C1 = B1+A1
+
+    // An opened file is journaled as the cells it changed, so the journal replays without it
+    assert!(!is_journaled("open data.csv"));
+    assert!(!is_journaled("load data.wb"));
+    let csv = std::env::temp_dir().join("spreadsheet_test_journal_open.csv");
+    std::fs::write(&csv, "3,=A1+1\n").unwrap();
+    let mut sheet = ScriptSheet::new(10, 10);
+    assert_eq!(run(&mut sheet, "C3=9"), Ok(()));
+    assert!(
+        sheet
+            .options
+            .start_journal(path, &sheet.cells, total_dims)
+            .is_ok()
+    );
+    assert_eq!(run(&mut sheet, &format!("open {}", csv.display())), Ok(()));
+    assert_eq!(run(&mut sheet, "journal off"), Ok(()));
+    std::fs::remove_file(&csv).unwrap();
+    let journal = std::fs::read_to_string(path).unwrap();
+    assert!(!journal.contains("open "));
+    let mut replayed = ScriptSheet::new(10, 10);
+    assert_eq!(run(&mut replayed, &replay), Ok(()));
+    assert_eq!(
+        dump_lines(&replayed.cells, 10),
+        dump_lines(&sheet.cells, 10)
+    );
+    // A resize, which the journal cannot replay, is refused with its own status
+    assert_eq!(
+        EvalStatus::message(Err(EvalStatus::JournalRecording)),
+        "journal recording"
+    );
+
+    // The names, rules and locks the sheet already has are journaled with its cells
+    let mut sheet = ScriptSheet::new(10, 10);
+    let setup = [
+        "A1=1",
+        "A2=2",
+        "name range total A1:A2",
+        "B1=SUM(total)",
+        "validate A1:A5 int 0..9 mark",
+        "lock A1:A5",
+        "unlock A3",
+    ];
+    assert_eq!(sheet.run(&setup), [Ok(()); 7]);
+    assert!(
+        sheet
+            .options
+            .start_journal(path, &sheet.cells, total_dims)
+            .is_ok()
+    );
+    assert_eq!(run(&mut sheet, "journal off"), Ok(()));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "# journal 10 10\nname range TOTAL A1:A2\nA1=1\nB1=SUM(TOTAL)\nA2=2\n\
+         validate A1:A5 int 0..9 mark\nlock A1:A5\nunlock A3:A3\n"
+    );
+    let mut replayed = ScriptSheet::new(10, 10);
+    assert_eq!(run(&mut replayed, &replay), Ok(()));
+    assert_eq!(
+        replayed.run(&["C1=SUM(total)", "A1=5", "A3=50"]),
+        [
+            Ok(()),
+            Err(EvalStatus::CellLocked),
+            Err(EvalStatus::ValidationFailed)
+        ]
+    );
+    assert_eq!(replayed.run(&["name range total A1:A3"]), [Ok(())]);
+    assert_eq!(replayed.cells[&1].value, Valtype::Int(53)); // B1 moved with the name
+
+    // A journal recorded on a sheet of another size is refused
+    std::fs::write(path, "# journal 5 5\nA1=1\n").unwrap();
+    assert_eq!(run(&mut replayed, &replay), Err(EvalStatus::InvalidRange));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_compute_range_large_areas() {
+    let (total_rows, total_cols) = (999, 18278);
+    let (last_row, last_col) = (total_rows - 1, total_cols - 1);
+    let area = (total_rows * total_cols) as i64;
+    let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    for (r, c) in [(0, 0), (0, 1), (last_row, last_col)] {
+        set_cell(
+            &mut sheet,
+            total_cols,
+            r,
+            c,
+            CellData::Const,
+            Valtype::Int(i32::MAX),
+        );
+    }
+    let whole = |choice| compute_range(&sheet, total_cols, 0, last_row, 0, last_col, choice);
+    let overflow = Err(EvalStatus::ErrValue(CellError::Num));
+    // The sum no longer wraps around, so AVG divides the full sum by every cell of the sheet
+    assert_eq!(whole(4), overflow); // SUM
+    assert_eq!(whole(3), Ok((3 * i32::MAX as i64 / area) as i32)); // AVG
+    assert_eq!(whole(3), Ok(352));
+    assert_eq!(whole(6), Ok(area as i32)); // COUNT
+    assert_eq!((whole(1), whole(2)), (Ok(i32::MAX), Ok(0))); // MAX, MIN
+    assert_eq!(whole(5), Ok(870449)); // STDEV
+    let sheet_twice = [((0, 0), (last_row, last_col)); 2];
+    assert_eq!(
+        compute_areas(&sheet, total_cols, &sheet_twice, 6),
+        Ok(2 * area as i32)
+    );
+    assert_eq!(
+        compute_areas(&sheet, total_cols, &sheet_twice, 3),
+        Ok((6 * i32::MAX as i64 / (2 * area)) as i32)
+    );
+
+    // A sum that fits is unchanged, however large its terms
+    set_cell(
+        &mut sheet,
+        total_cols,
+        0,
+        1,
+        CellData::Const,
+        Valtype::Int(i32::MIN),
+    );
+    assert_eq!(compute_range(&sheet, total_cols, 0, 0, 0, 1, 4), Ok(-1));
+    // i32::MIN and i32::MAX spread by more than an i32 can hold
+    assert_eq!(compute_range(&sheet, total_cols, 0, 0, 0, 1, 5), overflow);
+
+    // The error reaches the cell holding the formula
+    let mut sheet = Spreadsheet::new(10, 10);
+    for (cell, formula) in [("A1", "2147483647"), ("A2", "1"), ("A3", "SUM(A1:A2)")] {
+        let addr = CellAddr::parse(cell).unwrap();
+        assert_eq!(sheet.set_formula(addr, formula), Ok(()), "{}", cell);
+    }
+    let a3 = CellAddr::parse("A3").unwrap();
+    assert_eq!(sheet.get_value(a3), Some(Valtype::Err(CellError::Num)));
+    assert_eq!(CellError::Num.to_string(), "#NUM!");
+}
+
+#[test]
+fn test_range_cache() {
+    let total_dims = (20, 10);
+    let formulas = [
+        (5, 0, "SUM(A1:C4)"),
+        (5, 1, "AVG(A1:C4)"),
+        (5, 2, "MAX(A1:C4)"),
+        (5, 3, "MIN(A1:C4)"),
+        (5, 4, "COUNT(A1:C4)"),
+        (5, 5, "STDEV(A1:C4)"),
+        (5, 6, "SUM(A1:B2,A1:A1)"),
+        (6, 0, "F6+1"),
+    ];
+    let edits = [
+        (0, 0, "5"),
+        (1, 1, "-3"),
+        (0, 0, "10"),
+        // Lowers the largest value, so MAX reads the range again
+        (0, 0, "2"),
+        (0, 1, "A1*2"),
+        (3, 2, "7"),
+        (0, 0, "1/0"),
+        (0, 0, "4"),
+        // Outside every range
+        (9, 9, "100"),
+    ];
+    // Both sheets take the same edits, one of them through a cache
+    let mut cached: HashMap<CellKey, Cell> = HashMap::new();
+    let mut plain: HashMap<CellKey, Cell> = HashMap::new();
+    let (mut ranged1, mut ranged2) = (HashMap::new(), HashMap::new());
+    let mut is_range1 = vec![false; 200];
+    let mut is_range2 = vec![false; 200];
+    let mut cache = RangeCache::default();
+    for &(row, col, formula) in formulas.iter().chain(&edits) {
+        let status = RangeCache::scope(&mut cache, || {
+            assign_formula(
+                &mut cached,
+                &mut ranged1,
+                &mut is_range1,
+                total_dims,
+                row,
+                col,
+                formula,
+            )
+        });
+        let expected = assign_formula(
+            &mut plain,
+            &mut ranged2,
+            &mut is_range2,
+            total_dims,
+            row,
+            col,
+            formula,
+        );
+        assert_eq!(status, expected, "{}", formula);
+        assert_eq!(
+            dump_lines(&cached, 10),
+            dump_lines(&plain, 10),
+            "{}",
+            formula
+        );
+    }
+    assert_eq!(plain[&50].value, Valtype::Int(16)); // 4 + 8 - 3 + 7
+    assert_eq!(plain[&53].value, Valtype::Int(-3));
+    // Every range formula but STDEV keeps its totals
+    assert_eq!(cache.len(), 6);
+    assert_eq!(cache.hits(), 31);
+
+    // A recalculation outside single-cell edits forgets every total
+    RangeCache::scope(&mut cache, || {
+        recalc_all(&mut cached, &ranged1, total_dims);
+    });
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_sort_command() {
+    let spec = parse_sort_args("A1:C5 by b desc natural nocase", 10, 5).unwrap();
+    assert_eq!(spec.corners, ((0, 0), (4, 2)));
+    assert_eq!(
+        (
+            spec.key_col,
+            spec.descending,
+            spec.natural,
+            spec.ignore_case
+        ),
+        (1, true, true, true)
+    );
+    assert_eq!(
+        parse_sort_args("A1:A3 order=Low,High", 10, 5)
+            .unwrap()
+            .order,
+        Some(SortOrder::List(vec!["Low".to_string(), "High".to_string()]))
+    );
+    assert_eq!(
+        parse_sort_args("A1:A3 order=E1:E3", 10, 5).unwrap().order,
+        Some(SortOrder::Range(((0, 4), (2, 4))))
+    );
+    assert_eq!(
+        parse_sort_args("A1:B5 by C", 10, 5),
+        Err(EvalStatus::InvalidRange)
+    );
+    assert_eq!(
+        parse_sort_args("A1:B5 upward", 10, 5),
+        Err(EvalStatus::UnrecognizedCmd)
+    );
+
+    // Runs of digits compare by value in natural order, ignoring leading zeros
+    assert_eq!(compare_text("A10", "A2", false, false), Ordering::Less);
+    assert_eq!(compare_text("A10", "A2", true, false), Ordering::Greater);
+    assert_eq!(compare_text("A007", "A7", true, false), Ordering::Equal);
+    assert_eq!(compare_text("b", "A", false, false), Ordering::Greater);
+    assert_eq!(compare_text("b", "A", false, true), Ordering::Greater);
+    assert_eq!(compare_text("a", "B", false, true), Ordering::Less);
+
+    let text = |sheet: &ScriptSheet, key: CellKey| match &sheet.cells[&key].value {
+        Valtype::Str(text) => text.as_str().to_string(),
+        value => panic!("{:?} is not text", value),
+    };
+    let formula = |sheet: &ScriptSheet, key: CellKey| formula_string(&sheet.cells[&key]);
+    let mut sheet = ScriptSheet::new(10, 5);
+    assert_eq!(
+        sheet.run(&[
+            "A1=\"item10\"",
+            "A2=\"Item2\"",
+            "A3=\"item1\"",
+            "C1=1",
+            "C2=2",
+            "C3=3",
+            "C4=4",
+            "B1=C1*10",
+            "B2=C2*10",
+            "B3=C3*10",
+            "B4=C4*10",
+        ]),
+        [Ok(()); 11]
+    );
+
+    // Each row moves with its key, its formulas shifted as if copied; the empty key stays last
+    assert_eq!(
+        sheet.run(&["B3=$C$3*10", "sort A1:B4 natural nocase"]),
+        [Ok(()); 2]
+    );
+    assert_eq!(
+        [text(&sheet, 0), text(&sheet, 5), text(&sheet, 10)],
+        ["item1", "Item2", "item10"]
+    );
+    assert_eq!(formula(&sheet, 1), "$C$3*10");
+    assert_eq!(sheet.cells[&1].value, Valtype::Int(30));
+    assert_eq!(formula(&sheet, 11), "C3*10");
+    assert_eq!(sheet.cells[&11].value, Valtype::Int(30));
+    assert_eq!(formula(&sheet, 16), "C4*10");
+    assert!(!sheet.cells.contains_key(&15));
+
+    // Without natural order, digits compare one by one; descending keeps empty cells last
+    assert_eq!(sheet.run(&["sort A1:B4 nocase"]), [Ok(())]);
+    assert_eq!(
+        [text(&sheet, 0), text(&sheet, 5), text(&sheet, 10)],
+        ["item1", "item10", "Item2"]
+    );
+    assert_eq!(sheet.run(&["sort A1:B4 desc natural nocase"]), [Ok(())]);
+    assert_eq!(
+        [text(&sheet, 0), text(&sheet, 5), text(&sheet, 10)],
+        ["item10", "Item2", "item1"]
+    );
+    assert_eq!(formula(&sheet, 16), "C4*10");
+
+    // Numbers sort by value, before text, and descending order puts text first
+    assert_eq!(
+        sheet.run(&["C1=\"x\"", "sort C1:C4 by C"]),
+        [Ok(()), Ok(())]
+    );
+    assert_eq!(sheet.cells[&2].value, Valtype::Int(2));
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(3));
+    assert_eq!(text(&sheet, 17), "x");
+    assert_eq!(sheet.run(&["sort C1:C4 desc"]), [Ok(())]);
+    assert_eq!(text(&sheet, 2), "x");
+    assert_eq!(sheet.cells[&7].value, Valtype::Int(4));
+
+    // A custom order, inline or read from a range, ignores case
+    assert_eq!(
+        sheet.run(&[
+            "D1=\"High\"",
+            "D2=\"Low\"",
+            "D3=\"Medium\"",
+            "sort D1:D3 order=Low,Medium,High",
+        ]),
+        [Ok(()); 4]
+    );
+    assert_eq!(
+        [text(&sheet, 3), text(&sheet, 8), text(&sheet, 13)],
+        ["Low", "Medium", "High"]
+    );
+    assert_eq!(
+        sheet.run(&[
+            "E1=\"high\"",
+            "E2=\"medium\"",
+            "E3=\"low\"",
+            "sort D1:D3 order=E1:E3",
+        ]),
+        [Ok(()); 4]
+    );
+    assert_eq!(
+        [text(&sheet, 3), text(&sheet, 8), text(&sheet, 13)],
+        ["High", "Medium", "Low"]
+    );
+
+    // Nothing moves if a cell of the range is locked
+    assert_eq!(
+        sheet.run(&["lock B2", "sort A1:B4 natural"]),
+        [Ok(()), Err(EvalStatus::CellLocked)]
+    );
+    assert_eq!(text(&sheet, 0), "item10");
+}
+
+#[test]
+fn test_collab_log() {
+    use spreadsheet::collab::{Collab, MAX_CHANGES, PEER_TIMEOUT};
+
+    assert!(Collab::valid_client("ann_2-b"));
+    assert!(!Collab::valid_client(""));
+    assert!(!Collab::valid_client("ann b"));
+    assert!(!Collab::valid_client(&"a".repeat(33)));
+
+    // Each client keeps its color while it stays, and is dropped once it stops refreshing
+    let mut collab = Collab::default();
+    let start = Instant::now();
+    assert_eq!(collab.move_cursor("ann", "A1", start).color, 0);
+    assert_eq!(collab.move_cursor("bob", "B2", start).color, 1);
+    let later = start + PEER_TIMEOUT / 2;
+    assert_eq!(collab.move_cursor("ann", "C3", later).color, 0);
+    let peers = collab.peers(later);
+    assert_eq!(
+        peers
+            .iter()
+            .map(|p| (p.client.as_str(), p.cell.as_str()))
+            .collect::<Vec<_>>(),
+        [("ann", "C3"), ("bob", "B2")]
+    );
+    let peers = collab.peers(start + PEER_TIMEOUT);
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].client, "ann");
+    assert!(collab.leave("ann"));
+    assert!(!collab.leave("ann"));
+    assert!(collab.peers(later).is_empty());
+
+    // The log keeps the newest edits; a client further behind has to read the sheet again
+    assert_eq!(collab.changes_since(0), Some(Vec::new()));
+    for n in 0..MAX_CHANGES + 2 {
+        collab.record_edit("A1", &n.to_string(), None);
+    }
+    let version = collab.version();
+    assert_eq!(version, MAX_CHANGES as u64 + 2);
+    assert_eq!(collab.changes_since(version), Some(Vec::new()));
+    let changes = collab.changes_since(version - 1).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].formula, (MAX_CHANGES + 1).to_string());
+    assert_eq!(collab.changes_since(2).unwrap().len(), MAX_CHANGES);
+    assert_eq!(collab.changes_since(1), None);
+    assert_eq!(collab.changes_since(version + 1), None);
+}
@@ -0,0 +1,573 @@
+//! # TUI Module
+//! This module runs the sheet as a full-screen terminal interface, for sessions where the GUI
+//! cannot be opened, such as over SSH. Started with `--tui`, it switches the terminal to raw
+//! mode and an alternate screen, and draws the part of the sheet around a cursor:
+//!
+//! ```text
+//! A1: B1+2
+//!                A           B           C
+//!    1  7           5           0
+//!    2  0           0           0
+//! Ready
+//! ```
+//!
+//! The arrow keys move the cursor. Typing starts editing the cell with the typed text, and
+//! Enter or F2 edits its formula. While editing, Enter commits and moves down, Tab commits and
+//! moves right, and Esc cancels. `:` opens a command line for any command of the prompt.
+//! Ctrl+Q or Ctrl+C quits. Edits and commands go through the same `run_command` as the prompt.
+//!
+//! The prompt itself also reads its lines through this module when stdin is a terminal, so the
+//! Up and Down keys recall earlier commands.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use spreadsheet::storage::SheetStorage;
+use spreadsheet::utils::{
+    display_width, formula_string, pad_display, sparkline, to_cell_name, truncate_display,
+};
+use spreadsheet::{CellKey, EvalStatus, Valtype};
+
+use crate::{SessionOptions, run_command};
+
+/// Width of each column of the grid, in characters.
+const COL_WIDTH: usize = 10;
+/// Width of the row labels, in characters.
+const LABEL_WIDTH: usize = 4;
+
+/// A key read from the terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    F2,
+    Char(char),
+    /// Ctrl+Q or Ctrl+C.
+    Quit,
+}
+
+/// Reads the first key from bytes received from the terminal.
+///
+/// # Arguments
+/// * `bytes` - The bytes not read yet; must not be empty.
+///
+/// # Returns
+/// * `(Option<Key>, usize)` - The key, or `None` for a sequence without a meaning here, and
+///   the number of bytes it took.
+///
+/// # Examples
+/// ```
+/// assert_eq!(parse_key(b"\x1b[Ab"), (Some(Key::Up), 3));
+/// assert_eq!(parse_key(b"b"), (Some(Key::Char('b')), 1));
+/// ```
+pub fn parse_key(bytes: &[u8]) -> (Option<Key>, usize) {
+    match bytes {
+        [0x1b, b'[' | b'O', rest @ ..] => {
+            // CSI and SS3 sequences end with a byte from '@' to '~'
+            let Some(end) = rest.iter().position(|b| (0x40..=0x7e).contains(b)) else {
+                return (None, bytes.len());
+            };
+            let key = match &rest[..=end] {
+                b"A" => Some(Key::Up),
+                b"B" => Some(Key::Down),
+                b"C" => Some(Key::Right),
+                b"D" => Some(Key::Left),
+                b"Q" | b"12~" => Some(Key::F2),
+                _ => None,
+            };
+            (key, end + 3)
+        }
+        [0x1b, ..] => (Some(Key::Escape), 1),
+        [b'\r' | b'\n', ..] => (Some(Key::Enter), 1),
+        [b'\t', ..] => (Some(Key::Tab), 1),
+        [0x7f | 0x08, ..] => (Some(Key::Backspace), 1),
+        [0x03 | 0x11, ..] => (Some(Key::Quit), 1),
+        [first, ..] if *first < 0x20 => (None, 1),
+        _ => {
+            // One UTF-8 character, whose length is given by its first byte
+            let len = match bytes[0] {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            let len = len.min(bytes.len());
+            let key = std::str::from_utf8(&bytes[..len])
+                .ok()
+                .and_then(|text| text.chars().next())
+                .map(Key::Char);
+            (key, len)
+        }
+    }
+}
+
+/// What the edit bar is used for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Moving the cursor over the grid.
+    Navigate,
+    /// Editing the formula of the cell under the cursor.
+    Edit(String),
+    /// Typing a command of the prompt after `:`.
+    Command(String),
+}
+
+/// The state of the terminal interface.
+///
+/// # Fields
+/// * `cursor` - The (row, col) of the cell under the cursor.
+/// * `start` - The (row, col) of the top-left cell in view.
+/// * `mode` - What keys currently do.
+/// * `status` - The message on the last line, such as the outcome of the last edit.
+/// * `screen` - The size of the terminal, in (lines, characters).
+#[derive(Debug)]
+pub struct Tui {
+    pub cursor: (usize, usize),
+    pub start: (usize, usize),
+    pub mode: Mode,
+    pub status: String,
+    pub screen: (usize, usize),
+}
+
+impl Default for Tui {
+    fn default() -> Self {
+        Tui {
+            cursor: (0, 0),
+            start: (0, 0),
+            mode: Mode::Navigate,
+            status: "Ready".to_string(),
+            screen: (24, 80),
+        }
+    }
+}
+
+impl Tui {
+    /// The number of (rows, cols) of cells that fit on the screen below the edit bar and
+    /// headers and above the status line.
+    fn grid_size(&self) -> (usize, usize) {
+        (
+            self.screen.0.saturating_sub(3).max(1),
+            (self.screen.1.saturating_sub(LABEL_WIDTH + 2) / (COL_WIDTH + 2)).max(1),
+        )
+    }
+
+    /// Moves the cursor by one step, scrolling to keep it on the screen.
+    ///
+    /// # Arguments
+    /// * `step` - Tuple of the (row, col) change, each -1, 0 or 1.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    fn step(&mut self, step: (isize, isize), total_dims: (usize, usize)) {
+        let row = self.cursor.0.saturating_add_signed(step.0);
+        let col = self.cursor.1.saturating_add_signed(step.1);
+        self.cursor = (row.min(total_dims.0 - 1), col.min(total_dims.1 - 1));
+        self.scroll_to_cursor();
+    }
+
+    /// Scrolls the view so that the cursor is on the screen.
+    fn scroll_to_cursor(&mut self) {
+        let (rows, cols) = self.grid_size();
+        self.start.0 = self
+            .start
+            .0
+            .clamp((self.cursor.0 + 1).saturating_sub(rows), self.cursor.0);
+        self.start.1 = self
+            .start
+            .1
+            .clamp((self.cursor.1 + 1).saturating_sub(cols), self.cursor.1);
+    }
+
+    /// Handles one key.
+    ///
+    /// # Arguments
+    /// * `key` - The key pressed.
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `ranged` - A hash map tracking ranges for dependency management.
+    /// * `is_range` - A boolean array indicating whether each cell is part of a range.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    /// * `options` - The session settings shared with the commands.
+    ///
+    /// # Returns
+    /// * `bool` - `false` once the interface should close.
+    pub fn handle_key<S: SheetStorage + ?Sized>(
+        &mut self,
+        key: Key,
+        spreadsheet: &mut S,
+        ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+        is_range: &mut [bool],
+        total_dims: (usize, usize),
+        options: &mut SessionOptions,
+    ) -> bool {
+        if key == Key::Quit {
+            return false;
+        }
+        // An edit or command to run, and where the cursor moves after an edit
+        let mut pending = None;
+        match (&mut self.mode, key) {
+            (Mode::Navigate, Key::Up) => self.step((-1, 0), total_dims),
+            (Mode::Navigate, Key::Down) => self.step((1, 0), total_dims),
+            (Mode::Navigate, Key::Left) => self.step((0, -1), total_dims),
+            (Mode::Navigate, Key::Right | Key::Tab) => self.step((0, 1), total_dims),
+            (Mode::Navigate, Key::Enter | Key::F2) => {
+                let key = (self.cursor.0 * total_dims.1 + self.cursor.1) as CellKey;
+                let formula = spreadsheet
+                    .get(&key)
+                    .map_or_else(String::new, formula_string);
+                self.mode = Mode::Edit(formula);
+            }
+            (Mode::Navigate, Key::Char(':')) => self.mode = Mode::Command(String::new()),
+            (Mode::Navigate, Key::Char(c)) if !c.is_whitespace() => {
+                // Typing over a cell replaces its contents
+                self.mode = Mode::Edit(c.to_string());
+            }
+            (Mode::Edit(text) | Mode::Command(text), Key::Char(c)) => text.push(c),
+            (Mode::Edit(text) | Mode::Command(text), Key::Backspace) => {
+                text.pop();
+            }
+            (Mode::Edit(_) | Mode::Command(_), Key::Escape) => self.mode = Mode::Navigate,
+            (Mode::Edit(text), Key::Enter | Key::Tab) => {
+                let name = to_cell_name(self.cursor.0, self.cursor.1);
+                let step = if key == Key::Tab { (0, 1) } else { (1, 0) };
+                pending = Some((format!("{}={}", name, text.trim()), Some(step)));
+            }
+            (Mode::Command(text), Key::Enter) => {
+                if text.trim() == "q" {
+                    return false;
+                }
+                pending = Some((text.trim().to_string(), None));
+            }
+            _ => {}
+        }
+        if let Some((command, step)) = pending {
+            self.mode = Mode::Navigate;
+            let start = self.start;
+            let status = run_command(
+                spreadsheet,
+                ranged,
+                is_range,
+                &command,
+                total_dims,
+                options,
+                &mut (&mut self.start.0, &mut self.start.1),
+            );
+            options.record(&command, status);
+            self.status = EvalStatus::message(status).to_string();
+            // Changes of watched cells replace the status, as there is no room to print them
+            let changes = options.watches.changes(spreadsheet, total_dims.1);
+            if !changes.is_empty() {
+                self.status = format!("watch {}", changes.join(", "));
+            }
+            options.count_command(spreadsheet, ranged, is_range, total_dims);
+            if let Some(step) = step {
+                self.step(step, total_dims);
+            } else if self.start != start {
+                // A command that moved the view, such as `scroll_to`, takes the cursor along
+                self.cursor = self.start;
+            }
+        }
+        true
+    }
+
+    /// Draws the whole screen.
+    ///
+    /// Each line clears what is left of the previous frame after it, so the screen is redrawn
+    /// without flicker.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    ///
+    /// # Returns
+    /// * `String` - The text and escape sequences to write to the terminal.
+    pub fn render<S: SheetStorage + ?Sized>(
+        &self,
+        spreadsheet: &S,
+        total_dims: (usize, usize),
+    ) -> String {
+        let (rows, cols) = self.grid_size();
+        let rows = rows.min(total_dims.0 - self.start.0);
+        let cols = cols.min(total_dims.1 - self.start.1);
+        let name = to_cell_name(self.cursor.0, self.cursor.1);
+        let mut lines = Vec::with_capacity(rows + 3);
+        lines.push(match &self.mode {
+            Mode::Navigate => {
+                let key = (self.cursor.0 * total_dims.1 + self.cursor.1) as CellKey;
+                let formula = spreadsheet
+                    .get(&key)
+                    .map_or_else(String::new, formula_string);
+                format!("{}: {}", name, formula)
+            }
+            Mode::Edit(text) => format!("{}> {}_", name, text),
+            Mode::Command(text) => format!(":{}_", text),
+        });
+
+        let mut header = " ".repeat(LABEL_WIDTH + 2);
+        for col in self.start.1..self.start.1 + cols {
+            let label = to_cell_name(0, col);
+            let label = &label[..label.len() - 1];
+            header.push_str(&format!("\x1b[2m{:>COL_WIDTH$}\x1b[0m  ", label));
+        }
+        lines.push(header);
+        for row in self.start.0..self.start.0 + rows {
+            let mut line = format!("\x1b[2m{:>LABEL_WIDTH$}\x1b[0m  ", row + 1);
+            for col in self.start.1..self.start.1 + cols {
+                let cell = spreadsheet.get(&((row * total_dims.1 + col) as CellKey));
+                let value = cell.map(|cell| &cell.value);
+                let sparkline = cell.and_then(|cell| sparkline(spreadsheet, total_dims, cell));
+                let mut text = match (sparkline, value) {
+                    (Some(line), _) => line,
+                    (None, Some(Valtype::Int(v))) => v.to_string(),
+                    (None, Some(Valtype::Str(s))) => s.to_string(),
+                    (None, Some(Valtype::Err(error))) => error.to_string(),
+                    (None, None) => "0".to_string(),
+                };
+                // The grid keeps its layout, so values too wide for a column are cut short
+                if display_width(&text) > COL_WIDTH {
+                    text = format!("{}~", truncate_display(&text, COL_WIDTH - 1));
+                }
+                let style = if (row, col) == self.cursor {
+                    "\x1b[7m"
+                } else if matches!(value, Some(Valtype::Err(_))) {
+                    "\x1b[31m"
+                } else {
+                    ""
+                };
+                line.push_str(&format!(
+                    "{}{}\x1b[0m  ",
+                    style,
+                    pad_display(&text, COL_WIDTH)
+                ));
+            }
+            lines.push(line);
+        }
+        lines.push(self.status.clone());
+
+        // No line break follows the status line, which would scroll a full screen
+        format!("\x1b[H{}\x1b[K\x1b[J", lines.join("\x1b[K\r\n"))
+    }
+}
+
+/// Puts the terminal in raw mode, optionally on an alternate screen, and restores it when
+/// dropped.
+struct Terminal {
+    saved: libc::termios,
+    alternate: bool,
+}
+
+impl Terminal {
+    /// Switches the terminal connected to stdin to raw mode and the alternate screen.
+    fn enter() -> io::Result<Self> {
+        let mut terminal = Terminal::raw()?;
+        print!("\x1b[?1049h\x1b[?25l");
+        terminal.alternate = true;
+        io::stdout().flush()?;
+        Ok(terminal)
+    }
+
+    /// Switches the terminal connected to stdin to raw mode, keeping the text on screen.
+    fn raw() -> io::Result<Self> {
+        // SAFETY: `termios` is plain data, filled in by `tcgetattr` before it is read
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: the pointer refers to a live `termios`
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        // SAFETY: the pointers refer to live `termios` values
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Terminal {
+            saved,
+            alternate: false,
+        })
+    }
+
+    /// Reads the size of the terminal.
+    ///
+    /// # Returns
+    /// * `Option<(usize, usize)>` - The (lines, characters), or `None` if it cannot be read.
+    fn size() -> Option<(usize, usize)> {
+        // SAFETY: `winsize` is plain data, filled in by the ioctl before it is read
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: TIOCGWINSZ writes one `winsize` through the pointer
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+        (ok && size.ws_row > 0 && size.ws_col > 0)
+            .then_some((size.ws_row as usize, size.ws_col as usize))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.alternate {
+            print!("\x1b[?25h\x1b[?1049l");
+            let _ = io::stdout().flush();
+        }
+        // SAFETY: the pointer refers to the `termios` saved by `enter`
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/// Runs the terminal interface until it is closed or the input ends.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `options` - The session settings shared with the commands.
+///
+/// # Returns
+/// * `io::Result<()>` - An error if stdin is not a terminal, or it could not be read.
+pub fn run<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    options: &mut SessionOptions,
+) -> io::Result<()> {
+    let _terminal = Terminal::enter()?;
+    let mut tui = Tui::default();
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    let mut buf = [0u8; 64];
+    loop {
+        if let Some(screen) = Terminal::size() {
+            tui.screen = screen;
+            tui.scroll_to_cursor();
+        }
+        stdout.write_all(tui.render(spreadsheet, total_dims).as_bytes())?;
+        stdout.flush()?;
+        let n = stdin.read(&mut buf)?;
+        let mut bytes = &buf[..n];
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        while !bytes.is_empty() {
+            let (key, len) = parse_key(bytes);
+            bytes = &bytes[len..];
+            if let Some(key) = key
+                && !tui.handle_key(key, spreadsheet, ranged, is_range, total_dims, options)
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Reads the lines typed at the prompt, where the Up and Down keys recall earlier commands.
+///
+/// # Fields
+/// * `line` - The line being typed.
+/// * `recalled` - The index in the history of the command shown, or `None` for a new line.
+/// * `draft` - The new line, kept while an earlier command is shown in its place.
+/// * `pending` - Bytes read past the end of the last line, such as the rest of a paste.
+#[derive(Debug, Default)]
+pub struct LineReader {
+    line: String,
+    recalled: Option<usize>,
+    draft: String,
+    pending: Vec<u8>,
+}
+
+impl LineReader {
+    /// Applies a key to the line being typed.
+    ///
+    /// # Arguments
+    /// * `key` - The key pressed.
+    /// * `history` - The commands typed before, oldest first.
+    ///
+    /// # Returns
+    /// * `Option<String>` - The line, once Enter is pressed.
+    pub fn handle_key(&mut self, key: Key, history: &[String]) -> Option<String> {
+        match key {
+            Key::Enter => {
+                self.recalled = None;
+                self.draft.clear();
+                return Some(std::mem::take(&mut self.line));
+            }
+            Key::Up => {
+                let index = match self.recalled {
+                    Some(index) => index.saturating_sub(1),
+                    None if history.is_empty() => return None,
+                    None => {
+                        self.draft = std::mem::take(&mut self.line);
+                        history.len() - 1
+                    }
+                };
+                self.recalled = Some(index);
+                self.line = history[index].clone();
+            }
+            Key::Down => match self.recalled {
+                Some(index) if index + 1 < history.len() => {
+                    self.recalled = Some(index + 1);
+                    self.line = history[index + 1].clone();
+                }
+                Some(_) => {
+                    self.recalled = None;
+                    self.line = std::mem::take(&mut self.draft);
+                }
+                None => {}
+            },
+            Key::Backspace => {
+                self.line.pop();
+            }
+            Key::Char(c) => self.line.push(c),
+            _ => {}
+        }
+        None
+    }
+
+    /// Reads a line typed at the prompt. A terminal is read in raw mode, redrawing the line as
+    /// keys are pressed; other input, such as a piped script, is read as it is.
+    ///
+    /// # Arguments
+    /// * `history` - The commands typed before, oldest first.
+    ///
+    /// # Returns
+    /// * `io::Result<Option<String>>` - The line, or `None` once the input ends or Ctrl+C,
+    ///   Ctrl+Q or Ctrl+D on an empty line is pressed.
+    pub fn read_line(&mut self, history: &[String]) -> io::Result<Option<String>> {
+        // SAFETY: `isatty` only inspects the file descriptor
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            let mut input = String::new();
+            return Ok((io::stdin().read_line(&mut input)? > 0).then_some(input));
+        }
+        let _terminal = Terminal::raw()?;
+        let mut stdin = io::stdin().lock();
+        let mut stdout = io::stdout().lock();
+        // The line is redrawn from the cursor position saved after the prompt
+        stdout.write_all(b"\x1b7")?;
+        let mut buf = [0u8; 64];
+        loop {
+            while !self.pending.is_empty() {
+                let (key, len) = parse_key(&self.pending);
+                let end_of_input = self.pending[0] == 0x04 && self.line.is_empty();
+                self.pending.drain(..len);
+                if end_of_input || key == Some(Key::Quit) {
+                    self.pending.clear();
+                    stdout.write_all(b"\r\n")?;
+                    return Ok(None);
+                }
+                if let Some(key) = key
+                    && let Some(line) = self.handle_key(key, history)
+                {
+                    write!(stdout, "\x1b8\x1b[K{}\r\n", line)?;
+                    return Ok(Some(line));
+                }
+            }
+            write!(stdout, "\x1b8\x1b[K{}", self.line)?;
+            stdout.flush()?;
+            let n = stdin.read(&mut buf)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+        }
+    }
+}
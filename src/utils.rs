@@ -2,42 +2,1204 @@
 //! This module provides utility functions for the spreadsheet application,
 //! including cell reference conversion, arithmetic operations, range computations,
 //! and helper functions for dependency management.
-use std::{collections::HashMap, f64, thread::sleep, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    f64,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::sleep,
+    time::Duration,
+};
 
-use crate::{Cell, STATUS_CODE, Valtype};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-/// A global flag indicating if an evaluation error occurred.
-pub static mut EVAL_ERROR: bool = false;
+use crate::format::Corners;
+use crate::storage::SheetStorage;
+use crate::{
+    Cell, CellAddr, CellData, CellError, CellKey, CellRef, EvalStatus, Expr, Lookup, Operand,
+    Valtype,
+};
+
+/// How often a `SLEEP` being evaluated checks whether it was cancelled.
+pub const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+thread_local! {
+    /// The token installed on this thread with `CancelToken::install`.
+    static CANCEL_TOKEN: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+    /// The context installed on this thread with `EvalContext::install` or `EvalContext::scope`.
+    static EVAL_CONTEXT: RefCell<Option<EvalContext>> = const { RefCell::new(None) };
+}
+
+/// Stops a long recalculation, e.g. on Esc in the GUI or Ctrl+C in the CLI.
+///
+/// The token is installed on the thread that edits the sheet, and a clone is kept by whoever
+/// may cancel. While `parser::update_and_recalc` runs on that thread, `cancel` wakes a `SLEEP`
+/// within `CANCEL_POLL` and stops the recalculation before its next cell; every value it
+/// changed is put back and the edited cell is rolled back to its backup, as for a cycle, with
+/// `EvalStatus::Cancelled`. Threads without a token are never cancelled.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// # use std::{thread, time::Duration};
+/// let token = CancelToken::default();
+/// token.install();
+/// let canceller = token.clone();
+/// thread::spawn(move || {
+///     thread::sleep(Duration::from_millis(100));
+///     canceller.cancel();
+/// });
+/// // A1=SLEEP(5) returns after about 0.1s, and A1 keeps its previous formula
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+/// The flags shared by the clones of a `CancelToken`.
+#[derive(Debug, Default)]
+struct CancelState {
+    running: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+impl CancelToken {
+    /// Makes the recalculations of the current thread stop when this token is cancelled.
+    pub fn install(&self) {
+        CANCEL_TOKEN.with(|token| *token.borrow_mut() = Some(self.clone()));
+    }
+
+    /// Returns the token installed on the current thread, if any.
+    pub fn current() -> Option<CancelToken> {
+        CANCEL_TOKEN.with(|token| token.borrow().clone())
+    }
+
+    /// Asks the running recalculation to stop. Only atomics are touched, so it may be called
+    /// from a signal handler.
+    ///
+    /// # Returns
+    /// * `bool` - `true` if a recalculation was running; otherwise nothing is cancelled, so a
+    ///   later recalculation runs to the end.
+    pub fn cancel(&self) -> bool {
+        let running = self.0.running.load(Ordering::SeqCst);
+        if running {
+            self.0.cancelled.store(true, Ordering::SeqCst);
+        }
+        running
+    }
+
+    /// Returns whether the running recalculation was asked to stop.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Marks a recalculation as started or finished, forgetting any earlier request to stop.
+    pub(crate) fn set_running(&self, running: bool) {
+        self.0.cancelled.store(false, Ordering::SeqCst);
+        self.0.running.store(running, Ordering::SeqCst);
+    }
+}
+
+/// The state a session evaluates the volatile formulas of its sheet with: the seed of `RAND`
+/// and `RANDBETWEEN`, set with `seed <n>`, the number of recalculations that redrew them since,
+/// whether the sheet may hold volatile formulas at all, and whether it runs in safe mode, set
+/// by `--safe`, where volatile formulas are rejected and no evaluation ever sleeps. With the
+/// `scripting` feature, it also holds the script functions loaded with `--functions`.
+///
+/// Each session, or each sheet of the GUI, owns a context and installs it on the thread that
+/// edits its sheet, as with `CancelToken`; the recalculation passes it down to every cell it
+/// evaluates. Sessions thus never draw from each other's seed, and a thread on which no context
+/// was installed gets one of its own.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::parser::reseed;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+/// let context = EvalContext::default();
+/// // A1 = RANDBETWEEN(1,6)
+/// context.scope(|| assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "RANDBETWEEN(1,6)"))?;
+/// context.scope(|| reseed(&mut sheet, &ranged, (3, 3), 42));
+/// let first = sheet[&0].value.clone();
+/// context.scope(|| reseed(&mut sheet, &ranged, (3, 3), 42));
+/// assert_eq!(sheet[&0].value, first);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct EvalContext(Arc<EvalState>);
+
+/// The values shared by the clones of an `EvalContext`.
+#[derive(Debug, Default)]
+struct EvalState {
+    seed: AtomicU64,
+    draws: AtomicU64,
+    volatile_seen: AtomicBool,
+    safe: AtomicBool,
+    #[cfg(feature = "scripting")]
+    functions: std::sync::RwLock<Option<Arc<crate::scripting::UserFunctions>>>,
+}
+
+impl EvalContext {
+    /// Makes the recalculations of the current thread use this context.
+    pub fn install(&self) {
+        EVAL_CONTEXT.with(|context| *context.borrow_mut() = Some(self.clone()));
+    }
+
+    /// Runs a closure with this context installed on the current thread, then installs the one
+    /// that was there before.
+    ///
+    /// # Arguments
+    /// * `run` - The closure, which edits the sheet of this context.
+    ///
+    /// # Returns
+    /// * `R` - What the closure returned.
+    pub fn scope<R>(&self, run: impl FnOnce() -> R) -> R {
+        let previous = EVAL_CONTEXT.with(|context| context.borrow_mut().replace(self.clone()));
+        let result = run();
+        EVAL_CONTEXT.with(|context| *context.borrow_mut() = previous);
+        result
+    }
+
+    /// Returns the context installed on the current thread, first installing a new one if there
+    /// is none.
+    pub fn current() -> EvalContext {
+        EVAL_CONTEXT.with(|context| context.borrow_mut().get_or_insert_default().clone())
+    }
+
+    /// Sets the seed of `RAND` and `RANDBETWEEN`, so that the draws start again from it.
+    ///
+    /// # Arguments
+    /// * `seed` - The new seed.
+    pub fn set_seed(&self, seed: u64) {
+        self.0.seed.store(seed, Ordering::Relaxed);
+        self.0.draws.store(0, Ordering::Relaxed);
+    }
+
+    /// Starts a new round of draws, for a recalculation that redraws `RAND` and `RANDBETWEEN`.
+    pub(crate) fn next_draw(&self) {
+        self.0.draws.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that the sheet may hold volatile formulas, so that recalculations look for them.
+    pub fn note_volatile(&self) {
+        self.0.volatile_seen.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the sheet may hold volatile formulas; sheets without any do not look
+    /// for them at every recalculation.
+    pub fn volatile_seen(&self) -> bool {
+        self.0.volatile_seen.load(Ordering::Relaxed)
+    }
+
+    /// Turns safe mode on or off: in safe mode SLEEP, RAND and RANDBETWEEN formulas are
+    /// rejected and no evaluation sleeps, not even that of a SLEEP cell restored from a
+    /// workbook.
+    ///
+    /// # Arguments
+    /// * `safe` - Whether the session runs in safe mode.
+    pub fn set_safe(&self, safe: bool) {
+        self.0.safe.store(safe, Ordering::Relaxed);
+    }
+
+    /// Returns whether the session runs in safe mode.
+    pub fn is_safe(&self) -> bool {
+        self.0.safe.load(Ordering::Relaxed)
+    }
+
+    /// Sets the script functions the session's formulas may call, replacing any set before.
+    ///
+    /// # Arguments
+    /// * `functions` - The compiled script, or `None` for no script functions.
+    #[cfg(feature = "scripting")]
+    pub fn set_functions(&self, functions: Option<Arc<crate::scripting::UserFunctions>>) {
+        *self.0.functions.write().unwrap() = functions;
+    }
+
+    /// Returns the script functions the session's formulas may call, if a script was loaded.
+    #[cfg(feature = "scripting")]
+    pub fn functions(&self) -> Option<Arc<crate::scripting::UserFunctions>> {
+        self.0.functions.read().unwrap().clone()
+    }
+}
 
 /// Converts a cell reference (e.g., "A1") to row and column indices (0-based).
 ///
+/// Absolute markers are ignored, so "$A$1" resolves to the same cell as "A1".
+///
 /// # Arguments
 /// * `s` - The cell reference string.
 ///
 /// # Returns
-/// A tuple `(usize, usize)` representing (row, column) indices.
-///
-/// # Panics
-/// Panics if the string format is invalid (should be handled by caller).
+/// * `Result<(usize, usize), EvalStatus>` - The (row, column) indices,
+///   `EvalStatus::UnrecognizedCmd` if the column holds anything but the letters `A` to `Z`, or
+///   `EvalStatus::InvalidRange` if the row or column is missing, 0 or too large to count.
 ///
 /// # Examples
 /// ```
-/// let (row, col) = to_indices("A1");
-/// assert_eq!((row, col), (0, 0));
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// assert_eq!(to_indices("A1"), Ok((0, 0)));
+/// assert_eq!(to_indices("A0"), Err(EvalStatus::InvalidRange));
+/// assert_eq!(to_indices("ZZZZZZZZZZZZZZZZ1"), Err(EvalStatus::InvalidRange));
+/// assert_eq!(to_indices("a1"), Err(EvalStatus::UnrecognizedCmd));
 /// ```
-pub fn to_indices(s: &str) -> (usize, usize) {
+pub fn to_indices(s: &str) -> Result<(usize, usize), EvalStatus> {
     let split_pos = s.find(|c: char| c.is_ascii_digit()).unwrap_or(s.len());
-    let col = s[..split_pos]
-        .bytes()
-        .fold(0, |acc, b| acc * 26 + (b - b'A' + 1) as usize);
+    let letters = s[..split_pos].bytes().filter(|&b| b != b'$');
+    let mut col: usize = 0;
+    for b in letters {
+        if !b.is_ascii_uppercase() {
+            return Err(EvalStatus::UnrecognizedCmd);
+        }
+        col = col
+            .checked_mul(26)
+            .and_then(|col| col.checked_add((b - b'A' + 1) as usize))
+            .ok_or(EvalStatus::InvalidRange)?;
+    }
     let row = s[split_pos..].parse::<usize>().unwrap_or(0);
     if row == 0 || col == 0 {
-        unsafe {
-            STATUS_CODE = 1;
+        return Err(EvalStatus::InvalidRange);
+    }
+    Ok((row - 1, col - 1))
+}
+
+/// Converts 0-based row and column indices to a cell reference (e.g., (0, 0) to "A1").
+///
+/// # Arguments
+/// * `row` - The row index.
+/// * `col` - The column index.
+///
+/// # Returns
+/// A `String` with the cell reference.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// assert_eq!(to_cell_name(0, 0), "A1");
+/// assert_eq!(to_cell_name(9, 27), "AB10");
+/// ```
+pub fn to_cell_name(row: usize, col: usize) -> String {
+    let mut name = String::new();
+    let mut n = col + 1;
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        name.insert(0, (b'A' + rem as u8) as char);
+        n = (n - 1) / 26;
+    }
+    format!("{}{}", name, row + 1)
+}
+
+/// Formats a chain of cells as their names joined by arrows.
+///
+/// # Arguments
+/// * `path` - The keys of the cells, in order.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// A `String` such as "A1 -> B1 -> A1".
+pub fn format_cell_path(path: &[CellKey], total_cols: usize) -> String {
+    path.iter()
+        .map(|&key| to_cell_name(key as usize / total_cols, key as usize % total_cols))
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// Builds the canonical formula text of a cell, which re-creates the cell when entered into
+/// the formula bar.
+///
+/// This is the one place formulas are written out: the GUI formula bar, `fcsv` exports, the
+/// CLI `dump` file and the HTTP API all use it, and parsing its result with `detect_formula`
+/// gives back the same `CellData`.
+///
+/// # Arguments
+/// * `cell` - The cell to describe.
+///
+/// # Returns
+/// A `String` with the cell's formula or value, empty for empty or invalid cells.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// # use spreadsheet::parser::detect_formula;
+/// # let mut cell = Cell { value: Valtype::Int(0), data: CellData::Empty, dependents: Default::default() };
+/// detect_formula(&mut cell, "SUM(A1:A2,C1:C2)");
+/// assert_eq!(formula_string(&cell), "SUM(A1:A2,C1:C2)");
+/// ```
+pub fn formula_string(cell: &Cell) -> String {
+    match &cell.data {
+        CellData::Empty => String::new(),
+
+        CellData::Const => match &cell.value {
+            Valtype::Int(val) => val.to_string(),
+            Valtype::Str(text) => format!("\"{}\"", text.as_str().replace('"', "\"\"")),
+            Valtype::Err(_) => String::new(),
+        },
+
+        CellData::Ref { cell1 } => cell1.to_string(),
+
+        CellData::CoC {
+            value1,
+            op_code,
+            value2,
+        } => {
+            if let (Valtype::Int(val1), Valtype::Int(val2)) = (value1, value2) {
+                format!("{}{}{}", val1, op_code, val2)
+            } else {
+                String::new()
+            }
+        }
+
+        CellData::CoR {
+            op_code,
+            value2,
+            cell2,
+        } => {
+            if let Valtype::Int(val1) = value2 {
+                format!("{}{}{}", val1, op_code, cell2)
+            } else {
+                String::new()
+            }
+        }
+
+        CellData::RoC {
+            op_code,
+            value2,
+            cell1,
+        } => {
+            if let Valtype::Int(val2) = value2 {
+                format!("{}{}{}", cell1, op_code, val2)
+            } else {
+                String::new()
+            }
+        }
+
+        CellData::RoR {
+            op_code,
+            cell1,
+            cell2,
+        } => {
+            format!("{}{}{}", cell1, op_code, cell2)
+        }
+
+        CellData::Range {
+            cell1,
+            cell2,
+            value2,
+            extra,
+        } => {
+            if let Valtype::Str(func) = value2 {
+                // A script function called with one cell is written as it was typed
+                #[cfg(feature = "scripting")]
+                if cell1 == cell2
+                    && extra.is_empty()
+                    && crate::scripting::is_user_function(func.as_str())
+                {
+                    return format!("{}({})", func.as_str(), cell1);
+                }
+                let mut formula = format!("{}({}:{}", func.as_str(), cell1, cell2);
+                for (cell1, cell2) in extra {
+                    formula.push_str(&format!(",{}:{}", cell1, cell2));
+                }
+                formula.push(')');
+                formula
+            } else {
+                String::new()
+            }
+        }
+
+        CellData::SleepC => {
+            if let Valtype::Int(val) = cell.value {
+                format!("SLEEP({})", val)
+            } else {
+                String::new()
+            }
+        }
+
+        CellData::SleepR { cell1 } => {
+            format!("SLEEP({})", cell1)
+        }
+
+        CellData::Expr(expr) => format!("{}{}{}", expr.lhs, expr.op_code, expr.rhs),
+
+        CellData::Lookup(lookup) => lookup.to_string(),
+
+        CellData::Func { func, args } => format!(
+            "{}({})",
+            written_function_name(func.as_str()),
+            args.iter()
+                .map(Operand::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+
+        CellData::Invalid => String::new(),
+    }
+}
+
+/// Shifts every reference in a cell's formula data by a row and column offset.
+///
+/// # Arguments
+/// * `data` - The formula data to shift.
+/// * `d_row` - The row offset.
+/// * `d_col` - The column offset.
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Option<CellData>` - The shifted data, or `None` if any reference would leave the sheet.
+pub fn shift_cell_data(
+    data: &CellData,
+    d_row: isize,
+    d_col: isize,
+    total_rows: usize,
+    total_cols: usize,
+) -> Option<CellData> {
+    let shift = |name: &CellRef| name.shifted(d_row, d_col, total_rows, total_cols);
+    let shift_operand = |operand: &Operand| {
+        Some(match operand {
+            Operand::Const(value) => Operand::Const(*value),
+            Operand::Ref(cell1) => Operand::Ref(shift(cell1)?),
+            Operand::Range { func, cell1, cell2 } => Operand::Range {
+                func: *func,
+                cell1: shift(cell1)?,
+                cell2: shift(cell2)?,
+            },
+        })
+    };
+    Some(match data {
+        CellData::SleepR { cell1 } => CellData::SleepR {
+            cell1: shift(cell1)?,
+        },
+        CellData::Ref { cell1 } => CellData::Ref {
+            cell1: shift(cell1)?,
+        },
+        CellData::CoR {
+            op_code,
+            value2,
+            cell2,
+        } => CellData::CoR {
+            op_code: *op_code,
+            value2: value2.clone(),
+            cell2: shift(cell2)?,
+        },
+        CellData::RoC {
+            op_code,
+            value2,
+            cell1,
+        } => CellData::RoC {
+            op_code: *op_code,
+            value2: value2.clone(),
+            cell1: shift(cell1)?,
+        },
+        CellData::RoR {
+            op_code,
+            cell1,
+            cell2,
+        } => CellData::RoR {
+            op_code: *op_code,
+            cell1: shift(cell1)?,
+            cell2: shift(cell2)?,
+        },
+        CellData::Range {
+            cell1,
+            cell2,
+            value2,
+            extra,
+        } => CellData::Range {
+            cell1: shift(cell1)?,
+            cell2: shift(cell2)?,
+            value2: value2.clone(),
+            extra: extra
+                .iter()
+                .map(|(cell1, cell2)| Some((shift(cell1)?, shift(cell2)?)))
+                .collect::<Option<_>>()?,
+        },
+        CellData::Expr(expr) => CellData::Expr(Box::new(Expr {
+            op_code: expr.op_code,
+            lhs: shift_operand(&expr.lhs)?,
+            rhs: shift_operand(&expr.rhs)?,
+        })),
+        CellData::Lookup(lookup) => CellData::Lookup(Box::new(Lookup {
+            key: shift_operand(&lookup.key)?,
+            cell1: shift(&lookup.cell1)?,
+            cell2: shift(&lookup.cell2)?,
+            column: lookup.column,
+        })),
+        CellData::Func { func, args } => CellData::Func {
+            func: *func,
+            args: args.iter().map(shift_operand).collect::<Option<_>>()?,
+        },
+        other => other.clone(),
+    })
+}
+
+/// Rewrites the references of a formula that point into a block of cells being moved.
+///
+/// A single-cell reference into the block follows the cell it names, '$' markers included,
+/// as after a cut and paste. A range, or each area of a multi-area function, follows only if
+/// it lies entirely inside the block, so a range that merely overlaps it keeps its corners.
+///
+/// # Arguments
+/// * `data` - The formula data to rewrite.
+/// * `source` - The (top-left, bottom-right) corners of the moved block.
+/// * `target` - The cell the top-left corner of the block moves to.
+///
+/// # Returns
+/// * `Option<CellData>` - The rewritten data, or `None` if no reference points into the block.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// let data = CellData::Ref { cell1: CellRef::new("A1").unwrap() };
+/// let moved = move_cell_data(&data, ((0, 0), (0, 0)), (2, 2)).unwrap();
+/// assert_eq!(moved, CellData::Ref { cell1: CellRef::new("C3").unwrap() });
+/// ```
+pub fn move_cell_data(
+    data: &CellData,
+    source: ((usize, usize), (usize, usize)),
+    target: (usize, usize),
+) -> Option<CellData> {
+    let ((r1, c1), (r2, c2)) = source;
+    let inside = |cell: &CellRef| {
+        cell.addr()
+            .filter(|a| (r1..=r2).contains(&a.row) && (c1..=c2).contains(&a.col))
+    };
+    let follow = |cell: &CellRef| match inside(cell) {
+        Some(a) => cell.with_addr(CellAddr::new(a.row - r1 + target.0, a.col - c1 + target.1)),
+        None => *cell,
+    };
+    let follow_operand = |operand: &Operand| match operand {
+        Operand::Ref(cell1) => Operand::Ref(follow(cell1)),
+        Operand::Range { func, cell1, cell2 }
+            if inside(cell1).is_some() && inside(cell2).is_some() =>
+        {
+            Operand::Range {
+                func: *func,
+                cell1: follow(cell1),
+                cell2: follow(cell2),
+            }
+        }
+        other => other.clone(),
+    };
+    let moved = match data {
+        CellData::Range {
+            cell1,
+            cell2,
+            value2,
+            extra,
+        } => {
+            // Areas lying entirely inside the block follow it; the others keep their corners
+            let moves = |cell1: &CellRef, cell2: &CellRef| {
+                inside(cell1).is_some() && inside(cell2).is_some()
+            };
+            let area = |cell1: &CellRef, cell2: &CellRef| match moves(cell1, cell2) {
+                true => (follow(cell1), follow(cell2)),
+                false => (*cell1, *cell2),
+            };
+            if !moves(cell1, cell2) && !extra.iter().any(|(cell1, cell2)| moves(cell1, cell2)) {
+                return None;
+            }
+            let (cell1, cell2) = area(cell1, cell2);
+            CellData::Range {
+                cell1,
+                cell2,
+                value2: value2.clone(),
+                extra: extra
+                    .iter()
+                    .map(|(cell1, cell2)| area(cell1, cell2))
+                    .collect(),
+            }
+        }
+        CellData::SleepR { cell1 } => CellData::SleepR {
+            cell1: follow(cell1),
+        },
+        CellData::Ref { cell1 } => CellData::Ref {
+            cell1: follow(cell1),
+        },
+        CellData::CoR {
+            op_code,
+            value2,
+            cell2,
+        } => CellData::CoR {
+            op_code: *op_code,
+            value2: value2.clone(),
+            cell2: follow(cell2),
+        },
+        CellData::RoC {
+            op_code,
+            value2,
+            cell1,
+        } => CellData::RoC {
+            op_code: *op_code,
+            value2: value2.clone(),
+            cell1: follow(cell1),
+        },
+        CellData::RoR {
+            op_code,
+            cell1,
+            cell2,
+        } => CellData::RoR {
+            op_code: *op_code,
+            cell1: follow(cell1),
+            cell2: follow(cell2),
+        },
+        CellData::Expr(expr) => CellData::Expr(Box::new(Expr {
+            op_code: expr.op_code,
+            lhs: follow_operand(&expr.lhs),
+            rhs: follow_operand(&expr.rhs),
+        })),
+        CellData::Lookup(lookup) => {
+            let table_moves = inside(&lookup.cell1).is_some() && inside(&lookup.cell2).is_some();
+            CellData::Lookup(Box::new(Lookup {
+                key: follow_operand(&lookup.key),
+                cell1: if table_moves {
+                    follow(&lookup.cell1)
+                } else {
+                    lookup.cell1
+                },
+                cell2: if table_moves {
+                    follow(&lookup.cell2)
+                } else {
+                    lookup.cell2
+                },
+                column: lookup.column,
+            }))
+        }
+        CellData::Func { func, args } => CellData::Func {
+            func: *func,
+            args: args.iter().map(follow_operand).collect(),
+        },
+        _ => return None,
+    };
+    (moved != *data).then_some(moved)
+}
+
+/// Parses a range such as "A1:D20" into its normalized corners.
+///
+/// # Arguments
+/// * `range` - The range string, two cell references separated by ':'.
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Option<((usize, usize), (usize, usize))>` - The top-left and bottom-right (row, col)
+///   corners, or `None` if the range is malformed or outside the sheet.
+pub fn parse_range(
+    range: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Option<((usize, usize), (usize, usize))> {
+    let (first, second) = range.trim().split_once(':')?;
+    let mut corners = [(0, 0); 2];
+    for (corner, name) in corners.iter_mut().zip([first, second]) {
+        let CellAddr { row, col } = CellAddr::parse(name)?;
+        if row >= total_rows || col >= total_cols {
+            return None;
+        }
+        *corner = (row, col);
+    }
+    let [(r1, c1), (r2, c2)] = corners;
+    Some(((r1.min(r2), c1.min(c2)), (r1.max(r2), c1.max(c2))))
+}
+
+/// Builds the aggregate formulas appended around a range by the `autototal` command.
+///
+/// One formula is produced per column in the row below the range, one per row in the
+/// column to its right, and a grand total in the corner cell between them. Since they are
+/// ordinary range formulas, the dependency graph keeps them updated as the range changes.
+///
+/// # Arguments
+/// * `range` - The range to total (e.g., "A1:D20").
+/// * `func` - The aggregate to apply: SUM, AVG, MIN, MAX or STDEV (case-insensitive).
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<Vec<(usize, usize, String)>, EvalStatus>` - The (row, col, formula) assignments, or
+///   the status describing why the command was rejected.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// let formulas = autototal_formulas("A1:B2", "sum", 10, 10).unwrap();
+/// assert!(formulas.contains(&(2, 0, "SUM(A1:A2)".to_string())));
+/// assert!(formulas.contains(&(2, 2, "SUM(A1:B2)".to_string())));
+/// ```
+pub fn autototal_formulas(
+    range: &str,
+    func: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<Vec<(usize, usize, String)>, EvalStatus> {
+    let func = func.to_uppercase();
+    if !["SUM", "AVG", "MIN", "MAX", "STDEV"].contains(&func.as_str()) {
+        return Err(EvalStatus::UnrecognizedCmd);
+    }
+    let ((r1, c1), (r2, c2)) =
+        parse_range(range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    if r2 + 1 >= total_rows || c2 + 1 >= total_cols {
+        return Err(EvalStatus::InvalidRange);
+    }
+    let mut formulas = Vec::with_capacity((c2 - c1 + 1) + (r2 - r1 + 2));
+    for col in c1..=c2 {
+        let formula = format!(
+            "{}({}:{})",
+            func,
+            to_cell_name(r1, col),
+            to_cell_name(r2, col)
+        );
+        formulas.push((r2 + 1, col, formula));
+    }
+    for row in r1..=r2 {
+        let formula = format!(
+            "{}({}:{})",
+            func,
+            to_cell_name(row, c1),
+            to_cell_name(row, c2)
+        );
+        formulas.push((row, c2 + 1, formula));
+    }
+    let grand_total = format!(
+        "{}({}:{})",
+        func,
+        to_cell_name(r1, c1),
+        to_cell_name(r2, c2)
+    );
+    formulas.push((r2 + 1, c2 + 1, grand_total));
+    Ok(formulas)
+}
+
+/// The inclusive lower edge, inclusive upper edge and count of one histogram bin.
+pub type HistogramBin = (i32, i32, usize);
+
+/// The parsed arguments of a `histogram` command.
+///
+/// # Fields
+/// * `source` - Top-left and bottom-right (row, col) corners of the range to bin.
+/// * `bins` - The number of bins in the frequency table.
+/// * `target` - The (row, col) of the table's top-left cell.
+/// * `chart` - Whether a bar chart of the table was requested.
+#[derive(Debug, PartialEq)]
+pub struct HistogramSpec {
+    pub source: ((usize, usize), (usize, usize)),
+    pub bins: usize,
+    pub target: (usize, usize),
+    pub chart: bool,
+}
+
+/// Parses the arguments of `histogram <range> [bins=N] -> <cell> [chart]`.
+///
+/// The frequency table is three columns wide (lower edge, upper edge, count) with one row
+/// per bin, and must fit in the sheet below and to the right of the target cell.
+///
+/// # Arguments
+/// * `args` - The text following the `histogram` keyword.
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<HistogramSpec, EvalStatus>` - The parsed command, or the status
+///   describing why it was rejected.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// let spec = parse_histogram_args("A1:A500 bins=5 -> C1", 999, 10).unwrap();
+/// assert_eq!((spec.bins, spec.target, spec.chart), (5, (0, 2), false));
+/// ```
+pub fn parse_histogram_args(
+    args: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<HistogramSpec, EvalStatus> {
+    let (source, rest) = args.split_once("->").ok_or(EvalStatus::UnrecognizedCmd)?;
+    let mut source = source.split_whitespace();
+    let range = source.next().ok_or(EvalStatus::UnrecognizedCmd)?;
+    let bins = match source.next() {
+        Some(arg) => arg
+            .strip_prefix("bins=")
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|&n| n > 0)
+            .ok_or(EvalStatus::UnrecognizedCmd)?,
+        None => 10,
+    };
+    let mut rest = rest.split_whitespace();
+    let target = rest.next().ok_or(EvalStatus::UnrecognizedCmd)?;
+    let chart = match rest.next() {
+        Some("chart") => true,
+        Some(_) => return Err(EvalStatus::UnrecognizedCmd),
+        None => false,
+    };
+    if source.next().is_some() || rest.next().is_some() {
+        return Err(EvalStatus::UnrecognizedCmd);
+    }
+    let source = parse_range(range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    let (target, _) = parse_range(&format!("{0}:{0}", target), total_rows, total_cols)
+        .ok_or(EvalStatus::InvalidRange)?;
+    if target.0 + bins > total_rows || target.1 + 3 > total_cols {
+        return Err(EvalStatus::InvalidRange);
+    }
+    Ok(HistogramSpec {
+        source,
+        bins,
+        target,
+        chart,
+    })
+}
+
+/// Splits values into equal-width integer bins covering their minimum to maximum.
+///
+/// # Arguments
+/// * `values` - The values to count.
+/// * `bins` - The number of bins (at least one).
+///
+/// # Returns
+/// * `Vec<HistogramBin>` - The bins in increasing order, each with the number of values
+///   that fall in it.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// let table = histogram_bins(&[1, 2, 2, 9], 2);
+/// assert_eq!(table, vec![(1, 5, 3), (6, 10, 1)]);
+/// ```
+pub fn histogram_bins(values: &[i32], bins: usize) -> Vec<HistogramBin> {
+    let min = values.iter().copied().min().unwrap_or(0) as i64;
+    let max = values.iter().copied().max().unwrap_or(0) as i64;
+    let width = ((max - min + 1) as u64).div_ceil(bins as u64) as i64;
+    let mut counts = vec![0usize; bins];
+    for &v in values {
+        counts[((v as i64 - min) / width) as usize] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| {
+            let lower = min + i as i64 * width;
+            let upper = lower + width - 1;
+            (
+                lower.min(i32::MAX as i64) as i32,
+                upper.min(i32::MAX as i64) as i32,
+                count,
+            )
+        })
+        .collect()
+}
+
+/// Computes the frequency table of a `histogram` command and the cells it writes.
+///
+/// Only non-empty cells holding a number are counted. The table is written as constants,
+/// so it is a snapshot and does not follow later edits to the source range.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `spec` - The parsed command.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `(Vec<(usize, usize, String)>, Vec<HistogramBin>)` - The (row, col, formula)
+///   assignments and the bins they were built from.
+pub fn histogram_formulas<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    spec: &HistogramSpec,
+    total_cols: usize,
+) -> (Vec<(usize, usize, String)>, Vec<HistogramBin>) {
+    let ((r1, c1), (r2, c2)) = spec.source;
+    let mut values = Vec::new();
+    for row in r1..=r2 {
+        for col in c1..=c2 {
+            if let Some(cell) = sheet.get(&((row * total_cols + col) as CellKey))
+                && cell.data != CellData::Empty
+                && let Valtype::Int(v) = cell.value
+            {
+                values.push(v);
+            }
+        }
+    }
+    let table = histogram_bins(&values, spec.bins);
+    let (tr, tc) = spec.target;
+    let mut formulas = Vec::with_capacity(table.len() * 3);
+    for (i, &(lower, upper, count)) in table.iter().enumerate() {
+        formulas.push((tr + i, tc, lower.to_string()));
+        formulas.push((tr + i, tc + 1, upper.to_string()));
+        formulas.push((tr + i, tc + 2, count.to_string()));
+    }
+    (formulas, table)
+}
+
+/// Splits one CSV record into its fields.
+///
+/// Fields may be quoted with `"`, in which case they can contain commas and `""` stands for
+/// a literal quote. Surrounding whitespace and a trailing line terminator are not part of any
+/// field.
+///
+/// # Arguments
+/// * `line` - The record to split.
+///
+/// # Returns
+/// * `Vec<String>` - The unquoted fields, in order.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// assert_eq!(parse_csv_record("1,\"=SUM(A1:A2)\", B1"), vec!["1", "=SUM(A1:A2)", "B1"]);
+/// ```
+pub fn parse_csv_record(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches(['\r', '\n']).chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            _ => field.push(ch),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+/// Reads the values of a rectangular block of cells.
+///
+/// Corners may be given in any order. Cells that were never written read as `Int(0)`, and
+/// cells holding an error read as `Err`, such as `Err(CellError::DivZero)`.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `a` - One corner of the block.
+/// * `b` - The opposite corner of the block.
+///
+/// # Returns
+/// * `Vec<Vec<Valtype>>` - The values, one inner `Vec` per row from top to bottom.
+///
+/// # Panics
+/// Panics if either corner lies outside the sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "1")?;
+/// let block = get_range_values(&sheet, (10, 10), CellAddr::new(0, 0), CellAddr::new(1, 2));
+/// assert_eq!((block.len(), block[0].len()), (2, 3));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn get_range_values<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_dims: (usize, usize),
+    a: CellAddr,
+    b: CellAddr,
+) -> Vec<Vec<Valtype>> {
+    let (total_rows, total_cols) = total_dims;
+    assert!(
+        a.row.max(b.row) < total_rows && a.col.max(b.col) < total_cols,
+        "range {:?}..{:?} is outside the {}x{} sheet",
+        a,
+        b,
+        total_rows,
+        total_cols
+    );
+    (a.row.min(b.row)..=a.row.max(b.row))
+        .map(|row| {
+            (a.col.min(b.col)..=a.col.max(b.col))
+                .map(|col| {
+                    sheet
+                        .get(&((row * total_cols + col) as CellKey))
+                        .map_or(Valtype::Int(0), |cell| cell.value.clone())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The characters a sparkline is drawn with, lowest first.
+const SPARK_BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Returns the range drawn by a `SPARK(<range>)` cell.
+///
+/// # Arguments
+/// * `cell` - The cell to check.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+///
+/// # Returns
+/// * `Option<(CellAddr, CellAddr)>` - The top-left and bottom-right corners, or `None` if the
+///   cell holds another formula, several areas, or a range that is reversed or outside the
+///   sheet.
+pub fn spark_range(cell: &Cell, total_dims: (usize, usize)) -> Option<(CellAddr, CellAddr)> {
+    let CellData::Range {
+        cell1,
+        cell2,
+        value2: Valtype::Str(func),
+        extra,
+    } = &cell.data
+    else {
+        return None;
+    };
+    if !func.as_str().eq_ignore_ascii_case("SPARK") || !extra.is_empty() {
+        return None;
+    }
+    let (a, b) = (cell1.addr()?, cell2.addr()?);
+    (a.row <= b.row && a.col <= b.col && b.row < total_dims.0 && b.col < total_dims.1)
+        .then_some((a, b))
+}
+
+/// Draws the values of the range of a `SPARK(<range>)` cell as a line of block characters,
+/// one per cell in row-major order, scaled from the lowest value to the highest.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `cell` - The cell to draw.
+///
+/// # Returns
+/// * `Option<String>` - The sparkline, with a space for each text or error value, or `None`
+///   if the cell is not a sparkline.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 1, 0, "3")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 2, 0, "6")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 3, 0, "8")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "SPARK(A1:A4)")?;
+/// // A1..A4 hold 1, 3, 6, 8 and B1 = SPARK(A1:A4)
+/// assert_eq!(sparkline(&sheet, (10, 10), &sheet[&1]).as_deref(), Some("▁▃▆█"));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn sparkline<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_dims: (usize, usize),
+    cell: &Cell,
+) -> Option<String> {
+    let (a, b) = spark_range(cell, total_dims)?;
+    let values: Vec<Valtype> = get_range_values(sheet, total_dims, a, b)
+        .into_iter()
+        .flatten()
+        .collect();
+    let numbers = values.iter().filter_map(|value| match value {
+        Valtype::Int(v) => Some(*v as i64),
+        _ => None,
+    });
+    let low = numbers.clone().min().unwrap_or(0);
+    let high = numbers.max().unwrap_or(0);
+    let top = SPARK_BLOCKS.len() as i64 - 1;
+    Some(
+        values
+            .iter()
+            .map(|value| match value {
+                Valtype::Int(v) if high > low => {
+                    SPARK_BLOCKS[((*v as i64 - low) * top / (high - low)) as usize]
+                }
+                Valtype::Int(_) => SPARK_BLOCKS[0],
+                _ => ' ',
+            })
+            .collect(),
+    )
+}
+
+/// Returns how many terminal columns a text takes when printed: one for each grapheme, such
+/// as "é" whether it is written as one character or as 'e' and a combining accent, and two
+/// for a wide one such as "表" or most emoji.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// assert_eq!(display_width("Größe"), 5);
+/// assert_eq!(display_width("e\u{301}"), 1);
+/// assert_eq!(display_width("数量"), 4);
+/// ```
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(|g| g.width().min(2)).sum()
+}
+
+/// Pads a text with spaces on the right to fill `width` terminal columns, as `{:<width$}`
+/// does for ASCII text; a text already as wide is returned as it is.
+pub fn pad_display(text: &str, width: usize) -> String {
+    let padding = width.saturating_sub(display_width(text));
+    let mut padded = String::with_capacity(text.len() + padding);
+    padded.push_str(text);
+    padded.extend(std::iter::repeat_n(' ', padding));
+    padded
+}
+
+/// Cuts a text to the graphemes that fit in `width` terminal columns, never splitting a
+/// grapheme.
+pub fn truncate_display(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (at, g) in text.grapheme_indices(true) {
+        used += g.width().min(2);
+        if used > width {
+            return &text[..at];
         }
-        return (0, 0);
     }
-    (row - 1, col - 1)
+    text
+}
+
+/// Reads the values of a rectangular block of cells as a 2D array of floats.
+///
+/// Works like [`get_range_values`], with error cells mapped to `f64::NAN` so the result can
+/// go straight into numeric code.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `a` - One corner of the block.
+/// * `b` - The opposite corner of the block.
+///
+/// # Returns
+/// * `ndarray::Array2<f64>` - The values, with shape `(rows, cols)` of the block.
+///
+/// # Panics
+/// Panics if either corner lies outside the sheet.
+#[cfg(feature = "ndarray")]
+pub fn get_range_array<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_dims: (usize, usize),
+    a: CellAddr,
+    b: CellAddr,
+) -> ndarray::Array2<f64> {
+    let values = get_range_values(sheet, total_dims, a, b);
+    let shape = (values.len(), values[0].len());
+    let flat = values
+        .into_iter()
+        .flatten()
+        .map(|value| match value {
+            Valtype::Int(v) => v as f64,
+            Valtype::Str(_) | Valtype::Err(_) => f64::NAN,
+        })
+        .collect();
+    ndarray::Array2::from_shape_vec(shape, flat).expect("block rows have equal length")
 }
 
 /// Performs a binary arithmetic operation on two integers.
@@ -48,220 +1210,589 @@ pub fn to_indices(s: &str) -> (usize, usize) {
 /// * `b` - The second operand.
 ///
 /// # Returns
-/// The result of the operation as an `i32`.
+/// * `Result<i32, EvalStatus>` - The result of the operation, `CellError::DivZero` on a
+///   division by zero, `CellError::Num` for a result that does not fit in a cell, or
+///   `EvalStatus::UnrecognizedCmd` for an unknown operation.
 ///
 /// # Examples
 /// ```
-/// let result = compute(5, Some('+'), 3);
-/// assert_eq!(result, 8);
-/// ```
-pub fn compute(a: i32, op: Option<char>, b: i32) -> i32 {
-    match op {
-        Some('+') => a + b,
-        Some('-') => a - b,
-        Some('*') => a * b,
-        Some('/') => {
-            if b == 0 {
-                unsafe {
-                    EVAL_ERROR = true;
-                }
-                0
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// assert_eq!(compute(5, Some('+'), 3), Ok(8));
+/// assert_eq!(compute(5, Some('/'), 0), Err(EvalStatus::ErrValue(CellError::DivZero)));
+/// assert_eq!(compute(i32::MAX, Some('+'), 1), Err(EvalStatus::ErrValue(CellError::Num)));
+/// ```
+pub fn compute(a: i32, op: Option<char>, b: i32) -> Result<i32, EvalStatus> {
+    let result = match op {
+        Some('+') => a.checked_add(b),
+        Some('-') => a.checked_sub(b),
+        Some('*') => a.checked_mul(b),
+        Some('/') if b == 0 => return Err(EvalStatus::ErrValue(CellError::DivZero)),
+        // i32::MIN / -1 is the one quotient too large for a cell
+        Some('/') => a.checked_div(b),
+        _ => return Err(EvalStatus::UnrecognizedCmd),
+    };
+    result.ok_or(EvalStatus::ErrValue(CellError::Num))
+}
+
+/// The scalar functions, with the number of arguments each takes.
+pub const SCALAR_FUNCTIONS: [(&str, usize); 10] = [
+    ("RAND", 0),
+    ("RANDBETWEEN", 2),
+    ("ABS", 1),
+    ("MIN2", 2),
+    ("MAX2", 2),
+    ("MOD", 2),
+    ("POW", 2),
+    ("ROUND", 2),
+    ("FLOOR", 2),
+    ("CEIL", 2),
+];
+
+/// Function names too long for a `CellName`, with the shorter name each is stored under.
+const STORED_NAMES: [(&str, &str); 1] = [("RANDBETWEEN", "RANDBTW")];
+
+/// The scalar functions evaluated again at every recalculation, by the name they are stored
+/// under. `SLEEP` is volatile as well, but has formulas of its own.
+pub const VOLATILE_FUNCTIONS: [&str; 2] = ["RAND", "RANDBTW"];
+
+/// The largest value `RAND()` draws. Cells hold integers, so it draws a whole number from 0
+/// to `RAND_MAX`, as C's `rand` does, rather than a fraction.
+pub const RAND_MAX: i32 = 32767;
+
+/// Returns the name a function is stored under in a cell, which is its own name unless that is
+/// too long for a `CellName`.
+///
+/// # Arguments
+/// * `name` - The function name as written, e.g. "RANDBETWEEN".
+pub fn stored_function_name(name: &str) -> &str {
+    STORED_NAMES
+        .iter()
+        .find(|(written, _)| *written == name)
+        .map_or(name, |(_, stored)| stored)
+}
+
+/// Returns the name a function is written with, undoing `stored_function_name`.
+///
+/// # Arguments
+/// * `stored` - The function name held by a cell, e.g. "RANDBTW".
+pub fn written_function_name(stored: &str) -> &str {
+    STORED_NAMES
+        .iter()
+        .find(|(_, short)| *short == stored)
+        .map_or(stored, |(written, _)| written)
+}
+
+/// Draws the value of a `RAND()` or `RANDBETWEEN(low, high)` cell.
+///
+/// The value is picked from the seed and the number of draws of the session's context and the
+/// key of the cell, so it does not depend on the order cells are recalculated in, and the same
+/// commands after the same `seed` draw the same values.
+///
+/// # Arguments
+/// * `func` - The function name, "RAND" or "RANDBETWEEN".
+/// * `args` - The values of the arguments: none for `RAND`, the bounds for `RANDBETWEEN`.
+/// * `key` - The key of the cell.
+/// * `context` - The context of the session the sheet belongs to.
+///
+/// # Returns
+/// * `Result<i32, EvalStatus>` - A value from 0 to `RAND_MAX`, or from `low` to `high`
+///   included; `CellError::Value` if `low` is above `high`, or `EvalStatus::UnrecognizedCmd`
+///   for another function.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// let context = EvalContext::default();
+/// let die = draw_random("RANDBETWEEN", &[1, 6], 0, &context)?;
+/// assert!((1..=6).contains(&die));
+/// assert_eq!(draw_random("RANDBETWEEN", &[1, 6], 0, &context), Ok(die));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn draw_random(
+    func: &str,
+    args: &[i32],
+    key: CellKey,
+    context: &EvalContext,
+) -> Result<i32, EvalStatus> {
+    // SplitMix64 of the seed, draw and key
+    let mut x = context.0.seed.load(Ordering::Relaxed)
+        ^ context
+            .0
+            .draws
+            .load(Ordering::Relaxed)
+            .wrapping_mul(0xd1b5_4a32_d192_ed03)
+        ^ key.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    let (low, high) = match (func, args) {
+        ("RAND", &[]) => (0, RAND_MAX),
+        ("RANDBETWEEN", &[low, high]) if low > high => {
+            return Err(EvalStatus::ErrValue(CellError::Value));
+        }
+        ("RANDBETWEEN", &[low, high]) => (low, high),
+        _ => return Err(EvalStatus::UnrecognizedCmd),
+    };
+    let span = (high as i64 - low as i64 + 1) as u64;
+    Ok((low as i64 + (x % span) as i64) as i32)
+}
+
+/// Applies a scalar function such as ABS or MOD to its arguments.
+///
+/// # Arguments
+/// * `func` - The function name, one of `SCALAR_FUNCTIONS` but `RAND` and `RANDBETWEEN`,
+///   which `draw_random` evaluates.
+/// * `args` - The values of the arguments, as many as the function takes.
+///
+/// Cells hold integers, so `ROUND(x, digits)` returns `x` unchanged for 0 or more digits, and
+/// for a negative number rounds half away from zero to tens, hundreds and so on. `FLOOR(x, step)`
+/// and `CEIL(x, step)` round down and up to a multiple of `step` (its sign is ignored).
+///
+/// # Returns
+/// * `Result<i32, EvalStatus>` - The result, `CellError::DivZero` for `MOD`, `FLOOR` or `CEIL`
+///   by 0, `CellError::Value` for `POW` with a negative exponent, `CellError::Num` for a result
+///   that does not fit in a cell, such as `POW(2, 40)` or `ABS(-2147483648)`, or
+///   `EvalStatus::UnrecognizedCmd` for an unknown function or the wrong number of arguments.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// assert_eq!(compute_func("MOD", &[-7, 3]), Ok(2));
+/// assert_eq!(compute_func("POW", &[2, 10]), Ok(1024));
+/// assert_eq!(compute_func("POW", &[2, 40]), Err(EvalStatus::ErrValue(CellError::Num)));
+/// assert_eq!(compute_func("ROUND", &[17, 2]), Ok(17));
+/// assert_eq!(compute_func("ROUND", &[1250, -2]), Ok(1300));
+/// assert_eq!(compute_func("FLOOR", &[-7, 5]), Ok(-10));
+/// ```
+pub fn compute_func(func: &str, args: &[i32]) -> Result<i32, EvalStatus> {
+    let too_large = EvalStatus::ErrValue(CellError::Num);
+    match (func, args) {
+        ("ABS", &[a]) => a.checked_abs().ok_or(too_large),
+        ("MIN2", &[a, b]) => Ok(a.min(b)),
+        ("MAX2", &[a, b]) => Ok(a.max(b)),
+        ("MOD", &[_, 0]) => Err(EvalStatus::ErrValue(CellError::DivZero)),
+        ("MOD", &[a, b]) => {
+            // The remainder takes the sign of the divisor, as in other spreadsheets
+            let rem = a.wrapping_rem(b);
+            Ok(if rem != 0 && (rem < 0) != (b < 0) {
+                rem + b
             } else {
-                a / b
-            }
+                rem
+            })
         }
-        _ => {
-            unsafe {
-                STATUS_CODE = 2;
-            }
-            0
+        ("POW", &[_, b]) if b < 0 => Err(EvalStatus::ErrValue(CellError::Value)),
+        ("POW", &[a, b]) => a.checked_pow(b as u32).ok_or(too_large),
+        ("ROUND", &[a, digits]) if digits >= 0 => Ok(a),
+        ("ROUND", &[a, digits]) => {
+            let step = 10i64.checked_pow(digits.unsigned_abs()).unwrap_or(i64::MAX);
+            let half = if a < 0 { -step / 2 } else { step / 2 };
+            i32::try_from((a as i64 + half) / step * step).map_err(|_| too_large)
+        }
+        ("FLOOR" | "CEIL", &[_, 0]) => Err(EvalStatus::ErrValue(CellError::DivZero)),
+        ("FLOOR", &[a, step]) => {
+            let step = (step as i64).abs();
+            i32::try_from((a as i64).div_euclid(step) * step).map_err(|_| too_large)
         }
+        ("CEIL", &[a, step]) => {
+            let step = (step as i64).abs();
+            i32::try_from(-(-(a as i64)).div_euclid(step) * step).map_err(|_| too_large)
+        }
+        _ => Err(EvalStatus::UnrecognizedCmd),
     }
 }
 
 /// Simulates a sleep operation for the given number of seconds.
 ///
+/// Does nothing when `context` is in safe mode, which also covers SLEEP cells restored from a
+/// workbook.
+///
 /// # Arguments
 /// * `x` - The number of seconds to sleep (non-negative).
-pub fn sleepy(x: i32) {
-    if x > 0 {
-        sleep(Duration::from_secs(x as u64))
+/// * `cancel` - The token of the running recalculation, checked every `CANCEL_POLL`.
+/// * `context` - The evaluation context of the recalculation.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Err(EvalStatus::Cancelled)` if the token was cancelled before
+///   the time was up.
+pub fn sleepy(
+    x: i32,
+    cancel: Option<&CancelToken>,
+    context: &EvalContext,
+) -> Result<(), EvalStatus> {
+    if x <= 0 || context.is_safe() {
+        return Ok(());
+    }
+    let Some(cancel) = cancel else {
+        sleep(Duration::from_secs(x as u64));
+        return Ok(());
+    };
+    let mut left = Duration::from_secs(x as u64);
+    while !left.is_zero() {
+        if cancel.is_cancelled() {
+            return Err(EvalStatus::Cancelled);
+        }
+        let step = left.min(CANCEL_POLL);
+        sleep(step);
+        left -= step;
     }
+    Ok(())
 }
 
-/// Compute MIN, MAX, SUM, AVG, or STDEV over a rectangular block in a sparse sheet.
+/// Compute MIN, MAX, SUM, AVG, STDEV or COUNT over a rectangular block in a sparse sheet.
+///
+/// Only the stored cells of the block are read, through `SheetStorage::block`; the others
+/// count as 0. Text cells are skipped, as if they were outside the block, so COUNT leaves
+/// them out and AVG divides by the other cells only. Sums are taken in `i64`, so even a block
+/// of the whole sheet cannot overflow while it is added up, and AVG divides the full sum,
+/// rounding toward zero.
 ///
 /// # Arguments
-/// * `sheet` - A hash map containing cell data, indexed by a unique `u32` key.
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
 /// * `total_cols` - The total number of columns in the spreadsheet.
 /// * `r_min` - The minimum row index of the range.
 /// * `r_max` - The maximum row index of the range.
 /// * `c_min` - The minimum column index of the range.
 /// * `c_max` - The maximum column index of the range.
-/// * `choice` - The function to apply (1=MAX, 2=MIN, 3=AVG, 4=SUM, 5=STDEV).
+/// * `choice` - The function to apply (1=MAX, 2=MIN, 3=AVG, 4=SUM, 5=STDEV, 6=COUNT, the
+///   number of cells in the block that do not hold text).
 ///
 /// # Returns
-/// The computed result as an `i32`.
+/// * `Result<i32, EvalStatus>` - The computed result, `EvalStatus::ErrValue` with the error
+///   of the first cell in the range holding one, `CellError::DivZero` for the AVG or STDEV
+///   of a block holding only text, or `CellError::Num` if the result does not fit in an
+///   `i32`, or `EvalStatus::UnrecognizedCmd` for an unknown `choice`.
 ///
 /// # Examples
 /// ```
-/// let mut sheet: HashMap<u32, Cell> = HashMap::new();
+/// # use spreadsheet::*;
+/// # use spreadsheet::utils::*;
+/// # use std::collections::{HashMap, HashSet};
+/// let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
 /// sheet.insert(0, Cell { value: Valtype::Int(5), data: CellData::Const, dependents: HashSet::new() });
 /// let result = compute_range(&sheet, 10, 0, 0, 0, 0, 4); // SUM
-/// assert_eq!(result, 5);
+/// assert_eq!(result, Ok(5));
 /// ```
-pub fn compute_range(
-    sheet: &HashMap<u32, Cell>,
+pub fn compute_range<S: SheetStorage + ?Sized>(
+    sheet: &S,
     total_cols: usize,
     r_min: usize,
     r_max: usize,
     c_min: usize,
     c_max: usize,
     choice: i32,
-) -> i32 {
-    let width = c_max - c_min + 1;
-    let height = r_max - r_min + 1;
-    let area = width * height;
-    let use_hashmap_iter = sheet.len() >= area;
-    // If area is small, do the simple full scan:
-    if use_hashmap_iter {
-        // --- original version ---
-        let mut res: i32 = match choice {
-            1 => i32::MIN, // MAX
-            2 => i32::MAX, // MIN
-            _ => 0,        // SUM/AVG/STDEV
-        };
-        let mut variance = 0.0;
-
-        for rr in r_min..=r_max {
-            for cc in c_min..=c_max {
-                let key = (rr * total_cols + cc) as u32;
-                let val = match sheet
-                    .get(&key)
-                    .map(|c| &c.value)
-                    .unwrap_or(&Valtype::Int(0))
-                {
-                    Valtype::Int(v) => *v,
-                    Valtype::Str(_) => {
-                        unsafe {
-                            EVAL_ERROR = true;
-                        }
-                        continue;
-                    }
-                };
-                match choice {
-                    1 => res = res.max(val),
-                    2 => res = res.min(val),
-                    3..=5 => res += val,
-                    _ => unsafe {
-                        STATUS_CODE = 2;
-                    },
-                }
+) -> Result<i32, EvalStatus> {
+    compute_areas(
+        sheet,
+        total_cols,
+        &[((r_min, c_min), (r_max, c_max))],
+        choice,
+    )
+}
+
+/// Computes a range function over the cells of several areas, as in `SUM(A1:A5,C1:C5)`.
+///
+/// The areas are aggregated as one list of cells, so a cell in two overlapping areas counts
+/// twice, as does a repeated area.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+/// * `areas` - The top-left and bottom-right (row, col) corners of each area, in order.
+/// * `choice` - The function to apply, as for `compute_range`.
+///
+/// # Returns
+/// * `Result<i32, EvalStatus>` - The computed result, `EvalStatus::ErrValue` with the error
+///   of the first cell holding one, `CellError::DivZero` for the AVG or STDEV of areas
+///   holding only text, or `CellError::Num` if the result does not fit in an `i32`, or
+///   `EvalStatus::UnrecognizedCmd` for an unknown `choice`.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "7")?;
+/// // A1 holds 5 and C1 holds 7
+/// let result = compute_areas(&sheet, 10, &[((0, 0), (0, 0)), ((0, 2), (0, 2))], 4); // SUM
+/// assert_eq!(result, Ok(12));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn compute_areas<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_cols: usize,
+    areas: &[Corners],
+    choice: i32,
+) -> Result<i32, EvalStatus> {
+    if !(1..=6).contains(&choice) {
+        return Err(EvalStatus::UnrecognizedCmd);
+    }
+    let totals = range_totals(sheet, total_cols, areas)?;
+    if let Some(result) = totals.result(choice) {
+        return result;
+    }
+    if totals.cells == 0 {
+        return Err(EvalStatus::ErrValue(CellError::DivZero));
+    }
+    // STDEV: second pass over the stored cells, then the zeros
+    let mean = totals.sum as f64 / totals.cells as f64;
+    let mut variance_acc = 0.0;
+    for &((r_min, c_min), (r_max, c_max)) in areas {
+        for cell in sheet.block(r_min..=r_max, c_min..=c_max, total_cols) {
+            if let Valtype::Int(v) = cell.value {
+                variance_acc += (v as f64 - mean).powi(2);
             }
         }
+    }
+    variance_acc += (totals.cells - totals.stored) as f64 * (0.0 - mean).powi(2);
+    let stdev = (variance_acc / totals.cells as f64).sqrt().round();
+    // Values far apart, e.g. i32::MIN and i32::MAX, spread by more than an `i32`
+    if stdev > i32::MAX as f64 {
+        return Err(EvalStatus::ErrValue(CellError::Num));
+    }
+    Ok(stdev as i32)
+}
 
-        match choice {
-            3 => res / (area as i32), // AVG
-            5 => {
-                // STDEV: second-pass
-                let mean = res as f64 / area as f64;
-                for rr in r_min..=r_max {
-                    for cc in c_min..=c_max {
-                        let key = (rr * total_cols + cc) as u32;
-                        if let Some(Valtype::Int(v)) = sheet.get(&key).map(|c| c.value.clone()) {
-                            variance += (v as f64 - mean).powi(2);
-                        } else {
-                            variance += (0.0 - mean).powi(2);
-                        }
-                    }
-                }
-                (variance / area as f64).sqrt().round() as i32
-            }
-            _ => res,
-        }
-    } else {
-        // --- optimized sparse scan ---
-        // Track number of entries seen in-range:
-        let mut count_in = 0usize;
-        // accumulators:
-        let mut max_v = i32::MIN;
-        let mut min_v = i32::MAX;
-        let mut sum = 0i32; // use i64 to avoid overflow on large areas
-        let mut variance_acc = 0.0;
-
-        // First pass: only look at the non-zero cells we actually stored
-        for (&key, cell) in sheet.iter() {
-            let row = (key as usize) / total_cols;
-            let col = (key as usize) % total_cols;
-            if row < r_min || row > r_max || col < c_min || col > c_max {
-                continue;
-            }
+/// The totals of the cells of one or more areas, from which every range function but STDEV is
+/// computed. Cells that are not stored count as 0, and text cells are left out.
+///
+/// # Fields
+/// * `cells` - The number of cells in the areas that do not hold text, a cell in two areas
+///   counting twice.
+/// * `stored` - The number of those cells that are stored.
+/// * `sum` - The sum of the values, taken in `i64` so that even a block of the whole sheet
+///   cannot overflow while it is added up.
+/// * `min` - The smallest value, 0 if a cell is not stored or no cell holds a number.
+/// * `max` - The largest value, 0 if a cell is not stored or no cell holds a number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RangeTotals {
+    pub cells: usize,
+    pub stored: usize,
+    pub sum: i64,
+    pub min: i32,
+    pub max: i32,
+}
+
+impl RangeTotals {
+    /// Computes a range function from the totals.
+    ///
+    /// # Arguments
+    /// * `choice` - The function to apply, as for `compute_range`.
+    ///
+    /// # Returns
+    /// * `Option<Result<i32, EvalStatus>>` - The result, `CellError::DivZero` for the AVG of
+    ///   no cells, or `CellError::Num` if it does not fit in an `i32`; `None` for STDEV, which
+    ///   needs every value again, and for an unknown `choice`.
+    pub fn result(&self, choice: i32) -> Option<Result<i32, EvalStatus>> {
+        let narrow =
+            |value: i64| i32::try_from(value).map_err(|_| EvalStatus::ErrValue(CellError::Num));
+        Some(match choice {
+            1 => Ok(self.max),
+            2 => Ok(self.min),
+            // AVG includes the zeros and rounds toward zero; the mean of `i32` values always
+            // fits in one
+            3 if self.cells == 0 => Err(EvalStatus::ErrValue(CellError::DivZero)),
+            3 => narrow(self.sum / self.cells as i64),
+            4 => narrow(self.sum),
+            6 => i32::try_from(self.cells).map_err(|_| EvalStatus::ErrValue(CellError::Num)),
+            _ => return None,
+        })
+    }
+}
+
+/// Adds up the cells of one or more areas, as in `SUM(A1:A5,C1:C5)`.
+///
+/// Only the stored cells are read, through `SheetStorage::block`; text cells are skipped.
+///
+/// # Arguments
+/// * `sheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+/// * `areas` - The top-left and bottom-right (row, col) corners of each area, in order.
+///
+/// # Returns
+/// * `Result<RangeTotals, EvalStatus>` - The totals, or `EvalStatus::ErrValue` with the error
+///   of the first cell holding one.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "-7")?;
+/// // A1 holds 5 and C1 holds -7
+/// let totals = range_totals(&sheet, 10, &[((0, 0), (0, 2))])?;
+/// assert_eq!((totals.cells, totals.stored, totals.sum), (3, 2, -2));
+/// assert_eq!((totals.min, totals.max), (-7, 5));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn range_totals<S: SheetStorage + ?Sized>(
+    sheet: &S,
+    total_cols: usize,
+    areas: &[Corners],
+) -> Result<RangeTotals, EvalStatus> {
+    let mut totals = RangeTotals {
+        cells: areas
+            .iter()
+            .map(|&((r_min, c_min), (r_max, c_max))| (c_max - c_min + 1) * (r_max - r_min + 1))
+            .sum(),
+        stored: 0,
+        sum: 0,
+        min: i32::MAX,
+        max: i32::MIN,
+    };
+    for &((r_min, c_min), (r_max, c_max)) in areas {
+        for cell in sheet.block(r_min..=r_max, c_min..=c_max, total_cols) {
             let v = match &cell.value {
                 Valtype::Int(v) => *v,
                 Valtype::Str(_) => {
-                    unsafe {
-                        EVAL_ERROR = true;
-                    }
+                    totals.cells -= 1;
                     continue;
                 }
+                Valtype::Err(error) => return Err(EvalStatus::ErrValue(*error)),
             };
-            count_in += 1;
-            sum += v;
-            max_v = max_v.max(v);
-            min_v = min_v.min(v);
-        }
-
-        let zero_count = area.saturating_sub(count_in);
-        match choice {
-            1 => {
-                // MAX: if any zeros were omitted, they could be the max
-                if zero_count > 0 {
-                    max_v = max_v.max(0);
-                }
-                max_v
-            }
-            2 => {
-                // MIN: zeros could be the min if no negatives
-                if zero_count > 0 {
-                    min_v = min_v.min(0);
-                }
-                min_v
-            }
-            4 => {
-                // SUM: zeros don't change sum
-                sum
-            }
-            3 => {
-                // AVG: include zeros
-                sum / (area as i32)
-            }
-            5 => {
-                // STDEV:
-                let mean = sum as f64 / area as f64;
-                // variance contribution from non-zero cells:
-                for (&key, cell) in sheet.iter() {
-                    let row = (key as usize) / total_cols;
-                    let col = (key as usize) % total_cols;
-                    if row < r_min || row > r_max || col < c_min || col > c_max {
-                        continue;
-                    }
-                    if let Valtype::Int(v) = cell.value {
-                        variance_acc += (v as f64 - mean).powi(2);
-                    }
-                }
-                // variance contribution from zeros:
-                variance_acc += (zero_count as f64) * ((0.0 - mean).powi(2));
+            totals.stored += 1;
+            // Only areas covering the largest sheet hundreds of times over can overflow
+            totals.sum = totals
+                .sum
+                .checked_add(v as i64)
+                .ok_or(EvalStatus::ErrValue(CellError::Num))?;
+            totals.min = totals.min.min(v);
+            totals.max = totals.max.max(v);
+        }
+    }
+    // The cells that are not stored hold 0, which may be the smallest or largest value, and
+    // areas without a number have 0 as both
+    if totals.stored < totals.cells || totals.stored == 0 {
+        totals.min = totals.min.min(0);
+        totals.max = totals.max.max(0);
+    }
+    Ok(totals)
+}
 
-                (variance_acc / area as f64).sqrt().round() as i32
-            }
-            _ => {
-                unsafe {
-                    STATUS_CODE = 2;
-                }
-                0
+/// Parses the arguments of `resize <rows> <cols>`.
+///
+/// # Arguments
+/// * `args` - The text following the `resize` keyword.
+///
+/// # Returns
+/// * `Result<(usize, usize), EvalStatus>` - The new `(total_rows, total_cols)`,
+///   `EvalStatus::UnrecognizedCmd` if the arguments are not two numbers, or
+///   `EvalStatus::InvalidRange` if they are outside the 1..=999 rows and 1..=18278 columns
+///   accepted at startup.
+pub fn parse_resize_args(args: &str) -> Result<(usize, usize), EvalStatus> {
+    let mut parts = args.split_whitespace();
+    let (Some(rows), Some(cols), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(EvalStatus::UnrecognizedCmd);
+    };
+    let rows: usize = rows.parse().map_err(|_| EvalStatus::UnrecognizedCmd)?;
+    let cols: usize = cols.parse().map_err(|_| EvalStatus::UnrecognizedCmd)?;
+    if !(1..=999).contains(&rows) || !(1..=18278).contains(&cols) {
+        return Err(EvalStatus::InvalidRange);
+    }
+    Ok((rows, cols))
+}
+
+/// Changes the dimensions of the sheet, keeping every cell at its row and column.
+///
+/// Cell keys depend on the number of columns, so the cells, their dependents, the ranges and
+/// the range flags are all re-keyed. Nothing changes if a non-empty cell lies outside the new
+/// bounds or a formula refers outside them.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean vector indicating whether each cell is part of a range, resized to
+///   the new dimensions.
+/// * `total_dims` - The current `(total_rows, total_cols)`.
+/// * `new_dims` - The new `(total_rows, total_cols)`, each at least 1.
+///
+/// # Returns
+/// * `Result<(), EvalStatus>` - `Ok(())` if the sheet was resized, or
+///   `EvalStatus::InvalidRange` if cells or references would be lost.
+pub fn resize_sheet<S: SheetStorage + ?Sized>(
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut Vec<bool>,
+    total_dims: (usize, usize),
+    new_dims: (usize, usize),
+) -> Result<(), EvalStatus> {
+    let (_, total_cols) = total_dims;
+    let (new_rows, new_cols) = new_dims;
+    let inside = |key: CellKey| {
+        (key as usize / total_cols) < new_rows && (key as usize % total_cols) < new_cols
+    };
+    let rekey = |key: CellKey| {
+        let (row, col) = (key as usize / total_cols, key as usize % total_cols);
+        (row * new_cols + col) as CellKey
+    };
+    let fits = |cell: &CellRef| {
+        cell.addr()
+            .is_some_and(|a| a.row < new_rows && a.col < new_cols)
+    };
+    for (key, cell) in spreadsheet.cells() {
+        let refs_fit = match &cell.data {
+            CellData::Range { .. } => cell
+                .data
+                .range_areas()
+                .iter()
+                .all(|(cell1, cell2)| fits(cell1) && fits(cell2)),
+            CellData::RoR { cell1, cell2, .. } => fits(cell1) && fits(cell2),
+            CellData::Ref { cell1 } | CellData::SleepR { cell1 } | CellData::RoC { cell1, .. } => {
+                fits(cell1)
             }
+            CellData::CoR { cell2, .. } => fits(cell2),
+            CellData::Expr(expr) => expr
+                .operands()
+                .into_iter()
+                .filter_map(Operand::corners)
+                .all(|(cell1, cell2)| fits(&cell1) && fits(&cell2)),
+            CellData::Lookup(lookup) => lookup
+                .corners()
+                .iter()
+                .all(|(cell1, cell2)| fits(cell1) && fits(cell2)),
+            CellData::Func { args, .. } => args
+                .iter()
+                .filter_map(Operand::corners)
+                .all(|(cell1, cell2)| fits(&cell1) && fits(&cell2)),
+            _ => true,
+        };
+        if !refs_fit || (!inside(key) && cell.data != CellData::Empty) {
+            return Err(EvalStatus::InvalidRange);
+        }
+    }
+
+    // Empty cells left outside are placeholders that no remaining formula refers to
+    for (key, mut cell) in spreadsheet.take_all(new_cols) {
+        if inside(key) {
+            cell.dependents = cell.dependents.iter().map(|&k| rekey(k)).collect();
+            spreadsheet.insert(rekey(key), cell);
+        }
+    }
+    *ranged = std::mem::take(ranged)
+        .into_iter()
+        .map(|(key, ranges)| {
+            let ranges = ranges.into_iter().map(|(s, e)| (rekey(s), rekey(e)));
+            (rekey(key), ranges.collect())
+        })
+        .collect();
+    let mut resized = vec![false; new_rows * new_cols];
+    for (idx, _) in is_range.iter().enumerate().filter(|&(_, &flag)| flag) {
+        if inside(idx as CellKey) {
+            resized[rekey(idx as CellKey) as usize] = true;
         }
     }
+    *is_range = resized;
+    Ok(())
 }
 
 /// Checks if a cell index falls within a given range.
@@ -274,9 +1805,404 @@ pub fn compute_range(
 ///
 /// # Returns
 /// * `bool` - `true` if the index is within the range, `false` otherwise.
-pub fn in_range(idx: u32, start: u32, end: u32, total_cols: usize) -> bool {
+pub fn in_range(idx: CellKey, start: CellKey, end: CellKey, total_cols: usize) -> bool {
     let (r0, c0) = (idx as usize / total_cols, idx as usize % total_cols);
     let (sr, sc) = (start as usize / total_cols, start as usize % total_cols);
     let (er, ec) = (end as usize / total_cols, end as usize % total_cols);
     (sr <= r0 && r0 <= er) && (sc <= c0 && c0 <= ec)
 }
+
+/// Returns the cells whose range formulas cover a cell.
+///
+/// # Arguments
+/// * `key` - The cell index to look up.
+/// * `ranged` - A hash map tracking the ranges read by each range formula.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// An iterator over the keys of the range formulas, each yielded once.
+pub fn range_parents(
+    key: CellKey,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_cols: usize,
+) -> impl Iterator<Item = CellKey> + '_ {
+    ranged.iter().filter_map(move |(&parent, ranges)| {
+        ranges
+            .iter()
+            .any(|&(start, end)| in_range(key, start, end, total_cols))
+            .then_some(parent)
+    })
+}
+
+/// An interval tree over the ranges read by range formulas, so that the formulas reading a cell
+/// are found without scanning every range in the sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # let total_cols = 10;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "SUM(A1:A3)")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "MAX(A2:A5)")?;
+/// // B1=SUM(A1:A3), C1=MAX(A2:A5)
+/// let index = RangeIndex::build(&ranged, total_cols);
+/// let mut parents = index.parents(2 * total_cols as CellKey); // A3
+/// parents.sort();
+/// assert_eq!(parents, vec![1, 2]);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RangeIndex {
+    total_cols: usize,
+    /// Each range as (first row, last row, first col, last col, formula key), sorted by first
+    /// row. The middle entry of any slice is the root of the subtree holding that slice.
+    spans: Vec<(usize, usize, usize, usize, CellKey)>,
+    /// Largest last row within the subtree rooted at each entry of `spans`.
+    max_end: Vec<usize>,
+}
+
+impl RangeIndex {
+    /// Indexes the ranges of every range formula.
+    ///
+    /// # Arguments
+    /// * `ranged` - A hash map tracking the ranges read by each range formula.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    pub fn build(ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>, total_cols: usize) -> Self {
+        let mut spans: Vec<(usize, usize, usize, usize, CellKey)> = ranged
+            .iter()
+            .flat_map(|(&parent, ranges)| {
+                ranges.iter().map(move |&(start, end)| {
+                    let (sr, sc) = (start as usize / total_cols, start as usize % total_cols);
+                    let (er, ec) = (end as usize / total_cols, end as usize % total_cols);
+                    (sr, er, sc, ec, parent)
+                })
+            })
+            .collect();
+        spans.sort_unstable();
+        let mut max_end = vec![0; spans.len()];
+        fill_max_end(&spans, &mut max_end, 0, spans.len());
+        RangeIndex {
+            total_cols,
+            spans,
+            max_end,
+        }
+    }
+
+    /// Returns the cells whose range formulas cover a cell.
+    ///
+    /// # Arguments
+    /// * `key` - The cell index to look up.
+    ///
+    /// # Returns
+    /// * `Vec<CellKey>` - The keys of the range formulas, once per covering range, in no particular
+    ///   order.
+    pub fn parents(&self, key: CellKey) -> Vec<CellKey> {
+        let (row, col) = (
+            key as usize / self.total_cols,
+            key as usize % self.total_cols,
+        );
+        let mut parents = Vec::new();
+        let mut pending = vec![(0, self.spans.len())];
+        while let Some((lo, hi)) = pending.pop() {
+            if lo >= hi {
+                continue;
+            }
+            let mid = lo + (hi - lo) / 2;
+            // No range in this subtree reaches down to the row
+            if self.max_end[mid] < row {
+                continue;
+            }
+            pending.push((lo, mid));
+            let (sr, er, sc, ec, parent) = self.spans[mid];
+            // Ranges to the right start at or after this one
+            if sr <= row {
+                if row <= er && sc <= col && col <= ec {
+                    parents.push(parent);
+                }
+                pending.push((mid + 1, hi));
+            }
+        }
+        parents
+    }
+}
+
+/// Records in `max_end` the largest last row of each subtree of `spans[lo..hi]`.
+///
+/// # Returns
+/// * `usize` - The largest last row in `spans[lo..hi]`, or 0 if it is empty.
+fn fill_max_end(
+    spans: &[(usize, usize, usize, usize, CellKey)],
+    max_end: &mut [usize],
+    lo: usize,
+    hi: usize,
+) -> usize {
+    if lo >= hi {
+        return 0;
+    }
+    let mid = lo + (hi - lo) / 2;
+    let left = fill_max_end(spans, max_end, lo, mid);
+    let right = fill_max_end(spans, max_end, mid + 1, hi);
+    max_end[mid] = spans[mid].1.max(left).max(right);
+    max_end[mid]
+}
+
+/// Orders cells so that every cell comes after all the cells it reads.
+///
+/// Inputs are followed through both single references (`Cell::dependents`) and ranges.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Vec<CellKey>` - The keys of the cells in the sheet and of every cell depending on them, in
+///   dependency order.
+pub fn dependency_order<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_cols: usize,
+) -> Vec<CellKey> {
+    let index = RangeIndex::build(ranged, total_cols);
+    // Count the inputs of each cell, through both single references and ranges
+    let mut in_degree: HashMap<CellKey, usize> = spreadsheet.cells().map(|(k, _)| (k, 0)).collect();
+    for (key, cell) in spreadsheet.cells() {
+        for &dep_key in &cell.dependents {
+            *in_degree.entry(dep_key).or_default() += 1;
+        }
+        for parent in index.parents(key) {
+            *in_degree.entry(parent).or_default() += 1;
+        }
+    }
+
+    let mut ready: Vec<CellKey> = in_degree
+        .iter()
+        .filter_map(|(&key, &d)| if d == 0 { Some(key) } else { None })
+        .collect();
+    let mut order = Vec::with_capacity(in_degree.len());
+    while let Some(key) = ready.pop() {
+        order.push(key);
+        let dependents = spreadsheet
+            .get(&key)
+            .into_iter()
+            .flat_map(|c| &c.dependents);
+        for dep_key in dependents.copied().chain(index.parents(key)) {
+            if let Some(d) = in_degree.get_mut(&dep_key) {
+                *d -= 1;
+                if *d == 0 {
+                    ready.push(dep_key);
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Returns the length of the longest chain of formulas ending at each cell.
+///
+/// Only formulas with inputs count, so a constant starts no chain and a cell reading it ends a
+/// chain of 1. Inputs are followed through both single references and ranges.
+///
+/// # Arguments
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `HashMap<CellKey, usize>` - The chain length of every cell in the sheet.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 0, "5")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "A1+1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "SUM(A1:B1)")?;
+/// // A1=5, B1=A1+1, C1=SUM(A1:B1)
+/// let depths = chain_depths(&sheet, &ranged, 10);
+/// assert_eq!((depths[&0], depths[&1], depths[&2]), (0, 1, 2));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn chain_depths<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_cols: usize,
+) -> HashMap<CellKey, usize> {
+    let index = RangeIndex::build(ranged, total_cols);
+    let mut depth: HashMap<CellKey, usize> = HashMap::new();
+    for key in dependency_order(spreadsheet, ranged, total_cols) {
+        let Some(cell) = spreadsheet.get(&key) else {
+            continue;
+        };
+        let reads = !matches!(
+            cell.data,
+            CellData::Empty | CellData::Const | CellData::CoC { .. } | CellData::SleepC
+        );
+        let d = depth.get(&key).copied().unwrap_or(0) + usize::from(reads);
+        depth.insert(key, d);
+        for dep_key in cell.dependents.iter().copied().chain(index.parents(key)) {
+            let entry = depth.entry(dep_key).or_default();
+            *entry = (*entry).max(d);
+        }
+    }
+    depth
+}
+
+/// Returns every cell whose value is computed from a cell, directly or through other cells.
+///
+/// # Arguments
+/// * `key` - The cell index to start from.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Vec<CellKey>` - The keys of the dependent cells in row-major order, without `key` itself.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "A1+1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "SUM(B1:B2)")?;
+/// // B1=A1+1, C1=SUM(B1:B2)
+/// assert_eq!(transitive_dependents(0, &sheet, &ranged, 10), vec![1, 2]);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn transitive_dependents<S: SheetStorage + ?Sized>(
+    key: CellKey,
+    spreadsheet: &S,
+    ranged: &HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    total_cols: usize,
+) -> Vec<CellKey> {
+    let index = RangeIndex::build(ranged, total_cols);
+    let mut found = BTreeSet::new();
+    let mut pending = vec![key];
+    while let Some(key) = pending.pop() {
+        let dependents = spreadsheet
+            .get(&key)
+            .into_iter()
+            .flat_map(|c| &c.dependents);
+        for dep_key in dependents.copied().chain(index.parents(key)) {
+            if found.insert(dep_key) {
+                pending.push(dep_key);
+            }
+        }
+    }
+    found.remove(&key);
+    found.into_iter().collect()
+}
+
+/// Returns every cell a cell's value is computed from, directly or through other cells.
+///
+/// Single references are always listed. Of the cells in a range, only those holding something
+/// are listed, as empty cells read as 0 and have no inputs of their own.
+///
+/// # Arguments
+/// * `key` - The cell index to start from.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Vec<CellKey>` - The keys of the cells read, in row-major order, without `key` itself.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::*;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::utils::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 1, "A1+1")?;
+/// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (10, 10), 0, 2, "SUM(B1:B2)")?;
+/// // B1=A1+1, C1=SUM(B1:B2)
+/// assert_eq!(transitive_precedents(2, &sheet, 10), vec![0, 1]);
+/// # Ok::<(), EvalStatus>(())
+/// ```
+pub fn transitive_precedents<S: SheetStorage + ?Sized>(
+    key: CellKey,
+    spreadsheet: &S,
+    total_cols: usize,
+) -> Vec<CellKey> {
+    let key_of = |cell: &CellRef| {
+        let (row, col) = cell.indices();
+        (row * total_cols + col) as CellKey
+    };
+    // The non-empty cells of a range
+    let stored_in = |cell1: &CellRef, cell2: &CellRef| -> Vec<CellKey> {
+        let ((r1, c1), (r2, c2)) = (cell1.indices(), cell2.indices());
+        (r1..=r2)
+            .flat_map(|row| (c1..=c2).map(move |col| (row * total_cols + col) as CellKey))
+            .filter(|k| {
+                spreadsheet
+                    .get(k)
+                    .is_some_and(|c| c.data != CellData::Empty)
+            })
+            .collect()
+    };
+    let mut found = BTreeSet::new();
+    let mut pending = vec![key];
+    while let Some(key) = pending.pop() {
+        let Some(cell) = spreadsheet.get(&key) else {
+            continue;
+        };
+        let inputs: Vec<CellKey> = match &cell.data {
+            CellData::Ref { cell1 } | CellData::SleepR { cell1 } | CellData::RoC { cell1, .. } => {
+                vec![key_of(cell1)]
+            }
+            CellData::CoR { cell2, .. } => vec![key_of(cell2)],
+            CellData::RoR { cell1, cell2, .. } => vec![key_of(cell1), key_of(cell2)],
+            CellData::Range { .. } => cell
+                .data
+                .range_areas()
+                .iter()
+                .flat_map(|(cell1, cell2)| stored_in(cell1, cell2))
+                .collect(),
+            CellData::Expr(expr) => expr
+                .operands()
+                .into_iter()
+                .flat_map(|operand| match operand {
+                    Operand::Const(_) => Vec::new(),
+                    Operand::Ref(cell1) => vec![key_of(cell1)],
+                    Operand::Range { cell1, cell2, .. } => stored_in(cell1, cell2),
+                })
+                .collect(),
+            CellData::Lookup(lookup) => {
+                let mut inputs = match &lookup.key {
+                    Operand::Ref(cell1) => vec![key_of(cell1)],
+                    _ => Vec::new(),
+                };
+                inputs.extend(stored_in(&lookup.cell1, &lookup.cell2));
+                inputs
+            }
+            CellData::Func { args, .. } => args
+                .iter()
+                .filter_map(|operand| match operand {
+                    Operand::Ref(cell1) => Some(key_of(cell1)),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        for input in inputs {
+            if found.insert(input) {
+                pending.push(input);
+            }
+        }
+    }
+    found.remove(&key);
+    found.into_iter().collect()
+}
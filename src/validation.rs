@@ -0,0 +1,439 @@
+//! # Validation Module
+//! This module keeps the data validation rules set with `validate <range> int <min>..<max>`.
+//! After an edit is recalculated, the edited cell and every cell computed from it are checked
+//! against the rules covering them. A rule either rejects the edit, restoring the cell, or
+//! keeps it and marks the cells breaking the rule, so that they can be shown and fixed later.
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::format::Corners;
+use crate::parser::{RecalcResult, assign_formulas_checked, detect_formula, update_and_recalc};
+use crate::storage::SheetStorage;
+use crate::utils::{parse_range, to_cell_name, transitive_dependents};
+use crate::{Cell, CellData, CellKey, EvalStatus, Valtype};
+
+/// The values a rule accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Constraint {
+    /// A whole number from `min` to `max`, both included. Error values are rejected.
+    Int { min: i32, max: i32 },
+}
+
+/// What happens to an edit that leaves a cell breaking a rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnFailure {
+    /// The edit is undone.
+    #[default]
+    Reject,
+    /// The edit is kept and the cells breaking the rule are marked.
+    Mark,
+}
+
+/// A constraint on the values of a block of cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValidationRule {
+    /// The top-left and bottom-right (row, col) of the cells checked.
+    pub corners: Corners,
+    /// The values accepted.
+    pub constraint: Constraint,
+    /// What happens to an edit breaking the rule.
+    pub on_failure: OnFailure,
+}
+
+impl Constraint {
+    /// Checks whether a value satisfies the constraint.
+    pub fn accepts(&self, value: &Valtype) -> bool {
+        match (self, value) {
+            (Constraint::Int { min, max }, Valtype::Int(number)) => (min..=max).contains(&number),
+            (Constraint::Int { .. }, Valtype::Str(_) | Valtype::Err(_)) => false,
+        }
+    }
+}
+
+impl ValidationRule {
+    /// Checks whether the rule covers a cell.
+    pub fn covers(&self, row: usize, col: usize) -> bool {
+        let ((r1, c1), (r2, c2)) = self.corners;
+        (r1..=r2).contains(&row) && (c1..=c2).contains(&col)
+    }
+}
+
+/// The validation rules of a sheet and the cells marked as breaking them.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::parser::assign_formula;
+/// # use std::collections::HashMap;
+/// # use spreadsheet::validation::*;
+/// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+/// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 100]);
+/// let mut rules = ValidationTable::default();
+/// rules.add(parse_validate_args("A1:A10 int 0..100", 10, 10)?, &sheet, 10);
+/// let status = validated_assign(&mut rules, &mut sheet, &mut ranged, &mut is_range, (10, 10), (0, 0), "250").status;
+/// assert_eq!(status, Err(EvalStatus::ValidationFailed));
+/// # Ok::<(), EvalStatus>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ValidationTable {
+    /// The rules, in the order they were added.
+    rules: Vec<ValidationRule>,
+    /// The indices of the cells found breaking a rule when last checked.
+    marked: BTreeSet<CellKey>,
+}
+
+impl ValidationTable {
+    /// Checks whether any rule is set.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the indices of the cells marked as breaking a rule, in row-major order.
+    pub fn marked(&self) -> &BTreeSet<CellKey> {
+        &self.marked
+    }
+
+    /// Adds a rule and marks the cells it covers that already break it.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule to add.
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    ///
+    /// # Returns
+    /// * `usize` - The number of cells already breaking the rule.
+    pub fn add<S: SheetStorage + ?Sized>(
+        &mut self,
+        rule: ValidationRule,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) -> usize {
+        let broken: Vec<CellKey> = spreadsheet
+            .cells()
+            .filter(|&(key, cell)| {
+                let (row, col) = (key as usize / total_cols, key as usize % total_cols);
+                rule.covers(row, col)
+                    && cell.data != CellData::Empty
+                    && !rule.constraint.accepts(&cell.value)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        self.marked.extend(&broken);
+        self.rules.push(rule);
+        broken.len()
+    }
+
+    /// Marks every cell breaking a rule, and only those, e.g. after the cells were re-keyed by
+    /// a resize, replaced by a load or restored by an undo.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    pub fn recheck<S: SheetStorage + ?Sized>(&mut self, spreadsheet: &S, total_cols: usize) {
+        if self.rules.is_empty() {
+            return;
+        }
+        let marked = spreadsheet
+            .cells()
+            .filter(|&(key, cell)| {
+                let (row, col) = (key as usize / total_cols, key as usize % total_cols);
+                self.broken_rule(row, col, Some(cell)).is_some()
+            })
+            .map(|(key, _)| key)
+            .collect();
+        self.marked = marked;
+    }
+
+    /// Lists the `validate` commands that add every rule again, in the order they were added.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::validation::*;
+    /// # use std::collections::HashMap;
+    /// # let mut rules = ValidationTable::default();
+    /// # let sheet: HashMap<CellKey, Cell> = HashMap::new();
+    /// rules.add(parse_validate_args("A1:A10 int 0..100 mark", 10, 10)?, &sheet, 10);
+    /// assert_eq!(rules.commands(), vec!["validate A1:A10 int 0..100 mark"]);
+    /// # Ok::<(), EvalStatus>(())
+    /// ```
+    pub fn commands(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let ((r1, c1), (r2, c2)) = rule.corners;
+                let Constraint::Int { min, max } = rule.constraint;
+                let on_failure = match rule.on_failure {
+                    OnFailure::Reject => "",
+                    OnFailure::Mark => " mark",
+                };
+                format!(
+                    "validate {}:{} int {}..{}{}",
+                    to_cell_name(r1, c1),
+                    to_cell_name(r2, c2),
+                    min,
+                    max,
+                    on_failure
+                )
+            })
+            .collect()
+    }
+
+    /// Finds the first rule a cell value breaks.
+    ///
+    /// # Arguments
+    /// * `row` - The row index of the cell.
+    /// * `col` - The column index of the cell.
+    /// * `cell` - The cell, or `None` if it is empty. Empty cells break no rule.
+    ///
+    /// # Returns
+    /// * `Option<&ValidationRule>` - The rule broken, or `None` if the value is accepted.
+    pub fn broken_rule(
+        &self,
+        row: usize,
+        col: usize,
+        cell: Option<&Cell>,
+    ) -> Option<&ValidationRule> {
+        let cell = cell.filter(|cell| cell.data != CellData::Empty)?;
+        self.rules
+            .iter()
+            .find(|rule| rule.covers(row, col) && !rule.constraint.accepts(&cell.value))
+    }
+}
+
+/// Parses the arguments of `validate <range> int <min>..<max> [reject|mark]`.
+///
+/// # Arguments
+/// * `args` - The text following `validate`, e.g. "A1:A10 int 0..100 mark".
+/// * `total_rows` - The total number of rows in the spreadsheet.
+/// * `total_cols` - The total number of columns in the spreadsheet.
+///
+/// # Returns
+/// * `Result<ValidationRule, EvalStatus>` - The rule, rejecting edits unless `mark` is given;
+///   `EvalStatus::InvalidRange` if the range (or single cell) is malformed or outside the
+///   sheet, or `EvalStatus::UnrecognizedCmd` if the constraint is malformed or its minimum is
+///   above its maximum.
+pub fn parse_validate_args(
+    args: &str,
+    total_rows: usize,
+    total_cols: usize,
+) -> Result<ValidationRule, EvalStatus> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let (range, bounds, on_failure) = match parts[..] {
+        [range, "int", bounds] => (range, bounds, OnFailure::Reject),
+        [range, "int", bounds, "reject"] => (range, bounds, OnFailure::Reject),
+        [range, "int", bounds, "mark"] => (range, bounds, OnFailure::Mark),
+        _ => return Err(EvalStatus::UnrecognizedCmd),
+    };
+    let (min, max) = bounds.split_once("..").ok_or(EvalStatus::UnrecognizedCmd)?;
+    let (Ok(min), Ok(max)) = (min.parse::<i32>(), max.parse::<i32>()) else {
+        return Err(EvalStatus::UnrecognizedCmd);
+    };
+    if min > max {
+        return Err(EvalStatus::UnrecognizedCmd);
+    }
+    let range = range.to_ascii_uppercase();
+    let range = if range.contains(':') {
+        range
+    } else {
+        format!("{}:{}", range, range)
+    };
+    let corners = parse_range(&range, total_rows, total_cols).ok_or(EvalStatus::InvalidRange)?;
+    Ok(ValidationRule {
+        corners,
+        constraint: Constraint::Int { min, max },
+        on_failure,
+    })
+}
+
+/// Recalculates an edited cell as `parser::update_and_recalc` does, then checks the rules.
+///
+/// The edited cell and every cell computed from it are checked. If one breaks a `reject`
+/// rule, the edit is undone and the sheet recalculated as it was. Otherwise cells breaking a
+/// `mark` rule are marked, and checked cells that now pass are unmarked.
+///
+/// # Arguments
+/// * `rules` - The validation rules of the sheet.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`, with the
+///   edited cell already holding its new formula.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `cell` - The (row, col) of the edited cell.
+/// * `backup` - The cell as it was before the edit.
+///
+/// # Returns
+/// * `RecalcResult` - The result of `update_and_recalc`, with `EvalStatus::ValidationFailed`
+///   if a cell breaks a rule, whether the edit was kept and marked or undone, in which case no
+///   cell is listed as changed.
+pub fn validated_update<S: SheetStorage + ?Sized>(
+    rules: &mut ValidationTable,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    (row, col): (usize, usize),
+    backup: Cell,
+) -> RecalcResult {
+    let total_cols = total_dims.1;
+    let key = (row * total_cols + col) as CellKey;
+    let restore = backup.clone();
+    let result = update_and_recalc(spreadsheet, ranged, is_range, total_dims, row, col, backup);
+    if result.status.is_err() || rules.is_empty() {
+        return result;
+    }
+    let mut checked = transitive_dependents(key, spreadsheet, ranged, total_cols);
+    checked.insert(0, key);
+    let broken: Vec<(CellKey, OnFailure)> = checked
+        .iter()
+        .filter_map(|&k| {
+            let (r, c) = (k as usize / total_cols, k as usize % total_cols);
+            let rule = rules.broken_rule(r, c, spreadsheet.get(&k))?;
+            Some((k, rule.on_failure))
+        })
+        .collect();
+    if broken
+        .iter()
+        .any(|&(_, on_failure)| on_failure == OnFailure::Reject)
+    {
+        let edited = spreadsheet.get(&key).cloned().unwrap_or(Cell {
+            value: Valtype::Int(0),
+            data: CellData::Empty,
+            dependents: HashSet::new(),
+        });
+        spreadsheet.insert(key, restore);
+        let _ = update_and_recalc(spreadsheet, ranged, is_range, total_dims, row, col, edited);
+        return RecalcResult {
+            changed: Vec::new(),
+            status: Err(EvalStatus::ValidationFailed),
+        };
+    }
+    for k in &checked {
+        rules.marked.remove(k);
+    }
+    rules.marked.extend(broken.iter().map(|&(k, _)| k));
+    if broken.is_empty() {
+        result
+    } else {
+        RecalcResult {
+            status: Err(EvalStatus::ValidationFailed),
+            ..result
+        }
+    }
+}
+
+/// Assigns a formula to a cell as `parser::assign_formula` does, checking the rules after.
+///
+/// # Arguments
+/// * `rules` - The validation rules of the sheet.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `cell` - The (row, col) of the cell to assign.
+/// * `formula` - The formula text (e.g., "B1+2").
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed and the outcome of the assignment, as from
+///   `validated_update`.
+pub fn validated_assign<S: SheetStorage + ?Sized>(
+    rules: &mut ValidationTable,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    (row, col): (usize, usize),
+    formula: &str,
+) -> RecalcResult {
+    let idx = (row * total_dims.1 + col) as CellKey;
+    let old_cell = spreadsheet.get(&idx).cloned().unwrap_or(Cell {
+        value: Valtype::Int(0),
+        data: CellData::Empty,
+        dependents: HashSet::new(),
+    });
+    let mut new_cell = old_cell.clone();
+    detect_formula(&mut new_cell, formula);
+    spreadsheet.insert(idx, new_cell);
+    spreadsheet.reserve_on_grow();
+    validated_update(
+        rules,
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        (row, col),
+        old_cell,
+    )
+}
+
+/// Assigns formulas to several cells as `parser::assign_formulas_checked` does, checking the
+/// rules once after the single recalculation.
+///
+/// The assigned cells and every cell computed from them are checked. If one breaks a `reject`
+/// rule, every cell is restored; otherwise the cells breaking a `mark` rule are marked, and
+/// checked cells that now pass are unmarked.
+///
+/// # Arguments
+/// * `rules` - The validation rules of the sheet.
+/// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+/// * `ranged` - A hash map tracking ranges for dependency management.
+/// * `is_range` - A boolean array indicating whether each cell is part of a range.
+/// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+/// * `formulas` - The (row, col, formula) assignments, e.g. from `fill_formulas`.
+///
+/// # Returns
+/// * `RecalcResult` - The cells whose value changed and the outcome of the assignments, with
+///   `EvalStatus::ValidationFailed` if a cell breaks a rule, whether the cells were kept and
+///   marked or restored, in which case no cell is listed as changed.
+pub fn validated_assign_formulas<S: SheetStorage + ?Sized>(
+    rules: &mut ValidationTable,
+    spreadsheet: &mut S,
+    ranged: &mut HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    is_range: &mut [bool],
+    total_dims: (usize, usize),
+    formulas: &[(usize, usize, String)],
+) -> RecalcResult {
+    let total_cols = total_dims.1;
+    let mut marked = false;
+    let result = assign_formulas_checked(
+        spreadsheet,
+        ranged,
+        is_range,
+        total_dims,
+        formulas,
+        |spreadsheet, ranged, keys| {
+            if rules.is_empty() {
+                return Ok(());
+            }
+            let mut checked: BTreeSet<CellKey> = keys.iter().copied().collect();
+            for &key in keys {
+                checked.extend(transitive_dependents(key, spreadsheet, ranged, total_cols));
+            }
+            let broken: Vec<(CellKey, OnFailure)> = checked
+                .iter()
+                .filter_map(|&k| {
+                    let (r, c) = (k as usize / total_cols, k as usize % total_cols);
+                    let rule = rules.broken_rule(r, c, spreadsheet.get(&k))?;
+                    Some((k, rule.on_failure))
+                })
+                .collect();
+            if broken
+                .iter()
+                .any(|&(_, on_failure)| on_failure == OnFailure::Reject)
+            {
+                return Err(EvalStatus::ValidationFailed);
+            }
+            rules.marked.retain(|k| !checked.contains(k));
+            rules.marked.extend(broken.iter().map(|&(k, _)| k));
+            marked = !broken.is_empty();
+            Ok(())
+        },
+    );
+    if marked {
+        RecalcResult {
+            status: Err(EvalStatus::ValidationFailed),
+            ..result
+        }
+    } else {
+        result
+    }
+}
@@ -0,0 +1,115 @@
+//! # Visits Module
+//! This module keeps the cells jumped to with `goto`, `scroll_to`, `copy` or `paste`, so that
+//! `back` and `forward` can retrace them in a large sheet, as a browser does with pages. The
+//! token `!last` names the cell visited before the current one, so `goto !last` switches
+//! between two places. Moving a step at a time, with the arrow keys or `w`, `a`, `s` and `d`,
+//! is not a visit; the place left is recorded when the next jump starts from it.
+use crate::EvalStatus;
+use crate::utils::to_cell_name;
+
+/// The most cells a history keeps; the oldest are forgotten first.
+pub const MAX_VISITS: usize = 100;
+
+/// The token standing for the cell visited before the current one.
+pub const LAST_TOKEN: &str = "!last";
+
+/// The cells visited, as (row, col), and how far `back` has moved through them.
+///
+/// # Fields
+/// * `cells` - The cells in the order they were visited.
+/// * `current` - The index in `cells` of the cell shown, which is the last one unless `back`
+///   was used.
+#[derive(Clone, Debug, Default)]
+pub struct VisitHistory {
+    cells: Vec<(usize, usize)>,
+    current: usize,
+}
+
+impl VisitHistory {
+    /// Records a jump from one cell to another. The cells `back` had moved past are forgotten,
+    /// as is a repeat of the cell shown.
+    ///
+    /// # Arguments
+    /// * `from` - The cell left, recorded first if it is not the cell shown.
+    /// * `to` - The cell jumped to.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::visits::*;
+    /// let mut visits = VisitHistory::default();
+    /// visits.visit((0, 0), (4, 2));
+    /// assert_eq!(visits.back(), Some((0, 0)));
+    /// assert_eq!(visits.forward(), Some((4, 2)));
+    /// ```
+    pub fn visit(&mut self, from: (usize, usize), to: (usize, usize)) {
+        self.cells.truncate(self.current + 1);
+        for cell in [from, to] {
+            if self.cells.last() != Some(&cell) {
+                self.cells.push(cell);
+            }
+        }
+        if self.cells.len() > MAX_VISITS {
+            self.cells.drain(..self.cells.len() - MAX_VISITS);
+        }
+        self.current = self.cells.len() - 1;
+    }
+
+    /// Moves to the cell visited before the one shown.
+    ///
+    /// # Returns
+    /// * `Option<(usize, usize)>` - The cell, or `None` at the start of the history.
+    pub fn back(&mut self) -> Option<(usize, usize)> {
+        self.current = self.current.checked_sub(1)?;
+        Some(self.cells[self.current])
+    }
+
+    /// Moves to the cell visited after the one shown, undoing a `back`.
+    ///
+    /// # Returns
+    /// * `Option<(usize, usize)>` - The cell, or `None` at the end of the history.
+    pub fn forward(&mut self) -> Option<(usize, usize)> {
+        if self.current + 1 >= self.cells.len() {
+            return None;
+        }
+        self.current += 1;
+        Some(self.cells[self.current])
+    }
+
+    /// Returns the cell visited before the one shown, which `!last` names.
+    pub fn last(&self) -> Option<(usize, usize)> {
+        self.current
+            .checked_sub(1)
+            .and_then(|index| self.cells.get(index).copied())
+    }
+
+    /// Replaces `!last` by the name of the cell it stands for; any other cell reference is
+    /// returned as it is.
+    ///
+    /// # Arguments
+    /// * `cell_ref` - A cell reference as typed, e.g. "B2" or "!last".
+    ///
+    /// # Returns
+    /// * `Result<String, EvalStatus>` - The cell reference, or `EvalStatus::InvalidRange` for
+    ///   `!last` when no cell was visited before the one shown.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::visits::*;
+    /// let mut visits = VisitHistory::default();
+    /// assert!(visits.expand("!last").is_err());
+    /// visits.visit((0, 0), (9, 1));
+    /// assert_eq!(visits.expand("!last")?, "A1");
+    /// assert_eq!(visits.expand("C3")?, "C3");
+    /// # Ok::<(), EvalStatus>(())
+    /// ```
+    pub fn expand(&self, cell_ref: &str) -> Result<String, EvalStatus> {
+        let cell_ref = cell_ref.trim();
+        if !cell_ref.eq_ignore_ascii_case(LAST_TOKEN) {
+            return Ok(cell_ref.to_string());
+        }
+        let (row, col) = self.last().ok_or(EvalStatus::InvalidRange)?;
+        Ok(to_cell_name(row, col))
+    }
+}
@@ -0,0 +1,124 @@
+//! # Watch Module
+//! This module keeps the cells watched with `watch A1`, and finds those whose value changed.
+//! The interfaces check the list after each command, so a change is reported once however
+//! many cells were recalculated on the way, and an edit that was undone, such as one rejected
+//! by a validation rule, is not reported at all.
+use std::collections::BTreeMap;
+
+use crate::storage::SheetStorage;
+use crate::utils::{to_cell_name, to_indices};
+use crate::{CellKey, EvalStatus, Valtype};
+
+/// The watched cells of a sheet, with the value each one had when last checked.
+#[derive(Clone, Debug, Default)]
+pub struct WatchList {
+    cells: BTreeMap<(usize, usize), Valtype>,
+}
+
+/// Reads the value of a cell, which is 0 if the cell was never set or lies outside the sheet.
+fn value_of<S: SheetStorage + ?Sized>(
+    spreadsheet: &S,
+    total_cols: usize,
+    (row, col): (usize, usize),
+) -> Valtype {
+    let key = (row * total_cols + col) as CellKey;
+    match spreadsheet.get(&key) {
+        Some(cell) if col < total_cols => cell.value.clone(),
+        _ => Valtype::Int(0),
+    }
+}
+
+/// Formats a value as the grid shows it.
+fn value_text(value: &Valtype) -> String {
+    match value {
+        Valtype::Int(v) => v.to_string(),
+        Valtype::Str(s) => s.to_string(),
+        Valtype::Err(error) => error.to_string(),
+    }
+}
+
+impl WatchList {
+    /// Starts watching a cell, or stops watching it.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_dims` - A tuple `(total_rows, total_cols)` defining the spreadsheet dimensions.
+    /// * `name` - The cell name, e.g. `A1`.
+    /// * `watch` - Whether to watch the cell, or to stop watching it.
+    ///
+    /// # Returns
+    /// * `Result<(), EvalStatus>` - `Ok(())`, `EvalStatus::UnrecognizedCmd` if `name` is not a
+    ///   cell name, or `EvalStatus::InvalidRange` if the cell is outside the sheet.
+    pub fn set<S: SheetStorage + ?Sized>(
+        &mut self,
+        spreadsheet: &S,
+        total_dims: (usize, usize),
+        name: &str,
+        watch: bool,
+    ) -> Result<(), EvalStatus> {
+        let (row, col) = to_indices(name.trim()).map_err(|_| EvalStatus::UnrecognizedCmd)?;
+        if row >= total_dims.0 || col >= total_dims.1 {
+            return Err(EvalStatus::InvalidRange);
+        }
+        if watch {
+            let value = value_of(spreadsheet, total_dims.1, (row, col));
+            self.cells.insert((row, col), value);
+        } else {
+            self.cells.remove(&(row, col));
+        }
+        Ok(())
+    }
+
+    /// Lists the watched cells, as (row, col), in row-major order.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.cells.keys().copied()
+    }
+
+    /// Finds the watched cells whose value changed since the last check, and records their
+    /// new values.
+    ///
+    /// # Arguments
+    /// * `spreadsheet` - A hash map containing cell data, indexed by a unique `CellKey`.
+    /// * `total_cols` - The total number of columns in the spreadsheet.
+    ///
+    /// # Returns
+    /// * `Vec<String>` - One `A1: before -> after` line per changed cell, in row-major order.
+    ///
+    /// # Examples
+    /// ```
+    /// # use spreadsheet::*;
+    /// # use spreadsheet::parser::assign_formula;
+    /// # use std::collections::HashMap;
+    /// # use spreadsheet::watch::*;
+    /// # let mut watches = WatchList::default();
+    /// # let mut sheet: HashMap<CellKey, Cell> = HashMap::new();
+    /// # let (mut ranged, mut is_range) = (HashMap::new(), vec![false; 9]);
+    /// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "5")?;
+    /// watches.set(&sheet, (3, 3), "A1", true)?;
+    /// // A1 changes from 5 to 6
+    /// # assign_formula(&mut sheet, &mut ranged, &mut is_range, (3, 3), 0, 0, "6")?;
+    /// assert_eq!(watches.changes(&sheet, 3), vec!["A1: 5 -> 6"]);
+    /// assert!(watches.changes(&sheet, 3).is_empty());
+    /// # Ok::<(), EvalStatus>(())
+    /// ```
+    pub fn changes<S: SheetStorage + ?Sized>(
+        &mut self,
+        spreadsheet: &S,
+        total_cols: usize,
+    ) -> Vec<String> {
+        let mut changes = Vec::new();
+        for (&cell, value) in self.cells.iter_mut() {
+            let current = value_of(spreadsheet, total_cols, cell);
+            if current != *value {
+                changes.push(format!(
+                    "{}: {} -> {}",
+                    to_cell_name(cell.0, cell.1),
+                    value_text(value),
+                    value_text(&current)
+                ));
+                *value = current;
+            }
+        }
+        changes
+    }
+}
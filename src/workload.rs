@@ -0,0 +1,180 @@
+//! # Workload Module
+//! This module generates synthetic sheets and formulas for the benchmarks in `benches/`, so
+//! that changes to the parser and the recalculation engine can be measured on the same inputs.
+//! Every generator is deterministic: the same arguments always give the same workload.
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::assign_formula;
+use crate::utils::to_cell_name;
+use crate::{Cell, CellData, CellKey, Valtype};
+
+/// A sheet with its dependency bookkeeping, ready to be edited or evaluated.
+pub struct Workload {
+    /// The cells, indexed by `row * total_cols + col`.
+    pub sheet: HashMap<CellKey, Cell>,
+    /// The ranges read by each range formula.
+    pub ranged: HashMap<CellKey, Vec<(CellKey, CellKey)>>,
+    /// Whether each cell is read by a range formula.
+    pub is_range: Vec<bool>,
+    /// The dimensions of the sheet as `(total_rows, total_cols)`.
+    pub total_dims: (usize, usize),
+}
+
+impl Workload {
+    /// Creates an empty sheet.
+    ///
+    /// # Arguments
+    /// * `total_rows` - The number of rows in the sheet.
+    /// * `total_cols` - The number of columns in the sheet.
+    pub fn new(total_rows: usize, total_cols: usize) -> Self {
+        Workload {
+            sheet: HashMap::new(),
+            ranged: HashMap::new(),
+            is_range: vec![false; total_rows * total_cols],
+            total_dims: (total_rows, total_cols),
+        }
+    }
+
+    /// Assigns a formula to a cell, panicking if the engine rejects it.
+    ///
+    /// # Arguments
+    /// * `row` - The row index of the cell.
+    /// * `col` - The column index of the cell.
+    /// * `formula` - The formula text.
+    pub fn set(&mut self, row: usize, col: usize, formula: &str) {
+        let status = assign_formula(
+            &mut self.sheet,
+            &mut self.ranged,
+            &mut self.is_range,
+            self.total_dims,
+            row,
+            col,
+            formula,
+        );
+        assert_eq!(status, Ok(()), "{} rejected at ({}, {})", formula, row, col);
+    }
+}
+
+/// A small pseudo-random generator, so workloads need no extra dependency.
+struct Lcg(u64);
+
+impl Lcg {
+    /// Returns a number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % bound as u64) as usize
+    }
+}
+
+/// Generates formulas of every kind the parser recognizes, for a sheet of the given size.
+///
+/// # Arguments
+/// * `count` - The number of formulas.
+/// * `total_rows` - The rows the references may point to.
+/// * `total_cols` - The columns the references may point to.
+/// * `seed` - Picks the formulas; the same seed gives the same corpus.
+///
+/// # Returns
+/// * `Vec<String>` - Constants, references, arithmetic on constants and references, range
+///   functions and `SLEEP` formulas, in a mix that cycles through the kinds.
+///
+/// # Examples
+/// ```
+/// # use spreadsheet::*;
+/// # use spreadsheet::workload::*;
+/// let corpus = formula_corpus(100, 50, 10, 7);
+/// assert_eq!(corpus.len(), 100);
+/// ```
+pub fn formula_corpus(
+    count: usize,
+    total_rows: usize,
+    total_cols: usize,
+    seed: u64,
+) -> Vec<String> {
+    const OPS: [char; 4] = ['+', '-', '*', '/'];
+    const FUNCS: [&str; 5] = ["MAX", "MIN", "AVG", "SUM", "STDEV"];
+    let mut rng = Lcg(seed);
+    let cell = |rng: &mut Lcg| to_cell_name(rng.below(total_rows), rng.below(total_cols));
+    (0..count)
+        .map(|i| {
+            let op = OPS[rng.below(OPS.len())];
+            match i % 8 {
+                0 => rng.below(10_000).to_string(),
+                1 => cell(&mut rng),
+                2 => format!("{}{}{}", rng.below(100), op, rng.below(100) + 1),
+                3 => format!("{}{}{}", cell(&mut rng), op, rng.below(100) + 1),
+                4 => format!("{}{}{}", rng.below(100), op, cell(&mut rng)),
+                5 => format!("{}{}{}", cell(&mut rng), op, cell(&mut rng)),
+                6 => {
+                    let (r1, c1) = (rng.below(total_rows), rng.below(total_cols));
+                    let (r2, c2) = (
+                        r1 + rng.below(total_rows - r1),
+                        c1 + rng.below(total_cols - c1),
+                    );
+                    let func = FUNCS[rng.below(FUNCS.len())];
+                    format!(
+                        "{}({}:{})",
+                        func,
+                        to_cell_name(r1, c1),
+                        to_cell_name(r2, c2)
+                    )
+                }
+                _ => format!("SLEEP({})", cell(&mut rng)),
+            }
+        })
+        .collect()
+}
+
+/// Builds a column of cells each reading the one above: `A1=1`, `A2=A1+1`, `A3=A2+1`, ...
+///
+/// Changing `A1` recalculates every cell of the chain, one after another.
+///
+/// # Arguments
+/// * `length` - The number of cells in the chain, at least 1.
+///
+/// # Returns
+/// * `Workload` - A sheet of `length` rows and one column.
+pub fn chain_sheet(length: usize) -> Workload {
+    let mut workload = Workload::new(length, 1);
+    workload.set(0, 0, "1");
+    for row in 1..length {
+        workload.set(row, 0, &format!("A{}+1", row));
+    }
+    workload
+}
+
+/// Builds a sheet of constants, with roughly one cell in `spacing` filled.
+///
+/// Cells are written directly, as a loaded sheet would hold them, without going through the
+/// parser.
+///
+/// # Arguments
+/// * `total_rows` - The number of rows in the sheet.
+/// * `total_cols` - The number of columns in the sheet.
+/// * `spacing` - 1 fills every cell; larger values leave the sheet sparser.
+/// * `seed` - Picks the cells and their values.
+///
+/// # Returns
+/// * `Workload` - The sheet.
+pub fn constant_sheet(total_rows: usize, total_cols: usize, spacing: usize, seed: u64) -> Workload {
+    let mut workload = Workload::new(total_rows, total_cols);
+    let mut rng = Lcg(seed);
+    for key in 0..total_rows * total_cols {
+        if spacing > 1 && rng.below(spacing) != 0 {
+            continue;
+        }
+        let value = rng.below(1000) as i32;
+        workload.sheet.insert(
+            key as CellKey,
+            Cell {
+                value: Valtype::Int(value),
+                data: CellData::Const,
+                dependents: HashSet::new(),
+            },
+        );
+    }
+    workload
+}